@@ -0,0 +1,12 @@
+from migen import *
+from misoc.interconnect.csr import *
+
+
+class BufferSpaceMonitor(Module, AutoCSR):
+    """Latches the buffer space answered on a CRI, each time its master asks for it."""
+    def __init__(self, cri):
+        self.buffer_space = CSRStatus(16, reset=0xffff)
+
+        # # #
+
+        self.sync += If(cri.o_buffer_space_valid, self.buffer_space.status.eq(cri.o_buffer_space))
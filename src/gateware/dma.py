@@ -4,6 +4,7 @@ from misoc.interconnect.csr import *
 from misoc.interconnect import stream
 from migen_axi.interconnect import axi
 
+from artiq.gateware.rtio import cri
 from artiq.gateware.rtio.dma import RawSlicer, RecordConverter, RecordSlicer, TimeOffset, CRIMaster
 
 import endianness
@@ -106,8 +107,11 @@ class DMAReader(Module, AutoCSR):
 
 
 class DMA(Module):
-    def __init__(self, membus):
+    def __init__(self, membus, tsc):
         self.enable = CSR()
+        # lowest slack of the events written since the last reset, in machine units
+        self.min_slack_reset = CSR()
+        self.min_slack = CSRStatus(64)
 
         flow_enable = Signal()
         self.submodules.dma = DMAReader(membus, flow_enable)
@@ -151,7 +155,22 @@ class DMA(Module):
             If(~self.cri_master.busy, NextState("IDLE"))
         )
 
+        # sampled as each event is written, not when the software gets to look
+        slack = Signal((64, True))
+        min_slack = Signal((64, True), reset=2**63 - 1)
+        self.comb += [
+            slack.eq(self.cri.o_timestamp - tsc.full_ts_cri),
+            self.min_slack.status.eq(min_slack)
+        ]
+        self.sync += [
+            If(self.min_slack_reset.re,
+                min_slack.eq(2**63 - 1)
+            ).Elif((self.cri.cmd == cri.commands["write"]) & (slack < min_slack),
+                min_slack.eq(slack)
+            )
+        ]
+
     def get_csrs(self):
-        return ([self.enable] +
+        return ([self.enable, self.min_slack_reset, self.min_slack] +
                 self.dma.get_csrs() + self.time_offset.get_csrs() +
                 self.cri_master.get_csrs())
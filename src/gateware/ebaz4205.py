@@ -226,7 +226,7 @@ class EBAZ4205(SoCCore):
             self.submodules.rtio = rtio.KernelInitiator(self.rtio_tsc, now64=True)
             self.csr_devices.append("rtio")
 
-        self.submodules.rtio_dma = dma.DMA(self.ps7.s_axi_hp0)
+        self.submodules.rtio_dma = dma.DMA(self.ps7.s_axi_hp0, self.rtio_tsc)
         self.csr_devices.append("rtio_dma")
 
         self.submodules.cri_con = rtio.CRIInterconnectShared(
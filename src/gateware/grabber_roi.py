@@ -0,0 +1,38 @@
+from migen import *
+from misoc.interconnect.csr import *
+
+
+class RoiReadout(Module, AutoCSR):
+    """Snapshot of the ROI engine outputs of a grabber, for the CPU to read all counts of one frame."""
+    def __init__(self, synchronizer):
+        self.snapshot = CSR()
+        self.frame = CSRStatus(32)
+        self.sel = CSRStorage(8)
+        self.count = CSRStatus(32)
+
+        # # #
+
+        frame = Signal(32)
+        counts = [Signal(32) for _ in synchronizer.counts]
+        self.sync.rio_phy += If(synchronizer.update,
+            frame.eq(frame + 1),
+            [count.eq(roi_count) for count, roi_count in zip(counts, synchronizer.counts)]
+        )
+
+        snapshot = [Signal(32) for _ in counts]
+        self.sync += If(self.snapshot.re,
+            self.frame.status.eq(frame),
+            [latched.eq(count) for latched, count in zip(snapshot, counts)]
+        )
+        self.comb += self.count.status.eq(Array(snapshot)[self.sel.storage])
+
+
+def add_roi_readout(soc):
+    soc.grabber_roi_csr_group = []
+    for name in soc.grabber_csr_group:
+        roi_name = name + "_roi"
+        setattr(soc.submodules, roi_name, RoiReadout(getattr(soc, name).synchronizer))
+        soc.grabber_roi_csr_group.append(roi_name)
+        soc.csr_devices.append(roi_name)
+    soc.add_csr_group("grabber_roi", soc.grabber_roi_csr_group)
+    soc.config["HAS_GRABBER_ROI"] = None
@@ -24,6 +24,8 @@ import dma
 import analyzer
 import acpki as acpki_lib
 import drtio_aux_controller
+import grabber_roi
+import buffer_space
 import zynq_clocking
 from config import generate_ident, write_csr_file, write_mem_file, write_rustc_cfg_file
 
@@ -250,7 +252,7 @@ class GenericStandalone(SoCCore):
             self.submodules.rtio = rtio.KernelInitiator(self.rtio_tsc, now64=True)
             self.csr_devices.append("rtio")
 
-        self.submodules.rtio_dma = dma.DMA(self.ps7.s_axi_hp0)
+        self.submodules.rtio_dma = dma.DMA(self.ps7.s_axi_hp0, self.rtio_tsc)
         self.csr_devices.append("rtio_dma")
 
         self.submodules.cri_con = rtio.CRIInterconnectShared(
@@ -268,6 +270,7 @@ class GenericStandalone(SoCCore):
         if has_grabber:
             self.config["HAS_GRABBER"] = None
             self.add_csr_group("grabber", self.grabber_csr_group)
+            grabber_roi.add_roi_readout(self)
             for grabber in self.grabber_csr_group:
                 self.platform.add_false_path_constraints(
                     self.sys_crg.cd_sys.clk, getattr(self, grabber).deserializer.cd_cl.clk)
@@ -430,7 +433,7 @@ class GenericMaster(SoCCore):
             self.submodules.rtio = rtio.KernelInitiator(self.rtio_tsc, now64=True)
             self.csr_devices.append("rtio")
 
-        self.submodules.rtio_dma = dma.DMA(self.ps7.s_axi_hp0)
+        self.submodules.rtio_dma = dma.DMA(self.ps7.s_axi_hp0, self.rtio_tsc)
         self.csr_devices.append("rtio_dma")
 
         self.submodules.cri_con = rtio.CRIInterconnectShared(
@@ -452,6 +455,7 @@ class GenericMaster(SoCCore):
         if has_grabber:
             self.config["HAS_GRABBER"] = None
             self.add_csr_group("grabber", self.grabber_csr_group)
+            grabber_roi.add_roi_readout(self)
         
 
         self.submodules.virtual_leds = virtual_leds.VirtualLeds()
@@ -644,7 +648,7 @@ class GenericSatellite(SoCCore):
             self.submodules.rtio = rtio.KernelInitiator(self.rtio_tsc, now64=True)
             self.csr_devices.append("rtio")
 
-        self.submodules.rtio_dma = dma.DMA(self.ps7.s_axi_hp0)
+        self.submodules.rtio_dma = dma.DMA(self.ps7.s_axi_hp0, self.rtio_tsc)
         self.csr_devices.append("rtio_dma")
 
         self.submodules.local_io = SyncRTIO(
@@ -661,6 +665,9 @@ class GenericSatellite(SoCCore):
             enable_routing=True)
         self.csr_devices.append("cri_con")
 
+        self.submodules.drtiosat_buffer_space = buffer_space.BufferSpaceMonitor(self.drtiosat.cri)
+        self.csr_devices.append("drtiosat_buffer_space")
+
         self.submodules.routing_table = rtio.RoutingTableAccess(self.cri_con)
         self.csr_devices.append("routing_table")     
 
@@ -707,6 +714,7 @@ class GenericSatellite(SoCCore):
         if has_grabber:
             self.config["HAS_GRABBER"] = None
             self.add_csr_group("grabber", self.grabber_csr_group)
+            grabber_roi.add_roi_readout(self)
             # no RTIO CRG here
         
         self.submodules.virtual_leds = virtual_leds.VirtualLeds()
@@ -139,7 +139,8 @@ class TB(Module):
 
         bus = axi.Interface(ws*8)
         self.memory = AXIMemorySim(bus, sequence)
-        self.submodules.dut = dma.DMA(bus)
+        self.submodules.tsc = rtio.TSC()
+        self.submodules.dut = dma.DMA(bus, self.tsc)
 
 
 test_writes_full_stack = [
@@ -167,8 +168,8 @@ class FullStackTB(Module):
 
         bus = axi.Interface(ws*8)
         self.memory = AXIMemorySim(bus, sequence)
-        self.submodules.dut = dma.DMA(bus)
         self.submodules.tsc = rtio.TSC()
+        self.submodules.dut = dma.DMA(bus, self.tsc)
         self.submodules.rtio = rtio.Core(self.tsc, rtio_channels)
         self.comb += self.dut.cri.connect(self.rtio.cri)
 
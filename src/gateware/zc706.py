@@ -24,6 +24,7 @@ import dma
 import analyzer
 import acpki
 import drtio_aux_controller
+import buffer_space
 import zynq_clocking
 import cxp_4r_fmc
 from config import generate_ident, write_csr_file, write_mem_file, write_rustc_cfg_file
@@ -181,7 +182,7 @@ class ZC706(SoCCore):
             self.submodules.rtio = rtio.KernelInitiator(self.rtio_tsc, now64=True)
             self.csr_devices.append("rtio")
 
-        self.submodules.rtio_dma = dma.DMA(self.ps7.s_axi_hp0)
+        self.submodules.rtio_dma = dma.DMA(self.ps7.s_axi_hp0, self.rtio_tsc)
         self.csr_devices.append("rtio_dma")
 
         self.submodules.cri_con = rtio.CRIInterconnectShared(
@@ -320,7 +321,7 @@ class _MasterBase(SoCCore):
             self.submodules.rtio = rtio.KernelInitiator(self.rtio_tsc, now64=True)
             self.csr_devices.append("rtio")
 
-        self.submodules.rtio_dma = dma.DMA(self.ps7.s_axi_hp0)
+        self.submodules.rtio_dma = dma.DMA(self.ps7.s_axi_hp0, self.rtio_tsc)
         self.csr_devices.append("rtio_dma")
 
         self.submodules.cri_con = rtio.CRIInterconnectShared(
@@ -488,7 +489,7 @@ class _SatelliteBase(SoCCore):
             self.submodules.rtio = rtio.KernelInitiator(self.rtio_tsc, now64=True)
             self.csr_devices.append("rtio")
 
-        self.submodules.rtio_dma = dma.DMA(self.ps7.s_axi_hp0)
+        self.submodules.rtio_dma = dma.DMA(self.ps7.s_axi_hp0, self.rtio_tsc)
         self.csr_devices.append("rtio_dma")
 
         self.submodules.local_io = SyncRTIO(self.rtio_tsc, rtio_channels)
@@ -502,6 +503,9 @@ class _SatelliteBase(SoCCore):
             enable_routing=True)
         self.csr_devices.append("cri_con")
 
+        self.submodules.drtiosat_buffer_space = buffer_space.BufferSpaceMonitor(self.drtiosat.cri)
+        self.csr_devices.append("drtiosat_buffer_space")
+
         self.submodules.rtio_analyzer = analyzer.Analyzer(self.rtio_tsc, self.local_io.cri,
                                                           self.ps7.s_axi_hp1)
         self.csr_devices.append("rtio_analyzer")
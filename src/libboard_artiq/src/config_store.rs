@@ -0,0 +1,53 @@
+use alloc::vec::Vec;
+use core::{fmt,
+           sync::atomic::{AtomicBool, Ordering}};
+
+use libconfig;
+
+// Config writes go through here, so that all of them are refused once the config is
+// switched to read-only, after the SD card holding it kept failing its checks.
+
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum Error {
+    ReadOnly,
+    Config(libconfig::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ReadOnly => write!(f, "config storage is read-only"),
+            Error::Config(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<libconfig::Error> for Error {
+    fn from(error: libconfig::Error) -> Self {
+        Error::Config(error)
+    }
+}
+
+pub fn set_read_only() {
+    READ_ONLY.store(true, Ordering::Relaxed);
+}
+
+pub fn read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+pub fn write(key: &str, value: Vec<u8>) -> Result<(), Error> {
+    if read_only() {
+        return Err(Error::ReadOnly);
+    }
+    Ok(libconfig::write(key, value)?)
+}
+
+pub fn remove(key: &str) -> Result<(), Error> {
+    if read_only() {
+        return Err(Error::ReadOnly);
+    }
+    Ok(libconfig::remove(key)?)
+}
@@ -0,0 +1,99 @@
+use alloc::{collections::VecDeque, vec::Vec};
+use core::fmt;
+
+use libboard_zynq::print;
+use libcortex_a9::mutex::Mutex;
+
+// Copy of what the firmware writes to the UART console, so that the console can be
+// followed over the network. The position counts every byte ever written, which lets
+// a reader resume where it left off and tell how much was overwritten in between.
+// Text typed on a remote console is queued as console input for the firmware to read.
+
+const CAPACITY: usize = 16 * 1024;
+const INPUT_CAPACITY: usize = 1024;
+
+struct Mirror {
+    buffer: [u8; CAPACITY],
+    position: u64,
+}
+
+impl Mirror {
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let index = (self.position % CAPACITY as u64) as usize;
+            self.buffer[index] = byte;
+            self.position += 1;
+        }
+    }
+}
+
+static MIRROR: Mutex<Mirror> = Mutex::new(Mirror {
+    buffer: [0; CAPACITY],
+    position: 0,
+});
+
+static INPUT: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+
+/// Writes to the UART and its mirror.
+pub struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        MIRROR.lock().push(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Like `Console`, for the panic handler: the mirror is left out rather than waited for
+/// while it is locked, as the panic may have interrupted its writer.
+pub struct PanicConsole;
+
+impl fmt::Write for PanicConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        if let Some(mut mirror) = MIRROR.try_lock() {
+            mirror.push(s.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+/// Returns the position of the next byte to be written.
+pub fn position() -> u64 {
+    MIRROR.lock().position
+}
+
+/// Appends the output written since `position` to `output`.
+/// Returns the new position and the number of bytes lost to wraparound.
+pub fn read_since(position: u64, output: &mut Vec<u8>) -> (u64, u64) {
+    let mirror = MIRROR.lock();
+    let oldest = mirror.position.saturating_sub(CAPACITY as u64);
+    let start = position.max(oldest);
+    for offset in start..mirror.position {
+        output.push(mirror.buffer[(offset % CAPACITY as u64) as usize]);
+    }
+    (mirror.position, start.saturating_sub(position))
+}
+
+/// Queues bytes typed on a remote console as console input, as they are: they need not be
+/// text. The oldest bytes are dropped if the input is not read.
+pub fn inject(bytes: &[u8]) {
+    let mut input = INPUT.lock();
+    for &byte in bytes {
+        if input.len() == INPUT_CAPACITY {
+            input.pop_front();
+        }
+        input.push_back(byte);
+    }
+}
+
+/// Takes console input into `buffer`, returns the number of bytes taken.
+pub fn read_input(buffer: &mut [u8]) -> usize {
+    let mut input = INPUT.lock();
+    let count = buffer.len().min(input.len());
+    for (slot, byte) in buffer.iter_mut().zip(input.drain(..count)) {
+        *slot = byte;
+    }
+    count
+}
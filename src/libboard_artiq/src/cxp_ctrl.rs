@@ -9,6 +9,10 @@ pub const CTRL_PACKET_MAXSIZE: usize = 128; // for compatibility with version1.x
 pub const DATA_MAXSIZE: usize =
     CTRL_PACKET_MAXSIZE - /*packet start KCodes, data packet types, CMD, Tag, Addr, CRC, packet end KCode*/4*7;
 
+// Section 9.6.4 (CXP-001-2021)
+// packet type, tag, size and CRC words around the event messages, each with at least a 12 bytes header
+pub const EVENT_PACKET_MAXEVENTS: usize = (CTRL_PACKET_MAXSIZE - 4 * 5) / 12;
+
 pub enum Error {
     CorruptedPacket,
     CtrlAckError(u8),
@@ -89,6 +93,15 @@ trait CxpRead: Read {
 impl<T: Read> CxpRead for T {}
 impl<T: Write> CxpWrite for T {}
 
+// An event raised by the camera, e.g. the start or end of an exposure. Event data is not kept.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CameraEvent {
+    pub namespace: u8,
+    pub id: u16,
+    // in camera time, ns
+    pub timestamp: u64,
+}
+
 #[derive(Debug)]
 pub enum RXCTRLPacket {
     CtrlReply {
@@ -103,6 +116,11 @@ pub enum RXCTRLPacket {
     CtrlAck {
         tag: Option<u8>,
     },
+    Event {
+        tag: u8,
+        count: usize,
+        events: [CameraEvent; EVENT_PACKET_MAXEVENTS],
+    },
 }
 
 impl RXCTRLPacket {
@@ -110,10 +128,53 @@ impl RXCTRLPacket {
         match reader.read_4x_u8()? {
             0x03 => RXCTRLPacket::get_ctrl_packet(reader, false),
             0x06 => RXCTRLPacket::get_ctrl_packet(reader, true),
+            0x07 => RXCTRLPacket::get_event_packet(reader),
             ty => Err(Error::UnknownPacket(ty)),
         }
     }
 
+    fn get_event_packet(reader: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+        // Section 9.6.4 (CXP-001-2021)
+        // the event packet is always tagged, its size is given in words of event messages
+        let tag = reader.read_4x_u8()?;
+        let size = reader.read_u32::<NetworkEndian>()? as usize * 4;
+        let end = reader.position() + size;
+        if end + 4 > reader.get_ref().len() {
+            return Err(Error::CorruptedPacket);
+        }
+
+        let mut count = 0;
+        let mut events = [CameraEvent::default(); EVENT_PACKET_MAXEVENTS];
+        while reader.position() < end {
+            // each message: size in bytes (including its header), namespace and event ID,
+            // 64-bit timestamp and data, padded to the word boundary
+            let msg_size = reader.read_u16::<NetworkEndian>()? as usize;
+            let id = reader.read_u16::<NetworkEndian>()?;
+            let timestamp = reader.read_u64::<NetworkEndian>()?;
+            if msg_size < 12 || count == EVENT_PACKET_MAXEVENTS {
+                return Err(Error::CorruptedPacket);
+            }
+            events[count] = CameraEvent {
+                namespace: (id >> 14) as u8,
+                id: id & 0xfff,
+                timestamp,
+            };
+            count += 1;
+            reader.set_position(reader.position() + (msg_size - 12 + 3) / 4 * 4);
+        }
+        if reader.position() != end {
+            return Err(Error::CorruptedPacket);
+        }
+
+        // Section 9.6.4 (CXP-001-2021)
+        // only bytes after the first 4 are used in calculating the checksum
+        let checksum = get_cxp_crc(&reader.get_ref()[4..reader.position()]);
+        if reader.read_u32::<NetworkEndian>()? != checksum {
+            return Err(Error::CorruptedPacket);
+        }
+        Ok(RXCTRLPacket::Event { tag, count, events })
+    }
+
     fn get_ctrl_packet(reader: &mut Cursor<&[u8]>, with_tag: bool) -> Result<Self, Error> {
         let mut tag: Option<u8> = None;
         if with_tag {
@@ -182,6 +243,9 @@ pub enum TXCTRLPacket {
         length: u32,
         data: [u8; DATA_MAXSIZE],
     },
+    EventAck {
+        tag: u8,
+    },
 }
 
 impl TXCTRLPacket {
@@ -244,6 +308,11 @@ impl TXCTRLPacket {
                 let checksum = get_cxp_crc(&writer.get_ref()[4..writer.position()]);
                 writer.write_u32::<NetworkEndian>(checksum)?;
             }
+            TXCTRLPacket::EventAck { tag } => {
+                // Section 9.6.5 (CXP-001-2021)
+                writer.write_4x_u8(0x08)?;
+                writer.write_4x_u8(tag)?;
+            }
         }
         Ok(())
     }
@@ -5,7 +5,7 @@ use log::{error, info};
 #[cfg(has_cxp_led)]
 use crate::cxp_led::{LEDState, update_led};
 use crate::{cxp_camera_setup::{camera_setup, discover_camera, master_channel_ready},
-            pl::csr};
+            cxp_packet, pl::csr};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum State {
@@ -63,6 +63,7 @@ async fn tick(_i2c: &mut i2c::I2c) {
                 Ok(with_tag) => {
                     info!("camera setup complete");
                     *WITH_TAG.async_lock().await = with_tag;
+                    cxp_packet::clear_events();
                     State::Connected
                 }
                 Err(e) => {
@@ -76,6 +77,9 @@ async fn tick(_i2c: &mut i2c::I2c) {
             #[cfg(has_cxp_led)]
             update_led(_i2c, LEDState::GreenSolid);
             if master_channel_ready() {
+                if let Err(e) = cxp_packet::poll_events() {
+                    error!("camera event packet error: {}", e);
+                }
                 unsafe {
                     if csr::cxp_grabber::stream_decoder_crc_error_read() == 1 {
                         error!("frame packet has CRC error");
@@ -1,16 +1,29 @@
+use alloc::collections::VecDeque;
 use core::slice;
 
 use byteorder::{ByteOrder, NetworkEndian};
 use io::Cursor;
 use libasync::task;
 use libboard_zynq::timer;
+use libcortex_a9::mutex::Mutex;
+use log::warn;
 
-use crate::{cxp_ctrl::{CTRL_PACKET_MAXSIZE, DATA_MAXSIZE, Error, RXCTRLPacket, TXCTRLPacket},
+use crate::{cxp_ctrl::{CTRL_PACKET_MAXSIZE, CameraEvent, DATA_MAXSIZE, Error, RXCTRLPacket, TXCTRLPacket},
             mem::mem,
             pl::csr};
 
 const TRANSMISSION_TIMEOUT: u64 = 200;
 
+// Section 9.6.4 (CXP-001-2021)
+const EVENT_PACKET_TYPE: u8 = 0x07;
+// events are kept until read by a kernel, the oldest are dropped when the FIFO is full
+const EVENT_FIFO_SIZE: usize = 64;
+// Mutex as it is needed by core1 cxp api calls
+static EVENTS: Mutex<VecDeque<CameraEvent>> = Mutex::new(VecDeque::new());
+// The RX buffer and the TX writer are used by core0, which polls events, and by core1 for
+// the register accesses of kernels: each packet is read or written under this lock.
+static CHANNEL: Mutex<()> = Mutex::new(());
+
 // Section 9.6.1.2 (CXP-001-2021)
 // CTRL packet need to be tagged for CXP 2.0 or greater
 static mut TAG: u8 = 0;
@@ -33,23 +46,75 @@ fn check_tag(tag: Option<u8>) -> Result<(), Error> {
     }
 }
 
+unsafe fn rx_buffer() -> &'static [u8] {
+    let read_buffer_ptr = csr::cxp_grabber::core_rx_read_ptr_read() as usize;
+    let ptr = (mem::CXP_MEM_BASE + mem::CXP_MEM_SIZE / 2 + read_buffer_ptr * CTRL_PACKET_MAXSIZE) as *mut u32;
+    slice::from_raw_parts(ptr as *const u8, CTRL_PACKET_MAXSIZE)
+}
+
+// Event packets are queued and acknowledged on the way, so that they do not get in the
+// way of a control transaction.
 fn receive_ctrl_packet() -> Result<Option<RXCTRLPacket>, Error> {
-    if unsafe { csr::cxp_grabber::core_rx_pending_packet_read() == 1 } {
-        unsafe {
-            let read_buffer_ptr = csr::cxp_grabber::core_rx_read_ptr_read() as usize;
-            let ptr = (mem::CXP_MEM_BASE + mem::CXP_MEM_SIZE / 2 + read_buffer_ptr * CTRL_PACKET_MAXSIZE) as *mut u32;
+    let _channel = CHANNEL.lock();
+    take_ctrl_packet()
+}
 
-            let mut reader = Cursor::new(slice::from_raw_parts(ptr as *const u8, CTRL_PACKET_MAXSIZE));
+// to be called with the channel locked
+fn take_ctrl_packet() -> Result<Option<RXCTRLPacket>, Error> {
+    if unsafe { csr::cxp_grabber::core_rx_pending_packet_read() == 1 } {
+        let packet = unsafe {
+            let mut reader = Cursor::new(rx_buffer());
             let packet = RXCTRLPacket::read_from(&mut reader);
 
             csr::cxp_grabber::core_rx_pending_packet_write(1);
-            Ok(Some(packet?))
+            packet?
+        };
+        match packet {
+            RXCTRLPacket::Event { tag, count, events } => {
+                queue_events(&events[..count]);
+                write_ctrl_packet(&TXCTRLPacket::EventAck { tag })?;
+                Ok(None)
+            }
+            packet => Ok(Some(packet)),
         }
     } else {
         Ok(None)
     }
 }
 
+fn queue_events(events: &[CameraEvent]) {
+    let mut fifo = EVENTS.lock();
+    for event in events {
+        if fifo.len() == EVENT_FIFO_SIZE {
+            warn!("camera event FIFO full, dropping the oldest event");
+            fifo.pop_front();
+        }
+        fifo.push_back(*event);
+    }
+}
+
+// Takes pending event packets out of the RX buffer between control transactions;
+// any other packet is left for the transaction waiting on it.
+pub fn poll_events() -> Result<(), Error> {
+    loop {
+        // held from the check, so that the packet cannot be taken by the other core in between
+        let _channel = CHANNEL.lock();
+        // packet types are sent 4 times, the first copy is enough to tell events apart
+        if unsafe { csr::cxp_grabber::core_rx_pending_packet_read() != 1 || rx_buffer()[0] != EVENT_PACKET_TYPE } {
+            return Ok(());
+        }
+        take_ctrl_packet()?;
+    }
+}
+
+pub fn pop_event() -> Option<CameraEvent> {
+    EVENTS.lock().pop_front()
+}
+
+pub fn clear_events() {
+    EVENTS.lock().clear();
+}
+
 fn receive_ctrl_packet_timeout(timeout_ms: u64) -> Result<RXCTRLPacket, Error> {
     // assume timer was initialized successfully
     let limit = timer::get_ms() + timeout_ms;
@@ -76,6 +141,12 @@ async fn async_receive_ctrl_packet_timeout(timeout_ms: u64) -> Result<RXCTRLPack
 }
 
 fn send_ctrl_packet(packet: &TXCTRLPacket) -> Result<(), Error> {
+    let _channel = CHANNEL.lock();
+    write_ctrl_packet(packet)
+}
+
+// to be called with the channel locked
+fn write_ctrl_packet(packet: &TXCTRLPacket) -> Result<(), Error> {
     unsafe {
         while csr::cxp_grabber::core_tx_writer_busy_read() == 1 {}
         let ptr = mem::CXP_MEM_BASE as *mut u32;
@@ -0,0 +1,187 @@
+use core::{ptr::{read_volatile, write_volatile},
+           sync::atomic::{AtomicBool, Ordering}};
+
+use libboard_zynq::timer;
+use libcortex_a9::{cache, mutex::Mutex};
+use log::warn;
+
+// Memory to memory copies with channel 0 of the PL330 DMA controller of the Zynq, in its
+// secure state. Programs are assembled in memory, and started and killed through the debug
+// instruction registers; completion is polled, the copies being short.
+
+const DMAC_BASE: usize = 0xf800_3000;
+const FTR0: usize = 0x040;
+const CSR0: usize = 0x100;
+const DBGSTATUS: usize = 0xd00;
+const DBGCMD: usize = 0xd04;
+const DBGINST0: usize = 0xd08;
+const DBGINST1: usize = 0xd0c;
+
+const CHANNEL: u32 = 0;
+const CHANNEL_STOPPED: u32 = 0;
+const CHANNEL_FAULTING: u32 = 0xf;
+
+// instructions
+const DMAEND: u8 = 0x00;
+const DMAKILL: u8 = 0x01;
+const DMALD: u8 = 0x04;
+const DMAST: u8 = 0x08;
+const DMAWMB: u8 = 0x13;
+const DMALP: u8 = 0x20;
+const DMALPEND: u8 = 0x38;
+const DMAGO: u8 = 0xa0;
+const DMAMOV: u8 = 0xbc;
+const SAR: u8 = 0;
+const CCR: u8 = 1;
+const DAR: u8 = 2;
+
+// 32-bit beats, incrementing source and destination addresses, secure and not cached
+const fn channel_control(beats: u32) -> u32 {
+    let beats = beats - 1;
+    1 | (2 << 1) | (beats << 4) | (1 << 14) | (2 << 15) | (beats << 18)
+}
+const BURST_BEATS: usize = 16;
+const BURST_CONTROL: u32 = channel_control(BURST_BEATS as u32);
+const BEAT_CONTROL: u32 = channel_control(1);
+// iterations of a loop instruction
+const MAX_LOOP: usize = 256;
+pub const MAX_LENGTH: usize = MAX_LOOP * BURST_BEATS * 4;
+
+const TIMEOUT_US: u64 = 1000;
+
+#[repr(align(32))]
+struct Program {
+    code: [u8; 64],
+    len: usize,
+}
+
+impl Program {
+    fn byte(&mut self, byte: u8) {
+        self.code[self.len] = byte;
+        self.len += 1;
+    }
+
+    fn mov(&mut self, register: u8, value: u32) {
+        self.byte(DMAMOV);
+        self.byte(register);
+        for byte in value.to_le_bytes() {
+            self.byte(byte);
+        }
+    }
+
+    // repeats loads and stores of the current channel control with loop counter `counter`
+    fn copy_loop(&mut self, counter: u8, iterations: usize) {
+        if iterations == 0 {
+            return;
+        }
+        self.byte(DMALP | (counter << 1));
+        self.byte((iterations - 1) as u8);
+        let start = self.len;
+        self.byte(DMALD);
+        self.byte(DMAST);
+        let jump = (self.len - start) as u8;
+        self.byte(DMALPEND | (counter << 2));
+        self.byte(jump);
+    }
+}
+
+static PROGRAM: Mutex<Program> = Mutex::new(Program { code: [0; 64], len: 0 });
+// cleared on the first fault, the callers falling back to copies by the CPU
+static AVAILABLE: AtomicBool = AtomicBool::new(true);
+
+fn read(offset: usize) -> u32 {
+    unsafe { read_volatile((DMAC_BASE + offset) as *const u32) }
+}
+
+fn write(offset: usize, value: u32) {
+    unsafe { write_volatile((DMAC_BASE + offset) as *mut u32, value) }
+}
+
+// false if the debug interface is still busy with the previous instruction after the timeout
+fn debug_idle() -> bool {
+    let deadline = timer::get_us() + TIMEOUT_US;
+    while read(DBGSTATUS) & 1 != 0 {
+        if timer::get_us() > deadline {
+            return false;
+        }
+    }
+    true
+}
+
+fn debug_instruction(instruction0: u32, instruction1: u32) -> bool {
+    if !debug_idle() {
+        return false;
+    }
+    write(DBGINST0, instruction0);
+    write(DBGINST1, instruction1);
+    write(DBGCMD, 0);
+    true
+}
+
+fn channel_status() -> u32 {
+    read(CSR0 + 8 * CHANNEL as usize) & 0xf
+}
+
+fn kill() {
+    // on the channel thread
+    if !debug_instruction(((DMAKILL as u32) << 16) | (CHANNEL << 8) | 1, 0) {
+        warn!("DMA debug interface busy, channel not killed");
+    }
+}
+
+fn fail() {
+    kill();
+    AVAILABLE.store(false, Ordering::Relaxed);
+}
+
+pub fn available() -> bool {
+    AVAILABLE.load(Ordering::Relaxed)
+}
+
+/// Copies `src` to `dst` in words, the length of `src` being a multiple of 4 and at most
+/// `MAX_LENGTH`. Returns false if the DMA controller failed, in which case nothing may have
+/// been copied.
+pub fn copy(src: &[u8], dst: *mut u32) -> bool {
+    if !available() {
+        return false;
+    }
+    let words = src.len() / 4;
+    let mut program = PROGRAM.lock();
+    program.len = 0;
+    program.mov(SAR, src.as_ptr() as u32);
+    program.mov(DAR, dst as u32);
+    program.mov(CCR, BURST_CONTROL);
+    program.copy_loop(0, words / BURST_BEATS);
+    program.mov(CCR, BEAT_CONTROL);
+    program.copy_loop(1, words % BURST_BEATS);
+    program.byte(DMAWMB);
+    program.byte(DMAEND);
+
+    cache::dcc_slice(src);
+    cache::dcc_slice(&program.code[..program.len]);
+    // on the manager thread, the channel is executing once the instruction is
+    if !debug_instruction((CHANNEL << 24) | ((DMAGO as u32) << 16), program.code.as_ptr() as u32) || !debug_idle() {
+        fail();
+        warn!("DMA debug interface stuck busy, copying with the CPU from now on");
+        return false;
+    }
+
+    let deadline = timer::get_us() + TIMEOUT_US;
+    loop {
+        match channel_status() {
+            CHANNEL_STOPPED => return true,
+            CHANNEL_FAULTING => {
+                let fault = read(FTR0 + 4 * CHANNEL as usize);
+                fail();
+                warn!("DMA channel fault 0x{:08x}, copying with the CPU from now on", fault);
+                return false;
+            }
+            _ if timer::get_us() > deadline => {
+                fail();
+                warn!("DMA copy timed out, copying with the CPU from now on");
+                return false;
+            }
+            _ => (),
+        }
+    }
+}
@@ -1,23 +1,125 @@
-use alloc::format;
+use alloc::{format, string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use libasync::task;
 use libboard_zynq::timer;
 use libconfig;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
-use crate::pl;
+use crate::{config_store, pl};
 
+// A transceiver is retrained when comma alignment keeps failing while its link is
+// down, at most once per interval so that an absent remote end is not retrained
+// over and over.
+const RETRAIN_FAILURES: u32 = 50;
+const RETRAIN_INTERVAL_MS: u64 = 60_000;
+// the 1:8 ISERDES is back at its first position after this many bitslips
+const BITSLIP_POSITIONS: u32 = 8;
+
+// stored as the delay tap of each lane, followed by the wordslip setting
+// (absent in records written by older firmware)
+#[derive(Clone, Copy)]
 struct SerdesConfig {
     pub delay: [u8; 4],
+    pub wordslip: Option<u8>,
 }
 
 impl SerdesConfig {
-    pub fn as_bytes(&self) -> &[u8] {
-        unsafe {
-            core::slice::from_raw_parts(
-                (self as *const SerdesConfig) as *const u8,
-                core::mem::size_of::<SerdesConfig>(),
-            )
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.delay.to_vec();
+        if let Some(wordslip) = self.wordslip {
+            bytes.push(wordslip);
         }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<SerdesConfig> {
+        if bytes.len() < 4 {
+            return None;
+        }
+        let mut delay = [0; 4];
+        delay.copy_from_slice(&bytes[..4]);
+        Some(SerdesConfig {
+            delay: delay,
+            wordslip: bytes.get(4).copied(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LaneMargin {
+    pub delay: u8,
+    // deviation of the phase detector from 50% at the selected tap, in thousandths
+    pub deviation: u16,
+    // taps around the selected one where the phase detector is neither stuck low nor high
+    pub transition_width: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Training {
+    pub wordslip: u8,
+    pub lanes: [LaneMargin; 4],
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LaneErrors {
+    // the checker found the PRBS sequence in the received data
+    pub locked: bool,
+    pub bits: u64,
+    pub errors: u64,
+}
+
+#[derive(Clone, Copy)]
+struct TransceiverState {
+    delay: [u8; 4],
+    wordslip: u8,
+    failures: u32,
+    last_retrain: u64,
+    // settings found while polling the link, left to store_unsaved to write to the config
+    unsaved: Option<SerdesConfig>,
+}
+
+const INITIAL_STATE: TransceiverState = TransceiverState {
+    delay: [0; 4],
+    wordslip: 0,
+    failures: 0,
+    last_retrain: 0,
+    unsaved: None,
+};
+
+static mut STATE: [TransceiverState; pl::csr::CONFIG_EEM_DRTIO_COUNT as usize] =
+    [INITIAL_STATE; pl::csr::CONFIG_EEM_DRTIO_COUNT as usize];
+
+// A retraining yields to other tasks while it measures, with its transceiver selected;
+// the others are left alone until it is done.
+static RETRAINING: AtomicBool = AtomicBool::new(false);
+
+// clears RETRAINING once the retraining is over, also when its future is dropped
+struct RetrainingGuard;
+
+impl Drop for RetrainingGuard {
+    fn drop(&mut self) {
+        RETRAINING.store(false, Ordering::Release);
+    }
+}
+
+fn config_key(trx_no: u8) -> String {
+    format!("eem_drtio_delay{}", trx_no)
+}
+
+fn load_config(trx_no: u8) -> Option<SerdesConfig> {
+    libconfig::read(&config_key(trx_no))
+        .ok()
+        .and_then(|record| SerdesConfig::from_bytes(&record))
+}
+
+fn store_config(trx_no: u8, config: &SerdesConfig) {
+    match config_store::write(&config_key(trx_no), config.to_bytes()) {
+        Ok(()) => info!("storing calibration timing values into sd card"),
+        Err(e) => error!(
+            "calibration successful but calibration timing values cannot be stored into sd card. Error:{}",
+            e
+        ),
     }
 }
 
@@ -43,56 +145,55 @@ fn apply_config(config: &SerdesConfig) {
     }
 }
 
-unsafe fn assign_delay() -> SerdesConfig {
-    // Select an appropriate delay for lane 0
-    select_lane(0);
-
-    //
-
-    let mut best_dly = None;
-
-    loop {
-        let mut prev = None;
-        for curr_dly in 0..32 {
-            //let read_align = read_align_fn(curr_dly);
-            let curr_low_rate = read_align(curr_dly);
-
-            if let Some(prev_low_rate) = prev {
-                // This is potentially a crossover position
-                if prev_low_rate <= curr_low_rate && curr_low_rate >= 0.5 {
-                    let prev_dev = 0.5 - prev_low_rate;
-                    let curr_dev = curr_low_rate - 0.5;
-                    let selected_idx = if prev_dev < curr_dev { curr_dly - 1 } else { curr_dly };
-
-                    // The setup setup/hold calibration timing (even with
-                    // tolerance) might be invalid in other lanes due to skew.
-                    // 5 taps is very conservative, generally it is 1 or 2
-                    if selected_idx < 5 {
-                        prev = None;
-                        continue;
-                    } else {
-                        best_dly = Some(selected_idx);
-                        break;
-                    }
+async fn find_lane0_delay() -> Option<u8> {
+    let mut prev = None;
+    for curr_dly in 0..32 {
+        //let read_align = read_align_fn(curr_dly);
+        let curr_low_rate = read_align(curr_dly).await;
+
+        if let Some(prev_low_rate) = prev {
+            // This is potentially a crossover position
+            if prev_low_rate <= curr_low_rate && curr_low_rate >= 0.5 {
+                let prev_dev = 0.5 - prev_low_rate;
+                let curr_dev = curr_low_rate - 0.5;
+                let selected_idx = if prev_dev < curr_dev { curr_dly - 1 } else { curr_dly };
+
+                // The setup setup/hold calibration timing (even with
+                // tolerance) might be invalid in other lanes due to skew.
+                // 5 taps is very conservative, generally it is 1 or 2
+                if selected_idx < 5 {
+                    prev = None;
+                    continue;
+                } else {
+                    return Some(selected_idx);
                 }
             }
-
-            // Only rising slope from <= 0.5 can result in a rising low rate
-            // crossover at 50%.
-            if curr_low_rate <= 0.5 {
-                prev = Some(curr_low_rate);
-            }
         }
 
-        if best_dly.is_none() {
-            error!("setup/hold timing calibration failed, retry in 1s...");
-            timer::delay_us(1_000_000);
-        } else {
-            break;
+        // Only rising slope from <= 0.5 can result in a rising low rate
+        // crossover at 50%.
+        if curr_low_rate <= 0.5 {
+            prev = Some(curr_low_rate);
         }
     }
+    None
+}
 
-    let best_dly = best_dly.unwrap();
+// Retries until calibration succeeds if `retry` is set, gives up after one attempt otherwise.
+async fn assign_delay(retry: bool) -> Option<SerdesConfig> {
+    // Select an appropriate delay for lane 0
+    select_lane(0);
+
+    let best_dly = loop {
+        match find_lane0_delay().await {
+            Some(best_dly) => break best_dly,
+            None if retry => {
+                error!("setup/hold timing calibration failed, retry in 1s...");
+                timer::async_delay_ms(1000).await;
+            }
+            None => return None,
+        }
+    };
 
     apply_delay(best_dly);
     let mut delay_list = [best_dly; 4];
@@ -105,9 +206,7 @@ unsafe fn assign_delay() -> SerdesConfig {
         let mut min_idx = 0;
         for dly_delta in -3..=3 {
             let index = (best_dly as isize + dly_delta) as u8;
-            let low_rate = read_align(index);
-            // abs() from f32 is not available in core library
-            let deviation = if low_rate < 0.5 { 0.5 - low_rate } else { low_rate - 0.5 };
+            let deviation = deviation(read_align(index).await);
 
             if deviation < min_deviation {
                 min_deviation = deviation;
@@ -121,16 +220,44 @@ unsafe fn assign_delay() -> SerdesConfig {
 
     debug!("setup/hold timing calibration: {:?}", delay_list);
 
-    SerdesConfig { delay: delay_list }
+    Some(SerdesConfig {
+        delay: delay_list,
+        wordslip: None,
+    })
+}
+
+fn deviation(low_rate: f32) -> f32 {
+    // abs() from f32 is not available in core library
+    if low_rate < 0.5 { 0.5 - low_rate } else { low_rate - 0.5 }
+}
+
+// Samples the phase detector around the selected tap of a lane, then puts the tap back.
+async fn measure_margin(lane_no: u8, delay: u8) -> LaneMargin {
+    select_lane(lane_no);
+    let at_delay = read_align(delay).await;
+    let mut transition_width = 0;
+    for dly in delay.saturating_sub(8)..=(delay + 8).min(31) {
+        let low_rate = read_align(dly).await;
+        if low_rate > 0.1 && low_rate < 0.9 {
+            transition_width += 1;
+        }
+    }
+    apply_delay(delay);
+    LaneMargin {
+        delay: delay,
+        deviation: (deviation(at_delay) * 1000.0) as u16,
+        transition_width: transition_width,
+    }
 }
 
-fn read_align(dly: u8) -> f32 {
+async fn read_align(dly: u8) -> f32 {
+    apply_delay(dly);
     unsafe {
-        apply_delay(dly);
         pl::csr::eem_transceiver::counter_reset_write(1);
-
         pl::csr::eem_transceiver::counter_enable_write(1);
-        timer::delay_us(2000);
+    }
+    timer::async_delay_us(2000).await;
+    unsafe {
         pl::csr::eem_transceiver::counter_enable_write(0);
 
         let (high, low) = (
@@ -145,7 +272,9 @@ fn read_align(dly: u8) -> f32 {
     }
 }
 
-unsafe fn align_comma() {
+// Returns the wordslip setting that aligned the comma, or None if it failed
+// and `retry` is not set. Counts the bitslips applied into `bitslips`.
+async fn align_comma(retry: bool, bitslips: &mut u32) -> Option<u8> {
     loop {
         for slip in 1..=10 {
             // The soft transceiver has 2 8b10b decoders, which receives lane
@@ -162,84 +291,240 @@ unsafe fn align_comma() {
             // takes 2 sysclk cycles. Adjusting bitslip only via ISERDES
             // limits the range to 1 cycle. The wordslip bit extends the range
             // to 2 sysclk cycles.
-            pl::csr::eem_transceiver::wordslip_write((slip > 5) as u8);
+            let aligned = unsafe {
+                pl::csr::eem_transceiver::wordslip_write((slip > 5) as u8);
 
-            // Apply a double bitslip since the ISERDES is 2x oversampled.
-            // Bitslip is used for comma alignment purposes once setup/hold
-            // timing is met.
-            pl::csr::eem_transceiver::bitslip_write(1);
-            pl::csr::eem_transceiver::bitslip_write(1);
-            timer::delay_us(1);
+                // Apply a double bitslip since the ISERDES is 2x oversampled.
+                // Bitslip is used for comma alignment purposes once setup/hold
+                // timing is met.
+                pl::csr::eem_transceiver::bitslip_write(1);
+                pl::csr::eem_transceiver::bitslip_write(1);
+                timer::delay_us(1);
 
-            pl::csr::eem_transceiver::comma_align_reset_write(1);
-            timer::delay_us(100);
+                pl::csr::eem_transceiver::comma_align_reset_write(1);
+                timer::delay_us(100);
 
-            if pl::csr::eem_transceiver::comma_read() == 1 {
+                pl::csr::eem_transceiver::comma_read() == 1
+            };
+            *bitslips += 2;
+
+            if aligned {
                 debug!("comma alignment completed after {} bitslips", slip);
-                return;
+                return Some((slip > 5) as u8);
             }
         }
 
+        if !retry {
+            return None;
+        }
         error!("comma alignment failed, retrying in 1s...");
-        timer::delay_us(1_000_000);
+        timer::async_delay_ms(1000).await;
     }
 }
 
-pub unsafe fn align_wordslip(trx_no: u8) -> bool {
-    pl::csr::eem_transceiver::transceiver_sel_write(trx_no);
+// Tries the wordslip setting that worked last first. When alignment keeps failing,
+// the transceiver is retrained (see RETRAIN_FAILURES).
+pub async fn align_wordslip(trx_no: u8) -> bool {
+    if RETRAINING.load(Ordering::Relaxed) {
+        return false;
+    }
+    let retrain_due = unsafe {
+        pl::csr::eem_transceiver::transceiver_sel_write(trx_no);
 
-    for slip in 0..=1 {
-        pl::csr::eem_transceiver::wordslip_write(slip as u8);
-        timer::delay_us(1);
-        pl::csr::eem_transceiver::comma_align_reset_write(1);
-        timer::delay_us(100);
+        let state = &mut STATE[trx_no as usize];
+        let preferred = state.wordslip;
+        for slip in [preferred, preferred ^ 1] {
+            pl::csr::eem_transceiver::wordslip_write(slip);
+            timer::delay_us(1);
+            pl::csr::eem_transceiver::comma_align_reset_write(1);
+            timer::delay_us(100);
 
-        if pl::csr::eem_transceiver::comma_read() == 1 {
-            debug!("comma alignment completed with {} wordslip", slip);
-            return true;
+            if pl::csr::eem_transceiver::comma_read() == 1 {
+                debug!("comma alignment completed with {} wordslip", slip);
+                state.failures = 0;
+                if slip != preferred {
+                    state.wordslip = slip;
+                    state.unsaved = Some(SerdesConfig {
+                        delay: state.delay,
+                        wordslip: Some(slip),
+                    });
+                }
+                return true;
+            }
+        }
+
+        state.failures += 1;
+        let now = timer::get_ms();
+        let retrain_due = state.failures >= RETRAIN_FAILURES && now >= state.last_retrain + RETRAIN_INTERVAL_MS;
+        if retrain_due {
+            state.failures = 0;
+            state.last_retrain = now;
+        }
+        retrain_due
+    };
+
+    if retrain_due {
+        warn!("EEM transceiver {}: comma alignment keeps failing, retraining", trx_no);
+        match retrain(trx_no).await {
+            Ok(training) => info!("EEM transceiver {}: retrained, {:?}", trx_no, training),
+            Err(e) => warn!("EEM transceiver {}: retraining failed: {}", trx_no, e),
         }
     }
 
     false
 }
 
+// Calibrates the delays and aligns the comma of a transceiver again, going back to the
+// previous delays, wordslip and bitslip if that fails. The new settings are left for
+// store_unsaved to write to the config. Other tasks keep running in the meantime.
+pub async fn retrain(trx_no: u8) -> Result<Training, &'static str> {
+    if trx_no as u32 >= pl::csr::CONFIG_EEM_DRTIO_COUNT as u32 {
+        return Err("no such transceiver");
+    }
+    if RETRAINING.swap(true, Ordering::Acquire) {
+        return Err("another transceiver is being retrained");
+    }
+    let _guard = RetrainingGuard;
+    unsafe {
+        pl::csr::eem_transceiver::transceiver_sel_write(trx_no);
+    }
+    let previous = unsafe { STATE[trx_no as usize] };
+    let mut bitslips = 0;
+    let result = train(&mut bitslips).await;
+    match result {
+        Ok((config, _)) => unsafe {
+            let state = &mut STATE[trx_no as usize];
+            state.delay = config.delay;
+            state.wordslip = config.wordslip.unwrap_or(previous.wordslip);
+            state.unsaved = Some(config);
+        },
+        Err(_) => {
+            apply_config(&SerdesConfig {
+                delay: previous.delay,
+                wordslip: None,
+            });
+            unsafe {
+                pl::csr::eem_transceiver::wordslip_write(previous.wordslip);
+                // bitslips only move forward, so go around to where they were
+                for _ in 0..(BITSLIP_POSITIONS - bitslips % BITSLIP_POSITIONS) % BITSLIP_POSITIONS {
+                    pl::csr::eem_transceiver::bitslip_write(1);
+                }
+            }
+        }
+    }
+    result.map(|(_, training)| training)
+}
+
+/// Writes the settings found since the last call to the config. Called apart from
+/// the polling of the links, as the config is written to the SD card.
+pub fn store_unsaved() {
+    for trx_no in 0..pl::csr::CONFIG_EEM_DRTIO_COUNT as u8 {
+        let unsaved = unsafe { STATE[trx_no as usize].unsaved.take() };
+        if let Some(config) = unsaved {
+            store_config(trx_no, &config);
+        }
+    }
+}
+
+async fn train(bitslips: &mut u32) -> Result<(SerdesConfig, Training), &'static str> {
+    let mut config = assign_delay(false)
+        .await
+        .ok_or("setup/hold timing calibration failed")?;
+    let mut lanes = [LaneMargin::default(); 4];
+    for (lane_no, lane) in lanes.iter_mut().enumerate() {
+        *lane = measure_margin(lane_no as u8, config.delay[lane_no]).await;
+    }
+    let wordslip = align_comma(false, bitslips).await.ok_or("comma alignment failed")?;
+    config.wordslip = Some(wordslip);
+    Ok((
+        config,
+        Training {
+            wordslip: wordslip,
+            lanes: lanes,
+        },
+    ))
+}
+
+// longest PRBS test, other tasks keep running while it counts
+pub const PRBS_TEST_MAX_MS: u64 = 10_000;
+
+// Sends a PRBS7 pattern on every lane of a transceiver and counts the bit errors of the
+// received pattern. With `near_end`, the serializer output is looped back to the receiver
+// within the FPGA; otherwise the pattern goes through the cable, which must be looped
+// back at its far end. A test that passes near the FPGA but fails through the cable points
+// at the cable or a connector. The link is down during the test, and the transceiver is
+// retrained afterwards.
+#[cfg(has_eem_prbs)]
+pub async fn prbs_test(trx_no: u8, near_end: bool, duration_ms: u64) -> Result<[LaneErrors; 4], &'static str> {
+    if trx_no as u32 >= pl::csr::CONFIG_EEM_DRTIO_COUNT as u32 {
+        return Err("no such transceiver");
+    }
+    let mut lanes = [LaneErrors::default(); 4];
+    unsafe {
+        pl::csr::eem_prbs::transceiver_sel_write(trx_no);
+        pl::csr::eem_prbs::loopback_write(near_end as u8);
+        pl::csr::eem_prbs::enable_write(1);
+    }
+    // give the checkers time to lock onto the pattern before counting
+    timer::async_delay_ms(1).await;
+    unsafe {
+        pl::csr::eem_prbs::counter_reset_write(1);
+    }
+    timer::async_delay_ms(duration_ms.min(PRBS_TEST_MAX_MS)).await;
+    unsafe {
+        pl::csr::eem_prbs::counter_freeze_write(1);
+        for (lane_no, lane) in lanes.iter_mut().enumerate() {
+            pl::csr::eem_prbs::lane_sel_write(lane_no as u8);
+            *lane = LaneErrors {
+                locked: pl::csr::eem_prbs::locked_read() != 0,
+                bits: pl::csr::eem_prbs::bit_count_read() as u64,
+                errors: pl::csr::eem_prbs::error_count_read() as u64,
+            };
+        }
+        pl::csr::eem_prbs::counter_freeze_write(0);
+        pl::csr::eem_prbs::enable_write(0);
+        pl::csr::eem_prbs::loopback_write(0);
+    }
+    // the loopback breaks the word alignment of the link
+    if let Err(e) = retrain(trx_no).await {
+        warn!("EEM transceiver {}: retraining after the PRBS test failed: {}", trx_no, e);
+    }
+    Ok(lanes)
+}
+
 pub fn init() {
     for trx_no in 0..pl::csr::CONFIG_EEM_DRTIO_COUNT {
         unsafe {
             pl::csr::eem_transceiver::transceiver_sel_write(trx_no as u8);
         }
 
-        let key = format!("eem_drtio_delay{}", trx_no);
-
-        let cfg_read = libconfig::read(&key);
-        match cfg_read {
-            Ok(record) => {
+        let config = match load_config(trx_no as u8) {
+            Some(config) => {
                 info!("loading calibrated timing values from sd card");
-                unsafe {
-                    apply_config(&*(record.as_ptr() as *const SerdesConfig));
-                }
+                apply_config(&config);
+                config
             }
-            Err(_) => {
+            None => {
                 info!("calibrating...");
-                let config = unsafe { assign_delay() };
-
-                match libconfig::write(&key, config.as_bytes().to_vec()) {
-                    Ok(()) => {
-                        info!("storing calibration timing values into sd card");
-                    }
-                    Err(e) => {
-                        error!(
-                            "calibration successful but calibration timing values cannot be stored into sd card. \
-                             Error:{}",
-                            e
-                        );
-                    }
-                };
+                let config = task::block_on(assign_delay(true)).unwrap();
+                store_config(trx_no as u8, &config);
+                config
             }
+        };
+
+        let wordslip = task::block_on(align_comma(true, &mut 0)).unwrap();
+        if config.wordslip != Some(wordslip) {
+            store_config(
+                trx_no as u8,
+                &SerdesConfig {
+                    delay: config.delay,
+                    wordslip: Some(wordslip),
+                },
+            );
         }
-
         unsafe {
-            align_comma();
+            STATE[trx_no as usize].delay = config.delay;
+            STATE[trx_no as usize].wordslip = wordslip;
         }
     }
 }
@@ -1,8 +1,10 @@
+use alloc::{string::String, vec::Vec};
 use core::fmt;
 
 use libconfig;
 use log::{info, warn};
 
+use crate::{config_store, drtioaux_proto::DESTINATION_MASK_SIZE};
 #[cfg(has_drtio_routing)]
 use crate::pl::csr;
 
@@ -13,9 +15,46 @@ pub const DEST_COUNT: usize = 0;
 pub const MAX_HOPS: usize = 32;
 pub const INVALID_HOP: u8 = 0xff;
 
+// last routing table received from the master by a satellite, followed by its rank
+const ROUTING_TABLE_CACHE_KEY: &str = "routing_table_cache";
+
+// destination sets are carried as bitmasks, one bit per destination
+pub fn mask_contains(mask: &[u8], destination: u8) -> bool {
+    mask[destination as usize / 8] & (1 << (destination % 8)) != 0
+}
+
+pub fn mask_insert(mask: &mut [u8], destination: u8) {
+    mask[destination as usize / 8] |= 1 << (destination % 8);
+}
+
 pub struct RoutingTable(pub [[u8; MAX_HOPS]; DEST_COUNT]);
 
 impl RoutingTable {
+    // Splits off the targets reached through `hop` at `rank`, along with the destination
+    // directly behind that hop, which is the one to hand them to.
+    pub fn targets_behind(&self, rank: u8, hop: u8, targets: &[u8]) -> ([u8; DESTINATION_MASK_SIZE], Option<u8>) {
+        let rank = rank as usize;
+        let mut hop_targets = [0; DESTINATION_MASK_SIZE];
+        let mut entry = None;
+        for (dest, hops) in self.0.iter().enumerate() {
+            if hops[rank] != hop {
+                continue;
+            }
+            if mask_contains(targets, dest as u8) {
+                mask_insert(&mut hop_targets, dest as u8);
+            }
+            if rank + 1 < MAX_HOPS && hops[rank + 1] == 0 {
+                entry = Some(dest as u8);
+            }
+        }
+        (hop_targets, entry)
+    }
+
+    // the master is the destination served by its own local RTIO, at hop 0 of the first rank
+    pub fn master_destination(&self) -> Option<u8> {
+        self.0.iter().position(|hops| hops[0] == 0).map(|dest| dest as u8)
+    }
+
     // default routing table is for star topology with no repeaters
     pub fn default_master(default_n_links: usize) -> RoutingTable {
         let mut ret = RoutingTable([[INVALID_HOP; MAX_HOPS]; DEST_COUNT]);
@@ -76,6 +115,64 @@ pub fn config_routing_table(default_n_links: usize) -> RoutingTable {
 }
 
 #[cfg(has_drtio_routing)]
+pub fn cached_routing_table() -> Option<(RoutingTable, u8)> {
+    let data = libconfig::read(ROUTING_TABLE_CACHE_KEY).ok()?;
+    if data.len() != DEST_COUNT * MAX_HOPS + 1 {
+        warn!("length of the cached routing table is incorrect, ignoring");
+        return None;
+    }
+    let mut ret = RoutingTable::default_empty();
+    for i in 0..DEST_COUNT {
+        for j in 0..MAX_HOPS {
+            ret.0[i][j] = data[i * MAX_HOPS + j];
+        }
+    }
+    Some((ret, data[DEST_COUNT * MAX_HOPS]))
+}
+
+// Only writes to the card when the table or rank differ from the cached ones.
+pub fn cache_routing_table(routing_table: &RoutingTable, rank: u8) {
+    let mut data = Vec::with_capacity(DEST_COUNT * MAX_HOPS + 1);
+    for hops in routing_table.0.iter() {
+        data.extend_from_slice(hops);
+    }
+    data.push(rank);
+    if libconfig::read(ROUTING_TABLE_CACHE_KEY).map_or(false, |cached| cached == data) {
+        return;
+    }
+    info!("routing table differs from the cached one, updating cache");
+    if let Err(e) = config_store::write(ROUTING_TABLE_CACHE_KEY, data) {
+        warn!("failed to cache routing table: {:?}", e);
+    }
+}
+
+// Looks up a named set of destinations in the `destination_groups` key, made of
+// `<name>=<destination>,<destination>,...` entries separated by semicolons.
+pub fn destination_group(name: &str) -> Option<Vec<u8>> {
+    let groups = libconfig::read_str("destination_groups").ok()?;
+    for entry in groups.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (group, destinations) = match entry.split_once('=') {
+            Some(pair) => pair,
+            None => {
+                warn!("destination_groups entry \"{}\" not supported, ignoring", entry);
+                continue;
+            }
+        };
+        if group.trim() != name {
+            continue;
+        }
+        let mut members = Vec::new();
+        for destination in destinations.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match destination.parse::<u8>() {
+                Ok(destination) if (destination as usize) < DEST_COUNT => members.push(destination),
+                _ => warn!("destination \"{}\" of group {} not supported, ignoring", destination, name),
+            }
+        }
+        return Some(members);
+    }
+    None
+}
+
 pub fn interconnect_enable(routing_table: &RoutingTable, rank: u8, destination: u8) {
     let hop = routing_table.0[destination as usize][rank as usize];
     unsafe {
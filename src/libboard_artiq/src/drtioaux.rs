@@ -1,28 +1,53 @@
-use core::{arch::asm, slice};
+use alloc::vec::Vec;
+use core::{arch::asm, slice,
+           sync::atomic::{AtomicBool, AtomicU32, Ordering}};
 
-use byteorder::NativeEndian;
+use byteorder::{ByteOrder, NativeEndian};
 use core_io::{Error as IoError, ErrorKind as IoErrorKind};
 use io::{Cursor,
          proto::{ProtoRead, ProtoWrite}};
 use libboard_zynq::timer;
+use libcortex_a9::mutex::Mutex;
 
-pub use crate::drtioaux_proto::{MAX_PACKET, Packet};
-use crate::{drtioaux_proto::Error as ProtocolError, mem::mem::DRTIOAUX_MEM, pl::csr::DRTIOAUX};
+pub use crate::drtioaux_proto::{AUX_CAPTURE_SIZE, AUX_TRACE_ENTRY_SIZE, MAX_PACKET, Packet};
+use crate::{dmac, drtioaux_proto::Error as ProtocolError, mem::mem::DRTIOAUX_MEM, pl::csr::DRTIOAUX};
 
 #[derive(Debug)]
 pub enum Error {
     GatewareError,
     CorruptedPacket,
+    TruncatedPacket,
+    MalformedPacket,
 
     LinkDown,
     TimedOut,
     UnexpectedReply,
 
     RoutingError,
+    IncompatibleVersion(u16),
 
     Protocol(ProtocolError),
 }
 
+impl Error {
+    // damaged in transit, rather than refused or lost
+    pub fn is_damaged(&self) -> bool {
+        matches!(self, Error::CorruptedPacket | Error::TruncatedPacket | Error::MalformedPacket)
+    }
+}
+
+impl Error {
+    // reported with captured packets: 1 corrupted, 2 truncated, 3 malformed, 4 unknown type
+    fn capture_kind(&self) -> u8 {
+        match self {
+            Error::CorruptedPacket => 1,
+            Error::TruncatedPacket => 2,
+            Error::Protocol(ProtocolError::UnknownPacket(_)) => 4,
+            _ => 3,
+        }
+    }
+}
+
 impl From<ProtocolError> for Error {
     fn from(value: ProtocolError) -> Error {
         Error::Protocol(value)
@@ -35,6 +60,273 @@ impl From<IoError> for Error {
     }
 }
 
+// First byte of a framed packet, which is never a packet type. A framed packet is
+// the marker, the u16 length of the packet, the packet, padding and the CRC32 of
+// everything before it. Framing is only sent to peers that advertised support for it,
+// but always understood on reception.
+const FRAME_MARKER: u8 = 0xff;
+const FRAME_HEADER_SIZE: usize = 3;
+
+static FRAMED_LINKS: AtomicU32 = AtomicU32::new(0);
+
+pub fn set_framing(linkno: u8, enabled: bool) {
+    if enabled {
+        FRAMED_LINKS.fetch_or(1 << linkno, Ordering::Relaxed);
+    } else {
+        FRAMED_LINKS.fetch_and(!(1 << linkno), Ordering::Relaxed);
+    }
+}
+
+pub fn framing(linkno: u8) -> bool {
+    FRAMED_LINKS.load(Ordering::Relaxed) & (1 << linkno) != 0
+}
+
+// retransmissions of damaged packets within one aux transaction
+pub const AUX_RETRANSMIT_LIMIT: u32 = 3;
+
+// Copy of the last packet sent on each framed link, as encoded, for a RetransmitRequest.
+// The TX buffer cannot be sent again: the DMA controller may have been copying the next
+// packet into it, or a packet to another destination may have gone out meanwhile.
+static LAST_SENT: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+pub(crate) fn remember(linkno: u8, buffer: &[u8]) {
+    if !framing(linkno) {
+        return;
+    }
+    let mut last_sent = LAST_SENT.lock();
+    let linkno = linkno as usize;
+    if last_sent.len() <= linkno {
+        last_sent.resize(linkno + 1, Vec::new());
+    }
+    last_sent[linkno].clear();
+    last_sent[linkno].extend_from_slice(buffer);
+}
+
+pub(crate) fn last_sent(linkno: u8) -> Option<Vec<u8>> {
+    LAST_SENT
+        .lock()
+        .get(linkno as usize)
+        .filter(|buffer| !buffer.is_empty())
+        .cloned()
+}
+
+// Diagnostic mode (config key `aux_capture`) keeping the leading bytes of the
+// latest packets that could not be decoded, to be read out over core management.
+const CAPTURE_COUNT: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Capture {
+    pub linkno: u8,
+    pub timestamp_ms: u64,
+    pub kind: u8,
+    pub data: [u8; AUX_CAPTURE_SIZE],
+}
+
+struct Captures {
+    entries: [Option<Capture>; CAPTURE_COUNT],
+    next: usize,
+}
+
+static CAPTURE_ENABLED: AtomicBool = AtomicBool::new(false);
+static CAPTURES: Mutex<Captures> = Mutex::new(Captures {
+    entries: [None; CAPTURE_COUNT],
+    next: 0,
+});
+
+pub fn set_capture(enabled: bool) {
+    CAPTURE_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+// oldest first
+pub fn captures() -> Vec<Capture> {
+    let captures = CAPTURES.lock();
+    (0..CAPTURE_COUNT)
+        .filter_map(|i| captures.entries[(captures.next + i) % CAPTURE_COUNT])
+        .collect()
+}
+
+pub(crate) fn capture(linkno: u8, buffer: &[u8], error: Error) -> Error {
+    if CAPTURE_ENABLED.load(Ordering::Relaxed) {
+        let mut data = [0; AUX_CAPTURE_SIZE];
+        let length = buffer.len().min(AUX_CAPTURE_SIZE);
+        data[..length].copy_from_slice(&buffer[..length]);
+        let mut captures = CAPTURES.lock();
+        let next = captures.next;
+        captures.entries[next] = Some(Capture {
+            linkno: linkno,
+            timestamp_ms: timer::get_ms(),
+            kind: error.capture_kind(),
+            data: data,
+        });
+        captures.next = (next + 1) % CAPTURE_COUNT;
+    }
+    error
+}
+
+// Protocol tracing, toggled over core management: the time, link, direction, type and
+// destination of the latest packets sent and received, to diagnose stuck exchanges.
+const TRACE_COUNT: usize = 256;
+// recorded for packets not addressed to a destination
+pub const TRACE_NO_DESTINATION: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEntry {
+    pub timestamp_ms: u64,
+    pub linkno: u8,
+    pub transmitted: bool,
+    pub packet_type: u8,
+    pub destination: u8,
+}
+
+impl TraceEntry {
+    pub fn to_bytes(&self) -> [u8; AUX_TRACE_ENTRY_SIZE] {
+        let mut bytes = [0; AUX_TRACE_ENTRY_SIZE];
+        NativeEndian::write_u64(&mut bytes[0..8], self.timestamp_ms);
+        bytes[8] = self.linkno;
+        bytes[9] = self.transmitted as u8;
+        bytes[10] = self.packet_type;
+        bytes[11] = self.destination;
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> TraceEntry {
+        TraceEntry {
+            timestamp_ms: NativeEndian::read_u64(&bytes[0..8]),
+            linkno: bytes[8],
+            transmitted: bytes[9] != 0,
+            packet_type: bytes[10],
+            destination: bytes[11],
+        }
+    }
+}
+
+struct Trace {
+    entries: [Option<TraceEntry>; TRACE_COUNT],
+    next: usize,
+}
+
+static TRACE_ENABLED: AtomicBool = AtomicBool::new(false);
+static TRACE: Mutex<Trace> = Mutex::new(Trace {
+    entries: [None; TRACE_COUNT],
+    next: 0,
+});
+
+// enabling starts a new trace
+pub fn set_tracing(enabled: bool) {
+    if enabled && !TRACE_ENABLED.load(Ordering::Relaxed) {
+        let mut trace = TRACE.lock();
+        trace.entries = [None; TRACE_COUNT];
+        trace.next = 0;
+    }
+    TRACE_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn tracing() -> bool {
+    TRACE_ENABLED.load(Ordering::Relaxed)
+}
+
+// oldest first
+pub fn trace() -> Vec<TraceEntry> {
+    let trace = TRACE.lock();
+    (0..TRACE_COUNT)
+        .filter_map(|i| trace.entries[(trace.next + i) % TRACE_COUNT])
+        .collect()
+}
+
+// `buffer` holds the packet as on the link
+pub(crate) fn trace_packet(linkno: u8, transmitted: bool, buffer: &[u8], packet: &Packet) {
+    if TRACE_ENABLED.load(Ordering::Relaxed) {
+        let packet_type = if buffer[0] == FRAME_MARKER {
+            buffer[FRAME_HEADER_SIZE]
+        } else {
+            buffer[0]
+        };
+        let mut trace = TRACE.lock();
+        let next = trace.next;
+        trace.entries[next] = Some(TraceEntry {
+            timestamp_ms: timer::get_ms(),
+            linkno: linkno,
+            transmitted: transmitted,
+            packet_type: packet_type,
+            destination: packet.routable_destination().unwrap_or(TRACE_NO_DESTINATION),
+        });
+        trace.next = (next + 1) % TRACE_COUNT;
+    }
+}
+
+fn checksum_padding(position: usize) -> usize {
+    // pad till offset 4 modulo 8, where the checksum goes
+    (12 - (position % 8)) % 8
+}
+
+pub(crate) fn decode_packet(buffer: &[u8]) -> Result<Packet, Error> {
+    if buffer.len() < 8 {
+        return Err(IoError::new(IoErrorKind::UnexpectedEof, "Unexpected end").into());
+    }
+
+    let mut reader = Cursor::new(buffer);
+
+    if buffer[0] == FRAME_MARKER {
+        reader.set_position(1);
+        let end = FRAME_HEADER_SIZE + reader.read_u16::<NativeEndian>()? as usize;
+        let checksum_at = end + checksum_padding(end);
+        if checksum_at + 4 > buffer.len() {
+            return Err(Error::TruncatedPacket);
+        }
+        // checked before parsing, so that corruption is not mistaken for a malformed packet
+        let checksum = crc::crc32::checksum_ieee(&buffer[0..checksum_at]);
+        reader.set_position(checksum_at);
+        if reader.read_u32::<NativeEndian>()? != checksum {
+            return Err(Error::CorruptedPacket);
+        }
+        reader.set_position(FRAME_HEADER_SIZE);
+        let packet = Packet::read_from(&mut reader).map_err(|_| Error::MalformedPacket)?;
+        if reader.position() != end {
+            return Err(Error::MalformedPacket);
+        }
+        return Ok(packet);
+    }
+
+    let packet = Packet::read_from(&mut reader)?;
+    let checksum_at = reader.position() + checksum_padding(reader.position());
+    let checksum = crc::crc32::checksum_ieee(&reader.get_ref()[0..checksum_at]);
+    reader.set_position(checksum_at);
+    if reader.read_u32::<NativeEndian>()? != checksum {
+        return Err(Error::CorruptedPacket);
+    }
+    Ok(packet)
+}
+
+pub(crate) fn encode_packet(buffer: &mut [u8], linkno: u8, packet: &Packet) -> Result<usize, Error> {
+    let mut writer = Cursor::new(buffer);
+
+    let framed = framing(linkno);
+    if framed {
+        writer.write_u8(FRAME_MARKER)?;
+        writer.write_u16::<NativeEndian>(0)?;
+    }
+
+    packet.write_to(&mut writer)?;
+
+    if framed {
+        let end = writer.position();
+        writer.set_position(1);
+        writer.write_u16::<NativeEndian>((end - FRAME_HEADER_SIZE) as u16)?;
+        writer.set_position(end);
+    }
+
+    // Pad till offset 4, insert checksum there
+    let padding = checksum_padding(writer.position());
+    for _ in 0..padding {
+        writer.write_u8(0)?;
+    }
+
+    let checksum = crc::crc32::checksum_ieee(&writer.get_ref()[0..writer.position()]);
+    writer.write_u32::<NativeEndian>(checksum)?;
+
+    Ok(writer.position())
+}
+
 pub fn copy_work_buffer(src: *mut u32, dst: *mut u32, len: isize) {
     // fix for artiq-zynq#344
     unsafe {
@@ -45,6 +337,17 @@ pub fn copy_work_buffer(src: *mut u32, dst: *mut u32, len: isize) {
     }
 }
 
+// Packets from this length on, i.e. the payloads of flash, subkernel and DMA trace transfers,
+// are copied to the TX buffer by the DMA controller in bursts rather than word by word.
+const DMA_MIN_LENGTH: usize = 256;
+
+pub(crate) fn copy_tx_buffer(buffer: &[u8], dst: *mut u32) {
+    if buffer.len() >= DMA_MIN_LENGTH && dmac::copy(buffer, dst) {
+        return;
+    }
+    copy_work_buffer(buffer.as_ptr() as *mut u32, dst, buffer.len() as isize);
+}
+
 pub fn reset(linkno: u8) {
     let linkno = linkno as usize;
     unsafe {
@@ -89,20 +392,8 @@ pub fn recv(linkno: u8) -> Result<Option<Packet>, Error> {
     }
 
     receive(linkno, |buffer| {
-        if buffer.len() < 8 {
-            return Err(IoError::new(IoErrorKind::UnexpectedEof, "Unexpected end").into());
-        }
-
-        let mut reader = Cursor::new(buffer);
-
-        let packet = Packet::read_from(&mut reader)?;
-        let padding = (12 - (reader.position() % 8)) % 8;
-        let checksum_at = reader.position() + padding;
-        let checksum = crc::crc32::checksum_ieee(&reader.get_ref()[0..checksum_at]);
-        reader.set_position(checksum_at);
-        if reader.read_u32::<NativeEndian>()? != checksum {
-            return Err(Error::CorruptedPacket);
-        }
+        let packet = decode_packet(buffer).map_err(|e| capture(linkno, buffer, e))?;
+        trace_packet(linkno, false, buffer, &packet);
         Ok(packet)
     })
 }
@@ -127,7 +418,7 @@ where F: FnOnce(&mut [u8]) -> Result<usize, Error> {
         let ptr = DRTIOAUX_MEM[linkno].base as *mut u32;
         let mut buf: [u8; MAX_PACKET] = [0; MAX_PACKET];
         let len = f(&mut buf)?;
-        copy_work_buffer(buf.as_mut_ptr() as *mut u32, ptr, len as isize);
+        copy_tx_buffer(&buf[..len], ptr);
         (DRTIOAUX[linkno].aux_tx_length_write)(len as u16);
         (DRTIOAUX[linkno].aux_tx_write)(1);
         Ok(())
@@ -136,19 +427,23 @@ where F: FnOnce(&mut [u8]) -> Result<usize, Error> {
 
 pub fn send(linkno: u8, packet: &Packet) -> Result<(), Error> {
     transmit(linkno, |buffer| {
-        let mut writer = Cursor::new(buffer);
-
-        packet.write_to(&mut writer)?;
-
-        // Pad till offset 4, insert checksum there
-        let padding = (12 - (writer.position() % 8)) % 8;
-        for _ in 0..padding {
-            writer.write_u8(0)?;
+        let length = encode_packet(buffer, linkno, packet)?;
+        trace_packet(linkno, true, buffer, packet);
+        // a request for retransmission is not what the peer would ask to be sent again
+        if !matches!(packet, Packet::RetransmitRequest) {
+            remember(linkno, &buffer[..length]);
         }
-
-        let checksum = crc::crc32::checksum_ieee(&writer.get_ref()[0..writer.position()]);
-        writer.write_u32::<NativeEndian>(checksum)?;
-
-        Ok(writer.position())
+        Ok(length)
     })
 }
+
+pub fn retransmit(linkno: u8) {
+    let buffer = match last_sent(linkno) {
+        Some(buffer) => buffer,
+        None => return,
+    };
+    let _ = transmit(linkno, |tx_buffer| {
+        tx_buffer[..buffer.len()].copy_from_slice(&buffer);
+        Ok(buffer.len())
+    });
+}
@@ -1,15 +1,12 @@
 use core::slice;
 
-use byteorder::NativeEndian;
-use core_io::{Error as IoError, ErrorKind as IoErrorKind};
-use io::{Cursor,
-         proto::{ProtoRead, ProtoWrite}};
 use libasync::{block_async, task};
 use libboard_zynq::timer;
 use void::Void;
 
 pub use crate::drtioaux_proto::{MAX_PACKET, Packet};
-use crate::{drtioaux::{Error, copy_work_buffer, has_rx_error},
+use crate::{drtioaux::{Error, capture, copy_tx_buffer, decode_packet, encode_packet, has_rx_error, last_sent, remember,
+                       trace_packet},
             mem::mem::DRTIOAUX_MEM,
             pl::csr::DRTIOAUX};
 
@@ -34,6 +31,10 @@ fn tx_ready(linkno: usize) -> nb::Result<(), Void> {
     }
 }
 
+pub fn tx_ready_now(linkno: u8) -> bool {
+    tx_ready(linkno as usize).is_ok()
+}
+
 async fn receive<F, T>(linkno: u8, f: F) -> Result<Option<T>, Error>
 where F: FnOnce(&[u8]) -> Result<T, Error> {
     let linkidx = linkno as usize;
@@ -56,20 +57,8 @@ pub async fn recv(linkno: u8) -> Result<Option<Packet>, Error> {
     }
 
     receive(linkno, |buffer| {
-        if buffer.len() < 8 {
-            return Err(IoError::new(IoErrorKind::UnexpectedEof, "Unexpected end").into());
-        }
-
-        let mut reader = Cursor::new(buffer);
-
-        let packet = Packet::read_from(&mut reader)?;
-        let padding = (12 - (reader.position() % 8)) % 8;
-        let checksum_at = reader.position() + padding;
-        let checksum = crc::crc32::checksum_ieee(&reader.get_ref()[0..checksum_at]);
-        reader.set_position(checksum_at);
-        if reader.read_u32::<NativeEndian>()? != checksum {
-            return Err(Error::CorruptedPacket);
-        }
+        let packet = decode_packet(buffer).map_err(|e| capture(linkno, buffer, e))?;
+        trace_packet(linkno, false, buffer, &packet);
         Ok(packet)
     })
     .await
@@ -103,7 +92,7 @@ where F: FnOnce(&mut [u8]) -> Result<usize, Error> {
         let ptr = DRTIOAUX_MEM[linkno].base as *mut u32;
         let mut buf: [u8; MAX_PACKET] = [0; MAX_PACKET];
         let len = f(&mut buf)?;
-        copy_work_buffer(buf.as_mut_ptr() as *mut u32, ptr, len as isize);
+        copy_tx_buffer(&buf[..len], ptr);
         (DRTIOAUX[linkno].aux_tx_length_write)(len as u16);
         (DRTIOAUX[linkno].aux_tx_write)(1);
         Ok(())
@@ -112,20 +101,25 @@ where F: FnOnce(&mut [u8]) -> Result<usize, Error> {
 
 pub async fn send(linkno: u8, packet: &Packet) -> Result<(), Error> {
     transmit(linkno, |buffer| {
-        let mut writer = Cursor::new(buffer);
-
-        packet.write_to(&mut writer)?;
-
-        // Pad till offset 4, insert checksum there
-        let padding = (12 - (writer.position() % 8)) % 8;
-        for _ in 0..padding {
-            writer.write_u8(0)?;
+        let length = encode_packet(buffer, linkno, packet)?;
+        trace_packet(linkno, true, buffer, packet);
+        // a request for retransmission is not what the peer would ask to be sent again
+        if !matches!(packet, Packet::RetransmitRequest) {
+            remember(linkno, &buffer[..length]);
         }
-
-        let checksum = crc::crc32::checksum_ieee(&writer.get_ref()[0..writer.position()]);
-        writer.write_u32::<NativeEndian>(checksum)?;
-
-        Ok(writer.position())
+        Ok(length)
     })
     .await
 }
+
+pub async fn retransmit(linkno: u8) {
+    let buffer = match last_sent(linkno) {
+        Some(buffer) => buffer,
+        None => return,
+    };
+    let _ = transmit(linkno, |tx_buffer| {
+        tx_buffer[..buffer.len()].copy_from_slice(&buffer);
+        Ok(buffer.len())
+    })
+    .await;
+}
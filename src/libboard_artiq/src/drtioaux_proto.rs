@@ -7,18 +7,123 @@ pub const MAX_PACKET: usize = 1024;
 // maximum size of arbitrary payloads
 // used by satellite -> master CoaXPress communication and errors
 pub const CXP_PAYLOAD_MAX_SIZE: usize = /*max size*/
-    MAX_PACKET - /*packet ID*/1 - /*length*/2 - /*CRC*/4 - /*padding to keep CXP register access 4 bytes align*/1;
+    MAX_PACKET - /*packet ID*/1 - /*length*/2 - /*CRC*/4 - /*padding to keep CXP register access 4 bytes align*/1
+        - /*framing header, padded to keep alignment*/8;
 // used by satellite -> master CoaXPress roi viewer pixel data transfer
 pub const CXP_PAYLOAD_MAX_SIZE_U64: usize = CXP_PAYLOAD_MAX_SIZE / 8;
 // used by satellite -> master analyzer, subkernel exceptions
 pub const SAT_PAYLOAD_MAX_SIZE: usize = /*max size*/
-    MAX_PACKET - /*CRC*/4 - /*packet ID*/1 - /*last*/1 - /*length*/2;
+    MAX_PACKET - /*CRC*/4 - /*packet ID*/1 - /*last*/1 - /*length*/2 - /*framing header, padded*/8;
 // used by DDMA, subkernel program data (need to provide extra ID and destination)
 pub const MASTER_PAYLOAD_MAX_SIZE: usize = SAT_PAYLOAD_MAX_SIZE - /*source*/1 - /*destination*/1 - /*ID*/4;
+// reply timeout of aux transactions, in ms; the master can raise it by class of
+// operation with the `aux_timeouts` config key
+pub const AUX_TIMEOUT: u64 = 200;
+// reply timeout of an eye scan forwarded over aux, in ms; the scan keeps the destination
+// busy for up to several seconds
+pub const EYE_SCAN_TIMEOUT: u64 = 30_000;
+// satellites forwarding a fan-out image only reply once their whole subtree has been
+// updated, so the wait for the final reply scales with the target count
+pub const FLASH_FAN_OUT_TIMEOUT_PER_TARGET: u64 = 30_000;
+// likewise for a DDMA playback broadcast, acknowledged once every target started it
+pub const DMA_BROADCAST_TIMEOUT_PER_TARGET: u64 = AUX_TIMEOUT;
+// largest window of subkernel message slices a receiver grants at once
+pub const SUBKERNEL_MESSAGE_WINDOW: u8 = 8;
+// bitmask with one bit per destination
+pub const DESTINATION_MASK_SIZE: usize = 256 / 8;
+
+// longest name of a dataset buffered by a subkernel
+pub const DATASET_NAME_MAX_SIZE: usize = 64;
+
+// number of ROI engines of a (non-CXP) grabber
+pub const GRABBER_ROI_COUNT: usize = 16;
+// probes read by a single MonitorBatchRequest
+pub const MONITOR_BATCH_SIZE: usize = 64;
+// input events read by a single RtioInputBatchRequest
+pub const RTIO_INPUT_BATCH_SIZE: usize = 64;
+// what ended an input batch: it is full and more events may follow, the timeout was reached,
+// the input FIFO overflowed, the RTIO core is in use by a subkernel or DMA playback, or the
+// satellite stopped waiting before the timeout and is to be polled again
+pub const RTIO_INPUT_BATCH_FULL: u8 = 0;
+pub const RTIO_INPUT_BATCH_TIMEOUT: u8 = 1;
+pub const RTIO_INPUT_BATCH_OVERFLOW: u8 = 2;
+pub const RTIO_INPUT_BATCH_BUSY: u8 = 3;
+pub const RTIO_INPUT_BATCH_PENDING: u8 = 4;
+// not sent over aux: the link does not support batches, the events are read one by one
+pub const RTIO_INPUT_BATCH_UNSUPPORTED: u8 = 5;
+// longest wait of the satellite for an input event within a batch request, in RTIO machine units
+pub const RTIO_INPUT_BATCH_SLICE: u64 = 10_000_000;
+// I/O expanders reported by CoreMgmtIoExpanderStatusReply (Kasli-SoC has two)
+pub const IO_EXPANDER_COUNT: usize = 2;
+// leading bytes kept of each aux packet that could not be decoded
+pub const AUX_CAPTURE_SIZE: usize = 64;
+// bytes per aux trace entry in CoreMgmtAuxTraceReply
+pub const AUX_TRACE_ENTRY_SIZE: usize = 12;
+// link transitions of a repeater kept by the satellite and reported by RepeaterStatsReply
+pub const REPEATER_HISTORY_SIZE: usize = 8;
+// repeater states in RepeaterStatsReply: link down, pinging the satellite behind it,
+// up, or brought down by a failed version check, TSC sync, routing table load or ping
+pub const REPEATER_DOWN: u8 = 0;
+pub const REPEATER_PINGING: u8 = 1;
+pub const REPEATER_UP: u8 = 2;
+pub const REPEATER_FAILED: u8 = 3;
+
+// version of the aux protocol, exchanged when a link comes up; links to peers
+// with a different version are not brought up
+pub const PROTOCOL_VERSION: u16 = 5;
+
+// optional capabilities advertised along with the protocol version
+pub const FEATURE_ROUTING: u32 = 1 << 0;
+pub const FEATURE_CXP_GRABBER: u32 = 1 << 1;
+pub const FEATURE_FLASH_FAN_OUT: u32 = 1 << 2;
+pub const FEATURE_EYE_SCAN: u32 = 1 << 3;
+// length-framed packets and RetransmitRequest, see drtioaux
+pub const FEATURE_FRAMING: u32 = 1 << 4;
+// RtioInputBatchRequest/Reply
+pub const FEATURE_INPUT_BATCH: u32 = 1 << 6;
+// CoreMgmtConfigListRequest
+pub const FEATURE_CONFIG_LIST: u32 = 1 << 8;
+// StorageStatsRequest/Reply and StorageSpaceRequest/Reply
+pub const FEATURE_STORAGE_STATS: u32 = 1 << 9;
+// SubkernelBarrierWithdraw
+pub const FEATURE_BARRIER_WITHDRAW: u32 = 1 << 11;
+// LatencyProbe/LatencyProbeReply
+pub const FEATURE_LATENCY_PROBE: u32 = 1 << 12;
+// RouterStatsRequest/Reply
+pub const FEATURE_ROUTER_STATS: u32 = 1 << 13;
+// RepeaterStatsRequest/Reply
+pub const FEATURE_REPEATER_STATS: u32 = 1 << 14;
+// CoreMgmtLogStatsRequest/Reply
+pub const FEATURE_LOG_STATS: u32 = 1 << 15;
+
+pub fn local_features() -> u32 {
+    let mut features = FEATURE_FLASH_FAN_OUT
+        | FEATURE_FRAMING
+        | FEATURE_INPUT_BATCH
+        | FEATURE_CONFIG_LIST
+        | FEATURE_STORAGE_STATS
+        | FEATURE_BARRIER_WITHDRAW
+        | FEATURE_LATENCY_PROBE
+        | FEATURE_ROUTER_STATS
+        | FEATURE_REPEATER_STATS
+        | FEATURE_LOG_STATS;
+    if cfg!(has_drtio_routing) {
+        features |= FEATURE_ROUTING;
+    }
+    if cfg!(has_cxp_grabber) {
+        features |= FEATURE_CXP_GRABBER;
+    }
+    if cfg!(has_drtio_eyescan) {
+        features |= FEATURE_EYE_SCAN;
+    }
+    features
+}
 
 #[derive(Debug)]
 pub enum Error {
     UnknownPacket(u8),
+    // a length or enumerated field out of range
+    InvalidField,
     Io(IoError),
 }
 
@@ -50,6 +155,13 @@ impl From<u8> for PayloadStatus {
 }
 
 impl PayloadStatus {
+    fn read_from<R: ProtoRead>(reader: &mut R) -> Result<PayloadStatus, Error> {
+        match reader.read_u8()? {
+            status @ 0..=3 => Ok(PayloadStatus::from(status)),
+            _ => Err(Error::InvalidField),
+        }
+    }
+
     pub fn is_first(self) -> bool {
         self == PayloadStatus::First || self == PayloadStatus::FirstAndLast
     }
@@ -68,19 +180,47 @@ impl PayloadStatus {
     }
 }
 
+// packets are serviced in order of priority class by the satellite router,
+// so that short control messages are not stuck behind bulk transfers
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum PacketPriority {
+    Control,
+    Bulk,
+}
+
 #[derive(PartialEq, Debug)]
 pub enum Packet {
     EchoRequest,
     EchoReply,
+    // asks the link peer to send its last packet again, after receiving it damaged
+    RetransmitRequest,
     ResetRequest,
     ResetAck,
     TSCAck,
+    VersionRequest {
+        version: u16,
+        features: u32,
+    },
+    VersionReply {
+        version: u16,
+        features: u32,
+    },
+    SessionRequest {
+        destination: u8,
+        epoch: u32,
+    },
+    SessionReply {
+        resumed: bool,
+    },
 
     DestinationStatusRequest {
         destination: u8,
     },
     DestinationDownReply,
-    DestinationOkReply,
+    DestinationOkReply {
+        buffer_space: u16,
+        min_buffer_space: u16,
+    },
     DestinationSequenceErrorReply {
         channel: u16,
     },
@@ -90,6 +230,12 @@ pub enum Packet {
     DestinationBusyReply {
         channel: u16,
     },
+    TimestampRequest {
+        destination: u8,
+    },
+    TimestampReply {
+        timestamp: u64,
+    },
 
     RoutingSetPath {
         destination: u8,
@@ -100,6 +246,20 @@ pub enum Packet {
     },
     RoutingAck,
 
+    EyeScanRequest {
+        destination: u8,
+        link: u8,
+        prescale: u8,
+    },
+    EyeScanContinue {
+        destination: u8,
+    },
+    EyeScanReply {
+        last: bool,
+        length: u16,
+        data: [u8; SAT_PAYLOAD_MAX_SIZE],
+    },
+
     MonitorRequest {
         destination: u8,
         channel: u16,
@@ -108,6 +268,28 @@ pub enum Packet {
     MonitorReply {
         value: u64,
     },
+    MonitorBatchRequest {
+        destination: u8,
+        count: u8,
+        channels: [u16; MONITOR_BATCH_SIZE],
+        probes: [u8; MONITOR_BATCH_SIZE],
+    },
+    MonitorBatchReply {
+        count: u8,
+        values: [u64; MONITOR_BATCH_SIZE],
+    },
+    RtioInputBatchRequest {
+        destination: u8,
+        channel: u32,
+        timeout: u64,
+        max_count: u8,
+    },
+    RtioInputBatchReply {
+        status: u8,
+        count: u8,
+        timestamps: [u64; RTIO_INPUT_BATCH_SIZE],
+        data: [u32; RTIO_INPUT_BATCH_SIZE],
+    },
     InjectionRequest {
         destination: u8,
         channel: u16,
@@ -162,6 +344,27 @@ pub enum Packet {
         address: u8,
         mask: u8,
     },
+    I2cWriteBlockRequest {
+        destination: u8,
+        busno: u8,
+        length: u16,
+        data: [u8; MASTER_PAYLOAD_MAX_SIZE],
+    },
+    I2cWriteBlockReply {
+        succeeded: bool,
+        acked: u16,
+    },
+    I2cReadBlockRequest {
+        destination: u8,
+        busno: u8,
+        length: u16,
+        ack: bool,
+    },
+    I2cReadBlockReply {
+        succeeded: bool,
+        length: u16,
+        data: [u8; SAT_PAYLOAD_MAX_SIZE],
+    },
 
     SpiSetConfigRequest {
         destination: u8,
@@ -204,6 +407,23 @@ pub enum Packet {
         length: u16,
         data: [u8; SAT_PAYLOAD_MAX_SIZE],
     },
+    AnalyzerArmRequest {
+        destination: u8,
+        arm: bool,
+        synchronized: bool,
+    },
+    AnalyzerArmReply {
+        succeeded: bool,
+    },
+    GrabberRoiRequest {
+        destination: u8,
+        grabber: u8,
+    },
+    GrabberRoiReply {
+        succeeded: bool,
+        frame: u32,
+        counts: [u32; GRABBER_ROI_COUNT],
+    },
 
     DmaAddTraceRequest {
         source: u8,
@@ -245,6 +465,29 @@ pub enum Packet {
         error: u8,
         channel: u32,
         timestamp: u64,
+        duration: u64,
+        events: u32,
+        min_slack: i64,
+    },
+    DmaPlaybackBroadcastRequest {
+        source: u8,
+        destination: u8,
+        id: u32,
+        timestamp: u64,
+        targets: [u8; DESTINATION_MASK_SIZE],
+    },
+    DmaPlaybackBroadcastReply {
+        succeeded: [u8; DESTINATION_MASK_SIZE],
+    },
+    DmaStatRequest {
+        source: u8,
+        destination: u8,
+        id: u32,
+    },
+    DmaStatReply {
+        found: bool,
+        size: u32,
+        events: u32,
     },
 
     SubkernelAddDataRequest {
@@ -263,6 +506,8 @@ pub enum Packet {
         id: u32,
         run: bool,
         timestamp: u64,
+        // timestamp is an offset from the destination counter when the request arrives
+        relative: bool,
     },
     SubkernelLoadRunReply {
         destination: u8,
@@ -277,9 +522,25 @@ pub enum Packet {
     SubkernelExceptionRequest {
         source: u8,
         destination: u8,
+        seq: u16,
     },
     SubkernelException {
         destination: u8,
+        seq: u16,
+        last: bool,
+        length: u16,
+        data: [u8; MASTER_PAYLOAD_MAX_SIZE],
+    },
+    SubkernelDatasetRequest {
+        destination: u8,
+        id: u32,
+        seq: u16,
+        name_len: u8,
+        name: [u8; DATASET_NAME_MAX_SIZE],
+    },
+    SubkernelDatasetReply {
+        seq: u16,
+        found: bool,
         last: bool,
         length: u16,
         data: [u8; MASTER_PAYLOAD_MAX_SIZE],
@@ -294,11 +555,35 @@ pub enum Packet {
     },
     SubkernelMessageAck {
         destination: u8,
+        credits: u8,
+    },
+    SubkernelMessageCredit {
+        source: u8,
+        destination: u8,
+        credits: u8,
+    },
+    SubkernelBarrierArrive {
+        source: u8,
+        destination: u8,
+        id: u32,
+        participants: [u8; DESTINATION_MASK_SIZE],
+    },
+    // a participant that timed out takes back its arrival
+    SubkernelBarrierWithdraw {
+        source: u8,
+        destination: u8,
+        id: u32,
+    },
+    SubkernelBarrierRelease {
+        destination: u8,
+        id: u32,
+        succeeded: bool,
     },
 
     CoreMgmtGetLogRequest {
         destination: u8,
         clear: bool,
+        max_level: u8,
     },
     CoreMgmtClearLogRequest {
         destination: u8,
@@ -311,6 +596,10 @@ pub enum Packet {
     CoreMgmtConfigReadContinue {
         destination: u8,
     },
+    // answered like a read of the newline-separated key names
+    CoreMgmtConfigListRequest {
+        destination: u8,
+    },
     CoreMgmtConfigWriteRequest {
         destination: u8,
         last: bool,
@@ -334,6 +623,7 @@ pub enum Packet {
     CoreMgmtFlashRequest {
         destination: u8,
         payload_length: u32,
+        verify_only: bool,
     },
     CoreMgmtFlashAddDataRequest {
         destination: u8,
@@ -398,6 +688,183 @@ pub enum Packet {
         height: u16,
         pixel_code: u16,
     },
+    CXPEventRequest {
+        destination: u8,
+    },
+    CXPEventReply {
+        available: bool,
+        namespace: u8,
+        id: u16,
+        timestamp: u64,
+    },
+    CoreMgmtFlashFanOutRequest {
+        destination: u8,
+        payload_length: u32,
+        targets: [u8; DESTINATION_MASK_SIZE],
+    },
+    CoreMgmtFlashFanOutReply {
+        succeeded: [u8; DESTINATION_MASK_SIZE],
+    },
+    CoreMgmtSetSedSpreadRequest {
+        destination: u8,
+        enable: bool,
+    },
+    CoreMgmtCriConRequest {
+        destination: u8,
+        reset: bool,
+    },
+    CoreMgmtCriConReply {
+        selected: u8,
+        kernel_running: bool,
+        dma_playing: bool,
+    },
+    CoreMgmtKernelCacheRequest {
+        destination: u8,
+        clear: bool,
+    },
+    CoreMgmtKernelCacheReply {
+        entries: u32,
+        size: u32,
+        quota: u32,
+        evictions: u32,
+        rejections: u32,
+    },
+
+    WallClockSet {
+        destination: u8,
+        time_us: u64,
+    },
+    WallClockAck,
+
+    CoreMgmtLogStatsRequest {
+        destination: u8,
+    },
+    CoreMgmtLogStatsReply {
+        dropped_bytes: u64,
+        dropped_messages: u32,
+    },
+
+    CoreMgmtAuxCaptureRequest {
+        destination: u8,
+        index: u8,
+    },
+    CoreMgmtAuxCaptureReply {
+        count: u8,
+        linkno: u8,
+        timestamp_ms: u64,
+        kind: u8,
+        data: [u8; AUX_CAPTURE_SIZE],
+    },
+    CoreMgmtClockStatusRequest {
+        destination: u8,
+    },
+    CoreMgmtClockStatusReply {
+        monitored: bool,
+        locked: bool,
+        unlocks: u32,
+        recoveries: u32,
+        holdover_monitored: bool,
+        los: bool,
+        lol: bool,
+        holdover: bool,
+        los_events: u32,
+        lol_events: u32,
+        holdover_events: u32,
+        input_switches: u32,
+    },
+    CoreMgmtAuxTraceEnable {
+        destination: u8,
+        enable: bool,
+    },
+    CoreMgmtAuxTraceRequest {
+        destination: u8,
+    },
+    CoreMgmtAuxTraceContinue {
+        destination: u8,
+    },
+    CoreMgmtAuxTraceReply {
+        last: bool,
+        length: u16,
+        data: [u8; SAT_PAYLOAD_MAX_SIZE],
+    },
+
+    TscDriftProbe {
+        destination: u8,
+        master_time: u64,
+    },
+    TscDriftReply {
+        offset: i64,
+        drift_ppb: i32,
+    },
+    LatencyProbe {
+        destination: u8,
+        timestamp: u64,
+    },
+    LatencyProbeReply {
+        timestamp: u64,
+    },
+    RouterStatsRequest {
+        destination: u8,
+    },
+    RouterStatsReply {
+        forwarded: u32,
+        queued: u16,
+        max_queued: u16,
+        dropped_full: u32,
+        dropped_aged: u32,
+    },
+    RepeaterStatsRequest {
+        destination: u8,
+        repno: u8,
+    },
+    RepeaterStatsReply {
+        repeaters: u8,
+        state: u8,
+        up_transitions: u32,
+        down_transitions: u32,
+        aux_send_failures: u32,
+        forward_timeouts: u32,
+        history_len: u8,
+        history_states: [u8; REPEATER_HISTORY_SIZE],
+        history_age_ms: [u32; REPEATER_HISTORY_SIZE],
+    },
+    StorageStatsRequest {
+        destination: u8,
+        length: u16,
+        key: [u8; MASTER_PAYLOAD_MAX_SIZE],
+    },
+    StorageStatsReply {
+        set: bool,
+        size: u32,
+    },
+    StorageSpaceRequest {
+        destination: u8,
+    },
+    // total and free bytes of the filesystem holding the config
+    StorageSpaceReply {
+        known: bool,
+        total: u64,
+        free: u64,
+    },
+    CoreMgmtIoExpanderStatusRequest {
+        destination: u8,
+    },
+    CoreMgmtIoExpanderStatusReply {
+        count: u8,
+        failing: [bool; IO_EXPANDER_COUNT],
+        errors: [u32; IO_EXPANDER_COUNT],
+        reinits: [u32; IO_EXPANDER_COUNT],
+    },
+}
+
+// Reads a variable-length payload into the start of its fixed-size buffer,
+// refusing a length that does not fit instead of panicking on it.
+fn read_payload<R: ProtoRead>(reader: &mut R, buffer: &mut [u8], length: usize) -> Result<(), Error> {
+    if length > buffer.len() {
+        return Err(Error::InvalidField);
+    }
+    reader.read_exact(&mut buffer[0..length])?;
+    Ok(())
 }
 
 impl Packet {
@@ -408,12 +875,81 @@ impl Packet {
             0x02 => Packet::ResetRequest,
             0x03 => Packet::ResetAck,
             0x04 => Packet::TSCAck,
+            0x05 => Packet::VersionRequest {
+                version: reader.read_u16::<NativeEndian>()?,
+                features: reader.read_u32::<NativeEndian>()?,
+            },
+            0x06 => Packet::VersionReply {
+                version: reader.read_u16::<NativeEndian>()?,
+                features: reader.read_u32::<NativeEndian>()?,
+            },
+            0x07 => Packet::SessionRequest {
+                destination: reader.read_u8()?,
+                epoch: reader.read_u32::<NativeEndian>()?,
+            },
+            0x08 => Packet::SessionReply {
+                resumed: reader.read_bool()?,
+            },
+            0x09 => Packet::CoreMgmtAuxTraceEnable {
+                destination: reader.read_u8()?,
+                enable: reader.read_bool()?,
+            },
+            0x0a => Packet::CoreMgmtAuxTraceRequest {
+                destination: reader.read_u8()?,
+            },
+            0x0b => Packet::CoreMgmtAuxTraceContinue {
+                destination: reader.read_u8()?,
+            },
+            0x0c => {
+                let last = reader.read_bool()?;
+                let length = reader.read_u16::<NativeEndian>()?;
+                let mut data: [u8; SAT_PAYLOAD_MAX_SIZE] = [0; SAT_PAYLOAD_MAX_SIZE];
+                read_payload(reader, &mut data, length as usize)?;
+                Packet::CoreMgmtAuxTraceReply {
+                    last: last,
+                    length: length,
+                    data: data,
+                }
+            }
+            0x0d => Packet::LatencyProbe {
+                destination: reader.read_u8()?,
+                timestamp: reader.read_u64::<NativeEndian>()?,
+            },
+            0x0e => Packet::LatencyProbeReply {
+                timestamp: reader.read_u64::<NativeEndian>()?,
+            },
+            0x0f => Packet::CoreMgmtIoExpanderStatusRequest {
+                destination: reader.read_u8()?,
+            },
+            0x10 => {
+                let count = reader.read_u8()?;
+                if count as usize > IO_EXPANDER_COUNT {
+                    return Err(Error::InvalidField);
+                }
+                let mut failing = [false; IO_EXPANDER_COUNT];
+                let mut errors = [0; IO_EXPANDER_COUNT];
+                let mut reinits = [0; IO_EXPANDER_COUNT];
+                for i in 0..count as usize {
+                    failing[i] = reader.read_bool()?;
+                    errors[i] = reader.read_u32::<NativeEndian>()?;
+                    reinits[i] = reader.read_u32::<NativeEndian>()?;
+                }
+                Packet::CoreMgmtIoExpanderStatusReply {
+                    count: count,
+                    failing: failing,
+                    errors: errors,
+                    reinits: reinits,
+                }
+            }
 
             0x20 => Packet::DestinationStatusRequest {
                 destination: reader.read_u8()?,
             },
             0x21 => Packet::DestinationDownReply,
-            0x22 => Packet::DestinationOkReply,
+            0x22 => Packet::DestinationOkReply {
+                buffer_space: reader.read_u16::<NativeEndian>()?,
+                min_buffer_space: reader.read_u16::<NativeEndian>()?,
+            },
             0x23 => Packet::DestinationSequenceErrorReply {
                 channel: reader.read_u16::<NativeEndian>()?,
             },
@@ -423,6 +959,12 @@ impl Packet {
             0x25 => Packet::DestinationBusyReply {
                 channel: reader.read_u16::<NativeEndian>()?,
             },
+            0x26 => Packet::TimestampRequest {
+                destination: reader.read_u8()?,
+            },
+            0x27 => Packet::TimestampReply {
+                timestamp: reader.read_u64::<NativeEndian>()?,
+            },
 
             0x30 => {
                 let destination = reader.read_u8()?;
@@ -438,6 +980,26 @@ impl Packet {
             },
             0x32 => Packet::RoutingAck,
 
+            0x33 => Packet::EyeScanRequest {
+                destination: reader.read_u8()?,
+                link: reader.read_u8()?,
+                prescale: reader.read_u8()?,
+            },
+            0x34 => Packet::EyeScanContinue {
+                destination: reader.read_u8()?,
+            },
+            0x35 => {
+                let last = reader.read_bool()?;
+                let length = reader.read_u16::<NativeEndian>()?;
+                let mut data: [u8; SAT_PAYLOAD_MAX_SIZE] = [0; SAT_PAYLOAD_MAX_SIZE];
+                read_payload(reader, &mut data, length as usize)?;
+                Packet::EyeScanReply {
+                    last: last,
+                    length: length,
+                    data: data,
+                }
+            }
+
             0x40 => Packet::MonitorRequest {
                 destination: reader.read_u8()?,
                 channel: reader.read_u16::<NativeEndian>()?,
@@ -446,6 +1008,64 @@ impl Packet {
             0x41 => Packet::MonitorReply {
                 value: reader.read_u64::<NativeEndian>()?,
             },
+            0x42 => {
+                let destination = reader.read_u8()?;
+                let count = reader.read_u8()?;
+                if count as usize > MONITOR_BATCH_SIZE {
+                    return Err(Error::InvalidField);
+                }
+                let mut channels: [u16; MONITOR_BATCH_SIZE] = [0; MONITOR_BATCH_SIZE];
+                let mut probes: [u8; MONITOR_BATCH_SIZE] = [0; MONITOR_BATCH_SIZE];
+                for i in 0..count as usize {
+                    channels[i] = reader.read_u16::<NativeEndian>()?;
+                    probes[i] = reader.read_u8()?;
+                }
+                Packet::MonitorBatchRequest {
+                    destination: destination,
+                    count: count,
+                    channels: channels,
+                    probes: probes,
+                }
+            }
+            0x43 => {
+                let count = reader.read_u8()?;
+                if count as usize > MONITOR_BATCH_SIZE {
+                    return Err(Error::InvalidField);
+                }
+                let mut values: [u64; MONITOR_BATCH_SIZE] = [0; MONITOR_BATCH_SIZE];
+                for i in 0..count as usize {
+                    values[i] = reader.read_u64::<NativeEndian>()?;
+                }
+                Packet::MonitorBatchReply {
+                    count: count,
+                    values: values,
+                }
+            }
+            0x44 => Packet::RtioInputBatchRequest {
+                destination: reader.read_u8()?,
+                channel: reader.read_u32::<NativeEndian>()?,
+                timeout: reader.read_u64::<NativeEndian>()?,
+                max_count: reader.read_u8()?,
+            },
+            0x45 => {
+                let status = reader.read_u8()?;
+                let count = reader.read_u8()?;
+                if count as usize > RTIO_INPUT_BATCH_SIZE {
+                    return Err(Error::InvalidField);
+                }
+                let mut timestamps: [u64; RTIO_INPUT_BATCH_SIZE] = [0; RTIO_INPUT_BATCH_SIZE];
+                let mut data: [u32; RTIO_INPUT_BATCH_SIZE] = [0; RTIO_INPUT_BATCH_SIZE];
+                for i in 0..count as usize {
+                    timestamps[i] = reader.read_u64::<NativeEndian>()?;
+                    data[i] = reader.read_u32::<NativeEndian>()?;
+                }
+                Packet::RtioInputBatchReply {
+                    status: status,
+                    count: count,
+                    timestamps: timestamps,
+                    data: data,
+                }
+            }
             0x50 => Packet::InjectionRequest {
                 destination: reader.read_u8()?,
                 channel: reader.read_u16::<NativeEndian>()?,
@@ -460,6 +1080,108 @@ impl Packet {
             0x52 => Packet::InjectionStatusReply {
                 value: reader.read_u8()?,
             },
+            0x53 => Packet::CXPEventRequest {
+                destination: reader.read_u8()?,
+            },
+            0x54 => Packet::CXPEventReply {
+                available: reader.read_bool()?,
+                namespace: reader.read_u8()?,
+                id: reader.read_u16::<NativeEndian>()?,
+                timestamp: reader.read_u64::<NativeEndian>()?,
+            },
+            0x55 => {
+                let destination = reader.read_u8()?;
+                let id = reader.read_u32::<NativeEndian>()?;
+                let seq = reader.read_u16::<NativeEndian>()?;
+                let name_len = reader.read_u8()?;
+                let mut name: [u8; DATASET_NAME_MAX_SIZE] = [0; DATASET_NAME_MAX_SIZE];
+                read_payload(reader, &mut name, name_len as usize)?;
+                Packet::SubkernelDatasetRequest {
+                    destination: destination,
+                    id: id,
+                    seq: seq,
+                    name_len: name_len,
+                    name: name,
+                }
+            }
+            0x56 => {
+                let seq = reader.read_u16::<NativeEndian>()?;
+                let found = reader.read_bool()?;
+                let last = reader.read_bool()?;
+                let length = reader.read_u16::<NativeEndian>()?;
+                let mut data: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
+                read_payload(reader, &mut data, length as usize)?;
+                Packet::SubkernelDatasetReply {
+                    seq: seq,
+                    found: found,
+                    last: last,
+                    length: length,
+                    data: data,
+                }
+            }
+            0x57 => Packet::RouterStatsRequest {
+                destination: reader.read_u8()?,
+            },
+            0x58 => Packet::RouterStatsReply {
+                forwarded: reader.read_u32::<NativeEndian>()?,
+                queued: reader.read_u16::<NativeEndian>()?,
+                max_queued: reader.read_u16::<NativeEndian>()?,
+                dropped_full: reader.read_u32::<NativeEndian>()?,
+                dropped_aged: reader.read_u32::<NativeEndian>()?,
+            },
+            0x59 => Packet::RepeaterStatsRequest {
+                destination: reader.read_u8()?,
+                repno: reader.read_u8()?,
+            },
+            0x5a => {
+                let repeaters = reader.read_u8()?;
+                let state = reader.read_u8()?;
+                let up_transitions = reader.read_u32::<NativeEndian>()?;
+                let down_transitions = reader.read_u32::<NativeEndian>()?;
+                let aux_send_failures = reader.read_u32::<NativeEndian>()?;
+                let forward_timeouts = reader.read_u32::<NativeEndian>()?;
+                let history_len = reader.read_u8()?;
+                let mut history_states: [u8; REPEATER_HISTORY_SIZE] = [0; REPEATER_HISTORY_SIZE];
+                reader.read_exact(&mut history_states)?;
+                let mut history_age_ms: [u32; REPEATER_HISTORY_SIZE] = [0; REPEATER_HISTORY_SIZE];
+                for age in history_age_ms.iter_mut() {
+                    *age = reader.read_u32::<NativeEndian>()?;
+                }
+                Packet::RepeaterStatsReply {
+                    repeaters: repeaters,
+                    state: state,
+                    up_transitions: up_transitions,
+                    down_transitions: down_transitions,
+                    aux_send_failures: aux_send_failures,
+                    forward_timeouts: forward_timeouts,
+                    history_len: history_len,
+                    history_states: history_states,
+                    history_age_ms: history_age_ms,
+                }
+            }
+            0x5b => {
+                let destination = reader.read_u8()?;
+                let length = reader.read_u16::<NativeEndian>()?;
+                let mut key: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
+                read_payload(reader, &mut key, length as usize)?;
+                Packet::StorageStatsRequest {
+                    destination: destination,
+                    length: length,
+                    key: key,
+                }
+            }
+            0x5c => Packet::StorageStatsReply {
+                set: reader.read_bool()?,
+                size: reader.read_u32::<NativeEndian>()?,
+            },
+            0x5d => Packet::StorageSpaceRequest {
+                destination: reader.read_u8()?,
+            },
+            0x5e => Packet::StorageSpaceReply {
+                known: reader.read_bool()?,
+                total: reader.read_u64::<NativeEndian>()?,
+                free: reader.read_u64::<NativeEndian>()?,
+            },
 
             0x80 => Packet::I2cStartRequest {
                 destination: reader.read_u8()?,
@@ -500,6 +1222,40 @@ impl Packet {
                 address: reader.read_u8()?,
                 mask: reader.read_u8()?,
             },
+            0x89 => {
+                let destination = reader.read_u8()?;
+                let busno = reader.read_u8()?;
+                let length = reader.read_u16::<NativeEndian>()?;
+                let mut data: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
+                read_payload(reader, &mut data, length as usize)?;
+                Packet::I2cWriteBlockRequest {
+                    destination: destination,
+                    busno: busno,
+                    length: length,
+                    data: data,
+                }
+            }
+            0x8a => Packet::I2cWriteBlockReply {
+                succeeded: reader.read_bool()?,
+                acked: reader.read_u16::<NativeEndian>()?,
+            },
+            0x8b => Packet::I2cReadBlockRequest {
+                destination: reader.read_u8()?,
+                busno: reader.read_u8()?,
+                length: reader.read_u16::<NativeEndian>()?,
+                ack: reader.read_bool()?,
+            },
+            0x8c => {
+                let succeeded = reader.read_bool()?;
+                let length = reader.read_u16::<NativeEndian>()?;
+                let mut data: [u8; SAT_PAYLOAD_MAX_SIZE] = [0; SAT_PAYLOAD_MAX_SIZE];
+                read_payload(reader, &mut data, length as usize)?;
+                Packet::I2cReadBlockReply {
+                    succeeded: succeeded,
+                    length: length,
+                    data: data,
+                }
+            }
 
             0x90 => Packet::SpiSetConfigRequest {
                 destination: reader.read_u8()?,
@@ -542,27 +1298,52 @@ impl Packet {
                 let last = reader.read_bool()?;
                 let length = reader.read_u16::<NativeEndian>()?;
                 let mut data: [u8; SAT_PAYLOAD_MAX_SIZE] = [0; SAT_PAYLOAD_MAX_SIZE];
-                reader.read_exact(&mut data[0..length as usize])?;
+                read_payload(reader, &mut data, length as usize)?;
                 Packet::AnalyzerData {
                     last: last,
                     length: length,
                     data: data,
                 }
             }
+            0xa4 => Packet::GrabberRoiRequest {
+                destination: reader.read_u8()?,
+                grabber: reader.read_u8()?,
+            },
+            0xa5 => {
+                let succeeded = reader.read_bool()?;
+                let frame = reader.read_u32::<NativeEndian>()?;
+                let mut counts: [u32; GRABBER_ROI_COUNT] = [0; GRABBER_ROI_COUNT];
+                for count in counts.iter_mut() {
+                    *count = reader.read_u32::<NativeEndian>()?;
+                }
+                Packet::GrabberRoiReply {
+                    succeeded: succeeded,
+                    frame: frame,
+                    counts: counts,
+                }
+            }
+            0xa6 => Packet::AnalyzerArmRequest {
+                destination: reader.read_u8()?,
+                arm: reader.read_bool()?,
+                synchronized: reader.read_bool()?,
+            },
+            0xa7 => Packet::AnalyzerArmReply {
+                succeeded: reader.read_bool()?,
+            },
 
             0xb0 => {
                 let source = reader.read_u8()?;
                 let destination = reader.read_u8()?;
                 let id = reader.read_u32::<NativeEndian>()?;
-                let status = reader.read_u8()?;
+                let status = PayloadStatus::read_from(reader)?;
                 let length = reader.read_u16::<NativeEndian>()?;
                 let mut trace: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
-                reader.read_exact(&mut trace[0..length as usize])?;
+                read_payload(reader, &mut trace, length as usize)?;
                 Packet::DmaAddTraceRequest {
                     source: source,
                     destination: destination,
                     id: id,
-                    status: PayloadStatus::from(status),
+                    status: status,
                     length: length as u16,
                     trace: trace,
                 }
@@ -599,15 +1380,48 @@ impl Packet {
                 error: reader.read_u8()?,
                 channel: reader.read_u32::<NativeEndian>()?,
                 timestamp: reader.read_u64::<NativeEndian>()?,
+                duration: reader.read_u64::<NativeEndian>()?,
+                events: reader.read_u32::<NativeEndian>()?,
+                min_slack: reader.read_u64::<NativeEndian>()? as i64,
+            },
+            0xb7 => {
+                let source = reader.read_u8()?;
+                let destination = reader.read_u8()?;
+                let id = reader.read_u32::<NativeEndian>()?;
+                let timestamp = reader.read_u64::<NativeEndian>()?;
+                let mut targets: [u8; DESTINATION_MASK_SIZE] = [0; DESTINATION_MASK_SIZE];
+                reader.read_exact(&mut targets)?;
+                Packet::DmaPlaybackBroadcastRequest {
+                    source: source,
+                    destination: destination,
+                    id: id,
+                    timestamp: timestamp,
+                    targets: targets,
+                }
+            }
+            0xb8 => {
+                let mut succeeded: [u8; DESTINATION_MASK_SIZE] = [0; DESTINATION_MASK_SIZE];
+                reader.read_exact(&mut succeeded)?;
+                Packet::DmaPlaybackBroadcastReply { succeeded: succeeded }
+            }
+            0xb9 => Packet::DmaStatRequest {
+                source: reader.read_u8()?,
+                destination: reader.read_u8()?,
+                id: reader.read_u32::<NativeEndian>()?,
+            },
+            0xba => Packet::DmaStatReply {
+                found: reader.read_bool()?,
+                size: reader.read_u32::<NativeEndian>()?,
+                events: reader.read_u32::<NativeEndian>()?,
             },
 
             0xc0 => {
                 let destination = reader.read_u8()?;
                 let id = reader.read_u32::<NativeEndian>()?;
-                let status = PayloadStatus::from(reader.read_u8()?);
+                let status = PayloadStatus::read_from(reader)?;
                 let length = reader.read_u16::<NativeEndian>()?;
                 let mut data: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
-                reader.read_exact(&mut data[0..length as usize])?;
+                read_payload(reader, &mut data, length as usize)?;
                 Packet::SubkernelAddDataRequest {
                     destination: destination,
                     id: id,
@@ -625,6 +1439,7 @@ impl Packet {
                 id: reader.read_u32::<NativeEndian>()?,
                 run: reader.read_bool()?,
                 timestamp: reader.read_u64::<NativeEndian>()?,
+                relative: reader.read_bool()?,
             },
             0xc5 => Packet::SubkernelLoadRunReply {
                 destination: reader.read_u8()?,
@@ -639,15 +1454,18 @@ impl Packet {
             0xc9 => Packet::SubkernelExceptionRequest {
                 source: reader.read_u8()?,
                 destination: reader.read_u8()?,
+                seq: reader.read_u16::<NativeEndian>()?,
             },
             0xca => {
                 let destination = reader.read_u8()?;
+                let seq = reader.read_u16::<NativeEndian>()?;
                 let last = reader.read_bool()?;
                 let length = reader.read_u16::<NativeEndian>()?;
                 let mut data: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
-                reader.read_exact(&mut data[0..length as usize])?;
+                read_payload(reader, &mut data, length as usize)?;
                 Packet::SubkernelException {
                     destination: destination,
+                    seq: seq,
                     last: last,
                     length: length,
                     data: data,
@@ -657,26 +1475,56 @@ impl Packet {
                 let source = reader.read_u8()?;
                 let destination = reader.read_u8()?;
                 let id = reader.read_u32::<NativeEndian>()?;
-                let status = reader.read_u8()?;
+                let status = PayloadStatus::read_from(reader)?;
                 let length = reader.read_u16::<NativeEndian>()?;
                 let mut data: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
-                reader.read_exact(&mut data[0..length as usize])?;
+                read_payload(reader, &mut data, length as usize)?;
                 Packet::SubkernelMessage {
                     source: source,
                     destination: destination,
                     id: id,
-                    status: PayloadStatus::from(status),
+                    status: status,
                     length: length as u16,
                     data: data,
                 }
             }
             0xcc => Packet::SubkernelMessageAck {
                 destination: reader.read_u8()?,
+                credits: reader.read_u8()?,
+            },
+            0xcd => Packet::SubkernelMessageCredit {
+                source: reader.read_u8()?,
+                destination: reader.read_u8()?,
+                credits: reader.read_u8()?,
+            },
+            0xce => {
+                let source = reader.read_u8()?;
+                let destination = reader.read_u8()?;
+                let id = reader.read_u32::<NativeEndian>()?;
+                let mut participants: [u8; DESTINATION_MASK_SIZE] = [0; DESTINATION_MASK_SIZE];
+                reader.read_exact(&mut participants)?;
+                Packet::SubkernelBarrierArrive {
+                    source: source,
+                    destination: destination,
+                    id: id,
+                    participants: participants,
+                }
+            }
+            0xc2 => Packet::SubkernelBarrierWithdraw {
+                source: reader.read_u8()?,
+                destination: reader.read_u8()?,
+                id: reader.read_u32::<NativeEndian>()?,
+            },
+            0xcf => Packet::SubkernelBarrierRelease {
+                destination: reader.read_u8()?,
+                id: reader.read_u32::<NativeEndian>()?,
+                succeeded: reader.read_bool()?,
             },
 
             0xd0 => Packet::CoreMgmtGetLogRequest {
                 destination: reader.read_u8()?,
                 clear: reader.read_bool()?,
+                max_level: reader.read_u8()?,
             },
             0xd1 => Packet::CoreMgmtClearLogRequest {
                 destination: reader.read_u8()?,
@@ -685,7 +1533,7 @@ impl Packet {
                 let destination = reader.read_u8()?;
                 let length = reader.read_u16::<NativeEndian>()?;
                 let mut key: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
-                reader.read_exact(&mut key[0..length as usize])?;
+                read_payload(reader, &mut key, length as usize)?;
                 Packet::CoreMgmtConfigReadRequest {
                     destination: destination,
                     length: length,
@@ -700,7 +1548,7 @@ impl Packet {
                 let last = reader.read_bool()?;
                 let length = reader.read_u16::<NativeEndian>()?;
                 let mut data: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
-                reader.read_exact(&mut data[0..length as usize])?;
+                read_payload(reader, &mut data, length as usize)?;
                 Packet::CoreMgmtConfigWriteRequest {
                     destination: destination,
                     last: last,
@@ -712,7 +1560,7 @@ impl Packet {
                 let destination = reader.read_u8()?;
                 let length = reader.read_u16::<NativeEndian>()?;
                 let mut key: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
-                reader.read_exact(&mut key[0..length as usize])?;
+                read_payload(reader, &mut key, length as usize)?;
                 Packet::CoreMgmtConfigRemoveRequest {
                     destination: destination,
                     length: length,
@@ -731,13 +1579,14 @@ impl Packet {
             0xd9 => Packet::CoreMgmtFlashRequest {
                 destination: reader.read_u8()?,
                 payload_length: reader.read_u32::<NativeEndian>()?,
+                verify_only: reader.read_bool()?,
             },
             0xda => {
                 let destination = reader.read_u8()?;
                 let last = reader.read_bool()?;
                 let length = reader.read_u16::<NativeEndian>()?;
                 let mut data: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
-                reader.read_exact(&mut data[0..length as usize])?;
+                read_payload(reader, &mut data, length as usize)?;
                 Packet::CoreMgmtFlashAddDataRequest {
                     destination: destination,
                     last: last,
@@ -753,7 +1602,7 @@ impl Packet {
                 let last = reader.read_bool()?;
                 let length = reader.read_u16::<NativeEndian>()?;
                 let mut data: [u8; SAT_PAYLOAD_MAX_SIZE] = [0; SAT_PAYLOAD_MAX_SIZE];
-                reader.read_exact(&mut data[0..length as usize])?;
+                read_payload(reader, &mut data, length as usize)?;
                 Packet::CoreMgmtGetLogReply {
                     last: last,
                     length: length,
@@ -764,7 +1613,7 @@ impl Packet {
                 let last = reader.read_bool()?;
                 let length = reader.read_u16::<NativeEndian>()?;
                 let mut value: [u8; SAT_PAYLOAD_MAX_SIZE] = [0; SAT_PAYLOAD_MAX_SIZE];
-                reader.read_exact(&mut value[0..length as usize])?;
+                read_payload(reader, &mut value, length as usize)?;
                 Packet::CoreMgmtConfigReadReply {
                     last: last,
                     length: length,
@@ -777,7 +1626,7 @@ impl Packet {
             0xe0 => {
                 let length = reader.read_u16::<NativeEndian>()?;
                 let mut message: [u8; CXP_PAYLOAD_MAX_SIZE] = [0; CXP_PAYLOAD_MAX_SIZE];
-                reader.read_exact(&mut message[0..length as usize])?;
+                read_payload(reader, &mut message, length as usize)?;
                 Packet::CXPError { length, message }
             }
             0xe1 => Self::CXPWaitReply,
@@ -789,7 +1638,7 @@ impl Packet {
             0xe3 => {
                 let length = reader.read_u16::<NativeEndian>()?;
                 let mut data: [u8; CXP_PAYLOAD_MAX_SIZE] = [0; CXP_PAYLOAD_MAX_SIZE];
-                reader.read_exact(&mut data[0..length as usize])?;
+                read_payload(reader, &mut data, length as usize)?;
                 Packet::CXPReadReply { length, data }
             }
             0xe4 => Packet::CXPWrite32Request {
@@ -812,6 +1661,9 @@ impl Packet {
             0xe9 => {
                 let length = reader.read_u16::<NativeEndian>()?;
                 let mut data: [u64; CXP_PAYLOAD_MAX_SIZE / 8] = [0; CXP_PAYLOAD_MAX_SIZE / 8];
+                if length as usize > data.len() {
+                    return Err(Error::InvalidField);
+                }
                 for i in 0..length as usize {
                     data[i] = reader.read_u64::<NativeEndian>()?;
                 }
@@ -822,6 +1674,107 @@ impl Packet {
                 height: reader.read_u16::<NativeEndian>()?,
                 pixel_code: reader.read_u16::<NativeEndian>()?,
             },
+            0xeb => Packet::CoreMgmtAuxCaptureRequest {
+                destination: reader.read_u8()?,
+                index: reader.read_u8()?,
+            },
+            0xec => {
+                let count = reader.read_u8()?;
+                let linkno = reader.read_u8()?;
+                let timestamp_ms = reader.read_u64::<NativeEndian>()?;
+                let kind = reader.read_u8()?;
+                let mut data: [u8; AUX_CAPTURE_SIZE] = [0; AUX_CAPTURE_SIZE];
+                reader.read_exact(&mut data)?;
+                Packet::CoreMgmtAuxCaptureReply {
+                    count: count,
+                    linkno: linkno,
+                    timestamp_ms: timestamp_ms,
+                    kind: kind,
+                    data: data,
+                }
+            }
+            0xed => Packet::CoreMgmtClockStatusRequest {
+                destination: reader.read_u8()?,
+            },
+            0xee => Packet::CoreMgmtClockStatusReply {
+                monitored: reader.read_bool()?,
+                locked: reader.read_bool()?,
+                unlocks: reader.read_u32::<NativeEndian>()?,
+                recoveries: reader.read_u32::<NativeEndian>()?,
+                holdover_monitored: reader.read_bool()?,
+                los: reader.read_bool()?,
+                lol: reader.read_bool()?,
+                holdover: reader.read_bool()?,
+                los_events: reader.read_u32::<NativeEndian>()?,
+                lol_events: reader.read_u32::<NativeEndian>()?,
+                holdover_events: reader.read_u32::<NativeEndian>()?,
+                input_switches: reader.read_u32::<NativeEndian>()?,
+            },
+            0xef => Packet::CoreMgmtConfigListRequest {
+                destination: reader.read_u8()?,
+            },
+            0xf0 => {
+                let destination = reader.read_u8()?;
+                let payload_length = reader.read_u32::<NativeEndian>()?;
+                let mut targets: [u8; DESTINATION_MASK_SIZE] = [0; DESTINATION_MASK_SIZE];
+                reader.read_exact(&mut targets)?;
+                Packet::CoreMgmtFlashFanOutRequest {
+                    destination: destination,
+                    payload_length: payload_length,
+                    targets: targets,
+                }
+            }
+            0xf1 => {
+                let mut succeeded: [u8; DESTINATION_MASK_SIZE] = [0; DESTINATION_MASK_SIZE];
+                reader.read_exact(&mut succeeded)?;
+                Packet::CoreMgmtFlashFanOutReply { succeeded: succeeded }
+            }
+            0xf2 => Packet::CoreMgmtSetSedSpreadRequest {
+                destination: reader.read_u8()?,
+                enable: reader.read_bool()?,
+            },
+            0xf3 => Packet::CoreMgmtCriConRequest {
+                destination: reader.read_u8()?,
+                reset: reader.read_bool()?,
+            },
+            0xf4 => Packet::CoreMgmtCriConReply {
+                selected: reader.read_u8()?,
+                kernel_running: reader.read_bool()?,
+                dma_playing: reader.read_bool()?,
+            },
+            0xf5 => Packet::CoreMgmtKernelCacheRequest {
+                destination: reader.read_u8()?,
+                clear: reader.read_bool()?,
+            },
+            0xf6 => Packet::CoreMgmtKernelCacheReply {
+                entries: reader.read_u32::<NativeEndian>()?,
+                size: reader.read_u32::<NativeEndian>()?,
+                quota: reader.read_u32::<NativeEndian>()?,
+                evictions: reader.read_u32::<NativeEndian>()?,
+                rejections: reader.read_u32::<NativeEndian>()?,
+            },
+            0xf7 => Packet::WallClockSet {
+                destination: reader.read_u8()?,
+                time_us: reader.read_u64::<NativeEndian>()?,
+            },
+            0xf8 => Packet::WallClockAck,
+            0xf9 => Packet::CoreMgmtLogStatsRequest {
+                destination: reader.read_u8()?,
+            },
+            0xfa => Packet::CoreMgmtLogStatsReply {
+                dropped_bytes: reader.read_u64::<NativeEndian>()?,
+                dropped_messages: reader.read_u32::<NativeEndian>()?,
+            },
+            0xfb => Packet::TscDriftProbe {
+                destination: reader.read_u8()?,
+                master_time: reader.read_u64::<NativeEndian>()?,
+            },
+            0xfc => Packet::TscDriftReply {
+                offset: reader.read_u64::<NativeEndian>()? as i64,
+                drift_ppb: reader.read_u32::<NativeEndian>()? as i32,
+            },
+            0xfe => Packet::RetransmitRequest,
+            // 0xff marks framed packets and is never a packet type
             ty => return Err(Error::UnknownPacket(ty)),
         })
     }
@@ -833,13 +1786,88 @@ impl Packet {
             Packet::ResetRequest => writer.write_u8(0x02)?,
             Packet::ResetAck => writer.write_u8(0x03)?,
             Packet::TSCAck => writer.write_u8(0x04)?,
+            Packet::VersionRequest { version, features } => {
+                writer.write_u8(0x05)?;
+                writer.write_u16::<NativeEndian>(version)?;
+                writer.write_u32::<NativeEndian>(features)?;
+            }
+            Packet::VersionReply { version, features } => {
+                writer.write_u8(0x06)?;
+                writer.write_u16::<NativeEndian>(version)?;
+                writer.write_u32::<NativeEndian>(features)?;
+            }
+            Packet::SessionRequest { destination, epoch } => {
+                writer.write_u8(0x07)?;
+                writer.write_u8(destination)?;
+                writer.write_u32::<NativeEndian>(epoch)?;
+            }
+            Packet::SessionReply { resumed } => {
+                writer.write_u8(0x08)?;
+                writer.write_bool(resumed)?;
+            }
+            Packet::CoreMgmtAuxTraceEnable { destination, enable } => {
+                writer.write_u8(0x09)?;
+                writer.write_u8(destination)?;
+                writer.write_bool(enable)?;
+            }
+            Packet::CoreMgmtAuxTraceRequest { destination } => {
+                writer.write_u8(0x0a)?;
+                writer.write_u8(destination)?;
+            }
+            Packet::CoreMgmtAuxTraceContinue { destination } => {
+                writer.write_u8(0x0b)?;
+                writer.write_u8(destination)?;
+            }
+            Packet::CoreMgmtAuxTraceReply { last, length, data } => {
+                writer.write_u8(0x0c)?;
+                writer.write_bool(last)?;
+                writer.write_u16::<NativeEndian>(length)?;
+                writer.write_all(&data[0..length as usize])?;
+            }
+            Packet::LatencyProbe {
+                destination,
+                timestamp,
+            } => {
+                writer.write_u8(0x0d)?;
+                writer.write_u8(destination)?;
+                writer.write_u64::<NativeEndian>(timestamp)?;
+            }
+            Packet::LatencyProbeReply { timestamp } => {
+                writer.write_u8(0x0e)?;
+                writer.write_u64::<NativeEndian>(timestamp)?;
+            }
+            Packet::CoreMgmtIoExpanderStatusRequest { destination } => {
+                writer.write_u8(0x0f)?;
+                writer.write_u8(destination)?;
+            }
+            Packet::CoreMgmtIoExpanderStatusReply {
+                count,
+                failing,
+                errors,
+                reinits,
+            } => {
+                writer.write_u8(0x10)?;
+                writer.write_u8(count)?;
+                for i in 0..count as usize {
+                    writer.write_bool(failing[i])?;
+                    writer.write_u32::<NativeEndian>(errors[i])?;
+                    writer.write_u32::<NativeEndian>(reinits[i])?;
+                }
+            }
 
             Packet::DestinationStatusRequest { destination } => {
                 writer.write_u8(0x20)?;
                 writer.write_u8(destination)?;
             }
             Packet::DestinationDownReply => writer.write_u8(0x21)?,
-            Packet::DestinationOkReply => writer.write_u8(0x22)?,
+            Packet::DestinationOkReply {
+                buffer_space,
+                min_buffer_space,
+            } => {
+                writer.write_u8(0x22)?;
+                writer.write_u16::<NativeEndian>(buffer_space)?;
+                writer.write_u16::<NativeEndian>(min_buffer_space)?;
+            }
             Packet::DestinationSequenceErrorReply { channel } => {
                 writer.write_u8(0x23)?;
                 writer.write_u16::<NativeEndian>(channel)?;
@@ -852,6 +1880,14 @@ impl Packet {
                 writer.write_u8(0x25)?;
                 writer.write_u16::<NativeEndian>(channel)?;
             }
+            Packet::TimestampRequest { destination } => {
+                writer.write_u8(0x26)?;
+                writer.write_u8(destination)?;
+            }
+            Packet::TimestampReply { timestamp } => {
+                writer.write_u8(0x27)?;
+                writer.write_u64::<NativeEndian>(timestamp)?;
+            }
 
             Packet::RoutingSetPath { destination, hops } => {
                 writer.write_u8(0x30)?;
@@ -864,6 +1900,27 @@ impl Packet {
             }
             Packet::RoutingAck => writer.write_u8(0x32)?,
 
+            Packet::EyeScanRequest {
+                destination,
+                link,
+                prescale,
+            } => {
+                writer.write_u8(0x33)?;
+                writer.write_u8(destination)?;
+                writer.write_u8(link)?;
+                writer.write_u8(prescale)?;
+            }
+            Packet::EyeScanContinue { destination } => {
+                writer.write_u8(0x34)?;
+                writer.write_u8(destination)?;
+            }
+            Packet::EyeScanReply { last, length, data } => {
+                writer.write_u8(0x35)?;
+                writer.write_bool(last)?;
+                writer.write_u16::<NativeEndian>(length)?;
+                writer.write_all(&data[0..length as usize])?;
+            }
+
             Packet::MonitorRequest {
                 destination,
                 channel,
@@ -878,6 +1935,53 @@ impl Packet {
                 writer.write_u8(0x41)?;
                 writer.write_u64::<NativeEndian>(value)?;
             }
+            Packet::MonitorBatchRequest {
+                destination,
+                count,
+                channels,
+                probes,
+            } => {
+                writer.write_u8(0x42)?;
+                writer.write_u8(destination)?;
+                writer.write_u8(count)?;
+                for i in 0..count as usize {
+                    writer.write_u16::<NativeEndian>(channels[i])?;
+                    writer.write_u8(probes[i])?;
+                }
+            }
+            Packet::MonitorBatchReply { count, values } => {
+                writer.write_u8(0x43)?;
+                writer.write_u8(count)?;
+                for i in 0..count as usize {
+                    writer.write_u64::<NativeEndian>(values[i])?;
+                }
+            }
+            Packet::RtioInputBatchRequest {
+                destination,
+                channel,
+                timeout,
+                max_count,
+            } => {
+                writer.write_u8(0x44)?;
+                writer.write_u8(destination)?;
+                writer.write_u32::<NativeEndian>(channel)?;
+                writer.write_u64::<NativeEndian>(timeout)?;
+                writer.write_u8(max_count)?;
+            }
+            Packet::RtioInputBatchReply {
+                status,
+                count,
+                timestamps,
+                data,
+            } => {
+                writer.write_u8(0x45)?;
+                writer.write_u8(status)?;
+                writer.write_u8(count)?;
+                for i in 0..count as usize {
+                    writer.write_u64::<NativeEndian>(timestamps[i])?;
+                    writer.write_u32::<NativeEndian>(data[i])?;
+                }
+            }
             Packet::InjectionRequest {
                 destination,
                 channel,
@@ -966,6 +2070,45 @@ impl Packet {
                 writer.write_u8(address)?;
                 writer.write_u8(mask)?;
             }
+            Packet::I2cWriteBlockRequest {
+                destination,
+                busno,
+                length,
+                data,
+            } => {
+                writer.write_u8(0x89)?;
+                writer.write_u8(destination)?;
+                writer.write_u8(busno)?;
+                writer.write_u16::<NativeEndian>(length)?;
+                writer.write_all(&data[0..length as usize])?;
+            }
+            Packet::I2cWriteBlockReply { succeeded, acked } => {
+                writer.write_u8(0x8a)?;
+                writer.write_bool(succeeded)?;
+                writer.write_u16::<NativeEndian>(acked)?;
+            }
+            Packet::I2cReadBlockRequest {
+                destination,
+                busno,
+                length,
+                ack,
+            } => {
+                writer.write_u8(0x8b)?;
+                writer.write_u8(destination)?;
+                writer.write_u8(busno)?;
+                writer.write_u16::<NativeEndian>(length)?;
+                writer.write_bool(ack)?;
+            }
+            Packet::I2cReadBlockReply {
+                succeeded,
+                length,
+                data,
+            } => {
+                writer.write_u8(0x8c)?;
+                writer.write_bool(succeeded)?;
+                writer.write_u16::<NativeEndian>(length)?;
+                writer.write_all(&data[0..length as usize])?;
+            }
 
             Packet::SpiSetConfigRequest {
                 destination,
@@ -1032,6 +2175,37 @@ impl Packet {
                 writer.write_u16::<NativeEndian>(length)?;
                 writer.write_all(&data[0..length as usize])?;
             }
+            Packet::GrabberRoiRequest { destination, grabber } => {
+                writer.write_u8(0xa4)?;
+                writer.write_u8(destination)?;
+                writer.write_u8(grabber)?;
+            }
+            Packet::GrabberRoiReply {
+                succeeded,
+                frame,
+                counts,
+            } => {
+                writer.write_u8(0xa5)?;
+                writer.write_bool(succeeded)?;
+                writer.write_u32::<NativeEndian>(frame)?;
+                for count in counts.iter() {
+                    writer.write_u32::<NativeEndian>(*count)?;
+                }
+            }
+            Packet::AnalyzerArmRequest {
+                destination,
+                arm,
+                synchronized,
+            } => {
+                writer.write_u8(0xa6)?;
+                writer.write_u8(destination)?;
+                writer.write_bool(arm)?;
+                writer.write_bool(synchronized)?;
+            }
+            Packet::AnalyzerArmReply { succeeded } => {
+                writer.write_u8(0xa7)?;
+                writer.write_bool(succeeded)?;
+            }
 
             Packet::DmaAddTraceRequest {
                 source,
@@ -1102,6 +2276,9 @@ impl Packet {
                 error,
                 channel,
                 timestamp,
+                duration,
+                events,
+                min_slack,
             } => {
                 writer.write_u8(0xb6)?;
                 writer.write_u8(source)?;
@@ -1110,6 +2287,43 @@ impl Packet {
                 writer.write_u8(error)?;
                 writer.write_u32::<NativeEndian>(channel)?;
                 writer.write_u64::<NativeEndian>(timestamp)?;
+                writer.write_u64::<NativeEndian>(duration)?;
+                writer.write_u32::<NativeEndian>(events)?;
+                writer.write_i64::<NativeEndian>(min_slack)?;
+            }
+            Packet::DmaPlaybackBroadcastRequest {
+                source,
+                destination,
+                id,
+                timestamp,
+                targets,
+            } => {
+                writer.write_u8(0xb7)?;
+                writer.write_u8(source)?;
+                writer.write_u8(destination)?;
+                writer.write_u32::<NativeEndian>(id)?;
+                writer.write_u64::<NativeEndian>(timestamp)?;
+                writer.write_all(&targets)?;
+            }
+            Packet::DmaPlaybackBroadcastReply { succeeded } => {
+                writer.write_u8(0xb8)?;
+                writer.write_all(&succeeded)?;
+            }
+            Packet::DmaStatRequest {
+                source,
+                destination,
+                id,
+            } => {
+                writer.write_u8(0xb9)?;
+                writer.write_u8(source)?;
+                writer.write_u8(destination)?;
+                writer.write_u32::<NativeEndian>(id)?;
+            }
+            Packet::DmaStatReply { found, size, events } => {
+                writer.write_u8(0xba)?;
+                writer.write_bool(found)?;
+                writer.write_u32::<NativeEndian>(size)?;
+                writer.write_u32::<NativeEndian>(events)?;
             }
 
             Packet::SubkernelAddDataRequest {
@@ -1136,6 +2350,7 @@ impl Packet {
                 id,
                 run,
                 timestamp,
+                relative,
             } => {
                 writer.write_u8(0xc4)?;
                 writer.write_u8(source)?;
@@ -1143,6 +2358,7 @@ impl Packet {
                 writer.write_u32::<NativeEndian>(id)?;
                 writer.write_bool(run)?;
                 writer.write_u64::<NativeEndian>(timestamp)?;
+                writer.write_bool(relative)?;
             }
             Packet::SubkernelLoadRunReply { destination, succeeded } => {
                 writer.write_u8(0xc5)?;
@@ -1161,19 +2377,26 @@ impl Packet {
                 writer.write_bool(with_exception)?;
                 writer.write_u8(exception_src)?;
             }
-            Packet::SubkernelExceptionRequest { source, destination } => {
+            Packet::SubkernelExceptionRequest {
+                source,
+                destination,
+                seq,
+            } => {
                 writer.write_u8(0xc9)?;
                 writer.write_u8(source)?;
                 writer.write_u8(destination)?;
+                writer.write_u16::<NativeEndian>(seq)?;
             }
             Packet::SubkernelException {
                 destination,
+                seq,
                 last,
                 length,
                 data,
             } => {
                 writer.write_u8(0xca)?;
                 writer.write_u8(destination)?;
+                writer.write_u16::<NativeEndian>(seq)?;
                 writer.write_bool(last)?;
                 writer.write_u16::<NativeEndian>(length)?;
                 writer.write_all(&data[0..length as usize])?;
@@ -1194,15 +2417,63 @@ impl Packet {
                 writer.write_u16::<NativeEndian>(length)?;
                 writer.write_all(&data[0..length as usize])?;
             }
-            Packet::SubkernelMessageAck { destination } => {
+            Packet::SubkernelMessageAck { destination, credits } => {
                 writer.write_u8(0xcc)?;
                 writer.write_u8(destination)?;
+                writer.write_u8(credits)?;
+            }
+            Packet::SubkernelMessageCredit {
+                source,
+                destination,
+                credits,
+            } => {
+                writer.write_u8(0xcd)?;
+                writer.write_u8(source)?;
+                writer.write_u8(destination)?;
+                writer.write_u8(credits)?;
+            }
+            Packet::SubkernelBarrierArrive {
+                source,
+                destination,
+                id,
+                participants,
+            } => {
+                writer.write_u8(0xce)?;
+                writer.write_u8(source)?;
+                writer.write_u8(destination)?;
+                writer.write_u32::<NativeEndian>(id)?;
+                writer.write_all(&participants)?;
+            }
+            Packet::SubkernelBarrierWithdraw {
+                source,
+                destination,
+                id,
+            } => {
+                writer.write_u8(0xc2)?;
+                writer.write_u8(source)?;
+                writer.write_u8(destination)?;
+                writer.write_u32::<NativeEndian>(id)?;
+            }
+            Packet::SubkernelBarrierRelease {
+                destination,
+                id,
+                succeeded,
+            } => {
+                writer.write_u8(0xcf)?;
+                writer.write_u8(destination)?;
+                writer.write_u32::<NativeEndian>(id)?;
+                writer.write_bool(succeeded)?;
             }
 
-            Packet::CoreMgmtGetLogRequest { destination, clear } => {
+            Packet::CoreMgmtGetLogRequest {
+                destination,
+                clear,
+                max_level,
+            } => {
                 writer.write_u8(0xd0)?;
                 writer.write_u8(destination)?;
                 writer.write_bool(clear)?;
+                writer.write_u8(max_level)?;
             }
             Packet::CoreMgmtClearLogRequest { destination } => {
                 writer.write_u8(0xd1)?;
@@ -1222,6 +2493,10 @@ impl Packet {
                 writer.write_u8(0xd3)?;
                 writer.write_u8(destination)?;
             }
+            Packet::CoreMgmtConfigListRequest { destination } => {
+                writer.write_u8(0xef)?;
+                writer.write_u8(destination)?;
+            }
             Packet::CoreMgmtConfigWriteRequest {
                 destination,
                 last,
@@ -1259,10 +2534,12 @@ impl Packet {
             Packet::CoreMgmtFlashRequest {
                 destination,
                 payload_length,
+                verify_only,
             } => {
                 writer.write_u8(0xd9)?;
                 writer.write_u8(destination)?;
                 writer.write_u32::<NativeEndian>(payload_length)?;
+                writer.write_bool(verify_only)?;
             }
             Packet::CoreMgmtFlashAddDataRequest {
                 destination,
@@ -1371,6 +2648,258 @@ impl Packet {
                 writer.write_u16::<NativeEndian>(height)?;
                 writer.write_u16::<NativeEndian>(pixel_code)?;
             }
+            Packet::SubkernelDatasetRequest {
+                destination,
+                id,
+                seq,
+                name_len,
+                name,
+            } => {
+                writer.write_u8(0x55)?;
+                writer.write_u8(destination)?;
+                writer.write_u32::<NativeEndian>(id)?;
+                writer.write_u16::<NativeEndian>(seq)?;
+                writer.write_u8(name_len)?;
+                writer.write_all(&name[0..name_len as usize])?;
+            }
+            Packet::SubkernelDatasetReply {
+                seq,
+                found,
+                last,
+                length,
+                data,
+            } => {
+                writer.write_u8(0x56)?;
+                writer.write_u16::<NativeEndian>(seq)?;
+                writer.write_bool(found)?;
+                writer.write_bool(last)?;
+                writer.write_u16::<NativeEndian>(length)?;
+                writer.write_all(&data[0..length as usize])?;
+            }
+            Packet::CXPEventRequest { destination } => {
+                writer.write_u8(0x53)?;
+                writer.write_u8(destination)?;
+            }
+            Packet::CXPEventReply {
+                available,
+                namespace,
+                id,
+                timestamp,
+            } => {
+                writer.write_u8(0x54)?;
+                writer.write_bool(available)?;
+                writer.write_u8(namespace)?;
+                writer.write_u16::<NativeEndian>(id)?;
+                writer.write_u64::<NativeEndian>(timestamp)?;
+            }
+            Packet::RouterStatsRequest { destination } => {
+                writer.write_u8(0x57)?;
+                writer.write_u8(destination)?;
+            }
+            Packet::RouterStatsReply {
+                forwarded,
+                queued,
+                max_queued,
+                dropped_full,
+                dropped_aged,
+            } => {
+                writer.write_u8(0x58)?;
+                writer.write_u32::<NativeEndian>(forwarded)?;
+                writer.write_u16::<NativeEndian>(queued)?;
+                writer.write_u16::<NativeEndian>(max_queued)?;
+                writer.write_u32::<NativeEndian>(dropped_full)?;
+                writer.write_u32::<NativeEndian>(dropped_aged)?;
+            }
+            Packet::RepeaterStatsRequest { destination, repno } => {
+                writer.write_u8(0x59)?;
+                writer.write_u8(destination)?;
+                writer.write_u8(repno)?;
+            }
+            Packet::RepeaterStatsReply {
+                repeaters,
+                state,
+                up_transitions,
+                down_transitions,
+                aux_send_failures,
+                forward_timeouts,
+                history_len,
+                history_states,
+                history_age_ms,
+            } => {
+                writer.write_u8(0x5a)?;
+                writer.write_u8(repeaters)?;
+                writer.write_u8(state)?;
+                writer.write_u32::<NativeEndian>(up_transitions)?;
+                writer.write_u32::<NativeEndian>(down_transitions)?;
+                writer.write_u32::<NativeEndian>(aux_send_failures)?;
+                writer.write_u32::<NativeEndian>(forward_timeouts)?;
+                writer.write_u8(history_len)?;
+                writer.write_all(&history_states)?;
+                for age in history_age_ms.iter() {
+                    writer.write_u32::<NativeEndian>(*age)?;
+                }
+            }
+            Packet::StorageStatsRequest {
+                destination,
+                length,
+                key,
+            } => {
+                writer.write_u8(0x5b)?;
+                writer.write_u8(destination)?;
+                writer.write_u16::<NativeEndian>(length)?;
+                writer.write_all(&key[0..length as usize])?;
+            }
+            Packet::StorageStatsReply { set, size } => {
+                writer.write_u8(0x5c)?;
+                writer.write_bool(set)?;
+                writer.write_u32::<NativeEndian>(size)?;
+            }
+            Packet::StorageSpaceRequest { destination } => {
+                writer.write_u8(0x5d)?;
+                writer.write_u8(destination)?;
+            }
+            Packet::StorageSpaceReply { known, total, free } => {
+                writer.write_u8(0x5e)?;
+                writer.write_bool(known)?;
+                writer.write_u64::<NativeEndian>(total)?;
+                writer.write_u64::<NativeEndian>(free)?;
+            }
+            Packet::CoreMgmtFlashFanOutRequest {
+                destination,
+                payload_length,
+                targets,
+            } => {
+                writer.write_u8(0xf0)?;
+                writer.write_u8(destination)?;
+                writer.write_u32::<NativeEndian>(payload_length)?;
+                writer.write_all(&targets)?;
+            }
+            Packet::CoreMgmtFlashFanOutReply { succeeded } => {
+                writer.write_u8(0xf1)?;
+                writer.write_all(&succeeded)?;
+            }
+            Packet::CoreMgmtSetSedSpreadRequest { destination, enable } => {
+                writer.write_u8(0xf2)?;
+                writer.write_u8(destination)?;
+                writer.write_bool(enable)?;
+            }
+            Packet::CoreMgmtCriConRequest { destination, reset } => {
+                writer.write_u8(0xf3)?;
+                writer.write_u8(destination)?;
+                writer.write_bool(reset)?;
+            }
+            Packet::CoreMgmtCriConReply {
+                selected,
+                kernel_running,
+                dma_playing,
+            } => {
+                writer.write_u8(0xf4)?;
+                writer.write_u8(selected)?;
+                writer.write_bool(kernel_running)?;
+                writer.write_bool(dma_playing)?;
+            }
+            Packet::CoreMgmtKernelCacheRequest { destination, clear } => {
+                writer.write_u8(0xf5)?;
+                writer.write_u8(destination)?;
+                writer.write_bool(clear)?;
+            }
+            Packet::CoreMgmtKernelCacheReply {
+                entries,
+                size,
+                quota,
+                evictions,
+                rejections,
+            } => {
+                writer.write_u8(0xf6)?;
+                writer.write_u32::<NativeEndian>(entries)?;
+                writer.write_u32::<NativeEndian>(size)?;
+                writer.write_u32::<NativeEndian>(quota)?;
+                writer.write_u32::<NativeEndian>(evictions)?;
+                writer.write_u32::<NativeEndian>(rejections)?;
+            }
+            Packet::WallClockSet { destination, time_us } => {
+                writer.write_u8(0xf7)?;
+                writer.write_u8(destination)?;
+                writer.write_u64::<NativeEndian>(time_us)?;
+            }
+            Packet::WallClockAck => writer.write_u8(0xf8)?,
+            Packet::CoreMgmtLogStatsRequest { destination } => {
+                writer.write_u8(0xf9)?;
+                writer.write_u8(destination)?;
+            }
+            Packet::CoreMgmtLogStatsReply {
+                dropped_bytes,
+                dropped_messages,
+            } => {
+                writer.write_u8(0xfa)?;
+                writer.write_u64::<NativeEndian>(dropped_bytes)?;
+                writer.write_u32::<NativeEndian>(dropped_messages)?;
+            }
+            Packet::CoreMgmtAuxCaptureRequest { destination, index } => {
+                writer.write_u8(0xeb)?;
+                writer.write_u8(destination)?;
+                writer.write_u8(index)?;
+            }
+            Packet::CoreMgmtAuxCaptureReply {
+                count,
+                linkno,
+                timestamp_ms,
+                kind,
+                data,
+            } => {
+                writer.write_u8(0xec)?;
+                writer.write_u8(count)?;
+                writer.write_u8(linkno)?;
+                writer.write_u64::<NativeEndian>(timestamp_ms)?;
+                writer.write_u8(kind)?;
+                writer.write_all(&data)?;
+            }
+            Packet::CoreMgmtClockStatusRequest { destination } => {
+                writer.write_u8(0xed)?;
+                writer.write_u8(destination)?;
+            }
+            Packet::CoreMgmtClockStatusReply {
+                monitored,
+                locked,
+                unlocks,
+                recoveries,
+                holdover_monitored,
+                los,
+                lol,
+                holdover,
+                los_events,
+                lol_events,
+                holdover_events,
+                input_switches,
+            } => {
+                writer.write_u8(0xee)?;
+                writer.write_bool(monitored)?;
+                writer.write_bool(locked)?;
+                writer.write_u32::<NativeEndian>(unlocks)?;
+                writer.write_u32::<NativeEndian>(recoveries)?;
+                writer.write_bool(holdover_monitored)?;
+                writer.write_bool(los)?;
+                writer.write_bool(lol)?;
+                writer.write_bool(holdover)?;
+                writer.write_u32::<NativeEndian>(los_events)?;
+                writer.write_u32::<NativeEndian>(lol_events)?;
+                writer.write_u32::<NativeEndian>(holdover_events)?;
+                writer.write_u32::<NativeEndian>(input_switches)?;
+            }
+            Packet::TscDriftProbe {
+                destination,
+                master_time,
+            } => {
+                writer.write_u8(0xfb)?;
+                writer.write_u8(destination)?;
+                writer.write_u64::<NativeEndian>(master_time)?;
+            }
+            Packet::TscDriftReply { offset, drift_ppb } => {
+                writer.write_u8(0xfc)?;
+                writer.write_i64::<NativeEndian>(offset)?;
+                writer.write_i32::<NativeEndian>(drift_ppb)?;
+            }
+            Packet::RetransmitRequest => writer.write_u8(0xfe)?,
         }
         Ok(())
     }
@@ -1387,7 +2916,11 @@ impl Packet {
             Packet::SubkernelLoadRunRequest { destination, .. } => Some(*destination),
             Packet::SubkernelLoadRunReply { destination, .. } => Some(*destination),
             Packet::SubkernelMessage { destination, .. } => Some(*destination),
-            Packet::SubkernelMessageAck { destination } => Some(*destination),
+            Packet::SubkernelMessageAck { destination, .. } => Some(*destination),
+            Packet::SubkernelMessageCredit { destination, .. } => Some(*destination),
+            Packet::SubkernelBarrierArrive { destination, .. } => Some(*destination),
+            Packet::SubkernelBarrierWithdraw { destination, .. } => Some(*destination),
+            Packet::SubkernelBarrierRelease { destination, .. } => Some(*destination),
             Packet::SubkernelExceptionRequest { destination, .. } => Some(*destination),
             Packet::SubkernelException { destination, .. } => Some(*destination),
             Packet::DmaPlaybackStatus { destination, .. } => Some(*destination),
@@ -1396,6 +2929,18 @@ impl Packet {
         }
     }
 
+    pub fn priority(&self) -> PacketPriority {
+        // only routable packets are queued by priority: those carrying the payload of
+        // multi-packet transfers are bulk, everything else (replies, acks, notifications)
+        // is control
+        match self {
+            Packet::DmaAddTraceRequest { .. } | Packet::SubkernelException { .. } | Packet::SubkernelMessage { .. } => {
+                PacketPriority::Bulk
+            }
+            _ => PacketPriority::Control,
+        }
+    }
+
     pub fn expects_response(&self) -> bool {
         // returns true if the routable packet should elicit a response
         // e.g. reply, ACK packets end a conversation,
@@ -1406,6 +2951,10 @@ impl Packet {
             | Packet::DmaPlaybackReply { .. }
             | Packet::SubkernelLoadRunReply { .. }
             | Packet::SubkernelMessageAck { .. }
+            | Packet::SubkernelMessageCredit { .. }
+            | Packet::SubkernelBarrierArrive { .. }
+            | Packet::SubkernelBarrierWithdraw { .. }
+            | Packet::SubkernelBarrierRelease { .. }
             | Packet::DmaPlaybackStatus { .. }
             | Packet::SubkernelFinished { .. }
             | Packet::CoreMgmtDropLinkAck { .. }
@@ -1414,3 +2963,121 @@ impl Packet {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use io::Cursor;
+
+    use super::*;
+
+    // encodes the packet into a buffer, returning the number of bytes written
+    fn encode(packet: &Packet, buffer: &mut [u8; MAX_PACKET]) -> usize {
+        let mut writer = Cursor::new(&mut buffer[..]);
+        packet.write_to(&mut writer).unwrap();
+        writer.position()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Packet, Error> {
+        Packet::read_from(&mut Cursor::new(bytes))
+    }
+
+    fn sample_packets() -> [Packet; 6] {
+        let mut trace_data = [0; SAT_PAYLOAD_MAX_SIZE];
+        trace_data[..3].copy_from_slice(&[1, 2, 3]);
+        let mut channels = [0; MONITOR_BATCH_SIZE];
+        let mut probes = [0; MONITOR_BATCH_SIZE];
+        channels[..2].copy_from_slice(&[7, 0x1234]);
+        probes[..2].copy_from_slice(&[1, 2]);
+        let mut trace = [0; MASTER_PAYLOAD_MAX_SIZE];
+        for (i, byte) in trace.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        [
+            Packet::EchoRequest,
+            Packet::VersionRequest {
+                version: PROTOCOL_VERSION,
+                features: local_features(),
+            },
+            Packet::CoreMgmtAuxTraceReply {
+                last: true,
+                length: 3,
+                data: trace_data,
+            },
+            Packet::CoreMgmtIoExpanderStatusReply {
+                count: 1,
+                failing: [true, false],
+                errors: [5, 0],
+                reinits: [2, 0],
+            },
+            Packet::MonitorBatchRequest {
+                destination: 3,
+                count: 2,
+                channels: channels,
+                probes: probes,
+            },
+            Packet::DmaAddTraceRequest {
+                source: 0,
+                destination: 1,
+                id: 0xdeadbeef,
+                status: PayloadStatus::FirstAndLast,
+                length: MASTER_PAYLOAD_MAX_SIZE as u16,
+                trace: trace,
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buffer = [0; MAX_PACKET];
+        for packet in sample_packets().iter() {
+            let length = encode(packet, &mut buffer);
+            let mut reader = Cursor::new(&buffer[..length]);
+            assert_eq!(&Packet::read_from(&mut reader).unwrap(), packet);
+            assert_eq!(reader.position(), length);
+        }
+    }
+
+    #[test]
+    fn short_payload() {
+        let mut buffer = [0; MAX_PACKET];
+        for packet in sample_packets().iter().skip(1) {
+            let length = encode(packet, &mut buffer);
+            for short in [1, length - 1].iter() {
+                assert!(matches!(decode(&buffer[..*short]), Err(Error::Io(_))));
+            }
+        }
+        assert!(matches!(decode(&[]), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn bad_length_field() {
+        let mut buffer = [0; MAX_PACKET];
+        // type, last, then the payload length
+        encode(&sample_packets()[2], &mut buffer);
+        buffer[2..4].copy_from_slice(&(SAT_PAYLOAD_MAX_SIZE as u16 + 1).to_ne_bytes());
+        assert!(matches!(decode(&buffer), Err(Error::InvalidField)));
+
+        // type, source, destination, ID, status, then the trace length
+        encode(&sample_packets()[5], &mut buffer);
+        buffer[8..10].copy_from_slice(&u16::MAX.to_ne_bytes());
+        assert!(matches!(decode(&buffer), Err(Error::InvalidField)));
+        encode(&sample_packets()[5], &mut buffer);
+        buffer[7] = 4;
+        assert!(matches!(decode(&buffer), Err(Error::InvalidField)));
+
+        // type, then the count
+        encode(&sample_packets()[3], &mut buffer);
+        buffer[1] = IO_EXPANDER_COUNT as u8 + 1;
+        assert!(matches!(decode(&buffer), Err(Error::InvalidField)));
+
+        // type, destination, then the count
+        encode(&sample_packets()[4], &mut buffer);
+        buffer[2] = MONITOR_BATCH_SIZE as u8 + 1;
+        assert!(matches!(decode(&buffer), Err(Error::InvalidField)));
+    }
+
+    #[test]
+    fn unknown_packet() {
+        assert!(matches!(decode(&[0xff]), Err(Error::UnknownPacket(0xff))));
+    }
+}
@@ -0,0 +1,77 @@
+use alloc::collections::BTreeMap;
+
+use libboard_zynq::timer;
+use libcortex_a9::mutex::Mutex;
+use log::error;
+
+// Repeating errors, such as a stuck channel underflowing on every event, are logged once
+// per interval for each kind of error and channel. The repeats in between are counted and
+// summarized once the interval is over, so that they do not flood the UART and the log buffer.
+
+const INTERVAL_MS: u64 = 10_000;
+
+struct Window {
+    start_ms: u64,
+    repeats: u32,
+}
+
+static WINDOWS: Mutex<BTreeMap<(&'static str, Option<u32>), Window>> = Mutex::new(BTreeMap::new());
+
+fn summarize(kind: &str, channel: Option<u32>, repeats: u32) {
+    match channel {
+        Some(channel) => error!(
+            "{} on ch {} repeated {} times in last {} s",
+            kind,
+            channel,
+            repeats,
+            INTERVAL_MS / 1000
+        ),
+        None => error!("{} repeated {} times in last {} s", kind, repeats, INTERVAL_MS / 1000),
+    }
+}
+
+/// Returns whether an error is to be logged, i.e. the same kind of error on the same channel
+/// was not logged within the interval; it is counted as a repeat otherwise.
+pub fn should_log(kind: &'static str, channel: Option<u32>) -> bool {
+    let now = timer::get_ms();
+    let mut windows = WINDOWS.lock();
+    match windows.get_mut(&(kind, channel)) {
+        Some(window) if now < window.start_ms + INTERVAL_MS => {
+            window.repeats += 1;
+            false
+        }
+        Some(window) => {
+            if window.repeats > 0 {
+                summarize(kind, channel, window.repeats);
+            }
+            window.start_ms = now;
+            window.repeats = 0;
+            true
+        }
+        None => {
+            windows.insert(
+                (kind, channel),
+                Window {
+                    start_ms: now,
+                    repeats: 0,
+                },
+            );
+            true
+        }
+    }
+}
+
+/// Summarizes the repeats of the intervals that are over. Called periodically,
+/// so that the summary does not wait for the next occurrence of the error.
+pub fn flush() {
+    let now = timer::get_ms();
+    WINDOWS.lock().retain(|&(kind, channel), window| {
+        if now < window.start_ms + INTERVAL_MS {
+            return true;
+        }
+        if window.repeats > 0 {
+            summarize(kind, channel, window.repeats);
+        }
+        false
+    });
+}
@@ -0,0 +1,138 @@
+use alloc::vec::Vec;
+
+use byteorder::{ByteOrder, NativeEndian};
+use libboard_zynq::timer;
+use log::{error, info};
+
+use crate::pl::csr;
+
+// Statistical eye scan of the DRTIO GTX receivers (UG476, "RX Margin Analysis"),
+// through the DRP port of the transceiver selected with `link_sel`.
+// The sampling point is swept over a coarse grid of horizontal (UI) and vertical
+// (eye height) offsets; for each point the error and sample counters are recorded.
+// The number of bits compared is sample_count * 2^(1 + prescale) * data width.
+
+const ES_QUAL_MASK: u16 = 0x031;
+const ES_SDATA_MASK: u16 = 0x036;
+const ES_VERT_OFFSET: u16 = 0x03b;
+const ES_HORZ_OFFSET: u16 = 0x03c;
+const ES_CONTROL: u16 = 0x03d;
+const ES_ERROR_COUNT: u16 = 0x14f;
+const ES_SAMPLE_COUNT: u16 = 0x150;
+const ES_CONTROL_STATUS: u16 = 0x151;
+
+const ES_CONTROL_RUN: u16 = 1 << 10;
+const ES_ERRDET_EN: u16 = 1 << 9;
+const ES_EYE_SCAN_EN: u16 = 1 << 8;
+const ES_PRESCALE_MASK: u16 = 0x1f;
+
+// 40-bit internal data width: compare the 40 received bits, ignore the 40 qualifier bits
+const SDATA_MASK_40BIT: [u16; 5] = [0x0000, 0x0000, 0xff00, 0xffff, 0xffff];
+
+// full rate GTX: horizontal offsets span -32..=32 (one UI)
+const HORZ_RANGE: i16 = 32;
+const HORZ_STEP: i16 = 4;
+const VERT_RANGE: i16 = 120;
+const VERT_STEP: i16 = 24;
+
+const POINT_TIMEOUT_MS: u64 = 1_000;
+// keeps a full scan within a few seconds (each step doubles the time per point)
+pub const MAX_PRESCALE: u8 = 5;
+
+// each point is horizontal offset (i8), vertical offset (i8), error count (u16), sample count (u16)
+pub const POINT_SIZE: usize = 6;
+
+fn drp_read(address: u16) -> u16 {
+    unsafe {
+        csr::drtio_eyescan::daddr_write(address);
+        csr::drtio_eyescan::dread_write(1);
+        while csr::drtio_eyescan::dready_read() != 1 {}
+        csr::drtio_eyescan::dout_read()
+    }
+}
+
+fn drp_write(address: u16, value: u16) {
+    unsafe {
+        csr::drtio_eyescan::daddr_write(address);
+        csr::drtio_eyescan::din_write(value);
+        csr::drtio_eyescan::din_stb_write(1);
+        while csr::drtio_eyescan::dready_read() != 1 {}
+    }
+}
+
+fn drp_modify(address: u16, mask: u16, value: u16) {
+    let current = drp_read(address);
+    drp_write(address, (current & !mask) | (value & mask));
+}
+
+pub fn link_count() -> u8 {
+    unsafe { csr::drtio_eyescan::link_count_read() }
+}
+
+fn setup(prescale: u8) {
+    for i in 0..5 {
+        drp_write(ES_QUAL_MASK + i, 0xffff);
+        drp_write(ES_SDATA_MASK + i, SDATA_MASK_40BIT[i as usize]);
+    }
+    drp_modify(
+        ES_CONTROL,
+        ES_CONTROL_RUN | ES_ERRDET_EN | ES_EYE_SCAN_EN | ES_PRESCALE_MASK,
+        ES_ERRDET_EN | ES_EYE_SCAN_EN | (prescale as u16 & ES_PRESCALE_MASK),
+    );
+}
+
+fn measure(horz: i16, vert: i16) -> Result<(u16, u16), ()> {
+    drp_modify(ES_HORZ_OFFSET, 0x0fff, horz as u16);
+    // sign and magnitude
+    let vert_code = if vert < 0 { 0x80 | (-vert) as u16 } else { vert as u16 };
+    drp_modify(ES_VERT_OFFSET, 0x00ff, vert_code);
+
+    drp_modify(ES_CONTROL, ES_CONTROL_RUN, ES_CONTROL_RUN);
+    let deadline = timer::get_ms() + POINT_TIMEOUT_MS;
+    while drp_read(ES_CONTROL_STATUS) & 1 == 0 {
+        if timer::get_ms() > deadline {
+            drp_modify(ES_CONTROL, ES_CONTROL_RUN, 0);
+            return Err(());
+        }
+    }
+    let counts = (drp_read(ES_ERROR_COUNT), drp_read(ES_SAMPLE_COUNT));
+    drp_modify(ES_CONTROL, ES_CONTROL_RUN, 0);
+    Ok(counts)
+}
+
+pub fn scan(link: u8, prescale: u8) -> Result<Vec<u8>, ()> {
+    if link >= link_count() {
+        error!("eye scan: link {} does not exist", link);
+        return Err(());
+    }
+    if prescale > MAX_PRESCALE {
+        error!("eye scan: prescale {} above maximum {}", prescale, MAX_PRESCALE);
+        return Err(());
+    }
+    unsafe {
+        csr::drtio_eyescan::link_sel_write(link);
+    }
+    info!("eye scan of link {} (prescale {})...", link, prescale);
+    setup(prescale);
+
+    let mut result = Vec::new();
+    let mut point = [0; POINT_SIZE];
+    let mut vert = -VERT_RANGE;
+    while vert <= VERT_RANGE {
+        let mut horz = -HORZ_RANGE;
+        while horz <= HORZ_RANGE {
+            let (errors, samples) = measure(horz, vert).map_err(|_| {
+                error!("eye scan: measurement timed out at ({}, {})", horz, vert);
+            })?;
+            point[0] = horz as i8 as u8;
+            point[1] = vert as i8 as u8;
+            NativeEndian::write_u16(&mut point[2..4], errors);
+            NativeEndian::write_u16(&mut point[4..6], samples);
+            result.extend_from_slice(&point);
+            horz += HORZ_STEP;
+        }
+        vert += VERT_STEP;
+    }
+    info!("eye scan of link {} done", link);
+    Ok(result)
+}
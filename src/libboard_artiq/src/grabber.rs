@@ -1,6 +1,6 @@
 use log::info;
 
-use crate::pl::csr;
+use crate::{drtioaux_proto::GRABBER_ROI_COUNT, pl::csr};
 
 #[derive(PartialEq, Clone, Copy)]
 enum State {
@@ -161,3 +161,30 @@ pub fn tick() {
         }
     }
 }
+
+// Latches the outputs of all ROI engines of grabber `g` at once, so kernels get
+// counts from a single frame. Only available while the grabber is aligned.
+#[cfg(has_grabber_roi)]
+pub fn roi_counts(g: usize) -> Option<(u32, [u32; GRABBER_ROI_COUNT])> {
+    if g >= csr::GRABBER_ROI.len() || unsafe { INFO[g].state } != State::Watch {
+        return None;
+    }
+    let mut counts = [0; GRABBER_ROI_COUNT];
+    let frame = unsafe {
+        (csr::GRABBER_ROI[g].snapshot_write)(1);
+        (csr::GRABBER_ROI[g].frame_read)()
+    };
+    for (n, count) in counts.iter_mut().enumerate() {
+        unsafe {
+            (csr::GRABBER_ROI[g].sel_write)(n as u8);
+            *count = (csr::GRABBER_ROI[g].count_read)();
+        }
+    }
+    Some((frame, counts))
+}
+
+// gateware without the ROI readout
+#[cfg(not(has_grabber_roi))]
+pub fn roi_counts(_g: usize) -> Option<(u32, [u32; GRABBER_ROI_COUNT])> {
+    None
+}
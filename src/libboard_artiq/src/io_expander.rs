@@ -1,5 +1,8 @@
-use libboard_zynq::i2c;
-use log::info;
+use core::{cmp::min,
+           sync::atomic::{AtomicBool, AtomicU32, Ordering}};
+
+use libboard_zynq::{i2c, timer};
+use log::{info, warn};
 
 #[cfg(has_virtual_leds)]
 use crate::pl::csr;
@@ -44,7 +47,51 @@ const IODIR1: [u8; 2] = [
     0xFF & !IODIR_OUT_SFP_TX_DISABLE & !IODIR_OUT_SFP_LED,
 ];
 
+// consecutive failures after which an expander is detected and initialized again
+const REINIT_AFTER_FAILURES: u32 = 4;
+// retries of a failing expander are spaced out from the first to the second
+const MIN_BACKOFF_MS: u64 = 10;
+const MAX_BACKOFF_MS: u64 = 10_000;
+// an expander is checked for presence this often, even when its outputs do not change
+const PRESENCE_CHECK_INTERVAL_MS: u64 = 1000;
+
+// health of both expanders, for the management port
+static FAILING: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+static ERRORS: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+static REINITS: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Health {
+    pub failing: bool,
+    // failed services and re-initializations since boot
+    pub errors: u32,
+    pub reinits: u32,
+}
+
+pub fn health(index: u8) -> Health {
+    let index = index as usize;
+    Health {
+        failing: FAILING[index].load(Ordering::Relaxed),
+        errors: ERRORS[index].load(Ordering::Relaxed),
+        reinits: REINITS[index].load(Ordering::Relaxed),
+    }
+}
+
+const MCP23017_REGISTERS: Registers = Registers {
+    iodira: 0x00,
+    iodirb: 0x01,
+    gpioa: 0x12,
+    gpiob: 0x13,
+};
+const PCA9539_REGISTERS: Registers = Registers {
+    iodira: 0x06,
+    iodirb: 0x07,
+    gpioa: 0x02,
+    gpiob: 0x03,
+};
+
 pub struct IoExpander {
+    index: u8,
     address: u8,
     #[cfg(has_virtual_leds)]
     virtual_led_mapping: &'static [(u8, u8, u8)],
@@ -52,10 +99,15 @@ pub struct IoExpander {
     out_current: [u8; 2],
     out_target: [u8; 2],
     registers: Registers,
+    ready: bool,
+    failures: u32,
+    backoff_ms: u64,
+    retry_at: u64,
+    next_presence_check: u64,
 }
 
 impl IoExpander {
-    pub fn new(i2c: &mut i2c::I2c, index: u8) -> Result<Self, &'static str> {
+    fn with_index(index: u8) -> Result<Self, &'static str> {
         #[cfg(all(hw_rev = "v1.0", has_virtual_leds))]
         const VIRTUAL_LED_MAPPING0: [(u8, u8, u8); 2] = [(0, 0, 6), (1, 1, 6)];
         #[cfg(all(any(hw_rev = "v1.1", hw_rev = "v1.2"), has_virtual_leds))]
@@ -64,51 +116,87 @@ impl IoExpander {
         const VIRTUAL_LED_MAPPING1: [(u8, u8, u8); 2] = [(2, 0, 6), (3, 1, 6)];
 
         // Both expanders on SHARED I2C bus
-        let mut io_expander = match index {
-            0 => IoExpander {
-                address: 0x40,
-                #[cfg(has_virtual_leds)]
-                virtual_led_mapping: &VIRTUAL_LED_MAPPING0,
-                iodir: IODIR0,
-                out_current: [0; 2],
-                out_target: [0; 2],
-                registers: Registers {
-                    iodira: 0x00,
-                    iodirb: 0x01,
-                    gpioa: 0x12,
-                    gpiob: 0x13,
-                },
-            },
-            1 => IoExpander {
-                address: 0x42,
-                #[cfg(has_virtual_leds)]
-                virtual_led_mapping: &VIRTUAL_LED_MAPPING1,
-                iodir: IODIR1,
-                out_current: [0; 2],
-                out_target: [0; 2],
-                registers: Registers {
-                    iodira: 0x00,
-                    iodirb: 0x01,
-                    gpioa: 0x12,
-                    gpiob: 0x13,
-                },
-            },
+        let (address, iodir) = match index {
+            0 => (0x40, IODIR0),
+            1 => (0x42, IODIR1),
             _ => return Err("incorrect I/O expander index"),
         };
-        if !io_expander.check_ack(i2c)? {
-            info!("MCP23017 io expander {} not found. Checking for PCA9539.", index);
-            io_expander.address += 0xa8; // translate to PCA9539 addresses (see schematic)
-            io_expander.registers = Registers {
-                iodira: 0x06,
-                iodirb: 0x07,
-                gpioa: 0x02,
-                gpiob: 0x03,
-            };
-            if !io_expander.check_ack(i2c)? {
+        Ok(IoExpander {
+            index: index,
+            address: address,
+            #[cfg(has_virtual_leds)]
+            virtual_led_mapping: if index == 0 {
+                &VIRTUAL_LED_MAPPING0
+            } else {
+                &VIRTUAL_LED_MAPPING1
+            },
+            iodir: iodir,
+            out_current: [0; 2],
+            out_target: [0; 2],
+            registers: MCP23017_REGISTERS,
+            ready: false,
+            failures: 0,
+            backoff_ms: MIN_BACKOFF_MS,
+            retry_at: 0,
+            next_presence_check: 0,
+        })
+    }
+
+    pub fn new(i2c: &mut i2c::I2c, index: u8) -> Result<Self, &'static str> {
+        let mut io_expander = IoExpander::with_index(index)?;
+        io_expander.detect(i2c)?;
+        Ok(io_expander)
+    }
+
+    /// Like `new` followed by `init`, except that an expander that cannot be brought up
+    /// is kept, and brought up by `poll` once it answers, e.g. after being plugged in.
+    pub fn attach(i2c: &mut i2c::I2c, index: u8) -> Result<Self, &'static str> {
+        let mut io_expander = IoExpander::with_index(index)?;
+        if let Err(e) = io_expander.reinit(i2c) {
+            warn!("I/O expander #{} could not be initialized ({}), will retry", index, e);
+            io_expander.record_failure();
+        }
+        Ok(io_expander)
+    }
+
+    fn detect(&mut self, i2c: &mut i2c::I2c) -> Result<(), &'static str> {
+        self.address = if self.index == 0 { 0x40 } else { 0x42 };
+        self.registers = MCP23017_REGISTERS;
+        if !self.check_ack(i2c)? {
+            info!("MCP23017 io expander {} not found. Checking for PCA9539.", self.index);
+            self.address += 0xa8; // translate to PCA9539 addresses (see schematic)
+            self.registers = PCA9539_REGISTERS;
+            if !self.check_ack(i2c)? {
                 return Err("Neither MCP23017 nor PCA9539 io expander found.");
             };
         }
-        Ok(io_expander)
+        Ok(())
+    }
+
+    // Brings the expander up with the outputs last set: the output registers are written
+    // before the directions, so that the outputs do not glitch low on the way.
+    fn reinit(&mut self, i2c: &mut i2c::I2c) -> Result<(), &'static str> {
+        self.ready = false;
+        self.detect(i2c)?;
+        self.select(i2c)?;
+        self.write(i2c, self.registers.gpioa, self.out_target[0])?;
+        self.write(i2c, self.registers.gpiob, self.out_target[1])?;
+        self.out_current = self.out_target;
+        self.update_iodir(i2c)?;
+        self.ready = true;
+        Ok(())
+    }
+
+    fn record_failure(&mut self) {
+        let index = self.index as usize;
+        self.failures += 1;
+        ERRORS[index].fetch_add(1, Ordering::Relaxed);
+        FAILING[index].store(true, Ordering::Relaxed);
+        self.retry_at = timer::get_ms() + self.backoff_ms;
+        self.backoff_ms = min(self.backoff_ms * 2, MAX_BACKOFF_MS);
+        if self.failures >= REINIT_AFTER_FAILURES {
+            self.ready = false;
+        }
     }
 
     fn select(&self, i2c: &mut i2c::I2c) -> Result<(), &'static str> {
@@ -155,6 +243,7 @@ impl IoExpander {
         self.write(i2c, self.registers.gpioa, 0x00)?;
         self.out_current[1] = 0x00;
         self.write(i2c, self.registers.gpiob, 0x00)?;
+        self.ready = true;
         Ok(())
     }
 
@@ -193,4 +282,58 @@ impl IoExpander {
 
         Ok(())
     }
+
+    /// Services the expander without failing: errors are logged and retried with an
+    /// exponential backoff, and after REINIT_AFTER_FAILURES of them in a row, or once
+    /// the expander stops answering, it is detected and initialized again, restoring
+    /// its outputs.
+    pub fn poll(&mut self, i2c: &mut i2c::I2c) {
+        let now = timer::get_ms();
+        if now < self.retry_at {
+            return;
+        }
+        let result = if self.ready {
+            self.service(i2c)
+        } else {
+            match self.reinit(i2c) {
+                Ok(()) => {
+                    info!("I/O expander #{} initialized again", self.index);
+                    REINITS[self.index as usize].fetch_add(1, Ordering::Relaxed);
+                    self.service(i2c)
+                }
+                Err(e) => Err(e),
+            }
+        };
+        let result = match result {
+            Ok(()) if now >= self.next_presence_check => {
+                self.next_presence_check = now + PRESENCE_CHECK_INTERVAL_MS;
+                match self.check_ack(i2c) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => {
+                        self.ready = false;
+                        Err("I/O expander not responding")
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            result => result,
+        };
+        match result {
+            Ok(()) => {
+                if self.failures > 0 {
+                    info!("I/O expander #{} recovered after {} failures", self.index, self.failures);
+                    FAILING[self.index as usize].store(false, Ordering::Relaxed);
+                }
+                self.failures = 0;
+                self.backoff_ms = MIN_BACKOFF_MS;
+            }
+            Err(e) => {
+                warn!(
+                    "I/O expander #{} service failed ({}), retrying in {} ms",
+                    self.index, e, self.backoff_ms
+                );
+                self.record_failure();
+            }
+        }
+    }
 }
@@ -16,16 +16,22 @@ extern crate libregister;
 extern crate log;
 extern crate log_buffer;
 
+pub mod config_store;
+pub mod console;
+#[cfg(has_drtio)]
+pub mod dmac;
 pub mod drtio_routing;
 #[cfg(has_drtio)]
 pub mod drtioaux;
 #[cfg(has_drtio)]
 pub mod drtioaux_async;
 pub mod drtioaux_proto;
+pub mod error_log;
 pub mod fiq;
 #[cfg(feature = "target_kasli_soc")]
 pub mod io_expander;
 pub mod logger;
+pub mod panic_report;
 #[cfg(any(has_drtio, has_cxp_grabber))]
 #[rustfmt::skip]
 #[path = "../../../build/mem.rs"]
@@ -33,21 +39,26 @@ pub mod mem;
 #[rustfmt::skip]
 #[path = "../../../build/pl.rs"]
 pub mod pl;
+pub mod rtio_frequency;
+pub mod startup_io;
+pub mod wall_clock;
 #[cfg(has_drtio_eem)]
 pub mod drtio_eem;
+#[cfg(has_drtio_eyescan)]
+pub mod eye_scan;
 #[cfg(has_grabber)]
 pub mod grabber;
 #[cfg(has_si5324)]
 pub mod si5324;
 #[cfg(has_si549)]
 pub mod si549;
-use alloc::{collections::BTreeMap, string::String};
+use alloc::{collections::BTreeMap, format, string::String};
 use core::{cmp, str};
 
 use byteorder::NativeEndian;
 use io::{Cursor, ProtoRead};
-use libcortex_a9::once_lock::OnceLock;
-use log::warn;
+use libcortex_a9::mutex::Mutex;
+use log::{info, warn};
 
 #[cfg(has_cxp_grabber)]
 pub mod cxp_camera_setup;
@@ -94,49 +105,68 @@ pub fn identifier_read(buf: &mut [u8]) -> &str {
     }
 }
 
-static RTIO_DEVICE_MAP: OnceLock<BTreeMap<u32, String>> = OnceLock::new();
+// replaced as a whole when the device map is reloaded
+static RTIO_DEVICE_MAP: Mutex<BTreeMap<u32, String>> = Mutex::new(BTreeMap::new());
 
-fn read_device_map() -> BTreeMap<u32, String> {
+fn read_device_map() -> Result<BTreeMap<u32, String>, String> {
+    let raw_bytes = libconfig::read("device_map").map_err(|err| format!("{}", err))?;
+    let mut bytes_cr = Cursor::new(raw_bytes);
     let mut device_map: BTreeMap<u32, String> = BTreeMap::new();
-    let _ = libconfig::read("device_map")
-        .and_then(|raw_bytes| {
-            let mut bytes_cr = Cursor::new(raw_bytes);
-            let size = bytes_cr.read_u32::<NativeEndian>().unwrap();
-            for _ in 0..size {
-                let channel = bytes_cr.read_u32::<NativeEndian>().unwrap();
-                let device_name = bytes_cr.read_string::<NativeEndian>().unwrap();
-                if let Some(old_entry) = device_map.insert(channel, device_name.clone()) {
-                    warn!(
-                        "conflicting device map entries for RTIO channel {}: '{}' and '{}'",
-                        channel, old_entry, device_name
-                    );
-                }
-            }
-            Ok(())
-        })
-        .or_else(|err| {
+    let size = bytes_cr
+        .read_u32::<NativeEndian>()
+        .map_err(|_| String::from("truncated entry count"))?;
+    for i in 0..size {
+        let channel = bytes_cr
+            .read_u32::<NativeEndian>()
+            .map_err(|_| format!("truncated entry {}", i))?;
+        let device_name = bytes_cr
+            .read_string::<NativeEndian>()
+            .map_err(|_| format!("malformed name in entry {}", i))?;
+        if let Some(old_entry) = device_map.insert(channel, device_name.clone()) {
             warn!(
-                "error reading device map ({}), device names will not be available in RTIO error messages",
-                err
+                "conflicting device map entries for RTIO channel {}: '{}' and '{}'",
+                channel, old_entry, device_name
             );
-            Err(err)
-        });
-    device_map
+        }
+    }
+    Ok(device_map)
 }
 
 pub fn resolve_channel_name(channel: u32) -> String {
-    match RTIO_DEVICE_MAP
-        .get()
-        .expect("cannot get device map before it is set up")
-        .get(&channel)
-    {
+    match RTIO_DEVICE_MAP.lock().get(&channel) {
         Some(val) => val.clone(),
         None => String::from("unknown"),
     }
 }
 
-pub fn setup_device_map() {
-    RTIO_DEVICE_MAP
-        .set(read_device_map())
-        .expect("device map can only be initialized once");
+/// Describes a channel in RTIO exception messages by its number and device name. A channel
+/// of another destination than the local one is followed by its destination, and by the
+/// link that destination is reached through when known.
+pub fn format_channel_info(channel: u32, local_destination: Option<u8>, link: Option<u8>) -> String {
+    let destination = (channel >> 16) as u8;
+    let mut info = format!("0x{:04x}:{}", channel, resolve_channel_name(channel));
+    if local_destination != Some(destination) {
+        match link {
+            Some(link) => info.push_str(&format!(" (destination {}, link {})", destination, link)),
+            None => info.push_str(&format!(" (destination {})", destination)),
+        }
+    }
+    info
+}
+
+/// Reads the device map from the config, at startup and whenever it is changed,
+/// so that RTIO error messages pick up corrected channel names without a reboot.
+/// A map that cannot be read leaves the one loaded before in place.
+pub fn reload_device_map() {
+    match read_device_map() {
+        Ok(device_map) => {
+            info!("device map loaded with {} channel names", device_map.len());
+            *RTIO_DEVICE_MAP.lock() = device_map;
+        }
+        Err(err) => warn!(
+            "error reading device map ({}), keeping the {} channel names loaded before",
+            err,
+            RTIO_DEVICE_MAP.lock().len()
+        ),
+    }
 }
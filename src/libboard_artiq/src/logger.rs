@@ -1,33 +1,155 @@
-use core::{cell::Cell, fmt::Write};
+use alloc::string::String;
+use core::{cell::Cell,
+           cmp::min,
+           fmt::{self, Write}};
 
-use libboard_zynq::{println, stdio, timer};
+use libboard_zynq::{stdio, timer};
 use libcortex_a9::{mutex::{Mutex, MutexGuard},
                    once_lock::OnceLock};
-use log::{LevelFilter, Log};
+use log::{Level, LevelFilter, Log};
 use log_buffer::LogBuffer;
 
+use crate::console::Console;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogStats {
+    // bytes and messages overwritten before they were read out, since boot
+    pub dropped_bytes: u64,
+    pub dropped_messages: u32,
+}
+
+// The log ring buffer, with the accounting of what it overwrote. Bytes are counted
+// as they are written; messages are counted when the buffer is read or cleared,
+// as those written since the last clear that are no longer complete in the buffer.
+struct Ring {
+    buffer: LogBuffer<&'static mut [u8]>,
+    capacity: usize,
+    used: usize,
+    messages: u32,
+    dropped_bytes: u64,
+    dropped_messages: u32,
+}
+
+impl Ring {
+    fn new(buffer: &'static mut [u8]) -> Ring {
+        Ring {
+            capacity: buffer.len(),
+            buffer: LogBuffer::new(buffer),
+            used: 0,
+            messages: 0,
+            dropped_bytes: 0,
+            dropped_messages: 0,
+        }
+    }
+
+    fn lost_messages(&mut self) -> u32 {
+        let present = self.buffer.extract().matches('\n').count() as u32;
+        self.messages.saturating_sub(present)
+    }
+}
+
+impl Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let overflow = (self.used + s.len()).saturating_sub(self.capacity);
+        self.dropped_bytes += overflow as u64;
+        self.used = min(self.used + s.len(), self.capacity);
+        self.buffer.write_str(s)
+    }
+}
+
+// Selects the log lines a reader gets, by severity and module path prefix. It is applied
+// when the log is read out, so the levels at which messages are buffered stay the same.
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    pub max_level: LevelFilter,
+    pub module_prefix: String,
+}
+
+impl LogFilter {
+    pub fn all() -> LogFilter {
+        LogFilter {
+            max_level: LevelFilter::Trace,
+            module_prefix: String::new(),
+        }
+    }
+
+    // levels are carried as bytes, from 0 (off) to 5 (trace)
+    pub fn level_from_u8(level: u8) -> LevelFilter {
+        match level {
+            0 => LevelFilter::Off,
+            1 => LevelFilter::Error,
+            2 => LevelFilter::Warn,
+            3 => LevelFilter::Info,
+            4 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
+    pub fn level_to_u8(level: LevelFilter) -> u8 {
+        level as usize as u8
+    }
+
+    fn parse_header(line: &str) -> Option<(Level, &str)> {
+        // "[     1.234567s]  INFO(runtime::comms): message"
+        let rest = line.strip_prefix('[')?;
+        let rest = rest[rest.find("] ")? + 2..].trim_start();
+        let open = rest.find('(')?;
+        let level = rest[..open].parse::<Level>().ok()?;
+        let target = &rest[open + 1..];
+        Some((level, &target[..target.find("): ")?]))
+    }
+
+    pub fn apply(&self, log: &str) -> String {
+        if self.max_level == LevelFilter::Trace && self.module_prefix.is_empty() {
+            return String::from(log);
+        }
+        let mut filtered = String::new();
+        // lines without a header continue a multi-line message and go along with it
+        let mut keep = false;
+        for line in log.split_inclusive('\n') {
+            if let Some((level, target)) = LogFilter::parse_header(line) {
+                keep = level <= self.max_level && target.starts_with(self.module_prefix.as_str());
+            }
+            if keep {
+                filtered.push_str(line);
+            }
+        }
+        filtered
+    }
+}
+
 pub struct LogBufferRef<'a> {
-    buffer: MutexGuard<'a, LogBuffer<&'static mut [u8]>>,
+    ring: MutexGuard<'a, Ring>,
     old_log_level: LevelFilter,
 }
 
 impl<'a> LogBufferRef<'a> {
-    fn new(buffer: MutexGuard<'a, LogBuffer<&'static mut [u8]>>) -> LogBufferRef<'a> {
+    fn new(ring: MutexGuard<'a, Ring>) -> LogBufferRef<'a> {
         let old_log_level = BufferLogger::get_logger().buffer_log_level();
         BufferLogger::get_logger().set_buffer_log_level(LevelFilter::Off);
-        LogBufferRef { buffer, old_log_level }
+        LogBufferRef { ring, old_log_level }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.buffer.is_empty()
+        self.ring.buffer.is_empty()
     }
 
     pub fn clear(&mut self) {
-        self.buffer.clear()
+        self.ring.dropped_messages += self.ring.lost_messages();
+        self.ring.messages = 0;
+        self.ring.used = 0;
+        self.ring.buffer.clear()
     }
 
     pub fn extract(&mut self) -> &str {
-        self.buffer.extract()
+        self.ring.buffer.extract()
+    }
+
+    pub fn stats(&mut self) -> LogStats {
+        LogStats {
+            dropped_bytes: self.ring.dropped_bytes,
+            dropped_messages: self.ring.dropped_messages + self.ring.lost_messages(),
+        }
     }
 }
 
@@ -38,7 +160,7 @@ impl<'a> Drop for LogBufferRef<'a> {
 }
 
 pub struct BufferLogger {
-    buffer: Mutex<LogBuffer<&'static mut [u8]>>,
+    ring: Mutex<Ring>,
     uart_filter: Cell<LevelFilter>,
     buffer_filter: Cell<LevelFilter>,
 }
@@ -48,7 +170,7 @@ static LOGGER: OnceLock<BufferLogger> = OnceLock::new();
 impl BufferLogger {
     pub fn new(buffer: &'static mut [u8]) -> BufferLogger {
         BufferLogger {
-            buffer: Mutex::new(LogBuffer::new(buffer)),
+            ring: Mutex::new(Ring::new(buffer)),
             uart_filter: Cell::new(LevelFilter::Info),
             buffer_filter: Cell::new(LevelFilter::Info),
         }
@@ -64,7 +186,19 @@ impl BufferLogger {
     }
 
     pub fn buffer<'a>(&'a self) -> Option<LogBufferRef<'a>> {
-        self.buffer.try_lock().map(LogBufferRef::new)
+        self.ring.try_lock().map(LogBufferRef::new)
+    }
+
+    /// Moves the log to a new buffer, keeping as much of its content as fits.
+    pub fn set_buffer(&self, buffer: &'static mut [u8]) {
+        let mut ring = self.ring.lock();
+        let lost = ring.lost_messages();
+        let mut resized = Ring::new(buffer);
+        resized.dropped_bytes = ring.dropped_bytes;
+        resized.dropped_messages = ring.dropped_messages + lost;
+        resized.messages = ring.messages - lost;
+        let _ = resized.write_str(ring.buffer.extract());
+        *ring = resized;
     }
 
     pub fn uart_log_level(&self) -> LevelFilter {
@@ -110,9 +244,9 @@ impl Log for BufferLogger {
             let micros = timestamp % 1_000_000;
 
             if record.level() <= self.buffer_log_level() {
-                let mut buffer = self.buffer.lock();
+                let mut ring = self.ring.lock();
                 writeln!(
-                    buffer,
+                    ring,
                     "[{:6}.{:06}s] {:>5}({}): {}",
                     seconds,
                     micros,
@@ -121,17 +255,20 @@ impl Log for BufferLogger {
                     record.args()
                 )
                 .unwrap();
+                ring.messages += 1;
             }
 
             if record.level() <= self.uart_log_level() {
-                println!(
+                writeln!(
+                    Console,
                     "[{:6}.{:06}s] {:>5}({}): {}",
                     seconds,
                     micros,
                     record.level(),
                     record.target(),
                     record.args()
-                );
+                )
+                .unwrap();
             }
         }
     }
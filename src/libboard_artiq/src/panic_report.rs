@@ -0,0 +1,130 @@
+use alloc::string::String;
+use core::{fmt::Write,
+           mem::MaybeUninit,
+           panic::PanicInfo,
+           ptr,
+           sync::atomic::{AtomicBool, Ordering}};
+
+use crc::crc32;
+use libboard_zynq::println;
+use libconfig;
+use log::{error, warn};
+
+use crate::config_store;
+
+// A panic is written to the config before the firmware stops, so that it can be
+// looked at after the reboot. On the next boot it is logged and moved to `last_panic`,
+// where it stays until the next panic replaces it.
+// The config is only written from core 0 once it is initialized and not read-only: a panic on
+// core 1 could meet core 0 in the middle of a write. Otherwise the report is only kept
+// in a RAM section that is neither loaded nor cleared at boot, and survives a reset.
+
+const PENDING_KEY: &str = "panic_report";
+pub const LAST_PANIC_KEY: &str = "last_panic";
+const MAX_FRAMES: usize = 16;
+
+const RETAINED_MAGIC: u32 = 0x5041_4e43;
+const RETAINED_CAPACITY: usize = 1024;
+
+struct Retained {
+    magic: u32,
+    length: u32,
+    checksum: u32,
+    text: [u8; RETAINED_CAPACITY],
+}
+
+#[link_section = ".uninit"]
+static mut RETAINED: MaybeUninit<Retained> = MaybeUninit::uninit();
+
+static CONFIG_READY: AtomicBool = AtomicBool::new(false);
+
+fn retain(text: &[u8]) {
+    let length = text.len().min(RETAINED_CAPACITY);
+    unsafe {
+        let retained = ptr::addr_of_mut!(RETAINED) as *mut Retained;
+        (*retained).text[..length].copy_from_slice(&text[..length]);
+        (*retained).length = length as u32;
+        (*retained).checksum = crc32::checksum_ieee(&text[..length]);
+        (*retained).magic = RETAINED_MAGIC;
+    }
+}
+
+fn take_retained() -> Option<String> {
+    unsafe {
+        let retained = ptr::addr_of_mut!(RETAINED) as *mut Retained;
+        // anything but the magic is garbage left from power-up
+        if (*retained).magic != RETAINED_MAGIC {
+            return None;
+        }
+        (*retained).magic = 0;
+        let length = ((*retained).length as usize).min(RETAINED_CAPACITY);
+        let text = &(*retained).text[..length];
+        if crc32::checksum_ieee(text) != (*retained).checksum {
+            return None;
+        }
+        Some(String::from_utf8_lossy(text).into_owned())
+    }
+}
+
+pub struct PanicReport {
+    core: usize,
+    text: String,
+    frames: usize,
+}
+
+impl PanicReport {
+    pub fn new(core: usize, info: &PanicInfo) -> PanicReport {
+        let mut text = String::new();
+        let _ = write!(text, "core {} panic at ", core);
+        let _ = match info.location() {
+            Some(location) => write!(text, "{}:{}:{}", location.file(), location.line(), location.column()),
+            None => write!(text, "unknown location"),
+        };
+        let _ = write!(text, ": {}\nbacktrace:", info.message());
+        PanicReport {
+            core: core,
+            text: text,
+            frames: 0,
+        }
+    }
+
+    pub fn add_frame(&mut self, ip: usize) {
+        if self.frames < MAX_FRAMES {
+            let _ = write!(self.text, " {:#08x}", ip);
+            self.frames += 1;
+        }
+    }
+
+    pub fn save(&self) {
+        retain(self.text.as_bytes());
+        if self.core != 0 || !CONFIG_READY.load(Ordering::Relaxed) || config_store::read_only() {
+            return;
+        }
+        if config_store::write(PENDING_KEY, self.text.as_bytes().to_vec()).is_err() {
+            println!("failed to save the panic report");
+        }
+    }
+}
+
+/// Logs the report of a panic before the last reboot and keeps it as `last_panic`.
+/// To be called after the config initialization, `config_ready` if it succeeded.
+pub fn report_previous(config_ready: bool) {
+    CONFIG_READY.store(config_ready, Ordering::Relaxed);
+    let retained = take_retained();
+    let text = match libconfig::read_str(PENDING_KEY) {
+        // the same panic, if it could also be written to the config
+        Ok(text) => text,
+        Err(_) => match retained {
+            Some(text) => text,
+            None => return,
+        },
+    };
+    error!("firmware panicked before the last reboot: {}", text);
+    if !config_ready {
+        warn!("config is not available, the panic report is not kept as {}", LAST_PANIC_KEY);
+    } else if config_store::write(LAST_PANIC_KEY, text.into_bytes()).is_err()
+        || (libconfig::read(PENDING_KEY).is_ok() && config_store::remove(PENDING_KEY).is_err())
+    {
+        warn!("failed to move the panic report to {}", LAST_PANIC_KEY);
+    }
+}
@@ -0,0 +1,100 @@
+use libconfig;
+use libcortex_a9::once_lock::OnceLock;
+use log::{error, info, warn};
+
+#[cfg(feature = "target_kasli_soc")]
+use crate::pl::csr;
+
+// RTIO clock frequency, selected at boot with the `rtio_frequency` config key ("100" or "125")
+// rather than fixed at build time. The build-time frequency is the default; a setting
+// that disagrees with the sys clock reported by the gateware is rejected.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RtioFrequency {
+    Mhz100,
+    Mhz125,
+}
+
+impl RtioFrequency {
+    pub fn mhz(self) -> u32 {
+        match self {
+            RtioFrequency::Mhz100 => 100,
+            RtioFrequency::Mhz125 => 125,
+        }
+    }
+
+    fn from_hz(hz: u32) -> Option<RtioFrequency> {
+        match hz {
+            100_000_000 => Some(RtioFrequency::Mhz100),
+            125_000_000 => Some(RtioFrequency::Mhz125),
+            _ => None,
+        }
+    }
+}
+
+static FREQUENCY: OnceLock<RtioFrequency> = OnceLock::new();
+
+fn build_default() -> RtioFrequency {
+    if cfg!(rtio_frequency = "100.0") {
+        RtioFrequency::Mhz100
+    } else {
+        RtioFrequency::Mhz125
+    }
+}
+
+fn gateware_frequency() -> Option<RtioFrequency> {
+    #[cfg(feature = "target_kasli_soc")]
+    {
+        RtioFrequency::from_hz(csr::CONFIG_CLOCK_FREQUENCY)
+    }
+    // the other gateware does not report its clock, the frequency it was built for stands in
+    #[cfg(not(feature = "target_kasli_soc"))]
+    {
+        if cfg!(rtio_frequency = "100.0") {
+            Some(RtioFrequency::Mhz100)
+        } else if cfg!(rtio_frequency = "125.0") {
+            Some(RtioFrequency::Mhz125)
+        } else {
+            None
+        }
+    }
+}
+
+fn load() -> RtioFrequency {
+    let default = gateware_frequency().unwrap_or_else(build_default);
+    let selected = match libconfig::read_str("rtio_frequency") {
+        Ok(setting) => match setting.trim() {
+            "100" => RtioFrequency::Mhz100,
+            "125" => RtioFrequency::Mhz125,
+            _ => {
+                warn!("unsupported rtio_frequency setting '{}', using {} MHz", setting, default.mhz());
+                return default;
+            }
+        },
+        Err(_) => return default,
+    };
+    match gateware_frequency() {
+        Some(gateware) if gateware != selected => {
+            error!(
+                "rtio_frequency {} MHz does not match the {} MHz sys clock of the gateware, ignoring",
+                selected.mhz(),
+                gateware.mhz()
+            );
+            gateware
+        }
+        _ => {
+            info!("RTIO frequency set to {} MHz", selected.mhz());
+            selected
+        }
+    }
+}
+
+// only valid once the config has been initialized
+pub fn get() -> RtioFrequency {
+    if let Some(frequency) = FREQUENCY.get() {
+        return *frequency;
+    }
+    let frequency = load();
+    let _ = FREQUENCY.set(frequency);
+    frequency
+}
@@ -1,8 +1,10 @@
-use core::result;
+use core::{result,
+           sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering}};
 
 use libboard_zynq::{i2c::{Error as I2cError, I2c},
                     timer};
-use log::info;
+use libconfig;
+use log::{info, warn};
 
 #[cfg(not(si5324_soft_reset))]
 use crate::pl::csr;
@@ -287,6 +289,119 @@ pub fn setup(i2c: &mut I2c, settings: &FrequencySettings, input: Input) -> Resul
     Ok(())
 }
 
+// Holdover monitoring. The LOS and LOL flags of the Si5324 are sticky, so polling them
+// catches reference dropouts between polls; they are counted and cleared on each poll.
+// Losing the active input puts the chip in digital hold, or with `si5324_auto_switch`
+// set to 1, makes it switch to the other input (revertive).
+// firmware polls at this interval from its main loop
+pub const POLL_INTERVAL_MS: u64 = 1000;
+
+static MONITORED: AtomicBool = AtomicBool::new(false);
+static LOS: AtomicBool = AtomicBool::new(false);
+static LOL: AtomicBool = AtomicBool::new(false);
+static HOLDOVER: AtomicBool = AtomicBool::new(false);
+// CK_ACTV bits of the input last seen active
+static ACTIVE_INPUT: AtomicU8 = AtomicU8::new(0);
+static LOS_EVENTS: AtomicU32 = AtomicU32::new(0);
+static LOL_EVENTS: AtomicU32 = AtomicU32::new(0);
+static HOLDOVER_EVENTS: AtomicU32 = AtomicU32::new(0);
+static INPUT_SWITCHES: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Status {
+    // configured for holdover and polled
+    pub monitored: bool,
+    // loss of signal on an input, loss of lock, in digital hold
+    pub los: bool,
+    pub lol: bool,
+    pub holdover: bool,
+    // since boot
+    pub los_events: u32,
+    pub lol_events: u32,
+    pub holdover_events: u32,
+    pub input_switches: u32,
+}
+
+pub fn configure_holdover(i2c: &mut I2c) -> Result<()> {
+    let auto_switch = match libconfig::read_str("si5324_auto_switch") {
+        Ok(value) => match value.as_ref() {
+            "1" => true,
+            "0" => false,
+            _ => {
+                warn!("si5324_auto_switch \"{}\" not supported, ignoring", value);
+                false
+            }
+        },
+        Err(_) => false,
+    };
+    i2c_mux_setup(i2c)?;
+    rmw(i2c, 3, |v| v & 0xdf)?; // DHOLD=0, hold only when the input is lost
+    let autosel_reg = if auto_switch { 0b10 } else { 0b00 };
+    rmw(i2c, 4, |v| (v & 0x3f) | (autosel_reg << 6))?; // AUTOSEL_REG
+    // flags raised while locking
+    write(i2c, 131, 0)?; // LOS2_FLG=0 LOS1_FLG=0 LOSX_FLG=0
+    write(i2c, 132, 0)?; // FOS2_FLG=0 FOS1_FLG=0 LOL_FLG=0
+    ACTIVE_INPUT.store(read(i2c, 128)? & 0x03, Ordering::Relaxed); // CK2_ACTV_REG, CK1_ACTV_REG
+    MONITORED.store(true, Ordering::Relaxed);
+    if auto_switch {
+        info!("Si5324 switches inputs automatically on loss of signal");
+    }
+    Ok(())
+}
+
+pub fn poll(i2c: &mut I2c) -> Result<Status> {
+    if MONITORED.load(Ordering::Relaxed) {
+        i2c_mux_setup(i2c)?;
+        let los = read(i2c, 129)? & 0x06 != 0; // LOS2_INT, LOS1_INT
+        let lol = read(i2c, 130)? & 0x01 != 0; // LOL_INT
+        let los_flags = read(i2c, 131)? & 0x06; // LOS2_FLG, LOS1_FLG
+        let lol_flag = read(i2c, 132)? & 0x02 != 0; // LOL_FLG
+        let active = read(i2c, 128)? & 0x03;
+        if los_flags != 0 {
+            write(i2c, 131, 0)?;
+            LOS_EVENTS.fetch_add(1, Ordering::Relaxed);
+            warn!("Si5324 lost an input signal");
+        }
+        if lol_flag {
+            write(i2c, 132, 0)?;
+            LOL_EVENTS.fetch_add(1, Ordering::Relaxed);
+            warn!("Si5324 lost lock");
+        }
+        // no input active: the output is held
+        let holdover = active == 0;
+        if holdover && !HOLDOVER.load(Ordering::Relaxed) {
+            HOLDOVER_EVENTS.fetch_add(1, Ordering::Relaxed);
+            warn!("Si5324 entered holdover");
+        }
+        let last_active = ACTIVE_INPUT.load(Ordering::Relaxed);
+        if active != 0 {
+            if last_active != 0 && active != last_active {
+                INPUT_SWITCHES.fetch_add(1, Ordering::Relaxed);
+                info!("Si5324 switched to CKIN{}", if active & 0x01 != 0 { 1 } else { 2 });
+            }
+            ACTIVE_INPUT.store(active, Ordering::Relaxed);
+        }
+        LOS.store(los, Ordering::Relaxed);
+        LOL.store(lol, Ordering::Relaxed);
+        HOLDOVER.store(holdover, Ordering::Relaxed);
+    }
+    Ok(status())
+}
+
+// as of the last poll
+pub fn status() -> Status {
+    Status {
+        monitored: MONITORED.load(Ordering::Relaxed),
+        los: LOS.load(Ordering::Relaxed),
+        lol: LOL.load(Ordering::Relaxed),
+        holdover: HOLDOVER.load(Ordering::Relaxed),
+        los_events: LOS_EVENTS.load(Ordering::Relaxed),
+        lol_events: LOL_EVENTS.load(Ordering::Relaxed),
+        holdover_events: HOLDOVER_EVENTS.load(Ordering::Relaxed),
+        input_switches: INPUT_SWITCHES.load(Ordering::Relaxed),
+    }
+}
+
 pub fn select_input(i2c: &mut I2c, input: Input) -> Result<()> {
     let cksel_reg = match input {
         Input::Ckin1 => 0b00,
@@ -312,6 +427,8 @@ pub mod siphaser {
         unsafe {
             csr::siphaser::switch_clocks_write(if rc { 1 } else { 0 });
         }
+        // the source of CKIN1 changes
+        INPUT_SWITCHES.fetch_add(1, Ordering::Relaxed);
         let val = read(i2c, 3)?;
         write(i2c, 3, (val & 0xdf) | (0 << 5))?; // DHOLD=0
         monitor_lock(i2c)?;
@@ -327,13 +327,39 @@ fn set_adpll(dcxo: i2c::DCXO, adpll: i32) -> Result<(), &'static str> {
 #[cfg(has_wrpll)]
 pub mod wrpll {
 
+    use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+    use log::{error, warn};
+
     use super::*;
+    use crate::rtio_frequency::{self, RtioFrequency};
 
     const BEATING_PERIOD: i32 = 0x8000;
     const BEATING_HALFPERIOD: i32 = 0x4000;
     const COUNTER_WIDTH: u32 = 24;
     const DIV_WIDTH: u32 = 2;
 
+    // There is no lock indicator in the gateware. The loops are taken as unlocked when no
+    // reference tag came in since the last check, or when the main loop phase error went
+    // beyond a quarter of the beating period.
+    const UNLOCK_PHASE_ERROR: u32 = (BEATING_HALFPERIOD / 2) as u32;
+
+    static MONITORED: AtomicBool = AtomicBool::new(false);
+    static LOCKED: AtomicBool = AtomicBool::new(false);
+    static REF_TAGS: AtomicU32 = AtomicU32::new(0);
+    static MAX_PHASE_ERROR: AtomicU32 = AtomicU32::new(0);
+    static UNLOCKS: AtomicU32 = AtomicU32::new(0);
+    static RECOVERIES: AtomicU32 = AtomicU32::new(0);
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct LockStatus {
+        // the recovered clock is selected and checked
+        pub monitored: bool,
+        pub locked: bool,
+        pub unlocks: u32,
+        pub recoveries: u32,
+    }
+
     // y[n] = b0*x[n] + b1*x[n-1] + b2*x[n-2] - a1*y[n-1] - a2*y[n-2]
     struct FilterParameters {
         pub b0: f64,
@@ -343,8 +369,7 @@ pub mod wrpll {
         pub a2: f64,
     }
 
-    #[cfg(rtio_frequency = "100.0")]
-    const LPF: FilterParameters = FilterParameters {
+    const LPF_100: FilterParameters = FilterParameters {
         b0: 0.03967479060647884,
         b1: 0.07934958121295768,
         b2: 0.03967479060647884,
@@ -352,8 +377,7 @@ pub mod wrpll {
         a2: 0.5452585365488082,
     };
 
-    #[cfg(rtio_frequency = "125.0")]
-    const LPF: FilterParameters = FilterParameters {
+    const LPF_125: FilterParameters = FilterParameters {
         b0: 0.07209205036273991,
         b1: 0.14418410072547982,
         b2: 0.07209205036273991,
@@ -361,6 +385,13 @@ pub mod wrpll {
         a2: -0.10022394739274834,
     };
 
+    fn lpf() -> &'static FilterParameters {
+        match rtio_frequency::get() {
+            RtioFrequency::Mhz100 => &LPF_100,
+            RtioFrequency::Mhz125 => &LPF_125,
+        }
+    }
+
     static mut H_ADPLL1: i32 = 0;
     static mut H_ADPLL2: i32 = 0;
     static mut PERIOD_ERR1: i32 = 0;
@@ -531,6 +562,7 @@ pub mod wrpll {
 
     pub fn interrupt_handler() {
         if is_pending(ISR::RefTag) {
+            REF_TAGS.fetch_add(1, Ordering::Relaxed);
             tag_collector::collect_tags(ISR::RefTag);
             clear_pending(ISR::RefTag);
             helper_pll().expect("failed to run helper DCXO PLL");
@@ -549,10 +581,11 @@ pub mod wrpll {
 
     fn helper_pll() -> Result<(), &'static str> {
         let period_err = tag_collector::get_period_error();
+        let lpf = lpf();
         unsafe {
-            let adpll = ((LPF.b0 * period_err as f64) + (LPF.b1 * PERIOD_ERR1 as f64) + (LPF.b2 * PERIOD_ERR2 as f64)
-                - (LPF.a1 * H_ADPLL1 as f64)
-                - (LPF.a2 * H_ADPLL2 as f64)) as i32;
+            let adpll = ((lpf.b0 * period_err as f64) + (lpf.b1 * PERIOD_ERR1 as f64) + (lpf.b2 * PERIOD_ERR2 as f64)
+                - (lpf.a1 * H_ADPLL1 as f64)
+                - (lpf.a2 * H_ADPLL2 as f64)) as i32;
             set_adpll(i2c::DCXO::Helper, BASE_ADPLL + adpll)?;
             H_ADPLL2 = H_ADPLL1;
             PERIOD_ERR2 = PERIOD_ERR1;
@@ -564,10 +597,12 @@ pub mod wrpll {
 
     fn main_pll() -> Result<(), &'static str> {
         let phase_err = tag_collector::get_phase_error();
+        MAX_PHASE_ERROR.fetch_max(phase_err.unsigned_abs(), Ordering::Relaxed);
+        let lpf = lpf();
         unsafe {
-            let adpll = ((LPF.b0 * phase_err as f64) + (LPF.b1 * PHASE_ERR1 as f64) + (LPF.b2 * PHASE_ERR2 as f64)
-                - (LPF.a1 * M_ADPLL1 as f64)
-                - (LPF.a2 * M_ADPLL2 as f64)) as i32;
+            let adpll = ((lpf.b0 * phase_err as f64) + (lpf.b1 * PHASE_ERR1 as f64) + (lpf.b2 * PHASE_ERR2 as f64)
+                - (lpf.a1 * M_ADPLL1 as f64)
+                - (lpf.a2 * M_ADPLL2 as f64)) as i32;
             set_adpll(i2c::DCXO::Main, BASE_ADPLL + adpll)?;
             M_ADPLL2 = M_ADPLL1;
             PHASE_ERR2 = PHASE_ERR1;
@@ -684,29 +719,78 @@ pub mod wrpll {
         Ok(())
     }
 
-    pub fn select_recovered_clock(rc: bool) {
-        set_isr(false);
+    fn acquire() -> Result<(), &'static str> {
+        tag_collector::reset();
+        reset_plls()?;
 
-        if rc {
-            tag_collector::reset();
-            reset_plls().expect("failed to reset main and helper PLL");
+        // get within capture range
+        set_base_adpll()?;
 
-            // get within capture range
-            set_base_adpll().expect("failed to set base adpll");
+        // clear gateware pending flag
+        clear_pending(ISR::RefTag);
+        clear_pending(ISR::MainTag);
 
-            // clear gateware pending flag
-            clear_pending(ISR::RefTag);
-            clear_pending(ISR::MainTag);
+        // use nFIQ to avoid IRQ being disabled by mutex lock and mess up PLL
+        set_isr(true);
+        info!("WRPLL interrupt enabled");
 
-            // use nFIQ to avoid IRQ being disabled by mutex lock and mess up PLL
-            set_isr(true);
-            info!("WRPLL interrupt enabled");
+        #[cfg(feature = "calibrate_wrpll_skew")]
+        calibrate_skew()?;
 
-            #[cfg(feature = "calibrate_wrpll_skew")]
-            calibrate_skew().expect("failed to set the correct skew");
+        #[cfg(wrpll_ref_clk = "GT_CDR")]
+        test_skew()?;
 
-            #[cfg(wrpll_ref_clk = "GT_CDR")]
-            test_skew().expect("skew test failed");
+        REF_TAGS.store(0, Ordering::Relaxed);
+        MAX_PHASE_ERROR.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn select_recovered_clock(rc: bool) {
+        set_isr(false);
+
+        if rc {
+            acquire().expect("failed to acquire the recovered clock");
+        }
+        MONITORED.store(rc, Ordering::Relaxed);
+        LOCKED.store(rc, Ordering::Relaxed);
+    }
+
+    // To be called periodically while the recovered clock is selected, at intervals
+    // spanning many reference tags. Re-acquires the clock when the loops lost lock.
+    pub fn check_lock() {
+        if !MONITORED.load(Ordering::Relaxed) {
+            return;
+        }
+        let ref_tags = REF_TAGS.swap(0, Ordering::Relaxed);
+        let max_phase_error = MAX_PHASE_ERROR.swap(0, Ordering::Relaxed);
+        if ref_tags > 0 && max_phase_error <= UNLOCK_PHASE_ERROR {
+            LOCKED.store(true, Ordering::Relaxed);
+            return;
+        }
+        if LOCKED.swap(false, Ordering::Relaxed) {
+            let unlocks = UNLOCKS.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                "WRPLL lost lock ({} reference tags, phase error {}), unlock #{}",
+                ref_tags, max_phase_error, unlocks
+            );
+        }
+        set_isr(false);
+        match acquire() {
+            Ok(()) => {
+                RECOVERIES.fetch_add(1, Ordering::Relaxed);
+                info!("WRPLL re-acquired the recovered clock");
+            }
+            // retried at the next check
+            Err(e) => error!("failed to re-acquire the recovered clock: {}", e),
+        }
+    }
+
+    pub fn lock_status() -> LockStatus {
+        LockStatus {
+            monitored: MONITORED.load(Ordering::Relaxed),
+            locked: LOCKED.load(Ordering::Relaxed),
+            unlocks: UNLOCKS.load(Ordering::Relaxed),
+            recoveries: RECOVERIES.load(Ordering::Relaxed),
         }
     }
 }
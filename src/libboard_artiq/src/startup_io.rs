@@ -0,0 +1,124 @@
+use alloc::vec::Vec;
+
+use libconfig;
+use log::{info, warn};
+
+#[cfg(feature = "target_kasli_soc")]
+use crate::io_expander::IoExpander;
+use crate::pl::csr;
+
+// Safe pin states applied at boot, before any kernel runs, from the `startup_io_state`
+// config key. Entries are separated by commas:
+//   ttl<channel>=0|1          override of a local RTIO TTL output through moninj injection
+//   sfp<n>=0|1                transmitter of SFP<n> enabled or disabled
+//   exp<n>:<port>.<bit>=0|1   any output pin of I/O expander <n>
+
+// TTL PHY injection overrides
+const TTL_OVERRIDE_EN: u8 = 0;
+const TTL_OVERRIDE_O: u8 = 1;
+
+#[derive(Debug, Clone, Copy)]
+pub enum PinState {
+    Ttl { channel: u16, high: bool },
+    Expander { index: u8, port: u8, bit: u8, high: bool },
+}
+
+fn parse_level(value: &str) -> Option<bool> {
+    match value {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None,
+    }
+}
+
+fn parse_entry(entry: &str) -> Option<PinState> {
+    let mut parts = entry.splitn(2, '=');
+    let pin = parts.next()?.trim();
+    let high = parse_level(parts.next()?.trim())?;
+    if let Some(channel) = pin.strip_prefix("ttl") {
+        Some(PinState::Ttl {
+            channel: channel.parse().ok()?,
+            high: high,
+        })
+    } else if let Some(sfp) = pin.strip_prefix("sfp") {
+        // TX_DISABLE lines, in the order they are wired to the expanders
+        let (index, port) = match sfp {
+            "0" => (0, 0),
+            "1" => (1, 0),
+            "2" => (0, 1),
+            "3" => (1, 1),
+            _ => return None,
+        };
+        Some(PinState::Expander {
+            index: index,
+            port: port,
+            bit: 1,
+            high: !high,
+        })
+    } else if let Some(expander) = pin.strip_prefix("exp") {
+        let mut parts = expander.splitn(2, ':');
+        let index: u8 = parts.next()?.parse().ok()?;
+        let mut parts = parts.next()?.splitn(2, '.');
+        let port: u8 = parts.next()?.parse().ok()?;
+        let bit: u8 = parts.next()?.parse().ok()?;
+        if index > 1 || port > 1 || bit > 7 {
+            return None;
+        }
+        Some(PinState::Expander {
+            index: index,
+            port: port,
+            bit: bit,
+            high: high,
+        })
+    } else {
+        None
+    }
+}
+
+pub fn load() -> Vec<PinState> {
+    let mut states = Vec::new();
+    if let Ok(config) = libconfig::read_str("startup_io_state") {
+        for entry in config.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+            match parse_entry(entry) {
+                Some(state) => states.push(state),
+                None => warn!("startup_io_state entry \"{}\" not supported, ignoring", entry),
+            }
+        }
+        info!("loaded {} startup I/O state(s)", states.len());
+    }
+    states
+}
+
+#[cfg(feature = "target_kasli_soc")]
+pub fn apply_expanders(states: &[PinState], io_expander0: &mut IoExpander, io_expander1: &mut IoExpander) {
+    // takes effect on the next expander service
+    for state in states {
+        if let PinState::Expander {
+            index,
+            port,
+            bit,
+            high,
+        } = *state
+        {
+            match index {
+                0 => io_expander0.set(port, bit, high),
+                _ => io_expander1.set(port, bit, high),
+            }
+        }
+    }
+}
+
+pub fn apply_ttl(states: &[PinState]) {
+    for state in states {
+        if let PinState::Ttl { channel, high } = *state {
+            unsafe {
+                csr::rtio_moninj::inj_chan_sel_write(channel as _);
+                // set the level first, so the output does not glitch when the override is enabled
+                csr::rtio_moninj::inj_override_sel_write(TTL_OVERRIDE_O);
+                csr::rtio_moninj::inj_value_write(high as _);
+                csr::rtio_moninj::inj_override_sel_write(TTL_OVERRIDE_EN);
+                csr::rtio_moninj::inj_value_write(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use libboard_zynq::timer;
+use libcortex_a9::mutex::Mutex;
+
+// Wall-clock time in microseconds since the Unix epoch, set by the host on the master
+// and pushed by the master to the satellites over DRTIO. It is kept as an offset
+// from the local timer, so it drifts along with the timer until the next update.
+// The generation counts updates, so that the master can tell which satellites
+// have not been sent the latest one yet.
+
+struct State {
+    offset_us: Option<i64>,
+    generation: u32,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    offset_us: None,
+    generation: 0,
+});
+
+pub fn set(unix_us: u64) {
+    let mut state = STATE.lock();
+    state.offset_us = Some(unix_us as i64 - timer::get_us() as i64);
+    state.generation = state.generation.wrapping_add(1);
+}
+
+pub fn get() -> Option<u64> {
+    STATE
+        .lock()
+        .offset_us
+        .map(|offset_us| (timer::get_us() as i64 + offset_us) as u64)
+}
+
+pub fn generation() -> u32 {
+    STATE.lock().generation
+}
@@ -48,6 +48,12 @@ SECTIONS
         __bss_end = .;
     } > SDRAM
 
+    /* neither loaded nor cleared, keeps its content across a reset */
+    .uninit (NOLOAD) : ALIGN(4)
+    {
+        *(.uninit .uninit.*);
+    } > SDRAM
+
     .heap (NOLOAD) : ALIGN(8)
     {
         __heap0_start = .;
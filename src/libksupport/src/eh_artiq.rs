@@ -12,6 +12,7 @@
 // except according to those terms.
 #![allow(non_camel_case_types)]
 
+use alloc::vec::Vec;
 use core::mem;
 
 use byteorder::NativeEndian;
@@ -118,6 +119,22 @@ static mut EXCEPTION_BUFFER: ExceptionBuffer = ExceptionBuffer {
     exception_count: 0,
 };
 
+// Exceptions raised on another device before the one a subkernel was terminated by, which was
+// passed on to this kernel. They point into the serialized exception they were read from.
+struct RemoteChain {
+    _raw: Vec<u8>,
+    exceptions: Vec<Exception<'static>>,
+}
+
+// indexed like EXCEPTION_BUFFER.exceptions, set for the exceptions passed on from subkernels
+static mut REMOTE_CHAINS: [Option<RemoteChain>; MAX_INFLIGHT_EXCEPTIONS + 1] =
+    [const { None }; MAX_INFLIGHT_EXCEPTIONS + 1];
+// handed from raise_raw to raise
+static mut PENDING_CHAIN: Option<RemoteChain> = None;
+// uncaught exceptions with their remote chains, reported instead of the buffer if there are chains
+static mut CHAINED_EXCEPTIONS: Vec<Option<Exception<'static>>> = Vec::new();
+static mut CHAINED_STACK_POINTERS: Vec<StackPointerBacktrace> = Vec::new();
+
 pub unsafe extern "C" fn reset_exception_buffer() {
     trace!("reset exception buffer");
     EXCEPTION_BUFFER.uw_exceptions = [const {
@@ -131,6 +148,24 @@ pub unsafe extern "C" fn reset_exception_buffer() {
     EXCEPTION_BUFFER.exception_stack = [-1; MAX_INFLIGHT_EXCEPTIONS + 1];
     EXCEPTION_BUFFER.backtrace_size = 0;
     EXCEPTION_BUFFER.exception_count = 0;
+    for chain in REMOTE_CHAINS.iter_mut() {
+        *chain = None;
+    }
+    PENDING_CHAIN = None;
+    CHAINED_EXCEPTIONS.clear();
+    CHAINED_STACK_POINTERS.clear();
+}
+
+/// Called when core 1 starts, before any kernel. The remote chains left by the previous
+/// run of core 1 were allocated from a heap that has been set up again since, so they
+/// are forgotten rather than dropped into it.
+pub unsafe fn forget_remote_chains() {
+    for chain in REMOTE_CHAINS.iter_mut() {
+        mem::forget(chain.take());
+    }
+    mem::forget(PENDING_CHAIN.take());
+    mem::forget(mem::take(&mut CHAINED_EXCEPTIONS));
+    mem::forget(mem::take(&mut CHAINED_STACK_POINTERS));
 }
 
 type _Unwind_Stop_Fn = extern "C" fn(
@@ -268,6 +303,7 @@ pub unsafe extern "C" fn raise(exception: *const Exception) -> ! {
                     *slot = Some(*mem::transmute::<*const Exception, *const Exception<'static>>(
                         exception,
                     ));
+                    REMOTE_CHAINS[i] = PENDING_CHAIN.take();
                     EXCEPTION_BUFFER.exception_stack[count] = i as isize;
                     EXCEPTION_BUFFER.uw_exceptions[i].private = [0; uw::unwinder_private_data_size];
                     EXCEPTION_BUFFER.stack_pointers[i] = StackPointerBacktrace {
@@ -282,6 +318,7 @@ pub unsafe extern "C" fn raise(exception: *const Exception) -> ! {
             }
         } else {
             error!("too many nested exceptions");
+            PENDING_CHAIN = None;
             // TODO: better reporting?
             let exception = Exception {
                 id: get_exception_id("RuntimeError"),
@@ -318,7 +355,7 @@ fn read_exception_string<'a>(reader: &mut Cursor<&[u8]>) -> Result<CSlice<'a, u8
     }
 }
 
-fn read_exception(raw_exception: &[u8]) -> Result<Exception, ReadError> {
+fn read_exceptions(raw_exception: &[u8]) -> Result<Vec<Exception>, ReadError> {
     let mut reader = Cursor::new(raw_exception);
 
     let mut byte = reader.read_u8()?;
@@ -330,29 +367,44 @@ fn read_exception(raw_exception: &[u8]) -> Result<Exception, ReadError> {
     while byte != 0x09 {
         byte = reader.read_u8()?;
     }
-    let _len = reader.read_u32::<NativeEndian>()?;
-    // ignore the remaining exceptions, stack traces etc. - unwinding from another device would be unwise anyway
-    Ok(Exception {
-        id: reader.read_u32::<NativeEndian>()?,
-        message: read_exception_string(&mut reader)?,
-        param: [
-            reader.read_u64::<NativeEndian>()? as i64,
-            reader.read_u64::<NativeEndian>()? as i64,
-            reader.read_u64::<NativeEndian>()? as i64,
-        ],
-        file: read_exception_string(&mut reader)?,
-        line: reader.read_u32::<NativeEndian>()?,
-        column: reader.read_u32::<NativeEndian>()?,
-        function: read_exception_string(&mut reader)?,
-    })
+    let count = reader.read_u32::<NativeEndian>()?;
+    // ignore the stack traces - unwinding from another device would be unwise anyway
+    let mut exceptions = Vec::new();
+    for _ in 0..count {
+        exceptions.push(Exception {
+            id: reader.read_u32::<NativeEndian>()?,
+            message: read_exception_string(&mut reader)?,
+            param: [
+                reader.read_u64::<NativeEndian>()? as i64,
+                reader.read_u64::<NativeEndian>()? as i64,
+                reader.read_u64::<NativeEndian>()? as i64,
+            ],
+            file: read_exception_string(&mut reader)?,
+            line: reader.read_u32::<NativeEndian>()?,
+            column: reader.read_u32::<NativeEndian>()?,
+            function: read_exception_string(&mut reader)?,
+        });
+    }
+    Ok(exceptions)
 }
 
-pub fn raise_raw(raw_exception: &[u8]) -> ! {
+/// Raises the last exception of a serialized exception of a subkernel, the one it was
+/// terminated by. The exceptions before it are kept, and reported with it if uncaught.
+pub fn raise_raw(raw_exception: Vec<u8>) -> ! {
     use crate::artiq_raise;
-    if let Ok(exception) = read_exception(raw_exception) {
-        unsafe { raise(&exception) };
-    } else {
-        artiq_raise!("SubkernelError", "Error passing exception");
+    let mut exceptions: Vec<Exception<'static>> = match read_exceptions(&raw_exception) {
+        Ok(exceptions) => unsafe { mem::transmute(exceptions) },
+        Err(_) => artiq_raise!("SubkernelError", "Error passing exception"),
+    };
+    match exceptions.pop() {
+        Some(exception) => unsafe {
+            PENDING_CHAIN = Some(RemoteChain {
+                _raw: raw_exception,
+                exceptions: exceptions,
+            });
+            raise(&exception)
+        },
+        None => artiq_raise!("SubkernelError", "Error passing exception"),
     }
 }
 
@@ -377,6 +429,7 @@ pub unsafe extern "C" fn end_catch() {
     let index = EXCEPTION_BUFFER.exception_stack[count - 1] as usize;
     EXCEPTION_BUFFER.exception_stack[count - 1] = -1;
     EXCEPTION_BUFFER.exceptions[index] = None;
+    REMOTE_CHAINS[index] = None;
     let outer_sp = EXCEPTION_BUFFER.stack_pointers[index].stack_pointer;
     count -= 1;
     for i in (0..count).rev() {
@@ -388,6 +441,7 @@ pub unsafe extern "C" fn end_catch() {
             break;
         }
         EXCEPTION_BUFFER.exceptions[index] = None;
+        REMOTE_CHAINS[index] = None;
         EXCEPTION_BUFFER.exception_stack[i] = -1;
         count -= 1;
     }
@@ -431,10 +485,40 @@ fn uncaught_exception() -> ! {
                     &mut EXCEPTION_BUFFER.stack_pointers[a as usize],
                     &mut EXCEPTION_BUFFER.stack_pointers[b as usize],
                 );
+                REMOTE_CHAINS.swap(a as usize, b as usize);
             }
         }
     }
     unsafe {
+        let count = EXCEPTION_BUFFER.exception_count;
+        if REMOTE_CHAINS[..count].iter().any(Option::is_some) {
+            // the remote exceptions precede the exception they were passed on to, with empty
+            // backtraces as their frames are not in this kernel
+            for i in 0..count {
+                let sp = &EXCEPTION_BUFFER.stack_pointers[i];
+                if let Some(chain) = &REMOTE_CHAINS[i] {
+                    for exception in chain.exceptions.iter() {
+                        CHAINED_EXCEPTIONS.push(Some(*exception));
+                        CHAINED_STACK_POINTERS.push(StackPointerBacktrace {
+                            stack_pointer: sp.stack_pointer,
+                            initial_backtrace_size: 0,
+                            current_backtrace_size: 0,
+                        });
+                    }
+                }
+                CHAINED_EXCEPTIONS.push(EXCEPTION_BUFFER.exceptions[i]);
+                CHAINED_STACK_POINTERS.push(StackPointerBacktrace {
+                    stack_pointer: sp.stack_pointer,
+                    initial_backtrace_size: sp.initial_backtrace_size,
+                    current_backtrace_size: sp.current_backtrace_size,
+                });
+            }
+            crate::kernel::core1::terminate(
+                CHAINED_EXCEPTIONS.as_ref(),
+                CHAINED_STACK_POINTERS.as_ref(),
+                EXCEPTION_BUFFER.backtrace[..EXCEPTION_BUFFER.backtrace_size].as_mut(),
+            )
+        }
         crate::kernel::core1::terminate(
             EXCEPTION_BUFFER.exceptions[..EXCEPTION_BUFFER.exception_count].as_ref(),
             EXCEPTION_BUFFER.stack_pointers[..EXCEPTION_BUFFER.exception_count].as_ref(),
@@ -1,15 +1,26 @@
 use core::{arch::{asm, naked_asm},
+           ptr::addr_of,
            sync::atomic::{AtomicBool, Ordering}};
 
 use libboard_zynq::{gic, mpcore, println, stdio};
-use libcortex_a9::{asm, interrupt_handler, notify_spin_lock, regs::MPIDR, spin_lock_yield};
+use libcortex_a9::{asm, interrupt_handler,
+                   mmu::{AccessPermissions, L1Table},
+                   notify_spin_lock,
+                   regs::MPIDR,
+                   spin_lock_yield};
 use libregister::RegisterR;
 
+use crate::kernel::profiler;
+
 extern "C" {
     static mut __stack1_start: u32;
+    static __stack1_end: u32;
     fn main_core1() -> !;
 }
 
+// size of the sections the MMU maps memory with
+const SECTION_SIZE: usize = 0x100000;
+
 static CORE1_RESTART: AtomicBool = AtomicBool::new(false);
 
 interrupt_handler!(IRQ, irq, __irq_stack0_start, __irq_stack1_start, {
@@ -22,6 +33,11 @@ interrupt_handler!(IRQ, irq, __irq_stack0_start, __irq_stack1_start, {
             asm::exit_irq();
             asm!("b core1_restart");
         }
+        if id.0 as u32 == profiler::TIMER_INTERRUPT {
+            profiler::sample();
+            gic.end_interrupt(id);
+            return;
+        }
     }
     stdio::drop_uart();
     println!("IRQ");
@@ -46,3 +62,21 @@ pub fn restart_core1() {
         spin_lock_yield();
     }
 }
+
+// The lowest full section of the core 1 stack is left without access, so that a
+// kernel overflowing its stack takes a data abort there instead of running into the heap.
+fn stack_guard() -> usize {
+    let stack_end = unsafe { addr_of!(__stack1_end) as usize };
+    (stack_end + SECTION_SIZE - 1) & !(SECTION_SIZE - 1)
+}
+
+pub fn setup_stack_guard() {
+    L1Table::get().update(stack_guard() as *const u8, |section| {
+        section.access = AccessPermissions::PermissionFault;
+    });
+}
+
+pub fn in_stack_guard(address: usize) -> bool {
+    let guard = stack_guard();
+    guard <= address && address < guard + SECTION_SIZE
+}
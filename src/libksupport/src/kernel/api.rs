@@ -6,13 +6,17 @@ use log::{info, warn};
 
 #[cfg(any(has_drtio, has_cxp_grabber))]
 use super::cxp;
+#[cfg(any(has_drtio, has_grabber))]
+use super::grabber;
 #[cfg(has_drtio)]
 use super::subkernel;
 use super::{cache,
-            core1::rtio_get_destination_status,
-            dma, i2c, linalg,
-            rpc::{rpc_recv, rpc_send, rpc_send_async},
-            rtio};
+            core1::{chain_stored_kernel, config_storage_stats, get_kernel_args, rtio_get_destination_counter,
+                    rtio_get_destination_group, rtio_get_destination_status, rtio_inject,
+                    rtio_input_timestamped_data_batch, rtio_read_injection, wall_clock_us},
+            dma, fpu, i2c, linalg, mailbox,
+            rpc::{rpc_recv, rpc_recv_region, rpc_send, rpc_send_async},
+            rtio, shm};
 use crate::eh_artiq;
 
 extern "C" {
@@ -79,21 +83,34 @@ pub fn resolve(required: &[u8]) -> Option<u32> {
         api!(now_mu = rtio::now_mu),
         api!(at_mu = rtio::at_mu),
         api!(delay_mu = rtio::delay_mu),
+        api!(wall_clock_us = wall_clock_us),
 
         // rpc
         api!(rpc_send = rpc_send),
         api!(rpc_send_async = rpc_send_async),
         api!(rpc_recv = rpc_recv),
+        api!(rpc_recv_region = rpc_recv_region),
+
+        // startup/idle kernel arguments
+        api!(get_kernel_args = get_kernel_args),
+        api!(chain_stored_kernel = chain_stored_kernel),
+        api!(config_storage_stats = config_storage_stats),
 
         // rtio
         api!(rtio_init = rtio::init),
+        api!(rtio_init_policy = rtio::init_policy),
         api!(rtio_get_destination_status = rtio_get_destination_status),
+        api!(rtio_get_destination_counter = rtio_get_destination_counter),
+        api!(rtio_get_destination_group = rtio_get_destination_group),
+        api!(rtio_inject = rtio_inject),
+        api!(rtio_read_injection = rtio_read_injection),
         api!(rtio_get_counter = rtio::get_counter),
         api!(rtio_output = rtio::output),
         api!(rtio_output_wide = rtio::output_wide),
         api!(rtio_input_timestamp = rtio::input_timestamp),
         api!(rtio_input_data = rtio::input_data),
         api!(rtio_input_timestamped_data = rtio::input_timestamped_data),
+        api!(rtio_input_timestamped_data_batch = rtio_input_timestamped_data_batch),
 
         // acp rtio batching
         #[cfg(ki_impl = "acp")]
@@ -105,17 +122,32 @@ pub fn resolve(required: &[u8]) -> Option<u32> {
         api!(core_log = core_log),
         api!(rtio_log = rtio_log),
 
+        // floating-point exception checks
+        api!(fp_exceptions_enable = fpu::enable_checks),
+        api!(fp_exceptions_check = fpu::check),
+
         // rtio dma
         api!(dma_record_start = dma::dma_record_start),
         api!(dma_record_stop = dma::dma_record_stop),
         api!(dma_erase = dma::dma_erase),
         api!(dma_retrieve = dma::dma_retrieve),
+        api!(dma_stat = dma::dma_stat),
         api!(dma_playback = dma::dma_playback),
+        #[cfg(has_drtio)]
+        api!(dma_playback_group = dma::dma_playback_group),
+        api!(dma_playback_stat = dma::dma_playback_stat),
 
         // cache
         api!(cache_get = cache::get),
         api!(cache_put = cache::put),
 
+        // mailbox
+        api!(mailbox_put = mailbox::put),
+        api!(mailbox_get = mailbox::get),
+
+        // shared memory
+        api!(shm_map = shm::map),
+
         // i2c
         api!(i2c_start = i2c::start),
         api!(i2c_restart = i2c::restart),
@@ -123,16 +155,30 @@ pub fn resolve(required: &[u8]) -> Option<u32> {
         api!(i2c_write = i2c::write),
         api!(i2c_read = i2c::read),
         api!(i2c_switch_select = i2c::switch_select),
+        api!(i2c_write_block = i2c::write_block),
+        api!(i2c_read_block = i2c::read_block),
 
         // subkernel
         #[cfg(has_drtio)]
         api!(subkernel_load_run = subkernel::load_run),
         #[cfg(has_drtio)]
+        api!(subkernel_load_run_relative = subkernel::load_run_relative),
+        #[cfg(has_drtio)]
+        api!(subkernel_load_run_group = subkernel::load_run_group),
+        #[cfg(has_drtio)]
         api!(subkernel_await_finish = subkernel::await_finish),
         #[cfg(has_drtio)]
+        api!(subkernel_await_many = subkernel::await_many),
+        #[cfg(has_drtio)]
         api!(subkernel_send_message = subkernel::send_message),
         #[cfg(has_drtio)]
         api!(subkernel_await_message = subkernel::await_message),
+        #[cfg(has_drtio)]
+        api!(subkernel_barrier = subkernel::barrier),
+        #[cfg(has_drtio)]
+        api!(subkernel_dataset_append = subkernel::dataset_append),
+        #[cfg(has_drtio)]
+        api!(subkernel_dataset_fetch = subkernel::dataset_fetch),
 
         // cxp grabber
         #[cfg(any(has_drtio, has_cxp_grabber))]
@@ -145,6 +191,12 @@ pub fn resolve(required: &[u8]) -> Option<u32> {
         api!(cxp_start_roi_viewer = cxp::start_roi_viewer),
         #[cfg(any(has_drtio, has_cxp_grabber))]
         api!(cxp_download_roi_viewer_frame = cxp::download_roi_viewer_frame),
+        #[cfg(any(has_drtio, has_cxp_grabber))]
+        api!(cxp_read_event = cxp::read_event),
+
+        // grabber
+        #[cfg(any(has_drtio, has_grabber))]
+        api!(grabber_read_roi_counts = grabber::read_roi_counts),
 
         // Double-precision floating-point arithmetic helper functions
         // RTABI chapter 4.1.2, Table 2
@@ -1,20 +1,22 @@
 //! Kernel prologue/epilogue that runs on the 2nd CPU core
 
-use alloc::borrow::ToOwned;
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
 use core::{cell::UnsafeCell, mem, ptr};
 
-use cslice::CSlice;
+use cslice::{AsCSlice, CMutSlice, CSlice};
 use dyld::{Library, elf::EXIDX_Entry};
 use libboard_zynq::{gic, mpcore};
-use libcortex_a9::{asm::{dsb, isb},
+use libcortex_a9::{asm::{dsb, enable_irq, isb},
                    cache::{bpiall, dcci_slice, iciallu},
+                   regs::MPIDR,
                    sync_channel};
+use libregister::RegisterR;
 use libsupport_zynq::ram;
 use log::{debug, error, info};
 
 use super::{CHANNEL_0TO1, CHANNEL_1TO0, CHANNEL_SEM, INIT_LOCK, KERNEL_CHANNEL_0TO1, KERNEL_CHANNEL_1TO0,
-            KERNEL_IMAGE, Message, api::resolve, dma, rpc::rpc_send_async};
-use crate::eh_artiq;
+            KERNEL_IMAGE, Message, api::resolve, core_dump, dma, fpu, profiler, rpc, rpc::rpc_send_async};
+use crate::{artiq_raise, eh_artiq, irq};
 
 // linker symbols
 extern "C" {
@@ -70,6 +72,11 @@ unsafe fn attribute_writeback(typeinfo: *const ()) {
     }
 }
 
+// arguments for the next kernel run, delivered by core0 before StartRequest
+static mut KERNEL_ARGS: Option<Vec<u8>> = None;
+// the slice of KERNEL_ARGS handed to the kernel by get_kernel_args
+static mut KERNEL_ARGS_SLICE: Option<CSlice<'static, u8>> = None;
+
 pub struct KernelImage {
     library: UnsafeCell<Library>,
     __modinit__: u32,
@@ -119,6 +126,8 @@ impl KernelImage {
         if let Some(typeinfo) = self.typeinfo {
             attribute_writeback(typeinfo as *const ());
         }
+        // flags set since the last RPC are reported as an uncaught exception
+        fpu::check();
     }
 
     pub fn get_load_addr(&self) -> usize {
@@ -131,7 +140,13 @@ pub extern "C" fn main_core1() {
     debug!("Core1 started");
 
     ram::init_alloc_core1();
+    unsafe {
+        eh_artiq::forget_remote_chains();
+    }
     gic::InterruptController::gic(mpcore::RegisterBlock::mpcore()).enable_interrupts();
+    irq::setup_stack_guard();
+    // a kernel killed while profiled leaves the sampling timer running
+    profiler::disarm();
 
     let (mut core0_tx, mut core1_rx) = sync_channel!(Message, 4);
     let (mut core1_tx, core0_rx) = sync_channel!(Message, 4);
@@ -144,6 +159,7 @@ pub extern "C" fn main_core1() {
             KERNEL_IMAGE = core::ptr::null();
         }
         dma::init_dma_recorder();
+        rpc::init_recv_region();
     }
     *CHANNEL_0TO1.lock() = Some(core0_tx);
     *CHANNEL_1TO0.lock() = Some(core0_rx);
@@ -155,6 +171,9 @@ pub extern "C" fn main_core1() {
         let message = core1_rx.recv();
         match message {
             Message::LoadRequest(data) => {
+                unsafe {
+                    KERNEL_ARGS = None;
+                }
                 let result = dyld::load(&data, &resolve).and_then(KernelImage::new);
                 match result {
                     Ok(kernel) => {
@@ -168,15 +187,24 @@ pub extern "C" fn main_core1() {
                     }
                 }
             }
+            Message::KernelArgs(args) => {
+                debug!("received {} bytes of kernel arguments", args.len());
+                unsafe {
+                    KERNEL_ARGS = Some(args);
+                }
+            }
             Message::StartRequest => {
                 info!("kernel starting");
                 if let Some(kernel) = loaded_kernel.take() {
                     unsafe {
                         eh_artiq::reset_exception_buffer();
+                        fpu::reset();
                         KERNEL_CHANNEL_0TO1 = Some(core1_rx);
                         KERNEL_CHANNEL_1TO0 = Some(core1_tx);
                         KERNEL_IMAGE = &kernel as *const KernelImage;
+                        profiler::arm(kernel.get_load_addr());
                         kernel.exec();
+                        profiler::disarm();
                         KERNEL_IMAGE = ptr::null();
                         core1_rx = KERNEL_CHANNEL_0TO1.take().unwrap();
                         core1_tx = KERNEL_CHANNEL_1TO0.take().unwrap();
@@ -185,11 +213,29 @@ pub extern "C" fn main_core1() {
                 info!("kernel finished");
                 core1_tx.send(Message::KernelFinished);
             }
+            #[cfg(has_drtio)]
+            Message::BulkCopyRequest { src, dst, len } => {
+                unsafe {
+                    ptr::copy(src, dst, len);
+                    dcci_slice(core::slice::from_raw_parts(dst, len));
+                }
+                core1_tx.send(Message::BulkCopyCompleted);
+            }
             _ => error!("Core1 received unexpected message: {:?}", message),
         }
     }
 }
 
+/// Links the kernel image as a LoadRequest would, then drops it, so that an image can be
+/// checked without disturbing the kernel running on core 1.
+pub fn check_load(data: &[u8]) -> Result<(), dyld::Error> {
+    let library = dyld::load(data, &resolve)?;
+    library
+        .lookup(b"__modinit__")
+        .ok_or(dyld::Error::Lookup("__modinit__".to_owned()))?;
+    Ok(())
+}
+
 /// Called by eh_artiq
 pub fn terminate(
     exceptions: &'static [Option<eh_artiq::Exception<'static>>],
@@ -203,6 +249,54 @@ pub fn terminate(
     loop {}
 }
 
+static mut FAULT_EXCEPTION: [Option<eh_artiq::Exception<'static>>; 1] = [None];
+static mut FAULT_STACK_POINTER: [eh_artiq::StackPointerBacktrace; 1] = [eh_artiq::StackPointerBacktrace {
+    stack_pointer: 0,
+    initial_backtrace_size: 0,
+    current_backtrace_size: 0,
+}];
+static mut FAULT_BACKTRACE: [(usize, usize); 1] = [(0, 0)];
+
+/// Called by the firmware exception handler. A fault of the kernel running on core 1
+/// cannot be unwound from, so it is reported as an uncaught exception located at the
+/// faulting instruction, after its state is saved as a core dump. Returns for any other fault.
+#[allow(static_mut_refs)]
+pub fn kernel_fault(vect: u32, regs: *const u32, pc: u32, ea: u32) {
+    if MPIDR.read().cpu_id() != 1 || unsafe { KERNEL_IMAGE.is_null() } {
+        return;
+    }
+    // SP is banked and not part of the frame saved by the exception entry
+    let sp = unsafe { core_dump::capture(vect, regs, pc, ea) } as usize;
+    let message = if irq::in_stack_guard(ea as usize) {
+        "kernel stack overflow at address {0}"
+    } else {
+        "kernel fault at address {0}, PC {1}"
+    };
+    unsafe {
+        let load_addr = (*KERNEL_IMAGE).get_load_addr();
+        FAULT_EXCEPTION[0] = Some(eh_artiq::Exception {
+            id: eh_artiq::get_exception_id("RuntimeError"),
+            file: file!().as_c_slice(),
+            line: line!(),
+            column: column!(),
+            function: "(Rust function)".as_c_slice(),
+            message: message.as_c_slice(),
+            param: [ea as i64, pc as i64, 0],
+        });
+        let mut backtrace_size = 0;
+        if pc as usize >= load_addr {
+            FAULT_BACKTRACE[0] = (pc as usize - load_addr, sp);
+            backtrace_size = 1;
+        }
+        FAULT_STACK_POINTER[0].stack_pointer = sp;
+        FAULT_STACK_POINTER[0].current_backtrace_size = backtrace_size;
+        // core 0 restarts core 1 with an interrupt once the exception is handled,
+        // which the exception mode entered for the fault has masked
+        enable_irq();
+        terminate(&FAULT_EXCEPTION, &FAULT_STACK_POINTER, &mut FAULT_BACKTRACE[..backtrace_size]);
+    }
+}
+
 /// Called by llvm_libunwind
 #[no_mangle]
 extern "C" fn dl_unwind_find_exidx(pc: *const u32, len_ptr: *mut u32) -> *const u32 {
@@ -254,3 +348,309 @@ pub extern "C" fn rtio_get_destination_status(destination: i32) -> bool {
         destination == 0
     }
 }
+
+/// Returns the RTIO counter of a destination. For remote destinations the value
+/// is sampled by the satellite, i.e. it lags by up to the aux round-trip time.
+pub extern "C" fn rtio_get_destination_counter(destination: i32) -> i64 {
+    #[cfg(has_drtio)]
+    {
+        if !(0..=255).contains(&destination) {
+            artiq_raise!(
+                "RTIODestinationUnreachable",
+                "invalid destination {0}",
+                destination as i64,
+                0,
+                0
+            );
+        }
+        let reply = unsafe {
+            let core1_rx = KERNEL_CHANNEL_0TO1.as_mut().unwrap();
+            let core1_tx = KERNEL_CHANNEL_1TO0.as_mut().unwrap();
+            core1_tx.send(Message::RtioCounterRequest(destination));
+            core1_rx.recv()
+        };
+        match reply {
+            Message::RtioCounterReply(Some(counter)) => counter,
+            Message::RtioCounterReply(None) => artiq_raise!(
+                "RTIODestinationUnreachable",
+                "could not read the RTIO counter of destination {0}",
+                destination as i64,
+                0,
+                0
+            ),
+            _ => panic!("received unexpected reply to RtioCounterRequest: {:?}", reply),
+        }
+    }
+    #[cfg(not(has_drtio))]
+    {
+        if destination != 0 {
+            artiq_raise!(
+                "RTIODestinationUnreachable",
+                "invalid destination {0}",
+                destination as i64,
+                0,
+                0
+            );
+        }
+        super::rtio::get_counter()
+    }
+}
+
+/// Reads the input events of `channel` until `timeout` into `timestamps` and `data`,
+/// as many as they have room for, and returns how many were read. The events of a
+/// remote destination are read by the satellite and sent back in batches.
+pub extern "C" fn rtio_input_timestamped_data_batch(
+    timeout: i64,
+    channel: i32,
+    timestamps: &mut CMutSlice<i64>,
+    data: &mut CMutSlice<i32>,
+) -> i32 {
+    let max_count = core::cmp::min(timestamps.len(), data.len());
+    let mut count = 0;
+    #[cfg(has_drtio)]
+    if channel >> 16 != 0 {
+        use libboard_artiq::drtioaux_proto::{RTIO_INPUT_BATCH_BUSY, RTIO_INPUT_BATCH_FULL, RTIO_INPUT_BATCH_OVERFLOW,
+                                             RTIO_INPUT_BATCH_PENDING, RTIO_INPUT_BATCH_UNSUPPORTED};
+
+        let mut batched = true;
+        while batched && count < max_count {
+            let reply = unsafe {
+                let core1_rx = KERNEL_CHANNEL_0TO1.as_mut().unwrap();
+                let core1_tx = KERNEL_CHANNEL_1TO0.as_mut().unwrap();
+                core1_tx.send(Message::RtioInputBatchRequest {
+                    channel: channel as u32,
+                    timeout: timeout,
+                    max_count: max_count - count,
+                });
+                core1_rx.recv()
+            };
+            let status = match reply {
+                Message::RtioInputBatchReply { status, events } => {
+                    for (timestamp, value) in events {
+                        timestamps.as_mut_slice()[count] = timestamp;
+                        data.as_mut_slice()[count] = value;
+                        count += 1;
+                    }
+                    status
+                }
+                _ => panic!("received unexpected reply to RtioInputBatchRequest: {:?}", reply),
+            };
+            match status {
+                Some(RTIO_INPUT_BATCH_FULL) | Some(RTIO_INPUT_BATCH_PENDING) => (),
+                // older satellite firmware, read through the single event path below
+                Some(RTIO_INPUT_BATCH_UNSUPPORTED) => batched = false,
+                Some(RTIO_INPUT_BATCH_OVERFLOW) => artiq_raise!(
+                    "RTIOOverflow",
+                    "RTIO input overflow on channel {rtio_channel_info:0}",
+                    channel as i64,
+                    0,
+                    0
+                ),
+                Some(RTIO_INPUT_BATCH_BUSY) => artiq_raise!(
+                    "RuntimeError",
+                    "RTIO inputs of channel {rtio_channel_info:0} are in use by a subkernel or DMA playback",
+                    channel as i64,
+                    0,
+                    0
+                ),
+                Some(_) => break,
+                None => artiq_raise!(
+                    "RTIODestinationUnreachable",
+                    "RTIO destination unreachable, input, on channel {rtio_channel_info:0}",
+                    channel as i64,
+                    0,
+                    0
+                ),
+            }
+        }
+        if batched {
+            return count as i32;
+        }
+    }
+    while count < max_count {
+        let event = super::rtio::input_timestamped_data(timeout, channel);
+        if event.timestamp < 0 {
+            break;
+        }
+        timestamps.as_mut_slice()[count] = event.timestamp;
+        data.as_mut_slice()[count] = event.data;
+        count += 1;
+    }
+    count as i32
+}
+
+pub extern "C" fn get_kernel_args() -> &'static CSlice<'static, u8> {
+    // empty if the kernel was started without arguments
+    unsafe {
+        let args: &'static [u8] = KERNEL_ARGS.as_ref().map_or(&[], |args| args.as_slice());
+        KERNEL_ARGS_SLICE = Some(args.as_c_slice());
+        KERNEL_ARGS_SLICE.as_ref().unwrap()
+    }
+}
+
+/// Queues the kernel stored under the config key to run after this one finishes.
+/// Returns false if there is no such kernel.
+pub extern "C" fn chain_stored_kernel(key: CSlice<u8>) -> bool {
+    let key = match String::from_utf8(key.as_ref().to_vec()) {
+        Ok(key) => key,
+        Err(_) => artiq_raise!("ValueError", "stored kernel key is not valid UTF-8"),
+    };
+    let reply = unsafe {
+        KERNEL_CHANNEL_1TO0
+            .as_mut()
+            .unwrap()
+            .send(Message::StoredKernelRequest(key));
+        KERNEL_CHANNEL_0TO1.as_mut().unwrap().recv()
+    };
+    match reply {
+        Message::StoredKernelReply(found) => found,
+        _ => panic!("received unexpected reply to StoredKernelRequest: {:?}", reply),
+    }
+}
+
+/// Sets the `overrd` override of `channel` to `value`, as the moninj dashboard does, e.g.
+/// to force a TTL level during a calibration. Overrides stay in effect after the kernel ends.
+/// Satellite channels are reached over aux by the master.
+pub extern "C" fn rtio_inject(channel: i32, overrd: i32, value: i32) {
+    let reply = unsafe {
+        KERNEL_CHANNEL_1TO0.as_mut().unwrap().send(Message::InjectionRequest {
+            channel: channel,
+            overrd: overrd as i8,
+            value: value as i8,
+        });
+        KERNEL_CHANNEL_0TO1.as_mut().unwrap().recv()
+    };
+    match reply {
+        Message::InjectionReply(true) => (),
+        Message::InjectionReply(false) => artiq_raise!(
+            "RTIODestinationUnreachable",
+            "cannot inject into channel {rtio_channel_info:0}",
+            channel as i64,
+            0,
+            0
+        ),
+        _ => panic!("received unexpected reply to InjectionRequest: {:?}", reply),
+    }
+}
+
+/// Reads back the `overrd` override of `channel`.
+pub extern "C" fn rtio_read_injection(channel: i32, overrd: i32) -> i32 {
+    let reply = unsafe {
+        KERNEL_CHANNEL_1TO0.as_mut().unwrap().send(Message::InjectionStatusRequest {
+            channel: channel,
+            overrd: overrd as i8,
+        });
+        KERNEL_CHANNEL_0TO1.as_mut().unwrap().recv()
+    };
+    match reply {
+        Message::InjectionStatusReply(Some(value)) => value as i32,
+        Message::InjectionStatusReply(None) => artiq_raise!(
+            "RTIODestinationUnreachable",
+            "cannot read the injection status of channel {rtio_channel_info:0}",
+            channel as i64,
+            0,
+            0
+        ),
+        _ => panic!("received unexpected reply to InjectionStatusRequest: {:?}", reply),
+    }
+}
+
+/// Writes the destinations of a group from the `destination_groups` config key into
+/// `destinations`, as many as fit, and returns the size of the group, so that the list
+/// can be passed on to barriers or used to address every crate of a kind.
+pub extern "C" fn rtio_get_destination_group(name: &CSlice<u8>, destinations: &mut CMutSlice<u8>) -> i32 {
+    let members = destination_group(name);
+    for (destination, member) in destinations.as_mut_slice().iter_mut().zip(members.iter()) {
+        *destination = *member;
+    }
+    members.len() as i32
+}
+
+// Destinations of a group, for the syscalls addressing a group as a whole.
+pub(super) fn destination_group(name: &CSlice<u8>) -> Vec<u8> {
+    let name = match String::from_utf8(name.as_ref().to_vec()) {
+        Ok(name) => name,
+        Err(_) => artiq_raise!("ValueError", "destination group name is not valid UTF-8"),
+    };
+    let reply = unsafe {
+        KERNEL_CHANNEL_1TO0
+            .as_mut()
+            .unwrap()
+            .send(Message::DestinationGroupRequest(name));
+        KERNEL_CHANNEL_0TO1.as_mut().unwrap().recv()
+    };
+    match reply {
+        Message::DestinationGroupReply(Some(members)) => members,
+        Message::DestinationGroupReply(None) => artiq_raise!("KeyError", "unknown destination group"),
+        _ => panic!("received unexpected reply to DestinationGroupRequest: {:?}", reply),
+    }
+}
+
+/// Writes the bytes stored under each of `keys` in the config of a destination into `usage`,
+/// -1 for keys that are not set, and the total and free bytes of its config storage into
+/// `space`, -1 when not known. Returns the bytes used by those keys in total, so that
+/// experiments logging to config keys can keep track of them.
+pub extern "C" fn config_storage_stats(
+    destination: i32,
+    keys: &CSlice<CSlice<u8>>,
+    usage: &mut CMutSlice<i64>,
+    space: &mut CMutSlice<i64>,
+) -> i64 {
+    if usage.len() < keys.len() || space.len() < 2 {
+        artiq_raise!("ValueError", "config usage buffer is too small");
+    }
+    if !(0..=255).contains(&destination) {
+        artiq_raise!(
+            "RTIODestinationUnreachable",
+            "invalid destination {0}",
+            destination as i64,
+            0,
+            0
+        );
+    }
+    let mut key_names = Vec::with_capacity(keys.len());
+    for key in keys.as_ref().iter() {
+        match String::from_utf8(key.as_ref().to_vec()) {
+            Ok(key) => key_names.push(key),
+            Err(_) => artiq_raise!("ValueError", "config key is not valid UTF-8"),
+        }
+    }
+    let reply = unsafe {
+        KERNEL_CHANNEL_1TO0.as_mut().unwrap().send(Message::StorageStatsRequest {
+            destination: destination as u8,
+            keys: key_names,
+        });
+        KERNEL_CHANNEL_0TO1.as_mut().unwrap().recv()
+    };
+    match reply {
+        Message::StorageStatsReply(Some(stats)) => {
+            for (usage, size) in usage.as_mut_slice().iter_mut().zip(stats.sizes.iter()) {
+                *usage = *size;
+            }
+            space.as_mut_slice()[0] = stats.total;
+            space.as_mut_slice()[1] = stats.free;
+            stats.sizes.iter().filter(|&&size| size > 0).sum()
+        }
+        Message::StorageStatsReply(None) => artiq_raise!(
+            "RTIODestinationUnreachable",
+            "could not read the config usage of destination {0}",
+            destination as i64,
+            0,
+            0
+        ),
+        _ => panic!("received unexpected reply to StorageStatsRequest: {:?}", reply),
+    }
+}
+
+/// Returns the wall-clock time in microseconds since the Unix epoch, as synchronized
+/// by the host, or -1 if it has not been set.
+pub extern "C" fn wall_clock_us() -> i64 {
+    let reply = unsafe {
+        KERNEL_CHANNEL_1TO0.as_mut().unwrap().send(Message::WallClockRequest);
+        KERNEL_CHANNEL_0TO1.as_mut().unwrap().recv()
+    };
+    match reply {
+        Message::WallClockReply(time_us) => time_us.map_or(-1, |time_us| time_us as i64),
+        _ => panic!("received unexpected reply to WallClockRequest: {:?}", reply),
+    }
+}
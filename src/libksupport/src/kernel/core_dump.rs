@@ -0,0 +1,110 @@
+use alloc::vec::Vec;
+use core::{arch::asm,
+           ptr::{self, addr_of},
+           sync::atomic::{AtomicBool, Ordering}};
+
+use byteorder::{ByteOrder, NativeEndian};
+
+use crate::irq;
+
+extern "C" {
+    static __stack1_start: u32;
+    static __stack1_end: u32;
+}
+
+// bytes of the kernel stack kept, upwards from its stack pointer at the fault
+pub const STACK_DUMP_SIZE: usize = 4096;
+// r0-r12, as saved by the exception entry
+const SAVED_REGS: usize = 13;
+
+// State of core 1 at the last kernel fault. It is written in place from the exception
+// handler, which runs on a small stack and cannot allocate, and kept until the next fault.
+struct CoreDump {
+    vect: u32,
+    pc: u32,
+    ea: u32,
+    sp: u32,
+    spsr: u32,
+    regs: [u32; SAVED_REGS],
+    stack_len: usize,
+    stack: [u8; STACK_DUMP_SIZE],
+}
+
+static mut CORE_DUMP: CoreDump = CoreDump {
+    vect: 0,
+    pc: 0,
+    ea: 0,
+    sp: 0,
+    spsr: 0,
+    regs: [0; SAVED_REGS],
+    stack_len: 0,
+    stack: [0; STACK_DUMP_SIZE],
+};
+static VALID: AtomicBool = AtomicBool::new(false);
+
+// SP is banked, so it is read back in the mode the fault was taken from;
+// user mode cannot be left again once entered and shares its SP with system mode.
+pub(super) unsafe fn interrupted_sp() -> (u32, u32) {
+    let spsr: u32;
+    asm!("mrs {}, spsr", out(reg) spsr);
+    let mut mode = spsr & 0x1f;
+    if mode == 0x10 {
+        mode = 0x1f;
+    }
+    let sp: u32;
+    asm!(
+        "mrs r1, cpsr",
+        "bic r2, r1, #0x1f",
+        "orr r2, r2, r3",
+        "msr cpsr_c, r2",
+        "mov r0, sp",
+        "msr cpsr_c, r1",
+        in("r3") mode,
+        out("r0") sp,
+        out("r1") _,
+        out("r2") _,
+    );
+    (sp, spsr)
+}
+
+/// Called from the exception handler on core 1, returns the stack pointer at the fault.
+pub unsafe fn capture(vect: u32, regs: *const u32, pc: u32, ea: u32) -> u32 {
+    VALID.store(false, Ordering::Release);
+    let (sp, spsr) = interrupted_sp();
+    CORE_DUMP.vect = vect;
+    CORE_DUMP.pc = pc;
+    CORE_DUMP.ea = ea;
+    CORE_DUMP.sp = sp;
+    CORE_DUMP.spsr = spsr;
+    if !regs.is_null() {
+        ptr::copy_nonoverlapping(regs, CORE_DUMP.regs.as_mut_ptr(), SAVED_REGS);
+    }
+    // a stack pointer in the guard section or outside of the stack is not followed
+    let stack_start = addr_of!(__stack1_start) as usize;
+    let stack_end = addr_of!(__stack1_end) as usize;
+    let sp = sp as usize;
+    CORE_DUMP.stack_len = if stack_end <= sp && sp < stack_start && !irq::in_stack_guard(sp) {
+        core::cmp::min(stack_start - sp, STACK_DUMP_SIZE)
+    } else {
+        0
+    };
+    ptr::copy_nonoverlapping(sp as *const u8, CORE_DUMP.stack.as_mut_ptr(), CORE_DUMP.stack_len);
+    VALID.store(true, Ordering::Release);
+    sp as u32
+}
+
+/// Serializes the last dump: the exception vector, PC, faulting address, SP and SPSR,
+/// r0-r12 and the stack length as 32-bit words, followed by the stack contents.
+pub fn get() -> Option<Vec<u8>> {
+    if !VALID.load(Ordering::Acquire) {
+        return None;
+    }
+    let dump = unsafe { &CORE_DUMP };
+    let mut words = vec![dump.vect, dump.pc, dump.ea, dump.sp, dump.spsr];
+    words.extend_from_slice(&dump.regs);
+    words.push(dump.stack_len as u32);
+    let mut data = vec![0; words.len() * 4];
+    NativeEndian::write_u32_into(&words, &mut data);
+    data.extend_from_slice(&dump.stack[..dump.stack_len]);
+    Some(data)
+}
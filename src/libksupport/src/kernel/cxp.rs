@@ -8,7 +8,8 @@ use libboard_artiq::drtioaux_proto::CXP_PAYLOAD_MAX_SIZE;
 #[cfg(has_cxp_grabber)]
 use libboard_artiq::{cxp_ctrl::DATA_MAXSIZE,
                      cxp_grabber::{camera_connected, roi_viewer_setup, with_tag},
-                     cxp_packet::{read_bytes, read_u32, write_u32}};
+                     cxp_packet::{poll_events, pop_event, read_bytes, read_u32, write_u32}};
+use libboard_zynq::timer;
 use log::info;
 
 #[cfg(has_drtio)]
@@ -26,6 +27,14 @@ pub struct ROIViewerFrame {
     pixel_width: i32,
 }
 
+// id is -1 if no event was raised within the timeout
+#[repr(C)]
+pub struct CameraEvent {
+    id: i32,
+    namespace: i32,
+    timestamp: i64,
+}
+
 enum Error {
     BufferSizeTooSmall(usize, usize),
     ROISizeTooBig(usize, usize),
@@ -395,3 +404,61 @@ pub extern "C" fn download_roi_viewer_frame(dest: i32, buffer: &mut CMutSlice<i6
         pixel_width: pixel_width as i32,
     }
 }
+
+fn next_event(dest: i32) -> Option<(u8, u16, u64)> {
+    match dest {
+        0 => {
+            #[cfg(has_cxp_grabber)]
+            {
+                if !camera_connected() {
+                    artiq_raise!("CXPError", "Camera is not connected");
+                };
+                if let Err(e) = poll_events() {
+                    artiq_raise!("CXPError", format!("{}", e));
+                }
+                pop_event().map(|event| (event.namespace, event.id, event.timestamp))
+            }
+            #[cfg(not(has_cxp_grabber))]
+            artiq_raise!("CXPError", "CXP Grabber is not available on destination 0");
+        }
+        _ => {
+            #[cfg(has_drtio)]
+            {
+                match kernel_channel_transact(Message::CXPEventRequest {
+                    destination: dest as u8,
+                }) {
+                    Message::CXPEventReply(event) => event,
+                    Message::CXPError(err_msg) => artiq_raise!("CXPError", err_msg),
+                    _ => unreachable!(),
+                }
+            }
+            #[cfg(not(has_drtio))]
+            artiq_raise!(
+                "CXPError",
+                format!("DRTIO is not avaiable, destination {} cannot be reached", dest)
+            );
+        }
+    }
+}
+
+/// Returns the oldest event raised by the camera (e.g. exposure start or end) that was
+/// not read yet, waiting up to `timeout_ms` for one; a timeout of 0 only polls.
+pub extern "C" fn read_event(dest: i32, timeout_ms: i64) -> CameraEvent {
+    let limit = timer::get_ms().saturating_add(timeout_ms.max(0) as u64);
+    loop {
+        if let Some((namespace, id, timestamp)) = next_event(dest) {
+            return CameraEvent {
+                id: id as i32,
+                namespace: namespace as i32,
+                timestamp: timestamp as i64,
+            };
+        }
+        if timer::get_ms() >= limit {
+            return CameraEvent {
+                id: -1,
+                namespace: 0,
+                timestamp: 0,
+            };
+        }
+    }
+}
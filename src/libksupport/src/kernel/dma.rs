@@ -1,9 +1,14 @@
 use alloc::{string::String, vec::Vec};
-use core::{mem, ptr};
+use core::{mem, ptr, sync::atomic::Ordering};
 
 use cslice::CSlice;
+#[cfg(has_drtio)]
+use libboard_artiq::drtio_routing;
+use libboard_artiq::drtioaux_proto::DESTINATION_MASK_SIZE;
 
-use super::{KERNEL_CHANNEL_0TO1, KERNEL_CHANNEL_1TO0, KERNEL_IMAGE, Message, rtio};
+#[cfg(has_drtio)]
+use super::core1::destination_group;
+use super::{DMA_PLAYBACK_COUNT, KERNEL_CHANNEL_0TO1, KERNEL_CHANNEL_1TO0, KERNEL_IMAGE, Message, rtio};
 use crate::{artiq_raise, pl::csr};
 
 #[repr(C)]
@@ -13,6 +18,13 @@ pub struct DmaTrace {
     uses_ddma: bool,
 }
 
+#[repr(C)]
+pub struct DmaStat {
+    duration: i64,
+    size: i64,
+    events: i32,
+}
+
 #[derive(Clone, Debug)]
 pub struct DmaRecorder {
     pub name: String,
@@ -21,16 +33,60 @@ pub struct DmaRecorder {
     pub enable_ddma: bool,
 }
 
+// Totals over the local trace and the parts held by satellites.
+#[derive(Clone, Debug)]
+pub struct DmaTraceStat {
+    pub duration: i64,
+    pub size: usize,
+    pub events: usize,
+    // destinations that did not confirm having their part of the trace loaded
+    pub missing: usize,
+}
+
+// Measured by satellites while playing back their part of a trace, in machine units.
+// The lowest slack is negative when events reached the RTIO core late.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DmaPlaybackStat {
+    pub duration: i64,
+    pub events: i32,
+    pub min_slack: i64,
+}
+
+impl DmaPlaybackStat {
+    // parts of a trace run in parallel on their destinations
+    pub fn combine(self, other: DmaPlaybackStat) -> DmaPlaybackStat {
+        DmaPlaybackStat {
+            duration: self.duration.max(other.duration),
+            events: self.events + other.events,
+            min_slack: self.min_slack.min(other.min_slack),
+        }
+    }
+}
+
 static mut RECORDER: Option<DmaRecorder> = None;
+static mut LAST_PLAYBACK_STAT: DmaPlaybackStat = DmaPlaybackStat {
+    duration: 0,
+    events: 0,
+    min_slack: 0,
+};
 
 pub unsafe fn init_dma_recorder() {
     // as static would remain after restart, we have to reset it,
     // without running its destructor.
     mem::forget(ptr::replace(&raw mut RECORDER, None));
+    LAST_PLAYBACK_STAT = DmaPlaybackStat::default();
+}
+
+fn trace_name(name: CSlice<u8>) -> String {
+    match String::from_utf8(name.as_ref().to_vec()) {
+        Ok(name) => name,
+        Err(_) => artiq_raise!("ValueError", "DMA trace name is not valid UTF-8"),
+    }
 }
 
 pub extern "C" fn dma_record_start(name: CSlice<u8>) {
-    let name = String::from_utf8(name.as_ref().to_vec()).unwrap();
+    let name = trace_name(name);
     unsafe {
         KERNEL_CHANNEL_1TO0
             .as_mut()
@@ -79,6 +135,17 @@ pub extern "C" fn dma_record_stop(duration: i64, enable_ddma: bool) {
     }
 }
 
+// Size in bytes and number of events of a trace, up to its terminating zero.
+pub fn trace_stat(trace: &[u8]) -> (usize, usize) {
+    let mut ptr = 0;
+    let mut events = 0;
+    while ptr < trace.len() && trace[ptr] != 0 {
+        ptr += trace[ptr] as usize;
+        events += 1;
+    }
+    (ptr.min(trace.len()), events)
+}
+
 #[inline(always)]
 unsafe fn dma_record_output_prepare(timestamp: i64, target: i32, words: usize) {
     // See gateware/rtio/dma.py.
@@ -136,7 +203,7 @@ pub extern "C" fn dma_record_output_wide(target: i32, words: &CSlice<i32>) {
 }
 
 pub extern "C" fn dma_erase(name: CSlice<u8>) {
-    let name = String::from_utf8(name.as_ref().to_vec()).unwrap();
+    let name = trace_name(name);
     unsafe {
         KERNEL_CHANNEL_1TO0
             .as_mut()
@@ -146,7 +213,7 @@ pub extern "C" fn dma_erase(name: CSlice<u8>) {
 }
 
 pub extern "C" fn dma_retrieve(name: CSlice<u8>) -> DmaTrace {
-    let name = String::from_utf8(name.as_ref().to_vec()).unwrap();
+    let name = trace_name(name);
     unsafe {
         KERNEL_CHANNEL_1TO0.as_mut().unwrap().send(Message::DmaGetRequest(name));
     }
@@ -165,7 +232,49 @@ pub extern "C" fn dma_retrieve(name: CSlice<u8>) -> DmaTrace {
     artiq_raise!("DMAError", "DMA trace not found");
 }
 
-pub extern "C" fn dma_playback(timestamp: i64, ptr: i32, _uses_ddma: bool) {
+pub extern "C" fn dma_stat(name: CSlice<u8>) -> DmaStat {
+    let name = trace_name(name);
+    unsafe {
+        KERNEL_CHANNEL_1TO0.as_mut().unwrap().send(Message::DmaStatRequest(name));
+    }
+    let stat = match unsafe { KERNEL_CHANNEL_0TO1.as_mut().unwrap() }.recv() {
+        Message::DmaStatReply(stat) => stat,
+        _ => panic!("Expected DmaStatReply after DmaStatRequest!"),
+    };
+    match stat {
+        None => artiq_raise!("DMAError", "DMA trace not found"),
+        Some(stat) if stat.missing > 0 => artiq_raise!(
+            "DMAError",
+            "DMA trace not loaded on {0} destination(s)",
+            stat.missing as i64,
+            0,
+            0
+        ),
+        Some(stat) => DmaStat {
+            duration: stat.duration,
+            size: stat.size as i64,
+            events: stat.events as i32,
+        },
+    }
+}
+
+pub extern "C" fn dma_playback(timestamp: i64, ptr: i32, uses_ddma: bool) {
+    playback(timestamp, ptr, uses_ddma, None)
+}
+
+// Plays the trace back with its remote parts limited to the destinations of a group.
+#[cfg(has_drtio)]
+pub extern "C" fn dma_playback_group(timestamp: i64, ptr: i32, uses_ddma: bool, group: &CSlice<u8>) {
+    let mut targets = [0; DESTINATION_MASK_SIZE];
+    for destination in destination_group(group) {
+        drtio_routing::mask_insert(&mut targets, destination);
+    }
+    playback(timestamp, ptr, uses_ddma, Some(targets))
+}
+
+#[cfg_attr(not(has_drtio), allow(unused_variables))]
+fn playback(timestamp: i64, ptr: i32, _uses_ddma: bool, targets: Option<[u8; DESTINATION_MASK_SIZE]>) {
+    DMA_PLAYBACK_COUNT.fetch_add(1, Ordering::Relaxed);
     unsafe {
         csr::rtio_dma::base_address_write(ptr as u32);
         csr::rtio_dma::time_offset_write(timestamp as u64);
@@ -181,6 +290,7 @@ pub extern "C" fn dma_playback(timestamp: i64, ptr: i32, _uses_ddma: bool) {
                 .send(Message::DmaStartRemoteRequest {
                     id: ptr,
                     timestamp: timestamp,
+                    targets: targets,
                 });
         }
         while csr::rtio_dma::enable_read() != 0 {}
@@ -222,7 +332,9 @@ pub extern "C" fn dma_playback(timestamp: i64, ptr: i32, _uses_ddma: bool) {
                     error,
                     channel,
                     timestamp,
+                    stat,
                 } => {
+                    LAST_PLAYBACK_STAT = stat;
                     if timeout {
                         artiq_raise!(
                             "DMAError",
@@ -253,3 +365,8 @@ pub extern "C" fn dma_playback(timestamp: i64, ptr: i32, _uses_ddma: bool) {
         }
     }
 }
+
+// Statistics of the satellite parts of the last trace played back with DDMA.
+pub extern "C" fn dma_playback_stat() -> DmaPlaybackStat {
+    unsafe { LAST_PLAYBACK_STAT }
+}
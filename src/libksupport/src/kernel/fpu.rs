@@ -0,0 +1,56 @@
+use core::arch::asm;
+
+use crate::artiq_raise;
+
+// The VFP of the Cortex-A9 does not trap floating-point exceptions, it only sets the
+// cumulative flags in FPSCR. Once a kernel enables checking, the flags are looked at
+// when it sends an RPC, on request, and when it returns, and a set flag is raised as
+// the matching exception.
+
+const FPSCR_IOC: u32 = 1 << 0;
+const FPSCR_DZC: u32 = 1 << 1;
+const FPSCR_OFC: u32 = 1 << 2;
+const FPSCR_CUMULATIVE: u32 = 0x9f;
+
+static mut CHECK_ENABLED: bool = false;
+
+fn read_fpscr() -> u32 {
+    let fpscr: u32;
+    unsafe { asm!("vmrs {}, fpscr", out(reg) fpscr) };
+    fpscr
+}
+
+fn clear_flags() {
+    let fpscr = read_fpscr() & !FPSCR_CUMULATIVE;
+    unsafe { asm!("vmsr fpscr, {}", in(reg) fpscr) };
+}
+
+pub unsafe fn reset() {
+    CHECK_ENABLED = false;
+    clear_flags();
+}
+
+pub extern "C" fn enable_checks(enable: bool) {
+    clear_flags();
+    unsafe {
+        CHECK_ENABLED = enable;
+    }
+}
+
+pub extern "C" fn check() {
+    if !unsafe { CHECK_ENABLED } {
+        return;
+    }
+    let flags = read_fpscr();
+    if flags & (FPSCR_IOC | FPSCR_DZC | FPSCR_OFC) == 0 {
+        return;
+    }
+    clear_flags();
+    if flags & FPSCR_DZC != 0 {
+        artiq_raise!("ZeroDivisionError", "floating-point division by zero");
+    }
+    if flags & FPSCR_OFC != 0 {
+        artiq_raise!("OverflowError", "floating-point overflow");
+    }
+    artiq_raise!("ValueError", "invalid floating-point operation");
+}
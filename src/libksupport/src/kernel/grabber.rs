@@ -0,0 +1,61 @@
+use cslice::CMutSlice;
+use libboard_artiq::drtioaux_proto::GRABBER_ROI_COUNT;
+#[cfg(has_grabber)]
+use libboard_artiq::grabber;
+
+#[cfg(has_drtio)]
+use super::{KERNEL_CHANNEL_0TO1, KERNEL_CHANNEL_1TO0, Message};
+use crate::artiq_raise;
+
+fn local_roi_counts(_grabber: i32) -> (u32, [u32; GRABBER_ROI_COUNT]) {
+    #[cfg(has_grabber)]
+    {
+        match grabber::roi_counts(_grabber as usize) {
+            Some(result) => result,
+            None => artiq_raise!("RuntimeError", "Grabber is not aligned or does not exist"),
+        }
+    }
+    #[cfg(not(has_grabber))]
+    artiq_raise!("RuntimeError", "Grabber is not available on destination 0");
+}
+
+fn remote_roi_counts(_dest: i32, _grabber: i32) -> (u32, [u32; GRABBER_ROI_COUNT]) {
+    #[cfg(has_drtio)]
+    {
+        let reply = unsafe {
+            KERNEL_CHANNEL_1TO0.as_mut().unwrap().send(Message::GrabberRoiRequest {
+                destination: _dest as u8,
+                grabber: _grabber as u8,
+            });
+            KERNEL_CHANNEL_0TO1.as_mut().unwrap().recv()
+        };
+        match reply {
+            Message::GrabberRoiReply {
+                succeeded: true,
+                frame,
+                counts,
+            } => (frame, counts),
+            Message::GrabberRoiReply { succeeded: false, .. } => {
+                artiq_raise!("RuntimeError", "Grabber ROI readout failed on satellite")
+            }
+            _ => panic!("expected GrabberRoiReply after GrabberRoiRequest"),
+        }
+    }
+    #[cfg(not(has_drtio))]
+    artiq_raise!("RTIODestinationUnreachable", "DRTIO is not available");
+}
+
+// Copies the outputs of the ROI engines of a grabber into `counts`, returns the frame number.
+pub extern "C" fn read_roi_counts(dest: i32, grabber: i32, counts: &mut CMutSlice<i32>) -> i32 {
+    if counts.len() < GRABBER_ROI_COUNT {
+        artiq_raise!("ValueError", "ROI count buffer is too small");
+    }
+    let (frame, roi_counts) = match dest {
+        0 => local_roi_counts(grabber),
+        _ => remote_roi_counts(dest, grabber),
+    };
+    for (count, roi_count) in counts.as_mut_slice().iter_mut().zip(roi_counts.iter()) {
+        *count = *roi_count as i32;
+    }
+    frame as i32
+}
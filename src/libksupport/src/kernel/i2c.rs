@@ -1,3 +1,7 @@
+use alloc::vec::Vec;
+
+use cslice::{CMutSlice, CSlice};
+
 use super::{KERNEL_CHANNEL_0TO1, KERNEL_CHANNEL_1TO0, Message};
 use crate::artiq_raise;
 
@@ -94,3 +98,43 @@ pub extern "C" fn switch_select(busno: i32, address: i32, mask: i32) {
         msg => panic!("Expected I2cBasicReply for I2cSwitchSelectRequest, got: {:?}", msg),
     }
 }
+
+// Writes the bytes in one transfer, stopping at the first byte not acknowledged.
+// Returns the number of bytes acknowledged.
+pub extern "C" fn write_block(busno: i32, data: &CSlice<i32>) -> i32 {
+    let data: Vec<u8> = data.as_ref().iter().map(|&byte| byte as u8).collect();
+    let reply = unsafe {
+        KERNEL_CHANNEL_1TO0.as_mut().unwrap().send(Message::I2cWriteBlockRequest {
+            busno: busno as u32,
+            data: data,
+        });
+        KERNEL_CHANNEL_0TO1.as_mut().unwrap().recv()
+    };
+    match reply {
+        Message::I2cWriteBlockReply { succeeded: true, acked } => acked as i32,
+        Message::I2cWriteBlockReply { succeeded: false, .. } => artiq_raise!("I2CError", "I2C write fail"),
+        msg => panic!("Expected I2cWriteBlockReply for I2cWriteBlockRequest, got: {:?}", msg),
+    }
+}
+
+// Fills the buffer, acknowledging every byte but the last, which is acknowledged
+// only with `ack`.
+pub extern "C" fn read_block(busno: i32, buffer: &mut CMutSlice<i32>, ack: bool) {
+    let reply = unsafe {
+        KERNEL_CHANNEL_1TO0.as_mut().unwrap().send(Message::I2cReadBlockRequest {
+            busno: busno as u32,
+            length: buffer.len(),
+            ack,
+        });
+        KERNEL_CHANNEL_0TO1.as_mut().unwrap().recv()
+    };
+    match reply {
+        Message::I2cReadBlockReply { succeeded: true, data } => {
+            for (slot, byte) in buffer.as_mut_slice().iter_mut().zip(data.iter()) {
+                *slot = *byte as i32;
+            }
+        }
+        Message::I2cReadBlockReply { succeeded: false, .. } => artiq_raise!("I2CError", "I2C read fail"),
+        msg => panic!("Expected I2cReadBlockReply for I2cReadBlockRequest, got: {:?}", msg),
+    }
+}
@@ -0,0 +1,62 @@
+use alloc::string::String;
+
+use cslice::{CMutSlice, CSlice};
+
+use super::{KERNEL_CHANNEL_0TO1, KERNEL_CHANNEL_1TO0, Message};
+use crate::artiq_raise;
+
+// Named slots where a kernel can leave a message for the kernels that run after it,
+// e.g. for the idle kernel to hold the outputs at the values left by the last experiment.
+// Messages are kept by the comms CPU until they are replaced or the device reboots.
+
+pub const MESSAGE_MAX_SIZE: usize = 4096;
+
+fn slot_name(name: CSlice<u8>) -> String {
+    match String::from_utf8(name.as_ref().to_vec()) {
+        Ok(name) => name,
+        Err(_) => artiq_raise!("ValueError", "mailbox slot name is not valid UTF-8"),
+    }
+}
+
+/// Replaces the message in the slot `name`; an empty message clears the slot.
+pub extern "C" fn put(name: CSlice<u8>, message: &CSlice<u8>) {
+    if message.len() > MESSAGE_MAX_SIZE {
+        artiq_raise!(
+            "ValueError",
+            "mailbox message of {0} bytes is larger than the maximum of {1} bytes",
+            message.len() as i64,
+            MESSAGE_MAX_SIZE as i64,
+            0
+        );
+    }
+    let name = slot_name(name);
+    unsafe {
+        KERNEL_CHANNEL_1TO0
+            .as_mut()
+            .unwrap()
+            .send(Message::MailboxPutRequest(name, message.as_ref().to_vec()));
+    }
+}
+
+/// Copies the message in the slot `name` into `buffer`, as much as fits, and returns
+/// its length, or -1 if the slot is empty. The message is left in the slot.
+pub extern "C" fn get(name: CSlice<u8>, buffer: &mut CMutSlice<u8>) -> i32 {
+    let name = slot_name(name);
+    let reply = unsafe {
+        KERNEL_CHANNEL_1TO0
+            .as_mut()
+            .unwrap()
+            .send(Message::MailboxGetRequest(name));
+        KERNEL_CHANNEL_0TO1.as_mut().unwrap().recv()
+    };
+    match reply {
+        Message::MailboxGetReply(Some(message)) => {
+            for (dst, src) in buffer.as_mut_slice().iter_mut().zip(message.iter()) {
+                *dst = *src;
+            }
+            message.len() as i32
+        }
+        Message::MailboxGetReply(None) => -1,
+        _ => panic!("received unexpected reply to MailboxGetRequest: {:?}", reply),
+    }
+}
@@ -1,8 +1,10 @@
 use alloc::{string::String, vec::Vec};
-use core::ptr;
+use core::{ptr,
+           sync::atomic::{AtomicBool, AtomicU32}};
 
 #[cfg(has_drtio)]
-use libboard_artiq::drtioaux_proto::{CXP_PAYLOAD_MAX_SIZE, CXP_PAYLOAD_MAX_SIZE_U64};
+use libboard_artiq::drtioaux_proto::{CXP_PAYLOAD_MAX_SIZE, CXP_PAYLOAD_MAX_SIZE_U64, DESTINATION_MASK_SIZE,
+                                     GRABBER_ROI_COUNT};
 use libcortex_a9::{mutex::Mutex, semaphore::Semaphore, sync_channel};
 
 use crate::{RPCException, eh_artiq};
@@ -11,8 +13,11 @@ mod control;
 pub use control::Control;
 mod api;
 pub mod core1;
+pub mod core_dump;
 mod dma;
+mod fpu;
 pub mod i2c;
+pub mod profiler;
 mod rpc;
 #[cfg(ki_impl = "csr")]
 #[path = "rtio_csr.rs"]
@@ -20,11 +25,15 @@ pub mod rtio;
 #[cfg(ki_impl = "acp")]
 #[path = "rtio_acp.rs"]
 pub mod rtio;
-pub use dma::DmaRecorder;
+pub use dma::{DmaPlaybackStat, DmaRecorder, DmaTraceStat, trace_stat};
 mod cache;
+mod mailbox;
 #[cfg(any(has_drtio, has_cxp_grabber))]
 mod cxp;
+#[cfg(any(has_drtio, has_grabber))]
+mod grabber;
 mod linalg;
+mod shm;
 #[cfg(has_drtio)]
 mod subkernel;
 
@@ -39,11 +48,55 @@ pub enum SubkernelStatus {
     OtherError,
 }
 
+// How the DRTIO links are reset when a kernel initializes RTIO: a full reset also clears
+// the pending events of the satellites, a PHY reset only resets the master side of each link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtioResetPolicy {
+    Full,
+    Phy,
+    Skip,
+}
+
+impl RtioResetPolicy {
+    pub fn from_i32(policy: i32) -> Option<RtioResetPolicy> {
+        match policy {
+            0 => Some(RtioResetPolicy::Full),
+            1 => Some(RtioResetPolicy::Phy),
+            2 => Some(RtioResetPolicy::Skip),
+            _ => None,
+        }
+    }
+}
+
+// Bytes stored under each key, -1 for keys that are not set, along with the total and
+// free bytes of the filesystem holding the config, -1 when they are not known.
+#[derive(Debug, Clone)]
+pub struct StorageStats {
+    pub total: i64,
+    pub free: i64,
+    pub sizes: Vec<i64>,
+}
+
+impl StorageStats {
+    pub fn local(keys: &[String]) -> StorageStats {
+        let (total, free) = libconfig::space().map_or((-1, -1), |(total, free)| (total as i64, free as i64));
+        StorageStats {
+            total: total,
+            free: free,
+            sizes: keys
+                .iter()
+                .map(|key| libconfig::read(key).map_or(-1, |value| value.len() as i64))
+                .collect(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Message {
     LoadRequest(Vec<u8>),
     LoadCompleted,
     LoadFailed,
+    KernelArgs(Vec<u8>),
     StartRequest,
     KernelFinished,
     KernelException(
@@ -52,11 +105,22 @@ pub enum Message {
         &'static [(usize, usize)],
     ),
 
+    // None for the policy set by the comms CPU configuration
     #[cfg(has_drtio)]
-    RtioInitRequest,
+    RtioInitRequest(Option<RtioResetPolicy>),
     #[cfg(has_drtio)]
     RtioInitReply,
 
+    // memory moves handed to core1 by satman while no kernel is running
+    #[cfg(has_drtio)]
+    BulkCopyRequest {
+        src: *const u8,
+        dst: *mut u8,
+        len: usize,
+    },
+    #[cfg(has_drtio)]
+    BulkCopyCompleted,
+
     RpcSend {
         is_async: bool,
         data: Vec<u8>,
@@ -64,18 +128,52 @@ pub enum Message {
     RpcRecvRequest(*mut ()),
     RpcRecvReply(Result<usize, RPCException>),
 
+    StoredKernelRequest(String),
+    StoredKernelReply(bool),
+
+    WallClockRequest,
+    WallClockReply(Option<u64>),
+
+    DestinationGroupRequest(String),
+    DestinationGroupReply(Option<Vec<u8>>),
+
+    StorageStatsRequest {
+        destination: u8,
+        keys: Vec<String>,
+    },
+    // None if the destination could not be reached
+    StorageStatsReply(Option<StorageStats>),
+
     CacheGetRequest(String),
     CacheGetReply(Vec<i32>),
     CachePutRequest(String, Vec<i32>),
 
+    MailboxPutRequest(String, Vec<u8>),
+    MailboxGetRequest(String),
+    MailboxGetReply(Option<Vec<u8>>),
+
+    ShmMapRequest {
+        name: String,
+        length: usize,
+    },
+    // null if the region cannot be allocated
+    ShmMapReply {
+        data: *mut i32,
+        length: usize,
+    },
+
     DmaPutRequest(DmaRecorder),
     DmaEraseRequest(String),
     DmaGetRequest(String),
     DmaGetReply(Option<(i32, i64, bool)>),
+    DmaStatRequest(String),
+    DmaStatReply(Option<DmaTraceStat>),
     #[cfg(has_drtio)]
     DmaStartRemoteRequest {
         id: i32,
         timestamp: i64,
+        // destinations to play back on, all of those holding a part of the trace if None
+        targets: Option<[u8; DESTINATION_MASK_SIZE]>,
     },
     #[cfg(has_drtio)]
     DmaAwaitRemoteRequest(i32),
@@ -85,12 +183,42 @@ pub enum Message {
         error: u8,
         channel: u32,
         timestamp: u64,
+        stat: DmaPlaybackStat,
     },
 
     #[cfg(has_drtio)]
     UpDestinationsRequest(i32),
     #[cfg(has_drtio)]
     UpDestinationsReply(bool),
+    #[cfg(has_drtio)]
+    RtioCounterRequest(i32),
+    #[cfg(has_drtio)]
+    RtioCounterReply(Option<i64>),
+    #[cfg(has_drtio)]
+    RtioInputBatchRequest {
+        channel: u32,
+        timeout: i64,
+        max_count: usize,
+    },
+    // status is one of the RTIO_INPUT_BATCH_* values, or None if the destination is unreachable
+    #[cfg(has_drtio)]
+    RtioInputBatchReply {
+        status: Option<u8>,
+        events: Vec<(i64, i32)>,
+    },
+
+    InjectionRequest {
+        channel: i32,
+        overrd: i8,
+        value: i8,
+    },
+    // false if the destination of the channel is unreachable
+    InjectionReply(bool),
+    InjectionStatusRequest {
+        channel: i32,
+        overrd: i8,
+    },
+    InjectionStatusReply(Option<i8>),
 
     I2cStartRequest(u32),
     I2cRestartRequest(u32),
@@ -117,6 +245,23 @@ pub enum Message {
         address: u8,
         mask: u8,
     },
+    I2cWriteBlockRequest {
+        busno: u32,
+        data: Vec<u8>,
+    },
+    I2cWriteBlockReply {
+        succeeded: bool,
+        acked: usize,
+    },
+    I2cReadBlockRequest {
+        busno: u32,
+        length: usize,
+        ack: bool,
+    },
+    I2cReadBlockReply {
+        succeeded: bool,
+        data: Vec<u8>,
+    },
 
     #[cfg(has_drtio)]
     SubkernelLoadRunRequest {
@@ -124,6 +269,7 @@ pub enum Message {
         destination: u8,
         run: bool,
         timestamp: u64,
+        relative: bool,
     },
     #[cfg(has_drtio)]
     SubkernelLoadRunReply {
@@ -137,6 +283,15 @@ pub enum Message {
     #[cfg(has_drtio)]
     SubkernelAwaitFinishReply,
     #[cfg(has_drtio)]
+    SubkernelAwaitManyRequest {
+        ids: Vec<u32>,
+        timeout: i64,
+    },
+    #[cfg(has_drtio)]
+    SubkernelAwaitManyReply {
+        statuses: Vec<SubkernelStatus>,
+    },
+    #[cfg(has_drtio)]
     SubkernelMsgSend {
         id: u32,
         destination: Option<u8>,
@@ -155,8 +310,43 @@ pub enum Message {
         count: u8,
     },
     #[cfg(has_drtio)]
+    SubkernelBarrierRequest {
+        id: u32,
+        participants: Vec<u8>,
+        timeout: i64,
+    },
+    #[cfg(has_drtio)]
+    SubkernelBarrierReply,
+    #[cfg(has_drtio)]
+    SubkernelDatasetAppendRequest {
+        name: String,
+        data: Vec<u8>,
+    },
+    #[cfg(has_drtio)]
+    SubkernelDatasetAppendReply {
+        succeeded: bool,
+    },
+    #[cfg(has_drtio)]
+    SubkernelDatasetFetchRequest {
+        id: u32,
+        name: String,
+    },
+    #[cfg(has_drtio)]
+    SubkernelDatasetFetchReply(Option<Vec<u8>>),
+    #[cfg(has_drtio)]
     SubkernelError(SubkernelStatus),
     #[cfg(has_drtio)]
+    GrabberRoiRequest {
+        destination: u8,
+        grabber: u8,
+    },
+    #[cfg(has_drtio)]
+    GrabberRoiReply {
+        succeeded: bool,
+        frame: u32,
+        counts: [u32; GRABBER_ROI_COUNT],
+    },
+    #[cfg(has_drtio)]
     CXPError(String),
     #[cfg(has_drtio)]
     CXPReadRequest {
@@ -202,6 +392,12 @@ pub enum Message {
         height: u16,
         pixel_code: u16,
     },
+    #[cfg(has_drtio)]
+    CXPEventRequest {
+        destination: u8,
+    },
+    #[cfg(has_drtio)]
+    CXPEventReply(Option<(u8, u16, u64)>),
 }
 
 static CHANNEL_0TO1: Mutex<Option<sync_channel::Sender<'static, Message>>> = Mutex::new(None);
@@ -213,4 +409,10 @@ static mut KERNEL_CHANNEL_1TO0: Option<sync_channel::Sender<'static, Message>> =
 
 pub static mut KERNEL_IMAGE: *const core1::KernelImage = ptr::null();
 
+// number of DMA playbacks started by the kernel, reset by comms for each kernel run
+pub static DMA_PLAYBACK_COUNT: AtomicU32 = AtomicU32::new(0);
+
+// set by comms for kernels run without a host, whose RPCs are refused with an exception
+pub static HEADLESS: AtomicBool = AtomicBool::new(false);
+
 static INIT_LOCK: Mutex<()> = Mutex::new(());
@@ -0,0 +1,129 @@
+use alloc::vec::Vec;
+use core::{ptr::{addr_of, read_volatile, write_volatile},
+           sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering}};
+
+use byteorder::{ByteOrder, NativeEndian};
+use libboard_zynq::clocks::Clocks;
+
+use super::core_dump;
+
+extern "C" {
+    static __irq_stack1_start: u32;
+}
+
+// samples kept, the oldest ones are overwritten once the buffer is full
+pub const SAMPLE_COUNT: usize = 16384;
+pub const MIN_INTERVAL_US: u32 = 10;
+
+// Sampled from an IRQ rather than an FIQ: the FIQ is taken by the nFIQ line driven from the
+// gateware past the GIC (see libboard_artiq::fiq), and an FIQ on core 1 is treated as fatal.
+// Kernels run with IRQs enabled, so the samples only miss sections that mask them.
+
+// private timer of core 1, counting down at half the CPU clock
+pub const TIMER_INTERRUPT: u32 = 29;
+const TIMER_LOAD: *mut u32 = 0xf8f0_0600 as *mut u32;
+const TIMER_CONTROL: *mut u32 = 0xf8f0_0608 as *mut u32;
+const TIMER_STATUS: *mut u32 = 0xf8f0_060c as *mut u32;
+// enable, auto reload, interrupt enable
+const TIMER_CONTROL_RUN: u32 = 0b111;
+// banked for the private peripheral interrupts, so written from core 1 itself
+const GIC_SET_ENABLE: *mut u32 = 0xf8f0_1100 as *mut u32;
+const GIC_CLEAR_ENABLE: *mut u32 = 0xf8f0_1180 as *mut u32;
+
+// the interrupt entry saves r0-r12 and the return address on the interrupted stack of
+// the IRQ mode, and pushes the pointer to them at the top of the core 1 interrupt stack
+const SAVED_FRAME_OFFSET: usize = 8;
+const SAVED_PC: usize = 13;
+
+// PC and SP of core 1, sampled from the private timer interrupt while a kernel runs.
+// Written from the interrupt handler only, which cannot allocate.
+static mut SAMPLES: [(u32, u32); SAMPLE_COUNT] = [(0, 0); SAMPLE_COUNT];
+static TAKEN: AtomicUsize = AtomicUsize::new(0);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static INTERVAL_US: AtomicU32 = AtomicU32::new(1000);
+static LOAD_ADDR: AtomicU32 = AtomicU32::new(0);
+
+/// Clears the samples and profiles kernels from the next one started on, every
+/// `interval_us` microseconds.
+pub fn start(interval_us: u32) -> Result<(), &'static str> {
+    if interval_us < MIN_INTERVAL_US {
+        return Err("sampling interval too short");
+    }
+    ENABLED.store(false, Ordering::Release);
+    TAKEN.store(0, Ordering::Release);
+    INTERVAL_US.store(interval_us, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Stops sampling, the samples are kept until the next start.
+pub fn stop() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Called on core 1 before a kernel is run.
+pub fn arm(load_addr: usize) {
+    if !enabled() {
+        return;
+    }
+    LOAD_ADDR.store(load_addr as u32, Ordering::Relaxed);
+    let ticks_per_us = Clocks::get().cpu_3x2x() / 1_000_000;
+    let load = INTERVAL_US.load(Ordering::Relaxed).saturating_mul(ticks_per_us);
+    unsafe {
+        write_volatile(TIMER_CONTROL, 0);
+        write_volatile(TIMER_STATUS, 1);
+        write_volatile(TIMER_LOAD, load);
+        write_volatile(GIC_SET_ENABLE, 1 << TIMER_INTERRUPT);
+        write_volatile(TIMER_CONTROL, TIMER_CONTROL_RUN);
+    }
+}
+
+/// Called on core 1 once a kernel has finished, and when core 1 is restarted.
+pub fn disarm() {
+    unsafe {
+        write_volatile(TIMER_CONTROL, 0);
+        write_volatile(GIC_CLEAR_ENABLE, 1 << TIMER_INTERRUPT);
+        write_volatile(TIMER_STATUS, 1);
+    }
+}
+
+/// Called from the IRQ handler on core 1 for the private timer interrupt.
+pub unsafe fn sample() {
+    write_volatile(TIMER_STATUS, 1);
+    if !enabled() {
+        return;
+    }
+    let frame_ptr = (addr_of!(__irq_stack1_start) as usize - SAVED_FRAME_OFFSET) as *const *const u32;
+    let pc = read_volatile(read_volatile(frame_ptr).add(SAVED_PC));
+    let (sp, _) = core_dump::interrupted_sp();
+    let taken = TAKEN.load(Ordering::Relaxed);
+    SAMPLES[taken % SAMPLE_COUNT] = (pc, sp);
+    TAKEN.store(taken + 1, Ordering::Release);
+}
+
+/// Serializes the profile: the sampling interval in microseconds, the load address
+/// of the kernel, the number of samples taken and the number kept as 32-bit words,
+/// followed by the kept samples as PC and SP words, oldest first.
+pub fn get() -> Vec<u8> {
+    let taken = TAKEN.load(Ordering::Acquire);
+    let kept = core::cmp::min(taken, SAMPLE_COUNT);
+    let mut words = vec![
+        INTERVAL_US.load(Ordering::Relaxed),
+        LOAD_ADDR.load(Ordering::Relaxed),
+        taken as u32,
+        kept as u32,
+    ];
+    let samples = unsafe { &*addr_of!(SAMPLES) };
+    for i in taken - kept..taken {
+        let (pc, sp) = samples[i % SAMPLE_COUNT];
+        words.push(pc);
+        words.push(sp);
+    }
+    let mut data = vec![0; words.len() * 4];
+    NativeEndian::write_u32_into(&words, &mut data);
+    data
+}
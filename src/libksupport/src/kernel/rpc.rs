@@ -1,13 +1,26 @@
 //! Kernel-side RPC API
 
 use alloc::vec::Vec;
+use core::{mem, ptr, slice,
+           sync::atomic::Ordering};
 
-use cslice::CSlice;
+use cslice::{CMutSlice, CSlice};
+use libcortex_a9::cache::dcci_slice;
 
-use super::{KERNEL_CHANNEL_0TO1, KERNEL_CHANNEL_1TO0, Message};
-use crate::{eh_artiq, rpc::send_args};
+use super::{HEADLESS, KERNEL_CHANNEL_0TO1, KERNEL_CHANNEL_1TO0, Message, fpu};
+use crate::{artiq_raise, eh_artiq, rpc::send_args};
 
 fn rpc_send_common(is_async: bool, service: u32, tag: &CSlice<u8>, data: *const *const ()) {
+    // async RPCs of kernels run without a host are queued for the next host
+    if HEADLESS.load(Ordering::Relaxed) && !is_async {
+        artiq_raise!(
+            "RuntimeError",
+            "RPC {0} not available, the kernel runs without a host",
+            service as i64,
+            0,
+            0
+        );
+    }
     let core1_tx = unsafe { KERNEL_CHANNEL_1TO0.as_mut().unwrap() };
     let mut buffer = Vec::<u8>::new();
     send_args(&mut buffer, service, tag.as_ref(), data, true).expect("RPC encoding failed");
@@ -15,23 +28,104 @@ fn rpc_send_common(is_async: bool, service: u32, tag: &CSlice<u8>, data: *const
 }
 
 pub extern "C" fn rpc_send(service: u32, tag: &CSlice<u8>, data: *const *const ()) {
+    fpu::check();
     rpc_send_common(false, service, tag, data);
 }
 
 pub extern "C" fn rpc_send_async(service: u32, tag: &CSlice<u8>, data: *const *const ()) {
+    fpu::check();
     rpc_send_common(true, service, tag, data);
 }
 
+// Alignment of values placed in the receive region, enough for DMA bursts and cache lines
+const RECV_REGION_ALIGN: usize = 64;
+
+struct RecvRegion {
+    base: *mut u8,
+    len: usize,
+    used: usize,
+    threshold: usize,
+    // size requested by the last reply, which the next slot will hold
+    pending: usize,
+}
+
+static mut RECV_REGION: RecvRegion = RecvRegion {
+    base: ptr::null_mut(),
+    len: 0,
+    used: 0,
+    threshold: usize::MAX,
+    pending: 0,
+};
+
+pub unsafe fn init_recv_region() {
+    RECV_REGION = RecvRegion {
+        base: ptr::null_mut(),
+        len: 0,
+        used: 0,
+        threshold: usize::MAX,
+        pending: 0,
+    };
+}
+
+/// Places RPC reply values of at least `threshold` bytes into `buffer` instead of the kernel stack.
+/// Each call resets the region; an empty buffer disables it.
+pub extern "C" fn rpc_recv_region(buffer: &mut CMutSlice<u8>, threshold: i32) {
+    unsafe {
+        RECV_REGION = RecvRegion {
+            base: buffer.as_mut_ptr(),
+            len: buffer.len(),
+            used: 0,
+            threshold: if buffer.len() == 0 || threshold < 0 {
+                usize::MAX
+            } else {
+                threshold as usize
+            },
+            pending: 0,
+        };
+    }
+}
+
+unsafe fn region_slot(slot: *mut ()) -> *mut () {
+    let region = &mut *(&raw mut RECV_REGION);
+    let size = mem::replace(&mut region.pending, 0);
+    if size == 0 || size < region.threshold {
+        return slot;
+    }
+    let start = (region.base as usize + region.used).next_multiple_of(RECV_REGION_ALIGN) - region.base as usize;
+    if start + size > region.len {
+        // out of space, fall back to the stack allocation
+        return slot;
+    }
+    region.used = start + size;
+    region.base.add(start) as *mut ()
+}
+
+unsafe fn flush_region() {
+    let region = &*(&raw const RECV_REGION);
+    if region.used > 0 {
+        // values written by core0 must reach DDR before DMA or RTIO output reads them
+        dcci_slice(slice::from_raw_parts(region.base, region.used));
+    }
+}
+
 pub extern "C" fn rpc_recv(slot: *mut ()) -> usize {
     let reply = unsafe {
         let core1_rx = KERNEL_CHANNEL_0TO1.as_mut().unwrap();
         let core1_tx = KERNEL_CHANNEL_1TO0.as_mut().unwrap();
-        core1_tx.send(Message::RpcRecvRequest(slot));
+        core1_tx.send(Message::RpcRecvRequest(region_slot(slot)));
         core1_rx.recv()
     };
     match reply {
-        Message::RpcRecvReply(Ok(alloc_size)) => alloc_size,
+        Message::RpcRecvReply(Ok(0)) => {
+            unsafe { flush_region() };
+            0
+        }
+        Message::RpcRecvReply(Ok(alloc_size)) => {
+            unsafe { RECV_REGION.pending = alloc_size };
+            alloc_size
+        }
         Message::RpcRecvReply(Err(exception)) => unsafe {
+            RECV_REGION.pending = 0;
             eh_artiq::raise(&eh_artiq::Exception {
                 id: exception.id,
                 file: CSlice::new(exception.file as *const u8, usize::MAX),
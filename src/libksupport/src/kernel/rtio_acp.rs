@@ -6,6 +6,7 @@ use vcell::VolatileCell;
 
 #[cfg(has_drtio)]
 use super::{KERNEL_CHANNEL_0TO1, KERNEL_CHANNEL_1TO0, Message};
+use super::RtioResetPolicy;
 use crate::{artiq_raise, kernel::KERNEL_IMAGE, pl::csr, rtio_core};
 
 pub const RTIO_O_STATUS_WAIT: i32 = 1;
@@ -25,8 +26,8 @@ const BATCH_DISABLED: i8 = 0;
 
 #[repr(C)]
 pub struct TimestampedData {
-    timestamp: i64,
-    data: i32,
+    pub timestamp: i64,
+    pub data: i32,
 }
 
 #[repr(C, align(16))]
@@ -84,6 +85,19 @@ static mut OUT_BUFFER: OutBuffer = OutBuffer {
 };
 
 pub extern "C" fn init() {
+    reset(None)
+}
+
+/// Same as `init`, with the reset of the DRTIO links chosen by the kernel instead of the
+/// `rtio_reset_policy` config key: 0 for a full reset, 1 for the PHY only, 2 for none.
+pub extern "C" fn init_policy(policy: i32) {
+    match RtioResetPolicy::from_i32(policy) {
+        Some(policy) => reset(Some(policy)),
+        None => artiq_raise!("ValueError", "unknown RTIO reset policy {0}", policy as i64, 0, 0),
+    }
+}
+
+fn reset(_policy: Option<RtioResetPolicy>) {
     unsafe {
         rtio_core::reset_write(1);
         csr::rtio::in_base_write(&IN_BUFFER as *const InTransaction as u32);
@@ -92,7 +106,10 @@ pub extern "C" fn init() {
     }
     #[cfg(has_drtio)]
     unsafe {
-        KERNEL_CHANNEL_1TO0.as_mut().unwrap().send(Message::RtioInitRequest);
+        KERNEL_CHANNEL_1TO0
+            .as_mut()
+            .unwrap()
+            .send(Message::RtioInitRequest(_policy));
         match KERNEL_CHANNEL_0TO1.as_mut().unwrap().recv() {
             Message::RtioInitReply => (),
             other => panic!("Expected RtioInitReply after RtioInitRequest, but got {:?}", other),
@@ -4,6 +4,7 @@ use cslice::CSlice;
 
 #[cfg(has_drtio)]
 use super::{KERNEL_CHANNEL_0TO1, KERNEL_CHANNEL_1TO0, Message};
+use super::RtioResetPolicy;
 use crate::{artiq_raise, pl::csr, rtio_core};
 
 pub const RTIO_O_STATUS_WAIT: u8 = 1;
@@ -16,17 +17,33 @@ pub const RTIO_I_STATUS_DESTINATION_UNREACHABLE: u8 = 8;
 
 #[repr(C)]
 pub struct TimestampedData {
-    timestamp: i64,
-    data: i32,
+    pub timestamp: i64,
+    pub data: i32,
 }
 
 pub extern "C" fn init() {
+    reset(None)
+}
+
+/// Same as `init`, with the reset of the DRTIO links chosen by the kernel instead of the
+/// `rtio_reset_policy` config key: 0 for a full reset, 1 for the PHY only, 2 for none.
+pub extern "C" fn init_policy(policy: i32) {
+    match RtioResetPolicy::from_i32(policy) {
+        Some(policy) => reset(Some(policy)),
+        None => artiq_raise!("ValueError", "unknown RTIO reset policy {0}", policy as i64, 0, 0),
+    }
+}
+
+fn reset(_policy: Option<RtioResetPolicy>) {
     unsafe {
         rtio_core::reset_write(1);
     }
     #[cfg(has_drtio)]
     unsafe {
-        KERNEL_CHANNEL_1TO0.as_mut().unwrap().send(Message::RtioInitRequest);
+        KERNEL_CHANNEL_1TO0
+            .as_mut()
+            .unwrap()
+            .send(Message::RtioInitRequest(_policy));
         match KERNEL_CHANNEL_0TO1.as_mut().unwrap().recv() {
             Message::RtioInitReply => (),
             other => panic!("Expected RtioInitReply after RtioInitRequest, but got {:?}", other),
@@ -0,0 +1,52 @@
+use alloc::{boxed::Box, string::String};
+
+use cslice::{CMutSlice, CSlice};
+
+use super::{KERNEL_CHANNEL_0TO1, KERNEL_CHANNEL_1TO0, Message};
+use crate::artiq_raise;
+
+// Named regions of int32 kept by the comms CPU until the next reboot, so that state
+// such as feedback loop parameters survives from one kernel (e.g. the idle kernel)
+// to the next. Unlike cache values, they are accessed in place rather than copied.
+// A region is zero-filled when first mapped; mapping it again returns the same memory.
+
+pub extern "C" fn map(name: CSlice<u8>, length: i32) -> &'static mut CMutSlice<'static, i32> {
+    if length < 0 {
+        artiq_raise!(
+            "ValueError",
+            "invalid shared memory region length {0}",
+            length as i64,
+            0,
+            0
+        );
+    }
+    let name = match String::from_utf8(name.as_ref().to_vec()) {
+        Ok(name) => name,
+        Err(_) => artiq_raise!("ValueError", "shared memory region name is not valid UTF-8"),
+    };
+    let reply = unsafe {
+        KERNEL_CHANNEL_1TO0.as_mut().unwrap().send(Message::ShmMapRequest {
+            name: name,
+            length: length as usize,
+        });
+        KERNEL_CHANNEL_0TO1.as_mut().unwrap().recv()
+    };
+    match reply {
+        Message::ShmMapReply { data, length: actual } => {
+            if data.is_null() {
+                artiq_raise!("RuntimeError", "shared memory region cannot be allocated");
+            }
+            if actual != length as usize {
+                artiq_raise!(
+                    "ValueError",
+                    "shared memory region has {0} elements, not {1}",
+                    actual as i64,
+                    length as i64,
+                    0
+                );
+            }
+            Box::leak(Box::new(unsafe { CMutSlice::new(data, actual) }))
+        }
+        _ => panic!("received unexpected reply to ShmMapRequest: {:?}", reply),
+    }
+}
@@ -1,11 +1,44 @@
-use alloc::vec::Vec;
+use alloc::{string::String, vec::Vec};
 
-use cslice::CSlice;
+use cslice::{CMutSlice, CSlice};
+use libboard_artiq::drtioaux_proto::DATASET_NAME_MAX_SIZE;
 
-use super::{KERNEL_CHANNEL_0TO1, KERNEL_CHANNEL_1TO0, Message, SubkernelStatus, rtio::now_mu};
+use super::{KERNEL_CHANNEL_0TO1, KERNEL_CHANNEL_1TO0, Message, SubkernelStatus, core1::destination_group,
+            rtio::now_mu};
 use crate::{artiq_raise, eh_artiq, rpc::send_args};
 
 pub extern "C" fn load_run(id: u32, destination: u8, run: bool) {
+    load_run_at(id, destination, run, now_mu() as u64, false);
+}
+
+// Starts the subkernel `offset` mu after the request reaches its destination, as seen by
+// the destination counter, instead of at the current timeline position.
+pub extern "C" fn load_run_relative(id: u32, destination: u8, run: bool, offset: i64) {
+    if offset < 0 {
+        artiq_raise!("ValueError", "subkernel start offset must not be negative");
+    }
+    load_run_at(id, destination, run, offset as u64, true);
+}
+
+// Fans out to the subkernels of `ids` whose destination, given by `destinations`, belongs to
+// the group, all starting at the current timeline position. Returns how many were started.
+pub extern "C" fn load_run_group(ids: &CSlice<i32>, destinations: &CSlice<u8>, group: &CSlice<u8>, run: bool) -> i32 {
+    if destinations.len() < ids.len() {
+        artiq_raise!("ValueError", "destination list is shorter than the subkernel list");
+    }
+    let members = destination_group(group);
+    let timestamp = now_mu() as u64;
+    let mut started = 0;
+    for (&id, destination) in ids.as_ref().iter().zip(destinations.as_ref().iter()) {
+        if members.contains(destination) {
+            load_run_at(id as u32, *destination, run, timestamp, false);
+            started += 1;
+        }
+    }
+    started
+}
+
+fn load_run_at(id: u32, destination: u8, run: bool, timestamp: u64, relative: bool) {
     unsafe {
         KERNEL_CHANNEL_1TO0
             .as_mut()
@@ -14,7 +47,8 @@ pub extern "C" fn load_run(id: u32, destination: u8, run: bool) {
                 id: id,
                 destination: destination,
                 run: run,
-                timestamp: now_mu() as u64,
+                timestamp: timestamp,
+                relative: relative,
             });
     }
     match unsafe { KERNEL_CHANNEL_0TO1.as_mut().unwrap() }.recv() {
@@ -48,11 +82,75 @@ pub extern "C" fn await_finish(id: u32, timeout: i64) {
         Message::SubkernelError(SubkernelStatus::OtherError) => {
             artiq_raise!("SubkernelError", "An error occurred during subkernel operation")
         }
-        Message::SubkernelError(SubkernelStatus::Exception(raw_exception)) => eh_artiq::raise_raw(&raw_exception),
+        Message::SubkernelError(SubkernelStatus::Exception(raw_exception)) => eh_artiq::raise_raw(raw_exception),
         _ => panic!("expected SubkernelAwaitFinishReply after SubkernelAwaitFinishRequest"),
     }
 }
 
+// Waits for all of `ids` with a single deadline and writes the outcome of each to
+// `statuses`: 0 finished, 1 timed out, 2 not running, 3 communication lost, 4 other error.
+// Returns how many finished; an exception raised by any of them is raised instead.
+pub extern "C" fn await_many(ids: &CSlice<i32>, timeout: i64, statuses: &mut CMutSlice<i32>) -> i32 {
+    if statuses.len() < ids.len() {
+        artiq_raise!("ValueError", "status list is shorter than the subkernel list");
+    }
+    unsafe {
+        KERNEL_CHANNEL_1TO0
+            .as_mut()
+            .unwrap()
+            .send(Message::SubkernelAwaitManyRequest {
+                ids: ids.as_ref().iter().map(|&id| id as u32).collect(),
+                timeout: timeout,
+            });
+    }
+    match unsafe { KERNEL_CHANNEL_0TO1.as_mut().unwrap() }.recv() {
+        Message::SubkernelAwaitManyReply { statuses: replies } => {
+            let mut finished = 0;
+            for (status, reply) in statuses.as_mut_slice().iter_mut().zip(replies.iter()) {
+                *status = match reply {
+                    SubkernelStatus::NoError => {
+                        finished += 1;
+                        0
+                    }
+                    SubkernelStatus::Timeout => 1,
+                    SubkernelStatus::IncorrectState => 2,
+                    SubkernelStatus::CommLost => 3,
+                    _ => 4,
+                };
+            }
+            finished
+        }
+        Message::SubkernelError(SubkernelStatus::Exception(raw_exception)) => eh_artiq::raise_raw(raw_exception),
+        Message::SubkernelError(_) => {
+            artiq_raise!("SubkernelError", "An error occurred during subkernel operation")
+        }
+        _ => panic!("expected SubkernelAwaitManyReply after SubkernelAwaitManyRequest"),
+    }
+}
+
+// blocks until every destination in `participants` has reached barrier `id`
+pub extern "C" fn barrier(id: u32, participants: &CSlice<u8>, timeout: i64) {
+    unsafe {
+        KERNEL_CHANNEL_1TO0
+            .as_mut()
+            .unwrap()
+            .send(Message::SubkernelBarrierRequest {
+                id: id,
+                participants: participants.as_ref().to_vec(),
+                timeout: timeout,
+            });
+    }
+    match unsafe { KERNEL_CHANNEL_0TO1.as_mut().unwrap() }.recv() {
+        Message::SubkernelBarrierReply => (),
+        Message::SubkernelError(SubkernelStatus::Timeout) => artiq_raise!("SubkernelError", "Barrier timed out"),
+        Message::SubkernelError(SubkernelStatus::CommLost) => {
+            artiq_raise!("SubkernelError", "Lost communication with satellite")
+        }
+        Message::SubkernelError(_) => artiq_raise!("SubkernelError", "Barrier failed"),
+        _ => panic!("expected SubkernelBarrierReply after SubkernelBarrierRequest"),
+    }
+}
+
 pub extern "C" fn send_message(
     id: u32,
     is_return: bool,
@@ -105,8 +203,76 @@ pub extern "C" fn await_message(id: i32, timeout: i64, tags: &CSlice<u8>, min: u
         Message::SubkernelError(SubkernelStatus::OtherError) => {
             artiq_raise!("SubkernelError", "An error occurred during subkernel operation")
         }
-        Message::SubkernelError(SubkernelStatus::Exception(raw_exception)) => eh_artiq::raise_raw(&raw_exception),
+        Message::SubkernelError(SubkernelStatus::Exception(raw_exception)) => eh_artiq::raise_raw(raw_exception),
         _ => panic!("expected SubkernelMsgRecvReply after SubkernelMsgRecvRequest"),
     }
     // RpcRecvRequest should be called after this to receive message data
 }
+
+fn dataset_name(name: CSlice<u8>) -> String {
+    if name.len() > DATASET_NAME_MAX_SIZE {
+        artiq_raise!(
+            "ValueError",
+            "dataset name of {0} bytes is longer than the maximum of {1} bytes",
+            name.len() as i64,
+            DATASET_NAME_MAX_SIZE as i64,
+            0
+        );
+    }
+    match String::from_utf8(name.as_ref().to_vec()) {
+        Ok(name) => name,
+        Err(_) => artiq_raise!("ValueError", "dataset name is not valid UTF-8"),
+    }
+}
+
+/// Appends `data` to the dataset `name`, buffered on the satellite until the master fetches
+/// it after the subkernel has finished. Only subkernels can buffer datasets.
+pub extern "C" fn dataset_append(name: CSlice<u8>, data: &CSlice<u8>) {
+    let name = dataset_name(name);
+    unsafe {
+        KERNEL_CHANNEL_1TO0
+            .as_mut()
+            .unwrap()
+            .send(Message::SubkernelDatasetAppendRequest {
+                name: name,
+                data: data.as_ref().to_vec(),
+            });
+    }
+    match unsafe { KERNEL_CHANNEL_0TO1.as_mut().unwrap() }.recv() {
+        Message::SubkernelDatasetAppendReply { succeeded: true } => (),
+        Message::SubkernelDatasetAppendReply { succeeded: false } => {
+            artiq_raise!("SubkernelError", "Dataset buffer is full or not available")
+        }
+        _ => panic!("expected SubkernelDatasetAppendReply after SubkernelDatasetAppendRequest"),
+    }
+}
+
+/// Copies the dataset `name` left by subkernel `id` into `buffer`, as much as fits, and
+/// returns its length, or -1 if the subkernel did not leave such a dataset.
+pub extern "C" fn dataset_fetch(id: u32, name: CSlice<u8>, buffer: &mut CMutSlice<u8>) -> i32 {
+    let name = dataset_name(name);
+    unsafe {
+        KERNEL_CHANNEL_1TO0
+            .as_mut()
+            .unwrap()
+            .send(Message::SubkernelDatasetFetchRequest { id: id, name: name });
+    }
+    match unsafe { KERNEL_CHANNEL_0TO1.as_mut().unwrap() }.recv() {
+        Message::SubkernelDatasetFetchReply(Some(data)) => {
+            let len = core::cmp::min(data.len(), buffer.len());
+            buffer.as_mut_slice()[..len].copy_from_slice(&data[..len]);
+            data.len() as i32
+        }
+        Message::SubkernelDatasetFetchReply(None) => -1,
+        Message::SubkernelError(SubkernelStatus::IncorrectState) => {
+            artiq_raise!("SubkernelError", "Subkernel has not finished")
+        }
+        Message::SubkernelError(SubkernelStatus::CommLost) => {
+            artiq_raise!("SubkernelError", "Lost communication with satellite")
+        }
+        Message::SubkernelError(_) => {
+            artiq_raise!("SubkernelError", "An error occurred while fetching the subkernel dataset")
+        }
+        _ => panic!("expected SubkernelDatasetFetchReply after SubkernelDatasetFetchRequest"),
+    }
+}
@@ -1,7 +1,8 @@
+use alloc::{vec, vec::Vec};
 use core::str;
 
 use byteorder::{ByteOrder, NativeEndian};
-use core_io::Error;
+use core_io::{Error, ErrorKind};
 use cslice::{CMutSlice, CSlice};
 use io::{ProtoRead, ProtoWrite};
 use log::trace;
@@ -35,6 +36,108 @@ pub unsafe fn align_ptr_mut<T>(ptr: *mut ()) -> *mut T {
     round_up_mut(ptr, core::mem::align_of::<T>()) as *mut T
 }
 
+/// Copies the elements of a strided ndarray view, received as the `span` of host
+/// memory between its lowest and highest addressed elements, into contiguous
+/// C-order storage at `dest`. `strides` and `offset` (the position of the first
+/// element in the span) are counted in elements and may be negative.
+///
+/// Returns false, without writing anything, if the view reaches outside the span.
+pub unsafe fn gather_strided(
+    dest: *mut u8,
+    span: &[u8],
+    elt_size: usize,
+    dims: &[usize],
+    strides: &[isize],
+    offset: isize,
+) -> bool {
+    if dims.iter().any(|&len| len == 0) {
+        return true;
+    }
+    let span_len = (span.len() / elt_size) as isize;
+    match strided_bounds(dims, strides, offset) {
+        Some((lowest, highest)) if lowest >= 0 && highest < span_len => (),
+        _ => return false,
+    }
+
+    let mut index = vec![0; dims.len()];
+    let mut position = offset;
+    let mut dest = dest;
+    loop {
+        let src = span.as_ptr().offset(position * elt_size as isize);
+        core::ptr::copy_nonoverlapping(src, dest, elt_size);
+        dest = dest.add(elt_size);
+        // advance the innermost dimension, carrying into the outer ones
+        let mut dim = dims.len();
+        loop {
+            if dim == 0 {
+                return true;
+            }
+            dim -= 1;
+            index[dim] += 1;
+            position += strides[dim];
+            if index[dim] < dims[dim] {
+                break;
+            }
+            position -= strides[dim] * dims[dim] as isize;
+            index[dim] = 0;
+        }
+    }
+}
+
+/// Positions of the lowest and highest addressed elements of a non-empty strided
+/// view, or None if they do not fit in an isize.
+fn strided_bounds(dims: &[usize], strides: &[isize], offset: isize) -> Option<(isize, isize)> {
+    let (mut lowest, mut highest) = (offset, offset);
+    for (&len, &stride) in dims.iter().zip(strides) {
+        let last = len.checked_sub(1)?;
+        if last > isize::MAX as usize {
+            return None;
+        }
+        let extent = stride.checked_mul(last as isize)?;
+        if extent < 0 {
+            lowest = lowest.checked_add(extent)?;
+        } else {
+            highest = highest.checked_add(extent)?;
+        }
+    }
+    Some((lowest, highest))
+}
+
+/// Checks the header of a strided array received from the host before anything is
+/// allocated for it. Returns the sizes in bytes of the contiguous array and of its
+/// span, or None if either overflows or `span_len` is not the length of the span
+/// the view reaches, which is never more than the total length of a contiguous view.
+pub fn strided_sizes(
+    elt_size: usize,
+    dims: &[usize],
+    strides: &[isize],
+    offset: isize,
+    span_len: usize,
+) -> Option<(usize, usize)> {
+    let total_len = dims.iter().try_fold(1usize, |total, &len| total.checked_mul(len))?;
+    let reach = if total_len == 0 {
+        0
+    } else {
+        match strided_bounds(dims, strides, offset)? {
+            (lowest, highest) if lowest >= 0 => highest as usize + 1,
+            _ => return None,
+        }
+    };
+    if span_len != reach {
+        return None;
+    }
+    Some((elt_size.checked_mul(total_len)?, elt_size.checked_mul(span_len)?))
+}
+
+/// Storage for the span of a strided array, aligned for any element type.
+pub fn span_storage(span_size: usize) -> Vec<u64> {
+    vec![0; round_up(span_size, 8) / 8]
+}
+
+fn too_large() -> Error {
+    Error::new(ErrorKind::InvalidData, "array too large")
+}
+
 // versions for reader rather than TcpStream
 // they will be made into sync for satellite subkernels later
 unsafe fn recv_elements<F, R: ProtoRead>(
@@ -136,9 +239,10 @@ where
 
                 let list_size = 4 + 4;
                 let storage_offset = round_up(list_size, tag.alignment());
-                let storage_size = tag.size() * length;
+                let storage_size = tag.size().checked_mul(length).ok_or_else(too_large)?;
 
-                let allocation = alloc(storage_offset + storage_size) as *mut u8;
+                let allocation_size = storage_offset.checked_add(storage_size).ok_or_else(too_large)?;
+                let allocation = alloc(allocation_size) as *mut u8;
                 *ptr_to_list = allocation as *mut List;
                 let storage = allocation.offset(storage_offset as isize) as *mut ();
 
@@ -152,15 +256,44 @@ where
                 let mut total_len: usize = 1;
                 for _ in 0..num_dims {
                     let len = reader.read_u32::<NativeEndian>()? as usize;
-                    total_len *= len;
+                    total_len = total_len.checked_mul(len).ok_or_else(too_large)?;
                     consume_value!(usize, |ptr| *ptr = len)
                 }
 
                 let elt_tag = it.clone().next().expect("truncated tag");
-                *buffer = alloc(elt_tag.size() * total_len);
+                let size = elt_tag.size().checked_mul(total_len).ok_or_else(too_large)?;
+                *buffer = alloc(size);
                 recv_elements(reader, elt_tag, total_len, *buffer, alloc)
             })
         }
+        Tag::StridedArray(it, num_dims) => {
+            consume_value!(*mut (), |buffer| {
+                let mut dims = Vec::with_capacity(num_dims as usize);
+                for _ in 0..num_dims {
+                    let len = reader.read_u32::<NativeEndian>()? as usize;
+                    dims.push(len);
+                    consume_value!(usize, |ptr| *ptr = len)
+                }
+                let mut strides = Vec::with_capacity(num_dims as usize);
+                for _ in 0..num_dims {
+                    strides.push(reader.read_u32::<NativeEndian>()? as i32 as isize);
+                }
+                let offset = reader.read_u32::<NativeEndian>()? as i32 as isize;
+                let span_len = reader.read_u32::<NativeEndian>()? as usize;
+
+                let elt_tag = it.clone().next().expect("truncated tag");
+                let (size, span_size) = strided_sizes(elt_tag.size(), &dims, &strides, offset, span_len)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "strided array does not match its span"))?;
+                *buffer = alloc(size);
+                let mut span = span_storage(span_size);
+                recv_elements(reader, elt_tag, span_len, span.as_mut_ptr() as *mut (), alloc)?;
+                let span = core::slice::from_raw_parts(span.as_ptr() as *const u8, span_size);
+                if !gather_strided(*buffer as *mut u8, span, elt_tag.size(), &dims, &strides, offset) {
+                    return Err(Error::new(ErrorKind::InvalidData, "strided array outside of its span"));
+                }
+                Ok(())
+            })
+        }
         Tag::Range(it) => {
             *data = round_up_mut(*data, tag.alignment());
             let tag = it.clone().next().expect("truncated tag");
@@ -243,6 +376,10 @@ unsafe fn send_value<W: ProtoWrite>(
         }};
     }
 
+    // arrays are always contiguous in kernel memory
+    if let Tag::StridedArray(it, num_dims) = tag {
+        return send_value(writer, Tag::Array(it, num_dims), data, write_tags);
+    }
     if write_tags {
         writer.write_u8(tag.as_u8())?;
     }
@@ -302,6 +439,7 @@ unsafe fn send_value<W: ProtoWrite>(
                 send_elements(writer, elt_tag, length, *buffer, write_tags)
             })
         }
+        Tag::StridedArray(_, _) => unreachable!(),
         Tag::Range(it) => {
             let tag = it.clone().next().expect("truncated tag");
             send_value(writer, tag, data, write_tags)?;
@@ -387,6 +525,8 @@ pub mod tag {
         Tuple(TagIterator<'a>, u8),
         List(TagIterator<'a>),
         Array(TagIterator<'a>, u8),
+        // same kernel layout as Array, only received from the host as a strided view
+        StridedArray(TagIterator<'a>, u8),
         Range(TagIterator<'a>),
         Keyword(TagIterator<'a>),
         Object,
@@ -406,6 +546,7 @@ pub mod tag {
                 Tag::Tuple(_, _) => b't',
                 Tag::List(_) => b'l',
                 Tag::Array(_, _) => b'a',
+                Tag::StridedArray(_, _) => b'v',
                 Tag::Range(_) => b'r',
                 Tag::Keyword(_) => b'k',
                 Tag::Object => b'O',
@@ -430,9 +571,12 @@ pub mod tag {
                     it.take(3).map(|t| t.alignment()).max().unwrap()
                 }
                 // the ptr/length(s) pair is basically CSlice
-                Tag::Bytes | Tag::String | Tag::ByteArray | Tag::List(_) | Tag::Array(_, _) => {
-                    core::mem::align_of::<CSlice<()>>()
-                }
+                Tag::Bytes
+                | Tag::String
+                | Tag::ByteArray
+                | Tag::List(_)
+                | Tag::Array(_, _)
+                | Tag::StridedArray(_, _) => core::mem::align_of::<CSlice<()>>(),
                 Tag::Keyword(_) => unreachable!("Tag::Keyword should not appear in composite types"),
                 Tag::Object => core::mem::align_of::<u32>(),
             }
@@ -465,7 +609,7 @@ pub mod tag {
                     size
                 }
                 Tag::List(_) => 4,
-                Tag::Array(_, num_dims) => 4 * (1 + num_dims as usize),
+                Tag::Array(_, num_dims) | Tag::StridedArray(_, num_dims) => 4 * (1 + num_dims as usize),
                 Tag::Range(it) => {
                     let tag = it.clone().next().expect("truncated tag");
                     tag.size() * 3
@@ -527,6 +671,11 @@ pub mod tag {
                     self.data = &self.data[1..];
                     Tag::Array(self.sub(1), count)
                 }
+                b'v' => {
+                    let count = self.data[0];
+                    self.data = &self.data[1..];
+                    Tag::StridedArray(self.sub(1), count)
+                }
                 b'r' => Tag::Range(self.sub(1)),
                 b'k' => Tag::Keyword(self.sub(1)),
                 b'O' => Tag::Object,
@@ -570,6 +719,11 @@ pub mod tag {
                         it.fmt(f)?;
                         write!(f, ", {})", num_dims)?;
                     }
+                    Tag::StridedArray(it, num_dims) => {
+                        write!(f, "StridedArray(")?;
+                        it.fmt(f)?;
+                        write!(f, ", {})", num_dims)?;
+                    }
                     Tag::Range(it) => {
                         write!(f, "Range(")?;
                         it.fmt(f)?;
@@ -1,15 +1,19 @@
-use alloc::rc::Rc;
-#[cfg(has_drtio)]
-use alloc::vec::Vec;
-use core::cell::RefCell;
+use alloc::{collections::BTreeMap, format, rc::Rc, string::String, vec::Vec};
+use core::{cell::RefCell,
+           fmt::Write,
+           sync::atomic::{AtomicBool, AtomicU32, Ordering}};
 
+use byteorder::{ByteOrder, NativeEndian};
 use libasync::{smoltcp::TcpStream, task};
-use libboard_artiq::drtio_routing;
+use libboard_artiq::{drtio_routing, resolve_channel_name};
 use libboard_zynq::smoltcp::Error;
+use libconfig;
 use libcortex_a9::cache;
 use log::{debug, info, warn};
 
-use crate::{pl, proto_async::*};
+#[cfg(has_drtio)]
+use crate::rtio_mgt::drtio;
+use crate::{net_buffers, net_stats, pl, proto_async::*, services};
 
 const BUFFER_SIZE: usize = 512 * 1024;
 
@@ -20,6 +24,18 @@ struct Buffer {
 
 static BUFFER: Buffer = Buffer { data: [0; BUFFER_SIZE] };
 
+// In auto-arm mode, set by the `analyzer_auto_arm` config key or over mgmt, the local and
+// satellite analyzers are armed right before each kernel is started and disarmed once it
+// has finished, instead of after each readout. The capture is then the session of that
+// kernel run, numbered as in the kernel statistics.
+static AUTO_ARM: AtomicBool = AtomicBool::new(false);
+// kernel run captured in auto-arm mode, 0 if none
+static SESSION_RUN: AtomicU32 = AtomicU32::new(0);
+static SESSION_OPEN: AtomicBool = AtomicBool::new(false);
+// the satellites were last armed for a kernel run, and so are not armed again after a readout
+#[cfg(has_drtio)]
+static REMOTE_SYNCHRONIZED: AtomicBool = AtomicBool::new(false);
+
 fn arm() {
     debug!("arming RTIO analyzer");
     unsafe {
@@ -43,6 +59,63 @@ fn disarm() {
     debug!("RTIO analyzer disarmed");
 }
 
+pub fn auto_arm() -> bool {
+    AUTO_ARM.load(Ordering::Relaxed)
+}
+
+pub fn set_auto_arm(enabled: bool) {
+    AUTO_ARM.store(enabled, Ordering::Relaxed);
+    // back to the analyzer being armed after each readout, it is not while waiting for one
+    if !enabled && !SESSION_OPEN.load(Ordering::Relaxed) && services::enabled(services::Service::Analyzer) {
+        SESSION_RUN.store(0, Ordering::Relaxed);
+        arm();
+    }
+}
+
+// Kernel run of the captured session, and whether that kernel has finished.
+pub fn session() -> Option<(u32, bool)> {
+    match SESSION_RUN.load(Ordering::Relaxed) {
+        0 => None,
+        run => Some((run, !SESSION_OPEN.load(Ordering::Relaxed))),
+    }
+}
+
+// Once auto-arming is turned off, satellites are armed again and left to re-arm after readouts.
+#[cfg(has_drtio)]
+async fn release_remote(up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>) {
+    if !auto_arm() && REMOTE_SYNCHRONIZED.swap(false, Ordering::Relaxed) {
+        drtio::analyzer_arm(up_destinations, true, false).await;
+    }
+}
+
+// Called right before the kernel CPU is asked to start a kernel.
+pub async fn kernel_started(kernel_run: u32, _up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>) {
+    if !auto_arm() || !services::enabled(services::Service::Analyzer) {
+        #[cfg(has_drtio)]
+        release_remote(_up_destinations).await;
+        return;
+    }
+    #[cfg(has_drtio)]
+    {
+        REMOTE_SYNCHRONIZED.store(true, Ordering::Relaxed);
+        drtio::analyzer_arm(_up_destinations, true, true).await;
+    }
+    arm();
+    SESSION_RUN.store(kernel_run, Ordering::Relaxed);
+    SESSION_OPEN.store(true, Ordering::Relaxed);
+}
+
+// Called once the kernel has finished, whether normally, with an exception or interrupted.
+pub async fn kernel_finished(_up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>) {
+    if !SESSION_OPEN.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    disarm();
+    #[cfg(has_drtio)]
+    drtio::analyzer_arm(_up_destinations, false, true).await;
+    info!("analyzer captured kernel run {}", SESSION_RUN.load(Ordering::Relaxed));
+}
+
 #[cfg(has_drtio)]
 pub mod remote_analyzer {
     use super::*;
@@ -103,6 +176,176 @@ async fn write_header(stream: &mut TcpStream, header: &Header) -> Result<(), Err
     Ok(())
 }
 
+// What is sent over the analyzer socket, selected with the `analyzer_format` config key:
+// the raw gateware dump (default), a summary of the events of each channel, or VCD text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Raw,
+    Summary,
+    Vcd,
+}
+
+fn get_format() -> Format {
+    match libconfig::read_str("analyzer_format") {
+        Ok(format) => match format.as_ref() {
+            "raw" => Format::Raw,
+            "summary" => Format::Summary,
+            "vcd" => Format::Vcd,
+            _ => {
+                warn!("analyzer_format \"{}\" not supported, sending the raw dump", format);
+                Format::Raw
+            }
+        },
+        Err(_) => Format::Raw,
+    }
+}
+
+// Each analyzer message is 32 bytes, starting with the message type (2 bits) and
+// channel (30 bits), followed by the timestamp, RTIO counter, address and data.
+const MESSAGE_LEN: usize = 32;
+const MESSAGE_TYPE_OUTPUT: u32 = 0;
+const MESSAGE_TYPE_INPUT: u32 = 1;
+const MESSAGE_TYPE_EXCEPTION: u32 = 2;
+
+// decoded text is sent in chunks of about this size
+const TEXT_CHUNK: usize = 4096;
+
+struct Event {
+    channel: u32,
+    timestamp: u64,
+    data: u64,
+    input: bool,
+}
+
+#[derive(Default)]
+struct ChannelSummary {
+    outputs: u32,
+    inputs: u32,
+    first: u64,
+    last: u64,
+}
+
+fn decode_messages(data: &[u8], events: &mut Vec<Event>, exceptions: &mut u32) {
+    for message in data.chunks_exact(MESSAGE_LEN) {
+        let type_channel = NativeEndian::read_u32(&message[0..4]);
+        match type_channel & 0b11 {
+            message_type @ (MESSAGE_TYPE_OUTPUT | MESSAGE_TYPE_INPUT) => events.push(Event {
+                channel: type_channel >> 2,
+                timestamp: NativeEndian::read_u64(&message[4..12]),
+                data: NativeEndian::read_u64(&message[24..32]),
+                input: message_type == MESSAGE_TYPE_INPUT,
+            }),
+            MESSAGE_TYPE_EXCEPTION => *exceptions += 1,
+            _ => (),
+        }
+    }
+}
+
+fn channel_name(channel: u32) -> String {
+    match resolve_channel_name(channel) {
+        name if name == "unknown" => format!("ch{}", channel),
+        name => name,
+    }
+}
+
+// VCD identifiers are made of printable characters, from '!' to '~'
+fn vcd_identifier(channel: u32) -> String {
+    let mut identifier = String::new();
+    let mut index = channel;
+    loop {
+        identifier.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return identifier;
+        }
+    }
+}
+
+async fn flush_text(stream: &mut TcpStream, text: &mut String, force: bool) -> Result<(), Error> {
+    if force || text.len() >= TEXT_CHUNK {
+        stream.send_slice(text.as_bytes()).await?;
+        text.clear();
+    }
+    Ok(())
+}
+
+async fn write_summary(
+    stream: &mut TcpStream,
+    events: &[Event],
+    exceptions: u32,
+    error: bool,
+    session: Option<(u32, bool)>,
+) -> Result<(), Error> {
+    let mut channels: BTreeMap<u32, ChannelSummary> = BTreeMap::new();
+    for event in events.iter() {
+        let summary = channels.entry(event.channel).or_insert(ChannelSummary {
+            first: event.timestamp,
+            last: event.timestamp,
+            ..Default::default()
+        });
+        if event.input {
+            summary.inputs += 1;
+        } else {
+            summary.outputs += 1;
+        }
+        summary.first = summary.first.min(event.timestamp);
+        summary.last = summary.last.max(event.timestamp);
+    }
+    let mut text = String::new();
+    if let Some((run, _)) = session {
+        let _ = writeln!(text, "# kernel run\t{}", run);
+    }
+    let _ = writeln!(text, "# channel\tname\toutputs\tinputs\tfirst_mu\tlast_mu");
+    for (channel, summary) in channels.iter() {
+        let _ = writeln!(
+            text,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            channel,
+            channel_name(*channel),
+            summary.outputs,
+            summary.inputs,
+            summary.first,
+            summary.last
+        );
+        flush_text(stream, &mut text, false).await?;
+    }
+    let _ = writeln!(text, "# exceptions\t{}", exceptions);
+    if error {
+        let _ = writeln!(text, "# error occurred, the dump is incomplete");
+    }
+    flush_text(stream, &mut text, true).await
+}
+
+async fn write_vcd(stream: &mut TcpStream, events: &mut [Event], session: Option<(u32, bool)>) -> Result<(), Error> {
+    events.sort_by_key(|event| event.timestamp);
+    let mut channels: Vec<u32> = events.iter().map(|event| event.channel).collect();
+    channels.sort();
+    channels.dedup();
+
+    let mut text = String::new();
+    if let Some((run, _)) = session {
+        let _ = writeln!(text, "$comment kernel run {} $end", run);
+    }
+    let _ = writeln!(text, "$timescale 1ns $end");
+    let _ = writeln!(text, "$scope module rtio $end");
+    for channel in channels.iter() {
+        let _ = writeln!(text, "$var wire 64 {} {} $end", vcd_identifier(*channel), channel_name(*channel));
+        flush_text(stream, &mut text, false).await?;
+    }
+    let _ = writeln!(text, "$upscope $end");
+    let _ = writeln!(text, "$enddefinitions $end");
+    let mut time = None;
+    for event in events.iter() {
+        if time != Some(event.timestamp) {
+            let _ = writeln!(text, "#{}", event.timestamp);
+            time = Some(event.timestamp);
+        }
+        let _ = writeln!(text, "b{:b} {}", event.data, vcd_identifier(event.channel));
+        flush_text(stream, &mut text, false).await?;
+    }
+    flush_text(stream, &mut text, true).await
+}
+
 async fn handle_connection(
     stream: &mut TcpStream,
     _up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
@@ -131,6 +374,8 @@ async fn handle_connection(
     #[cfg(has_drtio)]
     let remote = remote_analyzer::get_data(_up_destinations).await;
     #[cfg(has_drtio)]
+    release_remote(_up_destinations).await;
+    #[cfg(has_drtio)]
     let (header, remote_data) = match remote {
         Ok(remote) => (
             Header {
@@ -167,6 +412,29 @@ async fn handle_connection(
     };
     debug!("{:?}", header);
 
+    let session = session();
+    if let Some((run, finished)) = session {
+        if finished {
+            info!("sending the session of kernel run {}", run);
+        } else {
+            warn!("kernel run {} still running, its session is incomplete", run);
+        }
+    }
+
+    let format = get_format();
+    if format != Format::Raw {
+        let mut events = Vec::new();
+        let mut exceptions = 0;
+        decode_messages(&data[pointer..sent_bytes as usize], &mut events, &mut exceptions);
+        decode_messages(&data[..pointer], &mut events, &mut exceptions);
+        #[cfg(has_drtio)]
+        decode_messages(&remote_data, &mut events, &mut exceptions);
+        return match format {
+            Format::Summary => write_summary(stream, &events, exceptions, header.error_occurred, session).await,
+            _ => write_vcd(stream, &mut events, session).await,
+        };
+    }
+
     write_header(stream, &header).await?;
     if wraparound {
         stream.send(data[pointer..].iter().copied()).await?;
@@ -182,16 +450,36 @@ async fn handle_connection(
 
 pub fn start(up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>) {
     let up_destinations = up_destinations.clone();
+    match libconfig::read_str("analyzer_auto_arm") {
+        Ok(value) => match value.as_ref() {
+            "1" => AUTO_ARM.store(true, Ordering::Relaxed),
+            "0" => (),
+            _ => warn!("analyzer_auto_arm \"{}\" not supported, ignoring", value),
+        },
+        Err(_) => (),
+    }
+    if auto_arm() {
+        info!("analyzer armed for each kernel run");
+    }
     task::spawn(async move {
         loop {
-            arm();
-            let mut stream = TcpStream::accept(1382, 2048, 2048).await.unwrap();
+            // in auto-arm mode, the capture of the last kernel run is kept until the next one
+            if !auto_arm() {
+                SESSION_RUN.store(0, Ordering::Relaxed);
+                arm();
+            }
+            let size = net_buffers::size(net_buffers::Server::Analyzer);
+            net_stats::listening(net_stats::Service::Analyzer);
+            let mut stream = TcpStream::accept(1382, size, size).await.unwrap();
             disarm();
-            let _ = handle_connection(&mut stream, &up_destinations)
+            net_stats::accepted(net_stats::Service::Analyzer);
+            let result = handle_connection(&mut stream, &up_destinations)
                 .await
                 .map_err(|e| warn!("connection terminated: {:?}", e));
+            net_stats::closed(net_stats::Service::Analyzer, result.is_err());
             let _ = stream.flush().await;
             let _ = stream.close().await;
+            net_stats::released(net_stats::Service::Analyzer);
         }
     });
 }
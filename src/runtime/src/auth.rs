@@ -0,0 +1,217 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use libasync::smoltcp::TcpStream;
+use libboard_zynq::{smoltcp, timer};
+use libconfig;
+use log::warn;
+
+use crate::proto_async::*;
+
+// Optional pre-shared key authentication of the network ports. With the `auth_key`
+// config key set and a port listed in `auth_ports`, the device sends a 32-byte
+// challenge right after the connection greeting, and the host has to answer with
+// HMAC-SHA256(auth_key, challenge) before anything else is accepted.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    Mgmt,
+    Coredev,
+    Moninj,
+}
+
+impl Port {
+    fn name(&self) -> &'static str {
+        match self {
+            Port::Mgmt => "mgmt",
+            Port::Coredev => "coredev",
+            Port::Moninj => "moninj",
+        }
+    }
+}
+
+static CHALLENGES: AtomicU32 = AtomicU32::new(0);
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5, 0xd807aa98,
+    0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+    0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8,
+    0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819,
+    0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+    0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+    0xc67178f2,
+];
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([block[4 * i], block[4 * i + 1], block[4 * i + 2], block[4 * i + 3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let mut v = h;
+        for i in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v = [v[0], v[0], v[1], v[2], v[3], v[4], v[5], v[6]];
+            v[0] = t1.wrapping_add(t2);
+            v[4] = v[4].wrapping_add(t1);
+        }
+        for i in 0..8 {
+            h[i] = h[i].wrapping_add(v[i]);
+        }
+    }
+
+    let mut digest = [0; 32];
+    for i in 0..8 {
+        digest[4 * i..4 * i + 4].copy_from_slice(&h[i].to_be_bytes());
+    }
+    digest
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block_key = [0; 64];
+    if key.len() > 64 {
+        block_key[..32].copy_from_slice(&sha256(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+    let mut inner: Vec<u8> = block_key.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(message);
+    let mut outer: Vec<u8> = block_key.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+// Keys that are never read back over the network.
+pub fn is_secret(key: &str) -> bool {
+    key == "auth_key"
+}
+
+fn enabled(port: Port) -> bool {
+    match libconfig::read_str("auth_ports") {
+        Ok(ports) => ports.split(',').any(|name| name.trim() == port.name()),
+        Err(_) => false,
+    }
+}
+
+// There is no hardware RNG: the challenge only has to differ between connections,
+// so it is derived from the timer and a connection counter.
+fn new_challenge(key: &[u8]) -> [u8; 32] {
+    let mut seed = key.to_vec();
+    seed.extend_from_slice(&timer::get_us().to_le_bytes());
+    seed.extend_from_slice(&CHALLENGES.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    sha256(&seed)
+}
+
+// Returns false if the host failed to authenticate, the connection should then be dropped.
+pub async fn challenge(stream: &TcpStream, port: Port) -> Result<bool, smoltcp::Error> {
+    if !enabled(port) {
+        return Ok(true);
+    }
+    let key = match libconfig::read("auth_key") {
+        Ok(key) if !key.is_empty() => key,
+        _ => {
+            warn!("authentication is enabled on the {} port, but auth_key is not set", port.name());
+            return Ok(false);
+        }
+    };
+    let challenge = new_challenge(&key);
+    stream.send_slice(&challenge).await?;
+    let mut response = [0; 32];
+    read_chunk(stream, &mut response).await?;
+    let expected = hmac_sha256(&key, &challenge);
+    // compared in constant time
+    let difference = expected
+        .iter()
+        .zip(response.iter())
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    if difference != 0 {
+        warn!("authentication failed on the {} port", port.name());
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(digits: &str) -> Vec<u8> {
+        (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // RFC 4231 test cases, the fifth one is truncated to 128 bits
+    #[test]
+    fn hmac_sha256_rfc4231() {
+        let cases: [(Vec<u8>, Vec<u8>, &str); 7] = [
+            (
+                [0x0b; 20].to_vec(),
+                b"Hi There".to_vec(),
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe".to_vec(),
+                b"what do ya want for nothing?".to_vec(),
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                [0xaa; 20].to_vec(),
+                [0xdd; 50].to_vec(),
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                (1..=25).collect(),
+                [0xcd; 50].to_vec(),
+                "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+            ),
+            (
+                [0x0c; 20].to_vec(),
+                b"Test With Truncation".to_vec(),
+                "a3b6167473100ee06e0c796c2955552b",
+            ),
+            (
+                [0xaa; 131].to_vec(),
+                b"Test Using Larger Than Block-Size Key - Hash Key First".to_vec(),
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+            (
+                [0xaa; 131].to_vec(),
+                b"This is a test using a larger than block-size key and a larger than block-size data. \
+                  The key needs to be hashed before being used by the HMAC algorithm."
+                    .to_vec(),
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+        for (key, data, expected) in cases.iter() {
+            let expected = hex(expected);
+            assert_eq!(&hmac_sha256(key, data)[..expected.len()], &expected[..]);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use libboard_artiq::{drtioaux::Packet, drtioaux_proto::AUX_TIMEOUT};
+use libconfig;
+use libcortex_a9::once_lock::OnceLock;
+use log::{info, warn};
+
+// Reply timeouts of aux transactions, by class of operation. Defaults can be raised
+// for long chains of repeaters with the `aux_timeouts` config key, read at boot,
+// e.g. "analyzer=1000,flash=2000"; the effective values are reported through mgmt.
+
+#[derive(Debug, Clone, Copy)]
+pub enum OperationClass {
+    Default = 0,
+    Analyzer = 1,
+    Dma = 2,
+    Subkernel = 3,
+    Mgmt = 4,
+    Flash = 5,
+}
+
+pub const CLASSES: [OperationClass; 6] = [
+    OperationClass::Default,
+    OperationClass::Analyzer,
+    OperationClass::Dma,
+    OperationClass::Subkernel,
+    OperationClass::Mgmt,
+    OperationClass::Flash,
+];
+
+impl OperationClass {
+    pub fn name(self) -> &'static str {
+        match self {
+            OperationClass::Default => "default",
+            OperationClass::Analyzer => "analyzer",
+            OperationClass::Dma => "dma",
+            OperationClass::Subkernel => "subkernel",
+            OperationClass::Mgmt => "mgmt",
+            OperationClass::Flash => "flash",
+        }
+    }
+
+    fn of(request: &Packet) -> OperationClass {
+        match request {
+            Packet::AnalyzerHeaderRequest { .. } | Packet::AnalyzerDataRequest { .. } => OperationClass::Analyzer,
+            Packet::DmaAddTraceRequest { .. }
+            | Packet::DmaRemoveTraceRequest { .. }
+            | Packet::DmaPlaybackRequest { .. }
+            | Packet::DmaPlaybackBroadcastRequest { .. }
+            | Packet::DmaStatRequest { .. } => OperationClass::Dma,
+            Packet::SubkernelAddDataRequest { .. }
+            | Packet::SubkernelLoadRunRequest { .. }
+            | Packet::SubkernelExceptionRequest { .. }
+            | Packet::SubkernelDatasetRequest { .. }
+            | Packet::SubkernelMessage { .. } => OperationClass::Subkernel,
+            Packet::CoreMgmtFlashRequest { .. }
+            | Packet::CoreMgmtFlashAddDataRequest { .. }
+            | Packet::CoreMgmtFlashFanOutRequest { .. }
+            | Packet::CoreMgmtConfigWriteRequest { .. }
+            | Packet::CoreMgmtConfigRemoveRequest { .. }
+            | Packet::CoreMgmtConfigEraseRequest { .. } => OperationClass::Flash,
+            Packet::CoreMgmtGetLogRequest { .. }
+            | Packet::CoreMgmtClearLogRequest { .. }
+            | Packet::CoreMgmtLogStatsRequest { .. }
+            | Packet::CoreMgmtConfigReadRequest { .. }
+            | Packet::CoreMgmtConfigReadContinue { .. }
+            | Packet::CoreMgmtConfigListRequest { .. }
+            | Packet::StorageStatsRequest { .. }
+            | Packet::StorageSpaceRequest { .. }
+            | Packet::CoreMgmtRebootRequest { .. }
+            | Packet::CoreMgmtAllocatorDebugRequest { .. }
+            | Packet::CoreMgmtSetSedSpreadRequest { .. }
+            | Packet::CoreMgmtKernelCacheRequest { .. }
+            | Packet::CoreMgmtAuxCaptureRequest { .. }
+            | Packet::CoreMgmtClockStatusRequest { .. }
+            | Packet::CoreMgmtAuxTraceEnable { .. }
+            | Packet::CoreMgmtAuxTraceRequest { .. }
+            | Packet::CoreMgmtAuxTraceContinue { .. }
+            | Packet::CoreMgmtIoExpanderStatusRequest { .. } => OperationClass::Mgmt,
+            _ => OperationClass::Default,
+        }
+    }
+}
+
+static TIMEOUTS: OnceLock<[u64; 6]> = OnceLock::new();
+
+fn load() -> [u64; 6] {
+    let mut timeouts = [AUX_TIMEOUT; 6];
+    if let Ok(config) = libconfig::read_str("aux_timeouts") {
+        for entry in config.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+            let mut parts = entry.splitn(2, '=');
+            let name = parts.next().unwrap().trim();
+            let class = CLASSES.iter().find(|class| class.name() == name);
+            match (class, parts.next().and_then(|ms| ms.trim().parse::<u64>().ok())) {
+                (Some(class), Some(ms)) if ms > 0 => {
+                    info!("aux timeout for {} set to {} ms", name, ms);
+                    timeouts[*class as usize] = ms;
+                }
+                _ => warn!("aux_timeouts entry \"{}\" not supported, ignoring", entry),
+            }
+        }
+    }
+    timeouts
+}
+
+pub fn get(class: OperationClass) -> u64 {
+    if let Some(timeouts) = TIMEOUTS.get() {
+        return timeouts[class as usize];
+    }
+    let timeouts = load();
+    let _ = TIMEOUTS.set(timeouts);
+    timeouts[class as usize]
+}
+
+pub fn for_request(request: &Packet) -> u64 {
+    get(OperationClass::of(request))
+}
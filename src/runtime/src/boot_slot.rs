@@ -0,0 +1,149 @@
+use alloc::vec::Vec;
+
+use libboard_artiq::config_store;
+use libconfig;
+use log::{error, info, warn};
+
+use crate::storage_health;
+
+// A/B boot images are kept on the SD card under the `boot_a` and `boot_b` keys.
+// The image of the active slot is also copied to `boot` (boot.bin), which is what
+// the bootloader loads. A freshly flashed slot is booted on trial: the runtime
+// confirms it once the network is up, and rolls back to the other slot if it
+// panics before that.
+
+type Result<T> = core::result::Result<T, ()>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A = 0,
+    B = 1,
+}
+
+impl Slot {
+    pub fn from_u8(value: u8) -> Option<Slot> {
+        match value {
+            0 => Some(Slot::A),
+            1 => Some(Slot::B),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Slot::A => "a",
+            Slot::B => "b",
+        }
+    }
+
+    fn image_key(self) -> &'static str {
+        match self {
+            Slot::A => "boot_a",
+            Slot::B => "boot_b",
+        }
+    }
+
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+pub fn active() -> Slot {
+    match libconfig::read_str("boot_slot") {
+        Ok(slot) => match slot.as_ref() {
+            "a" => Slot::A,
+            "b" => Slot::B,
+            _ => {
+                warn!("boot_slot value not supported (only a, b allowed), assuming slot a");
+                Slot::A
+            }
+        },
+        Err(_) => Slot::A,
+    }
+}
+
+pub fn is_trial() -> bool {
+    libconfig::read_str("boot_trial").map_or(false, |trial| trial == "1")
+}
+
+fn check_writable() -> Result<()> {
+    if storage_health::read_only() {
+        error!("config storage is read-only, boot slots cannot be changed");
+        return Err(());
+    }
+    Ok(())
+}
+
+fn activate(slot: Slot, image: Vec<u8>, trial: bool) -> Result<()> {
+    config_store::write("boot", image).map_err(|err| error!("failed to write boot image: {:?}", err))?;
+    config_store::write("boot_slot", slot.name().as_bytes().to_vec())
+        .map_err(|err| error!("failed to write boot_slot: {:?}", err))?;
+    if trial {
+        config_store::write("boot_trial", b"1".to_vec()).map_err(|err| error!("failed to write boot_trial: {:?}", err))
+    } else {
+        let _ = config_store::remove("boot_trial");
+        Ok(())
+    }
+}
+
+// Copies the running image to the key of the active slot, so that it can be rolled back to.
+// Devices flashed before boot slots existed, or by other means, have it in `boot` only.
+fn save_active() -> Result<()> {
+    let active = active();
+    let image = match libconfig::read("boot") {
+        Ok(image) => image,
+        Err(_) => {
+            warn!("no boot image to keep in slot {}", active.name());
+            return Ok(());
+        }
+    };
+    if libconfig::read(active.image_key()).map_or(false, |saved| saved == image) {
+        return Ok(());
+    }
+    info!("keeping the running boot image in slot {}", active.name());
+    config_store::write(active.image_key(), image).map_err(|err| error!("failed to write boot image slot: {:?}", err))
+}
+
+/// Writes the image to the inactive slot and makes it the trial boot image.
+pub fn install(image: Vec<u8>) -> Result<Slot> {
+    check_writable()?;
+    save_active()?;
+    let slot = active().other();
+    info!("writing boot image to slot {}", slot.name());
+    config_store::write(slot.image_key(), image.clone())
+        .map_err(|err| error!("failed to write boot image slot: {:?}", err))?;
+    activate(slot, image, true)?;
+    Ok(slot)
+}
+
+/// Makes a previously flashed slot the active one, without trial.
+pub fn select(slot: Slot) -> Result<()> {
+    check_writable()?;
+    let image = libconfig::read(slot.image_key()).map_err(|_| error!("boot slot {} is empty", slot.name()))?;
+    info!("selecting boot slot {}", slot.name());
+    activate(slot, image, false)
+}
+
+/// Called after a successful network bring-up.
+pub fn confirm() {
+    if is_trial() {
+        match config_store::remove("boot_trial") {
+            Ok(()) => info!("trial boot of slot {} succeeded", active().name()),
+            Err(err) => error!("failed to clear boot_trial: {:?}", err),
+        }
+    }
+}
+
+/// Called on panic; restores the previous slot if the current one is on trial.
+/// Returns true if a rollback happened and the device should be rebooted.
+pub fn rollback() -> bool {
+    if !is_trial() {
+        return false;
+    }
+    let previous = active().other();
+    error!("trial boot failed, falling back to slot {}", previous.name());
+    select(previous).is_ok()
+}
@@ -1,7 +1,11 @@
 #[cfg(has_drtio)]
 use alloc::string::ToString;
-use alloc::{collections::BTreeMap, rc::Rc, string::String, vec::Vec};
-use core::{cell::RefCell, fmt, slice, str};
+use alloc::{boxed::Box,
+            collections::{BTreeMap, VecDeque},
+            rc::Rc,
+            string::String,
+            vec::Vec};
+use core::{cell::{Cell, RefCell}, fmt, ptr, slice, str, sync::atomic::{AtomicBool, Ordering}};
 
 use core_io::Error as IoError;
 use cslice::CSlice;
@@ -16,15 +20,15 @@ use libasync::{block_async,
                smoltcp::{Sockets, TcpStream},
                task};
 #[cfg(has_drtio)]
-use libboard_artiq::drtioaux::Packet;
+use libboard_artiq::{drtioaux::Packet, drtioaux_proto::{GRABBER_ROI_COUNT, RTIO_INPUT_BATCH_UNSUPPORTED}};
 use libboard_artiq::{drtio_routing::{self, RoutingTable},
-                     resolve_channel_name};
+                     error_log, format_channel_info, resolve_channel_name, wall_clock};
 #[cfg(feature = "target_kasli_soc")]
 use libboard_zynq::error_led::ErrorLED;
 use libboard_zynq::{self as zynq,
                     i2c::Error as I2cError,
                     smoltcp::{self,
-                              iface::{EthernetInterfaceBuilder, NeighborCache},
+                              iface::{EthernetInterfaceBuilder, NeighborCache, Routes},
                               time::{Duration, Instant},
                               wire::IpCidr},
                     timer};
@@ -46,7 +50,8 @@ use void::Void;
 
 #[cfg(any(has_rtio_core, has_drtiosat, has_drtio))]
 use crate::pl;
-use crate::{analyzer, mgmt, moninj, proto_async::*, rpc_async, rtio_dma, rtio_mgt};
+use crate::{analyzer, auth, boot_slot, idle_kernel, mgmt, moninj, moninj_udp, net_buffers, net_routes, net_stats,
+            ping, proto_async::*, rpc_async, rtio_dma, rtio_mgt, selftest, services, storage_health};
 #[cfg(has_drtio)]
 use crate::{subkernel, subkernel::Error as SubkernelError};
 
@@ -55,6 +60,8 @@ pub enum Error {
     NetworkError(smoltcp::Error),
     IoError,
     UnexpectedPattern,
+    AuthFailed,
+    DeviceLocked,
     UnrecognizedPacket,
     BufferExhausted,
     #[cfg(has_drtio)]
@@ -71,6 +78,8 @@ impl fmt::Display for Error {
             Error::NetworkError(error) => write!(f, "network error: {}", error),
             Error::IoError => write!(f, "io error"),
             Error::UnexpectedPattern => write!(f, "unexpected pattern"),
+            Error::AuthFailed => write!(f, "authentication failed"),
+            Error::DeviceLocked => write!(f, "device locked"),
             Error::UnrecognizedPacket => write!(f, "unrecognized packet"),
             Error::BufferExhausted => write!(f, "buffer exhausted"),
             #[cfg(has_drtio)]
@@ -108,6 +117,10 @@ enum Request {
     RPCReply = 7,
     RPCException = 8,
     UploadSubkernel = 9,
+    StopKernel = 10,
+    LoadStoredKernel = 11,
+    ClaimDevice = 12,
+    ReleaseDevice = 13,
 }
 
 #[derive(Debug, FromPrimitive, ToPrimitive)]
@@ -121,6 +134,11 @@ enum Reply {
     RPCRequest = 10,
     WatchdogExpired = 14,
     ClockFailure = 15,
+    KernelInterrupted = 16,
+    DeviceClaimed = 17,
+    DeviceLocked = 18,
+    DeviceReleased = 19,
+    PushedRPC = 20,
 }
 
 pub static mut SEEN_ASYNC_ERRORS: u8 = 0;
@@ -149,6 +167,14 @@ fn wait_for_async_rtio_error() -> nb::Result<(), Void> {
     }
 }
 
+// summarizes the repeats of the rate-limited errors once their interval is over
+async fn flush_error_log() {
+    loop {
+        timer::async_delay_ms(1000).await;
+        error_log::flush();
+    }
+}
+
 pub async fn report_async_rtio_errors() {
     loop {
         let _ = block_async!(wait_for_async_rtio_error()).await;
@@ -159,27 +185,33 @@ pub async fn report_async_rtio_errors() {
             let errors = rtio_core::protocol_error_read();
             if errors & ASYNC_ERROR_COLLISION != 0 {
                 let channel = rtio_core::collision_channel_read();
-                error!(
-                    "RTIO collision involving channel 0x{:04x}:{}",
-                    channel,
-                    resolve_channel_name(channel as u32)
-                );
+                if error_log::should_log("collision", Some(channel as u32)) {
+                    error!(
+                        "RTIO collision involving channel 0x{:04x}:{}",
+                        channel,
+                        resolve_channel_name(channel as u32)
+                    );
+                }
             }
             if errors & ASYNC_ERROR_BUSY != 0 {
                 let channel = rtio_core::busy_channel_read();
-                error!(
-                    "RTIO busy error involving channel 0x{:04x}:{}",
-                    channel,
-                    resolve_channel_name(channel as u32)
-                );
+                if error_log::should_log("busy error", Some(channel as u32)) {
+                    error!(
+                        "RTIO busy error involving channel 0x{:04x}:{}",
+                        channel,
+                        resolve_channel_name(channel as u32)
+                    );
+                }
             }
             if errors & ASYNC_ERROR_SEQUENCE_ERROR != 0 {
                 let channel = rtio_core::sequence_error_channel_read();
-                error!(
-                    "RTIO sequence error involving channel 0x{:04x}:{}",
-                    channel,
-                    resolve_channel_name(channel as u32)
-                );
+                if error_log::should_log("sequence error", Some(channel as u32)) {
+                    error!(
+                        "RTIO sequence error involving channel 0x{:04x}:{}",
+                        channel,
+                        resolve_channel_name(channel as u32)
+                    );
+                }
             }
             SEEN_ASYNC_ERRORS = errors;
             #[cfg(has_rtio_core)]
@@ -190,12 +222,172 @@ pub async fn report_async_rtio_errors() {
     }
 }
 
-static CACHE_STORE: Mutex<BTreeMap<String, Vec<i32>>> = Mutex::new(BTreeMap::new());
+pub static CACHE_STORE: Mutex<BTreeMap<String, Vec<i32>>> = Mutex::new(BTreeMap::new());
+
+// messages left by kernels for the ones that run after them, e.g. the idle kernel
+static MAILBOX: Mutex<BTreeMap<String, Vec<u8>>> = Mutex::new(BTreeMap::new());
+
+// shared memory regions mapped by kernels, kept until reboot and never moved
+static SHM_STORE: Mutex<BTreeMap<String, Box<[i32]>>> = Mutex::new(BTreeMap::new());
+// limit on the memory taken by all shared memory regions, in bytes
+const SHM_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+fn shm_map(name: String, length: usize) -> (*mut i32, usize) {
+    let mut store = SHM_STORE.lock();
+    if let Some(region) = store.get_mut(&name) {
+        return (region.as_mut_ptr(), region.len());
+    }
+    let size: usize = store.values().map(|region| region.len()).sum::<usize>() + length;
+    if size * 4 > SHM_MAX_SIZE {
+        warn!("shared memory region \"{}\" would exceed {} bytes in total", name, SHM_MAX_SIZE);
+        return (ptr::null_mut(), 0);
+    }
+    let mut region = vec![0; length].into_boxed_slice();
+    let data = region.as_mut_ptr();
+    info!("shared memory region \"{}\" allocated, {} elements", name, length);
+    store.insert(name, region);
+    (data, length)
+}
 
 pub static RESTART_IDLE: Semaphore = Semaphore::new(1, 1);
 
+// set while a host holds the coredev port
+static HOST_SESSION: AtomicBool = AtomicBool::new(false);
+// kernel requested through the management port, run before the idle kernel
+static HEADLESS_KERNEL_REQUEST: Mutex<Option<HeadlessKernel>> = Mutex::new(None);
+// set from the request of a kernel sent over mgmt until its outcome is known
+static MGMT_KERNEL_PENDING: AtomicBool = AtomicBool::new(false);
+static MGMT_KERNEL_OUTCOME: Mutex<Option<core::result::Result<(), String>>> = Mutex::new(None);
+static MGMT_KERNEL_DONE: Semaphore = Semaphore::new(0, 1);
+// longest wait for a kernel sent over mgmt, which keeps running past it
+const MGMT_KERNEL_TIMEOUT_MS: u64 = 60_000;
+// Token of the host that claimed the device. While set, only sessions presenting it may
+// load or run kernels; it is kept across sessions until released or rebooted.
+static DEVICE_OWNER: Mutex<Option<String>> = Mutex::new(None);
+// bound on the greeting of a new host, which holds up accepting other ones
+const GREETING_TIMEOUT_MS: u64 = 10_000;
+
 pub static ROUTING_TABLE: OnceLock<RoutingTable> = OnceLock::new();
 
+// Async RPCs of kernels run without a host, such as the idle kernel, are queued and delivered
+// to the next host kernel before it starts, each with the wall clock time it was made at.
+// Once the queue is full the oldest are dropped, so that it holds the latest data.
+const PUSHED_RPC_MAX_COUNT: usize = 64;
+const PUSHED_RPC_MAX_BYTES: usize = 64 * 1024;
+
+struct PushedRpc {
+    unix_us: Option<u64>,
+    data: Vec<u8>,
+}
+
+struct PushedRpcs {
+    rpcs: VecDeque<PushedRpc>,
+    bytes: usize,
+    dropped: u32,
+}
+
+static PUSHED_RPCS: Mutex<PushedRpcs> = Mutex::new(PushedRpcs {
+    rpcs: VecDeque::new(),
+    bytes: 0,
+    dropped: 0,
+});
+
+fn push_rpc(data: Vec<u8>) {
+    let mut queue = PUSHED_RPCS.lock();
+    if data.len() > PUSHED_RPC_MAX_BYTES {
+        queue.dropped += 1;
+        return;
+    }
+    while queue.rpcs.len() >= PUSHED_RPC_MAX_COUNT || queue.bytes + data.len() > PUSHED_RPC_MAX_BYTES {
+        let oldest = queue.rpcs.pop_front().unwrap();
+        queue.bytes -= oldest.data.len();
+        queue.dropped += 1;
+    }
+    queue.bytes += data.len();
+    queue.rpcs.push_back(PushedRpc {
+        unix_us: wall_clock::get(),
+        data: data,
+    });
+}
+
+async fn deliver_pushed_rpcs(stream: &TcpStream) -> Result<()> {
+    let (rpcs, dropped) = {
+        let mut queue = PUSHED_RPCS.lock();
+        queue.bytes = 0;
+        (
+            core::mem::take(&mut queue.rpcs),
+            core::mem::replace(&mut queue.dropped, 0),
+        )
+    };
+    if dropped > 0 {
+        warn!("{} RPCs of kernels run without a host were dropped, the queue was full", dropped);
+    }
+    if !rpcs.is_empty() {
+        info!("delivering {} RPCs of kernels run without a host", rpcs.len());
+    }
+    for rpc in rpcs {
+        write_header(stream, Reply::PushedRPC).await?;
+        write_i64(stream, rpc.unix_us.map_or(-1, |unix_us| unix_us as i64)).await?;
+        // laid out as an async RPCRequest from there on
+        write_bool(stream, true).await?;
+        stream.send_slice(&rpc.data).await?;
+    }
+    Ok(())
+}
+
+// Counters are for the last kernel run, except kernels_run and total_duration_ms
+// which accumulate since boot.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelStats {
+    pub kernels_run: u32,
+    pub total_duration_ms: u64,
+    pub last_duration_ms: u64,
+    pub rpc_count: u32,
+    pub rpc_total_ms: u64,
+    pub rpc_max_ms: u64,
+    pub dma_playbacks: u32,
+}
+
+impl KernelStats {
+    const fn new() -> KernelStats {
+        KernelStats {
+            kernels_run: 0,
+            total_duration_ms: 0,
+            last_duration_ms: 0,
+            rpc_count: 0,
+            rpc_total_ms: 0,
+            rpc_max_ms: 0,
+            dma_playbacks: 0,
+        }
+    }
+
+    fn start_kernel(&mut self) {
+        self.kernels_run += 1;
+        self.last_duration_ms = 0;
+        self.rpc_count = 0;
+        self.rpc_total_ms = 0;
+        self.rpc_max_ms = 0;
+        self.dma_playbacks = 0;
+        kernel::DMA_PLAYBACK_COUNT.store(0, Ordering::Relaxed);
+    }
+
+    fn finish_kernel(&mut self, duration_ms: u64) {
+        self.last_duration_ms = duration_ms;
+        self.total_duration_ms += duration_ms;
+        self.dma_playbacks = kernel::DMA_PLAYBACK_COUNT.load(Ordering::Relaxed);
+    }
+
+    fn record_rpc(&mut self, latency_ms: Option<u64>) {
+        self.rpc_count += 1;
+        if let Some(latency_ms) = latency_ms {
+            self.rpc_total_ms += latency_ms;
+            self.rpc_max_ms = self.rpc_max_ms.max(latency_ms);
+        }
+    }
+}
+
+pub static KERNEL_STATS: Mutex<KernelStats> = Mutex::new(KernelStats::new());
+
 async fn write_header(stream: &TcpStream, reply: Reply) -> Result<()> {
     stream
         .send_slice(&[0x5a, 0x5a, 0x5a, 0x5a, reply.to_u8().unwrap()])
@@ -233,6 +425,38 @@ async fn read_bytes(stream: &TcpStream, max_length: usize) -> Result<Vec<u8>> {
     Ok(buffer)
 }
 
+const DEFAULT_KERNEL_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+pub fn kernel_max_size() -> usize {
+    match libconfig::read_str("kernel_max_size") {
+        Ok(size) => match size.parse() {
+            Ok(size) => size,
+            Err(_) => {
+                warn!("kernel_max_size \"{}\" not supported, ignoring", size);
+                DEFAULT_KERNEL_MAX_SIZE
+            }
+        },
+        Err(_) => DEFAULT_KERNEL_MAX_SIZE,
+    }
+}
+
+// Reads a kernel or subkernel image into a buffer allocated for its announced length,
+// as it arrives. Images over kernel_max_size, or that do not fit in memory, are refused.
+async fn read_kernel_image(stream: &TcpStream) -> Result<Vec<u8>> {
+    let length = read_i32(&stream).await? as u32 as usize;
+    let max_length = kernel_max_size();
+    let mut buffer = Vec::new();
+    if length > max_length || buffer.try_reserve_exact(length).is_err() {
+        error!("kernel image of {} bytes refused (kernel_max_size is {})", length, max_length);
+        write_header(stream, Reply::LoadFailed).await?;
+        write_chunk(stream, b"kernel image too large").await?;
+        return Err(Error::BufferExhausted);
+    }
+    buffer.resize(length, 0);
+    read_chunk(&stream, &mut buffer).await?;
+    Ok(buffer)
+}
+
 const RETRY_LIMIT: usize = 100;
 
 async fn fast_send(sender: &mut Sender<'_, kernel::Message>, content: kernel::Message) {
@@ -258,6 +482,38 @@ async fn fast_recv(receiver: &mut Receiver<'_, kernel::Message>) -> kernel::Mess
     receiver.async_recv().await
 }
 
+async fn recv_kernel_message(
+    stream: Option<&TcpStream>,
+    control: &Rc<RefCell<kernel::Control>>,
+) -> Option<kernel::Message> {
+    // None when the host sent a request while the kernel is running
+    let mut control = control.borrow_mut();
+    match stream {
+        Some(stream) => select_biased! {
+            message = control.rx.async_recv().fuse() => Some(message),
+            // peek only, the request itself is read by the caller
+            _ = stream.recv(|_| (0, ())).fuse() => None,
+        },
+        None => Some(control.rx.async_recv().await),
+    }
+}
+
+async fn interrupt_kernel(stream: &TcpStream, control: &Rc<RefCell<kernel::Control>>) -> Result<()> {
+    match read_request(stream, false).await?.unwrap() {
+        Request::StopKernel => {
+            info!("kernel interrupted by host");
+            // restarting core1 also drops any pending RPC messages in the channels
+            control.borrow_mut().restart();
+            write_header(stream, Reply::KernelInterrupted).await?;
+            Ok(())
+        }
+        request => {
+            error!("unexpected request from host while running kernel: {:?}", request);
+            Err(Error::UnrecognizedPacket)
+        }
+    }
+}
+
 async fn write_exception_string(stream: &TcpStream, s: CSlice<'static, u8>) -> Result<()> {
     if s.len() == usize::MAX {
         write_i32(stream, -1).await?;
@@ -268,22 +524,204 @@ async fn write_exception_string(stream: &TcpStream, s: CSlice<'static, u8>) -> R
     Ok(())
 }
 
+// Channels of satellites are described with their destination and the link it is reached through.
+fn channel_info(channel: u32) -> String {
+    #[cfg(has_drtio)]
+    let (local_destination, hop) = (
+        rtio_mgt::drtio::get_master_destination(),
+        ROUTING_TABLE.get().unwrap().0[(channel >> 16) as u8 as usize][0],
+    );
+    #[cfg(not(has_drtio))]
+    let (local_destination, hop) = (0, 0);
+    let link = if hop > 0 { Some(hop - 1) } else { None };
+    format_channel_info(channel, Some(local_destination), link)
+}
+
+// config keys under which kernels can be stored for loading by name
+const STORED_KERNEL_PREFIX: &str = "kernels/";
+
+fn read_stored_kernel(key: &str) -> Option<Vec<u8>> {
+    if !key.starts_with(STORED_KERNEL_PREFIX) {
+        warn!("stored kernel key \"{}\" must start with \"{}\"", key, STORED_KERNEL_PREFIX);
+        return None;
+    }
+    libconfig::read(key).ok()
+}
+
+enum HeadlessKernel {
+    Stored(String),
+    Mgmt(Vec<u8>),
+}
+
+// Runs a stored kernel without a host, in place of the idle kernel. It has no RPC support,
+// its output goes to the log only. Refused while a host session is open.
+pub fn request_stored_kernel(key: String) -> core::result::Result<(), &'static str> {
+    if HOST_SESSION.load(Ordering::Relaxed) {
+        return Err("the core device is in use by a host");
+    }
+    if DEVICE_OWNER.lock().is_some() {
+        return Err("the core device is claimed by a host");
+    }
+    if read_stored_kernel(&key).is_none() {
+        return Err("stored kernel not found");
+    }
+    *HEADLESS_KERNEL_REQUEST.lock() = Some(HeadlessKernel::Stored(key));
+    RESTART_IDLE.signal();
+    Ok(())
+}
+
+// Runs a kernel sent over the management port like a stored kernel, for when the coredev
+// port cannot be reached, and waits until it has finished or the wait timed out.
+pub async fn run_mgmt_kernel(buffer: Vec<u8>) -> core::result::Result<(), String> {
+    if HOST_SESSION.load(Ordering::Relaxed) {
+        return Err("the core device is in use by a host".to_string());
+    }
+    if let Some(owner) = locked_by(&None) {
+        return Err(format!("the core device is claimed by \"{}\"", owner));
+    }
+    if MGMT_KERNEL_PENDING.swap(true, Ordering::Relaxed) {
+        return Err("another kernel sent over mgmt is pending".to_string());
+    }
+    // left signaled if the previous requester went away before the outcome
+    let _ = MGMT_KERNEL_DONE.try_wait();
+    *HEADLESS_KERNEL_REQUEST.lock() = Some(HeadlessKernel::Mgmt(buffer));
+    RESTART_IDLE.signal();
+    select_biased! {
+        _ = MGMT_KERNEL_DONE.async_wait().fuse() => MGMT_KERNEL_OUTCOME.lock().take().unwrap_or(Ok(())),
+        _ = timer::async_delay_ms(MGMT_KERNEL_TIMEOUT_MS).fuse() => Err(format!(
+            "still running after {} s, its outcome is not reported",
+            MGMT_KERNEL_TIMEOUT_MS / 1000
+        )),
+    }
+}
+
+fn finish_mgmt_kernel(outcome: core::result::Result<(), String>) {
+    *MGMT_KERNEL_OUTCOME.lock() = Some(outcome);
+    MGMT_KERNEL_PENDING.store(false, Ordering::Relaxed);
+    MGMT_KERNEL_DONE.signal();
+}
+
+// Override for a host that went away without releasing its claim.
+pub fn release_device() -> Option<String> {
+    DEVICE_OWNER.lock().take()
+}
+
+// Token of the owner if the device is claimed by another host than the session's.
+fn locked_by(session_token: &Option<String>) -> Option<String> {
+    match &*DEVICE_OWNER.lock() {
+        Some(owner) if Some(owner) != session_token.as_ref() => Some(owner.clone()),
+        _ => None,
+    }
+}
+
 async fn handle_run_kernel(
     stream: Option<&TcpStream>,
     control: &Rc<RefCell<kernel::Control>>,
-    _up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
+    up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
 ) -> Result<()> {
+    loop {
+        KERNEL_STATS.lock().start_kernel();
+        let start = timer::get_ms();
+        let result = serve_kernel(stream, control, up_destinations).await;
+        KERNEL_STATS.lock().finish_kernel(timer::get_ms() - start);
+        analyzer::kernel_finished(up_destinations).await;
+        match result? {
+            Some(buffer) => {
+                info!("loading chained kernel");
+                if let Err(e) = handle_flash_kernel(buffer, control, up_destinations).await {
+                    if let Some(stream) = stream {
+                        write_header(stream, Reply::KernelStartupFailed).await?;
+                    }
+                    return Err(e);
+                }
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+fn channel_reachable(channel: i32, _up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>) -> bool {
+    #[cfg(has_drtio)]
+    {
+        _up_destinations.borrow()[(channel >> 16) as u8 as usize]
+    }
+    #[cfg(not(has_drtio))]
+    {
+        channel >> 16 == 0
+    }
+}
+
+// Config usage of a destination, None if it cannot be reached. Satellites are asked over aux.
+async fn storage_stats(destination: u8, keys: &[String]) -> Option<kernel::StorageStats> {
+    #[cfg(has_drtio)]
+    {
+        if ROUTING_TABLE.get().unwrap().0[destination as usize][0] != 0 {
+            return match rtio_mgt::drtio::storage_stats(destination, keys).await {
+                Ok(stats) => Some(stats),
+                Err(e) => {
+                    error!("failed to read the config usage of destination {} ({})", destination, e);
+                    None
+                }
+            };
+        }
+    }
+    #[cfg(not(has_drtio))]
+    {
+        if destination != 0 {
+            return None;
+        }
+    }
+    Some(kernel::StorageStats::local(keys))
+}
+
+// returns the kernel chained by the finished one, if any
+async fn serve_kernel(
+    stream: Option<&TcpStream>,
+    control: &Rc<RefCell<kernel::Control>>,
+    _up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
+) -> Result<Option<Vec<u8>>> {
     let i2c_bus = libboard_artiq::i2c::get_bus();
+    if stream.is_none() {
+        // startup and idle kernels are parameterized through the config
+        if let Ok(args) = libconfig::read("startup_kernel_args") {
+            info!("passing {} bytes of startup_kernel_args to kernel", args.len());
+            control
+                .borrow_mut()
+                .tx
+                .async_send(kernel::Message::KernelArgs(args))
+                .await;
+        }
+    }
+    kernel::HEADLESS.store(stream.is_none(), Ordering::Relaxed);
+    if let Some(stream) = stream {
+        deliver_pushed_rpcs(stream).await?;
+        // kernels run without a host, such as the idle kernel, would overwrite the capture
+        let kernel_run = KERNEL_STATS.lock().kernels_run;
+        analyzer::kernel_started(kernel_run, _up_destinations).await;
+    }
     control.borrow_mut().tx.async_send(kernel::Message::StartRequest).await;
+    let mut requested_kernel = None;
+    let mut chained_kernel = None;
     loop {
-        let reply = control.borrow_mut().rx.async_recv().await;
+        let reply = match recv_kernel_message(stream, control).await {
+            Some(reply) => reply,
+            None => {
+                interrupt_kernel(stream.unwrap(), control).await?;
+                break;
+            }
+        };
         match reply {
             kernel::Message::RpcSend { is_async, data } => {
                 if stream.is_none() {
+                    if is_async {
+                        push_rpc(data);
+                        continue;
+                    }
                     error!("Unexpected RPC from startup/idle kernel!");
                     break;
                 }
                 let stream = stream.unwrap();
+                let rpc_start = timer::get_ms();
                 write_header(stream, Reply::RPCRequest).await?;
                 write_bool(stream, is_async).await?;
                 stream.send_slice(&data).await?;
@@ -358,8 +796,19 @@ async fn handle_run_kernel(
                         }
                     }
                 }
+                let latency_ms = if is_async {
+                    None
+                } else {
+                    Some(timer::get_ms() - rpc_start)
+                };
+                KERNEL_STATS.lock().record_rpc(latency_ms);
             }
             kernel::Message::KernelFinished => {
+                chained_kernel = requested_kernel.take();
+                if chained_kernel.is_some() {
+                    // the host is only told once the last kernel of the chain finishes
+                    break;
+                }
                 let async_errors = unsafe { get_async_errors() };
                 if let Some(stream) = stream {
                     write_header(stream, Reply::KernelFinished).await?;
@@ -387,14 +836,7 @@ async fn handle_run_kernel(
                                     slice::from_raw_parts(exception.message.as_ptr(), exception.message.len())
                                 })
                                 .unwrap()
-                                .replace(
-                                    "{rtio_channel_info:0}",
-                                    &format!(
-                                        "0x{:04x}:{}",
-                                        exception.param[0],
-                                        resolve_channel_name(exception.param[0] as u32)
-                                    ),
-                                );
+                                .replace("{rtio_channel_info:0}", &channel_info(exception.param[0] as u32));
                                 write_exception_string(stream, unsafe { CSlice::new(msg.as_ptr(), msg.len()) }).await?;
                             }
 
@@ -424,6 +866,60 @@ async fn handle_run_kernel(
                 }
                 break;
             }
+            kernel::Message::StoredKernelRequest(key) => {
+                requested_kernel = read_stored_kernel(&key);
+                let found = requested_kernel.is_some();
+                control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::StoredKernelReply(found))
+                    .await;
+            }
+            kernel::Message::WallClockRequest => {
+                control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::WallClockReply(wall_clock::get()))
+                    .await;
+            }
+            kernel::Message::StorageStatsRequest { destination, keys } => {
+                let stats = storage_stats(destination, &keys).await;
+                control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::StorageStatsReply(stats))
+                    .await;
+            }
+            kernel::Message::InjectionRequest { channel, overrd, value } => {
+                let reachable = channel_reachable(channel, _up_destinations);
+                if reachable {
+                    moninj::inject(channel, overrd, value).await;
+                }
+                control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::InjectionReply(reachable))
+                    .await;
+            }
+            kernel::Message::InjectionStatusRequest { channel, overrd } => {
+                let value = if channel_reachable(channel, _up_destinations) {
+                    Some(moninj::read_injection_status(channel, overrd).await)
+                } else {
+                    None
+                };
+                control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::InjectionStatusReply(value))
+                    .await;
+            }
+            kernel::Message::DestinationGroupRequest(name) => {
+                control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::DestinationGroupReply(drtio_routing::destination_group(&name)))
+                    .await;
+            }
             kernel::Message::CachePutRequest(key, value) => {
                 CACHE_STORE.lock().insert(key, value);
             }
@@ -436,6 +932,29 @@ async fn handle_run_kernel(
                     .async_send(kernel::Message::CacheGetReply(value))
                     .await;
             }
+            kernel::Message::MailboxPutRequest(name, message) => {
+                if message.is_empty() {
+                    MAILBOX.lock().remove(&name);
+                } else {
+                    MAILBOX.lock().insert(name, message);
+                }
+            }
+            kernel::Message::MailboxGetRequest(name) => {
+                let message = MAILBOX.lock().get(&name).cloned();
+                control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::MailboxGetReply(message))
+                    .await;
+            }
+            kernel::Message::ShmMapRequest { name, length } => {
+                let (data, length) = shm_map(name, length);
+                control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::ShmMapReply { data, length })
+                    .await;
+            }
             kernel::Message::DmaPutRequest(recorder) => {
                 let _id = rtio_dma::put_record(recorder).await;
                 #[cfg(has_drtio)]
@@ -453,9 +972,17 @@ async fn handle_run_kernel(
                     .async_send(kernel::Message::DmaGetReply(result))
                     .await;
             }
+            kernel::Message::DmaStatRequest(name) => {
+                let result = rtio_dma::stat(name).await;
+                control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::DmaStatReply(result))
+                    .await;
+            }
             #[cfg(has_drtio)]
-            kernel::Message::DmaStartRemoteRequest { id, timestamp } => {
-                rtio_dma::remote_dma::playback(id as u32, timestamp as u64).await;
+            kernel::Message::DmaStartRemoteRequest { id, timestamp, targets } => {
+                rtio_dma::remote_dma::playback(id as u32, timestamp as u64, targets).await;
             }
             #[cfg(has_drtio)]
             kernel::Message::DmaAwaitRemoteRequest(id) => {
@@ -465,17 +992,20 @@ async fn handle_run_kernel(
                         error,
                         channel,
                         timestamp,
+                        stat,
                     }) => kernel::Message::DmaAwaitRemoteReply {
                         timeout: false,
                         error: error,
                         channel: channel,
                         timestamp: timestamp,
+                        stat: stat,
                     },
                     _ => kernel::Message::DmaAwaitRemoteReply {
                         timeout: true,
                         error: 0,
                         channel: 0,
                         timestamp: 0,
+                        stat: kernel::DmaPlaybackStat::default(),
                     },
                 };
                 control.borrow_mut().tx.async_send(reply).await;
@@ -585,14 +1115,84 @@ async fn handle_run_kernel(
                     .async_send(kernel::Message::I2cReadReply { succeeded, data })
                     .await;
             }
+            kernel::Message::I2cWriteBlockRequest { busno, data } => {
+                let _destination = (busno >> 16) as u8;
+                #[cfg(has_drtio)]
+                if _destination != 0 {
+                    let result = rtio_mgt::drtio::i2c_send_write_block(busno, &data).await;
+                    let reply = match result {
+                        Ok((succeeded, acked)) => kernel::Message::I2cWriteBlockReply { succeeded, acked },
+                        Err(_) => kernel::Message::I2cWriteBlockReply {
+                            succeeded: false,
+                            acked: 0,
+                        },
+                    };
+                    control.borrow_mut().tx.async_send(reply).await;
+                    continue;
+                }
+                let mut succeeded = busno == 0;
+                let mut acked = 0;
+                if succeeded {
+                    for &byte in data.iter() {
+                        match i2c_bus.write(byte) {
+                            Ok(()) => acked += 1,
+                            Err(I2cError::Nack) => break,
+                            Err(_) => {
+                                succeeded = false;
+                                break;
+                            }
+                        }
+                    }
+                }
+                control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::I2cWriteBlockReply { succeeded, acked })
+                    .await;
+            }
+            kernel::Message::I2cReadBlockRequest { busno, length, ack } => {
+                let _destination = (busno >> 16) as u8;
+                #[cfg(has_drtio)]
+                if _destination != 0 {
+                    let result = rtio_mgt::drtio::i2c_send_read_block(busno, length, ack).await;
+                    let reply = match result {
+                        Ok((succeeded, data)) => kernel::Message::I2cReadBlockReply { succeeded, data },
+                        Err(_) => kernel::Message::I2cReadBlockReply {
+                            succeeded: false,
+                            data: Vec::new(),
+                        },
+                    };
+                    control.borrow_mut().tx.async_send(reply).await;
+                    continue;
+                }
+                let mut succeeded = busno == 0;
+                let mut data = Vec::with_capacity(length);
+                if succeeded {
+                    for i in 0..length {
+                        match i2c_bus.read(ack || i + 1 < length) {
+                            Ok(byte) => data.push(byte),
+                            Err(_) => {
+                                succeeded = false;
+                                break;
+                            }
+                        }
+                    }
+                }
+                control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::I2cReadBlockReply { succeeded, data })
+                    .await;
+            }
             #[cfg(has_drtio)]
             kernel::Message::SubkernelLoadRunRequest {
                 id,
                 destination: _,
                 run,
                 timestamp,
+                relative,
             } => {
-                let succeeded = match subkernel::load(id, run, timestamp).await {
+                let succeeded = match subkernel::load(id, run, timestamp, relative).await {
                     Ok(()) => true,
                     Err(e) => {
                         error!("Error loading subkernel: {:?}", e);
@@ -627,6 +1227,71 @@ async fn handle_run_kernel(
                 control.borrow_mut().tx.async_send(response).await;
             }
             #[cfg(has_drtio)]
+            kernel::Message::SubkernelAwaitManyRequest { ids, timeout } => {
+                let results = subkernel::await_many(&ids, timeout).await;
+                // the first exception is raised in the kernel in place of the statuses
+                let exception = results.iter().find_map(|result| match result {
+                    Ok(res) => res.exception.clone(),
+                    Err(_) => None,
+                });
+                let response = match exception {
+                    Some(exception) => kernel::Message::SubkernelError(kernel::SubkernelStatus::Exception(exception)),
+                    None => kernel::Message::SubkernelAwaitManyReply {
+                        statuses: results
+                            .iter()
+                            .map(|result| match result {
+                                Ok(res) if res.status == subkernel::FinishStatus::CommLost => {
+                                    kernel::SubkernelStatus::CommLost
+                                }
+                                Ok(_) => kernel::SubkernelStatus::NoError,
+                                Err(SubkernelError::Timeout) => kernel::SubkernelStatus::Timeout,
+                                Err(SubkernelError::IncorrectState) | Err(SubkernelError::SubkernelNotFound) => {
+                                    kernel::SubkernelStatus::IncorrectState
+                                }
+                                Err(_) => kernel::SubkernelStatus::OtherError,
+                            })
+                            .collect(),
+                    },
+                };
+                control.borrow_mut().tx.async_send(response).await;
+            }
+            #[cfg(has_drtio)]
+            kernel::Message::SubkernelBarrierRequest {
+                id,
+                participants,
+                timeout,
+            } => {
+                let response = match subkernel::barrier_wait(id, &participants, timeout).await {
+                    Ok(()) => kernel::Message::SubkernelBarrierReply,
+                    Err(SubkernelError::Timeout) => kernel::Message::SubkernelError(kernel::SubkernelStatus::Timeout),
+                    Err(_) => kernel::Message::SubkernelError(kernel::SubkernelStatus::OtherError),
+                };
+                control.borrow_mut().tx.async_send(response).await;
+            }
+            #[cfg(has_drtio)]
+            kernel::Message::SubkernelDatasetAppendRequest { name, .. } => {
+                warn!("dataset \"{}\" not buffered, only subkernels can buffer datasets", name);
+                control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::SubkernelDatasetAppendReply { succeeded: false })
+                    .await;
+            }
+            #[cfg(has_drtio)]
+            kernel::Message::SubkernelDatasetFetchRequest { id, name } => {
+                let response = match subkernel::dataset_fetch(id, &name).await {
+                    Ok(data) => kernel::Message::SubkernelDatasetFetchReply(data),
+                    Err(SubkernelError::IncorrectState) => {
+                        kernel::Message::SubkernelError(kernel::SubkernelStatus::IncorrectState)
+                    }
+                    Err(e) => {
+                        error!("error fetching subkernel dataset \"{}\": {:?}", name, e);
+                        kernel::Message::SubkernelError(kernel::SubkernelStatus::OtherError)
+                    }
+                };
+                control.borrow_mut().tx.async_send(response).await;
+            }
+            #[cfg(has_drtio)]
             kernel::Message::SubkernelMsgSend { id, destination, data } => {
                 let res = subkernel::message_send(id, destination.unwrap(), data).await;
                 match res {
@@ -708,11 +1373,77 @@ async fn handle_run_kernel(
                     .await;
             }
             #[cfg(has_drtio)]
-            kernel::Message::RtioInitRequest => {
-                rtio_mgt::drtio::reset().await;
+            kernel::Message::RtioCounterRequest(destination) => {
+                let counter = match rtio_mgt::drtio::destination_counter(destination as u8).await {
+                    Ok(counter) => Some(counter as i64),
+                    Err(e) => {
+                        error!("failed to read RTIO counter of destination {} ({})", destination, e);
+                        None
+                    }
+                };
+                control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::RtioCounterReply(counter))
+                    .await;
+            }
+            #[cfg(has_drtio)]
+            kernel::Message::RtioInputBatchRequest {
+                channel,
+                timeout,
+                max_count,
+            } => {
+                let reply = match rtio_mgt::drtio::rtio_input_batch(channel, timeout, max_count).await {
+                    Ok((status, events)) => kernel::Message::RtioInputBatchReply {
+                        status: Some(status),
+                        events: events,
+                    },
+                    Err(rtio_mgt::drtio::Error::Unsupported) => kernel::Message::RtioInputBatchReply {
+                        status: Some(RTIO_INPUT_BATCH_UNSUPPORTED),
+                        events: Vec::new(),
+                    },
+                    Err(e) => {
+                        error!("failed to read RTIO inputs of channel {} ({})", channel, e);
+                        kernel::Message::RtioInputBatchReply {
+                            status: None,
+                            events: Vec::new(),
+                        }
+                    }
+                };
+                control.borrow_mut().tx.async_send(reply).await;
+            }
+            #[cfg(has_drtio)]
+            kernel::Message::RtioInitRequest(policy) => {
+                rtio_mgt::drtio::reset(policy).await;
                 control.borrow_mut().tx.async_send(kernel::Message::RtioInitReply).await;
             }
             #[cfg(has_drtio)]
+            kernel::Message::GrabberRoiRequest { destination, grabber } => {
+                let roi_counts = match rtio_mgt::drtio::grabber_roi_counts(destination, grabber).await {
+                    Ok(roi_counts) => roi_counts,
+                    Err(e) => {
+                        error!(
+                            "failed to read ROI counts of grabber {} of destination {} ({})",
+                            grabber, destination, e
+                        );
+                        None
+                    }
+                };
+                let reply = match roi_counts {
+                    Some((frame, counts)) => kernel::Message::GrabberRoiReply {
+                        succeeded: true,
+                        frame,
+                        counts,
+                    },
+                    None => kernel::Message::GrabberRoiReply {
+                        succeeded: false,
+                        frame: 0,
+                        counts: [0; GRABBER_ROI_COUNT],
+                    },
+                };
+                control.borrow_mut().tx.async_send(reply).await;
+            }
+            #[cfg(has_drtio)]
             kernel::Message::CXPReadRequest {
                 destination,
                 address,
@@ -859,16 +1590,43 @@ async fn handle_run_kernel(
                 };
                 control.borrow_mut().tx.async_send(reply).await;
             }
+            #[cfg(has_drtio)]
+            kernel::Message::CXPEventRequest { destination } => {
+                let linkno = ROUTING_TABLE.get().unwrap().0[destination as usize][0] - 1;
+                let drtioaux_packet =
+                    rtio_mgt::drtio::aux_transact(linkno, &Packet::CXPEventRequest { destination }).await;
+
+                let reply = match drtioaux_packet {
+                    Ok(Packet::CXPEventReply {
+                        available,
+                        namespace,
+                        id,
+                        timestamp,
+                    }) => kernel::Message::CXPEventReply(available.then_some((namespace, id, timestamp))),
+                    Ok(Packet::CXPError { length, message }) => {
+                        kernel::Message::CXPError(String::from_utf8_lossy(&message[..length as usize]).to_string())
+                    }
+                    Ok(packet) => {
+                        error!("received unexpected aux packet {:?}", packet);
+                        kernel::Message::CXPError("recevied unexpected drtio aux reply".to_string())
+                    }
+                    Err(e) => {
+                        error!("aux packet error ({})", e);
+                        kernel::Message::CXPError("drtio aux error".to_string())
+                    }
+                };
+                control.borrow_mut().tx.async_send(reply).await;
+            }
             _ => {
                 panic!("unexpected message from core1 while kernel was running: {:?}", reply);
             }
         }
     }
-    Ok(())
+    Ok(chained_kernel)
 }
 
 async fn handle_flash_kernel(
-    buffer: &Vec<u8>,
+    buffer: Vec<u8>,
     control: &Rc<RefCell<kernel::Control>>,
     _up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
 ) -> Result<()> {
@@ -905,7 +1663,7 @@ async fn handle_flash_kernel(
                     }
                 }
             }
-            load_kernel(&main_lib, control, None).await
+            load_kernel(main_lib, control, None).await
         }
         #[cfg(not(has_drtio))]
         {
@@ -914,8 +1672,9 @@ async fn handle_flash_kernel(
     }
 }
 
+// takes the image, as it is handed over to core1 without a copy
 async fn load_kernel(
-    buffer: &Vec<u8>,
+    buffer: Vec<u8>,
     control: &Rc<RefCell<kernel::Control>>,
     stream: Option<&TcpStream>,
 ) -> Result<()> {
@@ -923,7 +1682,7 @@ async fn load_kernel(
     control.restart();
     control
         .tx
-        .async_send(kernel::Message::LoadRequest(buffer.to_vec()))
+        .async_send(kernel::Message::LoadRequest(buffer))
         .await;
     let reply = control.rx.async_recv().await;
     match reply {
@@ -953,17 +1712,59 @@ async fn load_kernel(
     }
 }
 
-async fn handle_connection(
-    stream: &mut TcpStream,
-    control: Rc<RefCell<kernel::Control>>,
-    up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
-) -> Result<()> {
+// Greeting of a new host, done before the current session is terminated for it, so that
+// a host failing to authenticate, or not presenting the token of a claimed device, cannot
+// interrupt the session or the kernel running. Returns the token the host claimed it with.
+async fn greet(stream: &mut TcpStream) -> Result<Option<String>> {
     stream.set_ack_delay(None);
 
     if !expect(stream, b"ARTIQ coredev\n").await? {
         return Err(Error::UnexpectedPattern);
     }
+    if !auth::challenge(stream, auth::Port::Coredev).await? {
+        return Err(Error::AuthFailed);
+    }
     stream.send_slice("e".as_bytes()).await?;
+
+    // while the device is claimed, requests are answered here until the owner's token is presented
+    while let Some(owner) = locked_by(&None) {
+        let request = match read_request(stream, true).await? {
+            Some(request) => request,
+            None => return Err(Error::DeviceLocked),
+        };
+        match request {
+            Request::SystemInfo => {
+                write_header(stream, Reply::SystemInfo).await?;
+                stream.send_slice("ARZQ".as_bytes()).await?;
+            }
+            Request::ClaimDevice => {
+                let token = String::from_utf8(read_bytes(stream, 256).await?).map_err(|_| Error::UnexpectedPattern)?;
+                if token == owner {
+                    write_header(stream, Reply::DeviceClaimed).await?;
+                    return Ok(Some(token));
+                }
+                write_header(stream, Reply::DeviceLocked).await?;
+                write_chunk(stream, owner.as_bytes()).await?;
+            }
+            Request::ReleaseDevice => write_header(stream, Reply::DeviceReleased).await?,
+            _ => {
+                // the request payload is not read, the connection is dropped
+                warn!("request refused, the device is locked by \"{}\"", owner);
+                write_header(stream, Reply::DeviceLocked).await?;
+                write_chunk(stream, owner.as_bytes()).await?;
+                return Err(Error::DeviceLocked);
+            }
+        }
+    }
+    Ok(None)
+}
+
+async fn handle_connection(
+    stream: &mut TcpStream,
+    mut session_token: Option<String>,
+    control: Rc<RefCell<kernel::Control>>,
+    up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
+) -> Result<()> {
     #[cfg(has_drtio)]
     subkernel::clear_subkernels().await;
     loop {
@@ -974,24 +1775,83 @@ async fn handle_connection(
             return Ok(());
         }
         let request = request.unwrap();
+        match request {
+            Request::SystemInfo | Request::ClaimDevice | Request::ReleaseDevice => (),
+            _ => {
+                if let Some(owner) = locked_by(&session_token) {
+                    // the request payload is not read, the connection is dropped
+                    warn!("request refused, the device is locked by \"{}\"", owner);
+                    write_header(stream, Reply::DeviceLocked).await?;
+                    write_chunk(stream, owner.as_bytes()).await?;
+                    return Err(Error::DeviceLocked);
+                }
+            }
+        }
         match request {
             Request::SystemInfo => {
                 write_header(stream, Reply::SystemInfo).await?;
                 stream.send_slice("ARZQ".as_bytes()).await?;
             }
+            Request::ClaimDevice => {
+                let token = String::from_utf8(read_bytes(stream, 256).await?).map_err(|_| Error::UnexpectedPattern)?;
+                let mut owner = DEVICE_OWNER.lock();
+                match &*owner {
+                    Some(current) if *current != token => {
+                        let current = current.clone();
+                        drop(owner);
+                        write_header(stream, Reply::DeviceLocked).await?;
+                        write_chunk(stream, current.as_bytes()).await?;
+                    }
+                    _ => {
+                        if owner.is_none() {
+                            info!("device claimed by \"{}\"", token);
+                        }
+                        *owner = Some(token.clone());
+                        drop(owner);
+                        session_token = Some(token);
+                        write_header(stream, Reply::DeviceClaimed).await?;
+                    }
+                }
+            }
+            Request::ReleaseDevice => {
+                let mut owner = DEVICE_OWNER.lock();
+                if session_token.is_some() && *owner == session_token {
+                    info!("device released by \"{}\"", session_token.as_ref().unwrap());
+                    *owner = None;
+                }
+                drop(owner);
+                session_token = None;
+                write_header(stream, Reply::DeviceReleased).await?;
+            }
             Request::LoadKernel => {
-                let buffer = read_bytes(stream, 1024 * 1024).await?;
-                load_kernel(&buffer, &control, Some(stream)).await?;
+                let buffer = read_kernel_image(stream).await?;
+                load_kernel(buffer, &control, Some(stream)).await?;
             }
             Request::RunKernel => {
                 handle_run_kernel(Some(stream), &control, &up_destinations).await?;
             }
+            Request::LoadStoredKernel => {
+                let key = String::from_utf8(read_bytes(stream, 256).await?).map_err(|_| Error::UnexpectedPattern)?;
+                match read_stored_kernel(&key) {
+                    Some(buffer) => match handle_flash_kernel(buffer, &control, &up_destinations).await {
+                        Ok(()) => write_header(stream, Reply::LoadCompleted).await?,
+                        Err(_) => {
+                            write_header(stream, Reply::LoadFailed).await?;
+                            write_chunk(stream, b"stored kernel failed to load").await?;
+                        }
+                    },
+                    None => {
+                        write_header(stream, Reply::LoadFailed).await?;
+                        write_chunk(stream, b"stored kernel not found").await?;
+                    }
+                }
+            }
             Request::UploadSubkernel => {
                 #[cfg(has_drtio)]
                 {
                     let id = read_i32(stream).await? as u32;
                     let destination = read_i8(stream).await? as u8;
-                    let buffer = read_bytes(stream, 1024 * 1024).await?;
+                    let buffer = read_kernel_image(stream).await?;
                     subkernel::add_subkernel(id, destination, buffer).await;
                     match subkernel::upload(id).await {
                         Ok(_) => write_header(stream, Reply::LoadCompleted).await?,
@@ -1037,10 +1897,11 @@ pub fn main() {
                 IpCidr::new(net_addresses.ipv6_ll_addr, 0),
                 IpCidr::new(addr, 0),
             ];
-            EthernetInterfaceBuilder::new(&mut eth)
+            EthernetInterfaceBuilder::new(ping::Device::new(&mut eth))
                 .ethernet_addr(net_addresses.hardware_addr)
                 .ip_addrs(ip_addrs)
                 .neighbor_cache(neighbor_cache)
+                .routes(Routes::new(BTreeMap::new()))
                 .finalize()
         }
         None => {
@@ -1048,15 +1909,19 @@ pub fn main() {
                 IpCidr::new(net_addresses.ipv4_addr, 0),
                 IpCidr::new(net_addresses.ipv6_ll_addr, 0),
             ];
-            EthernetInterfaceBuilder::new(&mut eth)
+            EthernetInterfaceBuilder::new(ping::Device::new(&mut eth))
                 .ethernet_addr(net_addresses.hardware_addr)
                 .ip_addrs(ip_addrs)
                 .neighbor_cache(neighbor_cache)
+                .routes(Routes::new(BTreeMap::new()))
                 .finalize()
         }
     };
+    net_routes::apply(&mut iface);
 
-    Sockets::init(32);
+    net_buffers::load();
+    Sockets::init(net_buffers::sockets());
+    boot_slot::confirm();
 
     #[cfg(has_drtio)]
     let res = ROUTING_TABLE.set(drtio_routing::config_routing_table(pl::csr::DRTIO.len()));
@@ -1069,16 +1934,26 @@ pub fn main() {
     drtio_routing::interconnect_disable_all();
 
     task::spawn(report_async_rtio_errors());
+    task::spawn(flush_error_log());
+    task::spawn(storage_health::monitor());
     rtio_mgt::startup(&up_destinations);
-    libboard_artiq::setup_device_map();
+    libboard_artiq::reload_device_map();
+    if selftest::enabled() {
+        task::spawn(selftest::run());
+    }
 
-    analyzer::start(&up_destinations);
-    moninj::start();
+    services::load();
+    if services::enabled(services::Service::Analyzer) {
+        analyzer::start(&up_destinations);
+    }
+    if services::enabled(services::Service::Moninj) {
+        moninj::start();
+    }
 
     let control: Rc<RefCell<kernel::Control>> = Rc::new(RefCell::new(kernel::Control::start()));
     if let Ok(buffer) = libconfig::read("startup_kernel") {
         info!("Loading startup kernel...");
-        if let Ok(()) = task::block_on(handle_flash_kernel(&buffer, &control, &up_destinations)) {
+        if let Ok(()) = task::block_on(handle_flash_kernel(buffer, &control, &up_destinations)) {
             info!("Starting startup kernel...");
             let _ = task::block_on(handle_run_kernel(None, &control, &up_destinations));
             info!("Startup kernel finished!");
@@ -1097,7 +1972,9 @@ pub fn main() {
             let control = control.clone();
             let mut maybe_stream = select_biased! {
                 s = (async {
-                        TcpStream::accept(1381, 0x10_000, 0x10_000).await.unwrap()
+                        let size = net_buffers::size(net_buffers::Server::Coredev);
+                        net_stats::listening(net_stats::Service::Coredev);
+                        TcpStream::accept(1381, size, size).await.unwrap()
                     }).fuse() => Some(s),
                 _ = (async {
                         RESTART_IDLE.async_wait().await;
@@ -1105,6 +1982,28 @@ pub fn main() {
                     }).fuse() => None
             };
 
+            let mut session_token = None;
+            if let Some(stream) = &mut maybe_stream {
+                let greeting = select_biased! {
+                    result = greet(stream).fuse() => Some(result),
+                    _ = timer::async_delay_ms(GREETING_TIMEOUT_MS).fuse() => None,
+                };
+                match greeting {
+                    Some(Ok(token)) => session_token = token,
+                    Some(Err(e)) => {
+                        warn!("connection refused: {}", e);
+                        let _ = stream.flush().await;
+                        let _ = stream.abort().await;
+                        continue;
+                    }
+                    None => {
+                        warn!("connection refused: greeting timed out");
+                        let _ = stream.abort().await;
+                        continue;
+                    }
+                }
+            }
+
             if connection.try_wait().is_none() {
                 // there is an existing connection
                 terminate.signal();
@@ -1112,6 +2011,7 @@ pub fn main() {
             }
 
             let maybe_idle_kernel = libconfig::read("idle_kernel").ok();
+            idle_kernel::set_loaded(maybe_idle_kernel.as_deref());
             if maybe_idle_kernel.is_none() && maybe_stream.is_none() {
                 control.borrow_mut().restart(); // terminate idle kernel if running
             }
@@ -1126,39 +2026,109 @@ pub fn main() {
             let _ = terminate.try_wait();
             let _ = can_restart_idle.try_wait();
             task::spawn(async move {
+                // still set if the connection is terminated by a newer one
+                let session_open = Cell::new(maybe_stream.is_some());
+                // set while a kernel sent over mgmt runs, whose requester is waiting
+                let mgmt_kernel_running = Cell::new(false);
+                if session_open.get() {
+                    net_stats::accepted(net_stats::Service::Coredev);
+                    HOST_SESSION.store(true, Ordering::Relaxed);
+                }
                 select_biased! {
                     _ = (async {
                         if let Some(stream) = &mut maybe_stream {
-                            let _ = handle_connection(stream, control.clone(), &up_destinations)
+                            let result = handle_connection(stream, session_token, control.clone(), &up_destinations)
                                 .await
                                 .map_err(|e| warn!("connection terminated: {}", e));
+                            session_open.set(false);
+                            HOST_SESSION.store(false, Ordering::Relaxed);
+                            net_stats::closed(net_stats::Service::Coredev, result.is_err());
                         }
                         can_restart_idle.signal();
+                        let headless_kernel = HEADLESS_KERNEL_REQUEST.lock().take();
+                        match headless_kernel {
+                            Some(HeadlessKernel::Stored(key)) => match read_stored_kernel(&key) {
+                                Some(buffer) => {
+                                    info!("loading stored kernel {}", key);
+                                    match handle_flash_kernel(buffer, &control, &up_destinations).await {
+                                        Ok(()) => match handle_run_kernel(None, &control, &up_destinations).await {
+                                            Ok(()) => info!("stored kernel {} finished", key),
+                                            Err(e) => warn!("stored kernel {} running error: {}", key, e),
+                                        },
+                                        Err(e) => warn!("stored kernel {} loading error: {}", key, e),
+                                    }
+                                }
+                                None => warn!("stored kernel {} not found", key),
+                            },
+                            Some(HeadlessKernel::Mgmt(buffer)) => {
+                                info!("loading kernel sent over mgmt");
+                                mgmt_kernel_running.set(true);
+                                let outcome = match handle_flash_kernel(buffer, &control, &up_destinations).await {
+                                    Ok(()) => handle_run_kernel(None, &control, &up_destinations)
+                                        .await
+                                        .map_err(|e| format!("running error: {}", e)),
+                                    Err(e) => Err(format!("loading error: {}", e)),
+                                };
+                                match &outcome {
+                                    Ok(()) => info!("kernel sent over mgmt finished"),
+                                    Err(e) => warn!("kernel sent over mgmt {}", e),
+                                }
+                                mgmt_kernel_running.set(false);
+                                finish_mgmt_kernel(outcome);
+                            }
+                            None => (),
+                        }
                         match maybe_idle_kernel {
                             Some(buffer) => {
-                                loop {
+                                while !idle_kernel::disabled() {
                                     info!("loading idle kernel");
-                                    match handle_flash_kernel(&buffer, &control, &up_destinations).await {
+                                    // reloaded on every pass, core1 takes the image it is handed
+                                    let image = buffer.clone();
+                                    let result = match handle_flash_kernel(image, &control, &up_destinations).await {
                                         Ok(_) => {
                                             info!("running idle kernel");
                                             match handle_run_kernel(None, &control, &up_destinations).await {
-                                                Ok(_) => info!("idle kernel finished"),
-                                                Err(_) => warn!("idle kernel running error")
+                                                Ok(_) => {
+                                                    info!("idle kernel finished");
+                                                    Ok(())
+                                                },
+                                                Err(_) => {
+                                                    warn!("idle kernel running error");
+                                                    Err(())
+                                                }
                                             }
                                         },
-                                        Err(_) => warn!("idle kernel loading error")
+                                        Err(_) => {
+                                            warn!("idle kernel loading error");
+                                            Err(())
+                                        }
+                                    };
+                                    match result {
+                                        Ok(()) => idle_kernel::succeeded(),
+                                        Err(()) => timer::async_delay_ms(idle_kernel::failed()).await,
                                     }
                                 }
+                                if idle_kernel::disabled() {
+                                    warn!("idle kernel disabled, re-arm it through the management port");
+                                }
                             },
                             None => info!("no idle kernel found")
                         }
                     }).fuse() => (),
                     _ = terminate.async_wait().fuse() => ()
                 }
+                if session_open.get() {
+                    HOST_SESSION.store(false, Ordering::Relaxed);
+                    net_stats::closed(net_stats::Service::Coredev, true);
+                }
+                if mgmt_kernel_running.get() {
+                    finish_mgmt_kernel(Err("interrupted by a host connection".to_string()));
+                }
                 connection.signal();
                 if let Some(stream) = maybe_stream {
                     let _ = stream.flush().await;
                     let _ = stream.abort().await;
+                    net_stats::released(net_stats::Service::Coredev);
                 }
             });
         }
@@ -1170,9 +2140,14 @@ pub fn main() {
 
         loop {
             let instant = Instant::from_millis(timer::get_ms() as i32);
+            if net_routes::reload_requested() {
+                net_routes::apply(&mut iface);
+            }
             Sockets::instance().poll(&mut iface, instant);
+            ping::polled();
+            while moninj_udp::flush(iface.device_mut()) {}
 
-            let dev = iface.device_mut();
+            let dev = iface.device_mut().inner_mut();
             if dev.is_idle() && instant >= last_link_check + Duration::from_millis(LINK_CHECK_INTERVAL) {
                 dev.check_link_change();
                 last_link_check = instant;
@@ -1207,6 +2182,7 @@ pub fn soft_panic_main() -> ! {
                 .ethernet_addr(net_addresses.hardware_addr)
                 .ip_addrs(ip_addrs)
                 .neighbor_cache(neighbor_cache)
+                .routes(Routes::new(BTreeMap::new()))
                 .finalize()
         }
         None => {
@@ -1218,9 +2194,11 @@ pub fn soft_panic_main() -> ! {
                 .ethernet_addr(net_addresses.hardware_addr)
                 .ip_addrs(ip_addrs)
                 .neighbor_cache(neighbor_cache)
+                .routes(Routes::new(BTreeMap::new()))
                 .finalize()
         }
     };
+    net_routes::apply(&mut iface);
 
     Sockets::init(32);
 
@@ -1240,6 +2218,9 @@ pub fn soft_panic_main() -> ! {
 
         loop {
             let instant = Instant::from_millis(timer::get_ms() as i32);
+            if net_routes::reload_requested() {
+                net_routes::apply(&mut iface);
+            }
             Sockets::instance().poll(&mut iface, instant);
 
             let dev = iface.device_mut();
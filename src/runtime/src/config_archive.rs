@@ -0,0 +1,101 @@
+use alloc::{string::{String, ToString},
+            vec::Vec};
+
+use log::warn;
+use tar_no_std::TarArchiveRef;
+
+// Config backups are tar archives with one file per key, named after the key. A backup
+// holds every key that is set except the secret ones, those written by the firmware
+// itself, calibrations of the transceivers of one board and stored kernels. Restores
+// also accept stored kernels.
+
+const EXCLUDED_KEYS: &[&str] = &[
+    "auth_key",
+    "boot",
+    "boot_a",
+    "boot_b",
+    "boot_slot",
+    "boot_trial",
+    "panic_report",
+    "last_panic",
+    "routing_table_cache",
+    "storage_sentinel",
+    "selftest_probe",
+];
+const EXCLUDED_PREFIXES: &[&str] = &["eem_drtio_delay", "kernels/"];
+
+pub fn backed_up(key: &str) -> bool {
+    !EXCLUDED_KEYS.contains(&key) && !EXCLUDED_PREFIXES.iter().any(|prefix| key.starts_with(prefix))
+}
+
+// Largest archive accepted for restoring, room for the config keys and a few stored
+// kernels of the default kernel_max_size.
+pub const MAX_SIZE: usize = 64 * 1024 * 1024;
+
+const BLOCK_SIZE: usize = 512;
+// the name field is NUL-terminated
+const NAME_MAX_SIZE: usize = 99;
+
+pub fn restorable(key: &str) -> bool {
+    backed_up(key) || key.starts_with("kernels/")
+}
+
+// keys are listed one per line, as they come from the config storage of a satellite
+pub fn parse_keys(list: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(list)
+        .lines()
+        .filter(|key| !key.is_empty())
+        .map(|key| key.to_string())
+        .collect()
+}
+
+fn write_octal(field: &mut [u8], value: usize) {
+    let digits = field.len() - 1;
+    for (i, byte) in field[..digits].iter_mut().enumerate() {
+        *byte = b'0' + ((value >> (3 * (digits - 1 - i))) & 7) as u8;
+    }
+    field[digits] = 0;
+}
+
+fn header(key: &str, size: usize) -> [u8; BLOCK_SIZE] {
+    let mut header = [0; BLOCK_SIZE];
+    header[..key.len()].copy_from_slice(key.as_bytes());
+    write_octal(&mut header[100..108], 0o644);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+    write_octal(&mut header[136..148], 0);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // the checksum is taken with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum = header.iter().map(|&byte| byte as usize).sum();
+    write_octal(&mut header[148..155], checksum);
+    header[155] = b' ';
+    header
+}
+
+pub fn build(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    for (key, value) in entries {
+        if key.len() > NAME_MAX_SIZE {
+            warn!("config key {} too long for a backup, skipping", key);
+            continue;
+        }
+        archive.extend_from_slice(&header(key, value.len()));
+        archive.extend_from_slice(value);
+        let padding = (BLOCK_SIZE - value.len() % BLOCK_SIZE) % BLOCK_SIZE;
+        archive.resize(archive.len() + padding, 0);
+    }
+    // end of archive
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+    archive
+}
+
+pub fn entries(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+    TarArchiveRef::new(archive)
+        .entries()
+        .map(|entry| (entry.filename().as_str().to_string(), entry.data().to_vec()))
+        .collect()
+}
@@ -0,0 +1,83 @@
+use alloc::{collections::VecDeque, vec::Vec};
+
+use libboard_zynq::timer;
+use libcortex_a9::mutex::Mutex;
+
+// Recent DRTIO and clocking events, pushed to the management clients subscribed to them.
+// Each event gets a sequence number, so a subscriber picks up where it left off;
+// only the last EVENT_HISTORY events are kept for subscribers that fall behind.
+
+const EVENT_HISTORY: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    DestinationUp(u8),
+    DestinationDown(u8),
+    LinkUp(u8),
+    LinkDown(u8),
+    // protocol error flags, as reported by the gateware
+    LinkError { linkno: u8, errors: u8 },
+    ClockSwitched { mhz: u32 },
+    // links of satellite repeaters, as seen when their stats are polled
+    SatelliteLinkUp { destination: u8, repno: u8 },
+    SatelliteLinkDown { destination: u8, repno: u8 },
+}
+
+impl Event {
+    // kind and up to two arguments, as sent to the host
+    pub fn encode(&self) -> (i8, i32, i32) {
+        match *self {
+            Event::DestinationUp(destination) => (0, destination as i32, 0),
+            Event::DestinationDown(destination) => (1, destination as i32, 0),
+            Event::LinkUp(linkno) => (2, linkno as i32, 0),
+            Event::LinkDown(linkno) => (3, linkno as i32, 0),
+            Event::LinkError { linkno, errors } => (4, linkno as i32, errors as i32),
+            Event::ClockSwitched { mhz } => (5, mhz as i32, 0),
+            Event::SatelliteLinkUp { destination, repno } => (6, destination as i32, repno as i32),
+            Event::SatelliteLinkDown { destination, repno } => (7, destination as i32, repno as i32),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Record {
+    pub sequence: u32,
+    pub timestamp_ms: u64,
+    pub event: Event,
+}
+
+struct History {
+    next_sequence: u32,
+    records: VecDeque<Record>,
+}
+
+static HISTORY: Mutex<History> = Mutex::new(History {
+    next_sequence: 0,
+    records: VecDeque::new(),
+});
+
+pub fn push(event: Event) {
+    let mut history = HISTORY.lock();
+    let sequence = history.next_sequence;
+    history.next_sequence = sequence.wrapping_add(1);
+    if history.records.len() == EVENT_HISTORY {
+        history.records.pop_front();
+    }
+    history.records.push_back(Record {
+        sequence: sequence,
+        timestamp_ms: timer::get_ms(),
+        event: event,
+    });
+}
+
+// events with a sequence number at or after `sequence`, and the sequence number to ask for next
+pub fn since(sequence: u32) -> (Vec<Record>, u32) {
+    let history = HISTORY.lock();
+    let records = history
+        .records
+        .iter()
+        .filter(|record| record.sequence.wrapping_sub(sequence) < history.next_sequence.wrapping_sub(sequence))
+        .cloned()
+        .collect();
+    (records, history.next_sequence)
+}
@@ -0,0 +1,98 @@
+use alloc::{format, string::String};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crc::crc32;
+use dyld::elf;
+use ksupport::kernel::core1;
+use libcortex_a9::mutex::Mutex;
+use log::{error, info, warn};
+#[cfg(has_drtio)]
+use tar_no_std::TarArchiveRef;
+
+// Crash-loop protection of the idle kernel: after a failed load or run, the next
+// attempt is delayed, doubling the delay with each failure in a row. Once too many
+// attempts failed in a row, the idle kernel is disabled until re-armed through mgmt.
+
+const MAX_CONSECUTIVE_FAILURES: u32 = 8;
+const BACKOFF_MIN_MS: u64 = 100;
+const BACKOFF_MAX_MS: u64 = 30_000;
+
+static DISABLED: AtomicBool = AtomicBool::new(false);
+static FAILURES: AtomicU32 = AtomicU32::new(0);
+static CONSECUTIVE_FAILURES: AtomicU32 = AtomicU32::new(0);
+// CRC32 of the idle kernel image last read from config, None if there was none
+static LOADED_CRC: Mutex<Option<u32>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Status {
+    pub disabled: bool,
+    pub failures: u32,
+    pub consecutive_failures: u32,
+}
+
+pub fn disabled() -> bool {
+    DISABLED.load(Ordering::Relaxed)
+}
+
+pub fn status() -> Status {
+    Status {
+        disabled: disabled(),
+        failures: FAILURES.load(Ordering::Relaxed),
+        consecutive_failures: CONSECUTIVE_FAILURES.load(Ordering::Relaxed),
+    }
+}
+
+pub fn succeeded() {
+    CONSECUTIVE_FAILURES.store(0, Ordering::Relaxed);
+}
+
+// returns how long to wait before the next attempt
+pub fn failed() -> u64 {
+    FAILURES.fetch_add(1, Ordering::Relaxed);
+    let consecutive = CONSECUTIVE_FAILURES.fetch_add(1, Ordering::Relaxed) + 1;
+    if consecutive >= MAX_CONSECUTIVE_FAILURES {
+        DISABLED.store(true, Ordering::Relaxed);
+        error!("idle kernel failed {} times in a row, disabling it", consecutive);
+        return 0;
+    }
+    let backoff = (BACKOFF_MIN_MS << (consecutive - 1)).min(BACKOFF_MAX_MS);
+    warn!("idle kernel failed ({} in a row), retrying in {} ms", consecutive, backoff);
+    backoff
+}
+
+pub fn rearm() {
+    CONSECUTIVE_FAILURES.store(0, Ordering::Relaxed);
+    if DISABLED.swap(false, Ordering::Relaxed) {
+        info!("idle kernel re-armed");
+    }
+}
+
+pub fn set_loaded(image: Option<&[u8]>) {
+    *LOADED_CRC.lock() = image.map(crc32::checksum_ieee);
+}
+
+// tells whether the image is the same as the idle kernel currently in use
+pub fn is_loaded(image: &[u8]) -> bool {
+    *LOADED_CRC.lock() == Some(crc32::checksum_ieee(image))
+}
+
+// Links the main kernel of a new idle kernel image on core 0, so that a corrupt upload
+// is rejected before the running idle kernel is terminated. Subkernels of a multi-kernel
+// archive can only be checked by their satellites once uploaded.
+pub fn verify(image: &[u8]) -> Result<(), String> {
+    if image.len() >= 4 && image[..4] == [elf::ELFMAG0, elf::ELFMAG1, elf::ELFMAG2, elf::ELFMAG3] {
+        return core1::check_load(image).map_err(|e| format!("{}", e));
+    }
+    #[cfg(has_drtio)]
+    {
+        let archive = TarArchiveRef::new(image);
+        match archive.entries().find(|entry| entry.filename().as_str() == "main.elf") {
+            Some(entry) => core1::check_load(entry.data()).map_err(|e| format!("{}", e)),
+            None => Err(String::from("no main.elf in kernel archive")),
+        }
+    }
+    #[cfg(not(has_drtio))]
+    {
+        Err(String::from("not an ELF file"))
+    }
+}
@@ -0,0 +1,115 @@
+use alloc::string::String;
+use core::fmt::{Display, Write};
+
+// Minimal JSON writer for the structured management replies. Documents are built
+// in one pass through nested closures, without an intermediate representation.
+
+fn write_str(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_object(out: &mut String, f: impl FnOnce(&mut Object)) {
+    out.push('{');
+    f(&mut Object {
+        out: &mut *out,
+        first: true,
+    });
+    out.push('}');
+}
+
+pub struct Object<'a> {
+    out: &'a mut String,
+    first: bool,
+}
+
+impl<'a> Object<'a> {
+    fn key(&mut self, key: &str) {
+        if !self.first {
+            self.out.push(',');
+        }
+        self.first = false;
+        write_str(self.out, key);
+        self.out.push(':');
+    }
+
+    pub fn bool(&mut self, key: &str, value: bool) -> &mut Self {
+        self.key(key);
+        self.out.push_str(if value { "true" } else { "false" });
+        self
+    }
+
+    // integers only, floats would need special cases for NaN and infinities
+    pub fn number(&mut self, key: &str, value: impl Display) -> &mut Self {
+        self.key(key);
+        let _ = write!(self.out, "{}", value);
+        self
+    }
+
+    pub fn str(&mut self, key: &str, value: &str) -> &mut Self {
+        self.key(key);
+        write_str(self.out, value);
+        self
+    }
+
+    pub fn array(&mut self, key: &str, f: impl FnOnce(&mut Array)) -> &mut Self {
+        self.key(key);
+        self.out.push('[');
+        f(&mut Array {
+            out: &mut *self.out,
+            first: true,
+        });
+        self.out.push(']');
+        self
+    }
+
+    pub fn object(&mut self, key: &str, f: impl FnOnce(&mut Object)) -> &mut Self {
+        self.key(key);
+        write_object(self.out, f);
+        self
+    }
+}
+
+pub struct Array<'a> {
+    out: &'a mut String,
+    first: bool,
+}
+
+impl<'a> Array<'a> {
+    fn separator(&mut self) {
+        if !self.first {
+            self.out.push(',');
+        }
+        self.first = false;
+    }
+
+    pub fn object(&mut self, f: impl FnOnce(&mut Object)) -> &mut Self {
+        self.separator();
+        write_object(self.out, f);
+        self
+    }
+}
+
+// Every document names its schema and version, so that clients can tell which fields to
+// expect; fields are only ever added within a version.
+pub fn document(schema: &str, version: u32, f: impl FnOnce(&mut Object)) -> String {
+    let mut out = String::new();
+    write_object(&mut out, |o| {
+        o.str("schema", schema).number("version", version);
+        f(o);
+    });
+    out
+}
@@ -20,7 +20,9 @@ use libboard_artiq::drtio_eem;
 use libboard_artiq::io_expander;
 #[cfg(has_cxp_grabber)]
 use libboard_artiq::{cxp_grabber, cxp_phys};
-use libboard_artiq::{i2c, identifier_read, logger, pl};
+#[cfg(has_si5324)]
+use libboard_artiq::si5324;
+use libboard_artiq::{i2c, identifier_read, logger, panic_report, pl, startup_io};
 use libboard_zynq::{gic, mpcore, timer};
 use libconfig;
 use libcortex_a9::l2c::enable_l2_cache;
@@ -28,16 +30,31 @@ use libsupport_zynq::{exception_vectors, ram};
 use log::{LevelFilter, info, warn};
 
 mod analyzer;
+mod auth;
+#[cfg(has_drtio)]
+mod aux_timeout;
+mod boot_slot;
 mod comms;
-
+mod config_archive;
+mod events;
+mod idle_kernel;
+mod json;
 mod mgmt;
 mod moninj;
+mod moninj_udp;
+mod net_buffers;
+mod net_routes;
+mod net_stats;
 mod panic;
+mod ping;
 mod proto_async;
 mod rpc_async;
 mod rtio_clocking;
 mod rtio_dma;
 mod rtio_mgt;
+mod selftest;
+mod services;
+mod storage_health;
 #[cfg(has_drtio)]
 mod subkernel;
 
@@ -46,7 +63,7 @@ extern "C" {
     static __exceptions_start: u32;
 }
 
-#[cfg(all(feature = "target_kasli_soc", has_virtual_leds))]
+#[cfg(feature = "target_kasli_soc")]
 async fn io_expanders_service(
     i2c_bus: RefCell<&mut libboard_zynq::i2c::I2c>,
     io_expander0: RefCell<io_expander::IoExpander>,
@@ -54,14 +71,8 @@ async fn io_expanders_service(
 ) {
     loop {
         task::r#yield().await;
-        io_expander0
-            .borrow_mut()
-            .service(&mut i2c_bus.borrow_mut())
-            .expect("I2C I/O expander #0 service failed");
-        io_expander1
-            .borrow_mut()
-            .service(&mut i2c_bus.borrow_mut())
-            .expect("I2C I/O expander #1 service failed");
+        io_expander0.borrow_mut().poll(&mut i2c_bus.borrow_mut());
+        io_expander1.borrow_mut().poll(&mut i2c_bus.borrow_mut());
     }
 }
 
@@ -78,6 +89,17 @@ mod grabber {
     }
 }
 
+// lock losses are counted from the sticky flags, which only hold one event each between polls
+#[cfg(has_si5324)]
+async fn si5324_monitor() {
+    loop {
+        timer::async_delay_ms(si5324::POLL_INTERVAL_MS).await;
+        if let Err(e) = si5324::poll(i2c::get_bus()) {
+            warn!("failed to poll Si5324 status: {}", e);
+        }
+    }
+}
+
 fn setup_log_levels() {
     if let Ok(level_string) = libconfig::read_str("log_level") {
         if let Ok(level) = level_string.parse::<LevelFilter>() {
@@ -101,6 +123,22 @@ fn setup_log_levels() {
     }
 }
 
+// the log starts in LOG_BUFFER and is moved to the heap if `log_buffer_size` is set
+const MIN_LOG_BUFFER_SIZE: usize = 4096;
+
+fn setup_log_buffer() {
+    if let Ok(size_string) = libconfig::read_str("log_buffer_size") {
+        match size_string.parse::<usize>() {
+            Ok(size) if size >= MIN_LOG_BUFFER_SIZE => {
+                let buffer = vec![0; size].leak();
+                logger::BufferLogger::get_logger().set_buffer(buffer);
+                info!("log buffer set to {} bytes by `log_buffer_size` config key", size);
+            }
+            _ => warn!("log_buffer_size \"{}\" not supported, ignoring", size_string),
+        }
+    }
+}
+
 static mut LOG_BUFFER: [u8; 1 << 17] = [0; 1 << 17];
 
 #[no_mangle]
@@ -123,17 +161,28 @@ pub fn main_core0() {
     info!("gateware ident: {}", identifier_read(&mut [0; 64]));
 
     i2c::init();
+
+    // config is needed early, to put the I/O into its startup state
+    let config_ready = match libconfig::init() {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("config initialization failed: {}", err);
+            false
+        }
+    };
+
+    setup_log_levels();
+    setup_log_buffer();
+    panic_report::report_previous(config_ready);
+
+    let startup_io = startup_io::load();
+
     #[cfg(feature = "target_kasli_soc")]
     {
         let i2c_bus = i2c::get_bus();
-        let mut io_expander0 = io_expander::IoExpander::new(i2c_bus, 0).unwrap();
-        let mut io_expander1 = io_expander::IoExpander::new(i2c_bus, 1).unwrap();
-        io_expander0
-            .init(i2c_bus)
-            .expect("I2C I/O expander #0 initialization failed");
-        io_expander1
-            .init(i2c_bus)
-            .expect("I2C I/O expander #1 initialization failed");
+        // expanders failing at boot are brought up later by the service task
+        let mut io_expander0 = io_expander::IoExpander::attach(i2c_bus, 0).unwrap();
+        let mut io_expander1 = io_expander::IoExpander::attach(i2c_bus, 1).unwrap();
 
         // Drive CLK_SEL to true
         #[cfg(has_si549)]
@@ -149,10 +198,11 @@ pub fn main_core0() {
         #[cfg(hw_rev = "v1.2")]
         io_expander1.set(0, 7, true);
 
-        io_expander0.service(i2c_bus).unwrap();
-        io_expander1.service(i2c_bus).unwrap();
+        startup_io::apply_expanders(&startup_io, &mut io_expander0, &mut io_expander1);
+
+        io_expander0.poll(i2c_bus);
+        io_expander1.poll(i2c_bus);
 
-        #[cfg(has_virtual_leds)]
         task::spawn(io_expanders_service(
             RefCell::new(i2c_bus),
             RefCell::new(io_expander0),
@@ -160,13 +210,12 @@ pub fn main_core0() {
         ));
     }
 
-    if let Err(err) = libconfig::init() {
-        warn!("config initialization failed: {}", err);
-    }
+    rtio_clocking::init();
 
-    setup_log_levels();
+    #[cfg(has_si5324)]
+    task::spawn(si5324_monitor());
 
-    rtio_clocking::init();
+    startup_io::apply_ttl(&startup_io);
 
     #[cfg(has_drtio_eem)]
     drtio_eem::init();
@@ -1,22 +1,35 @@
 use alloc::{rc::Rc, string::String, vec::Vec};
-use core::{cell::RefCell, str::Utf8Error};
+use core::{cell::RefCell, cmp::min, str::Utf8Error};
 
 use byteorder::{ByteOrder, NativeEndian};
 use crc::crc32;
-use futures::{future::poll_fn, task::Poll};
+use futures::{FutureExt, future::poll_fn, pin_mut, select_biased, task::Poll};
 use libasync::{smoltcp::TcpStream, task};
 #[cfg(has_drtio)]
-use libboard_artiq::drtio_routing;
-use libboard_artiq::logger::{BufferLogger, LogBufferRef};
-use libboard_zynq::smoltcp;
+use libboard_artiq::{drtio_routing, drtioaux_proto::DESTINATION_MASK_SIZE, pl::csr};
+#[cfg(has_drtio_eem)]
+use libboard_artiq::drtio_eem;
+#[cfg(has_drtio_eyescan)]
+use libboard_artiq::eye_scan;
+use libboard_artiq::{config_store, console,
+                     logger::{BufferLogger, LogBufferRef, LogFilter},
+                     panic_report, wall_clock};
+use libboard_zynq::{smoltcp, timer};
 use libconfig;
+#[cfg(has_drtio)]
+use libcortex_a9::semaphore::Semaphore;
 use log::{self, debug, error, info, warn};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 
-use crate::{comms::RESTART_IDLE, proto_async::*};
+use crate::{analyzer, auth, boot_slot,
+            comms,
+            config_archive,
+            comms::{CACHE_STORE, KERNEL_STATS, RESTART_IDLE},
+            events, idle_kernel, net_buffers, net_routes, net_stats, ping, proto_async::*, rtio_mgt, selftest,
+            services, storage_health};
 #[cfg(has_drtio)]
-use crate::{comms::ROUTING_TABLE, rtio_mgt::drtio};
+use crate::{aux_timeout, comms::ROUTING_TABLE, rtio_mgt::drtio};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
@@ -25,6 +38,7 @@ pub enum Error {
     UnknownLogLevel(),
     Utf8(Utf8Error),
     UnexpectedPattern,
+    AuthFailed,
     UnrecognizedPacket,
     #[cfg(has_drtio)]
     DrtioError(drtio::Error),
@@ -40,6 +54,7 @@ impl core::fmt::Display for Error {
             &Error::UnknownLogLevel() => write!(f, "unknown log level"),
             &Error::Utf8(error) => write!(f, "invalid UTF-8: {}", error),
             &Error::UnexpectedPattern => write!(f, "unexpected pattern"),
+            &Error::AuthFailed => write!(f, "authentication failed"),
             &Error::UnrecognizedPacket => write!(f, "unrecognized packet"),
             #[cfg(has_drtio)]
             &Error::DrtioError(error) => write!(f, "drtio error: {}", error),
@@ -81,6 +96,91 @@ pub enum Request {
     DebugAllocator = 8,
 
     Flash = 9,
+    FlashWithProgress = 16,
+    FlashVerify = 17,
+
+    GetBootSlot = 18,
+    SelectBootSlot = 19,
+
+    FlashFanOut = 20,
+
+    GetKernelStats = 21,
+
+    GetLinkStatus = 22,
+
+    SetSedSpread = 23,
+
+    GetStorageHealth = 24,
+
+    EyeScan = 25,
+
+    GetNetStats = 26,
+
+    GetAuxTimeouts = 27,
+
+    CriConStatus = 28,
+
+    GetIdleKernelStatus = 29,
+    RearmIdleKernel = 30,
+
+    SubscribeEvents = 31,
+
+    KernelCacheStatus = 32,
+
+    GetSelfTestReport = 33,
+
+    RetrainEem = 34,
+
+    SetWallClock = 35,
+
+    GetTscDrift = 36,
+
+    ConsoleBridge = 37,
+
+    GetAuxCaptures = 38,
+
+    GetLastPanic = 39,
+
+    RunStoredKernel = 40,
+
+    GetClockStatus = 41,
+
+    SetAuxTrace = 42,
+    GetAuxTrace = 43,
+
+    ReleaseDeviceLock = 44,
+
+    GetServices = 45,
+
+    GetDrtioStats = 46,
+
+    GetCoreDump = 47,
+
+    GetIoExpanderStatus = 48,
+
+    SetReplyFormat = 49,
+
+    EemPrbsTest = 50,
+
+    SetLinkEnabled = 51,
+
+    ReloadDeviceMap = 52,
+
+    SetProfiler = 53,
+    GetProfile = 54,
+
+    ConfigBackup = 55,
+    ConfigRestore = 56,
+
+    SetAnalyzerAutoArm = 57,
+    GetAnalyzerSession = 58,
+
+    LoadKernelViaMgmt = 59,
+
+    Ping = 60,
+
+    GetLogWithStats = 61,
+    PullLogWithStats = 62,
 }
 
 #[repr(i8)]
@@ -90,6 +190,96 @@ pub enum Reply {
     RebootImminent = 3,
     Error = 6,
     ConfigData = 7,
+    FlashProgress = 8,
+    BootSlot = 9,
+    FlashFanOutResult = 10,
+    KernelStats = 11,
+    LinkStatus = 12,
+    StorageHealth = 13,
+    EyeScanData = 14,
+    NetStats = 15,
+    AuxTimeouts = 16,
+    CriConStatus = 17,
+    IdleKernelStatus = 18,
+    Event = 19,
+    KernelCacheStatus = 20,
+    SelfTestReport = 21,
+    EemTraining = 22,
+    TscDrift = 23,
+    ConsoleOutput = 24,
+    AuxCaptures = 25,
+    LastPanic = 26,
+    ClockStatus = 27,
+    AuxTrace = 28,
+    Services = 29,
+    DrtioStats = 30,
+    CoreDump = 31,
+    IoExpanderStatus = 32,
+    ReplyFormat = 33,
+    Structured = 34,
+    EemPrbsResult = 35,
+    Profile = 36,
+    ConfigArchive = 37,
+    AnalyzerSession = 38,
+    KernelOutput = 39,
+    Pong = 40,
+}
+
+// boot images are received in chunks of this size, and the progress
+// is reported to the client every FLASH_PROGRESS_INTERVAL bytes (and on completion)
+const FLASH_CHUNK_SIZE: usize = 4096;
+const FLASH_PROGRESS_INTERVAL: usize = 64 * 1024;
+
+// layout of the DrtioStats reply, changed whenever fields are added:
+// 1 only had the latency, 2 added the router and repeater counters
+const DRTIO_STATS_VERSION: i8 = 2;
+
+async fn write_flash_progress(stream: &mut TcpStream, written: usize, total: usize) -> Result<()> {
+    write_i8(stream, Reply::FlashProgress as i8).await?;
+    write_i32(stream, written as i32).await?;
+    write_i32(stream, total as i32).await?;
+    Ok(())
+}
+
+async fn read_image(stream: &mut TcpStream, length: usize, report_progress: bool) -> Result<Vec<u8>> {
+    let mut image = Vec::with_capacity(length);
+    let mut next_report = FLASH_PROGRESS_INTERVAL;
+    while image.len() < length {
+        let done = image.len();
+        let len = min(FLASH_CHUNK_SIZE, length - done);
+        image.resize(done + len, 0);
+        read_chunk(stream, &mut image[done..]).await?;
+        if report_progress && (image.len() >= next_report || image.len() == length) {
+            write_flash_progress(stream, image.len(), length).await?;
+            next_report += FLASH_PROGRESS_INTERVAL;
+        }
+    }
+    Ok(image)
+}
+
+fn image_crc_ok(image: &[u8]) -> bool {
+    if image.len() < 4 {
+        error!("boot image too short ({} bytes)", image.len());
+        return false;
+    }
+    let bin_len = image.len() - 4;
+
+    let (image_ref, expected_crc) = {
+        let (image_ref, crc_slice) = image.split_at(bin_len);
+        (image_ref, NativeEndian::read_u32(crc_slice))
+    };
+
+    let actual_crc = crc32::checksum_ieee(image_ref);
+
+    if actual_crc != expected_crc {
+        error!(
+            "CRC failed, images have not been written to flash.\n(actual {:08x}, expected {:08x})",
+            actual_crc, expected_crc
+        );
+        false
+    } else {
+        true
+    }
 }
 
 async fn get_logger_buffer_pred<F>(f: F) -> LogBufferRef<'static>
@@ -111,6 +301,18 @@ async fn get_logger_buffer() -> LogBufferRef<'static> {
     get_logger_buffer_pred(|_| true).await
 }
 
+// PullLogWithStats is followed by the version of the options the client sends after it:
+// none at version 1, the log filter from version 2 and the pacing from version 3
+const PULL_LOG_VERSION: u8 = 3;
+
+// with GetLogWithStats and PullLogWithStats, log contents are followed by the bytes and
+// messages the log buffer overwrote before they could be read, since boot
+async fn write_log_stats(stream: &mut TcpStream, dropped_bytes: u64, dropped_messages: u32) -> Result<()> {
+    write_i64(stream, dropped_bytes as i64).await?;
+    write_i32(stream, dropped_messages as i32).await?;
+    Ok(())
+}
+
 async fn read_key(stream: &mut TcpStream) -> Result<String> {
     let len = read_i32(stream).await?;
     if len <= 0 {
@@ -129,16 +331,108 @@ async fn read_key(stream: &mut TcpStream) -> Result<String> {
     Ok(String::from_utf8(buffer).unwrap())
 }
 
+// the highest severity and the module prefix of the lines to stream
+async fn read_log_filter(stream: &mut TcpStream) -> Result<LogFilter> {
+    let max_level = LogFilter::level_from_u8(read_i8(stream).await? as u8);
+    let len = read_i32(stream).await?;
+    if len < 0 {
+        write_i8(stream, Reply::Error as i8).await?;
+        return Err(Error::UnexpectedPattern);
+    }
+    let mut prefix = Vec::with_capacity(len as usize);
+    prefix.resize(len as usize, 0);
+    read_chunk(stream, &mut prefix).await?;
+    if !prefix.is_ascii() {
+        write_i8(stream, Reply::Error as i8).await?;
+        return Err(Error::UnexpectedPattern);
+    }
+    Ok(LogFilter {
+        max_level: max_level,
+        module_prefix: String::from_utf8(prefix).unwrap(),
+    })
+}
+
+// The filter is followed by a rate cap in bytes per second and a chunk size in bytes, 0 for
+// neither. The cap applies to the aux transfers for the log of a satellite, so that a slow link
+// keeps room for other aux transactions, and to the stream itself for the local log.
+struct LogPacing {
+    rate: u32,
+    chunk_size: usize,
+}
+
+impl LogPacing {
+    fn none() -> LogPacing {
+        LogPacing {
+            rate: 0,
+            chunk_size: 0,
+        }
+    }
+
+    fn chunks<'a>(&self, log: &'a [u8]) -> Vec<&'a [u8]> {
+        if self.chunk_size == 0 || log.len() <= self.chunk_size {
+            return vec![log];
+        }
+        log.chunks(self.chunk_size).collect()
+    }
+
+    async fn wait(&self, len: usize) {
+        if self.rate > 0 {
+            timer::async_delay_ms(len as u64 * 1000 / self.rate as u64).await;
+        }
+    }
+}
+
+async fn read_log_pacing(stream: &mut TcpStream) -> Result<LogPacing> {
+    let rate = read_i32(stream).await?;
+    let chunk_size = read_i32(stream).await?;
+    if rate < 0 || chunk_size < 0 {
+        write_i8(stream, Reply::Error as i8).await?;
+        return Err(Error::UnexpectedPattern);
+    }
+    Ok(LogPacing {
+        rate: rate as u32,
+        chunk_size: chunk_size as usize,
+    })
+}
+
+// Each I/O expander is reported with whether it is failing, and its failed services
+// and re-initializations since boot.
+async fn write_io_expander_status(stream: &mut TcpStream, status: &[(bool, u32, u32)]) -> Result<()> {
+    write_i8(stream, Reply::IoExpanderStatus as i8).await?;
+    write_i8(stream, status.len() as i8).await?;
+    for &(failing, errors, reinits) in status {
+        write_bool(stream, failing).await?;
+        write_i32(stream, errors as i32).await?;
+        write_i32(stream, reinits as i32).await?;
+    }
+    Ok(())
+}
+
+#[cfg(has_drtio)]
+async fn write_aux_trace(stream: &mut TcpStream, entries: &[libboard_artiq::drtioaux::TraceEntry]) -> Result<()> {
+    write_i8(stream, Reply::AuxTrace as i8).await?;
+    write_i32(stream, entries.len() as i32).await?;
+    for entry in entries {
+        write_i64(stream, entry.timestamp_ms as i64).await?;
+        write_i8(stream, entry.linkno as i8).await?;
+        write_bool(stream, entry.transmitted).await?;
+        write_i8(stream, entry.packet_type as i8).await?;
+        write_i8(stream, entry.destination as i8).await?;
+    }
+    Ok(())
+}
+
 #[cfg(has_drtio)]
 mod remote_coremgmt {
-    use core_io::Read;
     use io::ProtoWrite;
-    use libboard_artiq::{drtioaux_async,
-                         drtioaux_proto::{MASTER_PAYLOAD_MAX_SIZE, Packet}};
+    use libboard_artiq::{drtioaux::{AUX_TRACE_ENTRY_SIZE, TraceEntry},
+                         drtioaux_async,
+                         drtioaux_proto::{EYE_SCAN_TIMEOUT, FEATURE_CONFIG_LIST, FEATURE_LOG_STATS,
+                                          FLASH_FAN_OUT_TIMEOUT_PER_TARGET, MASTER_PAYLOAD_MAX_SIZE, Packet}};
 
     use super::*;
 
-    pub async fn get_log(stream: &mut TcpStream, linkno: u8, destination: u8) -> Result<()> {
+    pub async fn get_log(stream: &mut TcpStream, linkno: u8, destination: u8, with_stats: bool) -> Result<()> {
         let mut buffer = Vec::new();
         loop {
             let reply = drtio::aux_transact(
@@ -146,6 +440,7 @@ mod remote_coremgmt {
                 &Packet::CoreMgmtGetLogRequest {
                     destination,
                     clear: false,
+                    max_level: LogFilter::level_to_u8(log::LevelFilter::Trace),
                 },
             )
             .await;
@@ -154,8 +449,22 @@ mod remote_coremgmt {
                 Ok(Packet::CoreMgmtGetLogReply { last, length, data }) => {
                     buffer.extend(&data[..length as usize]);
                     if last {
+                        let stats = if with_stats {
+                            match log_stats(linkno, destination).await {
+                                Ok(stats) => Some(stats),
+                                Err(e) => {
+                                    write_i8(stream, Reply::Error as i8).await?;
+                                    return Err(e);
+                                }
+                            }
+                        } else {
+                            None
+                        };
                         write_i8(stream, Reply::LogContent as i8).await?;
                         write_chunk(stream, &buffer).await?;
+                        if let Some((dropped_bytes, dropped_messages)) = stats {
+                            write_log_stats(stream, dropped_bytes, dropped_messages).await?;
+                        }
                         return Ok(());
                     }
                 }
@@ -173,6 +482,28 @@ mod remote_coremgmt {
         }
     }
 
+    // satellites that do not count lost log messages report none
+    async fn log_stats(linkno: u8, destination: u8) -> Result<(u64, u32)> {
+        if !drtio::link_supports(linkno, FEATURE_LOG_STATS) {
+            return Ok((0, 0));
+        }
+        let reply = drtio::aux_transact(linkno, &Packet::CoreMgmtLogStatsRequest { destination }).await;
+        match reply {
+            Ok(Packet::CoreMgmtLogStatsReply {
+                dropped_bytes,
+                dropped_messages,
+            }) => Ok((dropped_bytes, dropped_messages)),
+            Ok(packet) => {
+                error!("received unexpected aux packet: {:?}", packet);
+                Err(drtio::Error::UnexpectedReply.into())
+            }
+            Err(e) => {
+                error!("aux packet error ({})", e);
+                Err(e.into())
+            }
+        }
+    }
+
     pub async fn clear_log(stream: &mut TcpStream, linkno: u8, destination: u8) -> Result<()> {
         let reply = drtio::aux_transact(linkno, &Packet::CoreMgmtClearLogRequest { destination }).await;
 
@@ -194,7 +525,15 @@ mod remote_coremgmt {
         }
     }
 
-    pub async fn pull_log(stream: &mut TcpStream, linkno: u8, destination: u8, pull_id: &RefCell<u32>) -> Result<()> {
+    pub async fn pull_log(
+        stream: &mut TcpStream,
+        linkno: u8,
+        destination: u8,
+        pull_id: &RefCell<u32>,
+        filter: &LogFilter,
+        pacing: &LogPacing,
+        with_stats: bool,
+    ) -> Result<()> {
         let id = {
             let mut guard = pull_id.borrow_mut();
             *guard += 1;
@@ -214,6 +553,7 @@ mod remote_coremgmt {
                 &Packet::CoreMgmtGetLogRequest {
                     destination,
                     clear: true,
+                    max_level: LogFilter::level_to_u8(filter.max_level),
                 },
             )
             .await;
@@ -221,8 +561,21 @@ mod remote_coremgmt {
             match reply {
                 Ok(Packet::CoreMgmtGetLogReply { last, length, data }) => {
                     buffer.extend(&data[..length as usize]);
+                    pacing.wait(length as usize).await;
                     if last {
-                        write_chunk(stream, &buffer).await?;
+                        let stats = if with_stats {
+                            Some(log_stats(linkno, destination).await?)
+                        } else {
+                            None
+                        };
+                        // the satellite only filters by level, the module prefix is matched here
+                        let log = filter.apply(&String::from_utf8_lossy(&buffer));
+                        for chunk in pacing.chunks(log.as_bytes()) {
+                            write_chunk(stream, chunk).await?;
+                            if let Some((dropped_bytes, dropped_messages)) = stats {
+                                write_log_stats(stream, dropped_bytes, dropped_messages).await?;
+                            }
+                        }
                         buffer.clear();
                         task::r#yield().await;
                     }
@@ -288,18 +641,60 @@ mod remote_coremgmt {
         }
     }
 
-    pub async fn config_write(
-        stream: &mut TcpStream,
+    // Reads a config value of a satellite, None if the key is not set.
+    async fn read_value(linkno: u8, destination: u8, key: &str) -> core::result::Result<Option<Vec<u8>>, drtio::Error> {
+        let mut config_key: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
+        config_key[..key.len()].clone_from_slice(key.as_bytes());
+        read_reply(
+            linkno,
+            destination,
+            Packet::CoreMgmtConfigReadRequest {
+                destination: destination,
+                length: key.len() as u16,
+                key: config_key,
+            },
+        )
+        .await
+    }
+
+    // the value, or the key list, comes in slices until the last one
+    async fn read_reply(
         linkno: u8,
         destination: u8,
-        key: &String,
-        value: Vec<u8>,
-    ) -> Result<()> {
+        mut request: Packet,
+    ) -> core::result::Result<Option<Vec<u8>>, drtio::Error> {
+        let mut buffer = Vec::new();
+        loop {
+            match drtio::aux_transact(linkno, &request).await? {
+                Packet::CoreMgmtConfigReadReply { last, length, value } => {
+                    buffer.extend(&value[..length as usize]);
+                    if last {
+                        return Ok(Some(buffer));
+                    }
+                    request = Packet::CoreMgmtConfigReadContinue {
+                        destination: destination,
+                    };
+                }
+                Packet::CoreMgmtReply { succeeded: false } => return Ok(None),
+                packet => {
+                    error!("received unexpected aux packet: {:?}", packet);
+                    return Err(drtio::Error::UnexpectedReply);
+                }
+            }
+        }
+    }
+
+    async fn write_value(
+        linkno: u8,
+        destination: u8,
+        key: &str,
+        value: &[u8],
+    ) -> core::result::Result<(), drtio::Error> {
         let mut message = Vec::with_capacity(key.len() + value.len() + 4 * 2);
         message.write_string::<NativeEndian>(key).unwrap();
-        message.write_bytes::<NativeEndian>(&value).unwrap();
+        message.write_bytes::<NativeEndian>(value).unwrap();
 
-        match drtio::partition_data(
+        drtio::partition_data(
             linkno,
             &message,
             |slice, status, len: usize| Packet::CoreMgmtConfigWriteRequest {
@@ -317,7 +712,16 @@ mod remote_coremgmt {
             },
         )
         .await
-        {
+    }
+
+    pub async fn config_write(
+        stream: &mut TcpStream,
+        linkno: u8,
+        destination: u8,
+        key: &String,
+        value: Vec<u8>,
+    ) -> Result<()> {
+        match write_value(linkno, destination, key, &value).await {
             Ok(()) => {
                 write_i8(stream, Reply::Success as i8).await?;
                 Ok(())
@@ -363,6 +767,62 @@ mod remote_coremgmt {
         }
     }
 
+    pub async fn config_backup(stream: &mut TcpStream, linkno: u8, destination: u8) -> Result<()> {
+        if !drtio::link_supports(linkno, FEATURE_CONFIG_LIST) {
+            error!("[DEST#{}] config backup is {}", destination, drtio::Error::Unsupported);
+            write_i8(stream, Reply::Error as i8).await?;
+            return Ok(());
+        }
+        let keys = match read_reply(linkno, destination, Packet::CoreMgmtConfigListRequest { destination }).await {
+            Ok(Some(list)) => config_archive::parse_keys(&list),
+            Ok(None) => {
+                error!("[DEST#{}] failed to list config keys", destination);
+                write_i8(stream, Reply::Error as i8).await?;
+                return Ok(());
+            }
+            Err(e) => {
+                error!("aux packet error ({})", e);
+                write_i8(stream, Reply::Error as i8).await?;
+                return Err(e.into());
+            }
+        };
+        let mut entries = Vec::new();
+        for key in keys.iter().filter(|key| config_archive::backed_up(key)) {
+            match read_value(linkno, destination, key).await {
+                Ok(Some(value)) => entries.push((key.as_str(), value)),
+                Ok(None) => (),
+                Err(e) => {
+                    error!("aux packet error ({})", e);
+                    write_i8(stream, Reply::Error as i8).await?;
+                    return Err(e.into());
+                }
+            }
+        }
+        info!("[DEST#{}] backed up {} config keys", destination, entries.len());
+        write_i8(stream, Reply::ConfigArchive as i8).await?;
+        write_chunk(stream, &config_archive::build(&entries)).await?;
+        Ok(())
+    }
+
+    pub async fn config_restore(stream: &mut TcpStream, linkno: u8, destination: u8, archive: Vec<u8>) -> Result<()> {
+        let entries = config_archive::entries(&archive);
+        if let Some((key, _)) = entries.iter().find(|(key, _)| !config_archive::restorable(key)) {
+            error!("config key {} cannot be restored, not restoring the backup", key);
+            write_i8(stream, Reply::Error as i8).await?;
+            return Ok(());
+        }
+        for (key, value) in entries.iter() {
+            if let Err(e) = write_value(linkno, destination, key, value).await {
+                error!("[DEST#{}] failed to restore config key {} ({})", destination, key, e);
+                write_i8(stream, Reply::Error as i8).await?;
+                return Err(e.into());
+            }
+        }
+        info!("[DEST#{}] restored {} config keys", destination, entries.len());
+        write_i8(stream, Reply::Success as i8).await?;
+        Ok(())
+    }
+
     pub async fn config_erase(stream: &mut TcpStream, linkno: u8, destination: u8) -> Result<()> {
         let reply = drtio::aux_transact(
             linkno,
@@ -442,14 +902,20 @@ mod remote_coremgmt {
         }
     }
 
-    pub async fn image_write(stream: &mut TcpStream, linkno: u8, destination: u8, image: Vec<u8>) -> Result<()> {
-        let mut image = &image[..];
-
+    pub async fn image_write(
+        stream: &mut TcpStream,
+        linkno: u8,
+        destination: u8,
+        length: usize,
+        report_progress: bool,
+        verify_only: bool,
+    ) -> Result<()> {
         let alloc_reply = drtio::aux_transact(
             linkno,
             &Packet::CoreMgmtFlashRequest {
                 destination: destination,
-                payload_length: image.len() as u32,
+                payload_length: length as u32,
+                verify_only: verify_only,
             },
         )
         .await;
@@ -468,10 +934,15 @@ mod remote_coremgmt {
             }
         }?;
 
-        while !image.is_empty() {
+        // image is passed on to the satellite as it arrives, without buffering it whole
+        let mut written = 0;
+        let mut next_report = FLASH_PROGRESS_INTERVAL;
+        while written < length {
             let mut data = [0; MASTER_PAYLOAD_MAX_SIZE];
-            let len = image.read(&mut data).unwrap();
-            let last = image.is_empty();
+            let len = min(MASTER_PAYLOAD_MAX_SIZE, length - written);
+            read_chunk(stream, &mut data[..len]).await?;
+            written += len;
+            let last = written == length;
 
             let reply = drtio::aux_transact(
                 linkno,
@@ -485,8 +956,13 @@ mod remote_coremgmt {
             .await;
 
             match reply {
-                Ok(Packet::CoreMgmtReply { succeeded: true }) if !last => Ok(()),
-                Ok(Packet::CoreMgmtDropLink) if last => drtioaux_async::send(
+                Ok(Packet::CoreMgmtReply { succeeded: true }) if !last || verify_only => Ok(()),
+                Ok(Packet::CoreMgmtReply { succeeded: false }) if last && verify_only => {
+                    error!("boot image verification failed on destination {}", destination);
+                    write_i8(stream, Reply::Error as i8).await?;
+                    return Ok(());
+                }
+                Ok(Packet::CoreMgmtDropLink) if last && !verify_only => drtioaux_async::send(
                     linkno,
                     &Packet::CoreMgmtDropLinkAck {
                         destination: destination,
@@ -505,54 +981,573 @@ mod remote_coremgmt {
                     Err(drtio::Error::AuxError)
                 }
             }?;
+
+            if report_progress && (written >= next_report || last) {
+                write_flash_progress(stream, written, length).await?;
+                next_report += FLASH_PROGRESS_INTERVAL;
+            }
         }
 
-        write_i8(stream, Reply::RebootImminent as i8).await?;
+        if verify_only {
+            write_i8(stream, Reply::Success as i8).await?;
+        } else {
+            write_i8(stream, Reply::RebootImminent as i8).await?;
+        }
         Ok(())
     }
-}
 
-mod local_coremgmt {
-    use libboard_zynq::slcr;
+    // Pushes an image to the satellite directly behind the link, which forwards it
+    // to the other targets downstream. Returns the set of destinations that accepted it.
+    pub async fn image_fan_out(
+        linkno: u8,
+        entry: u8,
+        targets: &[u8; DESTINATION_MASK_SIZE],
+        image: &[u8],
+    ) -> core::result::Result<[u8; DESTINATION_MASK_SIZE], drtio::Error> {
+        let target_count = targets.iter().map(|b| b.count_ones() as u64).sum::<u64>();
 
-    use super::*;
+        let reply = drtio::aux_transact(
+            linkno,
+            &Packet::CoreMgmtFlashFanOutRequest {
+                destination: entry,
+                payload_length: image.len() as u32,
+                targets: *targets,
+            },
+        )
+        .await?;
+        if reply != (Packet::CoreMgmtReply { succeeded: true }) {
+            error!("received unexpected aux packet: {:?}", reply);
+            return Err(drtio::Error::UnexpectedReply);
+        }
 
-    pub async fn get_log(stream: &mut TcpStream) -> Result<()> {
-        let buffer = get_logger_buffer().await.extract().as_bytes().to_vec();
-        write_i8(stream, Reply::LogContent as i8).await?;
-        write_chunk(stream, &buffer).await?;
-        Ok(())
+        let mut chunks = image.chunks(MASTER_PAYLOAD_MAX_SIZE).peekable();
+        while let Some(chunk) = chunks.next() {
+            let last = chunks.peek().is_none();
+            let mut data = [0; MASTER_PAYLOAD_MAX_SIZE];
+            data[..chunk.len()].copy_from_slice(chunk);
+            let request = Packet::CoreMgmtFlashAddDataRequest {
+                destination: entry,
+                last: last,
+                length: chunk.len() as u16,
+                data: data,
+            };
+            let reply = if last {
+                drtio::aux_transact_timeout(linkno, &request, FLASH_FAN_OUT_TIMEOUT_PER_TARGET * target_count.max(1))
+                    .await?
+            } else {
+                drtio::aux_transact(linkno, &request).await?
+            };
+            match reply {
+                Packet::CoreMgmtReply { succeeded: true } if !last => (),
+                Packet::CoreMgmtFlashFanOutReply { succeeded } if last => {
+                    if drtio_routing::mask_contains(&succeeded, entry) {
+                        drtioaux_async::send(linkno, &Packet::CoreMgmtDropLinkAck { destination: entry })
+                            .await
+                            .map_err(|_| drtio::Error::AuxError)?;
+                    }
+                    return Ok(succeeded);
+                }
+                packet => {
+                    error!("received unexpected aux packet: {:?}", packet);
+                    return Err(drtio::Error::UnexpectedReply);
+                }
+            }
+        }
+        Err(drtio::Error::UnexpectedReply)
     }
 
-    pub async fn clear_log(stream: &mut TcpStream) -> Result<()> {
-        let mut buffer = get_logger_buffer().await;
-        buffer.clear();
-        write_i8(stream, Reply::Success as i8).await?;
-        Ok(())
-    }
+    pub async fn set_sed_spread(stream: &mut TcpStream, linkno: u8, destination: u8, enable: bool) -> Result<()> {
+        let reply = drtio::aux_transact(
+            linkno,
+            &Packet::CoreMgmtSetSedSpreadRequest {
+                destination: destination,
+                enable: enable,
+            },
+        )
+        .await;
 
-    pub async fn pull_log(stream: &mut TcpStream, pull_id: &RefCell<u32>) -> Result<()> {
-        let id = {
-            let mut guard = pull_id.borrow_mut();
-            *guard += 1;
-            *guard
-        };
-        loop {
-            let mut buffer = get_logger_buffer_pred(|b| !b.is_empty()).await;
-            if id != *pull_id.borrow() {
-                // another connection attempts to pull the log...
-                // abort this connection...
-                return Err(Error::OvertakeError);
+        match reply {
+            Ok(Packet::CoreMgmtReply { succeeded: true }) => {
+                write_i8(stream, Reply::Success as i8).await?;
+                Ok(())
             }
-            let bytes = buffer.extract().as_bytes().to_vec();
-            buffer.clear();
-            core::mem::drop(buffer);
-            write_chunk(stream, &bytes).await?;
-            if BufferLogger::get_logger().buffer_log_level() == log::LevelFilter::Trace {
-                let logger = BufferLogger::get_logger();
-                logger.set_buffer_log_level(log::LevelFilter::Debug);
-                stream.flush().await?;
-                logger.set_buffer_log_level(log::LevelFilter::Trace);
+            Ok(packet) => {
+                error!("received unexpected aux packet: {:?}", packet);
+                write_i8(stream, Reply::Error as i8).await?;
+                Err(drtio::Error::UnexpectedReply.into())
+            }
+            Err(e) => {
+                error!("aux packet error ({})", e);
+                write_i8(stream, Reply::Error as i8).await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    // Reports which CRI master (0: DRTIO, 1: DMA, 2: subkernel) the satellite arbiter selects
+    // and whether a subkernel or DMA playback is running; with `reset`, a selection
+    // left behind by a crashed kernel is set back to match them.
+    pub async fn cri_con_status(stream: &mut TcpStream, linkno: u8, destination: u8, reset: bool) -> Result<()> {
+        let reply = drtio::aux_transact(
+            linkno,
+            &Packet::CoreMgmtCriConRequest {
+                destination: destination,
+                reset: reset,
+            },
+        )
+        .await;
+
+        match reply {
+            Ok(Packet::CoreMgmtCriConReply {
+                selected,
+                kernel_running,
+                dma_playing,
+            }) => {
+                write_i8(stream, Reply::CriConStatus as i8).await?;
+                write_i8(stream, selected as i8).await?;
+                write_bool(stream, kernel_running).await?;
+                write_bool(stream, dma_playing).await?;
+                Ok(())
+            }
+            Ok(packet) => {
+                error!("received unexpected aux packet: {:?}", packet);
+                write_i8(stream, Reply::Error as i8).await?;
+                Err(drtio::Error::UnexpectedReply.into())
+            }
+            Err(e) => {
+                error!("aux packet error ({})", e);
+                write_i8(stream, Reply::Error as i8).await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    pub async fn kernel_cache_status(stream: &mut TcpStream, linkno: u8, destination: u8, clear: bool) -> Result<()> {
+        let reply = drtio::aux_transact(
+            linkno,
+            &Packet::CoreMgmtKernelCacheRequest {
+                destination: destination,
+                clear: clear,
+            },
+        )
+        .await;
+
+        match reply {
+            Ok(Packet::CoreMgmtKernelCacheReply {
+                entries,
+                size,
+                quota,
+                evictions,
+                rejections,
+            }) => {
+                write_i8(stream, Reply::KernelCacheStatus as i8).await?;
+                write_i32(stream, entries as i32).await?;
+                write_i32(stream, size as i32).await?;
+                write_i32(stream, quota as i32).await?;
+                write_i32(stream, evictions as i32).await?;
+                write_i32(stream, rejections as i32).await?;
+                Ok(())
+            }
+            Ok(packet) => {
+                error!("received unexpected aux packet: {:?}", packet);
+                write_i8(stream, Reply::Error as i8).await?;
+                Err(drtio::Error::UnexpectedReply.into())
+            }
+            Err(e) => {
+                error!("aux packet error ({})", e);
+                write_i8(stream, Reply::Error as i8).await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    pub async fn get_tsc_drift(stream: &mut TcpStream, linkno: u8, destination: u8) -> Result<()> {
+        match drtio::tsc_drift_probe(linkno, destination).await {
+            Ok((offset, drift_ppb)) => {
+                write_i8(stream, Reply::TscDrift as i8).await?;
+                write_i64(stream, offset).await?;
+                write_i32(stream, drift_ppb).await?;
+                Ok(())
+            }
+            Err(e) => {
+                error!("aux packet error ({})", e);
+                write_i8(stream, Reply::Error as i8).await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    // the satellite keeps the report in its config, read it like any other key
+    pub async fn get_last_panic(stream: &mut TcpStream, linkno: u8, destination: u8) -> Result<()> {
+        let mut config_key: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
+        let key = panic_report::LAST_PANIC_KEY.as_bytes();
+        config_key[..key.len()].clone_from_slice(key);
+
+        let mut reply = drtio::aux_transact(
+            linkno,
+            &Packet::CoreMgmtConfigReadRequest {
+                destination: destination,
+                length: key.len() as u16,
+                key: config_key,
+            },
+        )
+        .await;
+
+        let mut buffer = Vec::<u8>::new();
+        loop {
+            match reply {
+                Ok(Packet::CoreMgmtConfigReadReply { last, length, value }) => {
+                    buffer.extend(&value[..length as usize]);
+
+                    if last {
+                        break;
+                    }
+
+                    reply = drtio::aux_transact(
+                        linkno,
+                        &Packet::CoreMgmtConfigReadContinue {
+                            destination: destination,
+                        },
+                    )
+                    .await;
+                }
+                // no panic recorded
+                Ok(Packet::CoreMgmtReply { succeeded: false }) => break,
+                Ok(packet) => {
+                    error!("received unexpected aux packet: {:?}", packet);
+                    write_i8(stream, Reply::Error as i8).await?;
+                    return Err(drtio::Error::UnexpectedReply.into());
+                }
+                Err(e) => {
+                    error!("aux packet error ({})", e);
+                    write_i8(stream, Reply::Error as i8).await?;
+                    return Err(e.into());
+                }
+            }
+        }
+        write_i8(stream, Reply::LastPanic as i8).await?;
+        write_chunk(stream, &buffer).await?;
+        Ok(())
+    }
+
+    pub async fn get_io_expander_status(stream: &mut TcpStream, linkno: u8, destination: u8) -> Result<()> {
+        let reply = drtio::aux_transact(
+            linkno,
+            &Packet::CoreMgmtIoExpanderStatusRequest {
+                destination: destination,
+            },
+        )
+        .await;
+
+        match reply {
+            Ok(Packet::CoreMgmtIoExpanderStatusReply {
+                count,
+                failing,
+                errors,
+                reinits,
+            }) => {
+                let status: Vec<(bool, u32, u32)> = (0..count as usize)
+                    .map(|i| (failing[i], errors[i], reinits[i]))
+                    .collect();
+                write_io_expander_status(stream, &status).await
+            }
+            Ok(packet) => {
+                error!("received unexpected aux packet: {:?}", packet);
+                write_i8(stream, Reply::Error as i8).await?;
+                Err(drtio::Error::UnexpectedReply.into())
+            }
+            Err(e) => {
+                error!("aux packet error ({})", e);
+                write_i8(stream, Reply::Error as i8).await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    pub async fn get_clock_status(stream: &mut TcpStream, linkno: u8, destination: u8) -> Result<()> {
+        let reply = drtio::aux_transact(
+            linkno,
+            &Packet::CoreMgmtClockStatusRequest {
+                destination: destination,
+            },
+        )
+        .await;
+
+        match reply {
+            Ok(Packet::CoreMgmtClockStatusReply {
+                monitored,
+                locked,
+                unlocks,
+                recoveries,
+                holdover_monitored,
+                los,
+                lol,
+                holdover,
+                los_events,
+                lol_events,
+                holdover_events,
+                input_switches,
+            }) => {
+                write_i8(stream, Reply::ClockStatus as i8).await?;
+                write_bool(stream, monitored).await?;
+                write_bool(stream, locked).await?;
+                write_i32(stream, unlocks as i32).await?;
+                write_i32(stream, recoveries as i32).await?;
+                write_bool(stream, holdover_monitored).await?;
+                write_bool(stream, los).await?;
+                write_bool(stream, lol).await?;
+                write_bool(stream, holdover).await?;
+                write_i32(stream, los_events as i32).await?;
+                write_i32(stream, lol_events as i32).await?;
+                write_i32(stream, holdover_events as i32).await?;
+                write_i32(stream, input_switches as i32).await?;
+                Ok(())
+            }
+            Ok(packet) => {
+                error!("received unexpected aux packet: {:?}", packet);
+                write_i8(stream, Reply::Error as i8).await?;
+                Err(drtio::Error::UnexpectedReply.into())
+            }
+            Err(e) => {
+                error!("aux packet error ({})", e);
+                write_i8(stream, Reply::Error as i8).await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    pub async fn set_aux_trace(stream: &mut TcpStream, linkno: u8, destination: u8, enable: bool) -> Result<()> {
+        let reply = drtio::aux_transact(
+            linkno,
+            &Packet::CoreMgmtAuxTraceEnable {
+                destination: destination,
+                enable: enable,
+            },
+        )
+        .await;
+
+        match reply {
+            Ok(Packet::CoreMgmtReply { succeeded: true }) => {
+                write_i8(stream, Reply::Success as i8).await?;
+                Ok(())
+            }
+            Ok(packet) => {
+                error!("received unexpected aux packet: {:?}", packet);
+                write_i8(stream, Reply::Error as i8).await?;
+                Err(drtio::Error::UnexpectedReply.into())
+            }
+            Err(e) => {
+                error!("aux packet error ({})", e);
+                write_i8(stream, Reply::Error as i8).await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    pub async fn get_aux_trace(stream: &mut TcpStream, linkno: u8, destination: u8) -> Result<()> {
+        let mut buffer = Vec::new();
+        let mut reply = drtio::aux_transact(
+            linkno,
+            &Packet::CoreMgmtAuxTraceRequest {
+                destination: destination,
+            },
+        )
+        .await;
+
+        loop {
+            match reply {
+                Ok(Packet::CoreMgmtAuxTraceReply { last, length, data }) => {
+                    buffer.extend(&data[..length as usize]);
+                    if last {
+                        break;
+                    }
+                }
+                Ok(packet) => {
+                    error!("received unexpected aux packet: {:?}", packet);
+                    write_i8(stream, Reply::Error as i8).await?;
+                    return Err(drtio::Error::UnexpectedReply.into());
+                }
+                Err(e) => {
+                    error!("aux packet error ({})", e);
+                    write_i8(stream, Reply::Error as i8).await?;
+                    return Err(e.into());
+                }
+            }
+            reply = drtio::aux_transact(linkno, &Packet::CoreMgmtAuxTraceContinue { destination }).await;
+        }
+
+        let entries: Vec<TraceEntry> = buffer
+            .chunks_exact(AUX_TRACE_ENTRY_SIZE)
+            .map(TraceEntry::from_bytes)
+            .collect();
+        write_aux_trace(stream, &entries).await
+    }
+
+    pub async fn get_aux_captures(stream: &mut TcpStream, linkno: u8, destination: u8) -> Result<()> {
+        let mut captures = Vec::new();
+        let mut index = 0;
+        loop {
+            let reply = drtio::aux_transact(
+                linkno,
+                &Packet::CoreMgmtAuxCaptureRequest {
+                    destination: destination,
+                    index: index,
+                },
+            )
+            .await;
+
+            match reply {
+                Ok(Packet::CoreMgmtAuxCaptureReply {
+                    count,
+                    linkno,
+                    timestamp_ms,
+                    kind,
+                    data,
+                }) => {
+                    if index < count {
+                        captures.push((linkno, timestamp_ms, kind, data));
+                        index += 1;
+                    }
+                    if index >= count {
+                        break;
+                    }
+                }
+                Ok(packet) => {
+                    error!("received unexpected aux packet: {:?}", packet);
+                    write_i8(stream, Reply::Error as i8).await?;
+                    return Err(drtio::Error::UnexpectedReply.into());
+                }
+                Err(e) => {
+                    error!("aux packet error ({})", e);
+                    write_i8(stream, Reply::Error as i8).await?;
+                    return Err(e.into());
+                }
+            }
+        }
+
+        write_i8(stream, Reply::AuxCaptures as i8).await?;
+        write_i32(stream, captures.len() as i32).await?;
+        for (linkno, timestamp_ms, kind, data) in captures {
+            write_i8(stream, linkno as i8).await?;
+            write_i64(stream, timestamp_ms as i64).await?;
+            write_i8(stream, kind as i8).await?;
+            write_chunk(stream, &data).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn get_kernel_stats(stream: &mut TcpStream, _linkno: u8, destination: u8) -> Result<()> {
+        error!("kernel statistics are not supported on satellites (destination {})", destination);
+        write_i8(stream, Reply::Error as i8).await?;
+        Ok(())
+    }
+
+    pub async fn eye_scan(stream: &mut TcpStream, linkno: u8, destination: u8, link: u8, prescale: u8) -> Result<()> {
+        let mut buffer = Vec::new();
+        let mut reply = drtio::aux_transact_timeout(
+            linkno,
+            &Packet::EyeScanRequest {
+                destination: destination,
+                link: link,
+                prescale: prescale,
+            },
+            EYE_SCAN_TIMEOUT,
+        )
+        .await;
+
+        loop {
+            match reply {
+                Ok(Packet::EyeScanReply { last, length, data }) => {
+                    buffer.extend(&data[..length as usize]);
+                    if last {
+                        write_i8(stream, Reply::EyeScanData as i8).await?;
+                        write_chunk(stream, &buffer).await?;
+                        return Ok(());
+                    }
+                }
+                Ok(Packet::CoreMgmtReply { succeeded: false }) => {
+                    error!("eye scan of link {} failed on destination {}", link, destination);
+                    write_i8(stream, Reply::Error as i8).await?;
+                    return Ok(());
+                }
+                Ok(packet) => {
+                    error!("received unexpected aux packet: {:?}", packet);
+                    write_i8(stream, Reply::Error as i8).await?;
+                    return Err(drtio::Error::UnexpectedReply.into());
+                }
+                Err(e) => {
+                    error!("aux packet error ({})", e);
+                    write_i8(stream, Reply::Error as i8).await?;
+                    return Err(e.into());
+                }
+            }
+            reply = drtio::aux_transact(linkno, &Packet::EyeScanContinue { destination }).await;
+        }
+    }
+}
+
+mod local_coremgmt {
+    #[cfg(has_si5324)]
+    use libboard_artiq::si5324;
+    use libboard_zynq::{slcr, timer};
+
+    use super::*;
+
+    pub async fn get_log(stream: &mut TcpStream, with_stats: bool) -> Result<()> {
+        let mut buffer = get_logger_buffer().await;
+        let bytes = buffer.extract().as_bytes().to_vec();
+        let stats = buffer.stats();
+        core::mem::drop(buffer);
+        write_i8(stream, Reply::LogContent as i8).await?;
+        write_chunk(stream, &bytes).await?;
+        if with_stats {
+            write_log_stats(stream, stats.dropped_bytes, stats.dropped_messages).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn clear_log(stream: &mut TcpStream) -> Result<()> {
+        let mut buffer = get_logger_buffer().await;
+        buffer.clear();
+        write_i8(stream, Reply::Success as i8).await?;
+        Ok(())
+    }
+
+    pub async fn pull_log(
+        stream: &mut TcpStream,
+        pull_id: &RefCell<u32>,
+        filter: &LogFilter,
+        pacing: &LogPacing,
+        with_stats: bool,
+    ) -> Result<()> {
+        let id = {
+            let mut guard = pull_id.borrow_mut();
+            *guard += 1;
+            *guard
+        };
+        loop {
+            let mut buffer = get_logger_buffer_pred(|b| !b.is_empty()).await;
+            if id != *pull_id.borrow() {
+                // another connection attempts to pull the log...
+                // abort this connection...
+                return Err(Error::OvertakeError);
+            }
+            let bytes = filter.apply(buffer.extract()).into_bytes();
+            buffer.clear();
+            let stats = buffer.stats();
+            core::mem::drop(buffer);
+            for chunk in pacing.chunks(&bytes) {
+                write_chunk(stream, chunk).await?;
+                if with_stats {
+                    write_log_stats(stream, stats.dropped_bytes, stats.dropped_messages).await?;
+                }
+                pacing.wait(chunk.len()).await;
+            }
+            if BufferLogger::get_logger().buffer_log_level() == log::LevelFilter::Trace {
+                let logger = BufferLogger::get_logger();
+                logger.set_buffer_log_level(log::LevelFilter::Debug);
+                stream.flush().await?;
+                logger.set_buffer_log_level(log::LevelFilter::Trace);
             }
         }
     }
@@ -571,13 +1566,32 @@ mod local_coremgmt {
     }
 
     pub async fn config_write(stream: &mut TcpStream, key: &String, value: Vec<u8>) -> Result<()> {
-        let res = libconfig::write(&key, value.clone());
+        if storage_health::read_only() {
+            error!("config storage is read-only, not writing {}", key);
+            write_i8(stream, Reply::Error as i8).await?;
+            return Ok(());
+        }
+        if key == "idle_kernel" {
+            // the running idle kernel is only replaced by one that can be loaded
+            if let Err(e) = idle_kernel::verify(&value) {
+                error!("new idle kernel cannot be loaded, keeping the current one: {}", e);
+                write_i8(stream, Reply::Error as i8).await?;
+                return Ok(());
+            }
+        }
+        let res = config_store::write(&key, value.clone());
         if res.is_ok() {
             debug!("write success");
             match key.as_str() {
                 "idle_kernel" => {
-                    RESTART_IDLE.signal();
+                    if idle_kernel::is_loaded(&value) {
+                        info!("idle kernel unchanged, not restarting it");
+                    } else {
+                        RESTART_IDLE.signal();
+                    }
                 }
+                "routes" => net_routes::reload(),
+                "device_map" => libboard_artiq::reload_device_map(),
                 "log_level" | "uart_log_level" => {
                     let value_str = core::str::from_utf8(&value).map_err(Error::from)?;
                     let max_level = value_str
@@ -605,11 +1619,19 @@ mod local_coremgmt {
 
     pub async fn config_remove(stream: &mut TcpStream, key: &String) -> Result<()> {
         debug!("erase key: {}", key);
-        let value = libconfig::remove(&key);
+        if storage_health::read_only() {
+            error!("config storage is read-only, not removing {}", key);
+            write_i8(stream, Reply::Error as i8).await?;
+            return Ok(());
+        }
+        let value = config_store::remove(&key);
         if value.is_ok() {
             debug!("erase success");
-            if key == "idle_kernel" {
-                RESTART_IDLE.signal();
+            match key.as_str() {
+                "idle_kernel" => RESTART_IDLE.signal(),
+                "routes" => net_routes::reload(),
+                "device_map" => libboard_artiq::reload_device_map(),
+                _ => {}
             }
             write_i8(stream, Reply::Success as i8).await?;
         } else {
@@ -619,51 +1641,273 @@ mod local_coremgmt {
         Ok(())
     }
 
-    pub async fn config_erase(stream: &mut TcpStream) -> Result<()> {
-        error!("zynq device does not support config erase");
-        write_i8(stream, Reply::Error as i8).await?;
+    pub async fn config_backup(stream: &mut TcpStream) -> Result<()> {
+        let keys = match libconfig::keys() {
+            Ok(keys) => keys,
+            Err(e) => {
+                error!("failed to list config keys: {:?}", e);
+                write_i8(stream, Reply::Error as i8).await?;
+                return Ok(());
+            }
+        };
+        let entries: Vec<(&str, Vec<u8>)> = keys
+            .iter()
+            .filter(|key| config_archive::backed_up(key))
+            .filter_map(|key| libconfig::read(key).ok().map(|value| (key.as_str(), value)))
+            .collect();
+        info!("backed up {} config keys", entries.len());
+        write_i8(stream, Reply::ConfigArchive as i8).await?;
+        write_chunk(stream, &config_archive::build(&entries)).await?;
+        Ok(())
+    }
+
+    // The keys are written as they are, and most of them take effect at the next boot.
+    pub async fn config_restore(stream: &mut TcpStream, archive: Vec<u8>) -> Result<()> {
+        if storage_health::read_only() {
+            error!("config storage is read-only, not restoring the backup");
+            write_i8(stream, Reply::Error as i8).await?;
+            return Ok(());
+        }
+        let entries = config_archive::entries(&archive);
+        if let Some((key, _)) = entries.iter().find(|(key, _)| !config_archive::restorable(key)) {
+            error!("config key {} cannot be restored, not restoring the backup", key);
+            write_i8(stream, Reply::Error as i8).await?;
+            return Ok(());
+        }
+        for (key, value) in entries {
+            if let Err(e) = config_store::write(&key, value) {
+                error!("failed to restore config key {}: {:?}", key, e);
+                write_i8(stream, Reply::Error as i8).await?;
+                return Ok(());
+            }
+        }
+        net_routes::reload();
+        libboard_artiq::reload_device_map();
+        info!("config restored, reboot to apply");
+        write_i8(stream, Reply::Success as i8).await?;
+        Ok(())
+    }
+
+    pub async fn config_erase(stream: &mut TcpStream) -> Result<()> {
+        error!("zynq device does not support config erase");
+        write_i8(stream, Reply::Error as i8).await?;
+        Ok(())
+    }
+
+    pub async fn reboot(stream: &mut TcpStream) -> Result<()> {
+        info!("rebooting");
+        log::logger().flush();
+        write_i8(stream, Reply::RebootImminent as i8).await?;
+        stream.flush().await?;
+        slcr::reboot();
+
+        unreachable!()
+    }
+
+    pub async fn debug_allocator(_stream: &mut TcpStream) -> Result<()> {
+        error!("zynq device does not support allocator debug print");
+        Ok(())
+    }
+
+    pub async fn image_write(
+        stream: &mut TcpStream,
+        length: usize,
+        report_progress: bool,
+        verify_only: bool,
+    ) -> Result<()> {
+        let mut image = read_image(stream, length, report_progress).await?;
+
+        if !image_crc_ok(&image) {
+            write_i8(stream, Reply::Error as i8).await?;
+        } else if verify_only {
+            info!("CRC passed for {} byte boot image, not writing (verify only)", image.len() - 4);
+            write_i8(stream, Reply::Success as i8).await?;
+        } else {
+            info!("CRC passed. Writing boot image to SD card...");
+            image.truncate(image.len() - 4);
+            if boot_slot::install(image).is_ok() {
+                reboot(stream).await?;
+            } else {
+                write_i8(stream, Reply::Error as i8).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn set_sed_spread(stream: &mut TcpStream, enable: bool) -> Result<()> {
+        let value: &[u8] = if enable { b"1" } else { b"0" };
+        if storage_health::read_only() {
+            // still apply it, it just does not survive a reboot
+            warn!("config storage is read-only, SED spreading setting not persisted");
+            rtio_mgt::toggle_sed_spread(enable as u8);
+            write_i8(stream, Reply::Success as i8).await?;
+            return Ok(());
+        }
+        match config_store::write("sed_spread_enable", value.to_vec()) {
+            Ok(()) => {
+                rtio_mgt::toggle_sed_spread(enable as u8);
+                info!("SED spreading {}", if enable { "enabled" } else { "disabled" });
+                write_i8(stream, Reply::Success as i8).await?;
+            }
+            Err(err) => {
+                error!("failed to write sed_spread_enable: {:?}", err);
+                write_i8(stream, Reply::Error as i8).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn cri_con_status(stream: &mut TcpStream, _reset: bool) -> Result<()> {
+        // the master CRI is only handed over for the duration of a DMA playback syscall
+        error!("CRI arbitration status is only available on satellites");
+        write_i8(stream, Reply::Error as i8).await?;
+        Ok(())
+    }
+
+    pub async fn get_clock_status(stream: &mut TcpStream) -> Result<()> {
+        // the recovered clock is only monitored on satellites, the Si5324 is polled here
+        // for the latest flags, besides its periodic poll
+        #[cfg(has_si5324)]
+        match si5324::poll(libboard_artiq::i2c::get_bus()) {
+            Ok(status) => {
+                write_i8(stream, Reply::ClockStatus as i8).await?;
+                write_bool(stream, false).await?;
+                write_bool(stream, false).await?;
+                write_i32(stream, 0).await?;
+                write_i32(stream, 0).await?;
+                write_bool(stream, status.monitored).await?;
+                write_bool(stream, status.los).await?;
+                write_bool(stream, status.lol).await?;
+                write_bool(stream, status.holdover).await?;
+                write_i32(stream, status.los_events as i32).await?;
+                write_i32(stream, status.lol_events as i32).await?;
+                write_i32(stream, status.holdover_events as i32).await?;
+                write_i32(stream, status.input_switches as i32).await?;
+            }
+            Err(e) => {
+                error!("failed to poll Si5324 status: {}", e);
+                write_i8(stream, Reply::Error as i8).await?;
+            }
+        }
+        #[cfg(not(has_si5324))]
+        {
+            error!("clock status is not available on this device");
+            write_i8(stream, Reply::Error as i8).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn get_io_expander_status(stream: &mut TcpStream) -> Result<()> {
+        #[cfg(feature = "target_kasli_soc")]
+        let status: Vec<(bool, u32, u32)> = (0..2)
+            .map(|index| {
+                let health = libboard_artiq::io_expander::health(index);
+                (health.failing, health.errors, health.reinits)
+            })
+            .collect();
+        #[cfg(not(feature = "target_kasli_soc"))]
+        let status = Vec::new();
+        write_io_expander_status(stream, &status).await
+    }
+
+    pub async fn get_tsc_drift(stream: &mut TcpStream) -> Result<()> {
+        // the master counter is the reference the satellites are measured against
+        write_i8(stream, Reply::TscDrift as i8).await?;
+        write_i64(stream, 0).await?;
+        write_i32(stream, 0).await?;
         Ok(())
     }
 
-    pub async fn reboot(stream: &mut TcpStream) -> Result<()> {
-        info!("rebooting");
-        log::logger().flush();
-        write_i8(stream, Reply::RebootImminent as i8).await?;
-        stream.flush().await?;
-        slcr::reboot();
+    pub async fn get_last_panic(stream: &mut TcpStream) -> Result<()> {
+        // empty if no panic has been recorded
+        let report = libconfig::read(panic_report::LAST_PANIC_KEY).unwrap_or_default();
+        write_i8(stream, Reply::LastPanic as i8).await?;
+        write_chunk(stream, &report).await?;
+        Ok(())
+    }
 
-        unreachable!()
+    pub async fn set_aux_trace(stream: &mut TcpStream, enable: bool) -> Result<()> {
+        #[cfg(has_drtio)]
+        libboard_artiq::drtioaux::set_tracing(enable);
+        info!("aux packet tracing {}", if enable { "enabled" } else { "disabled" });
+        write_i8(stream, Reply::Success as i8).await?;
+        Ok(())
     }
 
-    pub async fn debug_allocator(_stream: &mut TcpStream) -> Result<()> {
-        error!("zynq device does not support allocator debug print");
+    pub async fn get_aux_trace(stream: &mut TcpStream) -> Result<()> {
+        #[cfg(has_drtio)]
+        write_aux_trace(stream, &libboard_artiq::drtioaux::trace()).await?;
+        // no aux links to trace
+        #[cfg(not(has_drtio))]
+        {
+            write_i8(stream, Reply::AuxTrace as i8).await?;
+            write_i32(stream, 0).await?;
+        }
         Ok(())
     }
 
-    pub async fn image_write(stream: &mut TcpStream, image: Vec<u8>) -> Result<()> {
-        let mut image = image.clone();
-        let image_ref = &image[..];
-        let bin_len = image.len() - 4;
+    pub async fn get_aux_captures(stream: &mut TcpStream) -> Result<()> {
+        #[cfg(has_drtio)]
+        let captures = libboard_artiq::drtioaux::captures();
+        write_i8(stream, Reply::AuxCaptures as i8).await?;
+        #[cfg(has_drtio)]
+        {
+            write_i32(stream, captures.len() as i32).await?;
+            for capture in captures {
+                write_i8(stream, capture.linkno as i8).await?;
+                write_i64(stream, capture.timestamp_ms as i64).await?;
+                write_i8(stream, capture.kind as i8).await?;
+                write_chunk(stream, &capture.data).await?;
+            }
+        }
+        #[cfg(not(has_drtio))]
+        write_i32(stream, 0).await?;
+        Ok(())
+    }
 
-        let (image_ref, expected_crc) = {
-            let (image_ref, crc_slice) = image_ref.split_at(bin_len);
-            (image_ref, NativeEndian::read_u32(crc_slice))
-        };
+    pub async fn kernel_cache_status(stream: &mut TcpStream, clear: bool) -> Result<()> {
+        let mut cache = CACHE_STORE.lock();
+        let entries = cache.len();
+        let size: usize = cache.values().map(|value| value.len() * 4).sum();
+        if clear {
+            cache.clear();
+            info!("kernel cache cleared");
+        }
+        drop(cache);
+        // the master cache has no quota and never evicts
+        write_i8(stream, Reply::KernelCacheStatus as i8).await?;
+        write_i32(stream, entries as i32).await?;
+        write_i32(stream, size as i32).await?;
+        write_i32(stream, 0).await?;
+        write_i32(stream, 0).await?;
+        write_i32(stream, 0).await?;
+        Ok(())
+    }
 
-        let actual_crc = crc32::checksum_ieee(image_ref);
+    pub async fn get_kernel_stats(stream: &mut TcpStream) -> Result<()> {
+        let stats = *KERNEL_STATS.lock();
+        write_i8(stream, Reply::KernelStats as i8).await?;
+        write_i64(stream, timer::get_ms() as i64).await?;
+        write_i32(stream, stats.kernels_run as i32).await?;
+        write_i64(stream, stats.total_duration_ms as i64).await?;
+        write_i64(stream, stats.last_duration_ms as i64).await?;
+        write_i32(stream, stats.rpc_count as i32).await?;
+        write_i64(stream, stats.rpc_total_ms as i64).await?;
+        write_i64(stream, stats.rpc_max_ms as i64).await?;
+        write_i32(stream, stats.dma_playbacks as i32).await?;
+        Ok(())
+    }
 
-        if actual_crc == expected_crc {
-            info!("CRC passed. Writing boot image to SD card...");
-            image.truncate(bin_len);
-            libconfig::write("boot", image).expect("failed to write boot image");
-            reboot(stream).await?;
-        } else {
-            error!(
-                "CRC failed, images have not been written to flash.\n(actual {:08x}, expected {:08x})",
-                actual_crc, expected_crc
-            );
-            write_i8(stream, Reply::Error as i8).await?;
+    pub async fn eye_scan(stream: &mut TcpStream, link: u8, prescale: u8) -> Result<()> {
+        #[cfg(has_drtio_eyescan)]
+        if let Ok(result) = eye_scan::scan(link, prescale) {
+            write_i8(stream, Reply::EyeScanData as i8).await?;
+            write_chunk(stream, &result).await?;
+            return Ok(());
         }
+        #[cfg(not(has_drtio_eyescan))]
+        warn!("eye scan of link {} (prescale {}) not supported by the gateware", link, prescale);
+        write_i8(stream, Reply::Error as i8).await?;
         Ok(())
     }
 }
@@ -688,15 +1932,817 @@ macro_rules! process {
     }}
 }
 
+fn is_local(_destination: u8) -> bool {
+    #[cfg(has_drtio)]
+    {
+        ROUTING_TABLE.get().unwrap().0[_destination as usize][0] == 0
+    }
+    #[cfg(not(has_drtio))]
+    true
+}
+
+// Boot slots are kept by the master only, satellites are flashed with a single image.
+async fn get_boot_slot(stream: &mut TcpStream, destination: u8) -> Result<()> {
+    if !is_local(destination) {
+        error!("boot slots are not supported on satellites (destination {})", destination);
+        write_i8(stream, Reply::Error as i8).await?;
+        return Ok(());
+    }
+    write_i8(stream, Reply::BootSlot as i8).await?;
+    write_i8(stream, boot_slot::active() as i8).await?;
+    write_bool(stream, boot_slot::is_trial()).await?;
+    Ok(())
+}
+
+async fn select_boot_slot(stream: &mut TcpStream, destination: u8, slot: u8) -> Result<()> {
+    if !is_local(destination) {
+        error!("boot slots are not supported on satellites (destination {})", destination);
+        write_i8(stream, Reply::Error as i8).await?;
+        return Ok(());
+    }
+    match boot_slot::Slot::from_u8(slot).map(boot_slot::select) {
+        Some(Ok(())) => local_coremgmt::reboot(stream).await,
+        Some(Err(())) => {
+            write_i8(stream, Reply::Error as i8).await?;
+            Ok(())
+        }
+        None => {
+            error!("invalid boot slot: {}", slot);
+            write_i8(stream, Reply::Error as i8).await?;
+            Ok(())
+        }
+    }
+}
+
+// Reports the outcome of the aux protocol version handshake for each DRTIO link:
+// state (0: link down, 1: compatible, 2: incompatible), remote version and features.
+async fn get_link_status(stream: &mut TcpStream) -> Result<()> {
+    write_i8(stream, Reply::LinkStatus as i8).await?;
+    #[cfg(has_drtio)]
+    {
+        let links = *drtio::LINK_COMPAT.lock();
+        write_i8(stream, links.len() as i8).await?;
+        for link in links.iter() {
+            let (state, version, features) = match *link {
+                drtio::LinkCompat::Unknown => (0, 0, 0),
+                drtio::LinkCompat::Compatible { version, features } => (1, version, features),
+                drtio::LinkCompat::Incompatible { version } => (2, version, 0),
+            };
+            write_i8(stream, state).await?;
+            write_i32(stream, version as i32).await?;
+            write_i32(stream, features as i32).await?;
+        }
+    }
+    #[cfg(not(has_drtio))]
+    write_i8(stream, 0).await?;
+    Ok(())
+}
+
+// Reports the state of the config storage: read-only flag, health checks run,
+// failed checks in total and in a row.
+async fn get_storage_health(stream: &mut TcpStream) -> Result<()> {
+    let status = storage_health::status();
+    write_i8(stream, Reply::StorageHealth as i8).await?;
+    write_bool(stream, status.read_only).await?;
+    write_i32(stream, status.checks as i32).await?;
+    write_i32(stream, status.failures as i32).await?;
+    write_i32(stream, status.consecutive_failures as i32).await?;
+    Ok(())
+}
+
+// Reports whether the startup self test has completed and, if so, when (ms since boot)
+// and the name, result and failure reason of each check.
+async fn get_self_test_report(stream: &mut TcpStream) -> Result<()> {
+    write_i8(stream, Reply::SelfTestReport as i8).await?;
+    match selftest::report() {
+        Some(report) => {
+            write_bool(stream, true).await?;
+            write_i64(stream, report.finished_ms as i64).await?;
+            write_i8(stream, report.outcomes.len() as i8).await?;
+            for outcome in report.outcomes.iter() {
+                write_chunk(stream, outcome.name.as_bytes()).await?;
+                write_bool(stream, outcome.passed).await?;
+                write_chunk(stream, outcome.detail.as_bytes()).await?;
+            }
+        }
+        None => write_bool(stream, false).await?,
+    }
+    Ok(())
+}
+
+// Retrains an EEM transceiver and reports its wordslip setting and, for each lane,
+// the selected delay tap, the deviation of the phase detector from 50% at that tap
+// (in thousandths) and the width of the transition in taps.
+async fn retrain_eem(stream: &mut TcpStream, trx_no: u8) -> Result<()> {
+    #[cfg(has_drtio_eem)]
+    match drtio_eem::retrain(trx_no).await {
+        Ok(training) => {
+            drtio_eem::store_unsaved();
+            write_i8(stream, Reply::EemTraining as i8).await?;
+            write_i8(stream, training.wordslip as i8).await?;
+            write_i8(stream, training.lanes.len() as i8).await?;
+            for lane in training.lanes.iter() {
+                write_i8(stream, lane.delay as i8).await?;
+                write_i32(stream, lane.deviation as i32).await?;
+                write_i8(stream, lane.transition_width as i8).await?;
+            }
+            return Ok(());
+        }
+        Err(e) => error!("retraining EEM transceiver {} failed: {}", trx_no, e),
+    }
+    #[cfg(not(has_drtio_eem))]
+    warn!("retraining of EEM transceiver {} not supported by the gateware", trx_no);
+    write_i8(stream, Reply::Error as i8).await?;
+    Ok(())
+}
+
+// Runs a PRBS test on an EEM transceiver (see drtio_eem::prbs_test) and reports,
+// for each lane, whether the checker locked, the bits compared and the bit errors.
+async fn eem_prbs_test(stream: &mut TcpStream, trx_no: u8, near_end: bool, duration_ms: u64) -> Result<()> {
+    #[cfg(all(has_drtio_eem, has_eem_prbs))]
+    match drtio_eem::prbs_test(trx_no, near_end, duration_ms).await {
+        Ok(lanes) => {
+            write_i8(stream, Reply::EemPrbsResult as i8).await?;
+            write_i8(stream, lanes.len() as i8).await?;
+            for lane in lanes.iter() {
+                write_bool(stream, lane.locked).await?;
+                write_i64(stream, lane.bits as i64).await?;
+                write_i64(stream, lane.errors as i64).await?;
+            }
+            return Ok(());
+        }
+        Err(e) => error!("PRBS test of EEM transceiver {} failed: {}", trx_no, e),
+    }
+    #[cfg(not(all(has_drtio_eem, has_eem_prbs)))]
+    warn!(
+        "PRBS test of EEM transceiver {} ({} ms, near end: {}) not supported by the gateware",
+        trx_no, duration_ms, near_end
+    );
+    write_i8(stream, Reply::Error as i8).await?;
+    Ok(())
+}
+
+// Enables or disables a DRTIO link of the master until the next reboot; the
+// `drtio_disabled_links` config key sets the links disabled at boot.
+async fn set_link_enabled(stream: &mut TcpStream, linkno: u8, enabled: bool) -> Result<()> {
+    #[cfg(has_drtio)]
+    match drtio::set_link_enabled(linkno, enabled) {
+        Ok(()) => {
+            write_i8(stream, Reply::Success as i8).await?;
+            return Ok(());
+        }
+        Err(e) => error!("cannot set link {} enabled: {}", linkno, e),
+    }
+    #[cfg(not(has_drtio))]
+    warn!("cannot set link {} enabled to {}: DRTIO not supported", linkno, enabled);
+    write_i8(stream, Reply::Error as i8).await?;
+    Ok(())
+}
+
+// Reads the device map from the config again, e.g. after it was written directly to the SD card;
+// writes and removals of the key through the management port reload it already.
+async fn reload_device_map(stream: &mut TcpStream) -> Result<()> {
+    libboard_artiq::reload_device_map();
+    write_i8(stream, Reply::Success as i8).await?;
+    Ok(())
+}
+
+// Sets the wall clock returned to kernels, in microseconds since the Unix epoch;
+// satellites are sent the new setting by the DRTIO link task.
+async fn set_wall_clock(stream: &mut TcpStream, time_us: u64) -> Result<()> {
+    wall_clock::set(time_us);
+    info!("wall clock set to {} us", time_us);
+    write_i8(stream, Reply::Success as i8).await?;
+    Ok(())
+}
+
+// Reports the layout version, then the aux round trip latency of each destination that is
+// up, measured every second: destination, sample count, then the last, minimum, average and maximum
+// round trip in RTIO counter units, followed by the packets it forwarded downstream,
+// queued now and at most, and dropped on a full queue or once too old. Then, for each of its
+// repeaters: the link state (0 down, 1 pinging, 2 up, 3 failed), the times the link came up
+// and went down, aux send failures and forward timeouts, and the last transitions as
+// (state entered, ms ago), newest first.
+async fn get_drtio_stats(stream: &mut TcpStream) -> Result<()> {
+    write_i8(stream, Reply::DrtioStats as i8).await?;
+    write_i8(stream, DRTIO_STATS_VERSION).await?;
+    #[cfg(has_drtio)]
+    {
+        let stats = drtio::latency_stats();
+        write_i8(stream, stats.len() as i8).await?;
+        for (destination, latency) in stats {
+            write_i8(stream, destination as i8).await?;
+            write_i32(stream, latency.samples as i32).await?;
+            write_i64(stream, latency.last as i64).await?;
+            write_i64(stream, latency.min as i64).await?;
+            write_i64(stream, latency.average() as i64).await?;
+            write_i64(stream, latency.max as i64).await?;
+            let router = drtio::router_stats(destination);
+            write_i32(stream, router.forwarded as i32).await?;
+            write_i32(stream, router.queued as i32).await?;
+            write_i32(stream, router.max_queued as i32).await?;
+            write_i32(stream, router.dropped_full as i32).await?;
+            write_i32(stream, router.dropped_aged as i32).await?;
+            let repeaters = drtio::repeater_stats(destination);
+            write_i8(stream, repeaters.len() as i8).await?;
+            for repeater in repeaters {
+                write_i8(stream, repeater.state as i8).await?;
+                write_i32(stream, repeater.up_transitions as i32).await?;
+                write_i32(stream, repeater.down_transitions as i32).await?;
+                write_i32(stream, repeater.aux_send_failures as i32).await?;
+                write_i32(stream, repeater.forward_timeouts as i32).await?;
+                write_i8(stream, repeater.history.len() as i8).await?;
+                for (state, age_ms) in repeater.history {
+                    write_i8(stream, state as i8).await?;
+                    write_i32(stream, age_ms as i32).await?;
+                }
+            }
+        }
+    }
+    #[cfg(not(has_drtio))]
+    write_i8(stream, 0).await?;
+    Ok(())
+}
+
+// Reports the uptime, for each TCP service its port, whether it is listening, open and
+// closing connections, connections accepted and connections that ended on an error, then
+// the frame counters of the Ethernet interface.
+async fn get_net_stats(stream: &mut TcpStream) -> Result<()> {
+    write_i8(stream, Reply::NetStats as i8).await?;
+    write_i64(stream, timer::get_ms() as i64).await?;
+    write_i8(stream, net_stats::SERVICES.len() as i8).await?;
+    for service in net_stats::SERVICES.iter() {
+        let stats = net_stats::stats(*service);
+        write_i32(stream, stats.port as i32).await?;
+        write_bool(stream, stats.listening).await?;
+        write_i32(stream, stats.active as i32).await?;
+        write_i32(stream, stats.closing as i32).await?;
+        write_i32(stream, stats.accepted as i32).await?;
+        write_i32(stream, stats.dropped as i32).await?;
+    }
+    let interface = net_stats::interface();
+    write_i32(stream, interface.rx_frames as i32).await?;
+    write_i32(stream, interface.rx_bytes as i32).await?;
+    write_i32(stream, interface.tx_frames as i32).await?;
+    write_i32(stream, interface.tx_bytes as i32).await?;
+    write_i32(stream, interface.tx_exhausted as i32).await?;
+    Ok(())
+}
+
+// Sends the state of core 1 saved at the last kernel fault (see ksupport::kernel::core_dump),
+// empty if no kernel has faulted since boot.
+async fn get_core_dump(stream: &mut TcpStream) -> Result<()> {
+    let dump = ksupport::kernel::core_dump::get().unwrap_or_default();
+    write_i8(stream, Reply::CoreDump as i8).await?;
+    write_chunk(stream, &dump).await?;
+    Ok(())
+}
+
+// Starts sampling the kernels run from now on every interval_us microseconds, clearing
+// the previous profile, or stops sampling (see ksupport::kernel::profiler).
+async fn set_profiler(stream: &mut TcpStream, enabled: bool, interval_us: u32) -> Result<()> {
+    if enabled {
+        if let Err(e) = ksupport::kernel::profiler::start(interval_us) {
+            error!("cannot start kernel profiler: {}", e);
+            write_i8(stream, Reply::Error as i8).await?;
+            return Ok(());
+        }
+        info!("kernel profiler started, sampling every {} us", interval_us);
+    } else {
+        ksupport::kernel::profiler::stop();
+        info!("kernel profiler stopped");
+    }
+    write_i8(stream, Reply::Success as i8).await?;
+    Ok(())
+}
+
+async fn get_profile(stream: &mut TcpStream) -> Result<()> {
+    let profile = ksupport::kernel::profiler::get();
+    write_i8(stream, Reply::Profile as i8).await?;
+    write_chunk(stream, &profile).await?;
+    Ok(())
+}
+
+// Turns arming the analyzers for each kernel run on or off, until the next reboot
+// (the boot default is the `analyzer_auto_arm` config key).
+async fn set_analyzer_auto_arm(stream: &mut TcpStream, enabled: bool) -> Result<()> {
+    analyzer::set_auto_arm(enabled);
+    info!("analyzer auto-arm {}", if enabled { "enabled" } else { "disabled" });
+    write_i8(stream, Reply::Success as i8).await?;
+    Ok(())
+}
+
+// Reports whether auto-arm is on, the kernel run of the captured session (0 if none)
+// and whether that kernel has finished.
+async fn get_analyzer_session(stream: &mut TcpStream) -> Result<()> {
+    let (run, finished) = analyzer::session().unwrap_or((0, false));
+    write_i8(stream, Reply::AnalyzerSession as i8).await?;
+    write_bool(stream, analyzer::auto_arm()).await?;
+    write_i32(stream, run as i32).await?;
+    write_bool(stream, finished).await?;
+    Ok(())
+}
+
+// how often subscribers are sent the new events
+const EVENT_POLL_INTERVAL: u64 = 100;
+
+// Turns the connection into a stream of events: every event still in the history
+// is sent first, then new ones as they happen, until the client disconnects.
+// Each is sent as its sequence number, timestamp (ms since boot), kind and two arguments.
+// Anything the client sends is ignored; reading it is how a disconnect is noticed.
+async fn subscribe_events(stream: &mut TcpStream) -> Result<()> {
+    if !services::enabled(services::Service::Events) {
+        error!("the events service is disabled");
+        write_i8(stream, Reply::Error as i8).await?;
+        return Ok(());
+    }
+    let mut sequence = 0;
+    loop {
+        let (records, next) = events::since(sequence);
+        for record in records.iter() {
+            let (kind, arg0, arg1) = record.event.encode();
+            write_i8(stream, Reply::Event as i8).await?;
+            write_i32(stream, record.sequence as i32).await?;
+            write_i64(stream, record.timestamp_ms as i64).await?;
+            write_i8(stream, kind).await?;
+            write_i32(stream, arg0).await?;
+            write_i32(stream, arg1).await?;
+        }
+        if !records.is_empty() {
+            stream.flush().await?;
+        }
+        sequence = next;
+
+        let read_f = read_i8(stream).fuse();
+        let timeout_f = timer::async_delay_ms(EVENT_POLL_INTERVAL).fuse();
+        pin_mut!(read_f, timeout_f);
+        select_biased! {
+            byte = read_f => { byte?; },
+            _ = timeout_f => (),
+        }
+    }
+}
+
+// Reports each optional service by name, and whether it is enabled.
+async fn get_services(stream: &mut TcpStream) -> Result<()> {
+    write_i8(stream, Reply::Services as i8).await?;
+    write_i8(stream, services::SERVICES.len() as i8).await?;
+    for service in services::SERVICES.iter() {
+        write_chunk(stream, service.name().as_bytes()).await?;
+        write_bool(stream, services::enabled(*service)).await?;
+    }
+    Ok(())
+}
+
+// how often the console output is forwarded to a remote console
+const CONSOLE_POLL_INTERVAL: u64 = 50;
+
+// Bridges the UART console over the connection until the client disconnects.
+// Output is sent from the start of what is still buffered, each time as the number
+// of bytes lost to wraparound since the previous one, followed by the new output.
+// The client types into the console by sending up to 127 bytes at a time, preceded by
+// their count, which are queued as console input as they are.
+async fn console_bridge(stream: &mut TcpStream) -> Result<()> {
+    if !services::enabled(services::Service::Console) {
+        error!("the console service is disabled");
+        write_i8(stream, Reply::Error as i8).await?;
+        return Ok(());
+    }
+    write_i8(stream, Reply::Success as i8).await?;
+    let mut position = 0;
+    let mut output = Vec::new();
+    loop {
+        let (next, lost) = console::read_since(position, &mut output);
+        if !output.is_empty() {
+            write_i8(stream, Reply::ConsoleOutput as i8).await?;
+            write_i64(stream, lost as i64).await?;
+            write_chunk(stream, &output).await?;
+            stream.flush().await?;
+            output.clear();
+        }
+        position = next;
+
+        let read_f = read_i8(stream).fuse();
+        let timeout_f = timer::async_delay_ms(CONSOLE_POLL_INTERVAL).fuse();
+        pin_mut!(read_f, timeout_f);
+        let length = select_biased! {
+            length = read_f => Some(length?),
+            _ = timeout_f => None,
+        };
+        if let Some(length) = length {
+            let mut input = vec![0; length.max(0) as usize];
+            read_chunk(stream, &mut input).await?;
+            console::inject(&input);
+        }
+    }
+}
+
+// Reports whether the idle kernel was disabled after failing repeatedly,
+// its failures in total and in a row.
+async fn get_idle_kernel_status(stream: &mut TcpStream) -> Result<()> {
+    let status = idle_kernel::status();
+    write_i8(stream, Reply::IdleKernelStatus as i8).await?;
+    write_bool(stream, status.disabled).await?;
+    write_i32(stream, status.failures as i32).await?;
+    write_i32(stream, status.consecutive_failures as i32).await?;
+    Ok(())
+}
+
+async fn rearm_idle_kernel(stream: &mut TcpStream) -> Result<()> {
+    idle_kernel::rearm();
+    RESTART_IDLE.signal();
+    write_i8(stream, Reply::Success as i8).await?;
+    Ok(())
+}
+
+// Starts a kernel stored under a "kernels/" config key on the core device, without a host.
+// The reply only tells whether it was started, its output goes to the log.
+async fn run_stored_kernel(stream: &mut TcpStream, key: String) -> Result<()> {
+    match comms::request_stored_kernel(key) {
+        Ok(()) => write_i8(stream, Reply::Success as i8).await?,
+        Err(e) => {
+            warn!("cannot run stored kernel: {}", e);
+            write_i8(stream, Reply::Error as i8).await?;
+        }
+    }
+    Ok(())
+}
+
+// Runs a kernel ELF sent over mgmt without a host, for test stations that cannot reach the
+// coredev port. Its RPCs raise exceptions. The reply tells whether it was loaded and ran,
+// then the console output since it was requested and how much of it was lost to wraparound.
+async fn load_kernel_via_mgmt(stream: &mut TcpStream) -> Result<()> {
+    let length = read_i32(stream).await? as u32 as usize;
+    let max_length = comms::kernel_max_size();
+    let mut buffer = Vec::new();
+    if length > max_length || buffer.try_reserve_exact(length).is_err() {
+        error!("kernel image of {} bytes refused (kernel_max_size is {})", length, max_length);
+        write_i8(stream, Reply::Error as i8).await?;
+        return Err(Error::UnexpectedPattern);
+    }
+    buffer.resize(length, 0);
+    read_chunk(stream, &mut buffer).await?;
+
+    let position = console::position();
+    let outcome = comms::run_mgmt_kernel(buffer).await;
+    if let Err(e) = &outcome {
+        warn!("kernel sent over mgmt failed: {}", e);
+    }
+    let mut output = Vec::new();
+    let (_, lost) = console::read_since(position, &mut output);
+    write_i8(stream, Reply::KernelOutput as i8).await?;
+    write_bool(stream, outcome.is_ok()).await?;
+    write_i64(stream, lost as i64).await?;
+    write_chunk(stream, &output).await?;
+    Ok(())
+}
+
+// A liveness probe: replies with the echo requests received since boot, the time the event
+// loop took to come back to this connection, the time since the network stack was last polled
+// and the longest time between two polls since the previous ping, the latter in microseconds.
+// A long gap between polls tells of a task that held the loop, even once it has recovered.
+async fn answer_ping(stream: &mut TcpStream) -> Result<()> {
+    let start = timer::get_us();
+    task::r#yield().await;
+    let round_us = timer::get_us() - start;
+    let (since_poll_us, max_gap_us) = ping::loop_timing();
+    write_i8(stream, Reply::Pong as i8).await?;
+    write_i32(stream, ping::echo_requests() as i32).await?;
+    write_i64(stream, round_us as i64).await?;
+    write_i64(stream, since_poll_us as i64).await?;
+    write_i64(stream, max_gap_us as i64).await?;
+    Ok(())
+}
+
+async fn release_device_lock(stream: &mut TcpStream) -> Result<()> {
+    match comms::release_device() {
+        Some(owner) => warn!("device lock held by \"{}\" released over mgmt", owner),
+        None => info!("device was not locked"),
+    }
+    write_i8(stream, Reply::Success as i8).await?;
+    Ok(())
+}
+
+// Reports the effective aux transaction timeouts, as the name of each operation class
+// followed by its timeout in milliseconds.
+async fn get_aux_timeouts(stream: &mut TcpStream) -> Result<()> {
+    write_i8(stream, Reply::AuxTimeouts as i8).await?;
+    #[cfg(has_drtio)]
+    {
+        write_i8(stream, aux_timeout::CLASSES.len() as i8).await?;
+        for class in aux_timeout::CLASSES.iter() {
+            write_chunk(stream, class.name().as_bytes()).await?;
+            write_i32(stream, aux_timeout::get(*class) as i32).await?;
+        }
+    }
+    #[cfg(not(has_drtio))]
+    write_i8(stream, 0).await?;
+    Ok(())
+}
+
+// Updates the boot image of several destinations at once. Satellites that are not
+// directly connected to the master receive the image from their upstream satellite.
+// Links are served by one task each, so the transfers over different links overlap.
+async fn image_fan_out(stream: &mut TcpStream, destinations: &[u8], length: usize) -> Result<()> {
+    let image = read_image(stream, length, false).await?;
+    if !image_crc_ok(&image) {
+        write_i8(stream, Reply::Error as i8).await?;
+        return Ok(());
+    }
+
+    #[cfg(has_drtio)]
+    let (image, local, succeeded) = {
+        let routing_table = ROUTING_TABLE.get().unwrap();
+        let image = Rc::new(image);
+        let succeeded = Rc::new(RefCell::new([0; DESTINATION_MASK_SIZE]));
+        let done = Rc::new(Semaphore::new(0, csr::DRTIO.len() as i32));
+        let mut link_tasks = 0;
+        for linkno in 0..csr::DRTIO.len() {
+            let hop = linkno as u8 + 1;
+            let mut targets = [0; DESTINATION_MASK_SIZE];
+            for &destination in destinations {
+                if routing_table.0[destination as usize][0] == hop {
+                    drtio_routing::mask_insert(&mut targets, destination);
+                }
+            }
+            if targets.iter().all(|&b| b == 0) {
+                continue;
+            }
+            let entry = (0..drtio_routing::DEST_COUNT)
+                .find(|&d| routing_table.0[d][0] == hop && routing_table.0[d][1] == 0)
+                .map(|d| d as u8);
+            let entry = match entry {
+                Some(entry) => entry,
+                None => {
+                    error!("no destination directly behind link {}, boot image not sent", linkno);
+                    continue;
+                }
+            };
+            let link_image = image.clone();
+            let succeeded = succeeded.clone();
+            let done = done.clone();
+            link_tasks += 1;
+            task::spawn(async move {
+                match remote_coremgmt::image_fan_out(linkno as u8, entry, &targets, &link_image).await {
+                    Ok(link_succeeded) => {
+                        for (s, l) in succeeded.borrow_mut().iter_mut().zip(link_succeeded.iter()) {
+                            *s |= *l;
+                        }
+                    }
+                    Err(e) => error!("failed to update boot image over link {} ({})", linkno, e),
+                }
+                // released before signalling, for the image to be taken back once all links are done
+                drop(link_image);
+                done.signal();
+            });
+        }
+        for _ in 0..link_tasks {
+            done.async_wait().await;
+        }
+        let local = destinations
+            .iter()
+            .any(|&destination| routing_table.0[destination as usize][0] == 0);
+        let image = Rc::try_unwrap(image).unwrap();
+        let succeeded = *succeeded.borrow();
+        (image, local, succeeded)
+    };
+    #[cfg(not(has_drtio))]
+    let local = !destinations.is_empty();
+
+    let local_ok = local && {
+        info!("CRC passed. Writing boot image to SD card...");
+        let mut image = image;
+        let bin_len = image.len() - 4;
+        image.truncate(bin_len);
+        boot_slot::install(image).is_ok()
+    };
+
+    write_i8(stream, Reply::FlashFanOutResult as i8).await?;
+    write_i8(stream, destinations.len() as i8).await?;
+    for &destination in destinations {
+        #[cfg(has_drtio)]
+        let ok = if ROUTING_TABLE.get().unwrap().0[destination as usize][0] == 0 {
+            local_ok
+        } else {
+            drtio_routing::mask_contains(&succeeded, destination)
+        };
+        #[cfg(not(has_drtio))]
+        let ok = local_ok;
+        write_i8(stream, destination as i8).await?;
+        write_bool(stream, ok).await?;
+    }
+
+    if local_ok {
+        local_coremgmt::reboot(stream).await?;
+    }
+    Ok(())
+}
+
+// Status queries can be answered with JSON documents instead of their binary replies,
+// once a connection has asked for it with SetReplyFormat. Requests without a schema,
+// and queries about satellites, keep their binary replies.
+mod structured {
+    #[cfg(has_si5324)]
+    use libboard_artiq::si5324;
+
+    use super::*;
+    use crate::json;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Format {
+        Binary = 0,
+        Json = 1,
+    }
+
+    impl Format {
+        // CBOR (2) is not supported, it falls back to binary like any unknown format
+        pub fn negotiate(requested: u8) -> Format {
+            match requested {
+                1 => Format::Json,
+                _ => Format::Binary,
+            }
+        }
+    }
+
+    pub fn document(request: &Request, destination: u8) -> Option<String> {
+        match request {
+            Request::GetStorageHealth => {
+                let status = storage_health::status();
+                Some(json::document("storage_health", 1, |o| {
+                    o.bool("read_only", status.read_only)
+                        .number("checks", status.checks)
+                        .number("failures", status.failures)
+                        .number("consecutive_failures", status.consecutive_failures);
+                }))
+            }
+            Request::GetIdleKernelStatus => {
+                let status = idle_kernel::status();
+                Some(json::document("idle_kernel_status", 1, |o| {
+                    o.bool("disabled", status.disabled)
+                        .number("failures", status.failures)
+                        .number("consecutive_failures", status.consecutive_failures);
+                }))
+            }
+            Request::GetNetStats => Some(json::document("net_stats", 1, |o| {
+                let interface = net_stats::interface();
+                o.number("uptime_ms", timer::get_ms())
+                    .array("services", |a| {
+                        for service in net_stats::SERVICES.iter() {
+                            let stats = net_stats::stats(*service);
+                            a.object(|o| {
+                                o.number("port", stats.port)
+                                    .bool("listening", stats.listening)
+                                    .number("active", stats.active)
+                                    .number("closing", stats.closing)
+                                    .number("accepted", stats.accepted)
+                                    .number("dropped", stats.dropped);
+                            });
+                        }
+                    })
+                    .object("interface", |o| {
+                        o.number("rx_frames", interface.rx_frames)
+                            .number("rx_bytes", interface.rx_bytes)
+                            .number("tx_frames", interface.tx_frames)
+                            .number("tx_bytes", interface.tx_bytes)
+                            .number("tx_exhausted", interface.tx_exhausted);
+                    });
+            })),
+            Request::GetServices => Some(json::document("services", 1, |o| {
+                o.array("services", |a| {
+                    for service in services::SERVICES.iter() {
+                        a.object(|o| {
+                            o.str("name", service.name())
+                                .bool("enabled", services::enabled(*service));
+                        });
+                    }
+                });
+            })),
+            Request::GetSelfTestReport => {
+                let report = selftest::report();
+                Some(json::document("self_test_report", 1, |o| {
+                    o.bool("finished", report.is_some());
+                    if let Some(report) = &report {
+                        o.number("finished_ms", report.finished_ms).array("checks", |a| {
+                            for outcome in report.outcomes.iter() {
+                                a.object(|o| {
+                                    o.str("name", &outcome.name)
+                                        .bool("passed", outcome.passed)
+                                        .str("detail", outcome.detail);
+                                });
+                            }
+                        });
+                    }
+                }))
+            }
+            Request::GetLinkStatus => Some(json::document("link_status", 1, |_o| {
+                #[cfg(has_drtio)]
+                _o.array("links", |a| {
+                    for (linkno, link) in drtio::LINK_COMPAT.lock().iter().enumerate() {
+                        a.object(|o| match *link {
+                            drtio::LinkCompat::Unknown => {
+                                o.str("state", if drtio::link_enabled(linkno as u8) { "down" } else { "disabled" });
+                            }
+                            drtio::LinkCompat::Compatible { version, features } => {
+                                o.str("state", "compatible")
+                                    .number("version", version)
+                                    .number("features", features);
+                            }
+                            drtio::LinkCompat::Incompatible { version } => {
+                                o.str("state", "incompatible").number("version", version);
+                            }
+                        });
+                    }
+                });
+                #[cfg(not(has_drtio))]
+                _o.array("links", |_| ());
+            })),
+            Request::GetDrtioStats => Some(json::document("drtio_stats", 1, |_o| {
+                #[cfg(has_drtio)]
+                _o.array("destinations", |a| {
+                    for (destination, latency) in drtio::latency_stats() {
+                        let router = drtio::router_stats(destination);
+                        a.object(|o| {
+                            o.number("destination", destination)
+                                .number("samples", latency.samples)
+                                .number("last", latency.last)
+                                .number("min", latency.min)
+                                .number("average", latency.average())
+                                .number("max", latency.max)
+                                .number("forwarded", router.forwarded)
+                                .number("queued", router.queued)
+                                .number("max_queued", router.max_queued)
+                                .number("dropped_full", router.dropped_full)
+                                .number("dropped_aged", router.dropped_aged)
+                                .array("repeaters", |a| {
+                                    for repeater in drtio::repeater_stats(destination) {
+                                        a.object(|o| {
+                                            o.number("state", repeater.state)
+                                                .number("up_transitions", repeater.up_transitions)
+                                                .number("down_transitions", repeater.down_transitions)
+                                                .number("aux_send_failures", repeater.aux_send_failures)
+                                                .number("forward_timeouts", repeater.forward_timeouts)
+                                                .array("history", |a| {
+                                                    for &(state, age_ms) in repeater.history.iter() {
+                                                        a.object(|o| {
+                                                            o.number("state", state).number("age_ms", age_ms);
+                                                        });
+                                                    }
+                                                });
+                                        });
+                                    }
+                                });
+                        });
+                    }
+                });
+                #[cfg(not(has_drtio))]
+                _o.array("destinations", |_| ());
+            })),
+            Request::GetKernelStats if is_local(destination) => {
+                let stats = *KERNEL_STATS.lock();
+                Some(json::document("kernel_stats", 1, |o| {
+                    o.number("uptime_ms", timer::get_ms())
+                        .number("kernels_run", stats.kernels_run)
+                        .number("total_duration_ms", stats.total_duration_ms)
+                        .number("last_duration_ms", stats.last_duration_ms)
+                        .number("rpc_count", stats.rpc_count)
+                        .number("rpc_total_ms", stats.rpc_total_ms)
+                        .number("rpc_max_ms", stats.rpc_max_ms)
+                        .number("dma_playbacks", stats.dma_playbacks);
+                }))
+            }
+            // a failed poll is reported by the binary reply
+            #[cfg(has_si5324)]
+            Request::GetClockStatus if is_local(destination) => {
+                let status = si5324::poll(libboard_artiq::i2c::get_bus()).ok()?;
+                Some(json::document("clock_status", 1, |o| {
+                    o.bool("monitored", status.monitored)
+                        .bool("los", status.los)
+                        .bool("lol", status.lol)
+                        .bool("holdover", status.holdover)
+                        .number("los_events", status.los_events)
+                        .number("lol_events", status.lol_events)
+                        .number("holdover_events", status.holdover_events)
+                        .number("input_switches", status.input_switches);
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
 async fn handle_connection(stream: &mut TcpStream, pull_ids: Rc<[RefCell<u32>]>) -> Result<()> {
     if !expect(&stream, b"ARTIQ management\n").await? {
         return Err(Error::UnexpectedPattern);
     }
+    if !auth::challenge(stream, auth::Port::Mgmt).await? {
+        return Err(Error::AuthFailed);
+    }
 
     let _destination: u8 = read_i8(stream).await? as u8;
     stream.send_slice("e".as_bytes()).await?;
 
     let pull_id = &pull_ids[_destination as usize];
+    let mut reply_format = structured::Format::Binary;
 
     loop {
         let msg = read_i8(stream).await;
@@ -704,13 +2750,59 @@ async fn handle_connection(stream: &mut TcpStream, pull_ids: Rc<[RefCell<u32>]>)
             return Ok(());
         }
         let msg: Request = FromPrimitive::from_i8(msg?).ok_or(Error::UnrecognizedPacket)?;
+        if reply_format == structured::Format::Json {
+            if let Some(document) = structured::document(&msg, _destination) {
+                write_i8(stream, Reply::Structured as i8).await?;
+                write_chunk(stream, document.as_bytes()).await?;
+                continue;
+            }
+        }
         match msg {
-            Request::GetLog => process!(stream, _destination, get_log),
+            Request::SetReplyFormat => {
+                reply_format = structured::Format::negotiate(read_i8(stream).await? as u8);
+                write_i8(stream, Reply::ReplyFormat as i8).await?;
+                write_i8(stream, reply_format as i8).await?;
+                Ok(())
+            }
+            Request::GetLog => process!(stream, _destination, get_log, false),
+            Request::GetLogWithStats => process!(stream, _destination, get_log, true),
             Request::ClearLog => process!(stream, _destination, clear_log),
-            Request::PullLog => process!(stream, _destination, pull_log, pull_id),
+            Request::PullLog => process!(
+                stream,
+                _destination,
+                pull_log,
+                pull_id,
+                &LogFilter::all(),
+                &LogPacing::none(),
+                false
+            ),
+            Request::PullLogWithStats => {
+                let version = read_i8(stream).await? as u8;
+                if version == 0 || version > PULL_LOG_VERSION {
+                    write_i8(stream, Reply::Error as i8).await?;
+                    return Err(Error::UnexpectedPattern);
+                }
+                let filter = if version >= 2 {
+                    read_log_filter(stream).await?
+                } else {
+                    LogFilter::all()
+                };
+                let pacing = if version >= 3 {
+                    read_log_pacing(stream).await?
+                } else {
+                    LogPacing::none()
+                };
+                process!(stream, _destination, pull_log, pull_id, &filter, &pacing, true)
+            }
             Request::ConfigRead => {
                 let key = read_key(stream).await?;
-                process!(stream, _destination, config_read, &key)
+                if auth::is_secret(&key) {
+                    warn!("read of {} refused", key);
+                    write_i8(stream, Reply::Error as i8).await?;
+                    Ok(())
+                } else {
+                    process!(stream, _destination, config_read, &key)
+                }
             }
             Request::ConfigWrite => {
                 let key = read_key(stream).await?;
@@ -727,6 +2819,20 @@ async fn handle_connection(stream: &mut TcpStream, pull_ids: Rc<[RefCell<u32>]>)
                 let key = read_key(stream).await?;
                 process!(stream, _destination, config_remove, &key)
             }
+            Request::ConfigBackup => process!(stream, _destination, config_backup),
+            Request::ConfigRestore => {
+                let len = read_i32(stream).await?;
+                let len = if len <= 0 { 0 } else { len as usize };
+                let mut buffer = Vec::new();
+                if len > config_archive::MAX_SIZE || buffer.try_reserve_exact(len).is_err() {
+                    error!("config archive of {} bytes refused", len);
+                    write_i8(stream, Reply::Error as i8).await?;
+                    return Err(Error::UnexpectedPattern);
+                }
+                buffer.resize(len, 0);
+                read_chunk(stream, &mut buffer).await?;
+                process!(stream, _destination, config_restore, buffer)
+            }
             Request::Reboot => {
                 process!(stream, _destination, reboot)
             }
@@ -736,18 +2842,120 @@ async fn handle_connection(stream: &mut TcpStream, pull_ids: Rc<[RefCell<u32>]>)
             Request::DebugAllocator => {
                 process!(stream, _destination, debug_allocator)
             }
-            Request::Flash => {
+            Request::GetBootSlot => get_boot_slot(stream, _destination).await,
+            Request::GetKernelStats => process!(stream, _destination, get_kernel_stats),
+            Request::GetTscDrift => process!(stream, _destination, get_tsc_drift),
+            Request::GetAuxCaptures => process!(stream, _destination, get_aux_captures),
+            Request::GetLastPanic => process!(stream, _destination, get_last_panic),
+            Request::GetClockStatus => process!(stream, _destination, get_clock_status),
+            Request::GetIoExpanderStatus => process!(stream, _destination, get_io_expander_status),
+            Request::SetAuxTrace => {
+                let enable = read_bool(stream).await?;
+                process!(stream, _destination, set_aux_trace, enable)
+            }
+            Request::GetAuxTrace => process!(stream, _destination, get_aux_trace),
+            Request::GetLinkStatus => get_link_status(stream).await,
+            Request::GetStorageHealth => get_storage_health(stream).await,
+            Request::GetSelfTestReport => get_self_test_report(stream).await,
+            Request::SetWallClock => {
+                let time_us = read_i64(stream).await? as u64;
+                set_wall_clock(stream, time_us).await
+            }
+            Request::RetrainEem => {
+                let trx_no = read_i8(stream).await? as u8;
+                retrain_eem(stream, trx_no).await
+            }
+            Request::EemPrbsTest => {
+                let trx_no = read_i8(stream).await? as u8;
+                let near_end = read_bool(stream).await?;
+                let duration_ms = read_i32(stream).await? as u32 as u64;
+                eem_prbs_test(stream, trx_no, near_end, duration_ms).await
+            }
+            Request::SetLinkEnabled => {
+                let linkno = read_i8(stream).await? as u8;
+                let enabled = read_bool(stream).await?;
+                set_link_enabled(stream, linkno, enabled).await
+            }
+            Request::ReloadDeviceMap => reload_device_map(stream).await,
+            Request::GetNetStats => get_net_stats(stream).await,
+            Request::GetDrtioStats => get_drtio_stats(stream).await,
+            Request::GetCoreDump => get_core_dump(stream).await,
+            Request::SetProfiler => {
+                let enabled = read_bool(stream).await?;
+                let interval_us = read_i32(stream).await? as u32;
+                set_profiler(stream, enabled, interval_us).await
+            }
+            Request::GetProfile => get_profile(stream).await,
+            Request::SetAnalyzerAutoArm => {
+                let enabled = read_bool(stream).await?;
+                set_analyzer_auto_arm(stream, enabled).await
+            }
+            Request::GetAnalyzerSession => get_analyzer_session(stream).await,
+            Request::GetAuxTimeouts => get_aux_timeouts(stream).await,
+            Request::GetIdleKernelStatus => get_idle_kernel_status(stream).await,
+            Request::RearmIdleKernel => rearm_idle_kernel(stream).await,
+            Request::RunStoredKernel => {
+                let key = read_key(stream).await?;
+                run_stored_kernel(stream, key).await
+            }
+            Request::LoadKernelViaMgmt => load_kernel_via_mgmt(stream).await,
+            Request::Ping => answer_ping(stream).await,
+            Request::ReleaseDeviceLock => release_device_lock(stream).await,
+            Request::GetServices => get_services(stream).await,
+            Request::SubscribeEvents => subscribe_events(stream).await,
+            Request::ConsoleBridge => console_bridge(stream).await,
+            Request::EyeScan => {
+                let link = read_i8(stream).await? as u8;
+                let prescale = read_i8(stream).await? as u8;
+                process!(stream, _destination, eye_scan, link, prescale)
+            }
+            Request::CriConStatus => {
+                let reset = read_bool(stream).await?;
+                process!(stream, _destination, cri_con_status, reset)
+            }
+            Request::KernelCacheStatus => {
+                let clear = read_bool(stream).await?;
+                process!(stream, _destination, kernel_cache_status, clear)
+            }
+            Request::SetSedSpread => {
+                let enable = read_bool(stream).await?;
+                process!(stream, _destination, set_sed_spread, enable)
+            }
+            Request::SelectBootSlot => {
+                let slot = read_i8(stream).await? as u8;
+                select_boot_slot(stream, _destination, slot).await
+            }
+            Request::FlashFanOut => {
+                let count = read_i8(stream).await? as u8;
+                let mut destinations = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    destinations.push(read_i8(stream).await? as u8);
+                }
                 let len = read_i32(stream).await?;
-                if len <= 0 {
+                // image must at least contain the CRC
+                if len <= 4 {
                     write_i8(stream, Reply::Error as i8).await?;
                     return Err(Error::UnexpectedPattern);
                 }
-                let mut buffer = Vec::with_capacity(len as usize);
-                unsafe {
-                    buffer.set_len(len as usize);
+                image_fan_out(stream, &destinations, len as usize).await
+            }
+            Request::Flash | Request::FlashWithProgress | Request::FlashVerify => {
+                let len = read_i32(stream).await?;
+                // image must at least contain the CRC
+                if len <= 4 {
+                    write_i8(stream, Reply::Error as i8).await?;
+                    return Err(Error::UnexpectedPattern);
                 }
-                read_chunk(stream, &mut buffer).await?;
-                process!(stream, _destination, image_write, buffer)
+                let report_progress = !matches!(msg, Request::Flash);
+                let verify_only = matches!(msg, Request::FlashVerify);
+                process!(
+                    stream,
+                    _destination,
+                    image_write,
+                    len as usize,
+                    report_progress,
+                    verify_only
+                )
             }
         }?;
     }
@@ -760,15 +2968,20 @@ pub fn start() {
         #[cfg(not(has_drtio))]
         let pull_ids = Rc::new([RefCell::new(0u32); 1]);
         loop {
-            let mut stream = TcpStream::accept(1380, 2048, 2048).await.unwrap();
+            let size = net_buffers::size(net_buffers::Server::Mgmt);
+            net_stats::listening(net_stats::Service::Mgmt);
+            let mut stream = TcpStream::accept(1380, size, size).await.unwrap();
             let pull_ids = pull_ids.clone();
             task::spawn(async move {
                 info!("received connection");
-                let _ = handle_connection(&mut stream, pull_ids)
+                net_stats::accepted(net_stats::Service::Mgmt);
+                let result = handle_connection(&mut stream, pull_ids)
                     .await
                     .map_err(|e| warn!("connection terminated: {:?}", e));
+                net_stats::closed(net_stats::Service::Mgmt, result.is_err());
                 let _ = stream.flush().await;
                 let _ = stream.abort().await;
+                net_stats::released(net_stats::Service::Mgmt);
             });
         }
     });
@@ -1,8 +1,10 @@
-use alloc::collections::BTreeMap;
+use alloc::{collections::BTreeMap, vec::Vec};
 use core::fmt;
 
 use futures::{FutureExt, pin_mut, select_biased};
 use libasync::{smoltcp::TcpStream, task};
+#[cfg(has_drtio)]
+use libboard_artiq::drtioaux_proto::MONITOR_BATCH_SIZE;
 use libboard_zynq::{smoltcp, timer};
 use log::{debug, info, warn};
 use num_derive::{FromPrimitive, ToPrimitive};
@@ -10,12 +12,13 @@ use num_traits::{FromPrimitive, ToPrimitive};
 
 #[cfg(has_drtio)]
 use crate::comms::ROUTING_TABLE;
-use crate::proto_async::*;
+use crate::{auth, moninj_udp, net_buffers, net_stats, proto_async::*};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
     NetworkError(smoltcp::Error),
     UnexpectedPattern,
+    AuthFailed,
     UnrecognizedPacket,
 }
 
@@ -26,6 +29,7 @@ impl fmt::Display for Error {
         match self {
             &Error::NetworkError(error) => write!(f, "network error: {}", error),
             &Error::UnexpectedPattern => write!(f, "unexpected pattern"),
+            &Error::AuthFailed => write!(f, "authentication failed"),
             &Error::UnrecognizedPacket => write!(f, "unrecognized packet"),
         }
     }
@@ -43,12 +47,123 @@ enum HostMessage {
     MonitorInjection = 3,
     Inject = 1,
     GetInjectionStatus = 2,
+    MonitorProbeThreshold = 4,
+    ScopeCapture = 5,
+    MonitorBatched = 6,
+    MonitorDatagram = 7,
 }
 
 #[derive(Debug, FromPrimitive, ToPrimitive)]
 enum DeviceMessage {
     MonitorStatus = 0,
     InjectionStatus = 1,
+    ScopeData = 2,
+    MonitorBatch = 3,
+}
+
+// Scope captures sample a probe of a local channel in a busy loop, so they are
+// kept short: the core is not serving anything else meanwhile.
+const SCOPE_MAX_SAMPLES: u32 = 4096;
+const SCOPE_MAX_DURATION_US: u64 = 100_000;
+
+// A watched probe is reported when it is first read, and afterwards only when its
+// value moved by at least `threshold` since the last report (any change if 0).
+struct ProbeWatch {
+    threshold: u64,
+    last: Option<i64>,
+}
+
+impl ProbeWatch {
+    fn new(threshold: u64) -> ProbeWatch {
+        ProbeWatch { threshold, last: None }
+    }
+
+    fn should_report(&self, current: i64) -> bool {
+        match self.last {
+            None => true,
+            Some(last) => current.wrapping_sub(last).unsigned_abs() >= self.threshold.max(1),
+        }
+    }
+}
+
+// In batched mode, the updates of each polling round are sent as a single
+// numbered frame, and every watched value is resent periodically so that a
+// client can resynchronize its state without re-subscribing.
+// The frames can also be sent as UDP datagrams, see moninj_udp; those that cannot be
+// queued are dropped and made up for by the next resync.
+const DEFAULT_RESYNC_MS: u64 = 5000;
+const BATCH_HEADER_SIZE: usize = 10;
+const MAX_UPDATE_SIZE: usize = 14;
+
+struct DatagramStream(u32);
+
+impl Drop for DatagramStream {
+    fn drop(&mut self) {
+        moninj_udp::close(self.0);
+    }
+}
+
+struct Batching {
+    sequence: u32,
+    resync_ms: u64,
+    next_resync: u64,
+    datagram: Option<DatagramStream>,
+}
+
+impl Batching {
+    fn new(resync_ms: u64, datagram: Option<DatagramStream>) -> Batching {
+        Batching {
+            sequence: 0,
+            resync_ms: resync_ms,
+            next_resync: 0,
+            datagram: datagram,
+        }
+    }
+
+    fn resync_due(&mut self, now: u64) -> bool {
+        if now >= self.next_resync {
+            self.next_resync = now + self.resync_ms;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+enum Update {
+    Probe { channel: i32, probe: i8, value: i64 },
+    Injection { channel: i32, overrd: i8, value: i8 },
+}
+
+impl Update {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match *self {
+            Update::Probe { channel, probe, value } => {
+                buffer.push(DeviceMessage::MonitorStatus.to_u8().unwrap());
+                buffer.extend_from_slice(&channel.to_le_bytes());
+                buffer.push(probe as u8);
+                buffer.extend_from_slice(&value.to_le_bytes());
+            }
+            Update::Injection { channel, overrd, value } => {
+                buffer.push(DeviceMessage::InjectionStatus.to_u8().unwrap());
+                buffer.extend_from_slice(&channel.to_le_bytes());
+                buffer.push(overrd as u8);
+                buffer.push(value as u8);
+            }
+        }
+    }
+}
+
+fn encode_batch(sequence: u32, full: bool, updates: &[Update]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(BATCH_HEADER_SIZE + updates.len() * MAX_UPDATE_SIZE);
+    buffer.push(DeviceMessage::MonitorBatch.to_u8().unwrap());
+    buffer.extend_from_slice(&sequence.to_le_bytes());
+    buffer.push(full as u8);
+    buffer.extend_from_slice(&(updates.len() as u32).to_le_bytes());
+    for update in updates {
+        update.encode(&mut buffer);
+    }
+    buffer
 }
 
 #[cfg(has_drtio)]
@@ -59,29 +174,39 @@ mod remote_moninj {
     use super::*;
     use crate::rtio_mgt::drtio::{self, AUX_MUTEX, Error as DrtioError};
 
-    pub async fn read_probe(linkno: u8, destination: u8, channel: i32, probe: i8) -> i64 {
+    // reads up to MONITOR_BATCH_SIZE probes of the destination with a single aux round trip
+    pub async fn read_probes(linkno: u8, destination: u8, probes: &[(i32, i8)]) -> Option<Vec<i64>> {
+        let mut channels = [0; MONITOR_BATCH_SIZE];
+        let mut probe_sel = [0; MONITOR_BATCH_SIZE];
+        for (i, &(channel, probe)) in probes.iter().enumerate() {
+            channels[i] = channel as u16;
+            probe_sel[i] = probe as u8;
+        }
         let reply = drtio::aux_transact(
             linkno,
-            &drtioaux_async::Packet::MonitorRequest {
+            &drtioaux_async::Packet::MonitorBatchRequest {
                 destination: destination,
-                channel: channel as _,
-                probe: probe as _,
+                count: probes.len() as u8,
+                channels: channels,
+                probes: probe_sel,
             },
         )
         .await;
         match reply {
-            Ok(drtioaux_async::Packet::MonitorReply { value }) => return value as i64,
+            Ok(drtioaux_async::Packet::MonitorBatchReply { count, values }) if count as usize == probes.len() => {
+                return Some(values[..probes.len()].iter().map(|&value| value as i64).collect());
+            }
             Ok(packet) => error!("received unexpected aux packet: {:?}", packet),
             Err(DrtioError::LinkDown) => {
                 warn!("link is down");
             }
             Err(e) => error!("aux packet error ({})", e),
         }
-        0
+        None
     }
 
     pub async fn inject(linkno: u8, destination: u8, channel: i32, overrd: i8, value: i8) {
-        let _lock = AUX_MUTEX.async_lock().await;
+        let _lock = AUX_MUTEX[linkno as usize].async_lock().await;
         drtioaux_async::send(
             linkno,
             &drtioaux_async::Packet::InjectionRequest {
@@ -118,14 +243,17 @@ mod remote_moninj {
 }
 
 mod local_moninj {
+    use alloc::{vec, vec::Vec};
+
     use libboard_artiq::pl::csr;
+    use libboard_zynq::timer;
 
-    pub fn read_probe(channel: i32, probe: i8) -> i64 {
+    pub fn read_probe(channel: i32, probe: i8) -> Option<i64> {
         unsafe {
             csr::rtio_moninj::mon_chan_sel_write(channel as _);
             csr::rtio_moninj::mon_probe_sel_write(probe as _);
             csr::rtio_moninj::mon_value_update_write(1);
-            csr::rtio_moninj::mon_value_read() as i64
+            Some(csr::rtio_moninj::mon_value_read() as i64)
         }
     }
 
@@ -144,6 +272,29 @@ mod local_moninj {
             csr::rtio_moninj::inj_value_read() as i8
         }
     }
+
+    // Samples the probe every `period_us`, one bit per sample (LSB first).
+    // Returns the packed samples and the time actually taken, in microseconds.
+    pub fn scope_capture(channel: i32, probe: i8, period_us: u64, count: u32) -> (Vec<u8>, u64) {
+        let mut samples = vec![0; (count as usize + 7) / 8];
+        unsafe {
+            csr::rtio_moninj::mon_chan_sel_write(channel as _);
+            csr::rtio_moninj::mon_probe_sel_write(probe as _);
+        }
+        let start = timer::get_us();
+        for i in 0..count as usize {
+            let deadline = start + i as u64 * period_us;
+            while timer::get_us() < deadline {}
+            let level = unsafe {
+                csr::rtio_moninj::mon_value_update_write(1);
+                csr::rtio_moninj::mon_value_read() != 0
+            };
+            if level {
+                samples[i / 8] |= 1 << (i % 8);
+            }
+        }
+        (samples, timer::get_us() - start)
+    }
 }
 
 #[cfg(has_drtio)]
@@ -169,13 +320,62 @@ macro_rules! dispatch {
     }}
 }
 
+// Injection requested by kernels goes through the same CSRs and aux requests as that of
+// the dashboards, and is picked up by their watch lists.
+pub async fn inject(channel: i32, overrd: i8, value: i8) {
+    dispatch!(channel, inject, overrd, value)
+}
+
+pub async fn read_injection_status(channel: i32, overrd: i8) -> i8 {
+    dispatch!(channel, read_injection_status, overrd)
+}
+
+fn is_local(_channel: i32) -> bool {
+    #[cfg(has_drtio)]
+    {
+        let destination = (_channel >> 16) as u8;
+        ROUTING_TABLE.get().unwrap().0[destination as usize][0] == 0
+    }
+    #[cfg(not(has_drtio))]
+    true
+}
+
+// Reads probes that all belong to the same destination. Remote probes are read in
+// batches; once the destination fails to reply, its remaining probes are left as None
+// for this round rather than waiting for each of them to time out.
+async fn read_probes(_destination: u8, probes: &[(i32, i8)]) -> Vec<Option<i64>> {
+    #[cfg(has_drtio)]
+    {
+        let hop = ROUTING_TABLE.get().unwrap().0[_destination as usize][0];
+        if hop != 0 {
+            let mut values = Vec::with_capacity(probes.len());
+            for batch in probes.chunks(MONITOR_BATCH_SIZE) {
+                match remote_moninj::read_probes(hop - 1, _destination, batch).await {
+                    Some(batch_values) => values.extend(batch_values.into_iter().map(Some)),
+                    None => break,
+                }
+            }
+            values.resize(probes.len(), None);
+            return values;
+        }
+    }
+    probes
+        .iter()
+        .map(|&(channel, probe)| local_moninj::read_probe(channel, probe))
+        .collect()
+}
+
 async fn handle_connection(stream: &TcpStream) -> Result<()> {
     if !expect(&stream, b"ARTIQ moninj\n").await? {
         return Err(Error::UnexpectedPattern);
     }
+    if !auth::challenge(stream, auth::Port::Moninj).await? {
+        return Err(Error::AuthFailed);
+    }
 
-    let mut probe_watch_list: BTreeMap<(i32, i8), Option<i64>> = BTreeMap::new();
+    let mut probe_watch_list: BTreeMap<(i32, i8), ProbeWatch> = BTreeMap::new();
     let mut inject_watch_list: BTreeMap<(i32, i8), Option<i8>> = BTreeMap::new();
+    let mut batching: Option<Batching> = None;
     let mut next_check = 0;
     loop {
         // TODO: we don't need fuse() here.
@@ -194,13 +394,27 @@ async fn handle_connection(stream: &TcpStream) -> Result<()> {
                         let channel = read_i32(&stream).await?;
                         let probe = read_i8(&stream).await?;
                         if enable {
-                            let _ = probe_watch_list.entry((channel, probe)).or_insert(None);
+                            let _ = probe_watch_list.entry((channel, probe)).or_insert(ProbeWatch::new(0));
                             debug!("START monitoring channel {}, probe {}", channel, probe);
                         } else {
                             let _ = probe_watch_list.remove(&(channel, probe));
                             debug!("END monitoring channel {}, probe {}", channel, probe);
                         }
                     },
+                    HostMessage::MonitorProbeThreshold => {
+                        let enable = read_bool(&stream).await?;
+                        let channel = read_i32(&stream).await?;
+                        let probe = read_i8(&stream).await?;
+                        let threshold = read_i64(&stream).await?.max(0) as u64;
+                        if enable {
+                            let watch = probe_watch_list.entry((channel, probe)).or_insert(ProbeWatch::new(threshold));
+                            watch.threshold = threshold;
+                            debug!("START monitoring channel {}, probe {}, threshold {}", channel, probe, threshold);
+                        } else {
+                            let _ = probe_watch_list.remove(&(channel, probe));
+                            debug!("END monitoring channel {}, probe {}", channel, probe);
+                        }
+                    },
                     HostMessage::MonitorInjection => {
                         let enable = read_bool(&stream).await?;
                         let channel = read_i32(&stream).await?;
@@ -229,29 +443,136 @@ async fn handle_connection(stream: &TcpStream) -> Result<()> {
                         write_i8(&stream, overrd).await?;
                         write_i8(&stream, value).await?;
                     },
+                    HostMessage::ScopeCapture => {
+                        let channel = read_i32(&stream).await?;
+                        let probe = read_i8(&stream).await?;
+                        let period_us = read_i32(&stream).await?.max(1) as u64;
+                        let count = (read_i32(&stream).await?.max(0) as u32)
+                            .min(SCOPE_MAX_SAMPLES)
+                            .min((SCOPE_MAX_DURATION_US / period_us) as u32);
+                        let (samples, elapsed_us) = if is_local(channel) {
+                            debug!("SCOPE channel {}, probe {}, {} x {} us", channel, probe, count, period_us);
+                            local_moninj::scope_capture(channel, probe, period_us, count)
+                        } else {
+                            warn!("scope capture is only available on local channels, not {}", channel);
+                            (Vec::new(), 0)
+                        };
+                        write_i8(&stream, DeviceMessage::ScopeData.to_i8().unwrap()).await?;
+                        write_i32(&stream, channel).await?;
+                        write_i8(&stream, probe).await?;
+                        write_i32(&stream, period_us as i32).await?;
+                        write_i32(&stream, if samples.is_empty() { 0 } else { count as i32 }).await?;
+                        write_i64(&stream, elapsed_us as i64).await?;
+                        write_chunk(&stream, &samples).await?;
+                    },
+                    HostMessage::MonitorBatched => {
+                        let enable = read_bool(&stream).await?;
+                        let resync_ms = match read_i32(&stream).await? {
+                            ms if ms > 0 => ms as u64,
+                            _ => DEFAULT_RESYNC_MS,
+                        };
+                        if enable {
+                            batching = Some(Batching::new(resync_ms, None));
+                            debug!("START batched monitoring, resync every {} ms", resync_ms);
+                        } else {
+                            batching = None;
+                            debug!("END batched monitoring");
+                        }
+                    },
+                    HostMessage::MonitorDatagram => {
+                        let enable = read_bool(&stream).await?;
+                        let resync_ms = match read_i32(&stream).await? {
+                            ms if ms > 0 => ms as u64,
+                            _ => DEFAULT_RESYNC_MS,
+                        };
+                        let token = read_i32(&stream).await? as u32;
+                        // drop the previous stream first, it may have had the same token
+                        batching = None;
+                        if enable {
+                            moninj_udp::open(token);
+                            batching = Some(Batching::new(resync_ms, Some(DatagramStream(token))));
+                            debug!("START datagram monitoring, resync every {} ms", resync_ms);
+                        } else {
+                            debug!("END datagram monitoring");
+                        }
+                    },
                 }
             },
             _ = timeout_f => {
-                for (&(channel, probe), previous) in probe_watch_list.iter_mut() {
-                    let current = dispatch!(channel, read_probe, probe);
-                    if previous.is_none() || previous.unwrap() != current {
-                        write_i8(&stream, DeviceMessage::MonitorStatus.to_i8().unwrap()).await?;
-                        write_i32(&stream, channel).await?;
-                        write_i8(&stream, probe).await?;
-                        write_i64(&stream, current).await?;
-                        *previous = Some(current);
+                let full = match batching {
+                    Some(ref mut batching) => batching.resync_due(timer::get_ms()),
+                    None => false,
+                };
+                let mut updates = Vec::new();
+                // the list is ordered by channel, so the probes of each destination are
+                // next to each other and can be polled together
+                let probes: Vec<(i32, i8)> = probe_watch_list.keys().cloned().collect();
+                let mut start = 0;
+                while start < probes.len() {
+                    let destination = (probes[start].0 >> 16) as u8;
+                    let count = probes[start..]
+                        .iter()
+                        .take_while(|&&(channel, _)| (channel >> 16) as u8 == destination)
+                        .count();
+                    let group = &probes[start..start + count];
+                    start += count;
+                    let values = read_probes(destination, group).await;
+                    for (&(channel, probe), value) in group.iter().zip(values) {
+                        let watch = probe_watch_list.get_mut(&(channel, probe)).unwrap();
+                        if let Some(current) = value {
+                            if full || watch.should_report(current) {
+                                updates.push(Update::Probe { channel: channel, probe: probe, value: current });
+                                watch.last = Some(current);
+                            }
+                        }
                     }
                 }
                 for (&(channel, overrd), previous) in inject_watch_list.iter_mut() {
                     let current = dispatch!(channel, read_injection_status, overrd);
-                    if previous.is_none() || previous.unwrap() != current {
-                        write_i8(&stream, DeviceMessage::InjectionStatus.to_i8().unwrap()).await?;
-                        write_i32(&stream, channel).await?;
-                        write_i8(&stream, overrd).await?;
-                        write_i8(&stream, current).await?;
+                    if full || previous.is_none() || previous.unwrap() != current {
+                        updates.push(Update::Injection { channel: channel, overrd: overrd, value: current });
                         *previous = Some(current);
                     }
                 }
+                match batching {
+                    Some(ref mut batching) => if full || !updates.is_empty() {
+                        match batching.datagram {
+                            Some(DatagramStream(token)) => {
+                                let per_datagram = (moninj_udp::MAX_PAYLOAD - BATCH_HEADER_SIZE) / MAX_UPDATE_SIZE;
+                                let mut chunks: Vec<&[Update]> = updates.chunks(per_datagram).collect();
+                                if chunks.is_empty() {
+                                    chunks.push(&[]);
+                                }
+                                for chunk in chunks {
+                                    if !moninj_udp::send(token, &encode_batch(batching.sequence, full, chunk)) {
+                                        debug!("dropped moninj datagram {}", batching.sequence);
+                                    }
+                                    batching.sequence = batching.sequence.wrapping_add(1);
+                                }
+                            }
+                            None => {
+                                stream.send_slice(&encode_batch(batching.sequence, full, &updates)).await?;
+                                batching.sequence = batching.sequence.wrapping_add(1);
+                            }
+                        }
+                    },
+                    None => for update in updates.iter() {
+                        match *update {
+                            Update::Probe { channel, probe, value } => {
+                                write_i8(&stream, DeviceMessage::MonitorStatus.to_i8().unwrap()).await?;
+                                write_i32(&stream, channel).await?;
+                                write_i8(&stream, probe).await?;
+                                write_i64(&stream, value).await?;
+                            }
+                            Update::Injection { channel, overrd, value } => {
+                                write_i8(&stream, DeviceMessage::InjectionStatus.to_i8().unwrap()).await?;
+                                write_i32(&stream, channel).await?;
+                                write_i8(&stream, overrd).await?;
+                                write_i8(&stream, value).await?;
+                            }
+                        }
+                    },
+                }
                 next_check = 200;
             }
         }
@@ -261,17 +582,28 @@ async fn handle_connection(stream: &TcpStream) -> Result<()> {
 pub fn start() {
     task::spawn(async move {
         loop {
-            let stream = TcpStream::accept(1383, 2048, 2048).await.unwrap();
+            let size = net_buffers::size(net_buffers::Server::Moninj);
+            net_stats::listening(net_stats::Service::Moninj);
+            let stream = TcpStream::accept(1383, size, size).await.unwrap();
             task::spawn(async move {
                 info!("received connection");
+                net_stats::accepted(net_stats::Service::Moninj);
                 let result = handle_connection(&stream).await;
-                match result {
-                    Err(Error::NetworkError(smoltcp::Error::Finished)) => info!("peer closed connection"),
-                    Err(error) => warn!("connection terminated: {}", error),
-                    _ => (),
-                }
+                let dropped = match result {
+                    Err(Error::NetworkError(smoltcp::Error::Finished)) => {
+                        info!("peer closed connection");
+                        false
+                    }
+                    Err(error) => {
+                        warn!("connection terminated: {}", error);
+                        true
+                    }
+                    _ => false,
+                };
+                net_stats::closed(net_stats::Service::Moninj, dropped);
                 let _ = stream.flush().await;
                 let _ = stream.abort().await;
+                net_stats::released(net_stats::Service::Moninj);
             });
         }
     });
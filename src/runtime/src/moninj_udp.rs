@@ -0,0 +1,168 @@
+use alloc::{collections::VecDeque, vec, vec::Vec};
+
+use libboard_zynq::{smoltcp::{phy::{self, TxToken},
+                              time::Instant,
+                              wire::{EthernetAddress, EthernetFrame, EthernetProtocol, IpAddress, IpProtocol,
+                                     Ipv4Address, Ipv4Packet, UdpPacket}},
+                    timer};
+use libcortex_a9::mutex::Mutex;
+
+// Batched moninj updates can be streamed as UDP datagrams instead of over the TCP connection.
+// The network stack only has TCP sockets, so that datagrams are handled below it: a client that
+// switched its connection to datagram mode sends a datagram holding its 4-byte token to the
+// moninj port, which tells where to send the stream, and the datagrams built here are queued
+// until the main loop writes them to the Ethernet device.
+
+pub const PORT: u16 = 1383;
+// leaves room for the headers within a 1500-byte MTU
+pub const MAX_PAYLOAD: usize = 1400;
+const QUEUE_SIZE: usize = 16;
+const HOP_LIMIT: u8 = 64;
+
+#[derive(Debug, Clone, Copy)]
+struct Endpoint {
+    local_mac: EthernetAddress,
+    local_ip: Ipv4Address,
+    remote_mac: EthernetAddress,
+    remote_ip: Ipv4Address,
+    remote_port: u16,
+}
+
+struct Stream {
+    token: u32,
+    endpoint: Option<Endpoint>,
+}
+
+struct State {
+    streams: Vec<Stream>,
+    queue: VecDeque<Vec<u8>>,
+    ident: u16,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    streams: Vec::new(),
+    queue: VecDeque::new(),
+    ident: 0,
+});
+
+pub fn open(token: u32) {
+    let mut state = STATE.lock();
+    state.streams.retain(|stream| stream.token != token);
+    state.streams.push(Stream {
+        token: token,
+        endpoint: None,
+    });
+}
+
+pub fn close(token: u32) {
+    STATE.lock().streams.retain(|stream| stream.token != token);
+}
+
+fn build(endpoint: &Endpoint, ident: u16, payload: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + payload.len();
+    let ip_len = 20 + udp_len;
+    let mut buffer = vec![0; 14 + ip_len];
+
+    let mut frame = EthernetFrame::new_unchecked(&mut buffer[..]);
+    frame.set_src_addr(endpoint.local_mac);
+    frame.set_dst_addr(endpoint.remote_mac);
+    frame.set_ethertype(EthernetProtocol::Ipv4);
+
+    let mut packet = Ipv4Packet::new_unchecked(frame.payload_mut());
+    packet.set_version(4);
+    packet.set_header_len(20);
+    packet.set_dscp(0);
+    packet.set_ecn(0);
+    packet.set_total_len(ip_len as u16);
+    packet.set_ident(ident);
+    packet.clear_flags();
+    packet.set_dont_frag(true);
+    packet.set_frag_offset(0);
+    packet.set_hop_limit(HOP_LIMIT);
+    packet.set_protocol(IpProtocol::Udp);
+    packet.set_src_addr(endpoint.local_ip);
+    packet.set_dst_addr(endpoint.remote_ip);
+    packet.fill_checksum();
+
+    let mut datagram = UdpPacket::new_unchecked(packet.payload_mut());
+    datagram.set_src_port(PORT);
+    datagram.set_dst_port(endpoint.remote_port);
+    datagram.set_len(udp_len as u16);
+    datagram.payload_mut().copy_from_slice(payload);
+    datagram.fill_checksum(
+        &IpAddress::Ipv4(endpoint.local_ip),
+        &IpAddress::Ipv4(endpoint.remote_ip),
+    );
+    buffer
+}
+
+/// Queues a datagram of the stream, returns false if the client has not announced
+/// itself yet or the queue is full.
+pub fn send(token: u32, payload: &[u8]) -> bool {
+    let mut state = STATE.lock();
+    let endpoint = match state.streams.iter().find(|stream| stream.token == token) {
+        Some(Stream {
+            endpoint: Some(endpoint),
+            ..
+        }) => *endpoint,
+        _ => return false,
+    };
+    if state.queue.len() >= QUEUE_SIZE {
+        return false;
+    }
+    let ident = state.ident;
+    state.ident = ident.wrapping_add(1);
+    state.queue.push_back(build(&endpoint, ident, payload));
+    true
+}
+
+/// Checks a received frame for a datagram to the moninj port, recording where the client
+/// holding the token wants its stream. Such frames are not passed on to the network stack,
+/// which would answer them with a port unreachable message.
+pub fn intercept(frame: &[u8]) -> bool {
+    let frame = match EthernetFrame::new_checked(frame) {
+        Ok(frame) if frame.ethertype() == EthernetProtocol::Ipv4 => frame,
+        _ => return false,
+    };
+    let packet = match Ipv4Packet::new_checked(frame.payload()) {
+        Ok(packet) if packet.protocol() == IpProtocol::Udp => packet,
+        _ => return false,
+    };
+    let datagram = match UdpPacket::new_checked(packet.payload()) {
+        Ok(datagram) if datagram.dst_port() == PORT => datagram,
+        _ => return false,
+    };
+    if let [a, b, c, d] = *datagram.payload() {
+        let token = u32::from_le_bytes([a, b, c, d]);
+        if let Some(stream) = STATE.lock().streams.iter_mut().find(|stream| stream.token == token) {
+            stream.endpoint = Some(Endpoint {
+                local_mac: frame.dst_addr(),
+                local_ip: packet.dst_addr(),
+                remote_mac: frame.src_addr(),
+                remote_ip: packet.src_addr(),
+                remote_port: datagram.src_port(),
+            });
+        }
+    }
+    true
+}
+
+/// Writes the oldest queued datagram to the device, returns false if there was none or
+/// the device cannot take it now. Called by the main loop after polling the network stack.
+pub fn flush<'a, D: phy::Device<'a>>(device: &'a mut D) -> bool {
+    let mut state = STATE.lock();
+    let frame = match state.queue.front() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    let token = match device.transmit() {
+        Some(token) => token,
+        None => return false,
+    };
+    let _ = token.consume(Instant::from_millis(timer::get_ms() as i64), frame.len(), |buffer| {
+        buffer.copy_from_slice(frame);
+        Ok(())
+    });
+    state.queue.pop_front();
+    true
+}
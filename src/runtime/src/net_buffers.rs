@@ -0,0 +1,107 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use libconfig;
+use log::{info, warn};
+
+// Number of sockets and TCP buffer sizes of each server, set by the `tcp_buffers` config key:
+// comma-separated `sockets=<count>`, `coredev=<KiB>`, `mgmt=<KiB>`, `moninj=<KiB>` and
+// `analyzer=<KiB>` entries, the size being that of both the receive and transmit buffers.
+// Sizes the heap has no room for are ignored.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Server {
+    Coredev = 0,
+    Mgmt = 1,
+    Moninj = 2,
+    Analyzer = 3,
+}
+
+const SERVERS: [Server; 4] = [Server::Coredev, Server::Mgmt, Server::Moninj, Server::Analyzer];
+
+const DEFAULT_SOCKETS: usize = 32;
+// one per server listening, and room for the connections
+const MIN_SOCKETS: usize = 8;
+const MAX_SOCKETS: usize = 256;
+const MIN_SIZE: usize = 1024;
+const MAX_SIZE: usize = 4 * 1024 * 1024;
+
+static SOCKETS: AtomicUsize = AtomicUsize::new(DEFAULT_SOCKETS);
+static SIZES: [AtomicUsize; 4] = [
+    AtomicUsize::new(0x10_000),
+    AtomicUsize::new(2048),
+    AtomicUsize::new(2048),
+    AtomicUsize::new(2048),
+];
+
+impl Server {
+    fn name(self) -> &'static str {
+        match self {
+            Server::Coredev => "coredev",
+            Server::Mgmt => "mgmt",
+            Server::Moninj => "moninj",
+            Server::Analyzer => "analyzer",
+        }
+    }
+}
+
+// A connection can be accepted while the previous one is still open, so each server
+// may hold the buffers of two sockets.
+fn heap_has_room(sizes: &[usize; 4]) -> bool {
+    let total = sizes.iter().map(|size| 2 * 2 * size).sum();
+    let mut probe: Vec<u8> = Vec::new();
+    probe.try_reserve_exact(total).is_ok()
+}
+
+pub fn load() {
+    let text = match libconfig::read_str("tcp_buffers") {
+        Ok(text) => text,
+        Err(_) => return,
+    };
+    let mut sizes = [0; 4];
+    for (size, default) in sizes.iter_mut().zip(SIZES.iter()) {
+        *size = default.load(Ordering::Relaxed);
+    }
+    for entry in text.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (name, value) = match entry
+            .split_once('=')
+            .map(|(name, value)| (name.trim(), value.trim().parse::<usize>()))
+        {
+            Some((name, Ok(value))) => (name, value),
+            _ => {
+                warn!("tcp_buffers entry \"{}\" not supported, ignoring", entry);
+                continue;
+            }
+        };
+        if name == "sockets" {
+            if !(MIN_SOCKETS..=MAX_SOCKETS).contains(&value) {
+                warn!("tcp_buffers entry \"{}\" not supported, ignoring", entry);
+                continue;
+            }
+            SOCKETS.store(value, Ordering::Relaxed);
+            info!("{} sockets set by tcp_buffers", value);
+            continue;
+        }
+        let size = value.saturating_mul(1024);
+        match SERVERS.iter().find(|server| server.name() == name) {
+            Some(server) if (MIN_SIZE..=MAX_SIZE).contains(&size) => sizes[*server as usize] = size,
+            _ => warn!("tcp_buffers entry \"{}\" not supported, ignoring", entry),
+        }
+    }
+    if !heap_has_room(&sizes) {
+        warn!("not enough heap for the TCP buffers set by tcp_buffers, keeping the defaults");
+        return;
+    }
+    for (server, size) in SERVERS.iter().zip(sizes.iter()) {
+        SIZES[*server as usize].store(*size, Ordering::Relaxed);
+        info!("{} TCP buffers: {} KiB", server.name(), size / 1024);
+    }
+}
+
+pub fn sockets() -> usize {
+    SOCKETS.load(Ordering::Relaxed)
+}
+
+pub fn size(server: Server) -> usize {
+    SIZES[server as usize].load(Ordering::Relaxed)
+}
@@ -0,0 +1,107 @@
+use alloc::vec::Vec;
+use core::{str::FromStr,
+           sync::atomic::{AtomicBool, Ordering}};
+
+use libboard_zynq::smoltcp::{iface::{EthernetInterface, Route},
+                             phy::Device,
+                             wire::{IpAddress, IpCidr, Ipv4Address, Ipv6Address}};
+use libconfig;
+use log::{info, warn};
+
+// Static routes from the `routes` config key, a comma-separated list of entries:
+//   "<network>/<prefix>" for a network the device is directly connected to,
+//   "<network>/<prefix> via <gateway>" or "default via <gateway>" for a route.
+// Without the key, every address is treated as directly connected, as before.
+
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+#[derive(Default)]
+struct Config {
+    on_link: Vec<IpCidr>,
+    routes: Vec<(IpCidr, IpAddress)>,
+}
+
+fn parse(text: &str) -> Config {
+    let mut config = Config::default();
+    for entry in text.split(|c| c == ',' || c == '\n').map(str::trim) {
+        if entry.is_empty() {
+            continue;
+        }
+        let words: Vec<&str> = entry.split_whitespace().collect();
+        let parsed = match words.as_slice() {
+            [network] => IpCidr::from_str(network).map(|network| config.on_link.push(network)),
+            [network, "via", gateway] => IpAddress::from_str(gateway).and_then(|gateway| {
+                let network = match *network {
+                    "default" => match gateway {
+                        IpAddress::Ipv6(_) => IpCidr::new(Ipv6Address::UNSPECIFIED.into(), 0),
+                        _ => IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0),
+                    },
+                    network => IpCidr::from_str(network)?,
+                };
+                config.routes.push((network, gateway));
+                Ok(())
+            }),
+            _ => Err(()),
+        };
+        if parsed.is_err() {
+            warn!("route \"{}\" not supported, ignoring", entry);
+        }
+    }
+    config
+}
+
+fn prefix_len(config: &Config, address: IpAddress) -> u8 {
+    if config.on_link.is_empty() && config.routes.is_empty() {
+        return 0;
+    }
+    if let IpAddress::Ipv6(address) = address {
+        if address.is_link_local() {
+            return 64;
+        }
+    }
+    match config.on_link.iter().find(|network| network.contains_addr(&address)) {
+        Some(network) => network.prefix_len(),
+        None => match address {
+            IpAddress::Ipv6(_) => 128,
+            _ => 32,
+        },
+    }
+}
+
+pub fn apply<DeviceT>(iface: &mut EthernetInterface<'_, DeviceT>)
+where DeviceT: for<'d> Device<'d> {
+    let config = match libconfig::read_str("routes") {
+        Ok(text) => parse(&text),
+        Err(_) => Config::default(),
+    };
+    iface.update_ip_addrs(|addrs| {
+        for cidr in addrs.iter_mut() {
+            *cidr = IpCidr::new(cidr.address(), prefix_len(&config, cidr.address()));
+        }
+    });
+    iface.routes_mut().update(|storage| {
+        storage.clear();
+        for &(network, gateway) in config.routes.iter() {
+            let route = Route {
+                via_router: gateway,
+                preferred_until: None,
+                expires_at: None,
+            };
+            if storage.insert(network, route).is_err() {
+                warn!("no room for the route to {}", network);
+            }
+        }
+    });
+    for &(network, gateway) in config.routes.iter() {
+        info!("route to {} via {}", network, gateway);
+    }
+}
+
+// Called when the `routes` key changes; the network loop then applies it.
+pub fn reload() {
+    RELOAD.store(true, Ordering::Relaxed);
+}
+
+pub fn reload_requested() -> bool {
+    RELOAD.swap(false, Ordering::Relaxed)
+}
@@ -0,0 +1,136 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+// Connection counters of the TCP services, reported through the management port
+// to tell apart an unreachable device from one that refuses or drops connections.
+// The socket states of each service and the frame counters of the Ethernet interface,
+// counted by the device wrapper of ping.rs, tell whether requests reach the stack at all.
+
+#[derive(Debug, Clone, Copy)]
+pub enum Service {
+    Mgmt = 0,
+    Coredev = 1,
+    Analyzer = 2,
+    Moninj = 3,
+}
+
+pub const SERVICES: [Service; 4] = [Service::Mgmt, Service::Coredev, Service::Analyzer, Service::Moninj];
+
+struct Counters {
+    listening: AtomicBool,
+    active: AtomicU32,
+    closing: AtomicU32,
+    accepted: AtomicU32,
+    dropped: AtomicU32,
+}
+
+static COUNTERS: [Counters; 4] = [const {
+    Counters {
+        listening: AtomicBool::new(false),
+        active: AtomicU32::new(0),
+        closing: AtomicU32::new(0),
+        accepted: AtomicU32::new(0),
+        dropped: AtomicU32::new(0),
+    }
+}; 4];
+
+static RX_FRAMES: AtomicU32 = AtomicU32::new(0);
+static RX_BYTES: AtomicU32 = AtomicU32::new(0);
+static TX_FRAMES: AtomicU32 = AtomicU32::new(0);
+static TX_BYTES: AtomicU32 = AtomicU32::new(0);
+static TX_EXHAUSTED: AtomicU32 = AtomicU32::new(0);
+
+// listening: the service has a socket waiting for a connection
+// active: established connections being served
+// closing: connections being flushed and aborted after they ended
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub port: u16,
+    pub listening: bool,
+    pub active: u32,
+    pub closing: u32,
+    pub accepted: u32,
+    pub dropped: u32,
+}
+
+// tx_exhausted: transmissions deferred because the device had no free descriptor
+#[derive(Debug, Clone, Copy)]
+pub struct Interface {
+    pub rx_frames: u32,
+    pub rx_bytes: u32,
+    pub tx_frames: u32,
+    pub tx_bytes: u32,
+    pub tx_exhausted: u32,
+}
+
+impl Service {
+    pub fn port(self) -> u16 {
+        match self {
+            Service::Mgmt => 1380,
+            Service::Coredev => 1381,
+            Service::Analyzer => 1382,
+            Service::Moninj => 1383,
+        }
+    }
+}
+
+pub fn listening(service: Service) {
+    COUNTERS[service as usize].listening.store(true, Ordering::Relaxed);
+}
+
+pub fn accepted(service: Service) {
+    let counters = &COUNTERS[service as usize];
+    counters.listening.store(false, Ordering::Relaxed);
+    counters.accepted.fetch_add(1, Ordering::Relaxed);
+    counters.active.fetch_add(1, Ordering::Relaxed);
+}
+
+// dropped: the connection ended on an error instead of being closed by the peer
+pub fn closed(service: Service, dropped: bool) {
+    let counters = &COUNTERS[service as usize];
+    counters.active.fetch_sub(1, Ordering::Relaxed);
+    counters.closing.fetch_add(1, Ordering::Relaxed);
+    if dropped {
+        counters.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// the socket of a closed connection was aborted
+pub fn released(service: Service) {
+    COUNTERS[service as usize].closing.fetch_sub(1, Ordering::Relaxed);
+}
+
+pub fn received(len: usize) {
+    RX_FRAMES.fetch_add(1, Ordering::Relaxed);
+    RX_BYTES.fetch_add(len as u32, Ordering::Relaxed);
+}
+
+pub fn transmitted(len: usize) {
+    TX_FRAMES.fetch_add(1, Ordering::Relaxed);
+    TX_BYTES.fetch_add(len as u32, Ordering::Relaxed);
+}
+
+pub fn tx_exhausted() {
+    TX_EXHAUSTED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn stats(service: Service) -> Stats {
+    let counters = &COUNTERS[service as usize];
+    Stats {
+        port: service.port(),
+        listening: counters.listening.load(Ordering::Relaxed),
+        active: counters.active.load(Ordering::Relaxed),
+        closing: counters.closing.load(Ordering::Relaxed),
+        accepted: counters.accepted.load(Ordering::Relaxed),
+        dropped: counters.dropped.load(Ordering::Relaxed),
+    }
+}
+
+pub fn interface() -> Interface {
+    Interface {
+        rx_frames: RX_FRAMES.load(Ordering::Relaxed),
+        rx_bytes: RX_BYTES.load(Ordering::Relaxed),
+        tx_frames: TX_FRAMES.load(Ordering::Relaxed),
+        tx_bytes: TX_BYTES.load(Ordering::Relaxed),
+        tx_exhausted: TX_EXHAUSTED.load(Ordering::Relaxed),
+    }
+}
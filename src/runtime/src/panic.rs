@@ -1,32 +1,43 @@
+use core::fmt::Write;
+
 #[cfg(feature = "target_kasli_soc")]
 use libboard_zynq::error_led::ErrorLED;
-use libboard_zynq::{print, println, timer};
+use ksupport::kernel::core1;
+use libboard_artiq::{console::PanicConsole, panic_report::PanicReport};
+use libboard_zynq::{slcr, timer};
 use libconfig;
 use libcortex_a9::regs::MPIDR;
 use libregister::RegisterR;
 use log::error;
 use unwind::backtrace;
 
-use crate::comms::soft_panic_main;
+use crate::{boot_slot, comms::soft_panic_main};
 
 static mut PANICKED: [bool; 2] = [false; 2];
 static mut SOFT_PANICKED: bool = false;
 
+#[no_mangle]
+pub extern "C" fn exception(vect: u32, regs: *const u32, pc: u32, ea: u32) {
+    // faults of a running kernel are reported like its exceptions
+    core1::kernel_fault(vect, regs, pc, ea);
+    panic!("exception at PC 0x{:x}, EA 0x{:x}", pc, ea)
+}
+
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     let id = MPIDR.read().cpu_id() as usize;
     let soft_panicked = unsafe { SOFT_PANICKED };
-    print!("Core {} panic at ", id);
+    let _ = write!(PanicConsole, "Core {} panic at ", id);
     if let Some(location) = info.location() {
-        print!("{}:{}:{}", location.file(), location.line(), location.column());
+        let _ = write!(PanicConsole, "{}:{}:{}", location.file(), location.line(), location.column());
     } else {
-        print!("unknown location");
+        let _ = write!(PanicConsole, "unknown location");
     }
-    println!(": {}", info.message());
+    let _ = writeln!(PanicConsole, ": {}", info.message());
     unsafe {
         // soft panics only allowed for core 0
         if PANICKED[id] && (SOFT_PANICKED || id == 1) {
-            println!("nested panic!");
+            let _ = writeln!(PanicConsole, "nested panic!");
             loop {}
         }
         SOFT_PANICKED = true;
@@ -37,13 +48,16 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
         let mut err_led = ErrorLED::error_led();
         err_led.toggle(true);
     }
-    println!("Backtrace: ");
+    let mut report = PanicReport::new(id, info);
+    let _ = writeln!(PanicConsole, "Backtrace: ");
     let _ = backtrace(|ip| {
         // Backtrace gives us the return address, i.e. the address after the delay slot,
         // but we're interested in the call instruction.
-        print!("{:#08x} ", ip - 2 * 4);
+        let _ = write!(PanicConsole, "{:#08x} ", ip - 2 * 4);
+        report.add_frame(ip - 2 * 4);
     });
-    println!("\nEnd backtrace");
+    let _ = writeln!(PanicConsole, "\nEnd backtrace");
+    report.save();
     if !soft_panicked && id == 0 {
         soft_panic(info);
     }
@@ -60,6 +74,10 @@ fn soft_panic(info: &core::panic::PanicInfo) -> ! {
     error!("panic message: {}", info.message());
     timer::start();
     let _ = libconfig::init();
+    if boot_slot::rollback() {
+        log::logger().flush();
+        slcr::reboot();
+    }
     soft_panic_main();
 }
 
@@ -0,0 +1,217 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use libboard_zynq::{smoltcp::{self,
+                              phy::{self, DeviceCapabilities},
+                              time::Instant,
+                              wire::{EthernetFrame, EthernetProtocol, Icmpv4Message, Icmpv4Packet, Icmpv6Message,
+                                     Icmpv6Packet, IpProtocol, Ipv4Packet, Ipv6Packet}},
+                    timer};
+use libconfig;
+use libcortex_a9::mutex::Mutex;
+use log::{info, warn};
+
+use crate::{moninj_udp, net_stats};
+
+// Echo requests are answered by the network stack itself. The Ethernet device is wrapped to
+// count them, and to send the replies with the hop limit of the `icmp_ttl` config key, so that
+// replies of the firmware can be told apart from those of a switch or a host on the way.
+// The payload of the replies is left as received, ping tools check it against their requests.
+
+const DEFAULT_HOP_LIMIT: u8 = 64;
+
+static ECHO_REQUESTS: AtomicU32 = AtomicU32::new(0);
+
+struct LoopTiming {
+    last_poll_us: u64,
+    max_gap_us: u64,
+}
+
+static LOOP_TIMING: Mutex<LoopTiming> = Mutex::new(LoopTiming {
+    last_poll_us: 0,
+    max_gap_us: 0,
+});
+
+fn read_hop_limit() -> u8 {
+    let text = match libconfig::read_str("icmp_ttl") {
+        Ok(text) => text,
+        Err(_) => return DEFAULT_HOP_LIMIT,
+    };
+    match text.trim().parse::<u8>() {
+        Ok(hop_limit) if hop_limit > 0 => {
+            info!("ICMP echo replies sent with TTL {}", hop_limit);
+            hop_limit
+        }
+        _ => {
+            warn!("icmp_ttl \"{}\" not supported, ignoring", text);
+            DEFAULT_HOP_LIMIT
+        }
+    }
+}
+
+fn is_echo_request(frame: &[u8]) -> bool {
+    let frame = match EthernetFrame::new_checked(frame) {
+        Ok(frame) => frame,
+        Err(_) => return false,
+    };
+    match frame.ethertype() {
+        EthernetProtocol::Ipv4 => match Ipv4Packet::new_checked(frame.payload()) {
+            Ok(packet) if packet.protocol() == IpProtocol::Icmp => Icmpv4Packet::new_checked(packet.payload())
+                .map_or(false, |icmp| icmp.msg_type() == Icmpv4Message::EchoRequest),
+            _ => false,
+        },
+        EthernetProtocol::Ipv6 => match Ipv6Packet::new_checked(frame.payload()) {
+            Ok(packet) if packet.next_header() == IpProtocol::Icmpv6 => Icmpv6Packet::new_checked(packet.payload())
+                .map_or(false, |icmp| icmp.msg_type() == Icmpv6Message::EchoRequest),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn set_echo_reply_hop_limit(frame: &mut [u8], hop_limit: u8) {
+    let mut frame = match EthernetFrame::new_checked(frame) {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+    match frame.ethertype() {
+        EthernetProtocol::Ipv4 => {
+            if let Ok(mut packet) = Ipv4Packet::new_checked(frame.payload_mut()) {
+                let reply = packet.protocol() == IpProtocol::Icmp
+                    && Icmpv4Packet::new_checked(packet.payload())
+                        .map_or(false, |icmp| icmp.msg_type() == Icmpv4Message::EchoReply);
+                if reply {
+                    packet.set_hop_limit(hop_limit);
+                    packet.fill_checksum();
+                }
+            }
+        }
+        EthernetProtocol::Ipv6 => {
+            if let Ok(mut packet) = Ipv6Packet::new_checked(frame.payload_mut()) {
+                let reply = packet.next_header() == IpProtocol::Icmpv6
+                    && Icmpv6Packet::new_checked(packet.payload())
+                        .map_or(false, |icmp| icmp.msg_type() == Icmpv6Message::EchoReply);
+                if reply {
+                    packet.set_hop_limit(hop_limit);
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+pub struct Device<D> {
+    inner: D,
+    hop_limit: u8,
+}
+
+impl<D> Device<D> {
+    pub fn new(inner: D) -> Device<D> {
+        Device {
+            inner: inner,
+            hop_limit: read_hop_limit(),
+        }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+}
+
+impl<'a, D: phy::Device<'a>> phy::Device<'a> for Device<D> {
+    type RxToken = RxToken<D::RxToken>;
+    type TxToken = TxToken<D::TxToken>;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let hop_limit = self.hop_limit;
+        self.inner.receive().map(|(rx, tx)| {
+            (
+                RxToken { inner: rx },
+                TxToken {
+                    inner: tx,
+                    hop_limit: hop_limit,
+                },
+            )
+        })
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        let hop_limit = self.hop_limit;
+        let token = self.inner.transmit().map(|tx| TxToken {
+            inner: tx,
+            hop_limit: hop_limit,
+        });
+        if token.is_none() {
+            net_stats::tx_exhausted();
+        }
+        token
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+}
+
+pub struct RxToken<T> {
+    inner: T,
+}
+
+impl<T: phy::RxToken> phy::RxToken for RxToken<T> {
+    fn consume<R, F>(self, timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where F: FnOnce(&mut [u8]) -> smoltcp::Result<R> {
+        self.inner.consume(timestamp, |buffer| {
+            net_stats::received(buffer.len());
+            if is_echo_request(buffer) {
+                ECHO_REQUESTS.fetch_add(1, Ordering::Relaxed);
+            }
+            if moninj_udp::intercept(buffer) {
+                return Err(smoltcp::Error::Dropped);
+            }
+            f(buffer)
+        })
+    }
+}
+
+pub struct TxToken<T> {
+    inner: T,
+    hop_limit: u8,
+}
+
+impl<T: phy::TxToken> phy::TxToken for TxToken<T> {
+    fn consume<R, F>(self, timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where F: FnOnce(&mut [u8]) -> smoltcp::Result<R> {
+        let hop_limit = self.hop_limit;
+        net_stats::transmitted(len);
+        self.inner.consume(timestamp, len, |buffer| {
+            let result = f(buffer);
+            if hop_limit != DEFAULT_HOP_LIMIT {
+                set_echo_reply_hop_limit(buffer, hop_limit);
+            }
+            result
+        })
+    }
+}
+
+/// Echo requests received since boot, over IPv4 and IPv6.
+pub fn echo_requests() -> u32 {
+    ECHO_REQUESTS.load(Ordering::Relaxed)
+}
+
+/// Called by the main loop each time it polls the network stack.
+pub fn polled() {
+    let now = timer::get_us();
+    let mut timing = LOOP_TIMING.lock();
+    if timing.last_poll_us != 0 {
+        timing.max_gap_us = timing.max_gap_us.max(now - timing.last_poll_us);
+    }
+    timing.last_poll_us = now;
+}
+
+/// Returns the time since the last poll of the network stack, and the longest time between
+/// two polls since the previous call, in microseconds.
+pub fn loop_timing() -> (u64, u64) {
+    let now = timer::get_us();
+    let mut timing = LOOP_TIMING.lock();
+    let since_poll = now.saturating_sub(timing.last_poll_us);
+    let max_gap = core::mem::replace(&mut timing.max_gap_us, 0);
+    (since_poll, max_gap)
+}
@@ -1,4 +1,4 @@
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
 use core::future::Future;
 
 use async_recursion::async_recursion;
@@ -136,9 +136,10 @@ where
                 // with alignment larger than 8 bytes, so storage_offset == 0 always.)
                 let list_size = 4 + 4;
                 let storage_offset = round_up(list_size, tag.alignment());
-                let storage_size = tag.size() * length;
+                let storage_size = tag.size().checked_mul(length).ok_or(smoltcp::Error::Malformed)?;
 
-                let allocation = alloc(storage_offset + storage_size).await as *mut u8;
+                let allocation_size = storage_offset.checked_add(storage_size).ok_or(smoltcp::Error::Malformed)?;
+                let allocation = alloc(allocation_size).await as *mut u8;
                 *ptr_to_list = allocation as *mut List;
                 let storage = allocation.offset(storage_offset as isize) as *mut ();
 
@@ -154,16 +155,47 @@ where
                 let mut total_len: usize = 1;
                 for _ in 0..num_dims {
                     let len = proto_async::read_i32(stream).await? as usize;
-                    total_len *= len;
+                    total_len = total_len.checked_mul(len).ok_or(smoltcp::Error::Malformed)?;
                     consume_value!(usize, |ptr| *ptr = len)
                 }
 
                 // Allocate backing storage for elements; deserialize them.
                 let elt_tag = it.clone().next().expect("truncated tag");
-                *buffer = alloc(elt_tag.size() * total_len).await;
+                let size = elt_tag.size().checked_mul(total_len).ok_or(smoltcp::Error::Malformed)?;
+                *buffer = alloc(size).await;
                 recv_elements(stream, elt_tag, total_len, *buffer, alloc).await
             })
         }
+        Tag::StridedArray(it, num_dims) => {
+            consume_value!(*mut (), |buffer| {
+                // Same layout as an array in kernel memory, but the host sends the span
+                // of the view it was sliced from, so it does not need to copy it first.
+                let mut dims = Vec::with_capacity(num_dims as usize);
+                for _ in 0..num_dims {
+                    let len = proto_async::read_i32(stream).await? as usize;
+                    dims.push(len);
+                    consume_value!(usize, |ptr| *ptr = len)
+                }
+                let mut strides = Vec::with_capacity(num_dims as usize);
+                for _ in 0..num_dims {
+                    strides.push(proto_async::read_i32(stream).await? as isize);
+                }
+                let offset = proto_async::read_i32(stream).await? as isize;
+                let span_len = proto_async::read_i32(stream).await? as usize;
+
+                let elt_tag = it.clone().next().expect("truncated tag");
+                let (size, span_size) = strided_sizes(elt_tag.size(), &dims, &strides, offset, span_len)
+                    .ok_or(smoltcp::Error::Malformed)?;
+                *buffer = alloc(size).await;
+                let mut span = span_storage(span_size);
+                recv_elements(stream, elt_tag, span_len, span.as_mut_ptr() as *mut (), alloc).await?;
+                let span = core::slice::from_raw_parts(span.as_ptr() as *const u8, span_size);
+                if !gather_strided(*buffer as *mut u8, span, elt_tag.size(), &dims, &strides, offset) {
+                    return Err(smoltcp::Error::Malformed);
+                }
+                Ok(())
+            })
+        }
         Tag::Range(it) => {
             *data = round_up_mut(*data, tag.alignment());
             let tag = it.clone().next().expect("truncated tag");
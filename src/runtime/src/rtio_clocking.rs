@@ -1,5 +1,6 @@
 #[cfg(not(feature = "target_ebaz4205"))]
 use libboard_artiq::pl;
+use libboard_artiq::rtio_frequency::{self, RtioFrequency};
 #[cfg(has_si549)]
 use libboard_artiq::si549;
 #[cfg(has_si5324)]
@@ -9,6 +10,8 @@ use libboard_zynq::i2c::I2c;
 use libboard_zynq::timer;
 use libconfig;
 use log::{info, warn};
+
+use crate::events::{self, Event};
 #[cfg(feature = "target_ebaz4205")]
 use {libboard_zynq::slcr, libregister::RegisterRW};
 
@@ -26,7 +29,6 @@ pub enum RtioClock {
     Ext0_Synth0_125to125,
 }
 
-#[allow(unreachable_code)]
 fn get_rtio_clock_cfg() -> RtioClock {
     let mut res = RtioClock::Default;
     if let Ok(clk) = libconfig::read_str("rtio_clock") {
@@ -49,22 +51,26 @@ fn get_rtio_clock_cfg() -> RtioClock {
     } else {
         warn!("error reading configuration. Falling back to default.");
     }
+    let frequency = rtio_frequency::get();
     if res == RtioClock::Default {
-        #[cfg(rtio_frequency = "100.0")]
-        {
-            warn!("Using default configuration - internal 100MHz RTIO clock.");
-            return RtioClock::Int_100;
-        }
-        #[cfg(rtio_frequency = "125.0")]
-        {
-            warn!("Using default configuration - internal 125MHz RTIO clock.");
-            return RtioClock::Int_125;
-        }
-        // anything else
-        {
-            warn!("Using default configuration - internal 125MHz RTIO clock.");
-            return RtioClock::Int_125;
-        }
+        return match frequency {
+            RtioFrequency::Mhz100 => {
+                warn!("Using default configuration - internal 100MHz RTIO clock.");
+                RtioClock::Int_100
+            }
+            RtioFrequency::Mhz125 => {
+                warn!("Using default configuration - internal 125MHz RTIO clock.");
+                RtioClock::Int_125
+            }
+        };
+    }
+    let clock_mhz = match res {
+        RtioClock::Int_100 => Some(100),
+        RtioClock::Int_150 | RtioClock::Ext0_Bypass => None,
+        _ => Some(125),
+    };
+    if clock_mhz.is_some_and(|mhz| mhz != frequency.mhz()) {
+        warn!("rtio_clock setting '{:?}' does not match the {} MHz RTIO frequency", res, frequency.mhz());
     }
     res
 }
@@ -105,11 +111,8 @@ fn init_drtio() {
     }
     unsafe {
         pl::csr::rtio_core::reset_phy_write(1);
-        pl::csr::gt_drtio::txenable_write(0xffffffffu32 as _);
-
-        #[cfg(has_drtio_eem)]
-        pl::csr::eem_transceiver::txenable_write(0xffffffffu32 as _);
     }
+    crate::rtio_mgt::drtio::init_txenable();
 }
 
 // Si5324 input to select for locking to an external clock.
@@ -260,6 +263,9 @@ fn setup_si5324(i2c: &mut I2c, clk: RtioClock) {
         }
     };
     si5324::setup(i2c, &si5324_settings, si5324_ref_input).expect("cannot initialize Si5324");
+    if let Err(e) = si5324::configure_holdover(i2c) {
+        warn!("failed to configure Si5324 holdover: {}", e);
+    }
 }
 
 #[cfg(all(has_si549, has_wrpll))]
@@ -487,4 +493,10 @@ pub fn init() {
             _ => {}
         }
     }
+
+    let mhz = match clk {
+        RtioClock::Int_150 => 150,
+        _ => rtio_frequency::get().mhz(),
+    };
+    events::push(Event::ClockSwitched { mhz: mhz });
 }
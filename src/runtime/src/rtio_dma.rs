@@ -2,7 +2,7 @@ use alloc::{collections::BTreeMap, string::String, vec::Vec};
 #[cfg(has_drtio)]
 use core::mem;
 
-use ksupport::kernel::DmaRecorder;
+use ksupport::kernel::{DmaPlaybackStat, DmaRecorder, DmaTraceStat, trace_stat};
 #[cfg(has_drtio)]
 use libasync::task;
 use libcortex_a9::{cache::dcci_slice, mutex::Mutex};
@@ -14,6 +14,7 @@ static DMA_RECORD_STORE: Mutex<BTreeMap<String, (u32, Vec<u8>, i64)>> = Mutex::n
 #[allow(static_mut_refs)]
 #[cfg(has_drtio)]
 pub mod remote_dma {
+    use libboard_artiq::{drtio_routing, drtioaux_proto::DESTINATION_MASK_SIZE};
     use libboard_zynq::timer;
     use log::error;
 
@@ -24,7 +25,12 @@ pub mod remote_dma {
     pub enum RemoteState {
         NotLoaded,
         Loaded,
-        PlaybackEnded { error: u8, channel: u32, timestamp: u64 },
+        PlaybackEnded {
+            error: u8,
+            channel: u32,
+            timestamp: u64,
+            stat: DmaPlaybackStat,
+        },
     }
     #[derive(Debug, Clone)]
     struct RemoteTrace {
@@ -51,6 +57,8 @@ pub mod remote_dma {
     struct TraceSet {
         id: u32,
         done_count: Mutex<usize>,
+        // destinations that started the last playback, and are expected to report its status
+        started_count: Mutex<usize>,
         traces: Mutex<BTreeMap<u8, RemoteTrace>>,
     }
 
@@ -63,6 +71,7 @@ pub mod remote_dma {
             TraceSet {
                 id: id,
                 done_count: Mutex::new(0),
+                started_count: Mutex::new(0),
                 traces: Mutex::new(trace_map),
             }
         }
@@ -71,7 +80,7 @@ pub mod remote_dma {
             let timeout_ms = timeout.unwrap_or(10_000);
             let max_time = timer::get_ms() + timeout_ms;
             while (timer::get_ms() < max_time)
-                & (*(self.done_count.async_lock().await) < self.traces.async_lock().await.len())
+                & (*(self.done_count.async_lock().await) < *(self.started_count.async_lock().await))
             {
                 task::r#yield().await;
             }
@@ -83,7 +92,9 @@ pub mod remote_dma {
                 error: 0,
                 channel: 0,
                 timestamp: 0,
+                stat: DmaPlaybackStat::default(),
             };
+            let mut total_stat: Option<DmaPlaybackStat> = None;
             let mut lock = self.traces.async_lock().await;
             let trace_iter = lock.iter_mut();
             for (_dest, trace) in trace_iter {
@@ -92,15 +103,20 @@ pub mod remote_dma {
                         error: e,
                         channel: _c,
                         timestamp: _ts,
+                        stat,
                     } => {
                         if e != 0 {
                             playback_state = trace.state.clone();
                         }
+                        total_stat = Some(total_stat.map_or(stat, |total| total.combine(stat)));
                     }
                     _ => (),
                 }
                 trace.state = RemoteState::Loaded;
             }
+            if let RemoteState::PlaybackEnded { ref mut stat, .. } = playback_state {
+                *stat = total_stat.unwrap_or_default();
+            }
             Ok(playback_state)
         }
 
@@ -127,23 +143,35 @@ pub mod remote_dma {
             }
         }
 
-        pub async fn playback_done(&mut self, source: u8, error: u8, channel: u32, timestamp: u64) {
+        pub async fn playback_done(
+            &mut self,
+            source: u8,
+            error: u8,
+            channel: u32,
+            timestamp: u64,
+            stat: DmaPlaybackStat,
+        ) {
             let mut traces_locked = self.traces.async_lock().await;
             let trace = traces_locked.get_mut(&source).unwrap();
             trace.state = RemoteState::PlaybackEnded {
                 error: error,
                 channel: channel,
                 timestamp: timestamp,
+                stat: stat,
             };
             *(self.done_count.async_lock().await) += 1;
         }
 
-        pub async fn playback(&self, timestamp: u64) {
+        // `selected` limits the playback to the destinations of a group
+        pub async fn playback(&self, timestamp: u64, selected: Option<[u8; DESTINATION_MASK_SIZE]>) {
             let mut dest_list: Vec<u8> = Vec::new();
             {
                 let lock = self.traces.async_lock().await;
                 let trace_iter = lock.iter();
                 for (dest, trace) in trace_iter {
+                    if selected.map_or(false, |selected| !drtio_routing::mask_contains(&selected, *dest)) {
+                        continue;
+                    }
                     if trace.state != RemoteState::Loaded {
                         error!("Destination {} not ready for DMA, state: {:?}", dest, trace.state);
                         continue;
@@ -153,12 +181,31 @@ pub mod remote_dma {
             }
             // mutex lock must be dropped before sending a playback request to avoid a deadlock,
             // if PlaybackStatus is sent from another satellite and the state must be updated.
-            for destination in dest_list {
-                match drtio::ddma_send_playback(self.id, destination, timestamp).await {
-                    Ok(_) => (),
-                    Err(e) => error!("Error during remote DMA playback: {}", e),
+            *(self.done_count.async_lock().await) = 0;
+            let mut started = 0;
+            if dest_list.len() > 1 {
+                // one request per link instead of a round trip per destination
+                let mut targets = [0; DESTINATION_MASK_SIZE];
+                for destination in dest_list.iter() {
+                    drtio_routing::mask_insert(&mut targets, *destination);
+                }
+                let succeeded = drtio::ddma_send_playback_broadcast(self.id, &targets, timestamp).await;
+                for destination in dest_list {
+                    if drtio_routing::mask_contains(&succeeded, destination) {
+                        started += 1;
+                    } else {
+                        error!("Remote DMA playback did not start on destination {}", destination);
+                    }
+                }
+            } else {
+                for destination in dest_list {
+                    match drtio::ddma_send_playback(self.id, destination, timestamp).await {
+                        Ok(_) => started += 1,
+                        Err(e) => error!("Error during remote DMA playback: {}", e),
+                    }
                 }
             }
+            *(self.started_count.async_lock().await) = started;
         }
 
         pub async fn destination_changed(&mut self, destination: u8, up: bool) {
@@ -175,6 +222,29 @@ pub mod remote_dma {
             }
         }
 
+        // totals of the parts confirmed by their destination, and the count of those that did not
+        pub async fn stat(&self) -> (usize, usize, usize) {
+            let (mut size, mut events, mut missing) = (0, 0, 0);
+            let lock = self.traces.async_lock().await;
+            for destination in lock.keys() {
+                match drtio::ddma_stat(self.id, *destination).await {
+                    Ok(Some((trace_size, trace_events))) => {
+                        size += trace_size as usize;
+                        events += trace_events as usize;
+                    }
+                    Ok(None) => {
+                        error!("DMA trace not loaded on destination {}", destination);
+                        missing += 1;
+                    }
+                    Err(e) => {
+                        error!("Error querying DMA trace on destination {}: {}", destination, e);
+                        missing += 1;
+                    }
+                }
+            }
+            (size, events, missing)
+        }
+
         pub async fn is_empty(&self) -> bool {
             self.traces.async_lock().await.is_empty()
         }
@@ -204,14 +274,23 @@ pub mod remote_dma {
         trace_set.upload_traces().await;
     }
 
-    pub async fn playback(id: u32, timestamp: u64) {
+    pub async fn playback(id: u32, timestamp: u64, targets: Option<[u8; DESTINATION_MASK_SIZE]>) {
         let trace_set = unsafe { TRACES.get_mut(&id).unwrap() };
-        trace_set.playback(timestamp).await;
+        trace_set.playback(timestamp, targets).await;
     }
 
-    pub async fn playback_done(id: u32, destination: u8, error: u8, channel: u32, timestamp: u64) {
+    pub async fn playback_done(
+        id: u32,
+        destination: u8,
+        error: u8,
+        channel: u32,
+        timestamp: u64,
+        stat: DmaPlaybackStat,
+    ) {
         let trace_set = unsafe { TRACES.get_mut(&id).unwrap() };
-        trace_set.playback_done(destination, error, channel, timestamp).await;
+        trace_set
+            .playback_done(destination, error, channel, timestamp, stat)
+            .await;
     }
 
     pub async fn destination_changed(destination: u8, up: bool) {
@@ -221,6 +300,11 @@ pub mod remote_dma {
         }
     }
 
+    pub async fn stat(id: u32) -> (usize, usize, usize) {
+        let trace_set = unsafe { TRACES.get_mut(&id).unwrap() };
+        trace_set.stat().await
+    }
+
     pub async fn has_remote_traces(id: u32) -> bool {
         let trace_set = unsafe { TRACES.get_mut(&id).unwrap() };
         !(trace_set.is_empty().await)
@@ -300,3 +384,23 @@ pub async fn retrieve(name: String) -> Option<(i32, i64, bool)> {
     let uses_ddma = false;
     Some((ptr as i32, duration, uses_ddma))
 }
+
+pub async fn stat(name: String) -> Option<DmaTraceStat> {
+    let (_id, size, events, duration) = {
+        let store = DMA_RECORD_STORE.lock();
+        let (ptr, buffer, duration) = store.get(&name)?;
+        let padding = *ptr as usize - buffer.as_ptr() as usize;
+        let (size, events) = trace_stat(&buffer[padding..]);
+        (*ptr, size, events, *duration)
+    };
+    #[cfg(has_drtio)]
+    let (remote_size, remote_events, missing) = remote_dma::stat(_id).await;
+    #[cfg(not(has_drtio))]
+    let (remote_size, remote_events, missing) = (0, 0, 0);
+    Some(DmaTraceStat {
+        duration: duration,
+        size: size + remote_size,
+        events: events + remote_events,
+        missing: missing,
+    })
+}
@@ -7,24 +7,37 @@ use log::{info, warn};
 
 #[cfg(has_drtio)]
 pub mod drtio {
-    use alloc::vec::Vec;
-    use core::fmt;
+    use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+    use core::{cmp::min, fmt, future::Future, pin::Pin};
 
-    use ksupport::kernel::Message as KernelMessage;
+    use futures::{future::poll_fn, task::Poll};
+
+    use ksupport::kernel::{DmaPlaybackStat, Message as KernelMessage, RtioResetPolicy, StorageStats};
     use libasync::task;
     #[cfg(has_drtio_eem)]
     use libboard_artiq::drtio_eem;
-    use libboard_artiq::{drtioaux::Error as DrtioError,
+    use libboard_artiq::{drtioaux,
+                         drtioaux::{AUX_RETRANSMIT_LIMIT, Error as DrtioError},
                          drtioaux_async,
                          drtioaux_async::Packet,
-                         drtioaux_proto::{MASTER_PAYLOAD_MAX_SIZE, PayloadStatus},
-                         resolve_channel_name};
+                         drtioaux_proto::{DATASET_NAME_MAX_SIZE, DESTINATION_MASK_SIZE, FEATURE_FRAMING,
+                                          FEATURE_INPUT_BATCH, FEATURE_LATENCY_PROBE,
+                                          FEATURE_REPEATER_STATS, FEATURE_ROUTER_STATS,
+                                          FEATURE_STORAGE_STATS, GRABBER_ROI_COUNT,
+                                          MASTER_PAYLOAD_MAX_SIZE,
+                                          PROTOCOL_VERSION,
+                                          PayloadStatus, REPEATER_HISTORY_SIZE, REPEATER_UP, RTIO_INPUT_BATCH_SIZE,
+                                          SAT_PAYLOAD_MAX_SIZE,
+                                          SUBKERNEL_MESSAGE_WINDOW, local_features},
+                         error_log, resolve_channel_name, wall_clock};
     use libboard_zynq::timer;
     use libcortex_a9::mutex::Mutex;
     use log::{error, info, warn};
 
     use super::*;
     use crate::{analyzer::remote_analyzer::RemoteBuffer,
+                aux_timeout,
+                events::{self, Event},
                 comms::{ASYNC_ERROR_BUSY, ASYNC_ERROR_COLLISION, ASYNC_ERROR_SEQUENCE_ERROR, ROUTING_TABLE,
                         SEEN_ASYNC_ERRORS},
                 rtio_dma::remote_dma,
@@ -33,13 +46,138 @@ pub mod drtio {
     #[cfg(has_drtio_eem)]
     const DRTIO_EEM_LINKNOS: core::ops::Range<usize> =
         (csr::DRTIO.len() - csr::CONFIG_EEM_DRTIO_COUNT as usize)..csr::DRTIO.len();
+    // EEM transceiver settings found by the link task are written to the config at this interval
+    #[cfg(has_drtio_eem)]
+    const EEM_STORE_INTERVAL_MS: u64 = 1_000;
+
+    // one per link, so that transactions on different links can be interleaved
+    const AUX_MUTEX_INIT: Mutex<bool> = Mutex::new(false);
+    pub static AUX_MUTEX: [Mutex<bool>; csr::DRTIO.len()] = [AUX_MUTEX_INIT; csr::DRTIO.len()];
+    // links held down by the `drtio_disabled_links` config key or over mgmt, one bit per link
+    static DISABLED_LINKS: Mutex<u32> = Mutex::new(0);
+    // set by the `rtio_reset_policy` config key, used unless the kernel asks for another one
+    static RESET_POLICY: Mutex<RtioResetPolicy> = Mutex::new(RtioResetPolicy::Full);
+
+    // credit updates from satellites that ran out of message buffer space, by source destination
+    static MESSAGE_CREDITS: Mutex<BTreeMap<u8, u8>> = Mutex::new(BTreeMap::new());
+    // how long a message sender waits for a stalled receiver to free up space
+    const MESSAGE_CREDIT_TIMEOUT: u64 = 10_000;
+    // requests of a subkernel exception slice that got lost before giving up
+    const EXCEPTION_SLICE_RETRIES: u32 = 5;
+    // version requests that time out before the link is retried from the ping
+    const VERSION_CHECK_RETRIES: u32 = 3;
+
+    // warn when a satellite's minimum observed CRI buffer space drops to this level
+    const BUFFER_SPACE_WARN_LEVEL: u16 = 8;
+    // (current, minimum observed) buffer space last reported by each destination
+    static BUFFER_SPACE: Mutex<BTreeMap<u8, (u16, u16)>> = Mutex::new(BTreeMap::new());
+    // generation of the wall clock last pushed to each destination, 0 if none
+    static WALL_CLOCK_SENT: Mutex<[u32; drtio_routing::DEST_COUNT]> = Mutex::new([0; drtio_routing::DEST_COUNT]);
+    // identifies this run of the master firmware to the satellites, 0 until taken
+    static SESSION_EPOCH: Mutex<u32> = Mutex::new(0);
+    // satellites measure their TSC drift against the probes sent at this interval
+    const TSC_DRIFT_PROBE_INTERVAL_MS: u64 = 10_000;
+    // (time of the last probe in ms, last aux round trip in RTIO counter units) for each destination
+    static TSC_DRIFT_PROBES: Mutex<BTreeMap<u8, (u64, u64)>> = Mutex::new(BTreeMap::new());
+    // aux round trips are measured at this interval for each destination
+    const LATENCY_PROBE_INTERVAL_MS: u64 = 1_000;
+    static LATENCY: Mutex<BTreeMap<u8, LatencyStats>> = Mutex::new(BTreeMap::new());
+    // forwarding counters last reported by each destination, fetched along with the latency
+    static ROUTER_STATS: Mutex<BTreeMap<u8, RouterStats>> = Mutex::new(BTreeMap::new());
+    // link counters and transitions of the repeaters of each destination, fetched along with the latency
+    static REPEATER_STATS: Mutex<BTreeMap<u8, Vec<RepeaterStats>>> = Mutex::new(BTreeMap::new());
+
+    // Packets a satellite forwarded to the satellites behind it, summed over its repeaters:
+    // packets waiting now and at most, and packets dropped on a full queue or once too old.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct RouterStats {
+        pub forwarded: u32,
+        pub queued: u16,
+        pub max_queued: u16,
+        pub dropped_full: u32,
+        pub dropped_aged: u32,
+    }
+
+    // Link of a satellite to the satellite behind one of its repeaters, since the satellite booted:
+    // its state, the times it came up and went down, aux packets it could not send and forwarded
+    // requests left without a reply, then the (state entered, ms ago) of the last transitions,
+    // newest first. States are the REPEATER_* constants of the aux protocol.
+    #[derive(Debug, Clone, Default)]
+    pub struct RepeaterStats {
+        pub state: u8,
+        pub up_transitions: u32,
+        pub down_transitions: u32,
+        pub aux_send_failures: u32,
+        pub forward_timeouts: u32,
+        pub history: Vec<(u8, u32)>,
+    }
+
+    // Aux round trip latency of a destination, in RTIO counter units, since it came up,
+    // and when it was last probed, whether the probe succeeded or not.
+    #[derive(Debug, Clone, Copy)]
+    pub struct LatencyStats {
+        pub last_probe_ms: u64,
+        pub samples: u32,
+        pub last: u64,
+        pub min: u64,
+        pub max: u64,
+        total: u64,
+    }
+
+    impl LatencyStats {
+        fn new() -> LatencyStats {
+            LatencyStats {
+                last_probe_ms: timer::get_ms(),
+                samples: 0,
+                last: 0,
+                min: 0,
+                max: 0,
+                total: 0,
+            }
+        }
+
+        fn record(&mut self, round_trip: u64) {
+            self.min = if self.samples == 0 { round_trip } else { self.min.min(round_trip) };
+            self.samples += 1;
+            self.last = round_trip;
+            self.max = self.max.max(round_trip);
+            self.total += round_trip;
+        }
+
+        pub fn average(&self) -> u64 {
+            if self.samples == 0 { 0 } else { self.total / self.samples as u64 }
+        }
+    }
 
-    pub static AUX_MUTEX: Mutex<bool> = Mutex::new(false);
+    fn update_buffer_space(destination: u8, buffer_space: u16, min_buffer_space: u16) {
+        let previous = BUFFER_SPACE
+            .lock()
+            .insert(destination, (buffer_space, min_buffer_space));
+        let previous_min = previous.map_or(u16::MAX, |(_, min)| min);
+        if min_buffer_space <= BUFFER_SPACE_WARN_LEVEL && min_buffer_space < previous_min {
+            warn!(
+                "[DEST#{}] CRI buffer space dropped to {} (currently {}), close to buffer space timeouts",
+                destination, min_buffer_space, buffer_space
+            );
+        }
+    }
+
+    // result of the version handshake of each link, reported through mgmt
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum LinkCompat {
+        Unknown,
+        Compatible { version: u16, features: u32 },
+        Incompatible { version: u16 },
+    }
+
+    pub static LINK_COMPAT: Mutex<[LinkCompat; csr::DRTIO.len()]> =
+        Mutex::new([LinkCompat::Unknown; csr::DRTIO.len()]);
 
     #[derive(Debug, PartialEq, Eq, Clone, Copy)]
     pub enum Error {
         Timeout,
         AuxError,
+        PacketDamaged,
         LinkDown,
         UnexpectedReply,
         DmaAddTraceFail(u8),
@@ -47,6 +185,8 @@ pub mod drtio {
         DmaPlaybackFail(u8),
         SubkernelAddFail(u8),
         SubkernelRunFail(u8),
+        IncompatibleVersion(u16),
+        Unsupported,
     }
 
     impl fmt::Display for Error {
@@ -54,6 +194,7 @@ pub mod drtio {
             match self {
                 Error::Timeout => write!(f, "timed out"),
                 Error::AuxError => write!(f, "aux packet error"),
+                Error::PacketDamaged => write!(f, "aux packet damaged in transit"),
                 Error::LinkDown => write!(f, "link down"),
                 Error::UnexpectedReply => write!(f, "unexpected reply"),
                 Error::DmaAddTraceFail(dest) => write!(f, "error adding DMA trace on satellite #{}", dest),
@@ -61,6 +202,12 @@ pub mod drtio {
                 Error::DmaPlaybackFail(dest) => write!(f, "error playing back DMA trace on satellite #{}", dest),
                 Error::SubkernelAddFail(dest) => write!(f, "error adding subkernel on satellite #{}", dest),
                 Error::SubkernelRunFail(dest) => write!(f, "error on subkernel run request on satellite #{}", dest),
+                Error::IncompatibleVersion(version) => write!(
+                    f,
+                    "incompatible aux protocol version {} (master is {})",
+                    version, PROTOCOL_VERSION
+                ),
+                Error::Unsupported => write!(f, "not supported by the firmware on the link"),
             }
         }
     }
@@ -71,14 +218,99 @@ pub mod drtio {
         }
     }
 
+    // Whether the peer on the link advertised the feature when the link came up.
+    pub fn link_supports(linkno: u8, feature: u32) -> bool {
+        match LINK_COMPAT.lock()[linkno as usize] {
+            LinkCompat::Compatible { features, .. } => features & feature != 0,
+            _ => false,
+        }
+    }
+
     pub fn startup(up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>) {
+        if let Ok(aux_capture) = libconfig::read_str("aux_capture") {
+            match aux_capture.as_ref() {
+                "1" => {
+                    info!("capturing undecodable aux packets");
+                    drtioaux::set_capture(true)
+                }
+                "0" => (),
+                _ => warn!("aux_capture \"{}\" not supported, ignoring", aux_capture),
+            }
+        }
+        if let Ok(policy) = libconfig::read_str("rtio_reset_policy") {
+            match policy.as_ref() {
+                "full" => *RESET_POLICY.lock() = RtioResetPolicy::Full,
+                "phy" => *RESET_POLICY.lock() = RtioResetPolicy::Phy,
+                "none" => *RESET_POLICY.lock() = RtioResetPolicy::Skip,
+                _ => warn!("rtio_reset_policy \"{}\" not supported, ignoring", policy),
+            }
+            info!("RTIO reset policy: {:?}", *RESET_POLICY.lock());
+        }
         let up_destinations = up_destinations.clone();
         task::spawn(async move {
             link_task(&up_destinations).await;
         });
+        #[cfg(has_drtio_eem)]
+        task::spawn(async {
+            loop {
+                drtio_eem::store_unsaved();
+                timer::async_delay_ms(EEM_STORE_INTERVAL_MS).await;
+            }
+        });
+    }
+
+    // Reads the links to hold down from the config and enables the transmitters of the others.
+    // Called once the transceiver clocks are stable.
+    pub fn init_txenable() {
+        let mut disabled = 0;
+        if let Ok(links) = libconfig::read_str("drtio_disabled_links") {
+            for entry in links.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+                match entry.parse::<usize>() {
+                    Ok(linkno) if linkno < csr::DRTIO.len() => {
+                        info!("[LINK#{}] link disabled", linkno);
+                        disabled |= 1 << linkno;
+                    }
+                    _ => warn!("drtio_disabled_links entry \"{}\" not supported, ignoring", entry),
+                }
+            }
+        }
+        *DISABLED_LINKS.lock() = disabled;
+        write_txenable(disabled);
+    }
+
+    fn write_txenable(disabled: u32) {
+        unsafe {
+            csr::gt_drtio::txenable_write(!disabled as _);
+            #[cfg(has_drtio_eem)]
+            csr::eem_transceiver::txenable_write((!disabled >> DRTIO_EEM_LINKNOS.start) as _);
+        }
+    }
+
+    pub fn link_enabled(linkno: u8) -> bool {
+        *DISABLED_LINKS.lock() & (1 << linkno) == 0
+    }
+
+    // Disabled links are reported down to everything else, so the link task takes them
+    // down on its next pass and leaves them alone until they are enabled again.
+    pub fn set_link_enabled(linkno: u8, enabled: bool) -> Result<(), &'static str> {
+        if linkno as usize >= csr::DRTIO.len() {
+            return Err("no such link");
+        }
+        let mut disabled = DISABLED_LINKS.lock();
+        if enabled {
+            *disabled &= !(1 << linkno);
+        } else {
+            *disabled |= 1 << linkno;
+        }
+        write_txenable(*disabled);
+        info!("[LINK#{}] link {}", linkno, if enabled { "enabled" } else { "disabled" });
+        Ok(())
     }
 
     async fn link_rx_up(linkno: u8) -> bool {
+        if !link_enabled(linkno) {
+            return false;
+        }
         let linkno = linkno as usize;
         #[cfg(has_drtio_eem)]
         if DRTIO_EEM_LINKNOS.contains(&linkno) {
@@ -93,14 +325,11 @@ pub mod drtio {
         unsafe { (csr::DRTIO[linkno].rx_up_read)() == 1 }
     }
 
-    fn get_master_destination() -> u8 {
-        for i in 0..drtio_routing::DEST_COUNT {
-            if ROUTING_TABLE.get().unwrap().0[i][0] == 0 {
-                return i as u8;
-            }
-        }
-        error!("Master is not defined in the routing table");
-        0
+    pub fn get_master_destination() -> u8 {
+        ROUTING_TABLE.get().unwrap().master_destination().unwrap_or_else(|| {
+            error!("Master is not defined in the routing table");
+            0
+        })
     }
 
     async fn route_packet(linkno: u8, packet: Packet, destination: u8) {
@@ -125,9 +354,17 @@ pub mod drtio {
                 error,
                 channel,
                 timestamp,
+                duration,
+                events,
+                min_slack,
             } => {
                 if destination == master_destination {
-                    remote_dma::playback_done(id, source, error, channel, timestamp).await;
+                    let stat = DmaPlaybackStat {
+                        duration: duration as i64,
+                        events: events as i32,
+                        min_slack: min_slack,
+                    };
+                    remote_dma::playback_done(id, source, error, channel, timestamp, stat).await;
                 } else {
                     route_packet(linkno, packet, destination).await;
                 }
@@ -156,15 +393,48 @@ pub mod drtio {
             } => {
                 if destination == master_destination {
                     subkernel::message_handle_incoming(id, status, length as usize, &data).await;
-                    // acknowledge receiving part of the message
-                    drtioaux_async::send(linkno, &Packet::SubkernelMessageAck { destination: source })
-                        .await
-                        .unwrap();
+                    // acknowledge receiving part of the message,
+                    // master keeps whole messages and always grants the full window
+                    drtioaux_async::send(
+                        linkno,
+                        &Packet::SubkernelMessageAck {
+                            destination: source,
+                            credits: SUBKERNEL_MESSAGE_WINDOW,
+                        },
+                    )
+                    .await
+                    .unwrap();
                 } else {
                     route_packet(linkno, packet, destination).await;
                 }
                 None
             }
+            Packet::SubkernelMessageCredit {
+                source,
+                destination,
+                credits,
+            } => {
+                if destination == master_destination {
+                    MESSAGE_CREDITS.async_lock().await.insert(source, credits);
+                } else {
+                    route_packet(linkno, packet, destination).await;
+                }
+                None
+            }
+            Packet::SubkernelBarrierArrive {
+                source,
+                id,
+                participants,
+                ..
+            } => {
+                // barriers are always aggregated by the master
+                subkernel::barrier_arrive(source, id, &participants).await;
+                None
+            }
+            Packet::SubkernelBarrierWithdraw { source, id, .. } => {
+                subkernel::barrier_withdraw(source, id).await;
+                None
+            }
             // routable packets
             Packet::DmaAddTraceRequest { destination, .. }
             | Packet::DmaAddTraceReply { destination, .. }
@@ -176,7 +446,8 @@ pub mod drtio {
             | Packet::SubkernelLoadRunReply { destination, .. }
             | Packet::SubkernelMessageAck { destination, .. }
             | Packet::SubkernelException { destination, .. }
-            | Packet::SubkernelExceptionRequest { destination, .. } => {
+            | Packet::SubkernelExceptionRequest { destination, .. }
+            | Packet::SubkernelBarrierRelease { destination, .. } => {
                 if destination == master_destination {
                     Some(packet)
                 } else {
@@ -195,18 +466,40 @@ pub mod drtio {
         match drtioaux_async::recv_timeout(linkno, Some(timeout)).await {
             Ok(packet) => return Ok(packet),
             Err(DrtioError::TimedOut) => return Err(Error::Timeout),
+            Err(e) if e.is_damaged() => return Err(Error::PacketDamaged),
             Err(_) => return Err(Error::AuxError),
         }
     }
 
     pub async fn aux_transact(linkno: u8, request: &Packet) -> Result<Packet, Error> {
+        aux_transact_timeout(linkno, request, aux_timeout::for_request(request)).await
+    }
+
+    pub async fn aux_transact_timeout(linkno: u8, request: &Packet, timeout: u64) -> Result<Packet, Error> {
         if !link_rx_up(linkno).await {
             return Err(Error::LinkDown);
         }
-        let _lock = AUX_MUTEX.async_lock().await;
+        let _lock = AUX_MUTEX[linkno as usize].async_lock().await;
         drtioaux_async::send(linkno, request).await.unwrap();
+        let mut retransmits = 0;
         loop {
-            let packet = recv_aux_timeout(linkno, 200).await?;
+            let packet = match recv_aux_timeout(linkno, timeout).await {
+                Err(Error::PacketDamaged) if drtioaux::framing(linkno) && retransmits < AUX_RETRANSMIT_LIMIT => {
+                    retransmits += 1;
+                    warn!("[LINK#{}] damaged aux reply, asking for retransmission", linkno);
+                    drtioaux_async::send(linkno, &Packet::RetransmitRequest).await.unwrap();
+                    continue;
+                }
+                result => result?,
+            };
+            if let Packet::RetransmitRequest = packet {
+                if retransmits == AUX_RETRANSMIT_LIMIT {
+                    return Err(Error::PacketDamaged);
+                }
+                retransmits += 1;
+                drtioaux_async::retransmit(linkno).await;
+                continue;
+            }
             if let Some(packet) = process_async_packets(linkno, packet).await {
                 return Ok(packet);
             }
@@ -242,8 +535,48 @@ pub mod drtio {
         }
     }
 
+    // One aux round trip over a link, for the startup self test.
+    pub async fn link_echo(linkno: u8) -> Result<(), &'static str> {
+        if !link_rx_up(linkno).await {
+            return Err("no link");
+        }
+        match aux_transact(linkno, &Packet::EchoRequest).await {
+            Ok(Packet::EchoReply) => Ok(()),
+            Ok(_) => Err("unexpected reply"),
+            Err(_) => Err("no echo reply"),
+        }
+    }
+
+    async fn check_version(linkno: u8) -> Result<u32, Error> {
+        let mut retries = 0;
+        loop {
+            let reply = aux_transact(
+                linkno,
+                &Packet::VersionRequest {
+                    version: PROTOCOL_VERSION,
+                    features: local_features(),
+                },
+            )
+            .await;
+            match reply {
+                Ok(Packet::VersionReply { version, features }) => {
+                    return if version == PROTOCOL_VERSION {
+                        Ok(features)
+                    } else {
+                        Err(Error::IncompatibleVersion(version))
+                    };
+                }
+                Ok(_) => return Err(Error::UnexpectedReply),
+                // a lost request or reply is sent again; satellites predating the handshake
+                // drop the request as an unknown packet, and keep timing out
+                Err(Error::Timeout) if retries < VERSION_CHECK_RETRIES => retries += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     async fn sync_tsc(linkno: u8) -> Result<(), Error> {
-        let _lock = AUX_MUTEX.async_lock().await;
+        let _lock = AUX_MUTEX[linkno as usize].async_lock().await;
 
         unsafe {
             (csr::DRTIO[linkno as usize].set_time_write)(1);
@@ -301,7 +634,7 @@ pub mod drtio {
     }
 
     async fn process_unsolicited_aux(linkno: u8) {
-        let _lock = AUX_MUTEX.async_lock().await;
+        let _lock = AUX_MUTEX[linkno as usize].async_lock().await;
         match drtioaux_async::recv(linkno).await {
             Ok(Some(packet)) => {
                 if let Some(packet) = process_async_packets(linkno, packet).await {
@@ -322,6 +655,10 @@ pub mod drtio {
         }
         if errors != 0 {
             error!("[LINK#{}] error(s) found (0x{:02x}):", linkno, errors);
+            events::push(Event::LinkError {
+                linkno: linkno,
+                errors: errors,
+            });
             if errors & 1 != 0 {
                 error!("[LINK#{}] received packet of an unknown type", linkno);
             }
@@ -344,9 +681,294 @@ pub mod drtio {
         if up {
             drtio_routing::interconnect_enable(ROUTING_TABLE.get().unwrap(), 0, destination);
             info!("[DEST#{}] destination is up", destination);
+            events::push(Event::DestinationUp(destination));
         } else {
             drtio_routing::interconnect_disable(destination);
+            BUFFER_SPACE.lock().remove(&destination);
+            WALL_CLOCK_SENT.lock()[destination as usize] = 0;
+            TSC_DRIFT_PROBES.lock().remove(&destination);
+            LATENCY.lock().remove(&destination);
             info!("[DEST#{}] destination is down", destination);
+            events::push(Event::DestinationDown(destination));
+        }
+    }
+
+    // Pushes the wall clock to a destination that was not sent its latest setting yet.
+    async fn sync_wall_clock(linkno: u8, destination: u8) {
+        let generation = wall_clock::generation();
+        if WALL_CLOCK_SENT.lock()[destination as usize] == generation {
+            return;
+        }
+        let time_us = match wall_clock::get() {
+            Some(time_us) => time_us,
+            None => return,
+        };
+        let reply = aux_transact(
+            linkno,
+            &Packet::WallClockSet {
+                destination: destination,
+                time_us: time_us,
+            },
+        )
+        .await;
+        match reply {
+            Ok(Packet::WallClockAck) => WALL_CLOCK_SENT.lock()[destination as usize] = generation,
+            Ok(packet) => error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet),
+            Err(e) => error!("[DEST#{}] wall clock update failed ({})", destination, e),
+        }
+    }
+
+    fn rtio_counter() -> u64 {
+        unsafe {
+            csr::rtio::counter_update_write(1);
+            csr::rtio::counter_read()
+        }
+    }
+
+    // Sends the master time, corrected by half the previous round trip, for the destination
+    // to compare against its own counter. Returns the offset and drift in ppb it reports.
+    pub async fn tsc_drift_probe(linkno: u8, destination: u8) -> Result<(i64, i32), Error> {
+        let round_trip = TSC_DRIFT_PROBES.lock().get(&destination).map_or(0, |&(_, round_trip)| round_trip);
+        let sent = rtio_counter();
+        let reply = aux_transact(
+            linkno,
+            &Packet::TscDriftProbe {
+                destination: destination,
+                master_time: sent + round_trip / 2,
+            },
+        )
+        .await?;
+        let round_trip = rtio_counter() - sent;
+        TSC_DRIFT_PROBES.lock().insert(destination, (timer::get_ms(), round_trip));
+        match reply {
+            Packet::TscDriftReply { offset, drift_ppb } => Ok((offset, drift_ppb)),
+            packet => {
+                error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet);
+                Err(Error::UnexpectedReply)
+            }
+        }
+    }
+
+    async fn check_tsc_drift(linkno: u8, destination: u8) {
+        let last_probe = TSC_DRIFT_PROBES.lock().get(&destination).map(|&(last_probe, _)| last_probe);
+        if let Some(last_probe) = last_probe {
+            if timer::get_ms() < last_probe + TSC_DRIFT_PROBE_INTERVAL_MS {
+                return;
+            }
+        }
+        if let Err(e) = tsc_drift_probe(linkno, destination).await {
+            error!("[DEST#{}] TSC drift probe failed ({})", destination, e);
+        }
+    }
+
+    async fn check_latency(linkno: u8, destination: u8) {
+        {
+            let mut latency = LATENCY.lock();
+            // failed probes wait for the next interval as well
+            match latency.get_mut(&destination) {
+                Some(stats) if timer::get_ms() < stats.last_probe_ms + LATENCY_PROBE_INTERVAL_MS => return,
+                Some(stats) => stats.last_probe_ms = timer::get_ms(),
+                None => {
+                    latency.insert(destination, LatencyStats::new());
+                }
+            }
+        }
+        update_latency(linkno, destination).await;
+        update_router_stats(linkno, destination).await;
+        update_repeater_stats(linkno, destination).await;
+    }
+
+    async fn update_latency(linkno: u8, destination: u8) {
+        if !link_supports(linkno, FEATURE_LATENCY_PROBE) {
+            return;
+        }
+        let sent = rtio_counter();
+        let reply = aux_transact(
+            linkno,
+            &Packet::LatencyProbe {
+                destination: destination,
+                timestamp: sent,
+            },
+        )
+        .await;
+        match reply {
+            Ok(Packet::LatencyProbeReply { timestamp }) if timestamp == sent => {
+                let round_trip = rtio_counter() - sent;
+                LATENCY
+                    .lock()
+                    .entry(destination)
+                    .or_insert_with(LatencyStats::new)
+                    .record(round_trip);
+            }
+            Ok(packet) => error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet),
+            Err(e) => error!("[DEST#{}] latency probe failed ({})", destination, e),
+        }
+    }
+
+    async fn update_router_stats(linkno: u8, destination: u8) {
+        if !link_supports(linkno, FEATURE_ROUTER_STATS) {
+            return;
+        }
+        let reply = aux_transact(
+            linkno,
+            &Packet::RouterStatsRequest {
+                destination: destination,
+            },
+        )
+        .await;
+        match reply {
+            Ok(Packet::RouterStatsReply {
+                forwarded,
+                queued,
+                max_queued,
+                dropped_full,
+                dropped_aged,
+            }) => {
+                let stats = RouterStats {
+                    forwarded: forwarded,
+                    queued: queued,
+                    max_queued: max_queued,
+                    dropped_full: dropped_full,
+                    dropped_aged: dropped_aged,
+                };
+                let previous = ROUTER_STATS.lock().insert(destination, stats).unwrap_or_default();
+                // counters start over when the satellite restarts
+                let dropped = dropped_full
+                    .saturating_add(dropped_aged)
+                    .saturating_sub(previous.dropped_full.saturating_add(previous.dropped_aged));
+                if dropped > 0 {
+                    warn!("[DEST#{}] {} aux packets dropped while forwarding downstream", destination, dropped);
+                }
+            }
+            Ok(packet) => error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet),
+            Err(e) => error!("[DEST#{}] router stats request failed ({})", destination, e),
+        }
+    }
+
+    async fn update_repeater_stats(linkno: u8, destination: u8) {
+        if !link_supports(linkno, FEATURE_REPEATER_STATS) {
+            return;
+        }
+        let mut all_stats = Vec::new();
+        let mut repno = 0;
+        // every reply tells how many repeaters there are
+        let mut count = 1;
+        while repno < count {
+            let reply = aux_transact(
+                linkno,
+                &Packet::RepeaterStatsRequest {
+                    destination: destination,
+                    repno: repno,
+                },
+            )
+            .await;
+            match reply {
+                Ok(Packet::RepeaterStatsReply {
+                    repeaters,
+                    state,
+                    up_transitions,
+                    down_transitions,
+                    aux_send_failures,
+                    forward_timeouts,
+                    history_len,
+                    history_states,
+                    history_age_ms,
+                }) => {
+                    count = repeaters;
+                    if repno >= repeaters {
+                        break;
+                    }
+                    let history_len = (history_len as usize).min(REPEATER_HISTORY_SIZE);
+                    all_stats.push(RepeaterStats {
+                        state: state,
+                        up_transitions: up_transitions,
+                        down_transitions: down_transitions,
+                        aux_send_failures: aux_send_failures,
+                        forward_timeouts: forward_timeouts,
+                        history: history_states[..history_len]
+                            .iter()
+                            .copied()
+                            .zip(history_age_ms[..history_len].iter().copied())
+                            .collect(),
+                    });
+                    repno += 1;
+                }
+                Ok(packet) => {
+                    error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet);
+                    return;
+                }
+                Err(e) => {
+                    error!("[DEST#{}] repeater stats request failed ({})", destination, e);
+                    return;
+                }
+            }
+        }
+        let previous = REPEATER_STATS.lock().insert(destination, all_stats.clone());
+        // counters start over when the satellite restarts
+        for (repno, stats) in all_stats.iter().enumerate() {
+            let previous = previous.as_ref().and_then(|previous| previous.get(repno));
+            let previous_downs = previous.map_or(0, |previous| previous.down_transitions);
+            let downs = stats.down_transitions.saturating_sub(previous_downs);
+            if downs > 0 {
+                warn!("[DEST#{}] link of repeater {} went down {} times", destination, repno, downs);
+            }
+            // nothing to compare against on the first poll
+            if let Some(previous) = previous {
+                let ups = stats.up_transitions.saturating_sub(previous.up_transitions);
+                if downs > 0 || (previous.state == REPEATER_UP && stats.state != REPEATER_UP) {
+                    events::push(Event::SatelliteLinkDown {
+                        destination: destination,
+                        repno: repno as u8,
+                    });
+                }
+                if stats.state == REPEATER_UP && (ups > 0 || previous.state != REPEATER_UP) {
+                    events::push(Event::SatelliteLinkUp {
+                        destination: destination,
+                        repno: repno as u8,
+                    });
+                }
+            }
+        }
+    }
+
+    pub fn latency_stats() -> Vec<(u8, LatencyStats)> {
+        LATENCY.lock().iter().map(|(&destination, &stats)| (destination, stats)).collect()
+    }
+
+    pub fn router_stats(destination: u8) -> RouterStats {
+        ROUTER_STATS.lock().get(&destination).copied().unwrap_or_default()
+    }
+
+    pub fn repeater_stats(destination: u8) -> Vec<RepeaterStats> {
+        REPEATER_STATS.lock().get(&destination).cloned().unwrap_or_default()
+    }
+
+    // Taken from the timer when the first destination comes up, which depends on
+    // how long the links took to lock, so that it differs from one boot to the next.
+    fn session_epoch() -> u32 {
+        let mut epoch = SESSION_EPOCH.lock();
+        if *epoch == 0 {
+            *epoch = timer::get_us() as u32 | 1;
+        }
+        *epoch
+    }
+
+    // Satellites keep their DMA traces and subkernels when the epoch is the one they
+    // last saw, i.e. after a link flap, and clear them after a master restart.
+    async fn start_session(linkno: u8, destination: u8) {
+        let reply = aux_transact(
+            linkno,
+            &Packet::SessionRequest {
+                destination: destination,
+                epoch: session_epoch(),
+            },
+        )
+        .await;
+        match reply {
+            Ok(Packet::SessionReply { resumed: true }) => info!("[DEST#{}] session resumed", destination),
+            Ok(Packet::SessionReply { resumed: false }) => info!("[DEST#{}] new session started", destination),
+            Ok(packet) => error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet),
+            Err(e) => error!("[DEST#{}] failed to start session ({})", destination, e),
         }
     }
 
@@ -355,95 +977,164 @@ pub mod drtio {
         up_destinations[destination as usize]
     }
 
-    async fn destination_survey(up_links: &[bool], up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>) {
-        for destination in 0..drtio_routing::DEST_COUNT {
-            let hop = ROUTING_TABLE.get().unwrap().0[destination][0];
-            let destination = destination as u8;
+    // surveys in flight on each link; they take the aux lock of the link in turn, so that the
+    // follow-up transactions of one destination are interleaved with the probes of the others
+    const SURVEY_LINK_BUDGET: usize = 2;
 
-            if hop > 0 && hop as usize <= csr::DRTIO.len() {
-                let linkno = hop - 1;
-                if destination_up(up_destinations, destination).await {
-                    if up_links[linkno as usize] {
-                        let reply = aux_transact(
-                            linkno,
-                            &Packet::DestinationStatusRequest {
-                                destination: destination,
-                            },
-                        )
-                        .await;
-                        match reply {
-                            Ok(Packet::DestinationDownReply) => {
-                                destination_set_up(up_destinations, destination, false).await;
-                                remote_dma::destination_changed(destination, false).await;
-                                subkernel::destination_changed(destination, false).await;
-                            }
-                            Ok(Packet::DestinationOkReply) => (),
-                            Ok(Packet::DestinationSequenceErrorReply { channel }) => {
-                                let global_ch = ((destination as u32) << 16) | channel as u32;
-                                error!(
-                                    "[DEST#{}] RTIO sequence error involving channel 0x{:04x}:{}",
-                                    destination,
-                                    channel,
-                                    resolve_channel_name(global_ch)
-                                );
-                                unsafe { SEEN_ASYNC_ERRORS |= ASYNC_ERROR_SEQUENCE_ERROR };
-                            }
-                            Ok(Packet::DestinationCollisionReply { channel }) => {
-                                let global_ch = ((destination as u32) << 16) | channel as u32;
-                                error!(
-                                    "[DEST#{}] RTIO collision involving channel 0x{:04x}:{}",
-                                    destination,
-                                    channel,
-                                    resolve_channel_name(global_ch)
-                                );
-                                unsafe { SEEN_ASYNC_ERRORS |= ASYNC_ERROR_COLLISION };
-                            }
-                            Ok(Packet::DestinationBusyReply { channel }) => {
-                                let global_ch = ((destination as u32) << 16) | channel as u32;
-                                error!(
-                                    "[DEST#{}] RTIO busy error involving channel 0x{:04x}:{}",
-                                    destination,
-                                    channel,
-                                    resolve_channel_name(global_ch)
-                                );
-                                unsafe { SEEN_ASYNC_ERRORS |= ASYNC_ERROR_BUSY };
-                            }
-                            Ok(packet) => error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet),
-                            Err(e) => error!("[DEST#{}] communication failed ({})", destination, e),
-                        }
-                    } else {
+    async fn survey_destination(
+        linkno: u8,
+        destination: u8,
+        link_up: bool,
+        up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
+    ) {
+        if destination_up(up_destinations, destination).await {
+            if link_up {
+                let reply = aux_transact(
+                    linkno,
+                    &Packet::DestinationStatusRequest {
+                        destination: destination,
+                    },
+                )
+                .await;
+                match reply {
+                    Ok(Packet::DestinationDownReply) => {
                         destination_set_up(up_destinations, destination, false).await;
                         remote_dma::destination_changed(destination, false).await;
                         subkernel::destination_changed(destination, false).await;
                     }
-                } else {
-                    if up_links[linkno as usize] {
-                        let reply = aux_transact(
-                            linkno,
-                            &Packet::DestinationStatusRequest {
-                                destination: destination,
-                            },
-                        )
-                        .await;
-                        match reply {
-                            Ok(Packet::DestinationDownReply) => (),
-                            Ok(Packet::DestinationOkReply) => {
-                                destination_set_up(up_destinations, destination, true).await;
-                                init_buffer_space(destination as u8, linkno).await;
-                                remote_dma::destination_changed(destination, true).await;
-                                subkernel::destination_changed(destination, true).await;
-                            }
-                            Ok(packet) => error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet),
-                            Err(e) => error!("[DEST#{}] communication failed ({})", destination, e),
+                    Ok(Packet::DestinationOkReply {
+                        buffer_space,
+                        min_buffer_space,
+                    }) => {
+                        update_buffer_space(destination, buffer_space, min_buffer_space);
+                        sync_wall_clock(linkno, destination).await;
+                        check_tsc_drift(linkno, destination).await;
+                        check_latency(linkno, destination).await;
+                    }
+                    Ok(Packet::DestinationSequenceErrorReply { channel }) => {
+                        let global_ch = ((destination as u32) << 16) | channel as u32;
+                        if error_log::should_log("sequence error", Some(global_ch)) {
+                            error!(
+                                "[DEST#{}] RTIO sequence error involving channel 0x{:04x}:{}",
+                                destination,
+                                channel,
+                                resolve_channel_name(global_ch)
+                            );
+                        }
+                        unsafe { SEEN_ASYNC_ERRORS |= ASYNC_ERROR_SEQUENCE_ERROR };
+                    }
+                    Ok(Packet::DestinationCollisionReply { channel }) => {
+                        let global_ch = ((destination as u32) << 16) | channel as u32;
+                        if error_log::should_log("collision", Some(global_ch)) {
+                            error!(
+                                "[DEST#{}] RTIO collision involving channel 0x{:04x}:{}",
+                                destination,
+                                channel,
+                                resolve_channel_name(global_ch)
+                            );
                         }
+                        unsafe { SEEN_ASYNC_ERRORS |= ASYNC_ERROR_COLLISION };
                     }
+                    Ok(Packet::DestinationBusyReply { channel }) => {
+                        let global_ch = ((destination as u32) << 16) | channel as u32;
+                        if error_log::should_log("busy error", Some(global_ch)) {
+                            error!(
+                                "[DEST#{}] RTIO busy error involving channel 0x{:04x}:{}",
+                                destination,
+                                channel,
+                                resolve_channel_name(global_ch)
+                            );
+                        }
+                        unsafe { SEEN_ASYNC_ERRORS |= ASYNC_ERROR_BUSY };
+                    }
+                    Ok(packet) => error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet),
+                    Err(e) => error!("[DEST#{}] communication failed ({})", destination, e),
+                }
+            } else {
+                destination_set_up(up_destinations, destination, false).await;
+                remote_dma::destination_changed(destination, false).await;
+                subkernel::destination_changed(destination, false).await;
+            }
+        } else {
+            if link_up {
+                let reply = aux_transact(
+                    linkno,
+                    &Packet::DestinationStatusRequest {
+                        destination: destination,
+                    },
+                )
+                .await;
+                match reply {
+                    Ok(Packet::DestinationDownReply) => (),
+                    Ok(Packet::DestinationOkReply { .. }) => {
+                        start_session(linkno, destination).await;
+                        destination_set_up(up_destinations, destination, true).await;
+                        init_buffer_space(destination as u8, linkno).await;
+                        remote_dma::destination_changed(destination, true).await;
+                        subkernel::destination_changed(destination, true).await;
+                    }
+                    Ok(packet) => error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet),
+                    Err(e) => error!("[DEST#{}] communication failed ({})", destination, e),
                 }
             }
         }
     }
 
+    // Drives the futures concurrently until all of them have completed.
+    async fn join_all<F: Future<Output = ()>>(futures: Vec<F>) {
+        let mut futures: Vec<Option<Pin<Box<F>>>> = futures.into_iter().map(|future| Some(Box::pin(future))).collect();
+        poll_fn(|cx| {
+            let mut pending = false;
+            for slot in futures.iter_mut() {
+                if let Some(future) = slot {
+                    if future.as_mut().poll(cx).is_ready() {
+                        *slot = None;
+                    } else {
+                        pending = true;
+                    }
+                }
+            }
+            if pending { Poll::Pending } else { Poll::Ready(()) }
+        })
+        .await
+    }
+
+    // Destinations behind different links are surveyed concurrently, so that a slow or
+    // unreachable destination does not hold up the others.
+    async fn destination_survey(up_links: &[bool], up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>) {
+        let mut link_destinations: Vec<Vec<u8>> = vec![Vec::new(); csr::DRTIO.len()];
+        for destination in 0..drtio_routing::DEST_COUNT {
+            let hop = ROUTING_TABLE.get().unwrap().0[destination][0];
+            if hop > 0 && hop as usize <= csr::DRTIO.len() {
+                link_destinations[hop as usize - 1].push(destination as u8);
+            }
+        }
+        let mut surveys = Vec::new();
+        for (linkno, destinations) in link_destinations.iter().enumerate() {
+            let link_up = up_links[linkno];
+            for worker in 0..SURVEY_LINK_BUDGET {
+                let destinations: Vec<u8> = destinations
+                    .iter()
+                    .skip(worker)
+                    .step_by(SURVEY_LINK_BUDGET)
+                    .copied()
+                    .collect();
+                if destinations.is_empty() {
+                    continue;
+                }
+                surveys.push(async move {
+                    for destination in destinations {
+                        survey_destination(linkno as u8, destination, link_up, up_destinations).await;
+                    }
+                });
+            }
+        }
+        join_all(surveys).await;
+    }
+
     pub async fn link_task(up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>) {
         let mut up_links = [false; csr::DRTIO.len()];
+        let mut incompatible_links = [false; csr::DRTIO.len()];
         // set up local RTIO
         let master_destination = get_master_destination();
 
@@ -458,7 +1149,10 @@ pub mod drtio {
                         process_local_errors(linkno).await;
                     } else {
                         info!("[LINK#{}] link is down", linkno);
+                        events::push(Event::LinkDown(linkno));
                         up_links[linkno as usize] = false;
+                        drtioaux::set_framing(linkno, false);
+                        LINK_COMPAT.lock()[linkno as usize] = LinkCompat::Unknown;
 
                         #[cfg(has_drtio_eem)]
                         if DRTIO_EEM_LINKNOS.contains(&(linkno as usize)) {
@@ -468,12 +1162,19 @@ pub mod drtio {
                             while !matches!(drtioaux_async::recv(linkno).await, Ok(None)) {}
                         }
                     }
-                } else {
+                } else if incompatible_links[linkno as usize] {
+                    /* link was refused by the version check, wait until it goes down */
+                    if !link_rx_up(linkno).await {
+                        info!("[LINK#{}] link is down", linkno);
+                        incompatible_links[linkno as usize] = false;
+                        LINK_COMPAT.lock()[linkno as usize] = LinkCompat::Unknown;
+                    }
+                } else if link_enabled(linkno) {
                     /* link was previously down */
                     #[cfg(has_drtio_eem)]
                     if DRTIO_EEM_LINKNOS.contains(&(linkno as usize)) {
                         let eem_trx_no = linkno - DRTIO_EEM_LINKNOS.start as u8;
-                        if !unsafe { drtio_eem::align_wordslip(eem_trx_no) } {
+                        if !drtio_eem::align_wordslip(eem_trx_no).await {
                             continue;
                         }
                         unsafe {
@@ -486,6 +1187,34 @@ pub mod drtio {
                         let ping_count = ping_remote(linkno).await;
                         if ping_count > 0 {
                             info!("[LINK#{}] remote replied after {} packets", linkno, ping_count);
+                            match check_version(linkno).await {
+                                Ok(features) => {
+                                    drtioaux::set_framing(linkno, features & FEATURE_FRAMING != 0);
+                                    info!(
+                                        "[LINK#{}] remote aux protocol version {}, features 0x{:08x}",
+                                        linkno, PROTOCOL_VERSION, features
+                                    );
+                                    LINK_COMPAT.lock()[linkno as usize] = LinkCompat::Compatible {
+                                        version: PROTOCOL_VERSION,
+                                        features: features,
+                                    };
+                                }
+                                Err(Error::IncompatibleVersion(version)) => {
+                                    error!(
+                                        "[LINK#{}] remote firmware is not compatible ({}), link will not be brought up",
+                                        linkno,
+                                        Error::IncompatibleVersion(version)
+                                    );
+                                    LINK_COMPAT.lock()[linkno as usize] = LinkCompat::Incompatible { version };
+                                    incompatible_links[linkno as usize] = true;
+                                    continue;
+                                }
+                                Err(e) => {
+                                    // not a version mismatch, the link is pinged and checked again
+                                    error!("[LINK#{}] aux protocol version check failed ({}), retrying", linkno, e);
+                                    continue;
+                                }
+                            }
                             up_links[linkno as usize] = true;
                             if let Err(e) = sync_tsc(linkno).await {
                                 error!("[LINK#{}] failed to sync TSC ({})", linkno, e);
@@ -497,6 +1226,7 @@ pub mod drtio {
                                 error!("[LINK#{}] failed to set rank ({})", linkno, e);
                             }
                             info!("[LINK#{}] link initialization completed", linkno);
+                            events::push(Event::LinkUp(linkno));
                         } else {
                             error!("[LINK#{}] ping failed", linkno);
                         }
@@ -508,7 +1238,12 @@ pub mod drtio {
         }
     }
 
-    pub async fn reset() {
+    pub async fn reset(policy: Option<RtioResetPolicy>) {
+        let policy = policy.unwrap_or_else(|| *RESET_POLICY.lock());
+        if policy == RtioResetPolicy::Skip {
+            warn!("DRTIO links not reset, satellites keep their pending RTIO events");
+            return;
+        }
         for linkno in 0..csr::DRTIO.len() {
             unsafe {
                 (csr::DRTIO[linkno].reset_write)(1);
@@ -520,6 +1255,9 @@ pub mod drtio {
                 (csr::DRTIO[linkno].reset_write)(0);
             }
         }
+        if policy == RtioResetPolicy::Phy {
+            return;
+        }
 
         for linkno in 0..csr::DRTIO.len() {
             let linkno = linkno as u8;
@@ -682,6 +1420,81 @@ pub mod drtio {
         }
     }
 
+    // Returns the size in bytes and event count of the trace held by the destination,
+    // or None if it does not have it loaded.
+    pub async fn ddma_stat(id: u32, destination: u8) -> Result<Option<(u32, u32)>, Error> {
+        let linkno = ROUTING_TABLE.get().unwrap().0[destination as usize][0] - 1;
+        let reply = aux_transact(
+            linkno,
+            &Packet::DmaStatRequest {
+                id: id,
+                source: get_master_destination(),
+                destination: destination,
+            },
+        )
+        .await?;
+        match reply {
+            Packet::DmaStatReply {
+                found: true,
+                size,
+                events,
+            } => Ok(Some((size, events))),
+            Packet::DmaStatReply { found: false, .. } => Ok(None),
+            _ => Err(Error::UnexpectedReply),
+        }
+    }
+
+    // Starts the playback on all targets with one request per link; satellites pass it on
+    // to the targets behind them. Returns the destinations that started the playback.
+    pub async fn ddma_send_playback_broadcast(
+        id: u32,
+        targets: &[u8; DESTINATION_MASK_SIZE],
+        timestamp: u64,
+    ) -> [u8; DESTINATION_MASK_SIZE] {
+        let master_destination = get_master_destination();
+        let mut succeeded = [0; DESTINATION_MASK_SIZE];
+        for linkno in 0..csr::DRTIO.len() as u8 {
+            let (link_targets, entry) = ROUTING_TABLE.get().unwrap().targets_behind(0, linkno + 1, targets);
+            if link_targets.iter().all(|&b| b == 0) {
+                continue;
+            }
+            let entry = match entry {
+                Some(entry) => entry,
+                None => {
+                    error!("[LINK#{}] no satellite found on link, DMA playback not broadcast", linkno);
+                    continue;
+                }
+            };
+            let target_count = link_targets.iter().map(|b| b.count_ones() as u64).sum::<u64>();
+            // a broadcast reply only comes once every satellite behind the link has started
+            let timeout = aux_timeout::get(aux_timeout::OperationClass::Dma) * target_count;
+            let reply = aux_transact_timeout(
+                linkno,
+                &Packet::DmaPlaybackBroadcastRequest {
+                    source: master_destination,
+                    destination: entry,
+                    id: id,
+                    timestamp: timestamp,
+                    targets: link_targets,
+                },
+                timeout,
+            )
+            .await;
+            match reply {
+                Ok(Packet::DmaPlaybackBroadcastReply {
+                    succeeded: link_succeeded,
+                }) => {
+                    for (s, l) in succeeded.iter_mut().zip(link_succeeded.iter()) {
+                        *s |= *l;
+                    }
+                }
+                Ok(_) => error!("[LINK#{}] unexpected reply to DMA playback broadcast", linkno),
+                Err(e) => error!("[LINK#{}] DMA playback broadcast failed: {}", linkno, e),
+            }
+        }
+        succeeded
+    }
+
     async fn analyzer_get_data(destination: u8) -> Result<RemoteBuffer, Error> {
         let linkno = ROUTING_TABLE.get().unwrap().0[destination as usize][0] - 1;
         let reply = aux_transact(
@@ -741,6 +1554,41 @@ pub mod drtio {
         Ok(remote_buffers)
     }
 
+    async fn analyzer_set_armed(destination: u8, arm: bool, synchronized: bool) -> Result<(), Error> {
+        let linkno = ROUTING_TABLE.get().unwrap().0[destination as usize][0] - 1;
+        let reply = aux_transact(
+            linkno,
+            &Packet::AnalyzerArmRequest {
+                destination: destination,
+                arm: arm,
+                synchronized: synchronized,
+            },
+        )
+        .await?;
+        match reply {
+            // satellites without an analyzer buffer have nothing to capture, which is not an error
+            Packet::AnalyzerArmReply { .. } => Ok(()),
+            _ => Err(Error::UnexpectedReply),
+        }
+    }
+
+    // arms or disarms the analyzers of all satellites that are up; `synchronized` keeps
+    // them from being armed again after a readout, as they are armed for each kernel run
+    pub async fn analyzer_arm(
+        up_destinations: &Rc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
+        arm: bool,
+        synchronized: bool,
+    ) {
+        for i in 1..drtio_routing::DEST_COUNT {
+            if destination_up(up_destinations, i as u8).await {
+                if let Err(e) = analyzer_set_armed(i as u8, arm, synchronized).await {
+                    let action = if arm { "arming" } else { "disarming" };
+                    warn!("[DEST#{}] analyzer {} failed: {}", i, action, e);
+                }
+            }
+        }
+    }
+
     pub async fn subkernel_upload(id: u32, destination: u8, data: &Vec<u8>) -> Result<(), Error> {
         let linkno = ROUTING_TABLE.get().unwrap().0[destination as usize][0] - 1;
         partition_data(
@@ -762,7 +1610,13 @@ pub mod drtio {
         .await
     }
 
-    pub async fn subkernel_load(id: u32, destination: u8, run: bool, timestamp: u64) -> Result<(), Error> {
+    pub async fn subkernel_load(
+        id: u32,
+        destination: u8,
+        run: bool,
+        timestamp: u64,
+        relative: bool,
+    ) -> Result<(), Error> {
         let linkno = ROUTING_TABLE.get().unwrap().0[destination as usize][0] - 1;
         let master_destination = get_master_destination();
         let reply = aux_transact(
@@ -772,7 +1626,8 @@ pub mod drtio {
                 source: master_destination,
                 destination: destination,
                 run: run,
-                timestamp,
+                timestamp: timestamp,
+                relative: relative,
             },
         )
         .await?;
@@ -805,56 +1660,305 @@ pub mod drtio {
         let linkno = ROUTING_TABLE.get().unwrap().0[destination as usize][0] - 1;
         let mut remote_data: Vec<u8> = Vec::new();
         let master_destination = get_master_destination();
+        let mut seq = 0;
+        let mut retries = 0;
         loop {
             let reply = aux_transact(
                 linkno,
                 &Packet::SubkernelExceptionRequest {
                     source: master_destination,
                     destination: destination,
+                    seq: seq,
                 },
             )
-            .await?;
+            .await;
             match reply {
-                Packet::SubkernelException {
+                Ok(Packet::SubkernelException {
                     destination,
+                    seq: reply_seq,
                     last,
                     length,
                     data,
-                } => {
+                }) if reply_seq == seq => {
                     if destination == master_destination {
                         remote_data.extend(&data[0..length as usize]);
                         if last {
                             return Ok(remote_data);
                         }
+                        seq += 1;
+                        retries = 0;
                     } else {
                         return Err(Error::UnexpectedReply);
                     }
                 }
-                _ => return Err(Error::UnexpectedReply),
+                // a lost or stale slice is requested again
+                Ok(Packet::SubkernelException { .. }) | Err(Error::Timeout) if retries < EXCEPTION_SLICE_RETRIES => {
+                    retries += 1;
+                }
+                Ok(_) => return Err(Error::UnexpectedReply),
+                Err(e) => return Err(e),
             }
         }
     }
 
+    pub async fn subkernel_dataset_fetch(destination: u8, id: u32, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        let linkno = ROUTING_TABLE.get().unwrap().0[destination as usize][0] - 1;
+        let mut name_bytes: [u8; DATASET_NAME_MAX_SIZE] = [0; DATASET_NAME_MAX_SIZE];
+        name_bytes[..name.len()].copy_from_slice(name.as_bytes());
+        let mut remote_data: Vec<u8> = Vec::new();
+        let mut seq = 0;
+        let mut retries = 0;
+        loop {
+            let reply = aux_transact(
+                linkno,
+                &Packet::SubkernelDatasetRequest {
+                    destination: destination,
+                    id: id,
+                    seq: seq,
+                    name_len: name.len() as u8,
+                    name: name_bytes,
+                },
+            )
+            .await;
+            match reply {
+                Ok(Packet::SubkernelDatasetReply { found: false, .. }) => return Ok(None),
+                Ok(Packet::SubkernelDatasetReply {
+                    seq: reply_seq,
+                    last,
+                    length,
+                    data,
+                    ..
+                }) if reply_seq == seq => {
+                    remote_data.extend(&data[0..length as usize]);
+                    if last {
+                        return Ok(Some(remote_data));
+                    }
+                    seq += 1;
+                    retries = 0;
+                }
+                // a lost or stale slice is requested again
+                Ok(Packet::SubkernelDatasetReply { .. }) | Err(Error::Timeout) if retries < EXCEPTION_SLICE_RETRIES => {
+                    retries += 1;
+                }
+                Ok(_) => return Err(Error::UnexpectedReply),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn wait_message_credit(linkno: u8, destination: u8) -> Result<(), Error> {
+        let max_time = timer::get_ms() + MESSAGE_CREDIT_TIMEOUT;
+        while timer::get_ms() < max_time {
+            if let Some(credits) = MESSAGE_CREDITS.async_lock().await.remove(&destination) {
+                if credits > 0 {
+                    return Ok(());
+                }
+            }
+            if !link_rx_up(linkno).await {
+                return Err(Error::LinkDown);
+            }
+            task::r#yield().await;
+        }
+        Err(Error::Timeout)
+    }
+
     pub async fn subkernel_send_message(id: u32, destination: u8, message: &[u8]) -> Result<(), Error> {
         let linkno = ROUTING_TABLE.get().unwrap().0[destination as usize][0] - 1;
         let master_destination = get_master_destination();
-        partition_data(
-            linkno,
-            message,
-            |slice, status, len| Packet::SubkernelMessage {
-                source: master_destination,
-                destination: destination,
-                id: id,
-                status: status,
-                length: len as u16,
-                data: *slice,
-            },
-            |reply| match reply {
-                Packet::SubkernelMessageAck { .. } => Ok(()),
-                _ => Err(Error::UnexpectedReply),
+        // slices are sent one at a time; a receiver that runs out of buffer space
+        // acknowledges with no credits and sends a credit update once it has caught up
+        MESSAGE_CREDITS.async_lock().await.remove(&destination);
+        let mut i = 0;
+        while i < message.len() {
+            let mut slice: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
+            let len = min(MASTER_PAYLOAD_MAX_SIZE, message.len() - i);
+            let first = i == 0;
+            let last = i + len == message.len();
+            slice[..len].clone_from_slice(&message[i..i + len]);
+            i += len;
+            let reply = aux_transact(
+                linkno,
+                &Packet::SubkernelMessage {
+                    source: master_destination,
+                    destination: destination,
+                    id: id,
+                    status: PayloadStatus::from_status(first, last),
+                    length: len as u16,
+                    data: slice,
+                },
+            )
+            .await?;
+            match reply {
+                Packet::SubkernelMessageAck { credits: 0, .. } if !last => {
+                    wait_message_credit(linkno, destination).await?;
+                }
+                Packet::SubkernelMessageAck { .. } => (),
+                _ => return Err(Error::UnexpectedReply),
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn subkernel_barrier_release(destination: u8, id: u32, succeeded: bool) -> Result<(), Error> {
+        let linkno = ROUTING_TABLE.get().unwrap().0[destination as usize][0] - 1;
+        // sent without a transaction, as it may be issued while another one is in progress
+        let packet = Packet::SubkernelBarrierRelease {
+            destination: destination,
+            id: id,
+            succeeded: succeeded,
+        };
+        if !link_rx_up(linkno).await {
+            return Err(Error::LinkDown);
+        }
+        drtioaux_async::send(linkno, &packet).await?;
+        Ok(())
+    }
+
+    pub async fn destination_counter(destination: u8) -> Result<u64, Error> {
+        let hop = ROUTING_TABLE.get().unwrap().0[destination as usize][0];
+        if hop == 0 {
+            unsafe {
+                csr::rtio::counter_update_write(1);
+                Ok(csr::rtio::counter_read())
+            }
+        } else if hop as usize <= csr::DRTIO.len() {
+            let linkno = hop - 1;
+            let reply = aux_transact(linkno, &Packet::TimestampRequest { destination }).await?;
+            match reply {
+                Packet::TimestampReply { timestamp } => Ok(timestamp),
+                packet => {
+                    error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet);
+                    Err(Error::UnexpectedReply)
+                }
+            }
+        } else {
+            Err(Error::LinkDown)
+        }
+    }
+
+    // Returns the frame number and ROI counts of a grabber, None if it is not aligned
+    // or does not exist.
+    pub async fn grabber_roi_counts(
+        destination: u8,
+        grabber: u8,
+    ) -> Result<Option<(u32, [u32; GRABBER_ROI_COUNT])>, Error> {
+        let hop = ROUTING_TABLE.get().unwrap().0[destination as usize][0];
+        if hop == 0 {
+            #[cfg(has_grabber)]
+            return Ok(libboard_artiq::grabber::roi_counts(grabber as usize));
+            #[cfg(not(has_grabber))]
+            return Ok(None);
+        }
+        if hop as usize > csr::DRTIO.len() {
+            return Err(Error::LinkDown);
+        }
+        let reply = aux_transact(hop - 1, &Packet::GrabberRoiRequest { destination, grabber }).await?;
+        match reply {
+            Packet::GrabberRoiReply {
+                succeeded: true,
+                frame,
+                counts,
+            } => Ok(Some((frame, counts))),
+            Packet::GrabberRoiReply { succeeded: false, .. } => Ok(None),
+            packet => {
+                error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet);
+                Err(Error::UnexpectedReply)
+            }
+        }
+    }
+
+    // Returns the config usage of a satellite, asking for one key at a time.
+    pub async fn storage_stats(destination: u8, keys: &[String]) -> Result<StorageStats, Error> {
+        let hop = ROUTING_TABLE.get().unwrap().0[destination as usize][0];
+        if hop == 0 || hop as usize > csr::DRTIO.len() {
+            return Err(Error::LinkDown);
+        }
+        if !link_supports(hop - 1, FEATURE_STORAGE_STATS) {
+            return Err(Error::Unsupported);
+        }
+        let (total, free) = match aux_transact(hop - 1, &Packet::StorageSpaceRequest { destination }).await? {
+            Packet::StorageSpaceReply { known: true, total, free } => (total as i64, free as i64),
+            Packet::StorageSpaceReply { known: false, .. } => (-1, -1),
+            packet => {
+                error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet);
+                return Err(Error::UnexpectedReply);
+            }
+        };
+        let mut sizes = Vec::with_capacity(keys.len());
+        for key in keys {
+            // such keys cannot be stored either
+            if key.len() > MASTER_PAYLOAD_MAX_SIZE {
+                sizes.push(-1);
+                continue;
+            }
+            let mut key_slice = [0; MASTER_PAYLOAD_MAX_SIZE];
+            key_slice[..key.len()].copy_from_slice(key.as_bytes());
+            let reply = aux_transact(
+                hop - 1,
+                &Packet::StorageStatsRequest {
+                    destination: destination,
+                    length: key.len() as u16,
+                    key: key_slice,
+                },
+            )
+            .await?;
+            match reply {
+                Packet::StorageStatsReply { set: true, size } => sizes.push(size as i64),
+                Packet::StorageStatsReply { set: false, .. } => sizes.push(-1),
+                packet => {
+                    error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet);
+                    return Err(Error::UnexpectedReply);
+                }
+            }
+        }
+        Ok(StorageStats {
+            total: total,
+            free: free,
+            sizes: sizes,
+        })
+    }
+
+    // Returns what ended the batch along with the (timestamp, data) of the events read.
+    pub async fn rtio_input_batch(
+        channel: u32,
+        timeout: i64,
+        max_count: usize,
+    ) -> Result<(u8, Vec<(i64, i32)>), Error> {
+        let destination = (channel >> 16) as u8;
+        let hop = ROUTING_TABLE.get().unwrap().0[destination as usize][0];
+        if hop == 0 || hop as usize > csr::DRTIO.len() {
+            return Err(Error::LinkDown);
+        }
+        if !link_supports(hop - 1, FEATURE_INPUT_BATCH) {
+            return Err(Error::Unsupported);
+        }
+        let reply = aux_transact(
+            hop - 1,
+            &Packet::RtioInputBatchRequest {
+                destination,
+                channel,
+                timeout: timeout as u64,
+                max_count: min(max_count, RTIO_INPUT_BATCH_SIZE) as u8,
             },
         )
-        .await
+        .await?;
+        match reply {
+            Packet::RtioInputBatchReply {
+                status,
+                count,
+                timestamps,
+                data,
+            } => Ok((
+                status,
+                (0..count as usize)
+                    .map(|i| (timestamps[i] as i64, data[i] as i32))
+                    .collect(),
+            )),
+            packet => {
+                error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet);
+                Err(Error::UnexpectedReply)
+            }
+        }
     }
 
     pub async fn i2c_send_basic(request: &KernelMessage, busno: u32) -> Result<bool, Error> {
@@ -917,6 +2021,75 @@ pub mod drtio {
             _ => Err(Error::UnexpectedReply),
         }
     }
+
+    // split into as many aux transactions as the payload size requires
+    pub async fn i2c_send_write_block(busno: u32, data: &[u8]) -> Result<(bool, usize), Error> {
+        let destination = (busno >> 16) as u8;
+        let busno = busno as u8;
+        let linkno = ROUTING_TABLE.get().unwrap().0[destination as usize][0] - 1;
+        let mut acked = 0;
+        for chunk in data.chunks(MASTER_PAYLOAD_MAX_SIZE) {
+            let mut payload = [0; MASTER_PAYLOAD_MAX_SIZE];
+            payload[..chunk.len()].copy_from_slice(chunk);
+            let reply = aux_transact(
+                linkno,
+                &Packet::I2cWriteBlockRequest {
+                    destination,
+                    busno,
+                    length: chunk.len() as u16,
+                    data: payload,
+                },
+            )
+            .await?;
+            match reply {
+                Packet::I2cWriteBlockReply {
+                    succeeded: true,
+                    acked: chunk_acked,
+                } => {
+                    acked += chunk_acked as usize;
+                    if (chunk_acked as usize) < chunk.len() {
+                        break;
+                    }
+                }
+                Packet::I2cWriteBlockReply { succeeded: false, .. } => return Ok((false, acked)),
+                _ => return Err(Error::UnexpectedReply),
+            }
+        }
+        Ok((true, acked))
+    }
+
+    pub async fn i2c_send_read_block(busno: u32, length: usize, ack: bool) -> Result<(bool, Vec<u8>), Error> {
+        let destination = (busno >> 16) as u8;
+        let busno = busno as u8;
+        let linkno = ROUTING_TABLE.get().unwrap().0[destination as usize][0] - 1;
+        let mut data = Vec::with_capacity(length);
+        while data.len() < length {
+            let chunk_length = min(length - data.len(), SAT_PAYLOAD_MAX_SIZE);
+            // only the last byte of the whole block may be left unacknowledged
+            let last = data.len() + chunk_length == length;
+            let reply = aux_transact(
+                linkno,
+                &Packet::I2cReadBlockRequest {
+                    destination,
+                    busno,
+                    length: chunk_length as u16,
+                    ack: ack || !last,
+                },
+            )
+            .await?;
+            match reply {
+                // an empty chunk would have the loop ask again forever
+                Packet::I2cReadBlockReply {
+                    succeeded: true,
+                    length,
+                    data: chunk,
+                } if length > 0 && length as usize <= chunk_length => data.extend_from_slice(&chunk[..length as usize]),
+                Packet::I2cReadBlockReply { succeeded: false, .. } => return Ok((false, data)),
+                _ => return Err(Error::UnexpectedReply),
+            }
+        }
+        Ok((true, data))
+    }
 }
 
 #[cfg(not(has_drtio))]
@@ -929,7 +2102,7 @@ pub mod drtio {
     pub fn reset() {}
 }
 
-fn toggle_sed_spread(val: u8) {
+pub fn toggle_sed_spread(val: u8) {
     unsafe {
         csr::rtio_core::sed_spread_enable_write(val);
     }
@@ -0,0 +1,155 @@
+#[cfg(has_drtio)]
+use alloc::format;
+use alloc::{string::String, vec::Vec};
+
+#[cfg(feature = "target_kasli_soc")]
+use libboard_artiq::{i2c, io_expander};
+use libboard_artiq::{config_store, pl::csr};
+use libboard_zynq::timer;
+use libconfig;
+use libcortex_a9::mutex::Mutex;
+use log::{error, info};
+
+#[cfg(has_drtio)]
+use crate::rtio_mgt::drtio;
+use crate::storage_health;
+
+// Optional power-on self test (config key `selftest`), so that a crate can be
+// checked remotely after maintenance. Each check is recorded with a short
+// reason when it fails; the report is kept until the next boot.
+
+const PROBE_KEY: &str = "selftest_probe";
+// channel whose injection value the moninj check may toggle, unset to skip the check
+const MONINJ_CHANNEL_KEY: &str = "selftest_moninj_channel";
+#[cfg(has_drtio)]
+const LINK_WAIT_MS: u64 = 5000;
+
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub name: String,
+    pub passed: bool,
+    pub detail: &'static str,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub finished_ms: u64,
+    pub outcomes: Vec<Outcome>,
+}
+
+static REPORT: Mutex<Option<Report>> = Mutex::new(None);
+
+pub fn enabled() -> bool {
+    libconfig::read_str("selftest").map_or(false, |selftest| selftest == "1")
+}
+
+pub fn report() -> Option<Report> {
+    REPORT.lock().clone()
+}
+
+#[cfg(feature = "target_kasli_soc")]
+fn check_i2c() -> Result<(), &'static str> {
+    // the I2C switch sits in front of every other device on the bus
+    let i2c_bus = i2c::get_bus();
+    i2c_bus.start()?;
+    let ack = i2c_bus.write(0x70 << 1);
+    i2c_bus.stop()?;
+    ack.map_err(|_| "I2C switch did not acknowledge")
+}
+
+#[cfg(feature = "target_kasli_soc")]
+fn check_io_expanders() -> Result<(), &'static str> {
+    let i2c_bus = i2c::get_bus();
+    io_expander::IoExpander::new(i2c_bus, 0)?;
+    io_expander::IoExpander::new(i2c_bus, 1)?;
+    Ok(())
+}
+
+fn check_sd_card() -> Result<(), &'static str> {
+    if storage_health::read_only() {
+        return Err("config storage is read-only");
+    }
+    let pattern = b"ARTIQ self test".to_vec();
+    config_store::write(PROBE_KEY, pattern.clone()).map_err(|_| "write failed")?;
+    let readback = libconfig::read(PROBE_KEY).map_err(|_| "read back failed")?;
+    let _ = config_store::remove(PROBE_KEY);
+    if readback != pattern {
+        return Err("read back differs");
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "target_ebaz4205"))]
+fn check_clock() -> Result<(), &'static str> {
+    if unsafe { csr::sys_crg::current_clock_read() } != 1 {
+        return Err("sys clock not switched to the RTIO clock");
+    }
+    Ok(())
+}
+
+fn moninj_channel() -> Option<u32> {
+    libconfig::read_str(MONINJ_CHANNEL_KEY)
+        .ok()
+        .and_then(|channel| channel.trim().parse().ok())
+}
+
+fn check_moninj(channel: u32) -> Result<(), &'static str> {
+    // toggles the override value of the channel and restores it, without enabling the override;
+    // the channel is chosen in the config, as on most systems any channel is a live output
+    unsafe {
+        csr::rtio_moninj::inj_chan_sel_write(channel as _);
+        csr::rtio_moninj::inj_override_sel_write(1);
+        let value = csr::rtio_moninj::inj_value_read();
+        csr::rtio_moninj::inj_value_write(value ^ 1);
+        let readback = csr::rtio_moninj::inj_value_read();
+        csr::rtio_moninj::inj_value_write(value);
+        if readback != value ^ 1 || csr::rtio_moninj::inj_value_read() != value {
+            return Err("injection readback differs");
+        }
+    }
+    Ok(())
+}
+
+fn record(outcomes: &mut Vec<Outcome>, name: String, result: Result<(), &'static str>) {
+    match result {
+        Ok(()) => info!("self test {}: passed", name),
+        Err(detail) => error!("self test {}: FAILED ({})", name, detail),
+    }
+    outcomes.push(Outcome {
+        name: name,
+        passed: result.is_ok(),
+        detail: result.err().unwrap_or(""),
+    });
+}
+
+pub async fn run() {
+    info!("running self test...");
+    let mut outcomes = Vec::new();
+    #[cfg(feature = "target_kasli_soc")]
+    {
+        record(&mut outcomes, String::from("i2c"), check_i2c());
+        record(&mut outcomes, String::from("io_expanders"), check_io_expanders());
+    }
+    record(&mut outcomes, String::from("sd_card"), check_sd_card());
+    #[cfg(not(feature = "target_ebaz4205"))]
+    record(&mut outcomes, String::from("clock"), check_clock());
+    match moninj_channel() {
+        Some(channel) => record(&mut outcomes, String::from("moninj"), check_moninj(channel)),
+        None => info!("self test moninj: skipped, {} is not set", MONINJ_CHANNEL_KEY),
+    }
+    #[cfg(has_drtio)]
+    {
+        // give the links the time to come up before checking them
+        timer::async_delay_ms(LINK_WAIT_MS).await;
+        for linkno in 0..csr::DRTIO.len() {
+            let result = drtio::link_echo(linkno as u8).await;
+            record(&mut outcomes, format!("drtio_link{}", linkno), result);
+        }
+    }
+    let failed = outcomes.iter().filter(|outcome| !outcome.passed).count();
+    info!("self test finished, {} of {} checks failed", failed, outcomes.len());
+    *REPORT.lock() = Some(Report {
+        finished_ms: timer::get_ms(),
+        outcomes: outcomes,
+    });
+}
@@ -0,0 +1,57 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use libconfig;
+use log::{info, warn};
+
+// Optional services, selected at boot by the `services_enable` config key: a comma-separated
+// list out of `moninj`, `analyzer`, `events` and `console`. All of them run without it.
+// Disabled servers are not started, and disabled management push requests are refused.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    Moninj = 0,
+    Analyzer = 1,
+    Events = 2,
+    Console = 3,
+}
+
+pub const SERVICES: [Service; 4] = [Service::Moninj, Service::Analyzer, Service::Events, Service::Console];
+
+static ENABLED: AtomicU8 = AtomicU8::new(0x0f);
+
+impl Service {
+    pub fn name(self) -> &'static str {
+        match self {
+            Service::Moninj => "moninj",
+            Service::Analyzer => "analyzer",
+            Service::Events => "events",
+            Service::Console => "console",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << (self as u8)
+    }
+}
+
+pub fn load() {
+    let text = match libconfig::read_str("services_enable") {
+        Ok(text) => text,
+        Err(_) => return,
+    };
+    let mut enabled = 0;
+    for name in text.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        match SERVICES.iter().find(|service| service.name() == name) {
+            Some(service) => enabled |= service.bit(),
+            None => warn!("service \"{}\" not supported, ignoring", name),
+        }
+    }
+    for service in SERVICES.iter().filter(|service| enabled & service.bit() == 0) {
+        info!("{} service disabled", service.name());
+    }
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled(service: Service) -> bool {
+    ENABLED.load(Ordering::Relaxed) & service.bit() != 0
+}
@@ -0,0 +1,107 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use byteorder::{ByteOrder, NativeEndian};
+use crc::crc32;
+use libboard_artiq::config_store;
+use libboard_zynq::timer;
+use libconfig;
+use log::{error, info, warn};
+
+// The SD card holding the config is checked periodically by writing a sentinel
+// (a sequence number followed by its CRC), reading it back, and verifying the sentinel
+// left by the previous check. After a few failed checks in a row the config is switched
+// to read-only: writes, which all go through config_store, are refused with an error
+// instead of failing (or panicking) in the middle of an experiment.
+
+const SENTINEL_KEY: &str = "storage_sentinel";
+const CHECK_INTERVAL_MS: u64 = 60_000;
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+static CHECKS: AtomicU32 = AtomicU32::new(0);
+static FAILURES: AtomicU32 = AtomicU32::new(0);
+static CONSECUTIVE_FAILURES: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Status {
+    pub read_only: bool,
+    pub checks: u32,
+    pub failures: u32,
+    pub consecutive_failures: u32,
+}
+
+pub fn read_only() -> bool {
+    config_store::read_only()
+}
+
+pub fn status() -> Status {
+    Status {
+        read_only: read_only(),
+        checks: CHECKS.load(Ordering::Relaxed),
+        failures: FAILURES.load(Ordering::Relaxed),
+        consecutive_failures: CONSECUTIVE_FAILURES.load(Ordering::Relaxed),
+    }
+}
+
+fn encode_sentinel(sequence: u32) -> Vec<u8> {
+    let mut sentinel = [0; 8];
+    NativeEndian::write_u32(&mut sentinel[0..4], sequence);
+    NativeEndian::write_u32(&mut sentinel[4..8], crc32::checksum_ieee(&sentinel[0..4]));
+    sentinel.to_vec()
+}
+
+fn decode_sentinel(sentinel: &[u8]) -> Option<u32> {
+    if sentinel.len() != 8 || NativeEndian::read_u32(&sentinel[4..8]) != crc32::checksum_ieee(&sentinel[0..4]) {
+        return None;
+    }
+    Some(NativeEndian::read_u32(&sentinel[0..4]))
+}
+
+fn check(sequence: u32) -> Result<(), &'static str> {
+    // the first check has nothing to compare against, later ones expect their predecessor
+    match libconfig::read(SENTINEL_KEY) {
+        Ok(previous) => match decode_sentinel(&previous) {
+            Some(previous) if sequence == 0 || previous == sequence - 1 => (),
+            Some(_) => return Err("stale sentinel"),
+            None => return Err("sentinel CRC mismatch"),
+        },
+        Err(_) if sequence == 0 => (),
+        Err(_) => return Err("sentinel missing"),
+    }
+    let sentinel = encode_sentinel(sequence);
+    config_store::write(SENTINEL_KEY, sentinel.clone()).map_err(|_| "sentinel write failed")?;
+    match libconfig::read(SENTINEL_KEY) {
+        Ok(readback) if readback == sentinel => Ok(()),
+        Ok(_) => Err("sentinel read back differs"),
+        Err(_) => Err("sentinel read back failed"),
+    }
+}
+
+pub async fn monitor() {
+    let mut sequence = 0;
+    loop {
+        CHECKS.fetch_add(1, Ordering::Relaxed);
+        match check(sequence) {
+            Ok(()) => {
+                if CONSECUTIVE_FAILURES.swap(0, Ordering::Relaxed) > 0 {
+                    info!("config storage check passed again");
+                }
+                sequence += 1;
+            }
+            Err(err) => {
+                FAILURES.fetch_add(1, Ordering::Relaxed);
+                let consecutive = CONSECUTIVE_FAILURES.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!("config storage check failed: {} ({} in a row)", err, consecutive);
+                // restart the sequence, so a recovered card is not failed on a stale sentinel
+                sequence = 0;
+                if consecutive >= MAX_CONSECUTIVE_FAILURES {
+                    config_store::set_read_only();
+                    error!("config storage is failing, switching config to read-only");
+                    // no more test writes to a failing card
+                    return;
+                }
+            }
+        }
+        timer::async_delay_ms(CHECK_INTERVAL_MS).await;
+    }
+}
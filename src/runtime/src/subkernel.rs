@@ -1,7 +1,8 @@
 use alloc::{collections::BTreeMap, vec::Vec};
 
 use libasync::task;
-use libboard_artiq::drtioaux_proto::{MASTER_PAYLOAD_MAX_SIZE, PayloadStatus};
+use libboard_artiq::{drtio_routing::{mask_contains, mask_insert},
+                     drtioaux_proto::{DESTINATION_MASK_SIZE, MASTER_PAYLOAD_MAX_SIZE, PayloadStatus}};
 use libboard_zynq::timer;
 use libcortex_a9::mutex::Mutex;
 use log::{error, warn};
@@ -30,6 +31,7 @@ pub enum Error {
     SubkernelNotFound,
     SubkernelException,
     CommLost,
+    BarrierFailed,
     DrtioError(DrtioError),
 }
 
@@ -81,12 +83,12 @@ pub async fn upload(id: u32) -> Result<(), Error> {
     }
 }
 
-pub async fn load(id: u32, run: bool, timestamp: u64) -> Result<(), Error> {
+pub async fn load(id: u32, run: bool, timestamp: u64, relative: bool) -> Result<(), Error> {
     if let Some(subkernel) = SUBKERNELS.async_lock().await.get_mut(&id) {
         if subkernel.state != SubkernelState::Uploaded {
             return Err(Error::IncorrectState);
         }
-        drtio::subkernel_load(id, subkernel.destination, run, timestamp).await?;
+        drtio::subkernel_load(id, subkernel.destination, run, timestamp, relative).await?;
         if run {
             subkernel.state = SubkernelState::Running;
         }
@@ -100,6 +102,8 @@ pub async fn clear_subkernels() {
     SUBKERNELS.async_lock().await.clear();
     MESSAGE_QUEUE.async_lock().await.clear();
     CURRENT_MESSAGES.async_lock().await.clear();
+    BARRIERS.async_lock().await.clear();
+    BARRIER_RESULTS.async_lock().await.clear();
 }
 
 pub async fn subkernel_finished(id: u32, with_exception: bool, exception_src: u8) {
@@ -187,6 +191,79 @@ pub async fn await_finish(id: u32, timeout: i64) -> Result<SubkernelFinished, Er
     }
 }
 
+// Fetches a dataset buffered by a subkernel on its satellite, once it is no longer running.
+pub async fn dataset_fetch(id: u32, name: &str) -> Result<Option<Vec<u8>>, Error> {
+    let destination = match SUBKERNELS.async_lock().await.get(&id) {
+        Some(subkernel) if subkernel.state == SubkernelState::Running => return Err(Error::IncorrectState),
+        Some(subkernel) => subkernel.destination,
+        None => return Err(Error::SubkernelNotFound),
+    };
+    Ok(drtio::subkernel_dataset_fetch(destination, id, name).await?)
+}
+
+// Waits for every subkernel in `ids` with a single deadline. Returns the outcome of each,
+// in order: those still running at the deadline time out, the others are collected
+// like with `await_finish`.
+pub async fn await_many(ids: &[u32], timeout: i64) -> Vec<Result<SubkernelFinished, Error>> {
+    let max_time = if timeout > 0 {
+        Some(timer::get_ms() + timeout as u64)
+    } else {
+        None
+    };
+    loop {
+        let running = {
+            let subkernels = SUBKERNELS.async_lock().await;
+            ids.iter().any(|id| match subkernels.get(id) {
+                Some(subkernel) => subkernel.state == SubkernelState::Running,
+                None => false,
+            })
+        };
+        if !running {
+            break;
+        }
+        if let Some(max_time) = max_time {
+            if timer::get_ms() >= max_time {
+                error!("Remote subkernel finish await timed out");
+                break;
+            }
+        }
+        task::r#yield().await;
+    }
+
+    let mut results = Vec::with_capacity(ids.len());
+    for &id in ids {
+        let status = match SUBKERNELS.async_lock().await.get_mut(&id) {
+            Some(subkernel) => match subkernel.state {
+                SubkernelState::Finished { status } => {
+                    subkernel.state = SubkernelState::Uploaded;
+                    Ok(status)
+                }
+                SubkernelState::Running => Err(Error::Timeout),
+                _ => Err(Error::IncorrectState),
+            },
+            None => Err(Error::SubkernelNotFound),
+        };
+        let result = match status {
+            Ok(FinishStatus::Exception(destination)) => match drtio::subkernel_retrieve_exception(destination).await {
+                Ok(exception) => Ok(SubkernelFinished {
+                    id: id,
+                    status: FinishStatus::Exception(destination),
+                    exception: Some(exception),
+                }),
+                Err(e) => Err(e.into()),
+            },
+            Ok(status) => Ok(SubkernelFinished {
+                id: id,
+                status: status,
+                exception: None,
+            }),
+            Err(e) => Err(e),
+        };
+        results.push(result);
+    }
+    results
+}
+
 pub struct Message {
     from_id: u32,
     pub count: u8,
@@ -284,3 +361,85 @@ pub async fn message_await(id: u32, timeout: i64) -> Result<Message, Error> {
 pub async fn message_send<'a>(id: u32, destination: u8, message: Vec<u8>) -> Result<(), Error> {
     Ok(drtio::subkernel_send_message(id, destination, &message).await?)
 }
+
+// Barriers are aggregated on the master: every participant (satellites through
+// SubkernelBarrierArrive, the master kernel directly) reports its arrival, and once
+// all of them have arrived, the satellites are released and the master result is recorded.
+// A participant that times out withdraws its arrival, and a barrier is dropped once all of
+// its arrivals are withdrawn, so that arrivals after the timeout of the others do not stay
+// behind for the next use of the barrier.
+struct Barrier {
+    participants: [u8; DESTINATION_MASK_SIZE],
+    arrived: [u8; DESTINATION_MASK_SIZE],
+    failed: bool,
+}
+
+static BARRIERS: Mutex<BTreeMap<u32, Barrier>> = Mutex::new(BTreeMap::new());
+// outcome of barriers the master kernel takes part in, until it picks them up
+static BARRIER_RESULTS: Mutex<BTreeMap<u32, bool>> = Mutex::new(BTreeMap::new());
+
+pub async fn barrier_arrive(source: u8, id: u32, participants: &[u8; DESTINATION_MASK_SIZE]) {
+    let master_destination = drtio::get_master_destination();
+    let barrier = {
+        let mut barriers = BARRIERS.async_lock().await;
+        let barrier = barriers.entry(id).or_insert_with(|| Barrier {
+            participants: *participants,
+            arrived: [0; DESTINATION_MASK_SIZE],
+            failed: false,
+        });
+        if barrier.participants != *participants || !mask_contains(participants, source) {
+            error!("barrier #{}: inconsistent arrival from destination {}", id, source);
+            barrier.failed = true;
+        }
+        mask_insert(&mut barrier.arrived, source);
+        if !barrier.failed && barrier.arrived != barrier.participants {
+            return;
+        }
+        barriers.remove(&id).unwrap()
+    };
+    let succeeded = !barrier.failed;
+    for destination in 0..(DESTINATION_MASK_SIZE * 8) {
+        let destination = destination as u8;
+        if !mask_contains(&barrier.arrived, destination) {
+            continue;
+        }
+        if destination == master_destination {
+            BARRIER_RESULTS.async_lock().await.insert(id, succeeded);
+        } else if let Err(e) = drtio::subkernel_barrier_release(destination, id, succeeded).await {
+            // the participant times out and its kernel gets the error
+            error!("barrier #{}: cannot release destination {} ({})", id, destination, e);
+        }
+    }
+}
+
+pub async fn barrier_withdraw(source: u8, id: u32) {
+    let mut barriers = BARRIERS.async_lock().await;
+    if let Some(barrier) = barriers.get_mut(&id) {
+        barrier.arrived[source as usize / 8] &= !(1 << (source % 8));
+        if barrier.arrived == [0; DESTINATION_MASK_SIZE] {
+            barriers.remove(&id);
+        }
+    }
+}
+
+pub async fn barrier_wait(id: u32, participants: &[u8], timeout: i64) -> Result<(), Error> {
+    let master_destination = drtio::get_master_destination();
+    let mut mask = [0; DESTINATION_MASK_SIZE];
+    for destination in participants {
+        mask_insert(&mut mask, *destination);
+    }
+    barrier_arrive(master_destination, id, &mask).await;
+    let max_time = timer::get_ms() + timeout as u64;
+    loop {
+        if let Some(succeeded) = BARRIER_RESULTS.async_lock().await.remove(&id) {
+            return if succeeded { Ok(()) } else { Err(Error::BarrierFailed) };
+        }
+        if timeout > 0 && timer::get_ms() >= max_time {
+            // withdraw, so the barrier is not released without the master
+            barrier_withdraw(master_destination, id).await;
+            error!("barrier #{} timed out", id);
+            return Err(Error::Timeout);
+        }
+        task::r#yield().await;
+    }
+}
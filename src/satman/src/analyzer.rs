@@ -1,21 +1,50 @@
-use core::cmp::min;
+use alloc::alloc::{Layout, alloc_zeroed};
+use core::{cmp::min,
+           ptr, slice,
+           sync::atomic::{AtomicPtr, AtomicUsize, Ordering}};
 
 use libboard_artiq::{drtioaux_proto::SAT_PAYLOAD_MAX_SIZE, pl::csr};
 use libcortex_a9::cache;
 
-const BUFFER_SIZE: usize = 512 * 1024;
+use crate::memory_budget;
 
-#[repr(align(64))]
-struct Buffer {
-    data: [u8; BUFFER_SIZE],
+const ALIGNMENT: usize = 64;
+
+// allocated once at boot, with the size given by the memory budget
+static BUFFER: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+static BUFFER_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init() {
+    let size = memory_budget::analyzer() / ALIGNMENT * ALIGNMENT;
+    if size == 0 {
+        info!("analyzer disabled by the memory budget");
+        return;
+    }
+    let buffer = unsafe { alloc_zeroed(Layout::from_size_align(size, ALIGNMENT).unwrap()) };
+    if buffer.is_null() {
+        error!("cannot allocate {} bytes for the analyzer buffer, analyzer disabled", size);
+        return;
+    }
+    BUFFER.store(buffer, Ordering::Relaxed);
+    BUFFER_SIZE.store(size, Ordering::Relaxed);
 }
 
-static BUFFER: Buffer = Buffer { data: [0; BUFFER_SIZE] };
+fn buffer() -> &'static [u8] {
+    let size = BUFFER_SIZE.load(Ordering::Relaxed);
+    if size == 0 {
+        return &[];
+    }
+    unsafe { slice::from_raw_parts(BUFFER.load(Ordering::Relaxed), size) }
+}
 
 fn arm() {
+    let buffer = buffer();
+    if buffer.is_empty() {
+        return;
+    }
     unsafe {
-        let base_addr = (&raw const BUFFER.data[0]).addr();
-        let last_addr = (&raw const BUFFER.data[BUFFER_SIZE - 1]).addr();
+        let base_addr = (&raw const buffer[0]).addr();
+        let last_addr = (&raw const buffer[buffer.len() - 1]).addr();
         csr::rtio_analyzer::dma_base_address_write(base_addr as u32);
         csr::rtio_analyzer::message_encoder_overflow_reset_write(1);
         csr::rtio_analyzer::dma_last_address_write(last_addr as u32);
@@ -25,11 +54,15 @@ fn arm() {
 }
 
 fn disarm() {
+    let buffer = buffer();
+    if buffer.is_empty() {
+        return;
+    }
     unsafe {
         csr::rtio_analyzer::enable_write(0);
         while csr::rtio_analyzer::busy_read() != 0 {}
-        cache::dcci_slice(&BUFFER.data);
     }
+    cache::dcci_slice(buffer);
 }
 
 pub struct Analyzer {
@@ -37,6 +70,8 @@ pub struct Analyzer {
     data_len: usize,
     sent_bytes: usize,
     data_pointer: usize,
+    // armed by the master around kernel runs, rather than again after each readout
+    synchronized: bool,
 }
 
 pub struct Header {
@@ -64,23 +99,47 @@ impl Analyzer {
             data_len: 0,
             sent_bytes: 0,
             data_pointer: 0,
+            synchronized: false,
         }
     }
 
+    // returns false if there is no buffer to capture into
+    pub fn set_armed(&mut self, armed: bool, synchronized: bool) -> bool {
+        self.synchronized = synchronized;
+        if armed {
+            arm();
+        } else {
+            disarm();
+        }
+        !buffer().is_empty()
+    }
+
     pub fn get_header(&mut self) -> Header {
         disarm();
 
+        let buffer_size = buffer().len();
+        if buffer_size == 0 {
+            // never armed, there is nothing to send
+            self.data_len = 0;
+            self.data_pointer = 0;
+            self.sent_bytes = 0;
+            return Header {
+                total_byte_count: 0,
+                sent_bytes: 0,
+                error: false,
+            };
+        }
         let overflow = unsafe { csr::rtio_analyzer::message_encoder_overflow_read() != 0 };
         let bus_err = unsafe { csr::rtio_analyzer::dma_bus_error_read() != 0 };
         let total_byte_count = unsafe { csr::rtio_analyzer::dma_byte_count_read() as u64 };
-        let wraparound = total_byte_count >= BUFFER_SIZE as u64;
+        let wraparound = total_byte_count >= buffer_size as u64;
         self.data_len = if wraparound {
-            BUFFER_SIZE
+            buffer_size
         } else {
             total_byte_count as usize
         };
         self.data_pointer = if wraparound {
-            (total_byte_count % BUFFER_SIZE as u64) as usize
+            (total_byte_count % buffer_size as u64) as usize
         } else {
             0
         };
@@ -101,20 +160,21 @@ impl Analyzer {
     }
 
     pub fn get_data(&mut self, data_slice: &mut [u8; SAT_PAYLOAD_MAX_SIZE]) -> AnalyzerSliceMeta {
-        let data = &BUFFER.data[..];
-        let i = (self.data_pointer + self.sent_bytes) % BUFFER_SIZE;
+        let data = buffer();
+        let buffer_size = data.len().max(1);
+        let i = (self.data_pointer + self.sent_bytes) % buffer_size;
         let len = min(SAT_PAYLOAD_MAX_SIZE, self.data_len - self.sent_bytes);
         let last = self.sent_bytes + len == self.data_len;
 
-        if i + len >= BUFFER_SIZE {
-            data_slice[..(BUFFER_SIZE - i)].clone_from_slice(&data[i..BUFFER_SIZE]);
-            data_slice[(BUFFER_SIZE - i)..len].clone_from_slice(&data[..(i + len) % BUFFER_SIZE]);
+        if i + len >= buffer_size {
+            data_slice[..(buffer_size - i)].clone_from_slice(&data[i..buffer_size]);
+            data_slice[(buffer_size - i)..len].clone_from_slice(&data[..(i + len) % buffer_size]);
         } else {
             data_slice[..len].clone_from_slice(&data[i..i + len]);
         }
         self.sent_bytes += len;
 
-        if last {
+        if last && !self.synchronized {
             arm();
         }
 
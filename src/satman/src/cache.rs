@@ -0,0 +1,131 @@
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::mem::size_of;
+
+use log::{info, warn};
+
+// limits on the memory taken by kernel cache values, in bytes
+const DEFAULT_KEY_QUOTA: usize = 4 * 1024 * 1024;
+const DEFAULT_TOTAL_QUOTA: usize = 32 * 1024 * 1024;
+
+struct Entry {
+    value: Vec<i32>,
+    last_used: u64,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        self.value.len() * size_of::<i32>()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Status {
+    pub entries: u32,
+    pub size: u32,
+    pub quota: u32,
+    pub evictions: u32,
+    pub rejections: u32,
+}
+
+// Kernel cache shared by all subkernel sessions, least recently used values
+// are evicted to stay within the total quota.
+pub struct Cache {
+    entries: BTreeMap<String, Entry>,
+    size: usize,
+    key_quota: usize,
+    total_quota: usize,
+    uses: u64,
+    evictions: u32,
+    rejections: u32,
+}
+
+fn parse_quotas() -> (usize, usize) {
+    let mut key_quota = DEFAULT_KEY_QUOTA;
+    let mut total_quota = DEFAULT_TOTAL_QUOTA;
+    if let Ok(config) = libconfig::read_str("kernel_cache_quota") {
+        for entry in config.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+            let mut parts = entry.splitn(2, '=');
+            let name = parts.next().unwrap().trim();
+            let bytes = parts.next().and_then(|bytes| bytes.trim().parse::<usize>().ok());
+            match (name, bytes) {
+                ("key", Some(bytes)) => key_quota = bytes,
+                ("total", Some(bytes)) => total_quota = bytes,
+                _ => warn!("kernel_cache_quota entry \"{}\" not supported, ignoring", entry),
+            }
+        }
+    }
+    (key_quota, total_quota)
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        let (key_quota, total_quota) = parse_quotas();
+        info!("kernel cache quota: {} bytes per key, {} bytes in total", key_quota, total_quota);
+        Cache {
+            entries: BTreeMap::new(),
+            size: 0,
+            key_quota: key_quota,
+            total_quota: total_quota,
+            uses: 0,
+            evictions: 0,
+            rejections: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<&Vec<i32>> {
+        self.uses += 1;
+        let uses = self.uses;
+        self.entries.get_mut(key).map(|entry| {
+            entry.last_used = uses;
+            &entry.value
+        })
+    }
+
+    pub fn put(&mut self, key: String, value: Vec<i32>) {
+        if let Some(old) = self.entries.remove(&key) {
+            self.size -= old.size();
+        }
+        let size = value.len() * size_of::<i32>();
+        if size > self.key_quota.min(self.total_quota) {
+            warn!("kernel cache value of {} bytes for \"{}\" exceeds the quota, dropped", size, key);
+            self.rejections += 1;
+            return;
+        }
+        while self.size + size > self.total_quota {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+                .unwrap();
+            let evicted = self.entries.remove(&oldest).unwrap();
+            self.size -= evicted.size();
+            self.evictions += 1;
+            info!("evicted \"{}\" ({} bytes) from the kernel cache", oldest, evicted.size());
+        }
+        self.uses += 1;
+        self.size += size;
+        self.entries.insert(
+            key,
+            Entry {
+                value: value,
+                last_used: self.uses,
+            },
+        );
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            entries: self.entries.len() as u32,
+            size: self.size as u32,
+            quota: self.total_quota as u32,
+            evictions: self.evictions,
+            rejections: self.rejections,
+        }
+    }
+}
@@ -1,13 +1,14 @@
 use alloc::{collections::btree_map::BTreeMap, string::String, vec::Vec};
 use core::mem;
 
-use ksupport::kernel::DmaRecorder;
-use libboard_artiq::{drtio_routing::RoutingTable,
-                     drtioaux_proto::{MASTER_PAYLOAD_MAX_SIZE, Packet, PayloadStatus},
+use ksupport::kernel::{DmaPlaybackStat, DmaRecorder, DmaTraceStat, trace_stat};
+use libboard_artiq::{drtio_routing::{self, RoutingTable},
+                     drtioaux_proto::{DESTINATION_MASK_SIZE, MASTER_PAYLOAD_MAX_SIZE, Packet, PayloadStatus},
                      pl::csr};
 use libcortex_a9::cache::dcci_slice;
 
-use crate::{routing::{Router, Sliceable},
+use crate::{memory_budget,
+            routing::{Router, Sliceable},
             subkernel::Manager as KernelManager};
 
 const ALIGNMENT: usize = 64;
@@ -24,6 +25,7 @@ pub struct RtioStatus {
     pub error: u8,
     pub channel: u32,
     pub timestamp: u64,
+    pub stat: DmaPlaybackStat,
 }
 
 #[derive(Debug)]
@@ -33,6 +35,33 @@ pub enum Error {
     EntryNotComplete,
     MasterDmaFound,
     UploadFail,
+    OutOfMemory,
+}
+
+// Number of events, up to the terminating zero.
+fn trace_events(trace: &[u8]) -> u32 {
+    let mut ptr = 0;
+    let mut events = 0;
+    while ptr < trace.len() && trace[ptr] != 0 {
+        ptr += trace[ptr] as usize;
+        events += 1;
+    }
+    events
+}
+
+fn rtio_counter() -> u64 {
+    unsafe {
+        csr::rtio::counter_update_write(1);
+        csr::rtio::counter_read()
+    }
+}
+
+// Playback in progress, for its statistics. The slack of each event is sampled by the
+// gateware as the DMA core writes it.
+#[derive(Debug, Default)]
+struct PlaybackTiming {
+    start: u64,
+    events: u32,
 }
 
 #[derive(Debug)]
@@ -59,9 +88,9 @@ impl Entry {
         self.trace[self.padding_len..].as_ptr() as u32
     }
 
-    pub fn realign(&mut self) {
+    // grows the trace for alignment, the data then has to be moved up by the returned padding
+    fn prepare_realign(&mut self) -> usize {
         self.trace.push(0);
-        let data_len = self.trace.len();
 
         self.trace.reserve(ALIGNMENT - 1);
         let padding = ALIGNMENT - self.trace.as_ptr() as usize % ALIGNMENT;
@@ -70,13 +99,22 @@ impl Entry {
             // Vec guarantees that this will not reallocate
             self.trace.push(0)
         }
-        for i in 1..data_len + 1 {
-            self.trace[data_len + padding - i] = self.trace[data_len - i]
-        }
+        padding
+    }
+
+    fn set_aligned(&mut self, padding: usize) {
         self.complete = true;
         self.padding_len = padding;
+    }
 
+    pub fn realign(&mut self) {
+        let padding = self.prepare_realign();
+        let data_len = self.trace.len() - padding;
+        for i in 1..data_len + 1 {
+            self.trace[data_len + padding - i] = self.trace[data_len - i]
+        }
         dcci_slice(&self.trace);
+        self.set_aligned(padding);
     }
 }
 
@@ -92,6 +130,8 @@ enum RemoteTraceState {
 struct RemoteTraces {
     remote_traces: BTreeMap<u8, Sliceable>,
     state: RemoteTraceState,
+    // combined over the destinations that finished so far
+    playback_stat: Option<DmaPlaybackStat>,
 }
 
 impl RemoteTraces {
@@ -99,6 +139,7 @@ impl RemoteTraces {
         RemoteTraces {
             remote_traces: traces,
             state: RemoteTraceState::Unsent,
+            playback_stat: None,
         }
     }
 
@@ -139,7 +180,7 @@ impl RemoteTraces {
     // on incoming Packet::DmaAddTraceReply
     pub fn ack_upload(
         &mut self,
-        kernel_manager: &mut KernelManager,
+        kernel_manager: &mut KernelManager<'_>,
         source: u8,
         id: u32,
         succeeded: bool,
@@ -153,8 +194,8 @@ impl RemoteTraces {
                 if trace.at_end() {
                     if count - 1 == 0 {
                         self.state = RemoteTraceState::Ready;
-                        if let Some((id, timestamp)) = kernel_manager.ddma_remote_uploaded(succeeded) {
-                            self.playback(id, timestamp, router, rank, self_destination, routing_table);
+                        if let Some((id, timestamp, selected)) = kernel_manager.ddma_remote_uploaded(succeeded) {
+                            self.playback(id, timestamp, selected, router, rank, self_destination, routing_table);
                         }
                     } else {
                         self.state = RemoteTraceState::Sending(count - 1);
@@ -186,6 +227,7 @@ impl RemoteTraces {
         &mut self,
         id: u32,
         timestamp: u64,
+        selected: Option<[u8; DESTINATION_MASK_SIZE]>,
         router: &mut Router,
         rank: u8,
         self_destination: u8,
@@ -193,8 +235,20 @@ impl RemoteTraces {
     ) {
         // route all the playback requests
         // remote traces (local trace runs on core1 unlike mainline firmware)
-        self.state = RemoteTraceState::Running(self.remote_traces.len());
-        for (dest, _) in self.remote_traces.iter() {
+        // `selected` limits them to the destinations of a group
+        let dests: Vec<u8> = self
+            .remote_traces
+            .keys()
+            .filter(|&&dest| selected.map_or(true, |selected| drtio_routing::mask_contains(&selected, dest)))
+            .cloned()
+            .collect();
+        self.state = if dests.is_empty() {
+            RemoteTraceState::Ready
+        } else {
+            RemoteTraceState::Running(dests.len())
+        };
+        self.playback_stat = None;
+        for dest in dests.iter() {
             router.route(
                 Packet::DmaPlaybackRequest {
                     source: self_destination,
@@ -217,16 +271,23 @@ impl RemoteTraces {
         error: u8,
         channel: u32,
         timestamp: u64,
+        stat: DmaPlaybackStat,
     ) {
         if let RemoteTraceState::Running(count) = self.state {
+            let stat = match self.playback_stat {
+                Some(previous) => previous.combine(stat),
+                None => stat,
+            };
             if error != 0 || count - 1 == 0 {
                 // notify the kernel about a DDMA error or finish
-                kernel_manager.ddma_finished(error, channel, timestamp).await;
+                kernel_manager.ddma_finished(error, channel, timestamp, stat).await;
                 self.state = RemoteTraceState::Ready;
+                self.playback_stat = None;
                 // further messages will be ignored (if there was an error)
             } else {
                 // no error and not the last one awaited
                 self.state = RemoteTraceState::Running(count - 1);
+                self.playback_stat = Some(stat);
             }
         }
     }
@@ -257,6 +318,20 @@ impl RemoteTraces {
     pub fn has_remote_traces(&self) -> bool {
         self.remote_traces.len() > 0
     }
+
+    pub fn running(&self) -> bool {
+        match self.state {
+            RemoteTraceState::Running(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn stat(&self) -> (usize, usize) {
+        self.remote_traces
+            .values()
+            .map(|trace| trace_stat(trace.data()))
+            .fold((0, 0), |(size, events), (s, e)| (size + s, events + e))
+    }
 }
 
 #[derive(Debug)]
@@ -265,9 +340,12 @@ pub struct Manager {
     state: ManagerState,
     current_id: u32,
     current_source: u8,
+    timing: PlaybackTiming,
 
     remote_entries: BTreeMap<u32, RemoteTraces>,
     name_map: BTreeMap<String, u32>,
+    // entry being realigned by core1, with its padding
+    realigning: Option<((u8, u32), usize)>,
 }
 
 impl Manager {
@@ -279,20 +357,40 @@ impl Manager {
             entries: BTreeMap::new(),
             current_id: 0,
             current_source: 0,
+            timing: PlaybackTiming::default(),
             state: ManagerState::Idle,
             remote_entries: BTreeMap::new(),
             name_map: BTreeMap::new(),
+            realigning: None,
+        }
+    }
+
+    /// Completes a trace realignment offloaded to core1, blocking until it is done if `wait` is set.
+    pub fn service_realign(&mut self, kernel_manager: &mut KernelManager<'_>, wait: bool) {
+        if let Some((key, padding)) = self.realigning {
+            if kernel_manager.offload_done(wait) {
+                self.realigning = None;
+                if let Some(entry) = self.entries.get_mut(&key) {
+                    entry.set_aligned(padding);
+                }
+            }
         }
     }
 
     pub fn add(
         &mut self,
+        kernel_manager: &mut KernelManager<'_>,
         source: u8,
         id: u32,
         status: PayloadStatus,
         trace: &[u8],
         trace_len: usize,
     ) -> Result<(), Error> {
+        if self.realigning.is_some_and(|(key, _)| key == (source, id)) {
+            // core1 is still moving the trace that is about to be replaced
+            self.service_realign(kernel_manager, true);
+        }
+        let used = self.trace_memory(Some((source, id)));
         let entry = match self.entries.get_mut(&(source, id)) {
             Some(entry) => {
                 if entry.complete || status.is_first() {
@@ -325,16 +423,52 @@ impl Manager {
                 self.entries.get_mut(&(source, id)).unwrap()
             }
         };
+        // the last slice also needs room for the alignment padding
+        let additional = trace_len + if status.is_last() { ALIGNMENT } else { 0 };
+        if used + entry.trace.len() + additional > memory_budget::dma()
+            || entry.trace.try_reserve(additional).is_err()
+        {
+            error!(
+                "DMA trace {} from destination {} refused, it does not fit in the DMA memory budget",
+                id, source
+            );
+            self.entries.remove(&(source, id));
+            return Err(Error::OutOfMemory);
+        }
         entry.trace.extend(&trace[0..trace_len]);
 
         if status.is_last() {
-            entry.realign();
+            // moving a large trace takes long enough to delay aux replies, leave it to core1 if it is free
+            let padding = entry.prepare_realign();
+            let data_len = entry.trace.len() - padding;
+            let src = entry.trace.as_mut_ptr();
+            if kernel_manager.offload_copy(src, src.wrapping_add(padding), data_len) {
+                self.realigning = Some(((source, id), padding));
+            } else {
+                for i in 1..data_len + 1 {
+                    entry.trace[data_len + padding - i] = entry.trace[data_len - i]
+                }
+                dcci_slice(&entry.trace);
+                entry.set_aligned(padding);
+            }
         }
         Ok(())
     }
 
+    // heap taken by the traces, but for the one with the given key
+    fn trace_memory(&self, except: Option<(u8, u32)>) -> usize {
+        self.entries
+            .iter()
+            .filter(|(key, _)| Some(**key) != except)
+            .map(|(_, entry)| entry.trace.capacity())
+            .sum()
+    }
+
     // api for DRTIO
-    pub fn erase(&mut self, source: u8, id: u32) -> Result<(), Error> {
+    pub fn erase(&mut self, kernel_manager: &mut KernelManager<'_>, source: u8, id: u32) -> Result<(), Error> {
+        if self.realigning.is_some_and(|(key, _)| key == (source, id)) {
+            self.service_realign(kernel_manager, true);
+        }
         match self.entries.remove(&(source, id)) {
             Some(_) => Ok(()),
             None => Err(Error::IdNotFound),
@@ -367,15 +501,16 @@ impl Manager {
         error: u8,
         channel: u32,
         timestamp: u64,
+        stat: DmaPlaybackStat,
     ) {
         if let Some(entry) = self.remote_entries.get_mut(&id) {
-            entry.remote_finished(kernel_manager, error, channel, timestamp).await;
+            entry.remote_finished(kernel_manager, error, channel, timestamp, stat).await;
         }
     }
 
     pub fn ack_upload(
         &mut self,
-        kernel_manager: &mut KernelManager,
+        kernel_manager: &mut KernelManager<'_>,
         source: u8,
         id: u32,
         succeeded: bool,
@@ -420,13 +555,14 @@ impl Manager {
         &mut self,
         id: u32,
         timestamp: u64,
+        selected: Option<[u8; DESTINATION_MASK_SIZE]>,
         router: &mut Router,
         rank: u8,
         self_destination: u8,
         routing_table: &RoutingTable,
     ) -> Result<(), Error> {
         if let Some(traces) = self.remote_entries.get_mut(&id) {
-            traces.playback(id, timestamp, router, rank, self_destination, routing_table);
+            traces.playback(id, timestamp, selected, router, rank, self_destination, routing_table);
             Ok(())
         } else {
             Err(Error::IdNotFound)
@@ -454,6 +590,31 @@ impl Manager {
         Some((*id as i32, duration, uses_ddma))
     }
 
+    // API for subkernel
+    // remote parts are counted from the copies kept here, as they are sent on
+    pub fn stat(&self, self_destination: u8, name: &String) -> Option<DmaTraceStat> {
+        let id = self.name_map.get(name)?;
+        let entry = self.entries.get(&(self_destination, *id))?;
+        let (size, events) = trace_stat(&entry.trace[entry.padding_len..]);
+        let (remote_size, remote_events) = self.remote_entries.get(id).map_or((0, 0), |traces| traces.stat());
+        Some(DmaTraceStat {
+            duration: entry.duration,
+            size: size + remote_size,
+            events: events + remote_events,
+            missing: 0,
+        })
+    }
+
+    // api for DRTIO
+    pub fn stat_id(&self, source: u8, id: u32) -> Option<(u32, u32)> {
+        let entry = self.entries.get(&(source, id))?;
+        if !entry.complete {
+            return None;
+        }
+        let (size, events) = trace_stat(&entry.trace[entry.padding_len..]);
+        Some((size as u32, events as u32))
+    }
+
     pub fn has_remote_traces(&self, id: u32) -> bool {
         match self.remote_entries.get(&id) {
             Some(traces) => traces.has_remote_traces(),
@@ -461,6 +622,15 @@ impl Manager {
         }
     }
 
+    // API for subkernel
+    // false once the remote parts played back are done, or if none of them was selected
+    pub fn remote_playback_running(&self, id: u32) -> bool {
+        match self.remote_entries.get(&id) {
+            Some(traces) => traces.running(),
+            _ => false,
+        }
+    }
+
     pub fn put_record(&mut self, mut recorder: DmaRecorder, self_destination: u8) -> Result<u32, Error> {
         let mut remote_traces: BTreeMap<u8, Sliceable> = BTreeMap::new();
 
@@ -468,6 +638,13 @@ impl Manager {
         // analyze each entry and put in proper buckets, as the kernel core
         // sends whole chunks, to limit comms/kernel CPU communication,
         // and as only comms core has access to varios DMA buffers.
+        if self.trace_memory(None) + recorder.buffer.len() > memory_budget::dma() {
+            error!(
+                "DMA recording of {} bytes refused, it does not fit in the DMA memory budget",
+                recorder.buffer.len()
+            );
+            return Err(Error::OutOfMemory);
+        }
         let mut ptr = 0;
         recorder.buffer.push(0);
         while recorder.buffer[ptr] != 0 {
@@ -527,11 +704,18 @@ impl Manager {
         unsafe {
             csr::rtio_dma::base_address_write(ptr as u32);
             csr::rtio_dma::time_offset_write(timestamp as u64);
+            csr::rtio_dma::min_slack_reset_write(1);
 
             csr::cri_con::selected_write(1);
             csr::rtio_dma::enable_write(1);
             // playback has begun here, for status call check_state
         }
+        let start = rtio_counter();
+        // the trace is only read while the DMA core goes through it
+        self.timing = PlaybackTiming {
+            start: start,
+            events: trace_events(&entry.trace[entry.padding_len..]),
+        };
         Ok(())
     }
 
@@ -545,6 +729,16 @@ impl Manager {
             return None;
         } else {
             self.state = ManagerState::Idle;
+            let end = rtio_counter();
+            let stat = DmaPlaybackStat {
+                duration: end.wrapping_sub(self.timing.start) as i64,
+                events: self.timing.events as i32,
+                min_slack: if self.timing.events > 0 {
+                    unsafe { csr::rtio_dma::min_slack_read() as i64 }
+                } else {
+                    0
+                },
+            };
             unsafe {
                 csr::cri_con::selected_write(0);
                 let error = csr::rtio_dma::error_read();
@@ -559,6 +753,7 @@ impl Manager {
                     error: error,
                     channel: channel,
                     timestamp: timestamp,
+                    stat: stat,
                 });
             }
         }
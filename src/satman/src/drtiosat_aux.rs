@@ -1,13 +1,36 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use ksupport::kernel::DmaPlaybackStat;
+#[cfg(has_grabber)]
+use libboard_artiq::grabber;
+#[cfg(has_wrpll)]
+use libboard_artiq::si549;
+#[cfg(has_si5324)]
+use libboard_artiq::si5324;
 use libboard_artiq::{drtio_routing, drtioaux, drtioaux_async,
-                     drtioaux_proto::{MASTER_PAYLOAD_MAX_SIZE, SAT_PAYLOAD_MAX_SIZE},
-                     pl::csr};
+                     drtioaux_proto::{AUX_CAPTURE_SIZE, DESTINATION_MASK_SIZE, FEATURE_FRAMING,
+                                      GRABBER_ROI_COUNT, IO_EXPANDER_COUNT, MASTER_PAYLOAD_MAX_SIZE, MONITOR_BATCH_SIZE,
+                                      PROTOCOL_VERSION, RTIO_INPUT_BATCH_BUSY, RTIO_INPUT_BATCH_FULL,
+                                      RTIO_INPUT_BATCH_OVERFLOW, RTIO_INPUT_BATCH_PENDING, RTIO_INPUT_BATCH_SIZE,
+                                      RTIO_INPUT_BATCH_SLICE, RTIO_INPUT_BATCH_TIMEOUT, SAT_PAYLOAD_MAX_SIZE,
+                                      local_features},
+                     logger::LogFilter, pl::csr, wall_clock};
 use libboard_zynq::{i2c::{Error as I2cError, I2c},
                     slcr, timer};
+use libconfig;
 
 #[cfg(has_cxp_grabber)]
 use crate::drtiosat_cxp;
-use crate::{analyzer::Analyzer, dma::Manager as DmaManager, drtiosat_reset, mgmt, mgmt::Manager as CoreManager,
-            repeater, routing::Router, subkernel::Manager as KernelManager};
+use crate::{analyzer::Analyzer, dma::Manager as DmaManager, drtiosat_buffer_space, drtiosat_reset,
+            drtiosat_resume_session, mgmt, mgmt::Manager as CoreManager, repeater, routing::Router,
+            subkernel::Manager as KernelManager, tsc_drift};
+
+// features advertised by the upstream in its last VersionRequest
+static UPSTREAM_FEATURES: AtomicU32 = AtomicU32::new(0);
+
+pub fn upstream_supports(feature: u32) -> bool {
+    UPSTREAM_FEATURES.load(Ordering::Relaxed) & feature != 0
+}
 
 #[cfg(has_drtio_routing)]
 macro_rules! forward {
@@ -51,6 +74,120 @@ macro_rules! forward {
     ) => {};
 }
 
+// Verifies a fan-out boot image, pushes it to the targets behind each repeater
+// and returns the set of destinations that accepted it (including this one).
+async fn flash_fan_out(
+    _repeaters: &[repeater::Repeater],
+    _routing_table: &drtio_routing::RoutingTable,
+    _rank: u8,
+    self_destination: u8,
+    targets: &[u8; DESTINATION_MASK_SIZE],
+    core_manager: &CoreManager,
+    _router: &mut Router,
+) -> [u8; DESTINATION_MASK_SIZE] {
+    let mut succeeded = [0; DESTINATION_MASK_SIZE];
+    if core_manager.verify_image().is_err() {
+        return succeeded;
+    }
+
+    #[cfg(has_drtio_routing)]
+    for (repno, rep) in _repeaters.iter().enumerate() {
+        let (rep_targets, entry) = _routing_table.targets_behind(_rank, repno as u8 + 1, targets);
+        if rep_targets.iter().all(|&b| b == 0) {
+            continue;
+        }
+        match entry {
+            Some(entry) => {
+                match rep
+                    .flash_fan_out(
+                        entry,
+                        &rep_targets,
+                        core_manager.image_payload(),
+                        _router,
+                        _routing_table,
+                        _rank,
+                        self_destination,
+                    )
+                    .await
+                {
+                    Ok(rep_succeeded) => {
+                        for (s, r) in succeeded.iter_mut().zip(rep_succeeded.iter()) {
+                            *s |= *r;
+                        }
+                    }
+                    Err(e) => error!("[REP#{}] failed to propagate boot image ({:?})", repno, e),
+                }
+            }
+            None => error!("[REP#{}] no satellite found behind repeater, boot image not propagated", repno),
+        }
+    }
+
+    if drtio_routing::mask_contains(targets, self_destination) {
+        drtio_routing::mask_insert(&mut succeeded, self_destination);
+    }
+    succeeded
+}
+
+// Starts a DMA playback on the targets behind each repeater first, as their requests
+// take longer to arrive, then locally; returns the destinations that started it.
+async fn dma_playback_broadcast(
+    _repeaters: &[repeater::Repeater],
+    _routing_table: &drtio_routing::RoutingTable,
+    _rank: u8,
+    self_destination: u8,
+    source: u8,
+    id: u32,
+    timestamp: u64,
+    targets: &[u8; DESTINATION_MASK_SIZE],
+    dma_manager: &mut DmaManager,
+    kernel_manager: &mut KernelManager<'_>,
+    _router: &mut Router,
+) -> [u8; DESTINATION_MASK_SIZE] {
+    let mut succeeded = [0; DESTINATION_MASK_SIZE];
+
+    #[cfg(has_drtio_routing)]
+    for (repno, rep) in _repeaters.iter().enumerate() {
+        let (rep_targets, entry) = _routing_table.targets_behind(_rank, repno as u8 + 1, targets);
+        if rep_targets.iter().all(|&b| b == 0) {
+            continue;
+        }
+        match entry {
+            Some(entry) => {
+                match rep
+                    .dma_playback_broadcast(
+                        entry,
+                        source,
+                        id,
+                        timestamp,
+                        &rep_targets,
+                        _router,
+                        _routing_table,
+                        _rank,
+                        self_destination,
+                    )
+                    .await
+                {
+                    Ok(rep_succeeded) => {
+                        for (s, r) in succeeded.iter_mut().zip(rep_succeeded.iter()) {
+                            *s |= *r;
+                        }
+                    }
+                    Err(e) => error!("[REP#{}] failed to broadcast DMA playback ({:?})", repno, e),
+                }
+            }
+            None => error!("[REP#{}] no satellite found behind repeater, DMA playback not broadcast", repno),
+        }
+    }
+
+    if drtio_routing::mask_contains(targets, self_destination) && !kernel_manager.running() {
+        dma_manager.service_realign(kernel_manager, true);
+        if dma_manager.playback(source, id, timestamp).is_ok() {
+            drtio_routing::mask_insert(&mut succeeded, self_destination);
+        }
+    }
+    succeeded
+}
+
 async fn process_aux_packet<'a, 'b>(
     _repeaters: &mut [repeater::Repeater],
     _routing_table: &mut drtio_routing::RoutingTable,
@@ -68,6 +205,30 @@ async fn process_aux_packet<'a, 'b>(
     // and u16 otherwise; hence the `as _` conversion.
     match packet {
         drtioaux::Packet::EchoRequest => drtioaux_async::send(0, &drtioaux::Packet::EchoReply).await,
+        drtioaux::Packet::VersionRequest { version, features } => {
+            if version != PROTOCOL_VERSION {
+                error!(
+                    "upstream aux protocol version {} is incompatible with satellite version {}",
+                    version, PROTOCOL_VERSION
+                );
+            } else {
+                info!("upstream aux protocol version {}, features 0x{:08x}", version, features);
+            }
+            drtioaux::set_framing(0, version == PROTOCOL_VERSION && features & FEATURE_FRAMING != 0);
+            UPSTREAM_FEATURES.store(features, Ordering::Relaxed);
+            drtioaux_async::send(
+                0,
+                &drtioaux::Packet::VersionReply {
+                    version: PROTOCOL_VERSION,
+                    features: local_features(),
+                },
+            )
+            .await
+        }
+        drtioaux::Packet::RetransmitRequest => {
+            drtioaux_async::retransmit(0).await;
+            Ok(())
+        }
         drtioaux::Packet::ResetRequest => {
             info!("resetting RTIO");
             drtiosat_reset(true);
@@ -80,6 +241,31 @@ async fn process_aux_packet<'a, 'b>(
             }
             drtioaux_async::send(0, &drtioaux::Packet::ResetAck).await
         }
+        drtioaux::Packet::SessionRequest {
+            destination: _destination,
+            epoch,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            let resumed = drtiosat_resume_session(epoch);
+            if resumed {
+                info!("master session resumed, keeping DMA traces and subkernels");
+            } else {
+                info!("new master session, clearing DMA traces and subkernels");
+                dma_manager.service_realign(kernel_manager, true);
+                *dma_manager = DmaManager::new();
+                kernel_manager.flush();
+            }
+            drtioaux_async::send(0, &drtioaux::Packet::SessionReply { resumed }).await
+        }
 
         drtioaux::Packet::DestinationStatusRequest { destination } => {
             #[cfg(has_drtio_routing)]
@@ -115,7 +301,15 @@ async fn process_aux_packet<'a, 'b>(
                     }
                     drtioaux_async::send(0, &drtioaux::Packet::DestinationBusyReply { channel }).await?;
                 } else {
-                    drtioaux_async::send(0, &drtioaux::Packet::DestinationOkReply).await?;
+                    let (buffer_space, min_buffer_space) = drtiosat_buffer_space();
+                    drtioaux_async::send(
+                        0,
+                        &drtioaux::Packet::DestinationOkReply {
+                            buffer_space: buffer_space,
+                            min_buffer_space: min_buffer_space,
+                        },
+                    )
+                    .await?;
                 }
             }
 
@@ -179,6 +373,7 @@ async fn process_aux_packet<'a, 'b>(
 
             info!("rank: {}", rank);
             info!("routing table: {}", _routing_table);
+            drtio_routing::cache_routing_table(_routing_table, new_rank);
 
             drtioaux_async::send(0, &drtioaux::Packet::RoutingAck).await
         }
@@ -191,6 +386,61 @@ async fn process_aux_packet<'a, 'b>(
         #[cfg(not(has_drtio_routing))]
         drtioaux::Packet::RoutingSetRank { rank: _ } => drtioaux_async::send(0, &drtioaux::Packet::RoutingAck).await,
 
+        drtioaux::Packet::EyeScanRequest {
+            destination: _destination,
+            link,
+            prescale,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+            if core_manager.run_eye_scan(link, prescale).is_ok() {
+                let mut data_slice = [0; SAT_PAYLOAD_MAX_SIZE];
+                let meta = core_manager.get_eye_scan_slice(&mut data_slice);
+                drtioaux_async::send(
+                    0,
+                    &drtioaux::Packet::EyeScanReply {
+                        last: meta.status.is_last(),
+                        length: meta.len as u16,
+                        data: data_slice,
+                    },
+                )
+                .await
+            } else {
+                drtioaux_async::send(0, &drtioaux::Packet::CoreMgmtReply { succeeded: false }).await
+            }
+        }
+        drtioaux::Packet::EyeScanContinue {
+            destination: _destination,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+            let mut data_slice = [0; SAT_PAYLOAD_MAX_SIZE];
+            let meta = core_manager.get_eye_scan_slice(&mut data_slice);
+            drtioaux_async::send(
+                0,
+                &drtioaux::Packet::EyeScanReply {
+                    last: meta.status.is_last(),
+                    length: meta.len as u16,
+                    data: data_slice,
+                },
+            )
+            .await
+        }
+
         drtioaux::Packet::MonitorRequest {
             destination: _destination,
             channel,
@@ -220,6 +470,75 @@ async fn process_aux_packet<'a, 'b>(
             let reply = drtioaux::Packet::MonitorReply { value: value };
             drtioaux_async::send(0, &reply).await
         }
+        drtioaux::Packet::MonitorBatchRequest {
+            destination: _destination,
+            count,
+            channels: _channels,
+            probes: _probes,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+            let values;
+            #[cfg(has_rtio_moninj)]
+            {
+                let mut read = [0; MONITOR_BATCH_SIZE];
+                for i in 0..count as usize {
+                    unsafe {
+                        csr::rtio_moninj::mon_chan_sel_write(_channels[i] as _);
+                        csr::rtio_moninj::mon_probe_sel_write(_probes[i]);
+                        csr::rtio_moninj::mon_value_update_write(1);
+                        read[i] = csr::rtio_moninj::mon_value_read() as u64;
+                    }
+                }
+                values = read;
+            }
+            #[cfg(not(has_rtio_moninj))]
+            {
+                values = [0; MONITOR_BATCH_SIZE];
+            }
+            let reply = drtioaux::Packet::MonitorBatchReply {
+                count: count,
+                values: values,
+            };
+            drtioaux_async::send(0, &reply).await
+        }
+        drtioaux::Packet::RtioInputBatchRequest {
+            destination: _destination,
+            channel,
+            timeout,
+            max_count,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+            let mut timestamps = [0; RTIO_INPUT_BATCH_SIZE];
+            let mut data = [0; RTIO_INPUT_BATCH_SIZE];
+            let (status, count) = if kernel_manager.running() || dma_manager.running() {
+                (RTIO_INPUT_BATCH_BUSY, 0)
+            } else {
+                read_input_batch(channel, timeout, max_count, &mut timestamps, &mut data)
+            };
+            let reply = drtioaux::Packet::RtioInputBatchReply {
+                status: status,
+                count: count,
+                timestamps: timestamps,
+                data: data,
+            };
+            drtioaux_async::send(0, &reply).await
+        }
         drtioaux::Packet::InjectionRequest {
             destination: _destination,
             channel,
@@ -403,6 +722,83 @@ async fn process_aux_packet<'a, 'b>(
                 }
             }
         }
+        drtioaux::Packet::I2cWriteBlockRequest {
+            destination: _destination,
+            busno: _busno,
+            length,
+            data,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+            let mut succeeded = true;
+            let mut acked = 0;
+            for &byte in data[..length as usize].iter() {
+                match i2c.write(byte) {
+                    Ok(()) => acked += 1,
+                    Err(I2cError::Nack) => break,
+                    Err(_) => {
+                        succeeded = false;
+                        break;
+                    }
+                }
+            }
+            drtioaux_async::send(
+                0,
+                &drtioaux::Packet::I2cWriteBlockReply {
+                    succeeded: succeeded,
+                    acked: acked,
+                },
+            )
+            .await
+        }
+        drtioaux::Packet::I2cReadBlockRequest {
+            destination: _destination,
+            busno: _busno,
+            length,
+            ack,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+            let length = (length as usize).min(SAT_PAYLOAD_MAX_SIZE);
+            let mut succeeded = true;
+            let mut data = [0; SAT_PAYLOAD_MAX_SIZE];
+            let mut read = 0;
+            while read < length {
+                match i2c.read(ack || read + 1 < length) {
+                    Ok(byte) => {
+                        data[read] = byte;
+                        read += 1;
+                    }
+                    Err(_) => {
+                        succeeded = false;
+                        break;
+                    }
+                }
+            }
+            drtioaux_async::send(
+                0,
+                &drtioaux::Packet::I2cReadBlockReply {
+                    succeeded: succeeded,
+                    length: read as u16,
+                    data: data,
+                },
+            )
+            .await
+        }
         drtioaux::Packet::I2cSwitchSelectRequest {
             destination: _destination,
             busno: _busno,
@@ -551,6 +947,54 @@ async fn process_aux_packet<'a, 'b>(
             )
             .await
         }
+        drtioaux::Packet::GrabberRoiRequest {
+            destination: _destination,
+            grabber: _grabber,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+            #[cfg(has_grabber)]
+            let roi_counts = grabber::roi_counts(_grabber as usize);
+            #[cfg(not(has_grabber))]
+            let roi_counts = None;
+            let (succeeded, frame, counts) = match roi_counts {
+                Some((frame, counts)) => (true, frame, counts),
+                None => (false, 0, [0; GRABBER_ROI_COUNT]),
+            };
+            drtioaux_async::send(
+                0,
+                &drtioaux::Packet::GrabberRoiReply {
+                    succeeded: succeeded,
+                    frame: frame,
+                    counts: counts,
+                },
+            )
+            .await
+        }
+        drtioaux::Packet::AnalyzerArmRequest {
+            destination: _destination,
+            arm,
+            synchronized,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+            let succeeded = analyzer.set_armed(arm, synchronized);
+            drtioaux_async::send(0, &drtioaux::Packet::AnalyzerArmReply { succeeded: succeeded }).await
+        }
 
         drtioaux::Packet::DmaAddTraceRequest {
             source,
@@ -570,7 +1014,9 @@ async fn process_aux_packet<'a, 'b>(
                 &packet,
             );
             *self_destination = destination;
-            let succeeded = dma_manager.add(source, id, status, &trace, length as usize).is_ok();
+            let succeeded = dma_manager
+                .add(kernel_manager, source, id, status, &trace, length as usize)
+                .is_ok();
             router
                 .send(
                     drtioaux::Packet::DmaAddTraceReply {
@@ -626,7 +1072,7 @@ async fn process_aux_packet<'a, 'b>(
                 _repeaters,
                 &packet,
             );
-            let succeeded = dma_manager.erase(source, id).is_ok();
+            let succeeded = dma_manager.erase(kernel_manager, source, id).is_ok();
             router
                 .send(
                     drtioaux::Packet::DmaRemoveTraceReply {
@@ -670,6 +1116,7 @@ async fn process_aux_packet<'a, 'b>(
                 &packet,
             );
             let succeeded = if !kernel_manager.running() {
+                dma_manager.service_realign(kernel_manager, true);
                 dma_manager.playback(source, id, timestamp).is_ok()
             } else {
                 false
@@ -686,9 +1133,12 @@ async fn process_aux_packet<'a, 'b>(
                 )
                 .await
         }
-        drtioaux::Packet::DmaPlaybackReply {
+        drtioaux::Packet::DmaPlaybackBroadcastRequest {
+            source,
             destination: _destination,
-            succeeded,
+            id,
+            timestamp,
+            targets,
         } => {
             forward!(
                 router,
@@ -699,18 +1149,26 @@ async fn process_aux_packet<'a, 'b>(
                 _repeaters,
                 &packet,
             );
-            if !succeeded {
-                kernel_manager.ddma_nack().await;
-            }
-            Ok(())
+            let succeeded = dma_playback_broadcast(
+                _repeaters,
+                _routing_table,
+                *rank,
+                *self_destination,
+                source,
+                id,
+                timestamp,
+                &targets,
+                dma_manager,
+                kernel_manager,
+                router,
+            )
+            .await;
+            drtioaux_async::send(0, &drtioaux::Packet::DmaPlaybackBroadcastReply { succeeded: succeeded }).await
         }
-        drtioaux::Packet::DmaPlaybackStatus {
-            source: _,
+        drtioaux::Packet::DmaStatRequest {
+            source,
             destination: _destination,
             id,
-            error,
-            channel,
-            timestamp,
         } => {
             forward!(
                 router,
@@ -721,12 +1179,69 @@ async fn process_aux_packet<'a, 'b>(
                 _repeaters,
                 &packet,
             );
-            dma_manager
-                .remote_finished(kernel_manager, id, error, channel, timestamp)
-                .await;
-            Ok(())
-        }
-
+            let reply = match dma_manager.stat_id(source, id) {
+                Some((size, events)) => drtioaux::Packet::DmaStatReply {
+                    found: true,
+                    size: size,
+                    events: events,
+                },
+                None => drtioaux::Packet::DmaStatReply {
+                    found: false,
+                    size: 0,
+                    events: 0,
+                },
+            };
+            drtioaux_async::send(0, &reply).await
+        }
+        drtioaux::Packet::DmaPlaybackReply {
+            destination: _destination,
+            succeeded,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+            if !succeeded {
+                kernel_manager.ddma_nack().await;
+            }
+            Ok(())
+        }
+        drtioaux::Packet::DmaPlaybackStatus {
+            source: _,
+            destination: _destination,
+            id,
+            error,
+            channel,
+            timestamp,
+            duration,
+            events,
+            min_slack,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+            let stat = DmaPlaybackStat {
+                duration: duration as i64,
+                events: events as i32,
+                min_slack: min_slack,
+            };
+            dma_manager
+                .remote_finished(kernel_manager, id, error, channel, timestamp, stat)
+                .await;
+            Ok(())
+        }
+
         drtioaux::Packet::SubkernelAddDataRequest {
             destination,
             id,
@@ -753,6 +1268,7 @@ async fn process_aux_packet<'a, 'b>(
             id,
             run,
             timestamp,
+            relative,
         } => {
             forward!(
                 router,
@@ -763,6 +1279,15 @@ async fn process_aux_packet<'a, 'b>(
                 _repeaters,
                 &packet,
             );
+            // the offset counts from the arrival of the request, before loading
+            let timestamp = if relative {
+                unsafe {
+                    csr::rtio::counter_update_write(1);
+                    csr::rtio::counter_read() + timestamp
+                }
+            } else {
+                timestamp
+            };
             let mut succeeded = kernel_manager.load(id).await.is_ok();
             // allow preloading a kernel with delayed run
             if run {
@@ -802,6 +1327,23 @@ async fn process_aux_packet<'a, 'b>(
             kernel_manager.subkernel_load_run_reply(succeeded);
             Ok(())
         }
+        drtioaux::Packet::SubkernelBarrierRelease {
+            destination: _destination,
+            id,
+            succeeded,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+            kernel_manager.barrier_released(id, succeeded);
+            Ok(())
+        }
         drtioaux::Packet::SubkernelFinished {
             destination: _destination,
             id,
@@ -823,6 +1365,7 @@ async fn process_aux_packet<'a, 'b>(
         drtioaux::Packet::SubkernelExceptionRequest {
             source,
             destination: _destination,
+            seq,
         } => {
             forward!(
                 router,
@@ -834,11 +1377,12 @@ async fn process_aux_packet<'a, 'b>(
                 &packet,
             );
             let mut data_slice: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
-            let meta = kernel_manager.exception_get_slice(&mut data_slice);
+            let meta = kernel_manager.exception_get_slice(seq, &mut data_slice);
             router
                 .send(
                     drtioaux::Packet::SubkernelException {
                         destination: source,
+                        seq: seq,
                         last: meta.status.is_last(),
                         length: meta.len,
                         data: data_slice,
@@ -849,8 +1393,45 @@ async fn process_aux_packet<'a, 'b>(
                 )
                 .await
         }
+        drtioaux::Packet::SubkernelDatasetRequest {
+            destination: _destination,
+            id,
+            seq,
+            name_len,
+            name,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+            let mut data_slice: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
+            let slice = core::str::from_utf8(&name[..name_len as usize])
+                .ok()
+                .and_then(|name| kernel_manager.dataset_get_slice(id, name, seq, &mut data_slice));
+            let (found, last, length) = match slice {
+                Some((length, last)) => (true, last, length),
+                None => (false, true, 0),
+            };
+            drtioaux_async::send(
+                0,
+                &drtioaux::Packet::SubkernelDatasetReply {
+                    seq: seq,
+                    found: found,
+                    last: last,
+                    length: length,
+                    data: data_slice,
+                },
+            )
+            .await
+        }
         drtioaux::Packet::SubkernelException {
             destination: _destination,
+            seq,
             last,
             length,
             data,
@@ -865,6 +1446,7 @@ async fn process_aux_packet<'a, 'b>(
                 &packet,
             );
             kernel_manager.received_exception(
+                seq,
                 &data[..length as usize],
                 last,
                 router,
@@ -891,10 +1473,13 @@ async fn process_aux_packet<'a, 'b>(
                 _repeaters,
                 &packet,
             );
-            kernel_manager.message_handle_incoming(status, id, length as usize, &data);
+            let credits = kernel_manager.message_handle_incoming(source, status, id, length as usize, &data);
             router
                 .send(
-                    drtioaux::Packet::SubkernelMessageAck { destination: source },
+                    drtioaux::Packet::SubkernelMessageAck {
+                        destination: source,
+                        credits: credits,
+                    },
                     _routing_table,
                     *rank,
                     *self_destination,
@@ -903,6 +1488,7 @@ async fn process_aux_packet<'a, 'b>(
         }
         drtioaux::Packet::SubkernelMessageAck {
             destination: _destination,
+            credits,
         } => {
             forward!(
                 router,
@@ -913,32 +1499,34 @@ async fn process_aux_packet<'a, 'b>(
                 _repeaters,
                 &packet,
             );
-            if kernel_manager.message_ack_slice() {
-                let mut data_slice: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
-                if let Some(meta) = kernel_manager.message_get_slice(&mut data_slice) {
-                    // route and not send immediately as ACKs are not a beginning of a transaction
-                    router.route(
-                        drtioaux::Packet::SubkernelMessage {
-                            source: *self_destination,
-                            destination: meta.destination,
-                            id: kernel_manager.get_current_id().unwrap(),
-                            status: meta.status,
-                            length: meta.len as u16,
-                            data: data_slice,
-                        },
-                        _routing_table,
-                        *rank,
-                        *self_destination,
-                    );
-                } else {
-                    error!("Error receiving message slice");
-                }
+            if kernel_manager.message_ack_slice(credits) {
+                route_message_slices(kernel_manager, router, _routing_table, *rank, *self_destination);
+            }
+            Ok(())
+        }
+        drtioaux::Packet::SubkernelMessageCredit {
+            destination: _destination,
+            credits,
+            ..
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+            if kernel_manager.message_update_credits(credits) {
+                route_message_slices(kernel_manager, router, _routing_table, *rank, *self_destination);
             }
             Ok(())
         }
         drtioaux::Packet::CoreMgmtGetLogRequest {
             destination: _destination,
             clear,
+            max_level,
         } => {
             forward!(
                 router,
@@ -947,10 +1535,14 @@ async fn process_aux_packet<'a, 'b>(
                 *rank,
                 *self_destination,
                 _repeaters,
-                &packet,
+                &drtioaux::Packet::CoreMgmtGetLogRequest {
+                    destination: _destination,
+                    clear,
+                    max_level,
+                },
             );
             let mut data_slice = [0; SAT_PAYLOAD_MAX_SIZE];
-            let meta = core_manager.log_get_slice(&mut data_slice, clear);
+            let meta = core_manager.log_get_slice(&mut data_slice, clear, LogFilter::level_from_u8(max_level));
             drtioaux_async::send(
                 0,
                 &drtioaux::Packet::CoreMgmtGetLogReply {
@@ -976,6 +1568,28 @@ async fn process_aux_packet<'a, 'b>(
             mgmt::clear_log();
             drtioaux_async::send(0, &drtioaux::Packet::CoreMgmtReply { succeeded: true }).await
         }
+        drtioaux::Packet::CoreMgmtLogStatsRequest {
+            destination: _destination,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+            let stats = mgmt::log_stats();
+            drtioaux_async::send(
+                0,
+                &drtioaux::Packet::CoreMgmtLogStatsReply {
+                    dropped_bytes: stats.dropped_bytes,
+                    dropped_messages: stats.dropped_messages,
+                },
+            )
+            .await
+        }
         drtioaux::Packet::CoreMgmtConfigReadRequest {
             destination: _destination,
             length,
@@ -1015,6 +1629,35 @@ async fn process_aux_packet<'a, 'b>(
                 }
             }
         }
+        drtioaux::Packet::CoreMgmtConfigListRequest {
+            destination: _destination,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            if core_manager.fetch_config_keys().is_ok() {
+                let mut value_slice = [0; SAT_PAYLOAD_MAX_SIZE];
+                let meta = core_manager.get_config_value_slice(&mut value_slice);
+                drtioaux_async::send(
+                    0,
+                    &drtioaux::Packet::CoreMgmtConfigReadReply {
+                        last: meta.status.is_last(),
+                        length: meta.len as u16,
+                        value: value_slice,
+                    },
+                )
+                .await
+            } else {
+                drtioaux_async::send(0, &drtioaux::Packet::CoreMgmtReply { succeeded: false }).await
+            }
+        }
         drtioaux::Packet::CoreMgmtConfigReadContinue {
             destination: _destination,
         } => {
@@ -1147,6 +1790,482 @@ async fn process_aux_packet<'a, 'b>(
         drtioaux::Packet::CoreMgmtFlashRequest {
             destination: _destination,
             payload_length,
+            verify_only,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            core_manager.allocate_image_buffer(payload_length as usize, verify_only);
+            drtioaux_async::send(0, &drtioaux::Packet::CoreMgmtReply { succeeded: true }).await
+        }
+        drtioaux::Packet::TimestampRequest {
+            destination: _destination,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            let timestamp = unsafe {
+                csr::rtio::counter_update_write(1);
+                csr::rtio::counter_read()
+            };
+            drtioaux_async::send(0, &drtioaux::Packet::TimestampReply { timestamp }).await
+        }
+        drtioaux::Packet::CoreMgmtSetSedSpreadRequest {
+            destination: _destination,
+            enable,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            let succeeded = core_manager.set_sed_spread(enable).is_ok();
+            drtioaux_async::send(0, &drtioaux::Packet::CoreMgmtReply { succeeded }).await
+        }
+        drtioaux::Packet::CoreMgmtCriConRequest {
+            destination: _destination,
+            reset,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            // CRI masters: 0 is DRTIO (master), 1 is DMA, 2 is the subkernel
+            let selected = unsafe { csr::cri_con::selected_read() };
+            let kernel_running = kernel_manager.running();
+            let dma_playing = dma_manager.running();
+            let expected = if dma_playing {
+                1
+            } else if kernel_running {
+                2
+            } else {
+                0
+            };
+            if selected != expected {
+                warn!(
+                    "CRI selection is {}, expected {} (kernel running: {}, DMA playing: {})",
+                    selected, expected, kernel_running, dma_playing
+                );
+                if reset {
+                    info!("resetting CRI selection to {}", expected);
+                    unsafe {
+                        csr::cri_con::selected_write(expected);
+                    }
+                }
+            }
+            drtioaux_async::send(
+                0,
+                &drtioaux::Packet::CoreMgmtCriConReply {
+                    selected: selected,
+                    kernel_running: kernel_running,
+                    dma_playing: dma_playing,
+                },
+            )
+            .await
+        }
+        drtioaux::Packet::CoreMgmtKernelCacheRequest {
+            destination: _destination,
+            clear,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            let status = kernel_manager.cache_status(clear);
+            if clear {
+                info!("kernel cache cleared");
+            }
+            drtioaux_async::send(
+                0,
+                &drtioaux::Packet::CoreMgmtKernelCacheReply {
+                    entries: status.entries,
+                    size: status.size,
+                    quota: status.quota,
+                    evictions: status.evictions,
+                    rejections: status.rejections,
+                },
+            )
+            .await
+        }
+        drtioaux::Packet::WallClockSet {
+            destination: _destination,
+            time_us,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            wall_clock::set(time_us);
+            drtioaux_async::send(0, &drtioaux::Packet::WallClockAck).await
+        }
+        drtioaux::Packet::TscDriftProbe {
+            destination: _destination,
+            master_time,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            let (offset, drift_ppb) = tsc_drift::record(master_time);
+            drtioaux_async::send(0, &drtioaux::Packet::TscDriftReply { offset, drift_ppb }).await
+        }
+        drtioaux::Packet::LatencyProbe {
+            destination: _destination,
+            timestamp,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            drtioaux_async::send(0, &drtioaux::Packet::LatencyProbeReply { timestamp }).await
+        }
+        drtioaux::Packet::RouterStatsRequest {
+            destination: _destination,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            let stats = Router::forward_stats();
+            drtioaux_async::send(
+                0,
+                &drtioaux::Packet::RouterStatsReply {
+                    forwarded: stats.forwarded,
+                    queued: stats.queued,
+                    max_queued: stats.max_queued,
+                    dropped_full: stats.dropped_full,
+                    dropped_aged: stats.dropped_aged,
+                },
+            )
+            .await
+        }
+        drtioaux::Packet::RepeaterStatsRequest {
+            destination: _destination,
+            repno,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            drtioaux_async::send(0, &repeater::stats_reply(repno)).await
+        }
+        drtioaux::Packet::StorageStatsRequest {
+            destination: _destination,
+            length,
+            key,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            let size = core::str::from_utf8(&key[..length as usize])
+                .ok()
+                .and_then(|key| libconfig::read(key).ok())
+                .map(|value| value.len() as u32);
+            drtioaux_async::send(
+                0,
+                &drtioaux::Packet::StorageStatsReply {
+                    set: size.is_some(),
+                    size: size.unwrap_or(0),
+                },
+            )
+            .await
+        }
+        drtioaux::Packet::StorageSpaceRequest {
+            destination: _destination,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            let space = libconfig::space().ok();
+            drtioaux_async::send(
+                0,
+                &drtioaux::Packet::StorageSpaceReply {
+                    known: space.is_some(),
+                    total: space.map_or(0, |(total, _)| total),
+                    free: space.map_or(0, |(_, free)| free),
+                },
+            )
+            .await
+        }
+        drtioaux::Packet::CoreMgmtAuxCaptureRequest {
+            destination: _destination,
+            index,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            let captures = drtioaux::captures();
+            let reply = match captures.get(index as usize) {
+                Some(capture) => drtioaux::Packet::CoreMgmtAuxCaptureReply {
+                    count: captures.len() as u8,
+                    linkno: capture.linkno,
+                    timestamp_ms: capture.timestamp_ms,
+                    kind: capture.kind,
+                    data: capture.data,
+                },
+                None => drtioaux::Packet::CoreMgmtAuxCaptureReply {
+                    count: captures.len() as u8,
+                    linkno: 0,
+                    timestamp_ms: 0,
+                    kind: 0,
+                    data: [0; AUX_CAPTURE_SIZE],
+                },
+            };
+            drtioaux_async::send(0, &reply).await
+        }
+        drtioaux::Packet::CoreMgmtAuxTraceEnable {
+            destination: _destination,
+            enable,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            info!("aux packet tracing {}", if enable { "enabled" } else { "disabled" });
+            drtioaux::set_tracing(enable);
+            drtioaux_async::send(0, &drtioaux::Packet::CoreMgmtReply { succeeded: true }).await
+        }
+        drtioaux::Packet::CoreMgmtAuxTraceRequest {
+            destination: _destination,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            core_manager.fetch_aux_trace();
+            let mut data_slice = [0; SAT_PAYLOAD_MAX_SIZE];
+            let meta = core_manager.get_aux_trace_slice(&mut data_slice);
+            drtioaux_async::send(
+                0,
+                &drtioaux::Packet::CoreMgmtAuxTraceReply {
+                    last: meta.status.is_last(),
+                    length: meta.len as u16,
+                    data: data_slice,
+                },
+            )
+            .await
+        }
+        drtioaux::Packet::CoreMgmtAuxTraceContinue {
+            destination: _destination,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            let mut data_slice = [0; SAT_PAYLOAD_MAX_SIZE];
+            let meta = core_manager.get_aux_trace_slice(&mut data_slice);
+            drtioaux_async::send(
+                0,
+                &drtioaux::Packet::CoreMgmtAuxTraceReply {
+                    last: meta.status.is_last(),
+                    length: meta.len as u16,
+                    data: data_slice,
+                },
+            )
+            .await
+        }
+        drtioaux::Packet::CoreMgmtIoExpanderStatusRequest {
+            destination: _destination,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            #[allow(unused_mut)]
+            let mut failing = [false; IO_EXPANDER_COUNT];
+            #[allow(unused_mut)]
+            let mut errors = [0; IO_EXPANDER_COUNT];
+            #[allow(unused_mut)]
+            let mut reinits = [0; IO_EXPANDER_COUNT];
+            // polled in the main loop
+            #[cfg(feature = "target_kasli_soc")]
+            let count = {
+                for i in 0..IO_EXPANDER_COUNT {
+                    let health = libboard_artiq::io_expander::health(i as u8);
+                    failing[i] = health.failing;
+                    errors[i] = health.errors;
+                    reinits[i] = health.reinits;
+                }
+                IO_EXPANDER_COUNT
+            };
+            #[cfg(not(feature = "target_kasli_soc"))]
+            let count = 0;
+            drtioaux_async::send(
+                0,
+                &drtioaux::Packet::CoreMgmtIoExpanderStatusReply {
+                    count: count as u8,
+                    failing: failing,
+                    errors: errors,
+                    reinits: reinits,
+                },
+            )
+            .await
+        }
+        drtioaux::Packet::CoreMgmtClockStatusRequest {
+            destination: _destination,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+
+            #[cfg(has_wrpll)]
+            let (monitored, locked, unlocks, recoveries) = {
+                let status = si549::wrpll::lock_status();
+                (status.monitored, status.locked, status.unlocks, status.recoveries)
+            };
+            #[cfg(not(has_wrpll))]
+            let (monitored, locked, unlocks, recoveries) = (false, false, 0, 0);
+            // polled in the main loop
+            #[cfg(has_si5324)]
+            let holdover = si5324::status();
+            #[cfg(has_si5324)]
+            let reply = drtioaux::Packet::CoreMgmtClockStatusReply {
+                monitored: monitored,
+                locked: locked,
+                unlocks: unlocks,
+                recoveries: recoveries,
+                holdover_monitored: holdover.monitored,
+                los: holdover.los,
+                lol: holdover.lol,
+                holdover: holdover.holdover,
+                los_events: holdover.los_events,
+                lol_events: holdover.lol_events,
+                holdover_events: holdover.holdover_events,
+                input_switches: holdover.input_switches,
+            };
+            #[cfg(not(has_si5324))]
+            let reply = drtioaux::Packet::CoreMgmtClockStatusReply {
+                monitored: monitored,
+                locked: locked,
+                unlocks: unlocks,
+                recoveries: recoveries,
+                holdover_monitored: false,
+                los: false,
+                lol: false,
+                holdover: false,
+                los_events: 0,
+                lol_events: 0,
+                holdover_events: 0,
+                input_switches: 0,
+            };
+            drtioaux_async::send(0, &reply).await
+        }
+        drtioaux::Packet::CoreMgmtFlashFanOutRequest {
+            destination: _destination,
+            payload_length,
+            targets,
         } => {
             forward!(
                 router,
@@ -1158,7 +2277,8 @@ async fn process_aux_packet<'a, 'b>(
                 &packet,
             );
 
-            core_manager.allocate_image_buffer(payload_length as usize);
+            core_manager.allocate_image_buffer(payload_length as usize, false);
+            core_manager.set_image_fan_out(targets);
             drtioaux_async::send(0, &drtioaux::Packet::CoreMgmtReply { succeeded: true }).await
         }
         drtioaux::Packet::CoreMgmtFlashAddDataRequest {
@@ -1179,7 +2299,22 @@ async fn process_aux_packet<'a, 'b>(
 
             core_manager.add_image_data(&data, length as usize);
 
-            if last {
+            if last && core_manager.image_verify_only() {
+                let succeeded = core_manager.verify_image().is_ok();
+                drtioaux_async::send(0, &drtioaux::Packet::CoreMgmtReply { succeeded }).await
+            } else if let (true, Some(targets)) = (last, core_manager.image_fan_out()) {
+                let succeeded = flash_fan_out(
+                    _repeaters,
+                    _routing_table,
+                    *rank,
+                    *self_destination,
+                    &targets,
+                    core_manager,
+                    router,
+                )
+                .await;
+                drtioaux_async::send(0, &drtioaux::Packet::CoreMgmtFlashFanOutReply { succeeded }).await
+            } else if last {
                 drtioaux_async::send(0, &drtioaux::Packet::CoreMgmtDropLink).await
             } else {
                 drtioaux_async::send(0, &drtioaux::Packet::CoreMgmtReply { succeeded: true }).await
@@ -1286,6 +2421,37 @@ async fn process_aux_packet<'a, 'b>(
             drtiosat_cxp::process_roi_viewer_data_request().await?;
             Ok(())
         }
+        drtioaux::Packet::CXPEventRequest {
+            destination: _destination,
+        } => {
+            forward!(
+                router,
+                _routing_table,
+                _destination,
+                *rank,
+                *self_destination,
+                _repeaters,
+                &packet,
+            );
+            #[cfg(has_cxp_grabber)]
+            drtiosat_cxp::process_event_request().await?;
+            #[cfg(not(has_cxp_grabber))]
+            {
+                // kernels polling for events would wait for the reply until the aux timeout
+                let error = b"CXP grabber is not available on this destination";
+                let mut message = [0; libboard_artiq::drtioaux_proto::CXP_PAYLOAD_MAX_SIZE];
+                message[..error.len()].copy_from_slice(error);
+                drtioaux_async::send(
+                    0,
+                    &drtioaux::Packet::CXPError {
+                        length: error.len() as u16,
+                        message: message,
+                    },
+                )
+                .await?;
+            }
+            Ok(())
+        }
 
         p => {
             warn!("received unexpected aux packet: {:?}", p);
@@ -1294,6 +2460,85 @@ async fn process_aux_packet<'a, 'b>(
     }
 }
 
+const RTIO_I_STATUS_WAIT_EVENT: u32 = 1;
+const RTIO_I_STATUS_OVERFLOW: u32 = 2;
+const RTIO_I_STATUS_WAIT_STATUS: u32 = 4;
+
+// Reads input events through the CRI master of subkernels, which is free while none runs,
+// and gives the RTIO core back to the master afterwards. Waits for an event are bounded to
+// RTIO_INPUT_BATCH_SLICE, so that aux packets keep being served; the master polls again
+// while the batch is pending.
+fn read_input_batch(
+    channel: u32,
+    timeout: u64,
+    max_count: u8,
+    timestamps: &mut [u64; RTIO_INPUT_BATCH_SIZE],
+    data: &mut [u32; RTIO_INPUT_BATCH_SIZE],
+) -> (u8, u8) {
+    let max_count = (max_count as usize).min(RTIO_INPUT_BATCH_SIZE);
+    let mut status = RTIO_INPUT_BATCH_FULL;
+    let mut count = 0;
+    unsafe {
+        csr::cri_con::selected_write(2);
+        while count < max_count {
+            csr::rtio::counter_update_write(1);
+            let deadline = timeout.min(csr::rtio::counter_read().saturating_add(RTIO_INPUT_BATCH_SLICE));
+            csr::rtio::target_write(channel << 8);
+            csr::rtio::i_timeout_write(deadline);
+            let mut i_status = RTIO_I_STATUS_WAIT_STATUS;
+            while i_status & RTIO_I_STATUS_WAIT_STATUS != 0 {
+                i_status = csr::rtio::i_status_read() as u32;
+            }
+            if i_status & RTIO_I_STATUS_OVERFLOW != 0 {
+                status = RTIO_INPUT_BATCH_OVERFLOW;
+                break;
+            }
+            if i_status & RTIO_I_STATUS_WAIT_EVENT != 0 {
+                status = if deadline < timeout {
+                    RTIO_INPUT_BATCH_PENDING
+                } else {
+                    RTIO_INPUT_BATCH_TIMEOUT
+                };
+                break;
+            }
+            timestamps[count] = csr::rtio::i_timestamp_read();
+            // the least significant word of the data comes last
+            let lsb = csr::rtio::I_DATA_ADDR.offset((csr::rtio::I_DATA_SIZE - 1) as isize);
+            data[count] = core::ptr::read_volatile(lsb);
+            count += 1;
+        }
+        csr::cri_con::selected_write(0);
+    }
+    (status, count as u8)
+}
+
+fn route_message_slices(
+    kernel_manager: &mut KernelManager,
+    router: &mut Router,
+    routing_table: &drtio_routing::RoutingTable,
+    rank: u8,
+    self_destination: u8,
+) {
+    // sends as many slices of the outgoing message as the receiver's credits allow
+    let mut data_slice: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
+    while let Some(meta) = kernel_manager.message_get_slice(&mut data_slice) {
+        // route and not send immediately as ACKs are not a beginning of a transaction
+        router.route(
+            drtioaux::Packet::SubkernelMessage {
+                source: self_destination,
+                destination: meta.destination,
+                id: kernel_manager.get_current_id().unwrap(),
+                status: meta.status,
+                length: meta.len as u16,
+                data: data_slice,
+            },
+            routing_table,
+            rank,
+            self_destination,
+        );
+    }
+}
+
 pub async fn process_aux_packets<'a, 'b>(
     repeaters: &mut [repeater::Repeater],
     routing_table: &mut drtio_routing::RoutingTable,
@@ -1331,5 +2576,8 @@ pub async fn process_aux_packets<'a, 'b>(
     };
     if let Err(e) = result {
         warn!("aux packet error ({:?})", e);
+        if e.is_damaged() && drtioaux::framing(0) {
+            let _ = drtioaux_async::send(0, &drtioaux::Packet::RetransmitRequest).await;
+        }
     }
 }
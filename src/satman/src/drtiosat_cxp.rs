@@ -137,3 +137,27 @@ pub async fn process_roi_viewer_data_request() -> Result<(), drtioaux::Error> {
         .await
     }
 }
+
+pub async fn process_event_request() -> Result<(), drtioaux::Error> {
+    if !cxp_grabber::async_camera_connected().await {
+        return drtioaux_async::send(0, &get_cxp_error_packet("Camera is not connected")).await;
+    };
+    if let Err(e) = cxp_packet::poll_events() {
+        return drtioaux_async::send(0, &get_cxp_error_packet(&format!("{}", e))).await;
+    }
+    let packet = match cxp_packet::pop_event() {
+        Some(event) => drtioaux::Packet::CXPEventReply {
+            available: true,
+            namespace: event.namespace,
+            id: event.id,
+            timestamp: event.timestamp,
+        },
+        None => drtioaux::Packet::CXPEventReply {
+            available: false,
+            namespace: 0,
+            id: 0,
+            timestamp: 0,
+        },
+    };
+    drtioaux_async::send(0, &packet).await
+}
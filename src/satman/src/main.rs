@@ -26,7 +26,8 @@ extern crate unwind;
 
 extern crate alloc;
 
-use core::cell::RefCell;
+use core::{cell::RefCell,
+           sync::atomic::{AtomicU16, Ordering}};
 
 use analyzer::Analyzer;
 use dma::Manager as DmaManager;
@@ -36,43 +37,81 @@ use libasync::task;
 use libboard_artiq::drtio_eem;
 #[cfg(feature = "target_kasli_soc")]
 use libboard_artiq::io_expander;
+#[cfg(any(has_si5324, has_si549))]
+use libboard_artiq::rtio_frequency::{self, RtioFrequency};
 #[cfg(has_si549)]
 use libboard_artiq::si549;
 #[cfg(has_si5324)]
 use libboard_artiq::si5324;
 #[cfg(has_cxp_grabber)]
 use libboard_artiq::{cxp_grabber, cxp_phys};
-use libboard_artiq::{drtio_routing, drtioaux, drtioaux_async, identifier_read, logger, pl::csr};
+use libboard_artiq::{drtio_routing, drtioaux, drtioaux_async, error_log, identifier_read, logger, panic_report,
+                     panic_report::PanicReport, pl::csr, startup_io};
 #[cfg(feature = "target_kasli_soc")]
 use libboard_zynq::error_led::ErrorLED;
 use libboard_zynq::{i2c::I2c, print, println, timer};
-use libcortex_a9::{l2c::enable_l2_cache, regs::MPIDR};
+use libcortex_a9::{l2c::enable_l2_cache, mutex::Mutex, regs::MPIDR};
 use libregister::RegisterR;
 use libsupport_zynq::{exception_vectors, ram};
 use mgmt::Manager as CoreManager;
 use routing::Router;
+use standalone::Standalone;
 use subkernel::Manager as KernelManager;
 
 mod analyzer;
+mod cache;
 mod dma;
 mod drtiosat_aux;
 #[cfg(has_cxp_grabber)]
 mod drtiosat_cxp;
+mod memory_budget;
 mod mgmt;
 mod repeater;
 mod routing;
 mod rpc_async;
+mod standalone;
 mod subkernel;
+mod tsc_drift;
 
 // linker symbols
 extern "C" {
     static __exceptions_start: u32;
 }
 
+// lowest CRI buffer space observed since the last RTIO reset
+static MIN_BUFFER_SPACE: AtomicU16 = AtomicU16::new(u16::MAX);
+
 fn drtiosat_reset(reset: bool) {
     unsafe {
         csr::drtiosat::reset_write(if reset { 1 } else { 0 });
     }
+    if !reset {
+        MIN_BUFFER_SPACE.store(u16::MAX, Ordering::Relaxed);
+    }
+}
+
+// epoch of the master session that the retained DMA and subkernel state belongs to
+static SESSION_EPOCH: Mutex<Option<u32>> = Mutex::new(None);
+
+// Records the session epoch announced by the master. Returns true if the retained
+// state belongs to it, i.e. the link went down without the master restarting.
+fn drtiosat_resume_session(epoch: u32) -> bool {
+    SESSION_EPOCH.lock().replace(epoch) == Some(epoch)
+}
+
+// (current, minimum observed) buffer space, reported with DestinationOkReply. The current one
+// is the last answered to a buffer space request of the master, latched by the gateware.
+#[cfg(has_drtiosat_buffer_space)]
+fn drtiosat_buffer_space() -> (u16, u16) {
+    let buffer_space = unsafe { csr::drtiosat_buffer_space::buffer_space_read() };
+    let min_buffer_space = MIN_BUFFER_SPACE.fetch_min(buffer_space, Ordering::Relaxed);
+    (buffer_space, min_buffer_space.min(buffer_space))
+}
+
+// gateware without the buffer space monitor, never close to buffer space timeouts for the master
+#[cfg(not(has_drtiosat_buffer_space))]
+fn drtiosat_buffer_space() -> (u16, u16) {
+    (u16::MAX, u16::MAX)
 }
 
 fn drtiosat_reset_phy(reset: bool) {
@@ -106,10 +145,11 @@ fn drtiosat_process_errors() {
     unsafe {
         errors = csr::drtiosat::protocol_error_read();
     }
-    if errors & 1 != 0 {
+    drtiosat_buffer_space();
+    if errors & 1 != 0 && error_log::should_log("unknown packet type", None) {
         error!("received packet of an unknown type");
     }
-    if errors & 2 != 0 {
+    if errors & 2 != 0 && error_log::should_log("truncated packet", None) {
         error!("received truncated packet");
     }
     if errors & 4 != 0 {
@@ -117,10 +157,13 @@ fn drtiosat_process_errors() {
         unsafe {
             destination = csr::drtiosat::buffer_space_timeout_dest_read();
         }
-        error!(
-            "timeout attempting to get buffer space from CRI, destination=0x{:02x}",
-            destination
-        )
+        MIN_BUFFER_SPACE.store(0, Ordering::Relaxed);
+        if error_log::should_log("buffer space timeout", Some(destination as u32)) {
+            error!(
+                "timeout attempting to get buffer space from CRI, destination=0x{:02x}",
+                destination
+            )
+        }
     }
     if errors & 8 != 0 {
         let channel;
@@ -131,15 +174,17 @@ fn drtiosat_process_errors() {
             timestamp_event = csr::drtiosat::underflow_timestamp_event_read() as i64;
             timestamp_counter = csr::drtiosat::underflow_timestamp_counter_read() as i64;
         }
-        error!(
-            "write underflow, channel={}, timestamp={}, counter={}, slack={}",
-            channel,
-            timestamp_event,
-            timestamp_counter,
-            timestamp_event - timestamp_counter
-        );
+        if error_log::should_log("underflow", Some(channel as u32)) {
+            error!(
+                "write underflow, channel={}, timestamp={}, counter={}, slack={}",
+                channel,
+                timestamp_event,
+                timestamp_counter,
+                timestamp_event - timestamp_counter
+            );
+        }
     }
-    if errors & 16 != 0 {
+    if errors & 16 != 0 && error_log::should_log("write overflow", None) {
         error!("write overflow");
     }
     unsafe {
@@ -147,8 +192,8 @@ fn drtiosat_process_errors() {
     }
 }
 
-#[cfg(all(has_si5324, rtio_frequency = "125.0"))]
-const SI5324_SETTINGS: si5324::FrequencySettings = si5324::FrequencySettings {
+#[cfg(has_si5324)]
+const SI5324_SETTINGS_125: si5324::FrequencySettings = si5324::FrequencySettings {
     n1_hs: 5,
     nc1_ls: 8,
     n2_hs: 7,
@@ -159,8 +204,8 @@ const SI5324_SETTINGS: si5324::FrequencySettings = si5324::FrequencySettings {
     crystal_as_ckin2: true,
 };
 
-#[cfg(all(has_si5324, rtio_frequency = "100.0"))]
-const SI5324_SETTINGS: si5324::FrequencySettings = si5324::FrequencySettings {
+#[cfg(has_si5324)]
+const SI5324_SETTINGS_100: si5324::FrequencySettings = si5324::FrequencySettings {
     n1_hs: 5,
     nc1_ls: 10,
     n2_hs: 10,
@@ -171,8 +216,8 @@ const SI5324_SETTINGS: si5324::FrequencySettings = si5324::FrequencySettings {
     crystal_as_ckin2: true,
 };
 
-#[cfg(all(has_si549, rtio_frequency = "125.0"))]
-const SI549_SETTINGS: si549::FrequencySetting = si549::FrequencySetting {
+#[cfg(has_si549)]
+const SI549_SETTINGS_125: si549::FrequencySetting = si549::FrequencySetting {
     main: si549::DividerConfig {
         hsdiv: 0x058,
         lsdiv: 0,
@@ -186,8 +231,8 @@ const SI549_SETTINGS: si549::FrequencySetting = si549::FrequencySetting {
     },
 };
 
-#[cfg(all(has_si549, rtio_frequency = "100.0"))]
-const SI549_SETTINGS: si549::FrequencySetting = si549::FrequencySetting {
+#[cfg(has_si549)]
+const SI549_SETTINGS_100: si549::FrequencySetting = si549::FrequencySetting {
     main: si549::DividerConfig {
         hsdiv: 0x06C,
         lsdiv: 0,
@@ -201,6 +246,25 @@ const SI549_SETTINGS: si549::FrequencySetting = si549::FrequencySetting {
     },
 };
 
+#[cfg(has_si5324)]
+fn si5324_settings() -> &'static si5324::FrequencySettings {
+    match rtio_frequency::get() {
+        RtioFrequency::Mhz100 => &SI5324_SETTINGS_100,
+        RtioFrequency::Mhz125 => &SI5324_SETTINGS_125,
+    }
+}
+
+#[cfg(has_wrpll)]
+const WRPLL_LOCK_CHECK_INTERVAL_MS: u64 = 1000;
+
+#[cfg(has_si549)]
+fn si549_settings() -> &'static si549::FrequencySetting {
+    match rtio_frequency::get() {
+        RtioFrequency::Mhz100 => &SI549_SETTINGS_100,
+        RtioFrequency::Mhz125 => &SI549_SETTINGS_125,
+    }
+}
+
 #[cfg(has_grabber)]
 mod grabber {
     use libboard_artiq::grabber;
@@ -260,18 +324,31 @@ pub fn main_core0() {
     libboard_artiq::i2c::init();
     let i2c = libboard_artiq::i2c::get_bus();
 
+    // config is needed early, to put the I/O into its startup state
+    let config_ready = match libconfig::init() {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("config initialization failed: {}", err);
+            false
+        }
+    };
+
+    setup_log_levels();
+    panic_report::report_previous(config_ready);
+
+    // before the analyzer and managers take their share of the heap
+    memory_budget::load();
+    analyzer::init();
+
+    let startup_io = startup_io::load();
+
     #[cfg(feature = "target_kasli_soc")]
     let (mut io_expander0, mut io_expander1);
     #[cfg(feature = "target_kasli_soc")]
     {
-        io_expander0 = io_expander::IoExpander::new(i2c, 0).unwrap();
-        io_expander1 = io_expander::IoExpander::new(i2c, 1).unwrap();
-        io_expander0
-            .init(i2c)
-            .expect("I2C I/O expander #0 initialization failed");
-        io_expander1
-            .init(i2c)
-            .expect("I2C I/O expander #1 initialization failed");
+        // expanders failing at boot are brought up later from the main loop
+        io_expander0 = io_expander::IoExpander::attach(i2c, 0).unwrap();
+        io_expander1 = io_expander::IoExpander::attach(i2c, 1).unwrap();
 
         // Drive CLK_SEL to true
         #[cfg(has_si549)]
@@ -287,14 +364,21 @@ pub fn main_core0() {
         #[cfg(hw_rev = "v1.2")]
         io_expander1.set(0, 7, true);
 
-        io_expander0.service(i2c).unwrap();
-        io_expander1.service(i2c).unwrap();
+        startup_io::apply_expanders(&startup_io, &mut io_expander0, &mut io_expander1);
+
+        io_expander0.poll(i2c);
+        io_expander1.poll(i2c);
     }
 
     #[cfg(has_si5324)]
-    si5324::setup(i2c, &SI5324_SETTINGS, si5324::Input::Ckin1).expect("cannot initialize Si5324");
+    {
+        si5324::setup(i2c, si5324_settings(), si5324::Input::Ckin1).expect("cannot initialize Si5324");
+        if let Err(e) = si5324::configure_holdover(i2c) {
+            warn!("failed to configure Si5324 holdover: {}", e);
+        }
+    }
     #[cfg(has_si549)]
-    si549::main_setup(&SI549_SETTINGS).expect("cannot initialize main Si549");
+    si549::main_setup(si549_settings()).expect("cannot initialize main Si549");
 
     timer::delay_us(100_000);
     info!("Switching SYS clocks...");
@@ -319,13 +403,9 @@ pub fn main_core0() {
     }
 
     #[cfg(has_si549)]
-    si549::helper_setup(&SI549_SETTINGS).expect("cannot initialize helper Si549");
-
-    if let Err(err) = libconfig::init() {
-        warn!("config initialization failed: {}", err);
-    }
+    si549::helper_setup(si549_settings()).expect("cannot initialize helper Si549");
 
-    setup_log_levels();
+    startup_io::apply_ttl(&startup_io);
 
     if let Ok(spread_enable) = libconfig::read_str("sed_spread_enable") {
         match spread_enable.as_ref() {
@@ -341,6 +421,17 @@ pub fn main_core0() {
         toggle_sed_spread(0);
     }
 
+    if let Ok(aux_capture) = libconfig::read_str("aux_capture") {
+        match aux_capture.as_ref() {
+            "1" => {
+                info!("capturing undecodable aux packets");
+                drtioaux::set_capture(true)
+            }
+            "0" => (),
+            _ => warn!("aux_capture \"{}\" not supported, ignoring", aux_capture),
+        }
+    }
+
     #[cfg(has_drtio_eem)]
     {
         drtio_eem::init();
@@ -367,31 +458,64 @@ pub fn main_core0() {
     task::spawn(async {
         loop {
             drtiosat_process_errors();
+            error_log::flush();
             task::r#yield().await;
         }
     });
 
-    let mut routing_table = drtio_routing::RoutingTable::default_empty();
-    let mut rank = 1;
+    // the cached routing table lets repeaters forward before the master has set it up again,
+    // and is checked against the master once it does
+    let (mut routing_table, mut rank) = match drtio_routing::cached_routing_table() {
+        Some((routing_table, rank)) => {
+            info!("using cached routing table, rank: {}", rank);
+            #[cfg(has_drtio_routing)]
+            drtio_routing::interconnect_enable_all(&routing_table, rank);
+            (routing_table, rank)
+        }
+        None => (drtio_routing::RoutingTable::default_empty(), 1),
+    };
     let mut destination = 1;
 
     let control = RefCell::new(ksupport::kernel::Control::start());
+    // the kernel cache outlives uplink sessions, unlike the managers
+    let cache = RefCell::new(cache::Cache::new());
+    // DMA traces and subkernels are kept over a link flap, and only cleared
+    // once the master announces a new session (see drtiosat_resume_session)
+    let mut dma_manager = DmaManager::new();
+    let mut kernel_manager = KernelManager::new(&control, &cache);
+    let standalone_timeout = standalone::timeout_ms();
     task::block_on(async {
         loop {
             let mut router = Router::new();
 
+            let link_down_since = timer::get_ms();
+            let mut standalone = None;
             while !drtiosat_link_rx_up() {
+                // a subkernel left running by the link going down is not interrupted
+                if standalone.is_none()
+                    && !kernel_manager.running()
+                    && standalone_timeout.is_some_and(|timeout| timer::get_ms() >= link_down_since + timeout)
+                {
+                    kernel_manager.release_core1();
+                    standalone = Some(Standalone::enter(&control, &cache).await);
+                }
+                if let Some(standalone) = standalone.as_mut() {
+                    standalone.service().await;
+                }
                 #[allow(unused_mut)]
                 for mut rep in repeaters.iter_mut() {
                     rep.service(&routing_table, rank, destination, &mut router).await;
                 }
                 #[cfg(feature = "target_kasli_soc")]
                 {
-                    io_expander0.service(i2c).expect("I2C I/O expander #0 service failed");
-                    io_expander1.service(i2c).expect("I2C I/O expander #1 service failed");
+                    io_expander0.poll(i2c);
+                    io_expander1.poll(i2c);
                 }
                 task::r#yield().await;
             }
+            if let Some(standalone) = standalone.take() {
+                standalone.leave();
+            }
 
             info!("uplink is up, switching to recovered clock");
             #[cfg(has_siphaser)]
@@ -403,18 +527,19 @@ pub fn main_core0() {
             #[cfg(has_wrpll)]
             si549::wrpll::select_recovered_clock(true);
 
-            // Various managers created here, so when link is dropped, all DMA traces
-            // are cleared out for a clean slate on subsequent connections,
-            // without a manual intervention.
-            let mut dma_manager = DmaManager::new();
+            // the analyzer and pending management transfers start over with each link
             let mut analyzer = Analyzer::new();
-            let mut kernel_manager = KernelManager::new(&control);
             let mut core_manager = CoreManager::new();
 
             drtioaux::reset(0);
+            drtioaux::set_framing(0, false);
             drtiosat_reset(false);
             drtiosat_reset_phy(false);
 
+            #[cfg(has_wrpll)]
+            let mut last_lock_check = timer::get_ms();
+            #[cfg(has_si5324)]
+            let mut last_si5324_poll = timer::get_ms();
             while drtiosat_link_rx_up() {
                 linkup_service(
                     &mut repeaters,
@@ -429,13 +554,27 @@ pub fn main_core0() {
                     &mut router,
                 )
                 .await;
+                #[cfg(has_wrpll)]
+                if timer::get_ms() >= last_lock_check + WRPLL_LOCK_CHECK_INTERVAL_MS {
+                    si549::wrpll::check_lock();
+                    last_lock_check = timer::get_ms();
+                }
+                #[cfg(has_si5324)]
+                if timer::get_ms() >= last_si5324_poll + si5324::POLL_INTERVAL_MS {
+                    if let Err(e) = si5324::poll(i2c) {
+                        warn!("failed to poll Si5324 status: {}", e);
+                    }
+                    last_si5324_poll = timer::get_ms();
+                }
                 #[cfg(feature = "target_kasli_soc")]
                 {
-                    io_expander0.service(i2c).expect("I2C I/O expander #0 service failed");
-                    io_expander1.service(i2c).expect("I2C I/O expander #1 service failed");
+                    io_expander0.poll(i2c);
+                    io_expander1.poll(i2c);
                 }
                 task::r#yield().await;
             }
+            // the trace memory may be released with a new session, core1 must be done with it
+            dma_manager.service_realign(&mut kernel_manager, true);
 
             drtiosat_reset_phy(true);
             drtiosat_reset(true);
@@ -474,6 +613,7 @@ async fn linkup_service<'a>(
         router,
     )
     .await;
+    dma_manager.service_realign(kernel_manager, false);
     #[allow(unused_mut)]
     for mut rep in repeaters.iter_mut() {
         rep.service(&routing_table, *rank, *destination, router).await;
@@ -481,6 +621,7 @@ async fn linkup_service<'a>(
 
     if drtiosat_tsc_loaded() {
         info!("TSC loaded from uplink");
+        tsc_drift::reset();
         for rep in repeaters.iter() {
             if let Err(e) = rep.sync_tsc().await {
                 error!("failed to sync TSC ({:?})", e);
@@ -492,8 +633,13 @@ async fn linkup_service<'a>(
     }
     if let Some(status) = dma_manager.check_state() {
         info!(
-            "playback done, error: {}, channel: {}, timestamp: {}",
-            status.error, status.channel, status.timestamp
+            "playback done, error: {}, channel: {}, timestamp: {}, duration: {} mu, events: {}, min slack: {} mu",
+            status.error,
+            status.channel,
+            status.timestamp,
+            status.stat.duration,
+            status.stat.events,
+            status.stat.min_slack
         );
         router.route(
             drtioaux::Packet::DmaPlaybackStatus {
@@ -503,6 +649,9 @@ async fn linkup_service<'a>(
                 error: status.error,
                 channel: status.channel,
                 timestamp: status.timestamp,
+                duration: status.stat.duration as u64,
+                events: status.stat.events as u32,
+                min_slack: status.stat.min_slack,
             },
             &routing_table,
             *rank,
@@ -515,7 +664,7 @@ async fn linkup_service<'a>(
         .await;
 
     #[cfg(has_drtio_routing)]
-    if let Some((repno, packet)) = router.get_downstream_packet() {
+    if let Some((repno, packet)) = router.get_downstream_packet(|repno| repeaters[repno].aux_ready()) {
         if let Err(e) = repeaters[repno].aux_send(&packet).await {
             warn!("[REP#{}] Error when sending packet to satellite ({:?})", repno, e)
         }
@@ -533,7 +682,7 @@ extern "C" {
 static mut PANICKED: [bool; 2] = [false; 2];
 
 #[no_mangle]
-pub extern "C" fn exception(_vect: u32, _regs: *const u32, pc: u32, ea: u32) {
+pub extern "C" fn exception(vect: u32, regs: *const u32, pc: u32, ea: u32) {
     fn hexdump(addr: u32) {
         let addr = (addr - addr % 4) as *const u32;
         let mut ptr = addr;
@@ -551,6 +700,8 @@ pub extern "C" fn exception(_vect: u32, _regs: *const u32, pc: u32, ea: u32) {
         }
     }
 
+    // faults of a running subkernel are reported like its exceptions
+    ksupport::kernel::core1::kernel_fault(vect, regs, pc, ea);
     hexdump(pc);
     hexdump(ea);
     panic!("exception at PC 0x{:x}, EA 0x{:x}", pc, ea)
@@ -581,6 +732,15 @@ pub fn panic_fmt(info: &core::panic::PanicInfo) -> ! {
         err_led.toggle(true);
     }
 
+    let mut report = PanicReport::new(id, info);
+    println!("Backtrace: ");
+    let _ = unwind::backtrace(|ip| {
+        print!("{:#08x} ", ip - 2 * 4);
+        report.add_frame(ip - 2 * 4);
+    });
+    println!("\nEnd backtrace");
+    report.save();
+
     loop {}
 }
 
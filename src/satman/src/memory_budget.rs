@@ -0,0 +1,56 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use libconfig;
+use log::{info, warn};
+
+// Shares the satellite heap between the analyzer buffer, DMA traces and subkernel
+// libraries, set by the `memory_budget` config key: comma-separated `analyzer=<KiB>`,
+// `dma=<KiB>` and `subkernels=<KiB>` entries. The analyzer buffer is allocated once
+// at boot, DMA traces and subkernels are refused once they would exceed their share.
+
+pub const DEFAULT_ANALYZER: usize = 512 * 1024;
+// no limit, other than the heap itself
+const UNLIMITED: usize = usize::MAX;
+
+static ANALYZER: AtomicUsize = AtomicUsize::new(DEFAULT_ANALYZER);
+static DMA: AtomicUsize = AtomicUsize::new(UNLIMITED);
+static SUBKERNELS: AtomicUsize = AtomicUsize::new(UNLIMITED);
+
+pub fn load() {
+    let text = match libconfig::read_str("memory_budget") {
+        Ok(text) => text,
+        Err(_) => return,
+    };
+    for entry in text.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (name, kib) = match entry.split_once('=').map(|(name, kib)| (name.trim(), kib.trim().parse::<usize>())) {
+            Some((name, Ok(kib))) => (name, kib),
+            _ => {
+                warn!("memory_budget entry \"{}\" not supported, ignoring", entry);
+                continue;
+            }
+        };
+        let budget = match name {
+            "analyzer" => &ANALYZER,
+            "dma" => &DMA,
+            "subkernels" => &SUBKERNELS,
+            _ => {
+                warn!("memory_budget entry \"{}\" not supported, ignoring", entry);
+                continue;
+            }
+        };
+        budget.store(kib.saturating_mul(1024), Ordering::Relaxed);
+        info!("{} memory budget: {} KiB", name, kib);
+    }
+}
+
+pub fn analyzer() -> usize {
+    ANALYZER.load(Ordering::Relaxed)
+}
+
+pub fn dma() -> usize {
+    DMA.load(Ordering::Relaxed)
+}
+
+pub fn subkernels() -> usize {
+    SUBKERNELS.load(Ordering::Relaxed)
+}
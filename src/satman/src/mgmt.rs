@@ -1,11 +1,14 @@
-use alloc::vec::Vec;
+use alloc::{string::String, vec::Vec};
 
 use byteorder::{ByteOrder, NativeEndian};
 use core_io::Write;
 use crc::crc32;
 use io::ProtoRead;
-use libboard_artiq::{drtioaux_proto::SAT_PAYLOAD_MAX_SIZE,
-                     logger::{BufferLogger, LogBufferRef}};
+#[cfg(has_drtio_eyescan)]
+use libboard_artiq::eye_scan;
+use libboard_artiq::{config_store, drtioaux,
+                     drtioaux_proto::{DESTINATION_MASK_SIZE, SAT_PAYLOAD_MAX_SIZE},
+                     logger::{BufferLogger, LogBufferRef, LogFilter, LogStats}};
 use log::{LevelFilter, debug, error, info, warn};
 
 use crate::routing::{SliceMeta, Sliceable};
@@ -26,11 +29,19 @@ pub fn clear_log() {
     buffer.clear();
 }
 
+pub fn log_stats() -> LogStats {
+    get_logger_buffer().stats()
+}
+
 pub struct Manager {
     last_log: Sliceable,
     config_payload: Vec<u8>,
     last_value: Sliceable,
     image_payload: Vec<u8>,
+    image_verify_only: bool,
+    image_fan_out: Option<[u8; DESTINATION_MASK_SIZE]>,
+    eye_scan: Sliceable,
+    aux_trace: Sliceable,
 }
 
 impl Manager {
@@ -40,14 +51,27 @@ impl Manager {
             config_payload: Vec::new(),
             last_value: Sliceable::new(0, Vec::new()),
             image_payload: Vec::new(),
+            image_verify_only: false,
+            image_fan_out: None,
+            eye_scan: Sliceable::new(0, Vec::new()),
+            aux_trace: Sliceable::new(0, Vec::new()),
         }
     }
 
-    pub fn log_get_slice(&mut self, data_slice: &mut [u8; SAT_PAYLOAD_MAX_SIZE], consume: bool) -> SliceMeta {
+    pub fn log_get_slice(
+        &mut self,
+        data_slice: &mut [u8; SAT_PAYLOAD_MAX_SIZE],
+        consume: bool,
+        max_level: LevelFilter,
+    ) -> SliceMeta {
         // Populate buffer if depleted
         if self.last_log.at_end() {
             let mut buffer = get_logger_buffer();
-            self.last_log.extend(buffer.extract().as_bytes());
+            let filter = LogFilter {
+                max_level: max_level,
+                module_prefix: String::new(),
+            };
+            self.last_log.extend(filter.apply(buffer.extract()).as_bytes());
             if consume {
                 buffer.clear();
             }
@@ -65,10 +89,47 @@ impl Manager {
             .map_err(|_| warn!("read error: no such key"))
     }
 
+    pub fn fetch_config_keys(&mut self) -> Result<()> {
+        libconfig::keys()
+            .map(|keys| self.last_value = Sliceable::new(0, keys.join("\n").into_bytes()))
+            .map_err(|err| warn!("failed to list keys: {:?}", err))
+    }
+
     pub fn get_config_value_slice(&mut self, data_slice: &mut [u8; SAT_PAYLOAD_MAX_SIZE]) -> SliceMeta {
         self.last_value.get_slice_satellite(data_slice)
     }
 
+    // link 0 is the upstream link, repeater links follow
+    pub fn run_eye_scan(&mut self, link: u8, prescale: u8) -> Result<()> {
+        #[cfg(has_drtio_eyescan)]
+        {
+            self.eye_scan = Sliceable::new(0, eye_scan::scan(link, prescale)?);
+            Ok(())
+        }
+        #[cfg(not(has_drtio_eyescan))]
+        {
+            warn!("eye scan of link {} (prescale {}) not supported by the gateware", link, prescale);
+            Err(())
+        }
+    }
+
+    pub fn get_eye_scan_slice(&mut self, data_slice: &mut [u8; SAT_PAYLOAD_MAX_SIZE]) -> SliceMeta {
+        self.eye_scan.get_slice_satellite(data_slice)
+    }
+
+    // snapshot of the aux packet trace, read out in slices
+    pub fn fetch_aux_trace(&mut self) {
+        let mut data = Vec::new();
+        for entry in drtioaux::trace() {
+            data.extend_from_slice(&entry.to_bytes());
+        }
+        self.aux_trace = Sliceable::new(0, data);
+    }
+
+    pub fn get_aux_trace_slice(&mut self, data_slice: &mut [u8; SAT_PAYLOAD_MAX_SIZE]) -> SliceMeta {
+        self.aux_trace.get_slice_satellite(data_slice)
+    }
+
     pub fn add_config_data(&mut self, data: &[u8], data_len: usize) {
         self.config_payload.write_all(&data[..data_len]).unwrap();
     }
@@ -106,7 +167,7 @@ impl Manager {
             }
         };
 
-        libconfig::write(&key, value)
+        config_store::write(&key, value)
             .map(|()| debug!("write success"))
             .map_err(|err| error!("failed to write: {:?}", err))?;
 
@@ -119,40 +180,80 @@ impl Manager {
 
     pub fn remove_config(&mut self, key: &str) -> Result<()> {
         debug!("erase key: {}", key);
-        libconfig::remove(&key)
+        config_store::remove(&key)
             .map(|()| debug!("erase success"))
             .map_err(|err| warn!("failed to erase: {:?}", err))
     }
 
-    pub fn allocate_image_buffer(&mut self, image_size: usize) {
+    pub fn set_sed_spread(&mut self, enable: bool) -> Result<()> {
+        let value: &[u8] = if enable { b"1" } else { b"0" };
+        config_store::write("sed_spread_enable", value.to_vec())
+            .map_err(|err| error!("failed to write sed_spread_enable: {:?}", err))?;
+        crate::toggle_sed_spread(enable as u8);
+        info!("SED spreading {}", if enable { "enabled" } else { "disabled" });
+        Ok(())
+    }
+
+    pub fn allocate_image_buffer(&mut self, image_size: usize, verify_only: bool) {
         self.image_payload = Vec::with_capacity(image_size);
+        self.image_verify_only = verify_only;
+        self.image_fan_out = None;
+    }
+
+    pub fn set_image_fan_out(&mut self, targets: [u8; DESTINATION_MASK_SIZE]) {
+        self.image_fan_out = Some(targets);
     }
 
     pub fn add_image_data(&mut self, data: &[u8], data_len: usize) {
         self.image_payload.extend(&data[..data_len]);
     }
 
-    pub fn write_image(&self) {
-        let mut image = self.image_payload.clone();
-        let image_ref = &image[..];
-        let bin_len = image.len() - 4;
+    pub fn image_verify_only(&self) -> bool {
+        self.image_verify_only
+    }
+
+    pub fn image_fan_out(&self) -> Option<[u8; DESTINATION_MASK_SIZE]> {
+        self.image_fan_out
+    }
+
+    pub fn image_payload(&self) -> &[u8] {
+        &self.image_payload
+    }
+
+    pub fn verify_image(&self) -> Result<()> {
+        if self.image_payload.len() < 4 {
+            error!("boot image too short ({} bytes)", self.image_payload.len());
+            return Err(());
+        }
+        let bin_len = self.image_payload.len() - 4;
 
         let (image_ref, expected_crc) = {
-            let (image_ref, crc_slice) = image_ref.split_at(bin_len);
+            let (image_ref, crc_slice) = self.image_payload.split_at(bin_len);
             (image_ref, NativeEndian::read_u32(crc_slice))
         };
 
         let actual_crc = crc32::checksum_ieee(image_ref);
 
         if actual_crc == expected_crc {
-            info!("CRC passed. Writing boot image to SD card...");
-            image.truncate(bin_len);
-            libconfig::write("boot", image).expect("failed to write boot image");
+            info!("CRC passed for {} byte boot image", bin_len);
+            Ok(())
         } else {
-            panic!(
-                "CRC failed, images have not been written to flash.\n(actual {:08x}, expected {:08x})",
+            error!(
+                "CRC failed for boot image (actual {:08x}, expected {:08x})",
                 actual_crc, expected_crc
             );
+            Err(())
+        }
+    }
+
+    pub fn write_image(&self) {
+        if self.verify_image().is_ok() {
+            info!("Writing boot image to SD card...");
+            let mut image = self.image_payload.clone();
+            image.truncate(image.len() - 4);
+            config_store::write("boot", image).expect("failed to write boot image");
+        } else {
+            panic!("CRC failed, images have not been written to flash.");
         }
     }
 }
@@ -1,10 +1,20 @@
 #[cfg(has_drtio_routing)]
 use libasync::task;
 use libboard_artiq::{drtio_routing, drtioaux};
+#[cfg(not(has_drtio_routing))]
+use libboard_artiq::drtioaux_proto;
 #[cfg(has_drtio_routing)]
-use libboard_artiq::{drtioaux_async, pl::csr};
+use libboard_artiq::{drtioaux::AUX_RETRANSMIT_LIMIT,
+                     drtioaux_async,
+                     drtioaux_proto::{AUX_TIMEOUT, DESTINATION_MASK_SIZE, DMA_BROADCAST_TIMEOUT_PER_TARGET,
+                                      EYE_SCAN_TIMEOUT, FEATURE_FRAMING, FLASH_FAN_OUT_TIMEOUT_PER_TARGET,
+                                      MASTER_PAYLOAD_MAX_SIZE, PROTOCOL_VERSION, REPEATER_DOWN, REPEATER_FAILED,
+                                      REPEATER_HISTORY_SIZE, REPEATER_PINGING, REPEATER_UP, local_features},
+                     pl::csr};
 #[cfg(has_drtio_routing)]
 use libboard_zynq::timer;
+#[cfg(has_drtio_routing)]
+use libcortex_a9::mutex::Mutex;
 
 use crate::routing::Router;
 
@@ -31,6 +41,97 @@ impl Default for RepeaterState {
     }
 }
 
+#[cfg(has_drtio_routing)]
+impl RepeaterState {
+    fn code(&self) -> u8 {
+        match self {
+            RepeaterState::Down => REPEATER_DOWN,
+            RepeaterState::SendPing { .. } | RepeaterState::WaitPingReply { .. } => REPEATER_PINGING,
+            RepeaterState::Up => REPEATER_UP,
+            RepeaterState::Failed => REPEATER_FAILED,
+        }
+    }
+}
+
+// Link transitions and aux errors of each repeater since boot, queried by the master.
+// Kept apart from the repeaters, which are copied around and borrowed immutably to send.
+#[cfg(has_drtio_routing)]
+#[derive(Clone, Copy)]
+struct Stats {
+    state: u8,
+    up_transitions: u32,
+    down_transitions: u32,
+    aux_send_failures: u32,
+    forward_timeouts: u32,
+    // (time in ms, state entered) of the last transitions, the oldest overwritten first
+    history: [(u64, u8); REPEATER_HISTORY_SIZE],
+    history_len: usize,
+    history_next: usize,
+}
+
+#[cfg(has_drtio_routing)]
+const STATS_INIT: Stats = Stats {
+    state: REPEATER_DOWN,
+    up_transitions: 0,
+    down_transitions: 0,
+    aux_send_failures: 0,
+    forward_timeouts: 0,
+    history: [(0, REPEATER_DOWN); REPEATER_HISTORY_SIZE],
+    history_len: 0,
+    history_next: 0,
+};
+
+#[cfg(has_drtio_routing)]
+static STATS: Mutex<[Stats; csr::DRTIOREP.len()]> = Mutex::new([STATS_INIT; csr::DRTIOREP.len()]);
+
+#[cfg(has_drtio_routing)]
+fn record_transition(repno: u8, state: u8) {
+    let mut all_stats = STATS.lock();
+    let stats = &mut all_stats[repno as usize];
+    if stats.state == state {
+        return;
+    }
+    if stats.state == REPEATER_UP {
+        stats.down_transitions = stats.down_transitions.wrapping_add(1);
+    }
+    if state == REPEATER_UP {
+        stats.up_transitions = stats.up_transitions.wrapping_add(1);
+    }
+    stats.state = state;
+    stats.history[stats.history_next] = (timer::get_ms(), state);
+    stats.history_next = (stats.history_next + 1) % REPEATER_HISTORY_SIZE;
+    stats.history_len = (stats.history_len + 1).min(REPEATER_HISTORY_SIZE);
+}
+
+/// Reply to a RepeaterStatsRequest, with the transitions newest first.
+#[cfg(has_drtio_routing)]
+pub fn stats_reply(repno: u8) -> drtioaux::Packet {
+    let mut history_states = [0; REPEATER_HISTORY_SIZE];
+    let mut history_age_ms = [0; REPEATER_HISTORY_SIZE];
+    let stats = match STATS.lock().get(repno as usize) {
+        Some(stats) => *stats,
+        None => STATS_INIT,
+    };
+    let now = timer::get_ms();
+    for i in 0..stats.history_len {
+        let index = (stats.history_next + REPEATER_HISTORY_SIZE - 1 - i) % REPEATER_HISTORY_SIZE;
+        let (time, state) = stats.history[index];
+        history_states[i] = state;
+        history_age_ms[i] = now.saturating_sub(time).min(u32::MAX as u64) as u32;
+    }
+    drtioaux::Packet::RepeaterStatsReply {
+        repeaters: csr::DRTIOREP.len() as u8,
+        state: stats.state,
+        up_transitions: stats.up_transitions,
+        down_transitions: stats.down_transitions,
+        aux_send_failures: stats.aux_send_failures,
+        forward_timeouts: stats.forward_timeouts,
+        history_len: stats.history_len as u8,
+        history_states: history_states,
+        history_age_ms: history_age_ms,
+    }
+}
+
 #[cfg(has_drtio_routing)]
 #[derive(Clone, Copy, Default)]
 pub struct Repeater {
@@ -49,11 +150,21 @@ impl Repeater {
         }
     }
 
+    fn set_state(&mut self, state: RepeaterState) {
+        self.state = state;
+        record_transition(self.repno, state.code());
+    }
+
     #[allow(dead_code)]
     pub fn is_up(&self) -> bool {
         self.state == RepeaterState::Up
     }
 
+    // whether a packet can be sent downstream without waiting
+    pub fn aux_ready(&self) -> bool {
+        self.state == RepeaterState::Up && drtioaux_async::tx_ready_now(self.auxno)
+    }
+
     pub async fn service(
         &mut self,
         routing_table: &drtio_routing::RoutingTable,
@@ -67,7 +178,7 @@ impl Repeater {
             RepeaterState::Down => {
                 if rep_link_rx_up(self.repno) {
                     info!("[REP#{}] link RX became up, pinging", self.repno);
-                    self.state = RepeaterState::SendPing { ping_count: 0 };
+                    self.set_state(RepeaterState::SendPing { ping_count: 0 });
                 }
             }
             RepeaterState::SendPing { ping_count } => {
@@ -81,7 +192,7 @@ impl Repeater {
                     }
                 } else {
                     error!("[REP#{}] link RX went down during ping", self.repno);
-                    self.state = RepeaterState::Down;
+                    self.set_state(RepeaterState::Down);
                 }
             }
             RepeaterState::WaitPingReply { ping_count, timeout } => {
@@ -93,27 +204,34 @@ impl Repeater {
                             task::r#yield().await;
                             let _ = drtioaux::recv(self.auxno);
                         }
+                        // set before the handshake to send it, recorded once it succeeded
                         self.state = RepeaterState::Up;
+                        if let Err(e) = self.check_version().await {
+                            error!("[REP#{}] version check failed, not bringing link up ({:?})", self.repno, e);
+                            self.set_state(RepeaterState::Failed);
+                            return;
+                        }
                         if let Err(e) = self.sync_tsc().await {
                             error!("[REP#{}] failed to sync TSC ({:?})", self.repno, e);
-                            self.state = RepeaterState::Failed;
+                            self.set_state(RepeaterState::Failed);
                             return;
                         }
                         if let Err(e) = self.load_routing_table(routing_table).await {
                             error!("[REP#{}] failed to load routing table ({:?})", self.repno, e);
-                            self.state = RepeaterState::Failed;
+                            self.set_state(RepeaterState::Failed);
                             return;
                         }
                         if let Err(e) = self.set_rank(rank + 1).await {
                             error!("[REP#{}] failed to set rank ({:?})", self.repno, e);
-                            self.state = RepeaterState::Failed;
+                            self.set_state(RepeaterState::Failed);
                             return;
                         }
+                        self.set_state(RepeaterState::Up);
                     } else {
                         if timer::get_ms() > timeout {
                             if ping_count > 200 {
                                 error!("[REP#{}] ping failed", self.repno);
-                                self.state = RepeaterState::Failed;
+                                self.set_state(RepeaterState::Failed);
                             } else {
                                 self.state = RepeaterState::SendPing { ping_count: ping_count };
                             }
@@ -121,7 +239,7 @@ impl Repeater {
                     }
                 } else {
                     error!("[REP#{}] link RX went down during ping", self.repno);
-                    self.state = RepeaterState::Down;
+                    self.set_state(RepeaterState::Down);
                 }
             }
             RepeaterState::Up => {
@@ -129,13 +247,14 @@ impl Repeater {
                     .await;
                 if !rep_link_rx_up(self.repno) {
                     info!("[REP#{}] link is down", self.repno);
-                    self.state = RepeaterState::Down;
+                    self.set_state(RepeaterState::Down);
+                    drtioaux::set_framing(self.auxno, false);
                 }
             }
             RepeaterState::Failed => {
                 if !rep_link_rx_up(self.repno) {
                     info!("[REP#{}] link is down", self.repno);
-                    self.state = RepeaterState::Down;
+                    self.set_state(RepeaterState::Down);
                 }
             }
         }
@@ -149,6 +268,7 @@ impl Repeater {
         router: &mut Router,
     ) {
         match drtioaux::recv(self.auxno) {
+            Ok(Some(drtioaux::Packet::RetransmitRequest)) => drtioaux::retransmit(self.auxno),
             Ok(Some(packet)) => router.route(packet, routing_table, rank, destination),
             Ok(None) => (),
             Err(_) => warn!("[REP#{}] aux packet error", self.repno),
@@ -201,44 +321,212 @@ impl Repeater {
         drtioaux_async::recv_timeout(self.auxno, Some(timeout)).await
     }
 
-    pub async fn aux_forward(
+    async fn aux_transact(
         &self,
         request: &drtioaux::Packet,
+        timeout: u64,
         router: &mut Router,
         routing_table: &drtio_routing::RoutingTable,
         rank: u8,
         self_destination: u8,
-    ) -> Result<(), drtioaux::Error> {
+    ) -> Result<drtioaux::Packet, drtioaux::Error> {
         self.aux_send(request).await?;
+        let mut retransmits = 0;
         loop {
-            let reply = self.recv_aux_timeout(200).await?;
+            let reply = match self.recv_aux_timeout(timeout).await {
+                Err(e) if e.is_damaged() && drtioaux::framing(self.auxno) && retransmits < AUX_RETRANSMIT_LIMIT => {
+                    retransmits += 1;
+                    warn!("[REP#{}] damaged aux reply, asking for retransmission", self.repno);
+                    self.aux_send(&drtioaux::Packet::RetransmitRequest).await?;
+                    continue;
+                }
+                result => result?,
+            };
             match reply {
+                drtioaux::Packet::RetransmitRequest if retransmits < AUX_RETRANSMIT_LIMIT => {
+                    retransmits += 1;
+                    drtioaux_async::retransmit(self.auxno).await;
+                }
                 // async/locally requested packets to be consumed or routed
                 // these may come while a packet would be forwarded
                 drtioaux::Packet::DmaPlaybackStatus { .. }
                 | drtioaux::Packet::SubkernelFinished { .. }
                 | drtioaux::Packet::SubkernelMessage { .. }
                 | drtioaux::Packet::SubkernelMessageAck { .. }
+                | drtioaux::Packet::SubkernelMessageCredit { .. }
+                | drtioaux::Packet::SubkernelBarrierArrive { .. }
+                | drtioaux::Packet::SubkernelBarrierWithdraw { .. }
+                | drtioaux::Packet::SubkernelBarrierRelease { .. }
                 | drtioaux::Packet::SubkernelLoadRunReply { .. }
                 | drtioaux::Packet::SubkernelException { .. }
                 | drtioaux::Packet::DmaAddTraceReply { .. }
                 | drtioaux::Packet::DmaPlaybackReply { .. } => {
                     router.route(reply, routing_table, rank, self_destination);
                 }
-                _ => {
-                    drtioaux_async::send(0, &reply).await.unwrap();
-                    break;
-                }
+                _ => return Ok(reply),
             }
         }
+    }
+
+    pub async fn aux_forward(
+        &self,
+        request: &drtioaux::Packet,
+        router: &mut Router,
+        routing_table: &drtio_routing::RoutingTable,
+        rank: u8,
+        self_destination: u8,
+    ) -> Result<(), drtioaux::Error> {
+        let timeout = match request {
+            drtioaux::Packet::EyeScanRequest { .. } => EYE_SCAN_TIMEOUT,
+            _ => AUX_TIMEOUT,
+        };
+        let reply = self
+            .aux_transact(request, timeout, router, routing_table, rank, self_destination)
+            .await;
+        if let Err(drtioaux::Error::TimedOut) = reply {
+            let mut stats = STATS.lock();
+            let stats = &mut stats[self.repno as usize];
+            stats.forward_timeouts = stats.forward_timeouts.wrapping_add(1);
+        }
+        drtioaux_async::send(0, &reply?).await.unwrap();
         Ok(())
     }
 
+    pub async fn flash_fan_out(
+        &self,
+        entry: u8,
+        targets: &[u8; DESTINATION_MASK_SIZE],
+        image: &[u8],
+        router: &mut Router,
+        routing_table: &drtio_routing::RoutingTable,
+        rank: u8,
+        self_destination: u8,
+    ) -> Result<[u8; DESTINATION_MASK_SIZE], drtioaux::Error> {
+        // the entry satellite only replies to the last chunk once its own
+        // downstream satellites are done, allow time for each of them
+        let target_count = targets.iter().map(|b| b.count_ones() as u64).sum::<u64>();
+        let last_timeout = FLASH_FAN_OUT_TIMEOUT_PER_TARGET * target_count.max(1);
+
+        let reply = self
+            .aux_transact(
+                &drtioaux::Packet::CoreMgmtFlashFanOutRequest {
+                    destination: entry,
+                    payload_length: image.len() as u32,
+                    targets: *targets,
+                },
+                AUX_TIMEOUT,
+                router,
+                routing_table,
+                rank,
+                self_destination,
+            )
+            .await?;
+        if reply != (drtioaux::Packet::CoreMgmtReply { succeeded: true }) {
+            return Err(drtioaux::Error::UnexpectedReply);
+        }
+
+        let mut chunks = image.chunks(MASTER_PAYLOAD_MAX_SIZE).peekable();
+        while let Some(chunk) = chunks.next() {
+            let last = chunks.peek().is_none();
+            let mut data = [0; MASTER_PAYLOAD_MAX_SIZE];
+            data[..chunk.len()].copy_from_slice(chunk);
+            let reply = self
+                .aux_transact(
+                    &drtioaux::Packet::CoreMgmtFlashAddDataRequest {
+                        destination: entry,
+                        last: last,
+                        length: chunk.len() as u16,
+                        data: data,
+                    },
+                    if last { last_timeout } else { AUX_TIMEOUT },
+                    router,
+                    routing_table,
+                    rank,
+                    self_destination,
+                )
+                .await?;
+            match reply {
+                drtioaux::Packet::CoreMgmtReply { succeeded: true } if !last => (),
+                drtioaux::Packet::CoreMgmtFlashFanOutReply { succeeded } if last => {
+                    if drtio_routing::mask_contains(&succeeded, entry) {
+                        self.aux_send(&drtioaux::Packet::CoreMgmtDropLinkAck { destination: entry })
+                            .await?;
+                    }
+                    return Ok(succeeded);
+                }
+                _ => return Err(drtioaux::Error::UnexpectedReply),
+            }
+        }
+        Err(drtioaux::Error::UnexpectedReply)
+    }
+
+    pub async fn dma_playback_broadcast(
+        &self,
+        entry: u8,
+        source: u8,
+        id: u32,
+        timestamp: u64,
+        targets: &[u8; DESTINATION_MASK_SIZE],
+        router: &mut Router,
+        routing_table: &drtio_routing::RoutingTable,
+        rank: u8,
+        self_destination: u8,
+    ) -> Result<[u8; DESTINATION_MASK_SIZE], drtioaux::Error> {
+        // the entry satellite replies once its own downstream satellites have started
+        let target_count = targets.iter().map(|b| b.count_ones() as u64).sum::<u64>();
+        let reply = self
+            .aux_transact(
+                &drtioaux::Packet::DmaPlaybackBroadcastRequest {
+                    source: source,
+                    destination: entry,
+                    id: id,
+                    timestamp: timestamp,
+                    targets: *targets,
+                },
+                DMA_BROADCAST_TIMEOUT_PER_TARGET * target_count.max(1),
+                router,
+                routing_table,
+                rank,
+                self_destination,
+            )
+            .await?;
+        match reply {
+            drtioaux::Packet::DmaPlaybackBroadcastReply { succeeded } => Ok(succeeded),
+            _ => Err(drtioaux::Error::UnexpectedReply),
+        }
+    }
+
     pub async fn aux_send(&self, request: &drtioaux::Packet) -> Result<(), drtioaux::Error> {
-        if self.state != RepeaterState::Up {
-            return Err(drtioaux::Error::LinkDown);
+        let result = if self.state != RepeaterState::Up {
+            Err(drtioaux::Error::LinkDown)
+        } else {
+            drtioaux_async::send(self.auxno, request).await
+        };
+        if result.is_err() {
+            let mut stats = STATS.lock();
+            let stats = &mut stats[self.repno as usize];
+            stats.aux_send_failures = stats.aux_send_failures.wrapping_add(1);
+        }
+        result
+    }
+
+    async fn check_version(&self) -> Result<(), drtioaux::Error> {
+        self.aux_send(&drtioaux::Packet::VersionRequest {
+            version: PROTOCOL_VERSION,
+            features: local_features(),
+        })
+        .await?;
+        match self.recv_aux_timeout(AUX_TIMEOUT).await? {
+            drtioaux::Packet::VersionReply { version, features } => {
+                if version != PROTOCOL_VERSION {
+                    return Err(drtioaux::Error::IncompatibleVersion(version));
+                }
+                info!("[REP#{}] remote aux protocol version {}, features 0x{:08x}", self.repno, version, features);
+                drtioaux::set_framing(self.auxno, features & FEATURE_FRAMING != 0);
+                Ok(())
+            }
+            _ => Err(drtioaux::Error::UnexpectedReply),
         }
-        drtioaux_async::send(self.auxno, request).await
     }
 
     pub async fn sync_tsc(&self) -> Result<(), drtioaux::Error> {
@@ -276,7 +564,7 @@ impl Repeater {
         )
         .await
         .unwrap();
-        let reply = self.recv_aux_timeout(200).await?;
+        let reply = self.recv_aux_timeout(AUX_TIMEOUT).await?;
         if reply != drtioaux::Packet::RoutingAck {
             return Err(drtioaux::Error::UnexpectedReply);
         }
@@ -297,7 +585,7 @@ impl Repeater {
         drtioaux_async::send(self.auxno, &drtioaux::Packet::RoutingSetRank { rank: rank })
             .await
             .unwrap();
-        let reply = self.recv_aux_timeout(200).await?;
+        let reply = self.recv_aux_timeout(AUX_TIMEOUT).await?;
         if reply != drtioaux::Packet::RoutingAck {
             return Err(drtioaux::Error::UnexpectedReply);
         }
@@ -321,7 +609,7 @@ impl Repeater {
         drtioaux_async::send(self.auxno, &drtioaux::Packet::ResetRequest)
             .await
             .unwrap();
-        let reply = self.recv_aux_timeout(200).await?;
+        let reply = self.recv_aux_timeout(AUX_TIMEOUT).await?;
         if reply != drtioaux::Packet::ResetAck {
             return Err(drtioaux::Error::UnexpectedReply);
         }
@@ -329,6 +617,21 @@ impl Repeater {
     }
 }
 
+#[cfg(not(has_drtio_routing))]
+pub fn stats_reply(_repno: u8) -> drtioaux::Packet {
+    drtioaux::Packet::RepeaterStatsReply {
+        repeaters: 0,
+        state: 0,
+        up_transitions: 0,
+        down_transitions: 0,
+        aux_send_failures: 0,
+        forward_timeouts: 0,
+        history_len: 0,
+        history_states: [0; drtioaux_proto::REPEATER_HISTORY_SIZE],
+        history_age_ms: [0; drtioaux_proto::REPEATER_HISTORY_SIZE],
+    }
+}
+
 #[cfg(not(has_drtio_routing))]
 #[derive(Clone, Copy, Default)]
 pub struct Repeater {}
@@ -1,10 +1,14 @@
 use alloc::{collections::vec_deque::VecDeque, vec::Vec};
-use core::cmp::min;
+use core::cmp::{max, min};
 
 #[cfg(has_drtio_routing)]
 use libboard_artiq::pl::csr;
 use libboard_artiq::{drtio_routing, drtioaux, drtioaux_async,
-                     drtioaux_proto::{MASTER_PAYLOAD_MAX_SIZE, PayloadStatus, SAT_PAYLOAD_MAX_SIZE}};
+                     drtioaux_proto::{MASTER_PAYLOAD_MAX_SIZE, PacketPriority, PayloadStatus, SAT_PAYLOAD_MAX_SIZE}};
+#[cfg(has_drtio_routing)]
+use libboard_zynq::timer;
+#[cfg(has_drtio_routing)]
+use libcortex_a9::mutex::Mutex;
 
 pub struct SliceMeta {
     pub destination: u8,
@@ -53,6 +57,15 @@ impl Sliceable {
         self.it == self.data.len()
     }
 
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // moves back (or forward) to the given offset, so that a lost slice can be sent again
+    pub fn seek(&mut self, offset: usize) {
+        self.it = min(offset, self.data.len());
+    }
+
     pub fn extend(&mut self, data: &[u8]) {
         self.data.extend(data);
     }
@@ -70,23 +83,114 @@ impl Sliceable {
 // forward! macro is not deprecated, as routable packets are only these that can originate
 // from both master and satellite, e.g. DDMA and Subkernel.
 
+// Each queue keeps control and bulk packets apart; control packets (e.g. SubkernelFinished,
+// DmaPlaybackStatus, ACKs) are always serviced first, so they are not delayed by
+// a backlog of DDMA traces or subkernel messages.
+struct PriorityQueue<T> {
+    control: VecDeque<T>,
+    bulk: VecDeque<T>,
+}
+
+impl<T> PriorityQueue<T> {
+    fn new() -> PriorityQueue<T> {
+        PriorityQueue {
+            control: VecDeque::new(),
+            bulk: VecDeque::new(),
+        }
+    }
+
+    fn push_back(&mut self, priority: PacketPriority, item: T) {
+        match priority {
+            PacketPriority::Control => self.control.push_back(item),
+            PacketPriority::Bulk => self.bulk.push_back(item),
+        }
+    }
+
+    fn pop_front(&mut self) -> Option<T> {
+        self.control.pop_front().or_else(|| self.bulk.pop_front())
+    }
+
+    #[allow(dead_code)]
+    fn len(&self) -> usize {
+        self.control.len() + self.bulk.len()
+    }
+
+    #[allow(dead_code)]
+    fn retain(&mut self, mut f: impl FnMut(&T) -> bool) {
+        self.control.retain(|item| f(item));
+        self.bulk.retain(|item| f(item));
+    }
+}
+
+// Packets for a repeater are held while its link cannot take them, to ride through
+// brief congestion further down; a packet is dropped when the queue of its repeater
+// is full, or when it could not be forwarded for too long.
+#[cfg(has_drtio_routing)]
+const DOWNSTREAM_QUEUE_LIMIT: usize = 32;
+#[cfg(has_drtio_routing)]
+const DOWNSTREAM_MAX_AGE_MS: u64 = 200;
+
+// Forwarding counters of the downstream queues, since boot.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ForwardStats {
+    pub forwarded: u32,
+    pub queued: u16,
+    pub max_queued: u16,
+    pub dropped_full: u32,
+    pub dropped_aged: u32,
+}
+
+// for each repeater; a Router only lasts an uplink session, the counters outlive it
+#[cfg(has_drtio_routing)]
+static FORWARD_STATS: Mutex<Vec<ForwardStats>> = Mutex::new(Vec::new());
+
 pub struct Router {
-    upstream_queue: VecDeque<drtioaux::Packet>,
-    local_queue: VecDeque<drtioaux::Packet>,
+    upstream_queue: PriorityQueue<drtioaux::Packet>,
+    local_queue: PriorityQueue<drtioaux::Packet>,
+    // packets with the time they were queued at, for each repeater
+    #[cfg(has_drtio_routing)]
+    downstream_queues: Vec<PriorityQueue<(u64, drtioaux::Packet)>>,
+    // repeater whose queue is serviced first, in turn
     #[cfg(has_drtio_routing)]
-    downstream_queue: VecDeque<(usize, drtioaux::Packet)>,
+    next_repno: usize,
 }
 
 impl Router {
     pub fn new() -> Router {
+        #[cfg(has_drtio_routing)]
+        {
+            let mut forward_stats = FORWARD_STATS.lock();
+            forward_stats.resize(csr::DRTIOREP.len(), ForwardStats::default());
+            // the queues start empty
+            for stats in forward_stats.iter_mut() {
+                stats.queued = 0;
+            }
+        }
         Router {
-            upstream_queue: VecDeque::new(),
-            local_queue: VecDeque::new(),
+            upstream_queue: PriorityQueue::new(),
+            local_queue: PriorityQueue::new(),
+            #[cfg(has_drtio_routing)]
+            downstream_queues: (0..csr::DRTIOREP.len()).map(|_| PriorityQueue::new()).collect(),
             #[cfg(has_drtio_routing)]
-            downstream_queue: VecDeque::new(),
+            next_repno: 0,
         }
     }
 
+    #[cfg(has_drtio_routing)]
+    fn queue_downstream(&mut self, repno: usize, priority: PacketPriority, packet: drtioaux::Packet) {
+        let queue = &mut self.downstream_queues[repno];
+        let mut forward_stats = FORWARD_STATS.lock();
+        let stats = &mut forward_stats[repno];
+        if queue.len() >= DOWNSTREAM_QUEUE_LIMIT {
+            stats.dropped_full += 1;
+            warn!("[REP#{}] downstream queue full, dropping packet: {:?}", repno, packet);
+            return;
+        }
+        queue.push_back(priority, (timer::get_ms(), packet));
+        stats.queued = queue.len() as u16;
+        stats.max_queued = max(stats.max_queued, stats.queued);
+    }
+
     // Called by local sources (DDMA, kernel) and by repeaters on receiving async data;
     // messages are always buffered for both upstream and downstream
     pub fn route(
@@ -97,17 +201,18 @@ impl Router {
         self_destination: u8,
     ) {
         let destination = packet.routable_destination();
+        let priority = packet.priority();
         #[cfg(has_drtio_routing)]
         {
             if let Some(destination) = destination {
                 let hop = _routing_table.0[destination as usize][_rank as usize] as usize;
                 if destination == self_destination {
-                    self.local_queue.push_back(packet);
+                    self.local_queue.push_back(priority, packet);
                 } else if hop > 0 && hop < csr::DRTIOREP.len() {
                     let repno = (hop - 1) as usize;
-                    self.downstream_queue.push_back((repno, packet));
+                    self.queue_downstream(repno, priority, packet);
                 } else {
-                    self.upstream_queue.push_back(packet);
+                    self.upstream_queue.push_back(priority, packet);
                 }
             } else {
                 error!("Received an unroutable packet: {:?}", packet);
@@ -116,9 +221,9 @@ impl Router {
         #[cfg(not(has_drtio_routing))]
         {
             if destination == Some(self_destination) {
-                self.local_queue.push_back(packet);
+                self.local_queue.push_back(priority, packet);
             } else {
-                self.upstream_queue.push_back(packet);
+                self.upstream_queue.push_back(priority, packet);
             }
         }
     }
@@ -134,6 +239,7 @@ impl Router {
         #[cfg(has_drtio_routing)]
         {
             let destination = packet.routable_destination();
+            let priority = packet.priority();
             if let Some(destination) = destination {
                 let hop = _routing_table.0[destination as usize][_rank as usize] as usize;
                 if destination == 0 {
@@ -141,11 +247,11 @@ impl Router {
                     drtioaux_async::send(0, &packet).await?;
                 } else if !(hop > 0 && hop < csr::DRTIOREP.len()) {
                     // higher rank can wait
-                    self.upstream_queue.push_back(packet);
+                    self.upstream_queue.push_back(priority, packet);
                 } else {
                     let repno = (hop - 1) as usize;
                     // transaction will occur at closest possible opportunity
-                    self.downstream_queue.push_back((repno, packet));
+                    self.queue_downstream(repno, priority, packet);
                 }
                 Ok(())
             } else {
@@ -163,9 +269,51 @@ impl Router {
         self.upstream_queue.pop_front()
     }
 
+    // Returns the next packet for a repeater whose link can take it right away, so that
+    // a congested repeater does not hold up the others; packets too old are dropped.
     #[cfg(has_drtio_routing)]
-    pub fn get_downstream_packet(&mut self) -> Option<(usize, drtioaux::Packet)> {
-        self.downstream_queue.pop_front()
+    pub fn get_downstream_packet(&mut self, ready: impl Fn(usize) -> bool) -> Option<(usize, drtioaux::Packet)> {
+        let now = timer::get_ms();
+        let count = self.downstream_queues.len();
+        for i in 0..count {
+            let repno = (self.next_repno + i) % count;
+            let queue = &mut self.downstream_queues[repno];
+            let mut forward_stats = FORWARD_STATS.lock();
+            let stats = &mut forward_stats[repno];
+            let queued = queue.len();
+            queue.retain(|(queued_at, _)| now < queued_at + DOWNSTREAM_MAX_AGE_MS);
+            if queue.len() < queued {
+                let aged = queued - queue.len();
+                stats.dropped_aged += aged as u32;
+                warn!(
+                    "[REP#{}] dropped {} packets not forwarded within {} ms",
+                    repno, aged, DOWNSTREAM_MAX_AGE_MS
+                );
+            }
+            let packet = if ready(repno) { queue.pop_front() } else { None };
+            stats.queued = queue.len() as u16;
+            if let Some((_, packet)) = packet {
+                stats.forwarded += 1;
+                self.next_repno = (repno + 1) % count;
+                return Some((repno, packet));
+            }
+        }
+        None
+    }
+
+    // Sums the counters of all repeaters.
+    pub fn forward_stats() -> ForwardStats {
+        #[allow(unused_mut)]
+        let mut total = ForwardStats::default();
+        #[cfg(has_drtio_routing)]
+        for stats in FORWARD_STATS.lock().iter() {
+            total.forwarded += stats.forwarded;
+            total.queued += stats.queued;
+            total.max_queued = max(total.max_queued, stats.max_queued);
+            total.dropped_full += stats.dropped_full;
+            total.dropped_aged += stats.dropped_aged;
+        }
+        total
     }
 
     pub fn get_local_packet(&mut self) -> Option<drtioaux::Packet> {
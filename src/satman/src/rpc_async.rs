@@ -1,14 +1,18 @@
-use alloc::boxed::Box; // for async_recursion
+use alloc::{boxed::Box, vec::Vec}; // Box for async_recursion
 
 use async_recursion::async_recursion;
 use byteorder::{ByteOrder, NativeEndian};
-use core_io::Error;
+use core_io::{Error, ErrorKind};
 use cslice::CMutSlice;
 use io::ProtoRead;
 use ksupport::rpc::{tag::{Tag, TagIterator},
                     *};
 use log::trace;
 
+fn too_large() -> Error {
+    Error::new(ErrorKind::InvalidData, "array too large")
+}
+
 #[async_recursion(?Send)]
 async unsafe fn recv_elements<R: ProtoRead>(
     reader: &mut R,
@@ -108,9 +112,10 @@ async unsafe fn recv_value<R: ProtoRead>(
 
                 let list_size = 4 + 4;
                 let storage_offset = round_up(list_size, tag.alignment());
-                let storage_size = tag.size() * length;
+                let storage_size = tag.size().checked_mul(length).ok_or_else(too_large)?;
 
-                let allocation = alloc(storage_offset + storage_size).await as *mut u8;
+                let allocation_size = storage_offset.checked_add(storage_size).ok_or_else(too_large)?;
+                let allocation = alloc(allocation_size).await as *mut u8;
                 *ptr_to_list = allocation as *mut List;
                 let storage = allocation.offset(storage_offset as isize) as *mut ();
 
@@ -124,15 +129,44 @@ async unsafe fn recv_value<R: ProtoRead>(
                 let mut total_len: usize = 1;
                 for _ in 0..num_dims {
                     let len = reader.read_u32::<NativeEndian>()? as usize;
-                    total_len *= len;
+                    total_len = total_len.checked_mul(len).ok_or_else(too_large)?;
                     consume_value!(usize, |ptr| *ptr = len)
                 }
 
                 let elt_tag = it.clone().next().expect("truncated tag");
-                *buffer = alloc(elt_tag.size() * total_len).await;
+                let size = elt_tag.size().checked_mul(total_len).ok_or_else(too_large)?;
+                *buffer = alloc(size).await;
                 recv_elements(reader, elt_tag, total_len, *buffer, alloc).await
             })
         }
+        Tag::StridedArray(it, num_dims) => {
+            consume_value!(*mut (), |buffer| {
+                let mut dims = Vec::with_capacity(num_dims as usize);
+                for _ in 0..num_dims {
+                    let len = reader.read_u32::<NativeEndian>()? as usize;
+                    dims.push(len);
+                    consume_value!(usize, |ptr| *ptr = len)
+                }
+                let mut strides = Vec::with_capacity(num_dims as usize);
+                for _ in 0..num_dims {
+                    strides.push(reader.read_u32::<NativeEndian>()? as i32 as isize);
+                }
+                let offset = reader.read_u32::<NativeEndian>()? as i32 as isize;
+                let span_len = reader.read_u32::<NativeEndian>()? as usize;
+
+                let elt_tag = it.clone().next().expect("truncated tag");
+                let (size, span_size) = strided_sizes(elt_tag.size(), &dims, &strides, offset, span_len)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "strided array does not match its span"))?;
+                *buffer = alloc(size).await;
+                let mut span = span_storage(span_size);
+                recv_elements(reader, elt_tag, span_len, span.as_mut_ptr() as *mut (), alloc).await?;
+                let span = core::slice::from_raw_parts(span.as_ptr() as *const u8, span_size);
+                if !gather_strided(*buffer as *mut u8, span, elt_tag.size(), &dims, &strides, offset) {
+                    return Err(Error::new(ErrorKind::InvalidData, "strided array outside of its span"));
+                }
+                Ok(())
+            })
+        }
         Tag::Range(it) => {
             *data = round_up_mut(*data, tag.alignment());
             let tag = it.clone().next().expect("truncated tag");
@@ -164,3 +198,182 @@ where
 
     Ok(it.data)
 }
+
+// Element tag of values whose elements are plain data (lists and arrays of
+// bools, ints and floats); after the header, their payload can be copied
+// into kernel storage as it arrives.
+pub fn bulk_element(tag: Tag) -> Option<Tag> {
+    let elt_tag = match tag {
+        Tag::List(it) | Tag::Array(it, _) => it.clone().next().expect("truncated tag"),
+        _ => return None,
+    };
+    match elt_tag {
+        Tag::Bool | Tag::Int32 | Tag::Int64 | Tag::Float64 => Some(elt_tag),
+        _ => None,
+    }
+}
+
+pub fn bulk_header_size(tag: Tag) -> usize {
+    match tag {
+        Tag::Array(_, num_dims) => 4 * num_dims as usize,
+        _ => 4,
+    }
+}
+
+// Allocates kernel storage for a bulk value and fills in everything but its elements.
+// Returns the element storage and its size in bytes.
+pub async unsafe fn recv_bulk_header<R: ProtoRead>(
+    reader: &mut R,
+    tag: Tag<'_>,
+    data: *mut (),
+    alloc: &mut impl AsyncFnMut(usize) -> *mut (),
+) -> Result<(*mut u8, usize), Error> {
+    let elt_tag = bulk_element(tag).expect("not a bulk value");
+    match tag {
+        Tag::List(_) => {
+            #[repr(C)]
+            struct List {
+                elements: *mut (),
+                length: usize,
+            }
+            let ptr_to_list = align_ptr_mut::<*mut List>(data);
+            let length = reader.read_u32::<NativeEndian>()? as usize;
+
+            let list_size = 4 + 4;
+            let storage_offset = round_up(list_size, elt_tag.alignment());
+            let storage_size = elt_tag.size().checked_mul(length).ok_or_else(too_large)?;
+
+            let allocation_size = storage_offset.checked_add(storage_size).ok_or_else(too_large)?;
+            let allocation = alloc(allocation_size).await as *mut u8;
+            *ptr_to_list = allocation as *mut List;
+            let storage = allocation.offset(storage_offset as isize) as *mut ();
+
+            (**ptr_to_list).length = length;
+            (**ptr_to_list).elements = storage;
+            Ok((storage as *mut u8, storage_size))
+        }
+        Tag::Array(_, num_dims) => {
+            let buffer = align_ptr_mut::<*mut ()>(data);
+            let mut dims = buffer.offset(1) as *mut ();
+            let mut total_len: usize = 1;
+            for _ in 0..num_dims {
+                let len = reader.read_u32::<NativeEndian>()? as usize;
+                total_len = total_len.checked_mul(len).ok_or_else(too_large)?;
+                let ptr = align_ptr_mut::<usize>(dims);
+                *ptr = len;
+                dims = ptr.offset(1) as *mut ();
+            }
+            let size = elt_tag.size().checked_mul(total_len).ok_or_else(too_large)?;
+            *buffer = alloc(size).await;
+            Ok((*buffer as *mut u8, size))
+        }
+        _ => unreachable!(),
+    }
+}
+
+// Converts bulk elements copied in raw once all of them have arrived.
+pub unsafe fn finish_bulk(elt_size: usize, storage: *mut u8, size: usize) {
+    if size == 0 {
+        return;
+    }
+    match elt_size {
+        4 => NativeEndian::from_slice_u32(core::slice::from_raw_parts_mut(storage as *mut u32, size / 4)),
+        8 => NativeEndian::from_slice_u64(core::slice::from_raw_parts_mut(storage as *mut u64, size / 8)),
+        _ => (),
+    }
+}
+
+// Why a value could not be skipped over.
+enum Skip {
+    Truncated,
+    Overflow,
+}
+
+fn take(data: &[u8], pos: &mut usize, length: usize) -> Result<(), Skip> {
+    let end = pos.checked_add(length).ok_or(Skip::Overflow)?;
+    if end > data.len() {
+        return Err(Skip::Truncated);
+    }
+    *pos = end;
+    Ok(())
+}
+
+fn take_u32(data: &[u8], pos: &mut usize) -> Result<usize, Skip> {
+    let start = *pos;
+    take(data, pos, 4)?;
+    Ok(NativeEndian::read_u32(&data[start..*pos]) as usize)
+}
+
+fn skip_elements(data: &[u8], pos: &mut usize, elt_tag: Tag, length: usize) -> Result<(), Skip> {
+    match elt_tag {
+        Tag::Bool | Tag::Int32 | Tag::Int64 | Tag::Float64 => {
+            take(data, pos, elt_tag.size().checked_mul(length).ok_or(Skip::Overflow)?)
+        }
+        _ => {
+            for _ in 0..length {
+                skip_value(data, pos, elt_tag)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn skip_value(data: &[u8], pos: &mut usize, tag: Tag) -> Result<(), Skip> {
+    match tag {
+        Tag::None => Ok(()),
+        Tag::Bool => take(data, pos, 1),
+        Tag::Int32 => take(data, pos, 4),
+        Tag::Int64 | Tag::Float64 => take(data, pos, 8),
+        Tag::String | Tag::Bytes | Tag::ByteArray => {
+            let length = take_u32(data, pos)?;
+            take(data, pos, length)
+        }
+        Tag::Tuple(it, arity) => {
+            let mut it = it.clone();
+            for _ in 0..arity {
+                let tag = it.next().expect("truncated tag");
+                skip_value(data, pos, tag)?;
+            }
+            Ok(())
+        }
+        Tag::List(it) => {
+            let tag = it.clone().next().expect("truncated tag");
+            let length = take_u32(data, pos)?;
+            skip_elements(data, pos, tag, length)
+        }
+        Tag::Array(it, num_dims) => {
+            let mut total_len: usize = 1;
+            for _ in 0..num_dims {
+                total_len = total_len.checked_mul(take_u32(data, pos)?).ok_or(Skip::Overflow)?;
+            }
+            let elt_tag = it.clone().next().expect("truncated tag");
+            skip_elements(data, pos, elt_tag, total_len)
+        }
+        Tag::StridedArray(it, num_dims) => {
+            // lengths and strides of each dimension, then the offset of the first element
+            take(data, pos, 8 * num_dims as usize + 4)?;
+            let span_len = take_u32(data, pos)?;
+            let elt_tag = it.clone().next().expect("truncated tag");
+            skip_elements(data, pos, elt_tag, span_len)
+        }
+        Tag::Range(it) => {
+            let tag = it.clone().next().expect("truncated tag");
+            skip_value(data, pos, tag)?;
+            skip_value(data, pos, tag)?;
+            skip_value(data, pos, tag)
+        }
+        Tag::Keyword(_) => unreachable!(),
+        Tag::Object => unreachable!(),
+    }
+}
+
+// Size of the encoded value at the start of data, or None if it has not fully arrived yet.
+// Lengths that overflow are reported as InvalidData.
+pub fn value_size(data: &[u8], tag: Tag) -> Result<Option<usize>, Error> {
+    let mut pos = 0;
+    match skip_value(data, &mut pos, tag) {
+        Ok(()) => Ok(Some(pos)),
+        Err(Skip::Truncated) => Ok(None),
+        Err(Skip::Overflow) => Err(too_large()),
+    }
+}
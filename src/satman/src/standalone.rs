@@ -0,0 +1,155 @@
+use alloc::vec::Vec;
+use core::{cell::RefCell, sync::atomic::Ordering};
+
+use ksupport::kernel;
+use libboard_artiq::pl::csr;
+use libconfig;
+use log::{error, info, warn};
+
+use crate::{cache::Cache, drtiosat_reset, drtiosat_reset_phy};
+
+// Standalone mode, for a satellite run without a master. Once the uplink has been down for the
+// seconds of the `standalone_timeout` config key, the RTIO core is taken out of reset with the
+// TSC counting on the local oscillator, and the startup kernel of the satellite is run against
+// its own channels, without a host. As soon as an uplink appears the kernel is stopped and
+// the RTIO core is reset, before the link is brought up as usual.
+
+pub fn timeout_ms() -> Option<u64> {
+    let text = libconfig::read_str("standalone_timeout").ok()?;
+    match text.trim().parse::<u64>() {
+        Ok(0) => None,
+        Ok(seconds) => {
+            info!("standalone mode after {} s without uplink", seconds);
+            Some(seconds * 1000)
+        }
+        Err(_) => {
+            warn!("standalone_timeout \"{}\" not supported, ignoring", text);
+            None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    Loading,
+    Running,
+    Finished,
+}
+
+pub struct Standalone<'a> {
+    control: &'a RefCell<kernel::Control>,
+    cache: &'a RefCell<Cache>,
+    state: State,
+}
+
+impl<'a> Standalone<'a> {
+    /// Takes the RTIO core and core1, which must not be running a subkernel, and loads the
+    /// startup kernel if there is one.
+    pub async fn enter(control: &'a RefCell<kernel::Control>, cache: &'a RefCell<Cache>) -> Standalone<'a> {
+        info!("no uplink, entering standalone mode");
+        drtiosat_reset(false);
+        drtiosat_reset_phy(false);
+        let mut standalone = Standalone {
+            control: control,
+            cache: cache,
+            state: State::Finished,
+        };
+        match libconfig::read("startup_kernel") {
+            Ok(image) => {
+                control.borrow_mut().restart();
+                control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::LoadRequest(image))
+                    .await;
+                standalone.state = State::Loading;
+            }
+            Err(_) => info!("no startup kernel to run in standalone mode"),
+        }
+        standalone
+    }
+
+    fn stop(&mut self, restart: bool) {
+        if restart {
+            self.control.borrow_mut().restart();
+        }
+        unsafe {
+            csr::cri_con::selected_write(0);
+        }
+        kernel::HEADLESS.store(false, Ordering::Relaxed);
+        self.state = State::Finished;
+    }
+
+    /// Stops the kernel and resets the RTIO core, once an uplink has appeared.
+    pub fn leave(mut self) {
+        if self.state != State::Finished {
+            info!("uplink appeared, stopping the standalone kernel");
+            self.stop(true);
+        }
+        drtiosat_reset_phy(true);
+        drtiosat_reset(true);
+        info!("leaving standalone mode");
+    }
+
+    pub async fn service(&mut self) {
+        if self.state == State::Finished {
+            return;
+        }
+        let message = match self.control.borrow_mut().rx.try_recv() {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        match message {
+            kernel::Message::LoadCompleted if self.state == State::Loading => {
+                info!("running the startup kernel in standalone mode");
+                kernel::HEADLESS.store(true, Ordering::Relaxed);
+                unsafe {
+                    csr::cri_con::selected_write(2);
+                }
+                self.control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::StartRequest)
+                    .await;
+                self.state = State::Running;
+            }
+            kernel::Message::LoadFailed if self.state == State::Loading => {
+                error!("startup kernel could not be loaded");
+                self.stop(false);
+            }
+            kernel::Message::KernelFinished => {
+                info!("standalone kernel finished");
+                self.stop(false);
+            }
+            kernel::Message::KernelException(exceptions, _, backtrace) => {
+                error!("uncaught exception in standalone kernel");
+                for exception in exceptions {
+                    error!("{:?}", exception.unwrap());
+                }
+                error!("backtrace: {:?}", backtrace);
+                self.stop(false);
+            }
+            kernel::Message::RpcSend { .. } => {
+                // only async RPCs get here, there is no host to deliver them to
+                warn!("async RPC of standalone kernel dropped");
+            }
+            kernel::Message::CachePutRequest(key, value) => {
+                self.cache.borrow_mut().put(key, value);
+            }
+            kernel::Message::CacheGetRequest(key) => {
+                const DEFAULT: Vec<i32> = Vec::new();
+                let value = self.cache.borrow_mut().get(&key).unwrap_or(&DEFAULT).clone();
+                self.control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::CacheGetReply(value))
+                    .await;
+            }
+            other => {
+                // the other requests need the master, or a subkernel session to be answered
+                error!("{:?} not supported in standalone mode, stopping the kernel", other);
+                self.stop(true);
+            }
+        }
+    }
+}
@@ -1,24 +1,29 @@
-use alloc::{collections::BTreeMap,
+use alloc::{collections::{BTreeMap, BTreeSet},
             format,
             string::{String, ToString},
             vec::Vec};
-use core::{cell::RefCell, slice, str};
+use core::{cell::RefCell, cmp::min, ptr, slice, str};
 
 use byteorder::NativeEndian;
 use core_io::Error as IoError;
 use cslice::AsCSlice;
 use io::{Cursor, ProtoWrite};
-use ksupport::{eh_artiq, kernel, kernel::rtio};
+use ksupport::{eh_artiq, kernel, kernel::rtio, rpc::tag::TagIterator};
 use libasync::task;
-use libboard_artiq::{drtio_routing::RoutingTable,
+use libboard_artiq::{drtio_routing::{self, RoutingTable, mask_insert},
                      drtioaux,
-                     drtioaux_proto::{MASTER_PAYLOAD_MAX_SIZE, PayloadStatus},
-                     pl::csr};
+                     drtioaux_proto::{AUX_TIMEOUT, DESTINATION_MASK_SIZE, FEATURE_BARRIER_WITHDRAW, GRABBER_ROI_COUNT,
+                                      MASTER_PAYLOAD_MAX_SIZE, PayloadStatus, SUBKERNEL_MESSAGE_WINDOW},
+                     pl::csr, wall_clock};
+#[cfg(has_grabber)]
+use libboard_artiq::grabber;
 use libboard_zynq::timer;
 use libcortex_a9::sync_channel::Receiver;
 use log::warn;
 
-use crate::{dma::{Error as DmaError, Manager as DmaManager},
+use crate::{cache::{Cache, Status as CacheStatus},
+            dma::{Error as DmaError, Manager as DmaManager},
+            drtiosat_aux, memory_budget,
             routing::{Router, SliceMeta, Sliceable},
             rpc_async};
 
@@ -32,20 +37,27 @@ enum KernelState {
         id: u32,
         tags: Vec<u8>,
     },
+    MsgReceiving,
     MsgSending,
     SubkernelAwaitLoad,
     SubkernelAwaitFinish {
         max_time: Option<u64>,
         id: u32,
     },
+    SubkernelAwaitMany {
+        max_time: Option<u64>,
+        ids: Vec<u32>,
+    },
     DmaUploading,
     DmaPendingPlayback {
         id: u32,
         timestamp: u64,
+        selected: Option<[u8; DESTINATION_MASK_SIZE]>,
     },
     DmaPendingAwait {
         id: u32,
         timestamp: u64,
+        selected: Option<[u8; DESTINATION_MASK_SIZE]>,
         max_time: u64,
     },
     DmaAwait {
@@ -53,6 +65,13 @@ enum KernelState {
     },
     SubkernelRetrievingException {
         destination: u8,
+        seq: u16,
+        max_time: u64,
+        retries: u8,
+    },
+    BarrierWait {
+        max_time: Option<u64>,
+        id: u32,
     },
 }
 
@@ -68,6 +87,7 @@ pub enum Error {
     DrtioError,
     KernelException(Sliceable),
     DmaError(DmaError),
+    OutOfMemory,
 }
 
 impl From<IoError> for Error {
@@ -98,11 +118,44 @@ macro_rules! unexpected {
     ($($arg:tt)*) => (return Err(Error::Unexpected(format!($($arg)*))));
 }
 
+// incoming message data buffered beyond this limit is not granted any credits,
+// senders wait until the kernel consumes some of it
+const MESSAGE_BUFFER_LIMIT: usize = 256 * 1024;
+
+// a slice of a remote exception not received within the timeout is requested again,
+// the retrieval is given up after a few attempts
+const EXCEPTION_SLICE_TIMEOUT_MS: u64 = AUX_TIMEOUT;
+const EXCEPTION_SLICE_RETRIES: u8 = 5;
+
+// datasets buffered by all subkernels for the master, appends beyond this are refused
+const DATASET_BUFFER_LIMIT: usize = 1024 * 1024;
+
 /* represents interkernel messages */
 struct Message {
     count: u8,
     id: u32,
+    source: u8,
     data: Vec<u8>,
+    complete: bool,
+}
+
+/* list or array elements copied into kernel storage as they arrive */
+struct BulkTransfer {
+    storage: *mut u8,
+    size: usize,
+    received: usize,
+    elt_size: usize,
+}
+
+/* message being passed to the kernel, one value at a time;
+consumed data is dropped, so the message does not have to fit in memory at once */
+struct Delivery {
+    message: Message,
+    tags: Vec<u8>,
+    tag_offset: usize,
+    remaining: u8,
+    bulk: Option<BulkTransfer>,
+    waiting: bool,
 }
 
 #[derive(PartialEq)]
@@ -117,8 +170,12 @@ enum OutMessageState {
 struct MessageManager {
     out_message: Option<Sliceable>,
     out_state: OutMessageState,
+    out_in_flight: u8,
+    out_credits: u8,
     in_queue: Vec<Message>,
-    in_buffer: Option<Message>,
+    delivery: Option<Delivery>,
+    // senders told to stop, each waiting for a credit update
+    starved_sources: BTreeSet<u8>,
 }
 
 // Per-run state
@@ -157,14 +214,35 @@ impl Session {
 struct KernelLibrary {
     library: Vec<u8>,
     complete: bool,
+    // copy of the complete library made by core1, handed over on load
+    image: Option<Vec<u8>>,
+}
+
+impl KernelLibrary {
+    // heap taken by the library and its image
+    fn memory(&self) -> usize {
+        self.library.capacity() + self.image.as_ref().map_or(0, Vec::capacity)
+    }
+}
+
+// work handed to core1 while it has no kernel running
+#[derive(Clone, Copy, PartialEq)]
+enum Offload {
+    // a memory move completed by its owner, e.g. a DMA trace realignment
+    Copy,
+    // the image of a subkernel library
+    Image(u32),
 }
 
 pub struct Manager<'a> {
     kernels: BTreeMap<u32, KernelLibrary>,
     session: Session,
     control: &'a RefCell<kernel::Control>,
-    cache: BTreeMap<String, Vec<i32>>,
+    cache: &'a RefCell<Cache>,
     last_finished: Option<SubkernelFinished>,
+    offload: Option<Offload>,
+    // named datasets left by each subkernel, kept until it runs again
+    datasets: BTreeMap<u32, BTreeMap<String, Vec<u8>>>,
 }
 
 pub struct SubkernelFinished {
@@ -179,38 +257,91 @@ impl MessageManager {
         MessageManager {
             out_message: None,
             out_state: OutMessageState::NoMessage,
+            out_in_flight: 0,
+            out_credits: 0,
             in_queue: Vec::new(),
-            in_buffer: None,
+            delivery: None,
+            starved_sources: BTreeSet::new(),
         }
     }
 
     pub fn handle_incoming(
         &mut self,
+        source: u8,
         status: PayloadStatus,
         id: u32,
         length: usize,
         data: &[u8; MASTER_PAYLOAD_MAX_SIZE],
-    ) {
-        // called when receiving a message from master
+    ) -> u8 {
+        // called when receiving a message from master or another satellite,
+        // returns the credits granted to the sender
         if status.is_first() {
-            self.in_buffer = None;
-        }
-        match self.in_buffer.as_mut() {
-            Some(message) => message.data.extend(&data[..length]),
-            None => {
-                self.in_buffer = Some(Message {
-                    count: data[0],
-                    id: id,
-                    data: data[1..length].to_vec(),
-                });
+            // a sender has one message in flight at a time, a partial one left from it is stale
+            self.in_queue.retain(|message| message.complete || message.source != source);
+            self.in_queue.push(Message {
+                count: data[0],
+                id: id,
+                source: source,
+                data: data[1..length].to_vec(),
+                complete: status.is_last(),
+            });
+        } else {
+            let message = match self.delivery.as_mut() {
+                Some(delivery)
+                    if !delivery.message.complete
+                        && delivery.message.source == source
+                        && delivery.message.id == id =>
+                {
+                    Some(&mut delivery.message)
+                }
+                _ => self
+                    .in_queue
+                    .iter_mut()
+                    .find(|message| !message.complete && message.source == source && message.id == id),
+            };
+            match message {
+                Some(message) => {
+                    message.data.extend(&data[..length]);
+                    message.complete = status.is_last();
+                }
+                None => warn!("received a slice of an unknown message #{} from {}", id, source),
             }
-        };
-        if status.is_last() {
-            // when done, remove from working queue
-            self.in_queue.push(self.in_buffer.take().unwrap());
+        }
+        let credits = self.credits();
+        if credits == 0 {
+            self.starved_sources.insert(source);
+        }
+        credits
+    }
+
+    fn buffered(&self) -> usize {
+        let queued: usize = self.in_queue.iter().map(|message| message.data.len()).sum();
+        queued + self.delivery.as_ref().map_or(0, |delivery| delivery.message.data.len())
+    }
+
+    fn credits(&self) -> u8 {
+        let free = MESSAGE_BUFFER_LIMIT.saturating_sub(self.buffered());
+        let credits = min(free / MASTER_PAYLOAD_MAX_SIZE, SUBKERNEL_MESSAGE_WINDOW as usize) as u8;
+        // the kernel is waiting for data, e.g. a value that cannot be streamed,
+        // which has to arrive in whole regardless of the limit
+        if credits == 0 && self.delivery.as_ref().map_or(false, |delivery| delivery.waiting) {
+            1
+        } else {
+            credits
         }
     }
 
+    pub fn pending_credit(&mut self) -> Option<(u8, u8)> {
+        // credit update for a sender that was told to stop, once there is room again;
+        // called until it returns None, as several senders may be waiting
+        let credits = self.credits();
+        let waiting = self.delivery.as_ref().map_or(false, |delivery| delivery.waiting);
+        if credits == 0 || (credits < SUBKERNEL_MESSAGE_WINDOW / 2 && !waiting) {
+            return None;
+        }
+        self.starved_sources.pop_first().map(|source| (source, credits))
+    }
+
     pub fn was_message_acknowledged(&mut self) -> bool {
         match self.out_state {
             OutMessageState::MessageAcknowledged => {
@@ -222,10 +353,11 @@ impl MessageManager {
     }
 
     pub fn get_outgoing_slice(&mut self, data_slice: &mut [u8; MASTER_PAYLOAD_MAX_SIZE]) -> Option<SliceMeta> {
-        if self.out_state != OutMessageState::MessageBeingSent {
+        if self.out_state != OutMessageState::MessageBeingSent || self.out_in_flight >= self.out_credits {
             return None;
         }
         let meta = self.out_message.as_mut()?.get_slice_master(data_slice);
+        self.out_in_flight += 1;
         if meta.status.is_last() {
             // clear the message slot
             self.out_message = None;
@@ -235,12 +367,19 @@ impl MessageManager {
         Some(meta)
     }
 
-    pub fn ack_slice(&mut self) -> bool {
+    pub fn ack_slice(&mut self, credits: u8) -> bool {
         // returns whether or not there's more to be sent
         match self.out_state {
-            OutMessageState::MessageBeingSent => true,
+            OutMessageState::MessageBeingSent => {
+                self.out_in_flight = self.out_in_flight.saturating_sub(1);
+                self.out_credits = credits;
+                true
+            }
             OutMessageState::MessageSent => {
-                self.out_state = OutMessageState::MessageAcknowledged;
+                self.out_in_flight = self.out_in_flight.saturating_sub(1);
+                if self.out_in_flight == 0 {
+                    self.out_state = OutMessageState::MessageAcknowledged;
+                }
                 false
             }
             _ => {
@@ -250,6 +389,15 @@ impl MessageManager {
         }
     }
 
+    pub fn update_credits(&mut self, credits: u8) -> bool {
+        // returns whether or not sending can resume
+        if self.out_state != OutMessageState::MessageBeingSent {
+            return false;
+        }
+        self.out_credits = credits;
+        true
+    }
+
     pub fn accept_outgoing(
         &mut self,
         id: u32,
@@ -264,6 +412,9 @@ impl MessageManager {
 
         let mut data_slice: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
         self.out_state = OutMessageState::MessageBeingSent;
+        // the first slice goes alone, the receiver's ACK tells how many may follow
+        self.out_in_flight = 0;
+        self.out_credits = 1;
         let meta = self.get_outgoing_slice(&mut data_slice).unwrap();
         router.route(
             drtioaux::Packet::SubkernelMessage {
@@ -281,28 +432,136 @@ impl MessageManager {
         Ok(())
     }
 
-    pub fn get_incoming(&mut self, id: u32) -> Option<Message> {
-        for i in 0..self.in_queue.len() {
-            if self.in_queue[i].id == id {
-                return Some(self.in_queue.remove(i));
-            }
-        }
-        None
+    pub fn start_delivery(&mut self, id: u32, tags: Vec<u8>) -> Option<u8> {
+        // messages can be passed on as soon as their first slice has arrived
+        let i = self.in_queue.iter().position(|message| message.id == id)?;
+        let message = self.in_queue.remove(i);
+        let count = message.count;
+        self.delivery = Some(Delivery {
+            message: message,
+            tags: tags,
+            tag_offset: 0,
+            remaining: count,
+            bulk: None,
+            waiting: false,
+        });
+        Some(count)
     }
 }
 
 impl<'a> Manager<'a> {
-    pub fn new(control: &RefCell<kernel::Control>) -> Manager {
+    pub fn new(control: &'a RefCell<kernel::Control>, cache: &'a RefCell<Cache>) -> Manager<'a> {
         Manager {
             kernels: BTreeMap::new(),
             session: Session::new(0),
             control: control,
-            cache: BTreeMap::new(),
+            cache: cache,
             last_finished: None,
+            offload: None,
+            datasets: BTreeMap::new(),
+        }
+    }
+
+    /// Drops all subkernels and the session state, when the master starts a new session.
+    pub fn flush(&mut self) {
+        self.offload_done(true);
+        if self.running() {
+            self.control.borrow_mut().restart();
+        }
+        *self = Manager::new(self.control, self.cache);
+    }
+
+    /// Forgets the kernel loaded on core1 before standalone mode takes it over, keeping the subkernels.
+    pub fn release_core1(&mut self) {
+        self.offload_done(true);
+        self.session = Session::new(0);
+    }
+
+    /// Hands a memory move to core1, keeping core0 free for aux traffic: the realignment of a
+    /// completed DMA trace, and the image of a completed subkernel library. Analyzer data is
+    /// copied into each aux packet as it is sent and is not moved as a whole.
+    /// Returns false if core1 is busy with a kernel or another move.
+    pub fn offload_copy(&mut self, src: *const u8, dst: *mut u8, len: usize) -> bool {
+        self.start_offload(Offload::Copy, src, dst, len)
+    }
+
+    fn start_offload(&mut self, offload: Offload, src: *const u8, dst: *mut u8, len: usize) -> bool {
+        if self.running() || self.offload.is_some() {
+            return false;
+        }
+        self.control
+            .borrow_mut()
+            .tx
+            .send(kernel::Message::BulkCopyRequest { src, dst, len });
+        self.offload = Some(offload);
+        true
+    }
+
+    /// Checks if the offloaded move has finished, blocking until it does if `wait` is set.
+    pub fn offload_done(&mut self, wait: bool) -> bool {
+        let offload = match self.offload {
+            Some(offload) => offload,
+            None => return true,
+        };
+        let reply = if wait {
+            Some(self.control.borrow_mut().rx.recv())
+        } else {
+            self.control.borrow_mut().rx.try_recv().ok()
+        };
+        match reply {
+            Some(kernel::Message::BulkCopyCompleted) => (),
+            Some(other) => warn!("unexpected kernel CPU reply to bulk copy: {:?}", other),
+            None => return false,
+        }
+        if let Offload::Image(id) = offload {
+            if let Some(kernel) = self.kernels.get_mut(&id) {
+                let len = kernel.library.len();
+                if let Some(image) = kernel.image.as_mut() {
+                    unsafe { image.set_len(len) };
+                }
+            }
+        }
+        self.offload = None;
+        true
+    }
+
+    // Has core1 copy a complete library into the image handed over on load, if it is free
+    // and the copy fits in the subkernel memory budget, so that core0 does not copy it while
+    // the master waits for the load.
+    fn prepare_image(&mut self, id: u32) {
+        if self.running() || self.offload.is_some() {
+            return;
+        }
+        let used: usize = self.kernels.values().map(KernelLibrary::memory).sum();
+        let kernel = match self.kernels.get_mut(&id) {
+            Some(kernel) => kernel,
+            None => return,
+        };
+        let len = kernel.library.len();
+        let mut image = Vec::new();
+        if used + len > memory_budget::subkernels() || image.try_reserve_exact(len).is_err() {
+            return;
+        }
+        let src = kernel.library.as_ptr();
+        let dst = image.as_mut_ptr();
+        // the image is only set to its length once core1 is done
+        kernel.image = Some(image);
+        if !self.start_offload(Offload::Image(id), src, dst, len) {
+            self.kernels.get_mut(&id).unwrap().image = None;
         }
     }
 
     pub fn add(&mut self, id: u32, status: PayloadStatus, data: &[u8], data_len: usize) -> Result<(), Error> {
+        if self.offload == Some(Offload::Image(id)) {
+            // core1 is still copying the library that is about to be replaced
+            self.offload_done(true);
+        }
+        let used: usize = self
+            .kernels
+            .iter()
+            .filter(|(&kernel_id, _)| kernel_id != id)
+            .map(|(_, kernel)| kernel.memory())
+            .sum();
         let kernel = match self.kernels.get_mut(&id) {
             Some(kernel) => {
                 if kernel.complete || status.is_first() {
@@ -313,6 +572,7 @@ impl<'a> Manager<'a> {
                         KernelLibrary {
                             library: Vec::new(),
                             complete: false,
+                            image: None,
                         },
                     );
                     self.kernels.get_mut(&id).ok_or_else(|| Error::KernelNotFound)?
@@ -326,14 +586,25 @@ impl<'a> Manager<'a> {
                     KernelLibrary {
                         library: Vec::new(),
                         complete: false,
+                        image: None,
                     },
                 );
                 self.kernels.get_mut(&id).ok_or_else(|| Error::KernelNotFound)?
             }
         };
+        if used + kernel.library.len() + data_len > memory_budget::subkernels()
+            || kernel.library.try_reserve(data_len).is_err()
+        {
+            error!("subkernel {} refused, it does not fit in the subkernel memory budget", id);
+            self.kernels.remove(&id);
+            return Err(Error::OutOfMemory);
+        }
         kernel.library.extend(&data[0..data_len]);
 
         kernel.complete = status.is_last();
+        if kernel.complete {
+            self.prepare_image(id);
+        }
         Ok(())
     }
 
@@ -341,6 +612,15 @@ impl<'a> Manager<'a> {
         self.session.running()
     }
 
+    pub fn cache_status(&self, clear: bool) -> CacheStatus {
+        let mut cache = self.cache.borrow_mut();
+        let status = cache.status();
+        if clear {
+            cache.clear();
+        }
+        status
+    }
+
     pub fn get_current_id(&self) -> Option<u32> {
         match self.running() {
             true => Some(self.session.id),
@@ -349,11 +629,14 @@ impl<'a> Manager<'a> {
     }
 
     pub async fn run(&mut self, source: u8, id: u32, timestamp: u64) -> Result<(), Error> {
+        // core1 must be done with any offloaded work before it takes the kernel
+        self.offload_done(true);
         if self.session.kernel_state != KernelState::Loaded || self.session.id != id {
             self.load(id).await?;
         }
         self.session.kernel_state = KernelState::Running;
         self.session.source = source;
+        self.datasets.remove(&id);
         unsafe {
             csr::cri_con::selected_write(2);
         }
@@ -369,15 +652,17 @@ impl<'a> Manager<'a> {
 
     pub fn message_handle_incoming(
         &mut self,
+        source: u8,
         status: PayloadStatus,
         id: u32,
         length: usize,
         slice: &[u8; MASTER_PAYLOAD_MAX_SIZE],
-    ) {
+    ) -> u8 {
         if !self.running() {
-            return;
+            // message is dropped, do not hold the sender back
+            return SUBKERNEL_MESSAGE_WINDOW;
         }
-        self.session.messages.handle_incoming(status, id, length, slice);
+        self.session.messages.handle_incoming(source, status, id, length, slice)
     }
 
     pub fn message_get_slice(&mut self, slice: &mut [u8; MASTER_PAYLOAD_MAX_SIZE]) -> Option<SliceMeta> {
@@ -387,12 +672,19 @@ impl<'a> Manager<'a> {
         self.session.messages.get_outgoing_slice(slice)
     }
 
-    pub fn message_ack_slice(&mut self) -> bool {
+    pub fn message_ack_slice(&mut self, credits: u8) -> bool {
         if !self.running() {
             warn!("received unsolicited SubkernelMessageAck");
             return false;
         }
-        self.session.messages.ack_slice()
+        self.session.messages.ack_slice(credits)
+    }
+
+    pub fn message_update_credits(&mut self, credits: u8) -> bool {
+        if !self.running() {
+            return false;
+        }
+        self.session.messages.update_credits(credits)
     }
 
     pub async fn load(&mut self, id: u32) -> Result<(), Error> {
@@ -402,19 +694,17 @@ impl<'a> Manager<'a> {
         if !self.kernels.get(&id).ok_or_else(|| Error::KernelNotFound)?.complete {
             return Err(Error::KernelNotFound);
         }
+        self.offload_done(true);
         self.session = Session::new(id);
         self.control.borrow_mut().restart();
 
+        // the library is kept for later loads, core1 takes a copy of it
+        let kernel = self.kernels.get_mut(&id).ok_or_else(|| Error::KernelNotFound)?;
+        let image = kernel.image.take().unwrap_or_else(|| kernel.library.clone());
         self.control
             .borrow_mut()
             .tx
-            .async_send(kernel::Message::LoadRequest(
-                self.kernels
-                    .get(&id)
-                    .ok_or_else(|| Error::KernelNotFound)?
-                    .library
-                    .clone(),
-            ))
+            .async_send(kernel::Message::LoadRequest(image))
             .await;
         let reply = self.control.borrow_mut().rx.recv();
         match reply {
@@ -427,9 +717,13 @@ impl<'a> Manager<'a> {
         }
     }
 
-    pub fn exception_get_slice(&mut self, data_slice: &mut [u8; MASTER_PAYLOAD_MAX_SIZE]) -> SliceMeta {
+    pub fn exception_get_slice(&mut self, seq: u16, data_slice: &mut [u8; MASTER_PAYLOAD_MAX_SIZE]) -> SliceMeta {
         match self.session.last_exception.as_mut() {
-            Some(exception) => exception.get_slice_master(data_slice),
+            Some(exception) => {
+                // slices are requested by sequence number, so one can be sent again if lost
+                exception.seek(seq as usize * MASTER_PAYLOAD_MAX_SIZE);
+                exception.get_slice_master(data_slice)
+            }
             None => SliceMeta {
                 destination: 0,
                 len: 0,
@@ -438,6 +732,46 @@ impl<'a> Manager<'a> {
         }
     }
 
+    fn dataset_append(&mut self, name: String, data: &[u8]) -> bool {
+        let used: usize = self
+            .datasets
+            .values()
+            .flat_map(|datasets| datasets.values())
+            .map(Vec::len)
+            .sum();
+        if used + data.len() > DATASET_BUFFER_LIMIT {
+            error!("dataset \"{}\" of subkernel {} refused, the dataset buffer is full", name, self.session.id);
+            return false;
+        }
+        let dataset = self
+            .datasets
+            .entry(self.session.id)
+            .or_default()
+            .entry(name)
+            .or_default();
+        if dataset.try_reserve(data.len()).is_err() {
+            return false;
+        }
+        dataset.extend_from_slice(data);
+        true
+    }
+
+    /// Copies the slice `seq` of a buffered dataset, returning its length and whether it is the last,
+    /// or None if subkernel `id` did not leave a dataset of that name.
+    pub fn dataset_get_slice(
+        &self,
+        id: u32,
+        name: &str,
+        seq: u16,
+        data_slice: &mut [u8; MASTER_PAYLOAD_MAX_SIZE],
+    ) -> Option<(u16, bool)> {
+        let dataset = self.datasets.get(&id)?.get(name)?;
+        let start = min(seq as usize * MASTER_PAYLOAD_MAX_SIZE, dataset.len());
+        let len = min(dataset.len() - start, MASTER_PAYLOAD_MAX_SIZE);
+        data_slice[..len].copy_from_slice(&dataset[start..start + len]);
+        Some((len as u16, start + len == dataset.len()))
+    }
+
     fn kernel_stop(&mut self) {
         self.session.kernel_state = KernelState::Absent;
         unsafe {
@@ -472,7 +806,7 @@ impl<'a> Manager<'a> {
         self.kernel_stop();
     }
 
-    pub async fn ddma_finished(&mut self, error: u8, channel: u32, timestamp: u64) {
+    pub async fn ddma_finished(&mut self, error: u8, channel: u32, timestamp: u64, stat: kernel::DmaPlaybackStat) {
         if let KernelState::DmaAwait { .. } = self.session.kernel_state {
             self.control
                 .borrow_mut()
@@ -482,6 +816,7 @@ impl<'a> Manager<'a> {
                     error: error,
                     channel: channel,
                     timestamp: timestamp,
+                    stat: stat,
                 })
                 .await;
             self.session.kernel_state = KernelState::Running;
@@ -499,30 +834,32 @@ impl<'a> Manager<'a> {
                     error: 0,
                     channel: 0,
                     timestamp: 0,
+                    stat: kernel::DmaPlaybackStat::default(),
                 })
                 .await;
             self.session.kernel_state = KernelState::Running;
         }
     }
 
-    pub fn ddma_remote_uploaded(&mut self, succeeded: bool) -> Option<(u32, u64)> {
-        // returns a tuple of id, timestamp in case a playback needs to be started immediately
+    pub fn ddma_remote_uploaded(&mut self, succeeded: bool) -> Option<(u32, u64, Option<[u8; DESTINATION_MASK_SIZE]>)> {
+        // returns the id, timestamp and selected destinations in case a playback needs to be started immediately
         if !succeeded {
             self.kernel_stop();
             self.runtime_exception(Error::DmaError(DmaError::UploadFail));
         }
         let res = match self.session.kernel_state {
-            KernelState::DmaPendingPlayback { id, timestamp } => {
+            KernelState::DmaPendingPlayback { id, timestamp, selected } => {
                 self.session.kernel_state = KernelState::Running;
-                Some((id, timestamp))
+                Some((id, timestamp, selected))
             }
             KernelState::DmaPendingAwait {
                 id,
                 timestamp,
+                selected,
                 max_time,
             } => {
                 self.session.kernel_state = KernelState::DmaAwait { max_time: max_time };
-                Some((id, timestamp))
+                Some((id, timestamp, selected))
             }
             KernelState::DmaUploading => {
                 self.session.kernel_state = KernelState::Running;
@@ -648,16 +985,11 @@ impl<'a> Manager<'a> {
                     self.session.external_exception = Some(Vec::new());
                     self.session.kernel_state = KernelState::SubkernelRetrievingException {
                         destination: destination,
+                        seq: 0,
+                        max_time: timer::get_ms() + EXCEPTION_SLICE_TIMEOUT_MS,
+                        retries: 0,
                     };
-                    router.route(
-                        drtioaux::Packet::SubkernelExceptionRequest {
-                            source: self_destination,
-                            destination: destination,
-                        },
-                        &routing_table,
-                        rank,
-                        self_destination,
-                    );
+                    request_exception_slice(destination, 0, router, routing_table, rank, self_destination);
                 }
                 break;
             }
@@ -676,6 +1008,20 @@ impl<'a> Manager<'a> {
         }
     }
 
+    pub fn barrier_released(&mut self, id: u32, succeeded: bool) {
+        match self.session.kernel_state {
+            KernelState::BarrierWait { id: waiting_id, .. } if waiting_id == id => {
+                self.control.borrow_mut().tx.send(if succeeded {
+                    kernel::Message::SubkernelBarrierReply
+                } else {
+                    kernel::Message::SubkernelError(kernel::SubkernelStatus::OtherError)
+                });
+                self.session.kernel_state = KernelState::Running;
+            }
+            _ => warn!("received unsolicited release of barrier #{}", id),
+        }
+    }
+
     pub fn remote_subkernel_finished(&mut self, id: u32, with_exception: bool, exception_source: u8) {
         let exception_src = if with_exception { Some(exception_source) } else { None };
         self.session.subkernels_finished.push((id, exception_src));
@@ -683,6 +1029,7 @@ impl<'a> Manager<'a> {
 
     pub fn received_exception(
         &mut self,
+        seq: u16,
         exception_data: &[u8],
         last: bool,
         router: &mut Router,
@@ -690,7 +1037,16 @@ impl<'a> Manager<'a> {
         rank: u8,
         self_destination: u8,
     ) {
-        if let KernelState::SubkernelRetrievingException { destination } = self.session.kernel_state {
+        if let KernelState::SubkernelRetrievingException {
+            destination,
+            seq: expected_seq,
+            ..
+        } = self.session.kernel_state
+        {
+            if seq != expected_seq {
+                // a late reply to a request that was already repeated
+                return;
+            }
             self.session
                 .external_exception
                 .as_mut()
@@ -706,15 +1062,13 @@ impl<'a> Manager<'a> {
                 self.session.kernel_state = KernelState::Running;
             } else {
                 /* fetch another slice */
-                router.route(
-                    drtioaux::Packet::SubkernelExceptionRequest {
-                        source: self_destination,
-                        destination: destination,
-                    },
-                    routing_table,
-                    rank,
-                    self_destination,
-                );
+                self.session.kernel_state = KernelState::SubkernelRetrievingException {
+                    destination: destination,
+                    seq: seq + 1,
+                    max_time: timer::get_ms() + EXCEPTION_SLICE_TIMEOUT_MS,
+                    retries: 0,
+                };
+                request_exception_slice(destination, seq + 1, router, routing_table, rank, self_destination);
             }
         } else {
             warn!("Received unsolicited exception data");
@@ -753,11 +1107,87 @@ impl<'a> Manager<'a> {
                 return Err(Error::KernelException(Sliceable::new(0, writer.into_inner())));
             }
             kernel::Message::CachePutRequest(key, value) => {
-                self.cache.insert(key, value);
+                self.cache.borrow_mut().put(key, value);
+            }
+            kernel::Message::StoredKernelRequest(_) => {
+                // kernels stored on the master's SD card cannot be chained on satellites
+                self.control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::StoredKernelReply(false))
+                    .await;
+            }
+            kernel::Message::ShmMapRequest { .. } => {
+                // shared memory regions are only kept by the master
+                self.control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::ShmMapReply {
+                        data: ptr::null_mut(),
+                        length: 0,
+                    })
+                    .await;
+            }
+            kernel::Message::WallClockRequest => {
+                self.control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::WallClockReply(wall_clock::get()))
+                    .await;
+            }
+            /* as with RTIO counters, only the config of this satellite is available */
+            kernel::Message::StorageStatsRequest { destination, keys } => {
+                let stats = if destination == self_destination {
+                    Some(kernel::StorageStats::local(&keys))
+                } else {
+                    warn!("config usage of destination {} not available to the subkernel", destination);
+                    None
+                };
+                self.control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::StorageStatsReply(stats))
+                    .await;
+            }
+            kernel::Message::DestinationGroupRequest(name) => {
+                // groups are read from the configuration of the satellite
+                self.control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::DestinationGroupReply(drtio_routing::destination_group(&name)))
+                    .await;
+            }
+            kernel::Message::SubkernelDatasetAppendRequest { name, data } => {
+                let succeeded = self.dataset_append(name, &data);
+                self.control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::SubkernelDatasetAppendReply { succeeded: succeeded })
+                    .await;
+            }
+            /* datasets are fetched by the master only */
+            kernel::Message::SubkernelDatasetFetchRequest { name, .. } => {
+                warn!("dataset \"{}\" not fetched, only the master can fetch subkernel datasets", name);
+                self.control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::SubkernelError(kernel::SubkernelStatus::OtherError))
+                    .await;
+            }
+            /* the mailbox is kept by the master, for the kernels that run there */
+            kernel::Message::MailboxPutRequest(name, _) => {
+                warn!("mailbox slot \"{}\" not available to subkernels, message dropped", name);
+            }
+            kernel::Message::MailboxGetRequest(_) => {
+                self.control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::MailboxGetReply(None))
+                    .await;
             }
             kernel::Message::CacheGetRequest(key) => {
                 const DEFAULT: Vec<i32> = Vec::new();
-                let value = self.cache.get(&key).unwrap_or(&DEFAULT).clone();
+                let value = self.cache.borrow_mut().get(&key).unwrap_or(&DEFAULT).clone();
                 self.control
                     .borrow_mut()
                     .tx
@@ -766,12 +1196,20 @@ impl<'a> Manager<'a> {
             }
 
             kernel::Message::DmaPutRequest(recorder) => {
-                // ddma is always used on satellites
-                if let Ok(id) = dma_manager.put_record(recorder, self_destination) {
-                    dma_manager.upload_traces(id, router, rank, self_destination, routing_table)?;
-                    self.session.kernel_state = KernelState::DmaUploading;
-                } else {
-                    unexpected!("DMAError: found an unsupported call to RTIO devices on master")
+                // ddma is always used on satellites, the events of this satellite are kept here
+                // and played back by its own DMA core, without going through the master
+                match dma_manager.put_record(recorder, self_destination) {
+                    Ok(id) => {
+                        let uploads = dma_manager.upload_traces(id, router, rank, self_destination, routing_table)?;
+                        // a trace with local events only is ready at once, no upload is acked for it
+                        if uploads > 0 {
+                            self.session.kernel_state = KernelState::DmaUploading;
+                        }
+                    }
+                    Err(DmaError::OutOfMemory) => {
+                        unexpected!("DMAError: the recording does not fit in the DMA memory budget")
+                    }
+                    Err(_) => unexpected!("DMAError: found an unsupported call to RTIO devices on master"),
                 }
             }
             kernel::Message::DmaEraseRequest(name) => {
@@ -785,11 +1223,20 @@ impl<'a> Manager<'a> {
                     .async_send(kernel::Message::DmaGetReply(dma_meta))
                     .await;
             }
-            kernel::Message::DmaStartRemoteRequest { id, timestamp } => {
+            kernel::Message::DmaStatRequest(name) => {
+                let stat = dma_manager.stat(self_destination, &name);
+                self.control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::DmaStatReply(stat))
+                    .await;
+            }
+            kernel::Message::DmaStartRemoteRequest { id, timestamp, targets } => {
                 if self.session.kernel_state != KernelState::DmaUploading {
                     dma_manager.playback_remote(
                         id as u32,
                         timestamp as u64,
+                        targets,
                         router,
                         rank,
                         self_destination,
@@ -799,20 +1246,26 @@ impl<'a> Manager<'a> {
                     self.session.kernel_state = KernelState::DmaPendingPlayback {
                         id: id as u32,
                         timestamp: timestamp as u64,
+                        selected: targets,
                     };
                 }
             }
-            kernel::Message::DmaAwaitRemoteRequest(_id) => {
+            kernel::Message::DmaAwaitRemoteRequest(id) => {
                 let max_time = timer::get_ms() + 10000;
                 self.session.kernel_state = match self.session.kernel_state {
                     // if we are still waiting for the traces to be uploaded, extend the state by timeout
-                    KernelState::DmaPendingPlayback { id, timestamp } => KernelState::DmaPendingAwait {
+                    KernelState::DmaPendingPlayback { id, timestamp, selected } => KernelState::DmaPendingAwait {
                         id: id,
                         timestamp: timestamp,
+                        selected: selected,
                         max_time: max_time,
                     },
                     _ => KernelState::DmaAwait { max_time: max_time },
                 };
+                // no destination of the group holds a part of the trace
+                if !dma_manager.remote_playback_running(id as u32) {
+                    self.ddma_finished(0, 0, 0, kernel::DmaPlaybackStat::default()).await;
+                }
             }
 
             kernel::Message::SubkernelMsgSend {
@@ -850,6 +1303,7 @@ impl<'a> Manager<'a> {
                 destination: sk_destination,
                 run,
                 timestamp,
+                relative,
             } => {
                 self.session.kernel_state = KernelState::SubkernelAwaitLoad;
                 router.route(
@@ -859,6 +1313,7 @@ impl<'a> Manager<'a> {
                         id: id,
                         run: run,
                         timestamp,
+                        relative: relative,
                     },
                     routing_table,
                     rank,
@@ -877,6 +1332,49 @@ impl<'a> Manager<'a> {
                     id: id,
                 };
             }
+            kernel::Message::SubkernelAwaitManyRequest { ids, timeout } => {
+                let max_time = if timeout > 0 {
+                    Some(timer::get_ms() + timeout as u64)
+                } else {
+                    None
+                };
+                self.session.kernel_state = KernelState::SubkernelAwaitMany {
+                    max_time: max_time,
+                    ids: ids,
+                };
+            }
+            kernel::Message::SubkernelBarrierRequest {
+                id,
+                participants,
+                timeout,
+            } => {
+                let mut mask = [0; DESTINATION_MASK_SIZE];
+                for destination in participants {
+                    mask_insert(&mut mask, destination);
+                }
+                // the master aggregates all barriers
+                let master_destination = routing_table.master_destination().unwrap_or(0);
+                router.route(
+                    drtioaux::Packet::SubkernelBarrierArrive {
+                        source: self_destination,
+                        destination: master_destination,
+                        id: id,
+                        participants: mask,
+                    },
+                    routing_table,
+                    rank,
+                    self_destination,
+                );
+                let max_time = if timeout > 0 {
+                    Some(timer::get_ms() + timeout as u64)
+                } else {
+                    None
+                };
+                self.session.kernel_state = KernelState::BarrierWait {
+                    max_time: max_time,
+                    id: id,
+                };
+            }
             kernel::Message::UpDestinationsRequest(destination) => {
                 self.control
                     .borrow_mut()
@@ -886,8 +1384,78 @@ impl<'a> Manager<'a> {
                     ))
                     .await;
             }
+            /* only the local counter is available to subkernels */
+            kernel::Message::RtioCounterRequest(destination) => {
+                let counter = if destination == (self_destination as i32) {
+                    unsafe {
+                        csr::rtio::counter_update_write(1);
+                        Some(csr::rtio::counter_read() as i64)
+                    }
+                } else {
+                    None
+                };
+                self.control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::RtioCounterReply(counter))
+                    .await;
+            }
+            /* as with RTIO counters, subkernels read the grabbers of their own satellite only */
+            kernel::Message::GrabberRoiRequest {
+                destination: grabber_destination,
+                grabber: _grabber,
+            } => {
+                #[cfg(has_grabber)]
+                let roi_counts = if grabber_destination == self_destination {
+                    grabber::roi_counts(_grabber as usize)
+                } else {
+                    None
+                };
+                #[cfg(not(has_grabber))]
+                let roi_counts = None;
+                if roi_counts.is_none() {
+                    warn!(
+                        "ROI counts of grabber {} of destination {} not available to the subkernel",
+                        _grabber, grabber_destination
+                    );
+                }
+                let reply = match roi_counts {
+                    Some((frame, counts)) => kernel::Message::GrabberRoiReply {
+                        succeeded: true,
+                        frame: frame,
+                        counts: counts,
+                    },
+                    None => kernel::Message::GrabberRoiReply {
+                        succeeded: false,
+                        frame: 0,
+                        counts: [0; GRABBER_ROI_COUNT],
+                    },
+                };
+                self.control.borrow_mut().tx.async_send(reply).await;
+            }
+            /* only the local channels can be overridden by subkernels */
+            kernel::Message::InjectionRequest { channel, overrd, value } => {
+                let done = (channel >> 16) as u8 == self_destination && inject(channel, overrd, value);
+                self.control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::InjectionReply(done))
+                    .await;
+            }
+            kernel::Message::InjectionStatusRequest { channel, overrd } => {
+                let value = if (channel >> 16) as u8 == self_destination {
+                    read_injection_status(channel, overrd)
+                } else {
+                    None
+                };
+                self.control
+                    .borrow_mut()
+                    .tx
+                    .async_send(kernel::Message::InjectionStatusReply(value))
+                    .await;
+            }
             /* core.reset() on satellites only affects the satellite, ignore the request */
-            kernel::Message::RtioInitRequest => {
+            kernel::Message::RtioInitRequest(_) => {
                 self.control
                     .borrow_mut()
                     .tx
@@ -920,14 +1488,14 @@ impl<'a> Manager<'a> {
                         return Ok(());
                     }
                 }
-                if let Some(message) = self.session.messages.get_incoming(*id) {
+                if let Some(count) = self.session.messages.start_delivery(*id, tags.clone()) {
                     self.control
                         .borrow_mut()
                         .tx
-                        .send(kernel::Message::SubkernelMsgRecvReply { count: message.count });
-                    let tags = tags.clone();
-                    self.session.kernel_state = KernelState::Running;
-                    self.pass_message_to_kernel(&message, tags).await
+                        .send(kernel::Message::SubkernelMsgRecvReply { count: count });
+                    self.session.kernel_state = KernelState::MsgReceiving;
+                    self.pass_message_to_kernel(router, routing_table, rank, self_destination)
+                        .await
                 } else {
                     let id = *id;
                     self.check_finished_kernels(id, router, routing_table, rank, self_destination)
@@ -935,6 +1503,10 @@ impl<'a> Manager<'a> {
                     Err(Error::AwaitingMessage)
                 }
             }
+            KernelState::MsgReceiving => {
+                self.pass_message_to_kernel(router, routing_table, rank, self_destination)
+                    .await
+            }
             KernelState::MsgSending => {
                 if self.session.messages.was_message_acknowledged() {
                     self.session.kernel_state = KernelState::Running;
@@ -964,7 +1536,116 @@ impl<'a> Manager<'a> {
                     .await;
                 Ok(())
             }
-            KernelState::SubkernelRetrievingException { .. } => Err(Error::AwaitingMessage),
+            KernelState::SubkernelAwaitMany { max_time, ids } => {
+                let (max_time, ids) = (*max_time, ids.clone());
+                let finished = &self.session.subkernels_finished;
+                let all_finished = ids
+                    .iter()
+                    .all(|id| finished.iter().any(|(finished_id, _)| finished_id == id));
+                let timed_out = max_time.map_or(false, |max_time| timer::get_ms() > max_time);
+                if !all_finished && !timed_out {
+                    return Ok(());
+                }
+                // the first exception is raised in the kernel in place of the statuses
+                let exception_source = ids.iter().find_map(|id| {
+                    finished
+                        .iter()
+                        .find(|(finished_id, _)| finished_id == id)
+                        .and_then(|(_, exception_source)| *exception_source)
+                });
+                let mut statuses = Vec::with_capacity(ids.len());
+                for id in ids.iter() {
+                    let position = self
+                        .session
+                        .subkernels_finished
+                        .iter()
+                        .position(|(finished_id, _)| finished_id == id);
+                    match position {
+                        Some(i) => {
+                            self.session.subkernels_finished.swap_remove(i);
+                            statuses.push(kernel::SubkernelStatus::NoError);
+                        }
+                        None => statuses.push(kernel::SubkernelStatus::Timeout),
+                    }
+                }
+                match exception_source {
+                    Some(destination) => {
+                        self.session.external_exception = Some(Vec::new());
+                        self.session.kernel_state = KernelState::SubkernelRetrievingException {
+                            destination: destination,
+                            seq: 0,
+                            max_time: timer::get_ms() + EXCEPTION_SLICE_TIMEOUT_MS,
+                            retries: 0,
+                        };
+                        request_exception_slice(destination, 0, router, routing_table, rank, self_destination);
+                    }
+                    None => {
+                        self.control
+                            .borrow_mut()
+                            .tx
+                            .async_send(kernel::Message::SubkernelAwaitManyReply { statuses: statuses })
+                            .await;
+                        self.session.kernel_state = KernelState::Running;
+                    }
+                }
+                Ok(())
+            }
+            KernelState::SubkernelRetrievingException {
+                destination,
+                seq,
+                max_time,
+                retries,
+            } => {
+                if timer::get_ms() <= *max_time {
+                    return Err(Error::AwaitingMessage);
+                }
+                let (destination, seq, retries) = (*destination, *seq, *retries);
+                if retries >= EXCEPTION_SLICE_RETRIES {
+                    warn!("gave up retrieving the exception from destination {}", destination);
+                    self.session.external_exception = None;
+                    self.control
+                        .borrow_mut()
+                        .tx
+                        .send(kernel::Message::SubkernelError(kernel::SubkernelStatus::Timeout));
+                    self.session.kernel_state = KernelState::Running;
+                    return Ok(());
+                }
+                self.session.kernel_state = KernelState::SubkernelRetrievingException {
+                    destination: destination,
+                    seq: seq,
+                    max_time: timer::get_ms() + EXCEPTION_SLICE_TIMEOUT_MS,
+                    retries: retries + 1,
+                };
+                request_exception_slice(destination, seq, router, routing_table, rank, self_destination);
+                Err(Error::AwaitingMessage)
+            }
+            KernelState::BarrierWait { max_time, id } => {
+                if let Some(max_time) = *max_time {
+                    if timer::get_ms() > max_time {
+                        // take the arrival back, or the master would count it towards the next
+                        // use of the barrier
+                        if drtiosat_aux::upstream_supports(FEATURE_BARRIER_WITHDRAW) {
+                            router.route(
+                                drtioaux::Packet::SubkernelBarrierWithdraw {
+                                    source: self_destination,
+                                    destination: routing_table.master_destination().unwrap_or(0),
+                                    id: *id,
+                                },
+                                routing_table,
+                                rank,
+                                self_destination,
+                            );
+                        }
+                        self.control
+                            .borrow_mut()
+                            .tx
+                            .send(kernel::Message::SubkernelError(kernel::SubkernelStatus::Timeout));
+                        self.session.kernel_state = KernelState::Running;
+                        return Ok(());
+                    }
+                }
+                Err(Error::AwaitingMessage)
+            }
             KernelState::DmaAwait { max_time } | KernelState::DmaPendingAwait { max_time, .. } => {
                 if timer::get_ms() > *max_time {
                     self.control
@@ -975,6 +1656,7 @@ impl<'a> Manager<'a> {
                             error: 0,
                             channel: 0,
                             timestamp: 0,
+                            stat: kernel::DmaPlaybackStat::default(),
                         })
                         .await;
                     self.session.kernel_state = KernelState::Running;
@@ -985,72 +1667,220 @@ impl<'a> Manager<'a> {
         }
     }
 
-    async fn pass_message_to_kernel(&mut self, message: &Message, tags: Vec<u8>) -> Result<(), Error> {
-        let mut reader = Cursor::new(&message.data);
-        let mut current_tags: &[u8] = &tags;
-        let mut i = message.count;
+    async fn pass_message_to_kernel(
+        &mut self,
+        router: &mut Router,
+        routing_table: &RoutingTable,
+        rank: u8,
+        self_destination: u8,
+    ) -> Result<(), Error> {
+        let result = self.stream_message_values().await;
+        while let Some((destination, credits)) = self.session.messages.pending_credit() {
+            router.route(
+                drtioaux::Packet::SubkernelMessageCredit {
+                    source: self_destination,
+                    destination: destination,
+                    credits: credits,
+                },
+                routing_table,
+                rank,
+                self_destination,
+            );
+        }
+        result
+    }
+
+    async fn stream_message_values(&mut self) -> Result<(), Error> {
+        // passes on as many values as the received data allows,
+        // returns AwaitingMessage if the kernel has to wait for more
+        let control = self.control;
         loop {
-            let slot = match recv_w_timeout(&mut self.control.borrow_mut().rx, 100).await? {
-                kernel::Message::RpcRecvRequest(slot) => slot,
-                other => unexpected!("expected root value slot from core1, not {:?}", other),
+            let delivery = match self.session.messages.delivery.as_mut() {
+                Some(delivery) => delivery,
+                None => return Ok(()),
             };
-            let mut exception: Option<Sliceable> = None;
-            let mut unexpected: Option<String> = None;
-            let remaining_tags = rpc_async::recv_return(&mut reader, current_tags, slot, &mut async |size| {
-                if size == 0 {
-                    0 as *mut ()
-                } else {
-                    self.control
-                        .borrow_mut()
-                        .tx
-                        .async_send(kernel::Message::RpcRecvReply(Ok(size)))
-                        .await;
-                    match recv_w_timeout(&mut self.control.borrow_mut().rx, 100).await {
-                        Ok(kernel::Message::RpcRecvRequest(slot)) => slot,
-                        Ok(kernel::Message::KernelException(exceptions, stack_pointers, backtrace)) => {
-                            let buf: Vec<u8> = Vec::new();
-                            let mut writer = Cursor::new(buf);
-                            match write_exception(&mut writer, exceptions, stack_pointers, backtrace) {
-                                Ok(()) => {
-                                    exception = Some(Sliceable::new(0, writer.into_inner()));
-                                }
-                                Err(_) => {
-                                    unexpected = Some("Error writing exception data".to_string());
-                                }
-                            };
-                            0 as *mut ()
-                        }
-                        other => {
-                            unexpected = Some(format!("expected nested value slot from kernel CPU, not {:?}", other));
-                            0 as *mut ()
-                        }
+            delivery.waiting = false;
+            if let Some(bulk) = delivery.bulk.as_mut() {
+                let length = min(bulk.size - bulk.received, delivery.message.data.len());
+                if length > 0 {
+                    unsafe {
+                        let dest = bulk.storage.add(bulk.received);
+                        ptr::copy_nonoverlapping(delivery.message.data.as_ptr(), dest, length);
                     }
                 }
-            })
-            .await?;
-            if let Some(exception) = exception {
-                self.kernel_stop();
-                return Err(Error::KernelException(exception));
-            } else if let Some(unexpected) = unexpected {
-                self.kernel_stop();
-                unexpected!("{}", unexpected);
+                delivery.message.data.drain(..length);
+                bulk.received += length;
+                if bulk.received < bulk.size {
+                    delivery.waiting = true;
+                    return Err(Error::AwaitingMessage);
+                }
+                unsafe { rpc_async::finish_bulk(bulk.elt_size, bulk.storage, bulk.size) };
+                delivery.bulk = None;
+            } else {
+                let mut it = TagIterator::new(&delivery.tags[delivery.tag_offset..]);
+                let tag = it.next().expect("truncated tag");
+                let next_tag_offset = delivery.tags.len() - it.data.len();
+                let bulk_element = rpc_async::bulk_element(tag);
+                let ready = match bulk_element {
+                    Some(_) => delivery.message.data.len() >= rpc_async::bulk_header_size(tag),
+                    None => rpc_async::value_size(&delivery.message.data, tag)?.is_some(),
+                };
+                if !ready {
+                    if delivery.message.complete {
+                        unexpected!("subkernel message #{} is truncated", delivery.message.id);
+                    }
+                    delivery.waiting = true;
+                    return Err(Error::AwaitingMessage);
+                }
+
+                let slot = match recv_w_timeout(&mut control.borrow_mut().rx, 100).await? {
+                    kernel::Message::RpcRecvRequest(slot) => slot,
+                    other => unexpected!("expected root value slot from core1, not {:?}", other),
+                };
+                let mut exception: Option<Sliceable> = None;
+                let mut unexpected: Option<String> = None;
+                let mut reader = Cursor::new(&delivery.message.data[..]);
+                let bulk = match bulk_element {
+                    Some(elt_tag) => {
+                        let (storage, size) = unsafe {
+                            rpc_async::recv_bulk_header(&mut reader, tag, slot, &mut async |size| {
+                                kernel_alloc(control, size, &mut exception, &mut unexpected).await
+                            })
+                            .await?
+                        };
+                        Some(BulkTransfer {
+                            storage: storage,
+                            size: size,
+                            received: 0,
+                            elt_size: elt_tag.size(),
+                        })
+                    }
+                    None => {
+                        rpc_async::recv_return(
+                            &mut reader,
+                            &delivery.tags[delivery.tag_offset..],
+                            slot,
+                            &mut async |size| kernel_alloc(control, size, &mut exception, &mut unexpected).await,
+                        )
+                        .await?;
+                        None
+                    }
+                };
+                let consumed = reader.position();
+                if let Some(exception) = exception {
+                    self.kernel_stop();
+                    return Err(Error::KernelException(exception));
+                } else if let Some(unexpected) = unexpected {
+                    self.kernel_stop();
+                    unexpected!("{}", unexpected);
+                }
+                delivery.message.data.drain(..consumed);
+                delivery.tag_offset = next_tag_offset;
+                if bulk.is_some() {
+                    delivery.bulk = bulk;
+                    continue;
+                }
             }
-            self.control
+            control
                 .borrow_mut()
                 .tx
                 .async_send(kernel::Message::RpcRecvReply(Ok(0)))
                 .await;
-            i -= 1;
-            if i == 0 {
-                break;
-            } else {
-                current_tags = remaining_tags;
+            delivery.remaining -= 1;
+            if delivery.remaining == 0 {
+                self.session.messages.delivery = None;
+                self.session.kernel_state = KernelState::Running;
+                return Ok(());
             }
         }
-        Ok(())
     }
 }
 
+async fn kernel_alloc(
+    control: &RefCell<kernel::Control>,
+    size: usize,
+    exception: &mut Option<Sliceable>,
+    unexpected: &mut Option<String>,
+) -> *mut () {
+    if size == 0 {
+        return 0 as *mut ();
+    }
+    control
+        .borrow_mut()
+        .tx
+        .async_send(kernel::Message::RpcRecvReply(Ok(size)))
+        .await;
+    match recv_w_timeout(&mut control.borrow_mut().rx, 100).await {
+        Ok(kernel::Message::RpcRecvRequest(slot)) => slot,
+        Ok(kernel::Message::KernelException(exceptions, stack_pointers, backtrace)) => {
+            let buf: Vec<u8> = Vec::new();
+            let mut writer = Cursor::new(buf);
+            match write_exception(&mut writer, exceptions, stack_pointers, backtrace) {
+                Ok(()) => {
+                    *exception = Some(Sliceable::new(0, writer.into_inner()));
+                }
+                Err(_) => {
+                    *unexpected = Some("Error writing exception data".to_string());
+                }
+            };
+            0 as *mut ()
+        }
+        other => {
+            *unexpected = Some(format!("expected nested value slot from kernel CPU, not {:?}", other));
+            0 as *mut ()
+        }
+    }
+}
+
+#[cfg(has_rtio_moninj)]
+fn inject(channel: i32, overrd: i8, value: i8) -> bool {
+    unsafe {
+        csr::rtio_moninj::inj_chan_sel_write(channel as _);
+        csr::rtio_moninj::inj_override_sel_write(overrd as _);
+        csr::rtio_moninj::inj_value_write(value as _);
+    }
+    true
+}
+
+#[cfg(not(has_rtio_moninj))]
+fn inject(_channel: i32, _overrd: i8, _value: i8) -> bool {
+    false
+}
+
+#[cfg(has_rtio_moninj)]
+fn read_injection_status(channel: i32, overrd: i8) -> Option<i8> {
+    unsafe {
+        csr::rtio_moninj::inj_chan_sel_write(channel as _);
+        csr::rtio_moninj::inj_override_sel_write(overrd as _);
+        Some(csr::rtio_moninj::inj_value_read() as i8)
+    }
+}
+
+#[cfg(not(has_rtio_moninj))]
+fn read_injection_status(_channel: i32, _overrd: i8) -> Option<i8> {
+    None
+}
+
+fn request_exception_slice(
+    destination: u8,
+    seq: u16,
+    router: &mut Router,
+    routing_table: &RoutingTable,
+    rank: u8,
+    self_destination: u8,
+) {
+    router.route(
+        drtioaux::Packet::SubkernelExceptionRequest {
+            source: self_destination,
+            destination: destination,
+            seq: seq,
+        },
+        routing_table,
+        rank,
+        self_destination,
+    );
+}
+
 fn write_exception<W: ProtoWrite>(
     writer: &mut W,
     exceptions: &[Option<eh_artiq::Exception>],
@@ -1069,16 +1899,14 @@ fn write_exception<W: ProtoWrite>(
             writer.write_u32::<NativeEndian>(u32::MAX)?;
             writer.write_u32::<NativeEndian>(exception.message.as_ptr() as u32)?;
         } else {
+            // the channel is described with the destination of the satellite, which the
+            // subkernel exception is passed on from
             let msg =
                 str::from_utf8(unsafe { slice::from_raw_parts(exception.message.as_ptr(), exception.message.len()) })
                     .unwrap()
                     .replace(
                         "{rtio_channel_info:0}",
-                        &format!(
-                            "0x{:04x}:{}",
-                            exception.param[0],
-                            libboard_artiq::resolve_channel_name(exception.param[0] as u32)
-                        ),
+                        &libboard_artiq::format_channel_info(exception.param[0] as u32, None, None),
                     );
             writer.write_string::<NativeEndian>(&msg)?;
         }
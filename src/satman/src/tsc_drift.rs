@@ -0,0 +1,85 @@
+use libboard_artiq::pl::csr;
+use libconfig;
+use libcortex_a9::mutex::Mutex;
+use log::{info, warn};
+
+// Tracks how the local RTIO counter follows the master after the TSC has been
+// loaded. The master periodically sends its estimate of its own counter at the
+// time the probe arrives (its counter when sending plus half the last aux round
+// trip). The offset to the local counter is compared against the first sample
+// taken after the TSC was loaded; a constant aux latency error cancels out.
+
+const DEFAULT_WARN_PPB: u32 = 1000;
+// samples closer together than this are too noisy to give a drift figure
+const MIN_ELAPSED: u64 = 1_000_000_000;
+
+struct State {
+    baseline: Option<(u64, i64)>,
+    offset: i64,
+    drift_ppb: i32,
+    warn_ppb: Option<u32>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    baseline: None,
+    offset: 0,
+    drift_ppb: 0,
+    warn_ppb: None,
+});
+
+fn read_warn_ppb() -> u32 {
+    match libconfig::read_str("tsc_drift_warn_ppb") {
+        Ok(warn_ppb) => match warn_ppb.parse() {
+            Ok(warn_ppb) => warn_ppb,
+            Err(_) => {
+                warn!("tsc_drift_warn_ppb \"{}\" not supported, ignoring", warn_ppb);
+                DEFAULT_WARN_PPB
+            }
+        },
+        Err(_) => DEFAULT_WARN_PPB,
+    }
+}
+
+// Called when the TSC is (re)loaded from the uplink, which invalidates the baseline.
+pub fn reset() {
+    let mut state = STATE.lock();
+    state.baseline = None;
+    state.offset = 0;
+    state.drift_ppb = 0;
+}
+
+// Records a probe and returns the current offset (in RTIO counter units) and drift.
+pub fn record(master_time: u64) -> (i64, i32) {
+    let local_time = unsafe {
+        csr::rtio::counter_update_write(1);
+        csr::rtio::counter_read()
+    };
+    let offset = local_time as i64 - master_time as i64;
+
+    let mut state = STATE.lock();
+    let warn_ppb = *state.warn_ppb.get_or_insert_with(read_warn_ppb);
+    state.offset = offset;
+    match state.baseline {
+        None => {
+            info!("TSC drift baseline taken, offset {}", offset);
+            state.baseline = Some((master_time, offset));
+        }
+        Some((baseline_time, baseline_offset)) => {
+            let elapsed = master_time.saturating_sub(baseline_time);
+            if elapsed >= MIN_ELAPSED {
+                let drift_ppb = (offset - baseline_offset) as i128 * 1_000_000_000 / elapsed as i128;
+                state.drift_ppb = drift_ppb.max(i32::MIN as i128).min(i32::MAX as i128) as i32;
+                if (state.drift_ppb as i64).abs() > warn_ppb as i64 {
+                    warn!(
+                        "TSC drift {} ppb exceeds {} ppb (offset {}, {} since baseline)",
+                        state.drift_ppb,
+                        warn_ppb,
+                        offset - baseline_offset,
+                        elapsed
+                    );
+                }
+            }
+        }
+    }
+    (state.offset, state.drift_ppb)
+}